@@ -1,13 +1,18 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use ic_canister::{generate_exports, generate_idl, state_getter, update, Canister, Idl, PreUpdate};
+use ic_canister::{
+    generate_exports, generate_idl, state_getter, update, AsyncReturn, Canister, Idl, PreUpdate,
+};
 use ic_exports::candid::Principal;
 #[cfg(feature = "debug-logs")]
 use ic_exports::ic_cdk;
+use ic_exports::ic_kit::ic;
 use ic_metrics::Interval;
+use ic_task_scheduler::scheduler::TaskScheduler;
 
 use crate::error::{AuctionError, Result};
+use crate::escrow::{self, EscrowTransfer, TokenEscrowConfig};
 use crate::state::{AuctionInfo, AuctionState, BiddingInfo};
 
 pub trait Auction: Canister + Sized {
@@ -32,6 +37,15 @@ pub trait Auction: Canister + Sized {
         panic!("disburse_rewards is unimplemented")
     }
 
+    /// The scheduler executing the settlement and refund transfers of the token bids.
+    ///
+    /// Must be overwritten if the token bids are enabled, and the scheduler must be run
+    /// periodically, e.g. by a timer. Without it the token bids are rejected with
+    /// [`AuctionError::EscrowSchedulerNotSet`].
+    fn escrow_scheduler(&self) -> Result<Box<dyn TaskScheduler<EscrowTransfer>>> {
+        Err(AuctionError::EscrowSchedulerNotSet)
+    }
+
     /// Starts the cycle auction.
     ///
     /// This method can be called only once in a [BiddingState.auction_period]. If the time elapsed
@@ -39,11 +53,16 @@ pub trait Auction: Canister + Sized {
     ///
     /// The auction will distribute the accumulated fees in proportion to the user cycle bids, and
     /// then will update the fee ratio until the next auction.
+    ///
+    /// The escrowed token bids are counted as cycle bids, valued with
+    /// [TokenEscrowConfig::cycles_per_token], so their bidders get their share of the rewards.
+    /// The counted token bids are transferred to the settlement account if the rewards are
+    /// disbursed, the others are refunded to the bidders.
     #[update(trait = true)]
     fn run_auction(&self) -> Result<AuctionInfo> {
         let auction_state = self.auction_state();
 
-        if auction_state.borrow().bidding_state.bids.is_empty() {
+        if !auction_state.borrow().has_bids() {
             return Err(AuctionError::NoBids);
         }
 
@@ -56,8 +75,27 @@ pub trait Auction: Canister + Sized {
             ));
         }
 
+        // The scheduler is checked before the bids are taken out of the escrow, so the tokens
+        // are never settled without the transfers to execute it.
+        let scheduler = if auction_state.borrow().token_escrow.bids.is_empty() {
+            None
+        } else {
+            Some(self.escrow_scheduler()?)
+        };
+
+        let counted = auction_state.borrow_mut().count_token_bids();
         let result = self.disburse_rewards();
 
+        let transfers = auction_state
+            .borrow_mut()
+            .token_escrow
+            .settle(ic::time(), |bidder| {
+                result.is_ok() && counted.contains(bidder)
+            });
+        if let Some(scheduler) = scheduler {
+            escrow::schedule_transfers(scheduler.as_ref(), transfers);
+        }
+
         auction_state.borrow_mut().reset_bidding_state();
 
         if let Ok(result) = result.clone() {
@@ -77,6 +115,41 @@ pub trait Auction: Canister + Sized {
         self.auction_state().borrow_mut().bid_cycles(bidder)
     }
 
+    /// Bid ICRC-2 tokens for the next auction.
+    ///
+    /// The caller must approve the canister to transfer `amount` plus the token fee from their
+    /// account. The tokens are held in escrow until the auction, and refunded without the fee if
+    /// the bid doesn't win.
+    #[update(trait = true)]
+    fn bid_tokens(&self, amount: u128) -> AsyncReturn<'_, Result<u128>> {
+        let auction_state = self.auction_state();
+        Box::pin(async move {
+            // The tokens are only accepted if they can be settled or refunded at the auction.
+            self.escrow_scheduler()?;
+
+            // The bid is reserved while its tokens are transferred, so the configuration can't
+            // be changed in the meantime.
+            let config = auction_state
+                .borrow_mut()
+                .token_escrow
+                .reserve_bid(amount)?;
+            let bidder = ic::caller();
+            let result = escrow::escrow_bid(&config, bidder, amount).await;
+
+            let mut auction_state = auction_state.borrow_mut();
+            match result {
+                Ok(()) => {
+                    auction_state.token_escrow.complete_bid(bidder, amount);
+                    Ok(amount)
+                }
+                Err(err) => {
+                    auction_state.token_escrow.cancel_bid();
+                    Err(err)
+                }
+            }
+        })
+    }
+
     /// Current information about bids and auction.
     #[update(trait = true)]
     fn bidding_info(&self) -> BiddingInfo {
@@ -99,6 +172,17 @@ pub trait Auction: Canister + Sized {
         self.auction_state().borrow().min_cycles()
     }
 
+    /// Returns the configuration of the token bids, `None` if they are disabled.
+    #[update(trait = true)]
+    fn get_token_escrow_config(&self) -> Option<TokenEscrowConfig> {
+        self.auction_state()
+            .borrow()
+            .token_escrow
+            .config()
+            .ok()
+            .cloned()
+    }
+
     /// Update the controller of the auction.
     ///
     /// Only previous controller/owner is allowed to call this method.
@@ -135,6 +219,18 @@ pub trait Auction: Canister + Sized {
         Ok(())
     }
 
+    /// Sets the configuration of the token bids, `None` disables them. The configuration cannot
+    /// be changed while there are pending token bids.
+    ///
+    /// Only the owner is allowed to call this method.
+    #[update(trait = true)]
+    fn set_token_escrow_config(&self, config: Option<TokenEscrowConfig>) -> Result<()> {
+        self.auction_state()
+            .borrow_mut()
+            .authorize_owner()?
+            .set_token_escrow_config(config)
+    }
+
     // Important: This function *must* be defined to be the
     // last one in the trait because it depends on the order
     // of expansion of update/query(trait = true) methods.
@@ -3,10 +3,10 @@ use thiserror::Error;
 
 #[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
 pub enum AuctionError {
-    #[error("provided cycles in the `bid_cycles` call is less then the minimum allowed amount")]
+    #[error("the provided bid is less then the minimum allowed amount")]
     BiddingTooSmall,
 
-    #[error("there are no cycle bids pending, so the auction cannot be held")]
+    #[error("there are no bids pending, so the auction cannot be held")]
     NoBids,
 
     #[error("auction with the given id is not found")]
@@ -17,6 +17,33 @@ pub enum AuctionError {
 
     #[error("the principal {0} is not an auction controller")]
     Unauthorized(String),
+
+    #[error("bidding in tokens is not configured")]
+    TokenBidsDisabled,
+
+    #[error("token bids configuration cannot be changed while there are pending token bids")]
+    PendingTokenBids,
+
+    #[error("the scheduler of the escrow transfers is not set")]
+    EscrowSchedulerNotSet,
+
+    #[error("token transfer failed: {0}")]
+    TokenTransferFailed(String),
+
+    #[error("invalid auction format: {0}")]
+    InvalidAuctionFormat(String),
+
+    #[error("lot with the given id is not found")]
+    LotNotFound,
+
+    #[error("the auction of the lot is closed")]
+    LotClosed,
+
+    #[error("the auction of the lot has not ended yet")]
+    LotNotEnded,
+
+    #[error("the bid is too low, the minimum bid is {0}")]
+    BidTooLow(u128),
 }
 
 pub type Result<T> = std::result::Result<T, AuctionError>;
@@ -0,0 +1,421 @@
+//! Bids in ICRC-2 tokens, held in escrow by the auction canister until the auction.
+//!
+//! A bidder approves the auction canister to spend the bid amount plus the token fee, and calls
+//! `bid_tokens`. The canister moves the tokens with `icrc2_transfer_from` to its escrow
+//! subaccount. When the auction is held, the winning bids are transferred to the settlement
+//! account and the losing ones are refunded to the bidders.
+//!
+//! The settlement and refund transfers are executed by the task scheduler. A transfer is retried
+//! with the same `created_at_time` and memo, so the ledger deduplicates the retries and the
+//! tokens are never transferred twice.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use ic_canister::virtual_canister_call;
+use ic_exports::candid::{CandidType, Deserialize, Nat, Principal};
+use ic_exports::ic_kit::ic;
+use ic_exports::icrc_types::icrc1::account::{Account, Subaccount};
+use ic_exports::icrc_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use ic_exports::icrc_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use ic_task_scheduler::scheduler::TaskScheduler;
+use ic_task_scheduler::task::{ScheduledTask, Task, TaskOptions};
+use ic_task_scheduler::SchedulerError;
+use serde::Serialize;
+
+use crate::error::{AuctionError, Result};
+use crate::state::{Cycles, Timestamp};
+
+/// The subaccount of the auction canister holding the escrowed tokens.
+pub const ESCROW_SUBACCOUNT: Subaccount = {
+    let name = b"ic-auction/escrow";
+    let mut subaccount = [0; 32];
+    let mut i = 0;
+    while i < name.len() {
+        subaccount[i] = name[i];
+        i += 1;
+    }
+    subaccount
+};
+
+/// The number of retries of a failed escrow transfer.
+pub const TRANSFER_MAX_RETRIES: u32 = 10;
+
+/// The delay between the retries of a failed escrow transfer, in seconds.
+///
+/// All the retries must be done within the ledger deduplication window of 24 hours, otherwise
+/// the ledger rejects the transfer as too old.
+pub const TRANSFER_RETRY_DELAY_SECS: u32 = 60 * 60;
+
+/// Configuration of the bids in tokens.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct TokenEscrowConfig {
+    /// The ICRC-2 token canister.
+    pub token: Principal,
+    /// The transfer fee of the token.
+    pub fee: u128,
+    /// The account receiving the winning bids.
+    pub settlement_account: Account,
+    /// The minimum amount of a bid, must be greater than the fee.
+    pub min_bid: u128,
+    /// The cycles a bid of one token unit counts as, when the rewards of the auction are
+    /// distributed in proportion to the bids. The token bids worth no cycles are not counted and
+    /// are refunded at the auction.
+    #[serde(default)]
+    pub cycles_per_token: Cycles,
+}
+
+/// The token bids of the upcoming auction, held in escrow.
+#[derive(CandidType, Debug, Clone, Default, Deserialize)]
+pub struct TokenEscrow {
+    config: Option<TokenEscrowConfig>,
+    pub bids: HashMap<Principal, u128>,
+    next_transfer_id: u64,
+    /// The number of the bids whose tokens are being moved to the escrow account.
+    #[serde(default)]
+    pending_bids: u64,
+}
+
+impl TokenEscrow {
+    pub fn config(&self) -> Result<&TokenEscrowConfig> {
+        self.config.as_ref().ok_or(AuctionError::TokenBidsDisabled)
+    }
+
+    /// Sets the configuration of the token bids, `None` disables them.
+    ///
+    /// The configuration cannot be changed while there are pending token bids, including the
+    /// bids whose tokens are being transferred.
+    pub fn set_config(&mut self, config: Option<TokenEscrowConfig>) -> Result<()> {
+        if !self.bids.is_empty() || self.pending_bids > 0 {
+            return Err(AuctionError::PendingTokenBids);
+        }
+
+        self.config = config;
+        Ok(())
+    }
+
+    /// The cycles the bid of `amount` tokens counts as in the reward computation, see
+    /// [`TokenEscrowConfig::cycles_per_token`].
+    pub fn cycles_value(&self, amount: u128) -> Cycles {
+        let cycles_per_token = self
+            .config
+            .as_ref()
+            .map_or(0, |config| config.cycles_per_token);
+        amount
+            .saturating_mul(cycles_per_token as u128)
+            .min(Cycles::MAX as u128) as Cycles
+    }
+
+    /// Total amount of the escrowed tokens.
+    pub fn total(&self) -> u128 {
+        self.bids.values().sum()
+    }
+
+    /// Checks that the bid can be made, and returns the configuration to make it with.
+    pub fn check_bid(&self, amount: u128) -> Result<TokenEscrowConfig> {
+        let config = self.config()?;
+        if amount < config.min_bid || amount <= config.fee {
+            return Err(AuctionError::BiddingTooSmall);
+        }
+
+        Ok(config.clone())
+    }
+
+    /// Checks that the bid can be made, and reserves it until [`Self::complete_bid`] or
+    /// [`Self::cancel_bid`], so the configuration cannot be changed while the tokens of the bid
+    /// are being transferred. Returns the configuration to make the bid with.
+    pub fn reserve_bid(&mut self, amount: u128) -> Result<TokenEscrowConfig> {
+        let config = self.check_bid(amount)?;
+        self.pending_bids += 1;
+        Ok(config)
+    }
+
+    /// Records the reserved bid, whose tokens were moved to the escrow account.
+    pub fn complete_bid(&mut self, bidder: Principal, amount: u128) {
+        self.pending_bids = self.pending_bids.saturating_sub(1);
+        self.add_bid(bidder, amount);
+    }
+
+    /// Releases the reserved bid, whose tokens were not moved to the escrow account.
+    pub fn cancel_bid(&mut self) {
+        self.pending_bids = self.pending_bids.saturating_sub(1);
+    }
+
+    /// Records the bid, whose tokens were moved to the escrow account.
+    pub fn add_bid(&mut self, bidder: Principal, amount: u128) {
+        *self.bids.entry(bidder).or_insert(0) += amount;
+    }
+
+    /// Takes all the bids out of the escrow. The total of the winning bids is transferred to the
+    /// settlement account, and the losing bids are refunded to the bidders.
+    ///
+    /// The returned transfers must be scheduled with [`schedule_transfers`]. The bids which don't
+    /// cover the transfer fee are kept by the escrow account.
+    pub fn settle(
+        &mut self,
+        now: Timestamp,
+        is_winner: impl Fn(&Principal) -> bool,
+    ) -> Vec<EscrowTransfer> {
+        let bids = std::mem::take(&mut self.bids);
+        let Some(config) = self.config.clone() else {
+            return vec![];
+        };
+
+        let mut transfers = vec![];
+        let mut settled = 0;
+        let mut refunds = bids.into_iter().collect::<Vec<_>>();
+        refunds.sort();
+        for (bidder, amount) in refunds {
+            if is_winner(&bidder) {
+                settled += amount;
+            } else {
+                transfers.extend(self.transfer(
+                    &config,
+                    now,
+                    TransferKind::Refund,
+                    Account::from(bidder),
+                    amount,
+                ));
+            }
+        }
+
+        transfers.extend(self.transfer(
+            &config,
+            now,
+            TransferKind::Settlement,
+            config.settlement_account,
+            settled,
+        ));
+        transfers
+    }
+
+    fn transfer(
+        &mut self,
+        config: &TokenEscrowConfig,
+        now: Timestamp,
+        kind: TransferKind,
+        to: Account,
+        amount: u128,
+    ) -> Option<EscrowTransfer> {
+        if amount <= config.fee {
+            return None;
+        }
+
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+
+        Some(EscrowTransfer {
+            id,
+            kind,
+            token: config.token,
+            to,
+            amount: amount - config.fee,
+            fee: config.fee,
+            created_at_time: now,
+        })
+    }
+}
+
+/// Moves the bid of the `bidder` to the escrow account with `icrc2_transfer_from`.
+pub async fn escrow_bid(config: &TokenEscrowConfig, bidder: Principal, amount: u128) -> Result<()> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::from(bidder),
+        to: Account {
+            owner: ic::id(),
+            subaccount: Some(ESCROW_SUBACCOUNT),
+        },
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(config.fee)),
+        memo: None,
+        created_at_time: None,
+    };
+
+    virtual_canister_call!(
+        config.token,
+        "icrc2_transfer_from",
+        (args,),
+        std::result::Result<Nat, TransferFromError>
+    )
+    .await
+    .map_err(|(code, message)| AuctionError::TokenTransferFailed(format!("{code:?}: {message}")))?
+    .map_err(|err| AuctionError::TokenTransferFailed(format!("{err:?}")))?;
+
+    Ok(())
+}
+
+/// Adds the transfers to the scheduler, which retries the failed ones.
+pub fn schedule_transfers(
+    scheduler: &dyn TaskScheduler<EscrowTransfer>,
+    transfers: Vec<EscrowTransfer>,
+) -> Vec<u32> {
+    let options = || {
+        TaskOptions::new()
+            .with_max_retries_policy(TRANSFER_MAX_RETRIES)
+            .with_fixed_backoff_policy(TRANSFER_RETRY_DELAY_SECS)
+    };
+
+    scheduler.append_tasks(
+        transfers
+            .into_iter()
+            .map(|transfer| ScheduledTask::with_options(transfer, options()))
+            .collect(),
+    )
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    /// Transfer of the winning bids to the settlement account.
+    Settlement,
+    /// Return of a losing bid to the bidder.
+    Refund,
+}
+
+/// A transfer from the escrow account, executed by the task scheduler.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EscrowTransfer {
+    /// Unique id of the transfer, used as its memo.
+    pub id: u64,
+    pub kind: TransferKind,
+    pub token: Principal,
+    pub to: Account,
+    /// The transferred amount, without the fee.
+    pub amount: u128,
+    pub fee: u128,
+    /// Fixed for all the attempts of the transfer, so the ledger deduplicates them.
+    pub created_at_time: Timestamp,
+}
+
+impl Task for EscrowTransfer {
+    fn execute(
+        &self,
+        _task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+    ) -> Pin<Box<dyn Future<Output = std::result::Result<(), SchedulerError>>>> {
+        let transfer = self.clone();
+        Box::pin(async move {
+            let args = TransferArg {
+                from_subaccount: Some(ESCROW_SUBACCOUNT),
+                to: transfer.to,
+                amount: Nat::from(transfer.amount),
+                fee: Some(Nat::from(transfer.fee)),
+                memo: Some(Memo::from(transfer.id)),
+                created_at_time: Some(transfer.created_at_time),
+            };
+
+            let result = virtual_canister_call!(
+                transfer.token,
+                "icrc1_transfer",
+                (args,),
+                std::result::Result<Nat, TransferError>
+            )
+            .await;
+
+            match result {
+                // The previous attempt succeeded, but its response was lost.
+                Ok(Ok(_)) | Ok(Err(TransferError::Duplicate { .. })) => Ok(()),
+                Ok(Err(err)) => Err(SchedulerError::TaskExecutionFailed(format!(
+                    "{:?} transfer {} to {} failed: {err:?}",
+                    transfer.kind, transfer.id, transfer.to.owner
+                ))),
+                Err((code, message)) => Err(SchedulerError::TaskExecutionFailed(format!(
+                    "{:?} transfer {} to {} was rejected: {code:?}: {message}",
+                    transfer.kind, transfer.id, transfer.to.owner
+                ))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escrow() -> TokenEscrow {
+        let mut escrow = TokenEscrow::default();
+        escrow
+            .set_config(Some(TokenEscrowConfig {
+                token: Principal::management_canister(),
+                fee: 10,
+                settlement_account: Account::from(Principal::anonymous()),
+                min_bid: 100,
+                cycles_per_token: 1_000,
+            }))
+            .unwrap();
+        escrow
+    }
+
+    #[test]
+    fn winning_bids_are_settled_and_losing_bids_refunded() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+
+        let mut escrow = escrow();
+        assert_eq!(escrow.check_bid(99), Err(AuctionError::BiddingTooSmall));
+        escrow.add_bid(alice, 100);
+        escrow.add_bid(bob, 200);
+        escrow.add_bid(alice, 300);
+        escrow.add_bid(carol, 150);
+        assert_eq!(escrow.total(), 750);
+        assert_eq!(escrow.set_config(None), Err(AuctionError::PendingTokenBids));
+
+        let transfers = escrow.settle(42, |bidder| *bidder != bob);
+        assert!(escrow.bids.is_empty());
+        assert_eq!(
+            transfers
+                .iter()
+                .map(|transfer| (
+                    transfer.id,
+                    transfer.kind,
+                    transfer.to.owner,
+                    transfer.amount
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (0, TransferKind::Refund, bob, 190),
+                (1, TransferKind::Settlement, Principal::anonymous(), 540),
+            ]
+        );
+        assert!(transfers
+            .iter()
+            .all(|transfer| transfer.created_at_time == 42 && transfer.fee == 10));
+
+        escrow.add_bid(carol, 100);
+        let transfers = escrow.settle(43, |_| false);
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].id, 2);
+        assert_eq!(transfers[0].amount, 90);
+    }
+
+    #[test]
+    fn reserved_bids_block_config_changes() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let mut escrow = escrow();
+
+        assert_eq!(escrow.reserve_bid(99), Err(AuctionError::BiddingTooSmall));
+        assert!(escrow.set_config(escrow.config.clone()).is_ok());
+
+        escrow.reserve_bid(100).unwrap();
+        escrow.reserve_bid(200).unwrap();
+        assert_eq!(escrow.set_config(None), Err(AuctionError::PendingTokenBids));
+
+        escrow.cancel_bid();
+        assert_eq!(escrow.set_config(None), Err(AuctionError::PendingTokenBids));
+        escrow.complete_bid(alice, 100);
+        assert_eq!(escrow.bids[&alice], 100);
+
+        escrow.settle(42, |_| true);
+        assert!(escrow.set_config(None).is_ok());
+    }
+
+    #[test]
+    fn token_bids_are_valued_in_cycles() {
+        let mut escrow = escrow();
+        assert_eq!(escrow.cycles_value(150), 150_000);
+        assert_eq!(escrow.cycles_value(u128::MAX), Cycles::MAX);
+
+        escrow.set_config(None).unwrap();
+        assert_eq!(escrow.cycles_value(150), 0);
+    }
+}
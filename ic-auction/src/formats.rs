@@ -0,0 +1,544 @@
+//! Auctions of single lots in one of the formats: sealed-bid, Dutch or English.
+//!
+//! The format is selected when a lot is put up for auction, and all the formats share the same
+//! bidding API: [`Lots::bid`] and [`Lots::close`]. The lots are kept in stable memory, so the
+//! auctions survive the upgrades.
+//!
+//! The lots don't move any funds. The caller takes the bids into escrow, e.g. with
+//! [`crate::escrow`], and uses the outcomes of the bids and of the closing to settle the winning
+//! bid and refund the others.
+
+use std::borrow::Cow;
+
+use ic_exports::candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{BTreeMapStructure, Bound, StableBTreeMap, Storable};
+
+use crate::error::{AuctionError, Result};
+use crate::state::Timestamp;
+
+pub type LotId = u64;
+
+/// The rules of an auction.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum AuctionFormat {
+    /// The bids are not disclosed until the auction ends, then the highest bid wins. A bidder
+    /// can replace their bid while the auction is open.
+    SealedBid {
+        reserve_price: u128,
+        duration_nanos: u64,
+    },
+    /// The price decreases from the start price by the decrement every step down to the floor
+    /// price. The first bid of at least the current price wins at the current price.
+    Dutch {
+        start_price: u128,
+        floor_price: u128,
+        decrement: u128,
+        step_nanos: u64,
+    },
+    /// The open ascending auction. A bid must be at least the reserve price and exceed the
+    /// highest bid by the increment. A bid within the extension window before the end moves the
+    /// end to `extension_nanos` after the bid, so the other bidders can respond.
+    English {
+        reserve_price: u128,
+        min_increment: u128,
+        duration_nanos: u64,
+        extension_window_nanos: u64,
+        extension_nanos: u64,
+    },
+}
+
+impl AuctionFormat {
+    fn validate(&self) -> Result<()> {
+        let error = match self {
+            Self::SealedBid { duration_nanos, .. } if *duration_nanos == 0 => {
+                "auction duration must not be zero"
+            }
+            Self::Dutch {
+                start_price,
+                floor_price,
+                ..
+            } if start_price < floor_price => "start price must not be less than floor price",
+            Self::Dutch {
+                decrement,
+                step_nanos,
+                ..
+            } if *decrement == 0 || *step_nanos == 0 => "price decrement and step must not be zero",
+            Self::English { duration_nanos, .. } if *duration_nanos == 0 => {
+                "auction duration must not be zero"
+            }
+            Self::English { min_increment, .. } if *min_increment == 0 => {
+                "bid increment must not be zero"
+            }
+            _ => return Ok(()),
+        };
+
+        Err(AuctionError::InvalidAuctionFormat(error.to_string()))
+    }
+}
+
+#[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub struct Bid {
+    pub bidder: Principal,
+    pub amount: u128,
+    pub timestamp: Timestamp,
+}
+
+/// The state of a lot. A lot starts `Open`, and moves to `Closed` or `Cancelled`, which are
+/// final.
+#[derive(CandidType, Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub enum LotStatus {
+    Open,
+    Closed { winner: Option<Bid> },
+    Cancelled,
+}
+
+/// The result of an accepted bid.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct BidOutcome {
+    /// The bid, with the amount to pay.
+    pub bid: Bid,
+    /// The bids which can no longer win, e.g. the outbid one in the English auction or the
+    /// replaced one in the sealed-bid auction.
+    pub released: Vec<Bid>,
+    /// The auction was closed by the bid, which won.
+    pub closed: bool,
+}
+
+/// The result of a closed auction.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct CloseOutcome {
+    pub winner: Option<Bid>,
+    /// The bids which lost the auction.
+    pub released: Vec<Bid>,
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+struct Lot {
+    seller: Principal,
+    format: AuctionFormat,
+    started_at: Timestamp,
+    /// The end of the sealed-bid and English auctions, may be extended by the bids.
+    ends_at: Option<Timestamp>,
+    status: LotStatus,
+    /// The active bids, only the highest one for the English auction.
+    bids: Vec<Bid>,
+}
+
+impl Storable for Lot {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("serialization of auction lot failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("deserialization of auction lot failed")
+    }
+}
+
+impl Lot {
+    /// The current price of the Dutch auction.
+    fn dutch_price(&self, now: Timestamp) -> Option<u128> {
+        let AuctionFormat::Dutch {
+            start_price,
+            floor_price,
+            decrement,
+            step_nanos,
+        } = self.format
+        else {
+            return None;
+        };
+
+        let steps = (now.saturating_sub(self.started_at) / step_nanos) as u128;
+        Some(
+            start_price
+                .saturating_sub(steps.saturating_mul(decrement))
+                .max(floor_price),
+        )
+    }
+
+    /// The min amount of the next bid.
+    fn min_bid(&self, now: Timestamp) -> u128 {
+        match self.format {
+            AuctionFormat::SealedBid { reserve_price, .. } => reserve_price.max(1),
+            AuctionFormat::Dutch { .. } => self.dutch_price(now).unwrap_or_default(),
+            AuctionFormat::English {
+                reserve_price,
+                min_increment,
+                ..
+            } => match self.bids.first() {
+                Some(highest) => highest.amount.saturating_add(min_increment),
+                None => reserve_price.max(1),
+            },
+        }
+    }
+
+    fn highest_bid(&self) -> Option<Bid> {
+        // The earlier bid wins a tie.
+        self.bids.iter().copied().reduce(|highest, bid| {
+            if bid.amount > highest.amount {
+                bid
+            } else {
+                highest
+            }
+        })
+    }
+
+    fn bid(&mut self, bid: Bid) -> Result<BidOutcome> {
+        if self.status != LotStatus::Open || self.ends_at.is_some_and(|end| bid.timestamp >= end) {
+            return Err(AuctionError::LotClosed);
+        }
+
+        let min_bid = self.min_bid(bid.timestamp);
+        if bid.amount < min_bid {
+            return Err(AuctionError::BidTooLow(min_bid));
+        }
+
+        match self.format {
+            AuctionFormat::SealedBid { .. } => {
+                let released = self
+                    .bids
+                    .iter()
+                    .position(|active| active.bidder == bid.bidder)
+                    .map(|index| self.bids.remove(index));
+                self.bids.push(bid);
+
+                Ok(BidOutcome {
+                    bid,
+                    released: released.into_iter().collect(),
+                    closed: false,
+                })
+            }
+            AuctionFormat::Dutch { .. } => {
+                let bid = Bid {
+                    amount: min_bid,
+                    ..bid
+                };
+                self.bids.push(bid);
+                self.status = LotStatus::Closed { winner: Some(bid) };
+
+                Ok(BidOutcome {
+                    bid,
+                    released: vec![],
+                    closed: true,
+                })
+            }
+            AuctionFormat::English {
+                extension_window_nanos,
+                extension_nanos,
+                ..
+            } => {
+                if let Some(end) = self.ends_at {
+                    if end - bid.timestamp <= extension_window_nanos {
+                        self.ends_at = Some(end.max(bid.timestamp + extension_nanos));
+                    }
+                }
+                let released = std::mem::replace(&mut self.bids, vec![bid]);
+
+                Ok(BidOutcome {
+                    bid,
+                    released,
+                    closed: false,
+                })
+            }
+        }
+    }
+
+    fn close(&mut self, now: Timestamp) -> Result<CloseOutcome> {
+        if self.status != LotStatus::Open {
+            return Err(AuctionError::LotClosed);
+        }
+
+        let ended = match self.format {
+            AuctionFormat::Dutch { floor_price, .. } => self.dutch_price(now) == Some(floor_price),
+            _ => self.ends_at.is_some_and(|end| now >= end),
+        };
+        if !ended {
+            return Err(AuctionError::LotNotEnded);
+        }
+
+        let winner = self.highest_bid();
+        let released = self
+            .bids
+            .iter()
+            .filter(|bid| Some(**bid) != winner)
+            .copied()
+            .collect();
+        self.bids = winner.into_iter().collect();
+        self.status = LotStatus::Closed { winner };
+
+        Ok(CloseOutcome { winner, released })
+    }
+
+    fn info(&self, id: LotId, now: Timestamp) -> LotInfo {
+        let is_open = self.status == LotStatus::Open;
+        let sealed = is_open && matches!(self.format, AuctionFormat::SealedBid { .. });
+        LotInfo {
+            id,
+            seller: self.seller,
+            format: self.format.clone(),
+            status: self.status,
+            started_at: self.started_at,
+            ends_at: self.ends_at,
+            min_bid: is_open.then(|| self.min_bid(now)),
+            highest_bid: if sealed { None } else { self.highest_bid() },
+            bids_count: self.bids.len() as u64,
+        }
+    }
+}
+
+/// Public information about a lot. The bids of an open sealed-bid auction are not disclosed.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct LotInfo {
+    pub id: LotId,
+    pub seller: Principal,
+    pub format: AuctionFormat,
+    pub status: LotStatus,
+    pub started_at: Timestamp,
+    pub ends_at: Option<Timestamp>,
+    /// The min amount of the next bid, the current price for the Dutch auction.
+    pub min_bid: Option<u128>,
+    pub highest_bid: Option<Bid>,
+    pub bids_count: u64,
+}
+
+/// The lots put up for auction, in stable memory.
+///
+/// ```
+/// use ic_auction::formats::{AuctionFormat, Lots};
+/// use ic_exports::candid::Principal;
+/// use ic_stable_structures::VectorMemory;
+///
+/// let mut lots = Lots::new(VectorMemory::default());
+/// let (seller, alice, bob) = (
+///     Principal::from_slice(&[1]),
+///     Principal::from_slice(&[2]),
+///     Principal::from_slice(&[3]),
+/// );
+/// let format = AuctionFormat::English {
+///     reserve_price: 100,
+///     min_increment: 10,
+///     duration_nanos: 1_000,
+///     extension_window_nanos: 100,
+///     extension_nanos: 200,
+/// };
+///
+/// let lot = lots.create(seller, format, 0).unwrap();
+/// lots.bid(lot, alice, 100, 10).unwrap();
+/// let outcome = lots.bid(lot, bob, 110, 950).unwrap();
+/// assert_eq!(outcome.released[0].bidder, alice);
+/// assert_eq!(lots.info(lot, 950).unwrap().ends_at, Some(1_150));
+///
+/// let outcome = lots.close(lot, 1_150).unwrap();
+/// assert_eq!(outcome.winner.unwrap().bidder, bob);
+/// ```
+pub struct Lots<M: Memory> {
+    lots: StableBTreeMap<LotId, Lot, M>,
+}
+
+impl<M: Memory> Lots<M> {
+    /// Create the lots in the memory. If the memory contains lots, they are kept.
+    pub fn new(memory: M) -> Self {
+        Self {
+            lots: StableBTreeMap::new(memory),
+        }
+    }
+
+    /// Put up a lot for auction in the format, starting at `now`.
+    pub fn create(
+        &mut self,
+        seller: Principal,
+        format: AuctionFormat,
+        now: Timestamp,
+    ) -> Result<LotId> {
+        format.validate()?;
+
+        let id = self.lots.last_key_value().map_or(0, |(id, _)| id + 1);
+        let ends_at = match format {
+            AuctionFormat::SealedBid { duration_nanos, .. }
+            | AuctionFormat::English { duration_nanos, .. } => Some(now + duration_nanos),
+            AuctionFormat::Dutch { .. } => None,
+        };
+        self.lots.insert(
+            id,
+            Lot {
+                seller,
+                format,
+                started_at: now,
+                ends_at,
+                status: LotStatus::Open,
+                bids: vec![],
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Place a bid on the lot. The outcome lists the bids which can no longer win and should be
+    /// refunded.
+    pub fn bid(
+        &mut self,
+        id: LotId,
+        bidder: Principal,
+        amount: u128,
+        now: Timestamp,
+    ) -> Result<BidOutcome> {
+        self.update(id, |lot| {
+            lot.bid(Bid {
+                bidder,
+                amount,
+                timestamp: now,
+            })
+        })
+    }
+
+    /// Close the auction of the lot after its end. A Dutch auction ends when its price reaches
+    /// the floor price.
+    pub fn close(&mut self, id: LotId, now: Timestamp) -> Result<CloseOutcome> {
+        self.update(id, |lot| lot.close(now))
+    }
+
+    /// Cancel the open auction of the lot, all its bids are released.
+    pub fn cancel(&mut self, id: LotId) -> Result<Vec<Bid>> {
+        self.update(id, |lot| {
+            if lot.status != LotStatus::Open {
+                return Err(AuctionError::LotClosed);
+            }
+
+            lot.status = LotStatus::Cancelled;
+            Ok(std::mem::take(&mut lot.bids))
+        })
+    }
+
+    pub fn info(&self, id: LotId, now: Timestamp) -> Result<LotInfo> {
+        self.lots
+            .get(&id)
+            .map(|lot| lot.info(id, now))
+            .ok_or(AuctionError::LotNotFound)
+    }
+
+    fn update<R>(&mut self, id: LotId, f: impl FnOnce(&mut Lot) -> Result<R>) -> Result<R> {
+        let mut lot = self.lots.get(&id).ok_or(AuctionError::LotNotFound)?;
+        let result = f(&mut lot)?;
+        self.lots.insert(id, lot);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    const SELLER: Principal = Principal::from_slice(&[1]);
+    const ALICE: Principal = Principal::from_slice(&[2]);
+    const BOB: Principal = Principal::from_slice(&[3]);
+
+    #[test]
+    fn sealed_bid_auction() {
+        let mut lots = Lots::new(VectorMemory::default());
+        let format = AuctionFormat::SealedBid {
+            reserve_price: 50,
+            duration_nanos: 100,
+        };
+        let lot = lots.create(SELLER, format, 0).unwrap();
+
+        assert_eq!(
+            lots.bid(lot, ALICE, 40, 1),
+            Err(AuctionError::BidTooLow(50))
+        );
+        lots.bid(lot, ALICE, 60, 1).unwrap();
+        lots.bid(lot, BOB, 80, 2).unwrap();
+        let outcome = lots.bid(lot, ALICE, 70, 3).unwrap();
+        assert_eq!(outcome.released[0].amount, 60);
+
+        let info = lots.info(lot, 50).unwrap();
+        assert_eq!(info.highest_bid, None);
+        assert_eq!(info.bids_count, 2);
+
+        assert_eq!(lots.close(lot, 99), Err(AuctionError::LotNotEnded));
+        assert_eq!(lots.bid(lot, ALICE, 90, 100), Err(AuctionError::LotClosed));
+        let outcome = lots.close(lot, 100).unwrap();
+        assert_eq!(
+            outcome.winner.map(|bid| (bid.bidder, bid.amount)),
+            Some((BOB, 80))
+        );
+        assert_eq!(outcome.released.len(), 1);
+        assert_eq!(lots.info(lot, 100).unwrap().highest_bid, outcome.winner);
+    }
+
+    #[test]
+    fn dutch_auction() {
+        let mut lots = Lots::new(VectorMemory::default());
+        let format = AuctionFormat::Dutch {
+            start_price: 100,
+            floor_price: 40,
+            decrement: 25,
+            step_nanos: 10,
+        };
+        let lot = lots.create(SELLER, format.clone(), 0).unwrap();
+
+        assert_eq!(lots.info(lot, 25).unwrap().min_bid, Some(50));
+        assert_eq!(lots.close(lot, 25), Err(AuctionError::LotNotEnded));
+        let outcome = lots.bid(lot, ALICE, 60, 25).unwrap();
+        assert!(outcome.closed);
+        assert_eq!(outcome.bid.amount, 50);
+        assert_eq!(lots.bid(lot, BOB, 100, 26), Err(AuctionError::LotClosed));
+
+        let lot = lots.create(SELLER, format, 100).unwrap();
+        assert_eq!(lot, 1);
+        assert_eq!(lots.info(lot, 130).unwrap().min_bid, Some(40));
+        assert_eq!(lots.close(lot, 130).unwrap().winner, None);
+    }
+
+    #[test]
+    fn english_auction_is_extended() {
+        let mut lots = Lots::new(VectorMemory::default());
+        let format = AuctionFormat::English {
+            reserve_price: 100,
+            min_increment: 10,
+            duration_nanos: 1_000,
+            extension_window_nanos: 100,
+            extension_nanos: 200,
+        };
+        let lot = lots.create(SELLER, format, 0).unwrap();
+
+        assert_eq!(
+            lots.bid(lot, ALICE, 99, 1),
+            Err(AuctionError::BidTooLow(100))
+        );
+        lots.bid(lot, ALICE, 100, 1).unwrap();
+        assert_eq!(
+            lots.bid(lot, BOB, 105, 2),
+            Err(AuctionError::BidTooLow(110))
+        );
+        lots.bid(lot, BOB, 120, 500).unwrap();
+        assert_eq!(lots.info(lot, 500).unwrap().ends_at, Some(1_000));
+
+        let outcome = lots.bid(lot, ALICE, 130, 990).unwrap();
+        assert_eq!(outcome.released[0].bidder, BOB);
+        assert_eq!(lots.info(lot, 990).unwrap().ends_at, Some(1_190));
+
+        assert_eq!(lots.close(lot, 1_100), Err(AuctionError::LotNotEnded));
+        let outcome = lots.close(lot, 1_190).unwrap();
+        assert_eq!(outcome.winner.unwrap().amount, 130);
+        assert!(outcome.released.is_empty());
+
+        let error = lots
+            .create(
+                SELLER,
+                AuctionFormat::English {
+                    reserve_price: 0,
+                    min_increment: 0,
+                    duration_nanos: 10,
+                    extension_window_nanos: 0,
+                    extension_nanos: 0,
+                },
+                0,
+            )
+            .unwrap_err();
+        assert!(matches!(error, AuctionError::InvalidAuctionFormat(_)));
+    }
+}
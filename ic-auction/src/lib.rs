@@ -1,3 +1,5 @@
 pub mod api;
 pub mod error;
+pub mod escrow;
+pub mod formats;
 pub mod state;
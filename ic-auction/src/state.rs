@@ -1,12 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use ic_exports::candid::{CandidType, Deserialize, Principal};
 use ic_exports::ic_kit::ic;
-use ic_helpers::tokens::Tokens128;
+use ic_helpers::tokens::{Tokens128, Tokens256};
 use ic_metrics::Interval;
 use ic_storage::IcStorage;
 
 use crate::error::{AuctionError, Result};
+use crate::escrow::{TokenEscrow, TokenEscrowConfig};
 
 // Minimum bidding amount is required, for every update call costs cycles, and we want bidding
 // to add cycles rather then to decrease them. 1M is chosen as one ingress call costs 590K cycles.
@@ -48,6 +49,12 @@ pub struct BiddingInfo {
 
     /// The amount of cycles the caller bid for the upcoming auction.
     pub caller_cycles: Cycles,
+
+    /// Total tokens held in escrow for the upcoming auction.
+    pub total_tokens: u128,
+
+    /// The amount of tokens the caller bid for the upcoming auction.
+    pub caller_tokens: u128,
 }
 
 //------------------------------------------------------------------------------
@@ -75,6 +82,16 @@ impl BiddingState {
         let next_auction = self.last_auction + self.auction_period;
         (next_auction - curr_time) / 1_000_000
     }
+
+    /// The share of the `reward` due to the `bidder`, in proportion to their bid in the cycles
+    /// bid since the last auction.
+    pub fn reward_share(&self, bidder: &Principal, reward: Tokens128) -> Tokens128 {
+        let bid = self.bids.get(bidder).copied().unwrap_or(0);
+        (Tokens256::from(reward) * bid)
+            .and_then(|share| share / self.cycles_since_auction)
+            .and_then(|share| share.to_tokens128())
+            .unwrap_or_default()
+    }
 }
 
 impl Default for BiddingState {
@@ -99,6 +116,8 @@ pub struct AuctionState {
     pub history: Vec<AuctionInfo>,
     pub controller: Principal,
     min_cycles: Cycles,
+    #[serde(default)]
+    pub token_escrow: TokenEscrow,
 }
 
 impl Default for AuctionState {
@@ -108,6 +127,7 @@ impl Default for AuctionState {
             bidding_state: BiddingState::default(),
             history: Vec::new(),
             min_cycles: MIN_BIDDING_AMOUNT,
+            token_escrow: TokenEscrow::default(),
         }
     }
 }
@@ -122,6 +142,7 @@ impl AuctionState {
             },
             history: Vec::new(),
             min_cycles: MIN_BIDDING_AMOUNT,
+            token_escrow: TokenEscrow::default(),
         }
     }
 
@@ -174,18 +195,42 @@ impl AuctionState {
         Ok(amount_accepted)
     }
 
+    /// Adds the escrowed token bids to the cycle bids, valued with
+    /// [`TokenEscrowConfig::cycles_per_token`], so that the rewards are distributed in proportion
+    /// to both. Returns the bidders whose token bids were counted, the others must be refunded.
+    pub fn count_token_bids(&mut self) -> HashSet<Principal> {
+        let mut counted = HashSet::new();
+        for (bidder, amount) in &self.token_escrow.bids {
+            let cycles = self.token_escrow.cycles_value(*amount);
+            if cycles == 0 {
+                continue;
+            }
+
+            let bidding_state = &mut self.bidding_state;
+            bidding_state.cycles_since_auction =
+                bidding_state.cycles_since_auction.saturating_add(cycles);
+            let bid = bidding_state.bids.entry(*bidder).or_insert(0);
+            *bid = bid.saturating_add(cycles);
+            counted.insert(*bidder);
+        }
+
+        counted
+    }
+
+    pub fn has_bids(&self) -> bool {
+        !self.bidding_state.bids.is_empty() || !self.token_escrow.bids.is_empty()
+    }
+
     pub fn bidding_info(&self) -> BiddingInfo {
+        let caller = ic::caller();
         BiddingInfo {
             fee_ratio: self.bidding_state.fee_ratio,
             last_auction: self.bidding_state.last_auction,
             auction_period: self.bidding_state.auction_period,
             total_cycles: self.bidding_state.cycles_since_auction,
-            caller_cycles: self
-                .bidding_state
-                .bids
-                .get(&ic::caller())
-                .cloned()
-                .unwrap_or(0),
+            caller_cycles: self.bidding_state.bids.get(&caller).cloned().unwrap_or(0),
+            total_tokens: self.token_escrow.total(),
+            caller_tokens: self.token_escrow.bids.get(&caller).cloned().unwrap_or(0),
         }
     }
 
@@ -223,4 +268,102 @@ impl<'a> Authorized<Controller<'a>> {
     pub fn set_controller(&mut self, controller: Principal) {
         self.auth.state.controller = controller;
     }
+
+    pub fn set_token_escrow_config(&mut self, config: Option<TokenEscrowConfig>) -> Result<()> {
+        self.auth.state.token_escrow.set_config(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+    use ic_exports::icrc_types::icrc1::account::Account;
+
+    use super::*;
+    use crate::escrow::TransferKind;
+
+    #[test]
+    fn token_bids_share_the_rewards() {
+        MockContext::new().inject();
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+
+        let mut state = AuctionState::default();
+        state
+            .token_escrow
+            .set_config(Some(TokenEscrowConfig {
+                token: Principal::management_canister(),
+                fee: 10,
+                settlement_account: Account::from(Principal::anonymous()),
+                min_bid: 100,
+                cycles_per_token: 1_000,
+            }))
+            .unwrap();
+
+        state.bidding_state.bids.insert(alice, 3_000_000);
+        state.bidding_state.cycles_since_auction = 3_000_000;
+        state.token_escrow.reserve_bid(1_000).unwrap();
+        state.token_escrow.complete_bid(bob, 1_000);
+
+        let counted = state.count_token_bids();
+        assert_eq!(counted, HashSet::from([bob]));
+        assert_eq!(state.bidding_state.cycles_since_auction, 4_000_000);
+
+        let reward = Tokens128::from(4_000);
+        assert_eq!(
+            state.bidding_state.reward_share(&bob, reward),
+            Tokens128::from(1_000)
+        );
+        assert_eq!(
+            state.bidding_state.reward_share(&alice, reward),
+            Tokens128::from(3_000)
+        );
+        assert_eq!(
+            state.bidding_state.reward_share(&carol, reward),
+            Tokens128::from(0)
+        );
+
+        let transfers = state
+            .token_escrow
+            .settle(42, |bidder| counted.contains(bidder));
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].kind, TransferKind::Settlement);
+        assert_eq!(transfers[0].amount, 990);
+    }
+
+    #[test]
+    fn token_bids_worth_no_cycles_are_refunded() {
+        MockContext::new().inject();
+        let bob = Principal::from_slice(&[2; 29]);
+
+        let mut state = AuctionState::default();
+        state
+            .token_escrow
+            .set_config(Some(TokenEscrowConfig {
+                token: Principal::management_canister(),
+                fee: 10,
+                settlement_account: Account::from(Principal::anonymous()),
+                min_bid: 100,
+                cycles_per_token: 0,
+            }))
+            .unwrap();
+        state.token_escrow.add_bid(bob, 1_000);
+
+        let counted = state.count_token_bids();
+        assert!(counted.is_empty());
+        assert_eq!(
+            state
+                .bidding_state
+                .reward_share(&bob, Tokens128::from(4_000)),
+            Tokens128::from(0)
+        );
+
+        let transfers = state
+            .token_escrow
+            .settle(42, |bidder| counted.contains(bidder));
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].kind, TransferKind::Refund);
+        assert_eq!(transfers[0].to.owner, bob);
+    }
 }
@@ -25,6 +25,35 @@ impl TryFrom<&Path> for GenericIdentity {
     }
 }
 
+/// Derivation path of the key used by dfx for identities imported from a seed phrase.
+const SEED_PHRASE_DERIVATION_PATH: &str = "m/44'/223'/0'/0/0";
+
+impl GenericIdentity {
+    /// Reads the identity from the PEM file content.
+    pub fn from_pem(pem: &[u8]) -> std::result::Result<Self, AgentError> {
+        Secp256k1Identity::from_pem(pem)
+            .map(GenericIdentity::from)
+            .or(BasicIdentity::from_pem(pem).map(GenericIdentity::from))
+            .map_err(AgentError::InvalidPem)
+    }
+
+    /// Derives the secp256k1 identity from the BIP39 seed phrase the same way
+    /// as dfx does, so the principal matches the one of `dfx identity import --seed-file`.
+    pub fn from_seed_phrase(phrase: &str) -> std::result::Result<Self, AgentError> {
+        let mnemonic = bip32::Mnemonic::new(phrase.trim(), bip32::Language::English)
+            .map_err(|e| AgentError::SeedPhraseError(e.to_string()))?;
+        let path = SEED_PHRASE_DERIVATION_PATH
+            .parse::<bip32::DerivationPath>()
+            .map_err(|e| AgentError::SeedPhraseError(e.to_string()))?;
+        let key = bip32::XPrv::derive_from_path(mnemonic.to_seed("").as_bytes(), &path)
+            .map_err(|e| AgentError::SeedPhraseError(e.to_string()))?;
+        let secret_key = k256::SecretKey::from_slice(&key.to_bytes())
+            .map_err(|e| AgentError::SeedPhraseError(e.to_string()))?;
+
+        Ok(Secp256k1Identity::from_private_key(secret_key).into())
+    }
+}
+
 impl Identity for GenericIdentity {
     fn sender(&self) -> std::result::Result<Principal, String> {
         match self {
@@ -60,15 +89,63 @@ impl From<BasicIdentity> for GenericIdentity {
     }
 }
 
+/// Options of the IC Agent initialization.
+#[derive(Debug, Clone)]
+pub struct AgentOptions {
+    /// Timeout of the requests to the network. 120 seconds if not set.
+    pub timeout: Option<Duration>,
+    /// Whether to verify the replica signatures of query responses.
+    /// Disabling it makes queries faster, but the responses can not be
+    /// distinguished from the ones forged by a boundary node.
+    pub verify_query_signatures: bool,
+}
+
+impl Default for AgentOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            verify_query_signatures: true,
+        }
+    }
+}
+
 /// Initialize an IC Agent
 pub async fn init_agent(
     identity_path: impl AsRef<Path>,
     url: &str,
     timeout: Option<Duration>,
+) -> super::Result<Agent> {
+    let options = AgentOptions {
+        timeout,
+        ..Default::default()
+    };
+    init_agent_with_options(identity_path, url, options).await
+}
+
+/// Initialize an IC Agent with the given options.
+///
+/// The responses are verified against the IC root key. The root key is
+/// fetched from the network only for local replicas, for the mainnet the
+/// key built into the agent is used, so the boundary nodes are not trusted.
+pub async fn init_agent_with_options(
+    identity_path: impl AsRef<Path>,
+    url: &str,
+    options: AgentOptions,
 ) -> super::Result<Agent> {
     let identity = GenericIdentity::try_from(identity_path.as_ref())?;
+    init_agent_with_identity(identity, url, options).await
+}
 
-    let timeout = timeout.unwrap_or(Duration::from_secs(120));
+/// Initialize an IC Agent signing the requests with the given identity.
+///
+/// Any [`Identity`] implementation can be used, e.g. a hardware key signer.
+/// See [`init_agent_with_options`] for the responses verification.
+pub async fn init_agent_with_identity(
+    identity: impl Identity + 'static,
+    url: &str,
+    options: AgentOptions,
+) -> super::Result<Agent> {
+    let timeout = options.timeout.unwrap_or(Duration::from_secs(120));
 
     let client = ic_agent::agent::http_transport::reqwest_transport::reqwest::ClientBuilder::new()
         .timeout(timeout)
@@ -86,13 +163,28 @@ pub async fn init_agent(
         .with_transport(transport)
         .with_identity(identity)
         .with_ingress_expiry(Some(timeout))
+        .with_verify_query_signatures(options.verify_query_signatures)
         .build()?;
 
-    agent.fetch_root_key().await?;
+    if !is_mainnet_url(url) {
+        agent.fetch_root_key().await?;
+    }
 
     Ok(agent)
 }
 
+const MAINNET_DOMAINS: [&str; 3] = ["ic0.app", "icp0.io", "icp-api.io"];
+
+/// Returns whether the url points to the IC mainnet.
+fn is_mainnet_url(url: &str) -> bool {
+    let host = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let host = host.split(['/', ':']).next().unwrap_or_default();
+
+    MAINNET_DOMAINS
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+}
+
 #[cfg(test)]
 mod test {
 
@@ -100,6 +192,19 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn should_detect_mainnet_url() {
+        assert!(is_mainnet_url("https://ic0.app"));
+        assert!(is_mainnet_url("https://icp-api.io/"));
+        assert!(is_mainnet_url(
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.icp0.io:443/api"
+        ));
+
+        assert!(!is_mainnet_url("http://127.0.0.1:8000"));
+        assert!(!is_mainnet_url("http://localhost:4943"));
+        assert!(!is_mainnet_url("https://notic0.app"));
+    }
+
     #[test]
     fn should_get_identity_from_pem_file() {
         let path = Path::new("./tests/identity/identity.pem");
@@ -111,6 +216,34 @@ mod test {
         ));
     }
 
+    #[test]
+    fn should_get_identity_from_pem_content() {
+        let pem = std::fs::read("./tests/identity/identity.pem").unwrap();
+        let identity = GenericIdentity::from_pem(&pem).unwrap();
+        let from_file =
+            GenericIdentity::try_from(Path::new("./tests/identity/identity.pem")).unwrap();
+
+        assert_eq!(identity.sender(), from_file.sender());
+        assert!(GenericIdentity::from_pem(b"not a pem").is_err());
+    }
+
+    #[test]
+    fn should_derive_identity_from_seed_phrase() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let identity = GenericIdentity::from_seed_phrase(phrase).unwrap();
+        let same_identity = GenericIdentity::from_seed_phrase(&format!(" {phrase}\n")).unwrap();
+
+        // The principal of `dfx identity import --seed-file` for the same phrase.
+        let expected =
+            Principal::from_text("tgzar-4lpln-fq34h-6hxo4-wlm3x-6g3or-6hxvr-d6jbw-ooh2b-lzsw4-aqe")
+                .unwrap();
+
+        assert!(matches!(identity, GenericIdentity::Secp256k1Identity(_)));
+        assert_eq!(identity.sender(), Ok(expected));
+        assert_eq!(same_identity.sender(), Ok(expected));
+        assert!(GenericIdentity::from_seed_phrase("not a seed phrase").is_err());
+    }
+
     #[test]
     fn should_get_sender_from_identity() {
         let path = Path::new("./tests/identity/identity.pem");
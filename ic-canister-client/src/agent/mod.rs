@@ -6,6 +6,7 @@ use std::time::Duration;
 use candid::utils::ArgumentEncoder;
 use candid::{encode_args, CandidType, Decode, Principal};
 use ic_agent::identity::PemError;
+use ic_agent::Identity;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use thiserror::Error;
@@ -23,6 +24,12 @@ pub enum AgentError {
 
     #[error("failed to read PEM file {0}: {1}")]
     PemError(PathBuf, PemError),
+
+    #[error("invalid PEM content: {0}")]
+    InvalidPem(PemError),
+
+    #[error("invalid seed phrase: {0}")]
+    SeedPhraseError(String),
 }
 
 pub type Result<T> = std::result::Result<T, AgentError>;
@@ -48,6 +55,34 @@ impl IcAgentClient {
         })
     }
 
+    /// Initialize an IC Agent with a PEM file and the given options
+    pub async fn with_identity_and_options(
+        canister: Principal,
+        identity_path: impl AsRef<Path>,
+        network: &str,
+        options: identity::AgentOptions,
+    ) -> Result<Self> {
+        let agent = identity::init_agent_with_options(identity_path, network, options).await?;
+        Ok(Self {
+            canister_id: canister,
+            agent,
+        })
+    }
+
+    /// Initialize an IC Agent signing the requests with the given identity
+    pub async fn with_signer(
+        canister: Principal,
+        identity: impl Identity + 'static,
+        network: &str,
+        options: identity::AgentOptions,
+    ) -> Result<Self> {
+        let agent = identity::init_agent_with_identity(identity, network, options).await?;
+        Ok(Self {
+            canister_id: canister,
+            agent,
+        })
+    }
+
     /// Initialize an IC Agent with an existing agent
     pub fn with_agent(canister: Principal, agent: ic_agent::Agent) -> Self {
         Self {
@@ -55,6 +90,24 @@ impl IcAgentClient {
             agent,
         }
     }
+
+    /// Returns a client for the same canister and network, which signs the
+    /// requests with the given identity. The original client is not changed.
+    pub fn with_caller_identity(&self, identity: impl Identity + 'static) -> Self {
+        let mut agent = self.agent.clone();
+        agent.set_identity(identity);
+        Self {
+            canister_id: self.canister_id,
+            agent,
+        }
+    }
+
+    /// Returns the principal of the identity signing the requests
+    pub fn caller(&self) -> Result<Principal> {
+        self.agent
+            .get_principal()
+            .map_err(AgentError::ConfigurationError)
+    }
 }
 
 #[async_trait::async_trait]
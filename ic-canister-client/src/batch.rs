@@ -0,0 +1,107 @@
+use std::future::Future;
+
+use futures::stream::{self, StreamExt};
+
+/// Runs the futures concurrently, keeping at most `max_parallel` of them in
+/// progress at a time. `max_parallel` of zero is treated as one.
+///
+/// The outputs are returned in the same order as the futures.
+pub async fn join_all_bounded<I>(
+    futures: I,
+    max_parallel: usize,
+) -> Vec<<I::Item as Future>::Output>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    stream::iter(futures)
+        .buffered(max_parallel.max(1))
+        .collect()
+        .await
+}
+
+/// Runs the futures concurrently, keeping at most `max_parallel` of them in
+/// progress at a time, and collects their results.
+///
+/// Returns the first error in order of the futures. The futures not yet
+/// started when an error is encountered are not polled.
+pub async fn try_join_all_bounded<I, R, E>(futures: I, max_parallel: usize) -> Result<Vec<R>, E>
+where
+    I: IntoIterator,
+    I::Item: Future<Output = Result<R, E>>,
+{
+    let mut results = stream::iter(futures).buffered(max_parallel.max(1));
+    let mut values = vec![];
+    while let Some(result) = results.next().await {
+        values.push(result?);
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct Concurrency {
+        active: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    impl Concurrency {
+        fn new() -> Self {
+            Self {
+                active: AtomicUsize::new(0),
+                max: AtomicUsize::new(0),
+            }
+        }
+
+        async fn track<R>(&self, value: R) -> R {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(active, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            value
+        }
+    }
+
+    #[tokio::test]
+    async fn should_keep_order_and_bound_parallelism() {
+        let concurrency = Concurrency::new();
+        let results = join_all_bounded((0..20).map(|i| concurrency.track(i)), 4).await;
+
+        assert_eq!(results, (0..20).collect::<Vec<_>>());
+        assert_eq!(concurrency.max.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn should_treat_zero_parallelism_as_one() {
+        let concurrency = Concurrency::new();
+        let results = join_all_bounded((0..5).map(|i| concurrency.track(i)), 0).await;
+
+        assert_eq!(results.len(), 5);
+        assert_eq!(concurrency.max.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn should_return_first_error() {
+        let results = try_join_all_bounded(
+            (0..10).map(|i| async move {
+                if i % 4 == 3 {
+                    Err(i)
+                } else {
+                    Ok(i)
+                }
+            }),
+            3,
+        )
+        .await;
+        assert_eq!(results, Err(3));
+
+        let results: Result<Vec<_>, ()> =
+            try_join_all_bounded((0..10).map(|i| async move { Ok(i) }), 3).await;
+        assert_eq!(results, Ok((0..10).collect()));
+    }
+}
@@ -1,7 +1,13 @@
+use std::fmt::Debug;
+
 use candid::utils::ArgumentEncoder;
 use candid::CandidType;
+use ic_canister::with_call_context;
 use serde::de::DeserializeOwned;
 
+use crate::batch::join_all_bounded;
+use crate::reject::{FromReject, TypedCallError};
+use crate::retry::{call_with_policy, CallPolicy};
 use crate::CanisterClientResult;
 
 /// Generic client for interacting with a canister.
@@ -40,4 +46,139 @@ pub trait CanisterClient: Send + Clone {
     where
         T: ArgumentEncoder + Send + Sync,
         R: DeserializeOwned + CandidType;
+
+    /// Cycles attached to every update call made by the client.
+    fn update_cycles(&self) -> u64 {
+        0
+    }
 }
+
+/// Extension of the [`CanisterClient`] with higher level calls.
+/// It is implemented for every [`CanisterClient`].
+#[async_trait::async_trait]
+pub trait CanisterClientExt: CanisterClient + Sync {
+    /// Call an update method on the canister according to the `policy`.
+    ///
+    /// The call failed with a timeout or a transport error may have been executed, so it's
+    /// retried only if the policy is idempotent.
+    ///
+    /// The arguments must be `Clone` to be sent again on every retry attempt.
+    async fn update_with_policy<T, R>(
+        &self,
+        method: &str,
+        args: T,
+        policy: &CallPolicy,
+    ) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Clone + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+    {
+        call_with_policy(policy, || self.update(method, args.clone())).await
+    }
+
+    /// Call a query method on the canister according to the `policy`.
+    ///
+    /// The queries don't change the state, so they are retried on timeouts and transport
+    /// errors too.
+    ///
+    /// The arguments must be `Clone` to be sent again on every retry attempt.
+    async fn query_with_policy<T, R>(
+        &self,
+        method: &str,
+        args: T,
+        policy: &CallPolicy,
+    ) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Clone + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+    {
+        let policy = policy.clone().with_idempotent(true);
+        call_with_policy(&policy, || self.query(method, args.clone())).await
+    }
+
+    /// Call an update method on the canister, passing the [`ic_canister::CallContext`] of the
+    /// current message as the last argument.
+    async fn update_in_context<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+    {
+        self.update(method, with_call_context(args)).await
+    }
+
+    /// Call a query method on the canister, passing the [`ic_canister::CallContext`] of the
+    /// current message as the last argument.
+    async fn query_in_context<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+    {
+        self.query(method, with_call_context(args)).await
+    }
+
+    /// Call an update method returning `Result<R, E>` on the canister.
+    ///
+    /// Both the `Err` variant returned by the method and the call rejects
+    /// recognized by [`FromReject`] are returned as [`TypedCallError::Canister`].
+    async fn update_typed<T, R, E>(&self, method: &str, args: T) -> Result<R, TypedCallError<E>>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+        E: FromReject + DeserializeOwned + CandidType + Debug + Send,
+    {
+        let result: Result<R, E> = self.update(method, args).await?;
+        result.map_err(TypedCallError::Canister)
+    }
+
+    /// Call a query method returning `Result<R, E>` on the canister.
+    ///
+    /// Both the `Err` variant returned by the method and the call rejects
+    /// recognized by [`FromReject`] are returned as [`TypedCallError::Canister`].
+    async fn query_typed<T, R, E>(&self, method: &str, args: T) -> Result<R, TypedCallError<E>>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+        E: FromReject + DeserializeOwned + CandidType + Debug + Send,
+    {
+        let result: Result<R, E> = self.query(method, args).await?;
+        result.map_err(TypedCallError::Canister)
+    }
+
+    /// Call an update method on the canister once for every element of `args`,
+    /// running at most `max_parallel` calls concurrently.
+    ///
+    /// The results are returned in the order of the arguments.
+    async fn update_batch<T, R>(
+        &self,
+        method: &str,
+        args: Vec<T>,
+        max_parallel: usize,
+    ) -> Vec<CanisterClientResult<R>>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+    {
+        let calls = args.into_iter().map(|args| self.update(method, args));
+        join_all_bounded(calls, max_parallel).await
+    }
+
+    /// Call a query method on the canister once for every element of `args`,
+    /// running at most `max_parallel` calls concurrently.
+    ///
+    /// The results are returned in the order of the arguments.
+    async fn query_batch<T, R>(
+        &self,
+        method: &str,
+        args: Vec<T>,
+        max_parallel: usize,
+    ) -> Vec<CanisterClientResult<R>>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType + Send,
+    {
+        let calls = args.into_iter().map(|args| self.query(method, args));
+        join_all_bounded(calls, max_parallel).await
+    }
+}
+
+impl<C: CanisterClient + Sync> CanisterClientExt for C {}
@@ -1,4 +1,7 @@
+use std::time::Duration;
+
 use ic_exports::ic_cdk::api::call::RejectionCode;
+use ic_helpers::SdkError;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,6 +12,12 @@ pub enum CanisterClientError {
     #[error(transparent)]
     CandidError(#[from] candid::Error),
 
+    #[error("canister call timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("unsupported call policy: {0}")]
+    UnsupportedCallPolicy(String),
+
     #[cfg(feature = "ic-agent-client")]
     #[error("ic agent error: {0}")]
     IcAgentError(#[from] ic_agent::agent::AgentError),
@@ -43,6 +52,15 @@ impl From<ic_exports::ic_test_state_machine::UserError> for CanisterClientError
     }
 }
 
+impl From<CanisterClientError> for SdkError {
+    fn from(error: CanisterClientError) -> Self {
+        match error {
+            CanisterClientError::CanisterError(reject) => reject.into(),
+            error => Self::CallFailed(error.to_string()),
+        }
+    }
+}
+
 pub type CanisterClientResult<T> = Result<T, CanisterClientError>;
 
 /// This tuple is returned incase of IC errors such as Network, canister error.
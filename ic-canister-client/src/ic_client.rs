@@ -11,21 +11,32 @@ use crate::{CanisterClientError, CanisterClientResult};
 pub struct IcCanisterClient {
     /// The canister id of the Evm canister
     pub canister_id: Principal,
+    /// Cycles attached to every update call
+    cycles: u64,
 }
 
 impl IcCanisterClient {
     pub fn new(canister: Principal) -> Self {
         Self {
             canister_id: canister,
+            cycles: 0,
         }
     }
 
-    async fn call<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    /// Attach the given amount of cycles to every update call.
+    pub fn with_cycles(mut self, cycles: u64) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    // Cycles are not used by the virtual responders outside of wasm environment.
+    #[cfg_attr(not(target_family = "wasm"), allow(unused_variables))]
+    async fn call<T, R>(&self, method: &str, args: T, cycles: u64) -> CanisterClientResult<R>
     where
         T: ArgumentEncoder + Send,
         R: DeserializeOwned + CandidType,
     {
-        virtual_canister_call!(self.canister_id, method, args, R)
+        virtual_canister_call!(self.canister_id, method, args, R, cycles)
             .await
             .map_err(CanisterClientError::CanisterError)
     }
@@ -38,7 +49,7 @@ impl CanisterClient for IcCanisterClient {
         T: ArgumentEncoder + Send + Sync,
         R: DeserializeOwned + CandidType,
     {
-        self.call(method, args).await
+        self.call(method, args, self.cycles).await
     }
 
     async fn query<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
@@ -46,6 +57,10 @@ impl CanisterClient for IcCanisterClient {
         T: ArgumentEncoder + Send + Sync,
         R: DeserializeOwned + CandidType,
     {
-        self.call(method, args).await
+        self.call(method, args, 0).await
+    }
+
+    fn update_cycles(&self) -> u64 {
+        self.cycles
     }
 }
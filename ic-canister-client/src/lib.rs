@@ -1,9 +1,15 @@
 #[cfg(feature = "ic-agent-client")]
 pub mod agent;
 
+pub mod batch;
 pub mod client;
 pub mod error;
 pub mod ic_client;
+pub mod pagination;
+pub mod reject;
+pub mod retry;
+pub mod router;
+pub mod stats;
 
 #[cfg(feature = "state-machine-tests-client")]
 pub mod state_machine_tests;
@@ -11,14 +17,23 @@ pub mod state_machine_tests;
 #[cfg(feature = "pocket-ic-client")]
 pub mod pocket_ic;
 
+#[cfg(feature = "ic-agent-client")]
+pub use agent::identity::AgentOptions;
 #[cfg(feature = "ic-agent-client")]
 pub use agent::{AgentError, IcAgentClient};
-pub use client::CanisterClient;
+pub use batch::{join_all_bounded, try_join_all_bounded};
+pub use client::{CanisterClient, CanisterClientExt};
 pub use error::{CanisterClientError, CanisterClientResult, IcError, IcResult};
 #[cfg(feature = "ic-agent-client")]
 pub use ic_agent;
+pub use ic_canister::canister_client;
 pub use ic_client::IcCanisterClient;
+pub use pagination::{paginated_query, Page, Pagination};
 #[cfg(feature = "pocket-ic-client")]
 pub use pocket_ic::PocketIcClient;
+pub use reject::{FromReject, TypedCallError};
+pub use retry::{BackoffPolicy, CallPolicy, RetryPolicy, RetryStrategy};
+pub use router::RouterClient;
 #[cfg(feature = "state-machine-tests-client")]
 pub use state_machine_tests::StateMachineCanisterClient;
+pub use stats::{CallStats, StatsClient};
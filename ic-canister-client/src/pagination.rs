@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+use candid::utils::ArgumentEncoder;
+use candid::CandidType;
+use futures::stream::{self, Stream};
+pub use ic_helpers::types::{Page, Pagination};
+use serde::de::DeserializeOwned;
+
+use crate::{CanisterClient, CanisterClientResult};
+
+struct PaginationState<F, T> {
+    make_args: F,
+    pagination: Pagination,
+    buffer: VecDeque<T>,
+    is_finished: bool,
+}
+
+/// Returns a stream over all the items of a paginated query method.
+///
+/// The method must return a [`Page`] of items. The arguments of every call are
+/// built by the `make_args` closure from the [`Pagination`] of the requested
+/// page. The pages are fetched lazily, one at a time, while the stream is polled.
+///
+/// The stream ends after the first error.
+///
+/// ```ignore
+/// let logs = paginated_query(&client, "get_logs", 100, |pagination| {
+///     (pagination, Some("warn".to_string()), None::<String>)
+/// });
+/// let logs: Vec<CanisterClientResult<StableLogRecord>> = logs.collect().await;
+/// ```
+pub fn paginated_query<'a, C, F, A, T>(
+    client: &'a C,
+    method: &'a str,
+    page_size: u64,
+    make_args: F,
+) -> impl Stream<Item = CanisterClientResult<T>> + 'a
+where
+    C: CanisterClient,
+    F: FnMut(Pagination) -> A + 'a,
+    A: ArgumentEncoder + Send + Sync + 'a,
+    T: DeserializeOwned + CandidType + 'a,
+{
+    let state = PaginationState {
+        make_args,
+        pagination: Pagination::new(0, page_size.max(1)),
+        buffer: VecDeque::new(),
+        is_finished: false,
+    };
+
+    stream::unfold(state, move |mut state| async move {
+        loop {
+            if let Some(item) = state.buffer.pop_front() {
+                return Some((Ok(item), state));
+            }
+
+            if state.is_finished {
+                return None;
+            }
+
+            let args = (state.make_args)(state.pagination);
+            let page: Page<T> = match client.query(method, args).await {
+                Ok(page) => page,
+                Err(err) => {
+                    state.is_finished = true;
+                    return Some((Err(err), state));
+                }
+            };
+
+            let received = page.items.len() as u64;
+            state.pagination = Pagination::new(
+                state.pagination.offset.saturating_add(received),
+                state.pagination.count,
+            );
+            state.is_finished = received == 0 || state.pagination.offset >= page.total;
+            state.buffer.extend(page.items);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use candid::{Decode, Encode};
+    use futures::StreamExt;
+    use ic_exports::ic_kit::RejectionCode;
+
+    use super::*;
+    use crate::CanisterClientError;
+
+    #[derive(Clone)]
+    struct PagedClient {
+        items: Vec<u32>,
+        calls: Arc<AtomicUsize>,
+        fail_at_offset: Option<u64>,
+    }
+
+    impl PagedClient {
+        fn new(items: u32) -> Self {
+            Self {
+                items: (0..items).collect(),
+                calls: Arc::default(),
+                fail_at_offset: None,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl CanisterClient for PagedClient {
+        async fn update<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+        where
+            T: ArgumentEncoder + Send + Sync,
+            R: DeserializeOwned + CandidType,
+        {
+            self.query(method, args).await
+        }
+
+        async fn query<T, R>(&self, _method: &str, args: T) -> CanisterClientResult<R>
+        where
+            T: ArgumentEncoder + Send + Sync,
+            R: DeserializeOwned + CandidType,
+        {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            let args = candid::encode_args(args)?;
+            let pagination = Decode!(&args, Pagination)?;
+            if self.fail_at_offset == Some(pagination.offset) {
+                return Err(CanisterClientError::CanisterError((
+                    RejectionCode::CanisterError,
+                    "failed".to_string(),
+                )));
+            }
+
+            let page = Page::paginate(self.items.iter().copied(), pagination);
+            Ok(Decode!(&Encode!(&page)?, R)?)
+        }
+    }
+
+    #[tokio::test]
+    async fn should_iterate_over_all_pages() {
+        let client = PagedClient::new(25);
+        let items: Vec<u32> = paginated_query(&client, "items", 10, |p| (p,))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(items, (0..25).collect::<Vec<_>>());
+        assert_eq!(client.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn should_not_request_pages_after_total() {
+        let client = PagedClient::new(20);
+        let items: Vec<_> = paginated_query::<_, _, _, u32>(&client, "items", 10, |p| (p,))
+            .collect()
+            .await;
+
+        assert_eq!(items.len(), 20);
+        assert_eq!(client.calls.load(Ordering::SeqCst), 2);
+
+        let client = PagedClient::new(0);
+        let items: Vec<_> = paginated_query::<_, _, _, u32>(&client, "items", 10, |p| (p,))
+            .collect()
+            .await;
+        assert!(items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_stop_after_error() {
+        let client = PagedClient {
+            fail_at_offset: Some(10),
+            ..PagedClient::new(25)
+        };
+        let items: Vec<_> = paginated_query::<_, _, _, u32>(&client, "items", 10, |p| (p,))
+            .collect()
+            .await;
+
+        assert_eq!(items.len(), 11);
+        assert!(items[..10].iter().all(Result::is_ok));
+        assert!(items[10].is_err());
+    }
+}
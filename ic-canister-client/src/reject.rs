@@ -0,0 +1,132 @@
+use std::fmt::Debug;
+
+use ic_exports::ic_kit::RejectionCode;
+use ic_helpers::SdkError;
+use thiserror::Error;
+
+use crate::CanisterClientError;
+
+/// Error of a canister which can be recovered from a call reject.
+///
+/// Implementing it for the error enum of a canister allows client call sites
+/// to get the typed error back instead of matching reject message strings.
+pub trait FromReject: Sized {
+    /// Tries to build the error from the reject code and message.
+    ///
+    /// If the canister trapped, the `message` is the one passed to the trap,
+    /// see [`trap_message`].
+    fn from_reject(code: RejectionCode, message: &str) -> Option<Self>;
+}
+
+/// The rejects carrying an [`SdkError`] are converted back to the error, the other rejects to
+/// [`SdkError::CallRejected`].
+impl FromReject for SdkError {
+    fn from_reject(code: RejectionCode, message: &str) -> Option<Self> {
+        Some(SdkError::from_reject(code, message))
+    }
+}
+
+/// Error of a typed canister call.
+#[derive(Debug, Error)]
+pub enum TypedCallError<E: Debug> {
+    /// The canister returned or rejected with its own error.
+    #[error("canister returned an error: {0:?}")]
+    Canister(E),
+
+    /// The call failed for any other reason.
+    #[error(transparent)]
+    Client(CanisterClientError),
+}
+
+impl<E: Debug> TypedCallError<E> {
+    /// Returns the canister error, if any.
+    pub fn canister_error(self) -> Option<E> {
+        match self {
+            TypedCallError::Canister(err) => Some(err),
+            TypedCallError::Client(_) => None,
+        }
+    }
+}
+
+impl<E: FromReject + Debug> From<CanisterClientError> for TypedCallError<E> {
+    fn from(err: CanisterClientError) -> Self {
+        if let CanisterClientError::CanisterError((code, message)) = &err {
+            if let Some(typed) = E::from_reject(*code, trap_message(message)) {
+                return TypedCallError::Canister(typed);
+            }
+        }
+
+        TypedCallError::Client(err)
+    }
+}
+
+const TRAP_MESSAGE_PREFIX: &str = "trapped explicitly: ";
+
+/// Extracts the message passed to the trap from a reject message.
+/// Returns the message as is if the canister did not trap explicitly.
+pub fn trap_message(message: &str) -> &str {
+    message
+        .split_once(TRAP_MESSAGE_PREFIX)
+        .map(|(_, trap_message)| trap_message)
+        .unwrap_or(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum TestError {
+        NotFound,
+        Unauthorized,
+    }
+
+    impl FromReject for TestError {
+        fn from_reject(_code: RejectionCode, message: &str) -> Option<Self> {
+            match message {
+                "not found" => Some(TestError::NotFound),
+                "unauthorized" => Some(TestError::Unauthorized),
+                _ => None,
+            }
+        }
+    }
+
+    fn reject(message: &str) -> CanisterClientError {
+        CanisterClientError::CanisterError((RejectionCode::CanisterError, message.to_string()))
+    }
+
+    #[test]
+    fn should_extract_trap_message() {
+        assert_eq!(
+            trap_message("Canister rrkah-fqaaa-aaaaa-aaaaq-cai trapped explicitly: not found"),
+            "not found"
+        );
+        assert_eq!(trap_message("not found"), "not found");
+    }
+
+    #[test]
+    fn should_convert_reject_into_typed_error() {
+        let err: TypedCallError<TestError> =
+            reject("Canister rrkah-fqaaa-aaaaa-aaaaq-cai trapped explicitly: unauthorized").into();
+        assert_eq!(err.canister_error(), Some(TestError::Unauthorized));
+
+        let err: TypedCallError<TestError> = reject("not found").into();
+        assert_eq!(err.canister_error(), Some(TestError::NotFound));
+    }
+
+    #[test]
+    fn should_keep_unknown_rejects() {
+        let err: TypedCallError<TestError> = reject("out of cycles").into();
+        assert!(matches!(
+            err,
+            TypedCallError::Client(CanisterClientError::CanisterError(_))
+        ));
+
+        let err: TypedCallError<TestError> =
+            CanisterClientError::Timeout(std::time::Duration::from_secs(1)).into();
+        assert!(matches!(
+            err,
+            TypedCallError::Client(CanisterClientError::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,392 @@
+use std::future::Future;
+use std::time::Duration;
+
+use ic_exports::ic_kit::RejectionCode;
+pub use ic_helpers::retry::{BackoffPolicy, RetryPolicy, RetryStrategy};
+
+use crate::{CanisterClientError, CanisterClientResult};
+
+/// Policy applied to a single canister call.
+///
+/// Waiting between the attempts and timing out an attempt need the `tokio` feature, enabled by
+/// the clients running outside of wasm environment. A canister has no way to suspend a call
+/// without timers, so without the feature the default policy doesn't retry, and a policy with a
+/// backoff or a timeout is rejected with [`CanisterClientError::UnsupportedCallPolicy`] before
+/// the first attempt. The retries without a backoff, i.e. with [`BackoffPolicy::None`], are
+/// performed right away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallPolicy {
+    /// Retries of a call failed with a transient error and the backoff between them.
+    pub retry_strategy: RetryStrategy,
+    /// Max time to wait for a single call attempt.
+    pub timeout: Option<Duration>,
+    /// Whether the call can be executed more than once with the same effect.
+    ///
+    /// A call failed with a timeout or a transport error may have been
+    /// executed, so it's retried only if it's idempotent. Queries are always
+    /// idempotent.
+    pub idempotent: bool,
+}
+
+/// Three retries, waiting one second before the first one and doubling the wait before every
+/// next one. Without the `tokio` feature there are no retries.
+impl Default for CallPolicy {
+    #[cfg(feature = "tokio")]
+    fn default() -> Self {
+        Self::no_retry()
+            .with_max_retries(3)
+            .with_backoff_policy(BackoffPolicy::Exponential {
+                secs: 1,
+                multiplier: 2,
+            })
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    fn default() -> Self {
+        Self::no_retry()
+    }
+}
+
+impl CallPolicy {
+    /// Policy which performs a single attempt without a timeout.
+    pub fn no_retry() -> Self {
+        Self {
+            retry_strategy: RetryStrategy {
+                retry_policy: RetryPolicy::None,
+                backoff_policy: BackoffPolicy::None,
+            },
+            timeout: None,
+            idempotent: false,
+        }
+    }
+
+    /// Set the retry policy to RetryPolicy::MaxRetries.
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.retry_strategy.retry_policy = RetryPolicy::MaxRetries { retries };
+        self
+    }
+
+    /// Set the backoff policy applied before every retry.
+    pub fn with_backoff_policy(mut self, backoff_policy: BackoffPolicy) -> Self {
+        self.retry_strategy.backoff_policy = backoff_policy;
+        self
+    }
+
+    /// Set both the retry and the backoff policies.
+    pub fn with_retry_strategy(mut self, retry_strategy: RetryStrategy) -> Self {
+        self.retry_strategy = retry_strategy;
+        self
+    }
+
+    /// Set the timeout for a single call attempt.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retry the call also when it's not known whether it was executed.
+    pub fn with_idempotent(mut self, idempotent: bool) -> Self {
+        self.idempotent = idempotent;
+        self
+    }
+
+    /// Returns an error if the policy needs to wait, which is not possible without the `tokio`
+    /// feature.
+    fn check_supported(&self) -> CanisterClientResult<()> {
+        if cfg!(feature = "tokio") {
+            return Ok(());
+        }
+
+        if let Some(timeout) = self.timeout {
+            return Err(CanisterClientError::UnsupportedCallPolicy(format!(
+                "timeout of {timeout:?} requires the tokio feature"
+            )));
+        }
+
+        let retries = self.retry_strategy.retry_policy != RetryPolicy::None;
+        if retries && self.retry_strategy.backoff_policy != BackoffPolicy::None {
+            return Err(CanisterClientError::UnsupportedCallPolicy(format!(
+                "backoff {:?} requires the tokio feature",
+                self.retry_strategy.backoff_policy
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether a call rejected with the given code was not executed and
+/// may succeed if retried.
+pub fn is_transient_reject_code(code: RejectionCode) -> bool {
+    matches!(code, RejectionCode::SysTransient)
+}
+
+impl CanisterClientError {
+    /// Returns whether the error is transient, i.e. the call was not executed
+    /// and may succeed if retried later. All other errors are considered
+    /// permanent.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CanisterClientError::CanisterError((code, _)) => is_transient_reject_code(*code),
+            #[cfg(feature = "ic-agent-client")]
+            CanisterClientError::IcAgentError(err) => is_transient_agent_error(err),
+            _ => false,
+        }
+    }
+
+    /// Returns whether it's not known if the call was executed, e.g. the call
+    /// timed out, so the call may be retried only if it's idempotent.
+    pub fn is_unknown_outcome(&self) -> bool {
+        match self {
+            CanisterClientError::Timeout(_) => true,
+            #[cfg(feature = "ic-agent-client")]
+            CanisterClientError::IcAgentError(err) => is_unknown_outcome_agent_error(err),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "ic-agent-client")]
+fn is_transient_agent_error(err: &ic_agent::AgentError) -> bool {
+    use ic_agent::agent::RejectCode;
+    use ic_agent::AgentError;
+
+    match err {
+        AgentError::ReplicaError(response) => response.reject_code == RejectCode::SysTransient,
+        // The request was refused by the boundary node.
+        AgentError::HttpError(payload) => payload.status == 429 || payload.status == 503,
+        _ => false,
+    }
+}
+
+#[cfg(feature = "ic-agent-client")]
+fn is_unknown_outcome_agent_error(err: &ic_agent::AgentError) -> bool {
+    use ic_agent::AgentError;
+
+    match err {
+        AgentError::HttpError(payload) => payload.status >= 500 && payload.status != 503,
+        AgentError::TimeoutWaitingForResponse() | AgentError::TransportError(_) => true,
+        _ => false,
+    }
+}
+
+/// Performs `call` according to the `policy`: every attempt is limited by the
+/// policy timeout, and attempts failed with a transient error are retried
+/// with the policy backoff. The attempts failed without a known outcome are
+/// retried only if the policy is idempotent.
+///
+/// Fails with [`CanisterClientError::UnsupportedCallPolicy`] without any attempt if the policy
+/// can't be applied, see [`CallPolicy`].
+pub async fn call_with_policy<F, Fut, R>(
+    policy: &CallPolicy,
+    mut call: F,
+) -> CanisterClientResult<R>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = CanisterClientResult<R>>,
+{
+    policy.check_supported()?;
+
+    let mut failed_attempts = 0;
+    loop {
+        let err = match with_timeout(policy.timeout, call()).await {
+            Err(err) if err.is_transient() => err,
+            Err(err) if policy.idempotent && err.is_unknown_outcome() => err,
+            result => return result,
+        };
+
+        failed_attempts += 1;
+        let (retry, backoff_secs) = policy.retry_strategy.should_retry(failed_attempts);
+        if !retry {
+            return Err(err);
+        }
+        drop(err);
+
+        wait(Duration::from_secs(backoff_secs.into())).await;
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn with_timeout<R>(
+    timeout: Option<Duration>,
+    call: impl Future<Output = CanisterClientResult<R>>,
+) -> CanisterClientResult<R> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, call)
+            .await
+            .map_err(|_| CanisterClientError::Timeout(timeout))?,
+        None => call.await,
+    }
+}
+
+/// The timeouts are rejected by [`CallPolicy::check_supported`] without the `tokio` feature.
+#[cfg(not(feature = "tokio"))]
+async fn with_timeout<R>(
+    _timeout: Option<Duration>,
+    call: impl Future<Output = CanisterClientResult<R>>,
+) -> CanisterClientResult<R> {
+    call.await
+}
+
+#[cfg(feature = "tokio")]
+async fn wait(duration: Duration) {
+    if !duration.is_zero() {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+/// The backoffs are rejected by [`CallPolicy::check_supported`] without the `tokio` feature, so
+/// there is nothing to wait for.
+#[cfg(not(feature = "tokio"))]
+async fn wait(_duration: Duration) {}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn policy(retries: u32) -> CallPolicy {
+        CallPolicy::default()
+            .with_max_retries(retries)
+            .with_backoff_policy(BackoffPolicy::None)
+    }
+
+    fn reject(code: RejectionCode) -> CanisterClientError {
+        CanisterClientError::CanisterError((code, "rejected".to_string()))
+    }
+
+    #[test]
+    fn should_classify_reject_codes() {
+        assert!(reject(RejectionCode::SysTransient).is_transient());
+
+        let timeout = CanisterClientError::Timeout(Duration::from_secs(1));
+        assert!(!timeout.is_transient());
+        assert!(timeout.is_unknown_outcome());
+        assert!(!reject(RejectionCode::SysTransient).is_unknown_outcome());
+
+        assert!(!reject(RejectionCode::SysFatal).is_transient());
+        assert!(!reject(RejectionCode::DestinationInvalid).is_transient());
+        assert!(!reject(RejectionCode::CanisterReject).is_transient());
+        assert!(!reject(RejectionCode::CanisterError).is_transient());
+    }
+
+    #[tokio::test]
+    async fn should_retry_transient_errors() {
+        let attempts = Cell::new(0);
+        let result = call_with_policy(&policy(3), || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                if attempt < 3 {
+                    Err(reject(RejectionCode::SysTransient))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn should_stop_after_max_retries() {
+        let attempts = Cell::new(0);
+        let result: CanisterClientResult<()> = call_with_policy(&policy(2), || {
+            attempts.set(attempts.get() + 1);
+            async { Err(reject(RejectionCode::SysTransient)) }
+        })
+        .await;
+
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn should_not_retry_permanent_errors() {
+        let attempts = Cell::new(0);
+        let result: CanisterClientResult<()> = call_with_policy(&policy(5), || {
+            attempts.set(attempts.get() + 1);
+            async { Err(reject(RejectionCode::CanisterReject)) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn should_double_backoff() {
+        let policy = CallPolicy::default();
+        assert_eq!(policy.retry_strategy.should_retry(1), (true, 1));
+        assert_eq!(policy.retry_strategy.should_retry(3), (true, 4));
+        assert!(!policy.retry_strategy.should_retry(4).0);
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    #[tokio::test]
+    async fn should_reject_waiting_policies_without_tokio() {
+        let attempts = Cell::new(0);
+        let call = || {
+            attempts.set(attempts.get() + 1);
+            async { Ok(()) }
+        };
+
+        let backoff = policy(1).with_backoff_policy(BackoffPolicy::Fixed { secs: 1 });
+        let timeout = policy(0).with_timeout(Duration::from_secs(1));
+        for policy in [backoff, timeout] {
+            assert!(matches!(
+                call_with_policy(&policy, call).await,
+                Err(CanisterClientError::UnsupportedCallPolicy(_))
+            ));
+        }
+        assert_eq!(attempts.get(), 0);
+
+        assert_eq!(CallPolicy::default(), CallPolicy::no_retry());
+        assert!(call_with_policy(&policy(3), call).await.is_ok());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_retry_unknown_outcome_only_if_idempotent() {
+        let attempts = Cell::new(0);
+        let call = || {
+            attempts.set(attempts.get() + 1);
+            async { Err::<(), _>(CanisterClientError::Timeout(Duration::from_secs(1))) }
+        };
+
+        assert!(call_with_policy(&policy(2), call).await.is_err());
+        assert_eq!(attempts.get(), 1);
+
+        attempts.set(0);
+        let idempotent = policy(2).with_idempotent(true);
+        assert!(call_with_policy(&idempotent, call).await.is_err());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn should_time_out_slow_calls() {
+        let policy = policy(1)
+            .with_timeout(Duration::from_millis(10))
+            .with_idempotent(true);
+        let attempts = Cell::new(0);
+        let result: CanisterClientResult<()> = call_with_policy(&policy, || {
+            attempts.set(attempts.get() + 1);
+            async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            }
+        })
+        .await;
+
+        assert!(matches!(
+            result.unwrap_err(),
+            CanisterClientError::Timeout(_)
+        ));
+        assert_eq!(attempts.get(), 2);
+    }
+}
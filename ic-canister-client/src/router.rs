@@ -0,0 +1,154 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use candid::utils::ArgumentEncoder;
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+
+use crate::{CanisterClient, CanisterClientResult};
+
+/// A client sending the updates to the primary canister and spreading the queries over its
+/// read replicas, e.g. the canisters receiving the snapshots of the primary with
+/// `ic_helpers::read_replica`.
+///
+/// The replicas lag behind the primary, so the queries which must read the latest state are
+/// sent to the primary with [`RouterClient::with_primary_query`].
+///
+/// The round robin position is shared between the clones of the client.
+#[derive(Debug, Clone)]
+pub struct RouterClient<C> {
+    primary: C,
+    replicas: Vec<C>,
+    primary_queries: BTreeSet<String>,
+    next_replica: Arc<AtomicUsize>,
+}
+
+impl<C: CanisterClient> RouterClient<C> {
+    /// Create the router sending all the calls to the primary until replicas are added.
+    pub fn new(primary: C) -> Self {
+        Self {
+            primary,
+            replicas: vec![],
+            primary_queries: BTreeSet::new(),
+            next_replica: Default::default(),
+        }
+    }
+
+    /// Send the queries to the replica too.
+    pub fn with_replica(mut self, replica: C) -> Self {
+        self.replicas.push(replica);
+        self
+    }
+
+    /// Always send the query method to the primary.
+    pub fn with_primary_query(mut self, method: &str) -> Self {
+        self.primary_queries.insert(method.to_string());
+        self
+    }
+
+    pub fn primary(&self) -> &C {
+        &self.primary
+    }
+
+    pub fn replicas(&self) -> &[C] {
+        &self.replicas
+    }
+
+    /// Returns the client the query method is sent to next.
+    pub fn query_client(&self, method: &str) -> &C {
+        if self.replicas.is_empty() || self.primary_queries.contains(method) {
+            return &self.primary;
+        }
+
+        let index = self.next_replica.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+        &self.replicas[index]
+    }
+
+    /// Call the query method on a replica, and on the primary if the replica fails.
+    ///
+    /// The arguments must be `Clone` to be sent again to the primary.
+    pub async fn query_with_fallback<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Clone + Send + Sync,
+        R: DeserializeOwned + CandidType,
+    {
+        let client = self.query_client(method);
+        let result = client.query(method, args.clone()).await;
+        if result.is_err() && !std::ptr::eq(client, &self.primary) {
+            return self.primary.query(method, args).await;
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: CanisterClient + Sync> CanisterClient for RouterClient<C> {
+    async fn update<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType,
+    {
+        self.primary.update(method, args).await
+    }
+
+    async fn query<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType,
+    {
+        self.query_client(method).query(method, args).await
+    }
+
+    fn update_cycles(&self) -> u64 {
+        self.primary.update_cycles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder};
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+    use crate::IcCanisterClient;
+
+    #[tokio::test]
+    async fn should_route_queries_to_replicas() {
+        MockContext::new().inject();
+        let primary = Principal::from_slice(&[1]);
+        let replicas = [Principal::from_slice(&[2]), Principal::from_slice(&[3])];
+        for (canister, value) in [(primary, 0u32), (replicas[0], 1), (replicas[1], 2)] {
+            register_virtual_responder(canister, "get", move |()| value);
+            register_virtual_responder(canister, "set", move |()| value);
+            register_virtual_responder(canister, "nonce", move |()| value);
+        }
+        register_failing_virtual_responder(replicas[0], "stale", "not synced".to_string());
+        register_virtual_responder(primary, "stale", |()| 0u32);
+
+        let client = RouterClient::new(IcCanisterClient::new(primary))
+            .with_replica(IcCanisterClient::new(replicas[0]))
+            .with_replica(IcCanisterClient::new(replicas[1]))
+            .with_primary_query("nonce");
+
+        let mut answers = vec![];
+        for _ in 0..4 {
+            answers.push(client.query::<_, u32>("get", ()).await.unwrap());
+        }
+        assert_eq!(answers, [1, 2, 1, 2]);
+
+        assert_eq!(client.update::<_, u32>("set", ()).await.unwrap(), 0);
+        assert_eq!(client.query::<_, u32>("nonce", ()).await.unwrap(), 0);
+
+        assert!(client.query::<_, u32>("stale", ()).await.is_err());
+        client.query::<_, u32>("get", ()).await.unwrap();
+        assert_eq!(
+            client
+                .query_with_fallback::<_, u32>("stale", ())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Deserialize};
+use ic_exports::ic_kit::RejectionCode;
+use serde::de::DeserializeOwned;
+
+use crate::{CanisterClient, CanisterClientError, CanisterClientResult};
+
+/// Statistics of the calls made by a [`StatsClient`].
+#[derive(CandidType, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CallStats {
+    /// Number of calls.
+    pub calls: u64,
+    /// Number of failed calls.
+    pub errors: u64,
+    /// Number of calls failed with a transient error.
+    /// Such calls are retried by the call policies, so with a
+    /// [`crate::CallPolicy`] this is the number of retries.
+    pub transient_errors: u64,
+    /// Number of calls rejected with each reject code.
+    pub reject_codes: Vec<(RejectionCode, u64)>,
+    /// Sum of latencies of all calls in nanoseconds.
+    pub total_latency_nanos: u64,
+    /// Max latency of a call in nanoseconds.
+    pub max_latency_nanos: u64,
+    /// Cycles attached to the calls.
+    pub cycles_attached: u128,
+    /// Cycles refunded by the called canister.
+    pub cycles_refunded: u128,
+}
+
+impl CallStats {
+    /// Average latency of a call.
+    pub fn average_latency(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            calls => Duration::from_nanos(self.total_latency_nanos / calls),
+        }
+    }
+
+    /// Adds the stats of other calls to these ones.
+    pub fn merge(&mut self, other: &CallStats) {
+        self.calls += other.calls;
+        self.errors += other.errors;
+        self.transient_errors += other.transient_errors;
+        for (code, count) in &other.reject_codes {
+            self.add_reject_code(*code, *count);
+        }
+        self.total_latency_nanos = self
+            .total_latency_nanos
+            .saturating_add(other.total_latency_nanos);
+        self.max_latency_nanos = self.max_latency_nanos.max(other.max_latency_nanos);
+        self.cycles_attached += other.cycles_attached;
+        self.cycles_refunded += other.cycles_refunded;
+    }
+
+    fn add_reject_code(&mut self, code: RejectionCode, count: u64) {
+        match self.reject_codes.iter_mut().find(|(c, _)| *c == code) {
+            Some((_, total)) => *total += count,
+            None => self.reject_codes.push((code, count)),
+        }
+    }
+
+    fn record(
+        &mut self,
+        error: Option<&CanisterClientError>,
+        latency: Duration,
+        cycles: (u64, u64),
+    ) {
+        self.calls += 1;
+        if let Some(error) = error {
+            self.errors += 1;
+            if error.is_transient() {
+                self.transient_errors += 1;
+            }
+            if let CanisterClientError::CanisterError((code, _)) = error {
+                self.add_reject_code(*code, 1);
+            }
+        }
+
+        let latency = latency.as_nanos().try_into().unwrap_or(u64::MAX);
+        self.total_latency_nanos = self.total_latency_nanos.saturating_add(latency);
+        self.max_latency_nanos = self.max_latency_nanos.max(latency);
+
+        let (attached, refunded) = cycles;
+        self.cycles_attached += attached as u128;
+        self.cycles_refunded += refunded as u128;
+    }
+}
+
+/// A client which records the statistics of the calls made through the wrapped client.
+///
+/// The statistics are shared between the clones of the client.
+#[derive(Debug, Clone)]
+pub struct StatsClient<C> {
+    client: C,
+    stats: Arc<Mutex<BTreeMap<String, CallStats>>>,
+}
+
+impl<C: CanisterClient> StatsClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            stats: Default::default(),
+        }
+    }
+
+    /// Returns the wrapped client.
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    /// Returns the statistics of the calls of each method.
+    pub fn stats(&self) -> BTreeMap<String, CallStats> {
+        self.lock_stats().clone()
+    }
+
+    /// Returns the statistics of the calls of the method.
+    pub fn method_stats(&self, method: &str) -> CallStats {
+        self.lock_stats().get(method).cloned().unwrap_or_default()
+    }
+
+    /// Returns the statistics of all the calls.
+    pub fn summary(&self) -> CallStats {
+        self.lock_stats()
+            .values()
+            .fold(CallStats::default(), |mut summary, stats| {
+                summary.merge(stats);
+                summary
+            })
+    }
+
+    /// Removes all the recorded statistics.
+    pub fn reset_stats(&self) {
+        self.lock_stats().clear();
+    }
+
+    fn lock_stats(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, CallStats>> {
+        // The stats are always left consistent, so it is safe to ignore the poisoning.
+        self.stats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record<R>(
+        &self,
+        method: &str,
+        result: &CanisterClientResult<R>,
+        started: Duration,
+        cycles_attached: u64,
+    ) {
+        let latency = now().saturating_sub(started);
+        let cycles_refunded = if cycles_attached > 0 {
+            ic_exports::ic_kit::ic::msg_cycles_refunded()
+        } else {
+            0
+        };
+
+        self.lock_stats()
+            .entry(method.to_string())
+            .or_default()
+            .record(
+                result.as_ref().err(),
+                latency,
+                (cycles_attached, cycles_refunded),
+            );
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: CanisterClient + Sync> CanisterClient for StatsClient<C> {
+    async fn update<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType,
+    {
+        let started = now();
+        let result = self.client.update(method, args).await;
+        self.record(method, &result, started, self.client.update_cycles());
+        result
+    }
+
+    async fn query<T, R>(&self, method: &str, args: T) -> CanisterClientResult<R>
+    where
+        T: ArgumentEncoder + Send + Sync,
+        R: DeserializeOwned + CandidType,
+    {
+        let started = now();
+        let result = self.client.query(method, args).await;
+        self.record(method, &result, started, 0);
+        result
+    }
+
+    fn update_cycles(&self) -> u64 {
+        self.client.update_cycles()
+    }
+}
+
+#[cfg(target_family = "wasm")]
+fn now() -> Duration {
+    Duration::from_nanos(ic_exports::ic_cdk::api::time())
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn now() -> Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use ic_canister::{register_failing_virtual_responder, register_virtual_responder};
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+    use crate::IcCanisterClient;
+
+    #[tokio::test]
+    async fn should_record_call_stats() {
+        MockContext::new().inject();
+        let canister = Principal::management_canister();
+        register_virtual_responder(canister, "ok", |()| 42u32);
+        register_failing_virtual_responder(canister, "fail", "failed".to_string());
+
+        let client = StatsClient::new(IcCanisterClient::new(canister));
+        let clone = client.clone();
+
+        let value: u32 = client.query("ok", ()).await.unwrap();
+        assert_eq!(value, 42);
+        let value: u32 = clone.update("ok", ()).await.unwrap();
+        assert_eq!(value, 42);
+        let result: CanisterClientResult<u32> = client.update("fail", ()).await;
+        assert!(result.is_err());
+
+        let ok_stats = client.method_stats("ok");
+        assert_eq!(ok_stats.calls, 2);
+        assert_eq!(ok_stats.errors, 0);
+        assert!(ok_stats.reject_codes.is_empty());
+
+        let fail_stats = client.method_stats("fail");
+        assert_eq!(fail_stats.calls, 1);
+        assert_eq!(fail_stats.errors, 1);
+        assert_eq!(fail_stats.transient_errors, 0);
+        assert_eq!(fail_stats.reject_codes, vec![(RejectionCode::Unknown, 1)]);
+
+        let summary = client.summary();
+        assert_eq!(summary.calls, 3);
+        assert_eq!(summary.errors, 1);
+        assert!(summary.max_latency_nanos >= summary.average_latency().as_nanos() as u64);
+
+        client.reset_stats();
+        assert_eq!(clone.summary(), CallStats::default());
+    }
+
+    #[test]
+    fn should_merge_stats() {
+        let mut stats = CallStats::default();
+        stats.record(None, Duration::from_nanos(10), (0, 0));
+        stats.record(
+            Some(&CanisterClientError::CanisterError((
+                RejectionCode::SysTransient,
+                "busy".to_string(),
+            ))),
+            Duration::from_nanos(30),
+            (100, 40),
+        );
+
+        let mut other = CallStats::default();
+        other.record(
+            Some(&CanisterClientError::CanisterError((
+                RejectionCode::SysTransient,
+                "busy".to_string(),
+            ))),
+            Duration::from_nanos(50),
+            (0, 0),
+        );
+        stats.merge(&other);
+
+        assert_eq!(stats.calls, 3);
+        assert_eq!(stats.errors, 2);
+        assert_eq!(stats.transient_errors, 2);
+        assert_eq!(stats.reject_codes, vec![(RejectionCode::SysTransient, 2)]);
+        assert_eq!(stats.total_latency_nanos, 90);
+        assert_eq!(stats.max_latency_nanos, 50);
+        assert_eq!(stats.average_latency(), Duration::from_nanos(30));
+        assert_eq!(stats.cycles_attached, 100);
+        assert_eq!(stats.cycles_refunded, 40);
+    }
+}
@@ -202,12 +202,13 @@ pub(crate) fn api_method(
             #[export_name = #export_name]
             fn #internal_method() {
                 ::ic_exports::ic_cdk::setup();
-                ::ic_exports::ic_cdk::spawn(async {
+                let context = ::ic_canister::MessageContext::new(#method_name);
+                ::ic_exports::ic_cdk::spawn(::ic_canister::in_message_context(context, async {
                     #args_destr_tuple
                     let mut instance = Self::init_instance();
                     let result = instance. #method(#args_destr) #await_call #await_call_if_result_is_async;
                     #reply_call
-                });
+                }));
             }
         }
     };
@@ -425,13 +426,14 @@ pub(crate) fn generate_exports(input: TokenStream) -> TokenStream {
             #[export_name = #export_name]
             fn #internal_method() {
                 ::ic_exports::ic_cdk::setup();
-                ::ic_exports::ic_cdk::spawn(async {
+                let context = ::ic_canister::MessageContext::new(#method_name);
+                ::ic_exports::ic_cdk::spawn(::ic_canister::in_message_context(context, async {
                     #args_destr_tuple
                     let mut instance = #struct_name ::init_instance();
                     let result = instance. #method(#args_destr) #await_call #await_call_if_result_is_async;
 
                     #reply_call
-                });
+                }));
             }
         }
     });
@@ -618,11 +620,15 @@ pub(crate) fn generate_idl() -> TokenStream {
         None => quote! { let actor = ty; },
     };
 
+    // The constant condition lets the compiler drop the type descriptions when the IDL is
+    // disabled.
     let res = quote! {
-        {
+        if ::ic_canister::IDL_ENABLED {
             #service
             #actor
             Idl::new(env, actor)
+        } else {
+            Idl::empty()
         }
     };
 
@@ -167,6 +167,7 @@ pub(crate) fn virtual_canister_call(input: TokenStream) -> TokenStream {
     let method_name = &input.method_name;
     let response_type = &input.response_type;
     let cycles = input.cycles;
+    let recorded_cycles = recorded_cycles(cycles.as_ref());
 
     let cdk_call = get_cdk_call(
         quote! {#principal},
@@ -197,6 +198,8 @@ pub(crate) fn virtual_canister_call(input: TokenStream) -> TokenStream {
                 Err(e) => return Err((::ic_exports::ic_cdk::api::call::RejectionCode::Unknown, format!("failed to serialize arguments: {}", e))),
             };
 
+            ::ic_canister::record_virtual_call(#principal, #method_name, &encoded_args, #recorded_cycles, false);
+
             let __caller = ::ic_exports::ic_kit::ic::caller();
             let __id = ::ic_exports::ic_kit::ic::id();
             ::ic_exports::ic_kit::inject::get_context().update_caller(__id);
@@ -240,6 +243,7 @@ pub(crate) fn virtual_canister_notify(input: TokenStream) -> TokenStream {
     let args = normalize_expr(input.args);
     let method_name = &input.method_name;
     let cycles = input.cycles;
+    let recorded_cycles = recorded_cycles(cycles.as_ref());
 
     let cdk_call = get_cdk_notify(
         quote! {#principal},
@@ -255,6 +259,8 @@ pub(crate) fn virtual_canister_notify(input: TokenStream) -> TokenStream {
                 Err(e) => return Err((::ic_exports::ic_cdk::api::call::RejectionCode::Unknown, format!("failed to serialize arguments: {}", e))),
             };
 
+            ::ic_canister::record_virtual_call(#principal, #method_name, &encoded_args, #recorded_cycles, true);
+
             let result = ::ic_canister::call_virtual_responder(#principal, #method_name, encoded_args)?;
             Ok(())
         };
@@ -356,3 +362,11 @@ fn normalize_args(mut args: Punctuated<Expr, Token![,]>) -> Punctuated<Expr, Tok
 
     args
 }
+
+/// The cycles sent with a virtual call, as recorded by the mock context watcher.
+fn recorded_cycles(cycles: Option<&Expr>) -> proc_macro2::TokenStream {
+    match cycles {
+        Some(cycles) => quote! { (#cycles) as u64 },
+        None => quote! { 0u64 },
+    }
+}
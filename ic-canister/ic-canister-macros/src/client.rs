@@ -0,0 +1,110 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Error, FnArg, Ident, ItemTrait, Pat, ReturnType, TraitItem, Type};
+
+pub(crate) fn canister_client(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let client_name = parse_macro_input!(attr as Ident);
+    let input = parse_macro_input!(item as ItemTrait);
+
+    let methods = input
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            TraitItem::Fn(method) => Some(method),
+            _ => None,
+        })
+        .filter_map(|method| {
+            method.attrs.iter().find_map(|attr| {
+                let ident = attr.path().segments.last()?.ident.to_string();
+                match ident.as_str() {
+                    "query" | "update" => Some((ident, method)),
+                    _ => None,
+                }
+            })
+        })
+        .map(|(method_type, method)| client_method(&method_type, &method.sig))
+        .collect::<Result<Vec<_>, Error>>();
+
+    let methods = match methods {
+        Ok(methods) => methods,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let vis = &input.vis;
+    let doc = format!("Typed client for the [`{}`] canister API.", input.ident);
+
+    let expanded = quote! {
+        #input
+
+        #[doc = #doc]
+        #[derive(::std::clone::Clone, ::std::fmt::Debug)]
+        #vis struct #client_name<C> {
+            client: C,
+        }
+
+        impl<C: ::ic_canister_client::CanisterClient> #client_name<C> {
+            /// Creates the client using the given backend.
+            pub fn new(client: C) -> Self {
+                Self { client }
+            }
+
+            /// Returns the backend of the client.
+            pub fn client(&self) -> &C {
+                &self.client
+            }
+
+            #(#methods)*
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+fn client_method(
+    method_type: &str,
+    sig: &syn::Signature,
+) -> Result<proc_macro2::TokenStream, Error> {
+    let method = &sig.ident;
+    let method_name = method.to_string();
+    let call = Ident::new(method_type, Span::call_site());
+
+    let mut arg_names = vec![];
+    let mut arg_types = vec![];
+    for arg in &sig.inputs {
+        let arg = match arg {
+            FnArg::Receiver(_) => continue,
+            FnArg::Typed(arg) => arg,
+        };
+
+        match arg.pat.as_ref() {
+            Pat::Ident(pat) => arg_names.push(pat.ident.clone()),
+            pat => return Err(Error::new(pat.span(), "invalid arg name")),
+        }
+        arg_types.push(arg.ty.as_ref().clone());
+    }
+
+    let return_type = match &sig.output {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ty) => match ty.as_ref() {
+            Type::Tuple(tuple) if tuple.elems.is_empty() => quote! { () },
+            Type::Tuple(tuple) => {
+                return Err(Error::new(
+                    tuple.span(),
+                    "canister client does not support methods with multiple return values",
+                ))
+            }
+            ty => {
+                let ty = crate::derive::extract_type_if_matches("AsyncReturn", ty);
+                quote! { #ty }
+            }
+        },
+    };
+
+    Ok(quote! {
+        pub async fn #method(&self, #(#arg_names: #arg_types),*) -> ::ic_canister_client::CanisterClientResult<#return_type> {
+            ::ic_canister_client::CanisterClient::#call(&self.client, #method_name, (#(#arg_names,)*)).await
+        }
+    })
+}
@@ -2,6 +2,7 @@ use proc_macro::TokenStream;
 
 mod api;
 mod canister_call;
+mod client;
 mod derive;
 
 /// Makes an inter-canister call. This macro takes two inputs: the canister method invocation,
@@ -160,6 +161,33 @@ pub fn generate_exports(input: TokenStream) -> TokenStream {
     api::generate_exports(input)
 }
 
+/// Generates a typed client for the canister trait.
+///
+/// The client struct with the given name is generic over the
+/// `ic_canister_client::CanisterClient` backend, so the same client can be used for
+/// inter-canister calls, through the agent or in the test environments. For every trait method
+/// marked with `#[query]` or `#[update]` the client has an async method with the same
+/// arguments, which returns `CanisterClientResult` of the method return type.
+///
+/// The crate using this macro must depend on the `ic-canister-client` crate.
+///
+/// ```ignore
+/// #[canister_client(MyCanisterClient)]
+/// pub trait MyCanister: Canister {
+///     #[query(trait = true)]
+///     fn get_counter(&self) -> u32 {
+///         // ...
+///     }
+/// }
+///
+/// let client = MyCanisterClient::new(IcCanisterClient::new(canister_id));
+/// let counter: u32 = client.get_counter().await?;
+/// ```
+#[proc_macro_attribute]
+pub fn canister_client(attr: TokenStream, item: TokenStream) -> TokenStream {
+    client::canister_client(attr, item)
+}
+
 /// Derives [Canister] trait for a struct.
 #[proc_macro_derive(Canister, attributes(id, state, canister_no_upgrade_methods))]
 pub fn derive_canister(input: TokenStream) -> TokenStream {
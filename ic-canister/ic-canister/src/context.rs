@@ -0,0 +1,234 @@
+//! The context of the message executed by the canister: its caller, the called method and the
+//! correlation id, which identifies the message in the logs.
+//!
+//! The context is set by the `#[update]` and `#[query]` methods for the whole execution of the
+//! message, including the code after the `await` points.
+//!
+//! To trace a request across the canisters, the caller passes the [`CallContext`] of the
+//! message as the last argument of the calls, see [`with_call_context`], and the called method
+//! declares an `Option<CallContext>` last parameter, so it can be called without the context:
+//!
+//! ```ignore
+//! #[update]
+//! async fn transfer(&self, to: Principal, amount: u64, context: Option<CallContext>) {
+//!     adopt_call_context(context);
+//!     // The calls made here, and the logs written, have the correlation id of the request.
+//!     client.update("credit", with_call_context((to, amount))).await;
+//! }
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use ic_exports::candid::utils::ArgumentEncoder;
+use ic_exports::candid::{CandidType, Deserialize, IDLBuilder, Principal};
+use ic_exports::ic_kit::ic;
+
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct MessageContext {
+    pub caller: Principal,
+    pub method: String,
+    /// Unique among the messages of the canister, e.g. `17a2b9c3d4e5f601-2a`, or the
+    /// correlation id of the adopted [`CallContext`].
+    pub correlation_id: String,
+    /// The context passed by the caller, if adopted with [`adopt_call_context`].
+    pub incoming: Option<CallContext>,
+}
+
+impl MessageContext {
+    /// Create the context of the message executing the method.
+    pub fn new(method: &str) -> Self {
+        let counter = MESSAGE_COUNTER.with(|counter| {
+            let value = counter.get();
+            counter.set(value.wrapping_add(1));
+            value
+        });
+
+        Self {
+            caller: ic::caller(),
+            method: method.to_string(),
+            correlation_id: format!("{:x}-{counter:x}", ic::time()),
+            incoming: None,
+        }
+    }
+
+    /// The context to pass to the canisters called by the message: the adopted context one
+    /// call further, or a new context started by the message.
+    pub fn outgoing_call_context(&self) -> CallContext {
+        match &self.incoming {
+            Some(incoming) => CallContext {
+                hops: incoming.hops + 1,
+                ..incoming.clone()
+            },
+            None => CallContext {
+                correlation_id: self.correlation_id.clone(),
+                origin: self.caller,
+                deadline: None,
+                hops: 1,
+            },
+        }
+    }
+}
+
+/// The context of a request, propagated along the inter-canister calls made to serve it.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CallContext {
+    /// The correlation id of the message which started the request.
+    pub correlation_id: String,
+    /// The caller of the message which started the request.
+    pub origin: Principal,
+    /// The time in nanoseconds, after which the result of the request is not needed.
+    pub deadline: Option<u64>,
+    /// The number of the calls from the message which started the request.
+    pub hops: u32,
+}
+
+impl CallContext {
+    pub fn with_deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Whether the deadline of the request passed at `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.deadline.is_some_and(|deadline| now > deadline)
+    }
+}
+
+thread_local! {
+    static MESSAGE_COUNTER: Cell<u64> = const { Cell::new(0) };
+    static CURRENT_CONTEXT: RefCell<Option<MessageContext>> = const { RefCell::new(None) };
+}
+
+/// Returns the context of the current message, if it was set.
+pub fn message_context() -> Option<MessageContext> {
+    CURRENT_CONTEXT.with(|current| current.borrow().clone())
+}
+
+/// Continue the request of the caller in the current message: the context is returned by
+/// [`call_context`], passed further by [`with_call_context`], and its correlation id replaces
+/// the one of the message. Does nothing if the context is `None`.
+pub fn adopt_call_context(context: Option<CallContext>) {
+    let Some(context) = context else {
+        return;
+    };
+
+    CURRENT_CONTEXT.with(|current| {
+        if let Some(current) = &mut *current.borrow_mut() {
+            current.correlation_id = context.correlation_id.clone();
+            current.incoming = Some(context);
+        }
+    });
+}
+
+/// Returns the context adopted by the current message.
+pub fn call_context() -> Option<CallContext> {
+    message_context().and_then(|context| context.incoming)
+}
+
+/// Append the context of the current message to the arguments of a call, if the context is set.
+pub fn with_call_context<T: ArgumentEncoder>(args: T) -> WithCallContext<T> {
+    WithCallContext {
+        args,
+        context: message_context().map(|context| context.outgoing_call_context()),
+    }
+}
+
+/// The arguments of a call followed by the `opt CallContext` argument.
+#[derive(Debug, Clone)]
+pub struct WithCallContext<T> {
+    pub args: T,
+    pub context: Option<CallContext>,
+}
+
+impl<T: ArgumentEncoder> ArgumentEncoder for WithCallContext<T> {
+    fn encode(self, ser: &mut IDLBuilder) -> ic_exports::candid::Result<()> {
+        self.args.encode(ser)?;
+        ser.arg(&self.context)?;
+        Ok(())
+    }
+}
+
+/// Run the closure with the context set.
+pub fn with_message_context<R>(context: MessageContext, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT_CONTEXT.with(|current| current.replace(Some(context)));
+    let result = f();
+    CURRENT_CONTEXT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+/// Run the future with the context set whenever it's polled, so the context is kept after the
+/// `await` points, when other messages may have been executed.
+pub fn in_message_context<F: Future>(context: MessageContext, future: F) -> InMessageContext<F> {
+    InMessageContext {
+        context: Some(context),
+        future: Box::pin(future),
+    }
+}
+
+/// The future returned by [`in_message_context`].
+pub struct InMessageContext<F> {
+    context: Option<MessageContext>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for InMessageContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let previous = CURRENT_CONTEXT.with(|current| current.replace(this.context.take()));
+        let result = this.future.as_mut().poll(cx);
+        this.context = CURRENT_CONTEXT.with(|current| current.replace(previous));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::candid::{decode_args, encode_args};
+
+    use super::*;
+
+    #[test]
+    fn call_context_is_propagated() {
+        let caller = Principal::from_slice(&[1]);
+        let context = MessageContext {
+            caller,
+            method: "transfer".to_string(),
+            correlation_id: "a-1".to_string(),
+            incoming: None,
+        };
+
+        let outgoing = with_message_context(context, || {
+            assert_eq!(call_context(), None);
+            let started = with_call_context((10u64,));
+            assert_eq!(started.context.as_ref().unwrap().origin, caller);
+
+            adopt_call_context(Some(CallContext {
+                correlation_id: "b-2".to_string(),
+                origin: Principal::anonymous(),
+                deadline: Some(100),
+                hops: 1,
+            }));
+            assert_eq!(message_context().unwrap().correlation_id, "b-2");
+            with_call_context((10u64,))
+        });
+
+        let bytes = encode_args(outgoing).unwrap();
+        let (amount, context): (u64, Option<CallContext>) = decode_args(&bytes).unwrap();
+        let context = context.unwrap();
+        assert_eq!(amount, 10);
+        assert_eq!(context.correlation_id, "b-2");
+        assert_eq!(context.origin, Principal::anonymous());
+        assert_eq!(context.hops, 2);
+        assert!(context.is_expired(101));
+
+        // The methods declared without the context ignore it.
+        let (amount,): (u64,) =
+            decode_args(&encode_args(with_call_context((7u64,))).unwrap()).unwrap();
+        assert_eq!(amount, 7);
+    }
+}
@@ -5,6 +5,13 @@ use ic_exports::candid;
 use ic_exports::candid::types::internal::TypeContainer;
 use ic_exports::candid::types::{Type, TypeInner};
 
+/// `false` if the IDL is not generated, in the wasm builds with the `no-wasm-idl` feature.
+///
+/// The IDL is needed to write the candid files, which is done by the native builds, while its
+/// generation code with the type descriptions of all the arguments takes a noticeable part of
+/// the wasm module.
+pub const IDL_ENABLED: bool = !cfg!(all(target_family = "wasm", feature = "no-wasm-idl"));
+
 pub struct Idl {
     pub env: TypeContainer,
     pub actor: Type,
@@ -21,6 +28,14 @@ impl Idl {
         Self { env, actor }
     }
 
+    /// The IDL of a service without methods.
+    pub fn empty() -> Self {
+        Self::new(
+            TypeContainer::new(),
+            Type(Rc::new(TypeInner::Service(vec![]))),
+        )
+    }
+
     pub fn merge(&mut self, other: &Self) {
         self.env = candid::types::internal::TypeContainer {
             env: self.env.env.merge(&other.env.env).unwrap().clone(),
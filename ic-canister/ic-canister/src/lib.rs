@@ -524,6 +524,8 @@ use ic_exports::candid::utils::ArgumentDecoder;
 use ic_exports::candid::{self, CandidType, Deserialize, Principal};
 use ic_exports::ic_cdk::api::call::{CallResult, RejectionCode};
 
+pub mod context;
+pub use context::*;
 pub mod idl;
 pub use idl::*;
 
@@ -639,6 +641,25 @@ pub fn call_virtual_responder(
     })
 }
 
+/// Records a virtual call in the watcher of the injected mock context, if there is one, so it can
+/// be checked with the watcher assertions. This function is supposed to be called through
+/// [virtual_canister_call] and [virtual_canister_notify] macros.
+#[doc(hidden)]
+pub fn record_virtual_call(
+    principal: Principal,
+    method_name: &str,
+    args: &[u8],
+    cycles: u64,
+    is_notify: bool,
+) {
+    if let Some(ctx) = ic_exports::ic_kit::inject::try_get_context() {
+        // Virtual responders don't accept cycles, so all of them are refunded.
+        let call = ic_exports::ic_kit::WatcherCall::new(principal, method_name, args.to_vec())
+            .with_cycles(cycles, cycles);
+        ctx.record_call(if is_notify { call.with_notify() } else { call });
+    }
+}
+
 /// Saves a function that will be called when testing inter-canister calls, invoked with
 /// [virtual_canister_call] macro.
 ///
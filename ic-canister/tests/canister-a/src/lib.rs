@@ -2,7 +2,8 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use ic_canister::{
-    generate_exports, generate_idl, query, state_getter, update, Canister, Idl, PreUpdate,
+    canister_client, generate_exports, generate_idl, query, state_getter, update, Canister, Idl,
+    PreUpdate,
 };
 use ic_exports::candid::{CandidType, Deserialize, Principal};
 use ic_storage::stable::Versioned;
@@ -21,6 +22,7 @@ impl Versioned for StateA {
     }
 }
 
+#[canister_client(CanisterAClient)]
 pub trait CanisterA: Canister {
     #[state_getter]
     fn state(&self) -> Rc<RefCell<StateA>>;
@@ -57,7 +59,8 @@ generate_exports!(CanisterA, CanisterAImpl);
 
 #[cfg(test)]
 mod tests {
-    use ic_canister::{canister_call, Canister};
+    use ic_canister::{canister_call, register_virtual_responder, Canister};
+    use ic_canister_client::IcCanisterClient;
     use ic_exports::ic_kit::MockContext;
 
     use super::*;
@@ -116,4 +119,20 @@ mod tests {
         assert_eq!(ic_exports::ic_kit::ic::id(), id);
         assert_eq!(ic_exports::ic_kit::ic::caller(), caller);
     }
+
+    #[tokio::test]
+    async fn generated_client() {
+        MockContext::new().inject();
+
+        let canister = ic_exports::ic_kit::mock_principals::alice();
+        register_virtual_responder(canister, "get_counter", |()| 42u32);
+        register_virtual_responder(canister, "inc_counter", |(value,): (u32,)| {
+            assert_eq!(value, 3)
+        });
+
+        let client = CanisterAClient::new(IcCanisterClient::new(canister));
+        assert_eq!(client.get_counter().await.unwrap(), 42);
+        client.inc_counter(3).await.unwrap();
+        assert!(client.id().await.is_err());
+    }
 }
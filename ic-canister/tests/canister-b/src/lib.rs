@@ -202,4 +202,32 @@ mod tests {
             18
         );
     }
+
+    #[tokio::test]
+    async fn virtual_calls_are_recorded() {
+        let ctx = MockContext::new().with_id(alice()).inject();
+        let watcher = ctx.watch();
+        let canister_a = ic_exports::ic_kit::mock_principals::xtc();
+        ic_canister::register_virtual_responder(canister_a, "inc_counter", |(_,): (u32,)| ());
+        ic_canister::register_virtual_responder(canister_a, "get_counter", |()| 7u32);
+
+        let canister_b = get_canister_b(canister_a);
+        assert_eq!(canister_b.call_increment_virtual(5).await, 7);
+        assert!(canister_b.notify_increment_virtual(3).await);
+
+        watcher
+            .call(&canister_a, "inc_counter")
+            .with_args((5u32,))
+            .assert_called_once();
+        watcher
+            .call(&canister_a, "inc_counter")
+            .with_args((3u32,))
+            .notify()
+            .assert_called_once();
+        watcher.assert_call_order(&[
+            (canister_a, "inc_counter"),
+            (canister_a, "get_counter"),
+            (canister_a, "inc_counter"),
+        ]);
+    }
 }
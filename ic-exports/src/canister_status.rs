@@ -0,0 +1,172 @@
+/// The status and the settings of a canister with the numbers as the primitive integers,
+/// converted from the records of the management canister API.
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::management_canister::main as cdk;
+use ic_kit::interfaces::management as kit;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunStatus {
+    Running,
+    Stopping,
+    Stopped,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct CanisterStatus {
+    pub status: RunStatus,
+    pub controllers: Vec<Principal>,
+    pub compute_allocation: u64,
+    pub memory_allocation: u64,
+    pub freezing_threshold: u64,
+    /// The SHA-256 hash of the installed module, `None` for an empty canister.
+    pub module_hash: Option<Vec<u8>>,
+    pub memory_size: u64,
+    pub cycles: u128,
+}
+
+/// The settings to update, the settings which are `None` are not changed.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CanisterSettings {
+    pub controllers: Option<Vec<Principal>>,
+    pub compute_allocation: Option<u64>,
+    pub memory_allocation: Option<u64>,
+    pub freezing_threshold: Option<u64>,
+}
+
+/// Converts the number, saturating at `u64::MAX`.
+fn nat_to_u64(nat: &Nat) -> u64 {
+    u64::try_from(&nat.0).unwrap_or(u64::MAX)
+}
+
+/// Converts the number, saturating at `u128::MAX`.
+fn nat_to_u128(nat: &Nat) -> u128 {
+    u128::try_from(&nat.0).unwrap_or(u128::MAX)
+}
+
+impl From<cdk::CanisterStatusType> for RunStatus {
+    fn from(status: cdk::CanisterStatusType) -> Self {
+        match status {
+            cdk::CanisterStatusType::Running => Self::Running,
+            cdk::CanisterStatusType::Stopping => Self::Stopping,
+            cdk::CanisterStatusType::Stopped => Self::Stopped,
+        }
+    }
+}
+
+impl From<kit::Status> for RunStatus {
+    fn from(status: kit::Status) -> Self {
+        match status {
+            kit::Status::Running => Self::Running,
+            kit::Status::Stopping => Self::Stopping,
+            kit::Status::Stopped => Self::Stopped,
+        }
+    }
+}
+
+impl From<cdk::CanisterStatusResponse> for CanisterStatus {
+    fn from(response: cdk::CanisterStatusResponse) -> Self {
+        Self {
+            status: response.status.into(),
+            controllers: response.settings.controllers,
+            compute_allocation: nat_to_u64(&response.settings.compute_allocation),
+            memory_allocation: nat_to_u64(&response.settings.memory_allocation),
+            freezing_threshold: nat_to_u64(&response.settings.freezing_threshold),
+            module_hash: response.module_hash,
+            memory_size: nat_to_u64(&response.memory_size),
+            cycles: nat_to_u128(&response.cycles),
+        }
+    }
+}
+
+impl From<kit::CanisterStatusResponse> for CanisterStatus {
+    fn from(response: kit::CanisterStatusResponse) -> Self {
+        Self {
+            status: response.status.into(),
+            controllers: response.settings.controllers,
+            compute_allocation: nat_to_u64(&response.settings.compute_allocation),
+            memory_allocation: nat_to_u64(&response.settings.memory_allocation),
+            freezing_threshold: nat_to_u64(&response.settings.freezing_threshold),
+            module_hash: response.module_hash,
+            memory_size: nat_to_u64(&response.memory_size),
+            cycles: nat_to_u128(&response.cycles),
+        }
+    }
+}
+
+impl From<CanisterSettings> for kit::CanisterSettings {
+    fn from(settings: CanisterSettings) -> Self {
+        Self {
+            controllers: settings.controllers,
+            compute_allocation: settings.compute_allocation.map(Nat::from),
+            memory_allocation: settings.memory_allocation.map(Nat::from),
+            freezing_threshold: settings.freezing_threshold.map(Nat::from),
+        }
+    }
+}
+
+impl From<kit::CanisterSettings> for CanisterSettings {
+    fn from(settings: kit::CanisterSettings) -> Self {
+        Self {
+            controllers: settings.controllers,
+            compute_allocation: settings.compute_allocation.as_ref().map(nat_to_u64),
+            memory_allocation: settings.memory_allocation.as_ref().map(nat_to_u64),
+            freezing_threshold: settings.freezing_threshold.as_ref().map(nat_to_u64),
+        }
+    }
+}
+
+impl From<cdk::CanisterSettings> for CanisterSettings {
+    fn from(settings: cdk::CanisterSettings) -> Self {
+        Self {
+            controllers: settings.controllers,
+            compute_allocation: settings.compute_allocation.as_ref().map(nat_to_u64),
+            memory_allocation: settings.memory_allocation.as_ref().map(nat_to_u64),
+            freezing_threshold: settings.freezing_threshold.as_ref().map(nat_to_u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_is_flattened() {
+        let response = kit::CanisterStatusResponse {
+            status: kit::Status::Stopping,
+            settings: kit::DefiniteCanisterSettings {
+                controllers: vec![Principal::anonymous()],
+                compute_allocation: Nat::from(10u64),
+                memory_allocation: Nat::from(0u64),
+                freezing_threshold: Nat::from(2_592_000u64),
+            },
+            module_hash: None,
+            memory_size: Nat::from(1024u64),
+            cycles: Nat::from(u128::MAX) + Nat::from(1u64),
+        };
+
+        assert_eq!(
+            CanisterStatus::from(response),
+            CanisterStatus {
+                status: RunStatus::Stopping,
+                controllers: vec![Principal::anonymous()],
+                compute_allocation: 10,
+                memory_allocation: 0,
+                freezing_threshold: 2_592_000,
+                module_hash: None,
+                memory_size: 1024,
+                cycles: u128::MAX,
+            }
+        );
+
+        let settings = CanisterSettings {
+            freezing_threshold: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            CanisterSettings::from(kit::CanisterSettings::from(settings.clone())),
+            settings
+        );
+    }
+}
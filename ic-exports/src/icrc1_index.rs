@@ -0,0 +1,70 @@
+/// This structs are extracted from:
+/// https://github.com/dfinity/ic/blob/master/rs/rosetta-api/icrc1/index-ng/src/lib.rs
+///
+/// They need to be replaced with the official ones once they are available in crates.io
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::icrc_types::icrc1::account::{Account, Subaccount};
+use crate::icrc_types::icrc3::transactions::Transaction;
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub enum IndexArg {
+    Init(InitArg),
+    Upgrade(UpgradeArg),
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct InitArg {
+    pub ledger_id: Principal,
+    pub retrieve_blocks_from_ledger_interval_seconds: Option<u64>,
+}
+
+#[derive(Default, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct UpgradeArg {
+    pub ledger_id: Option<Principal>,
+    pub retrieve_blocks_from_ledger_interval_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct GetAccountTransactionsArgs {
+    pub account: Account,
+    /// The last transaction id seen by the client, the transactions are listed from the
+    /// previous one. The latest transactions are listed if `None`.
+    pub start: Option<Nat>,
+    pub max_results: Nat,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct TransactionWithId {
+    pub id: Nat,
+    pub transaction: Transaction,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct GetTransactions {
+    pub balance: Nat,
+    pub transactions: Vec<TransactionWithId>,
+    /// The id of the oldest transaction of the account, `None` if the account has no
+    /// transactions.
+    pub oldest_tx_id: Option<Nat>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct GetTransactionsErr {
+    pub message: String,
+}
+
+pub type GetAccountTransactionsResult = Result<GetTransactions, GetTransactionsErr>;
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct ListSubaccountsArgs {
+    pub owner: Principal,
+    /// The last subaccount seen by the client, the subaccounts are listed from the next one.
+    pub start: Option<Subaccount>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub num_blocks_synced: Nat,
+}
@@ -0,0 +1,101 @@
+/// The types of the ICRC-3 blocks endpoints, as defined in:
+/// https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-3/README.md
+///
+/// The blocks are the ICRC-3 generic values, so they can be decoded by any ledger
+/// implementing the standard.
+use candid::{CandidType, Nat};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+pub use crate::icrc_types::icrc::generic_value::Value;
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksFn : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+/// The blocks which must be fetched from an archive with the callback.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksFn,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct GetBlocksResult {
+    /// The total number of the blocks of the ledger.
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct DataCertificate {
+    /// The certificate of the tip of the chain, `None` in the replicated calls.
+    pub certificate: Option<ByteBuf>,
+    /// The CBOR encoded hash tree with the hash and the index of the last block.
+    pub hash_tree: ByteBuf,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    pub canister_id: candid::Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct GetArchivesArgs {
+    /// The last archive seen by the client, the archives are listed from the next one.
+    pub from: Option<candid::Principal>,
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{Decode, Encode};
+
+    use super::*;
+
+    #[test]
+    fn blocks_result_roundtrip() {
+        let result = GetBlocksResult {
+            log_length: Nat::from(10u64),
+            blocks: vec![BlockWithId {
+                id: Nat::from(9u64),
+                block: Value::Map(
+                    [("btype".to_string(), Value::Text("1xfer".to_string()))]
+                        .into_iter()
+                        .collect(),
+                ),
+            }],
+            archived_blocks: vec![ArchivedBlocks {
+                args: vec![GetBlocksArgs {
+                    start: Nat::from(0u64),
+                    length: Nat::from(9u64),
+                }],
+                callback: GetBlocksFn::new(
+                    candid::Principal::management_canister(),
+                    "icrc3_get_blocks".to_string(),
+                ),
+            }],
+        };
+
+        let bytes = Encode!(&result).unwrap();
+        assert_eq!(Decode!(&bytes, GetBlocksResult).unwrap(), result);
+    }
+}
@@ -0,0 +1,50 @@
+//! The instruction counter shared by the metrics, the logs and the scheduler, so that all of them
+//! count the same instructions and are driven by the same mock in the tests.
+
+#[cfg(not(target_family = "wasm"))]
+thread_local! {
+    /// The instructions counted outside of a canister instead of the system counter.
+    static MOCK_INSTRUCTIONS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// Returns the number of the instructions executed in the current call context, i.e. the
+/// performance counter `1` of the IC. Unlike the counter of the current message, it includes
+/// the instructions executed before the `await` points, so the instructions of an async job are
+/// counted in full. Within a single message both counters grow by the same amount.
+///
+/// Outside of a canister the counter is mocked: it's zero unless the tests move it with
+/// [`burn_mock_instructions`].
+#[inline]
+pub fn instruction_counter() -> u64 {
+    #[cfg(target_family = "wasm")]
+    {
+        ic_cdk::api::performance_counter(1)
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    {
+        MOCK_INSTRUCTIONS.with(|instructions| instructions.get())
+    }
+}
+
+/// Increases the instructions returned by [`instruction_counter`] in the current thread.
+#[cfg(not(target_family = "wasm"))]
+pub fn burn_mock_instructions(instructions: u64) {
+    MOCK_INSTRUCTIONS.with(|counter| counter.set(counter.get() + instructions));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_instructions_are_counted_per_thread() {
+        let start = instruction_counter();
+        burn_mock_instructions(40);
+        assert_eq!(instruction_counter() - start, 40);
+
+        std::thread::spawn(|| assert_eq!(instruction_counter(), 0))
+            .join()
+            .unwrap();
+    }
+}
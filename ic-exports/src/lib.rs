@@ -1,22 +1,71 @@
+//! The IC crates used by the SDK, re-exported with aligned versions.
+//!
+//! The crates needed by every canister are always exported, with the [`timers::Timers`]
+//! abstraction over the timers of the canister and the [`instructions::instruction_counter`]
+//! shared by the SDK crates. The other exports are grouped by features, so that the canisters
+//! compile and link only what they use:
+//!
+//! - `management`: the types of the management canister API;
+//! - `ledger`, `icrc` (or both with `ledgers`): the types of the ICP and the ICRC ledgers;
+//! - `pocket-ic-tests`, `pocket-ic-tests-async`, `ic-test-state-machine`: the test
+//!   environments with their HTTP clients, only for the native test builds.
+
 pub use candid; // this is needed for candid-derive macro exports
 pub use {ic_cdk, ic_cdk_macros, ic_cdk_timers, ic_kit};
 
+#[cfg(all(
+    target_family = "wasm",
+    any(feature = "pocket-ic-tests", feature = "ic-test-state-machine")
+))]
+compile_error!(
+    "the test environment features of ic-exports are not available in the wasm builds, \
+     enable them only in the dev-dependencies"
+);
+
 pub type BlockHeight = u64;
 
+pub mod instructions;
+
+pub mod timers;
+
+#[cfg(feature = "management")]
+mod canister_status;
+
+#[cfg(feature = "management")]
+pub mod management {
+    pub use ic_cdk::api::management_canister::*;
+
+    pub use crate::canister_status::*;
+}
+
 #[cfg(feature = "ledger")]
 pub mod ledger {
     pub use ic_ledger_types::*;
 }
 
+#[cfg(feature = "icrc")]
+mod icrc1_index;
 #[cfg(feature = "icrc")]
 mod icrc1_ledger;
+#[cfg(feature = "icrc")]
+mod icrc3_blocks;
 
+/// The ICRC types, re-exported with the version of `icrc-ledger-types` built with the candid
+/// of the SDK, so that the downstream crates don't depend on conflicting candid versions.
 #[cfg(feature = "icrc")]
 pub mod icrc_types {
     pub use icrc_ledger_types::*;
     pub mod icrc1_ledger {
         pub use crate::icrc1_ledger::*;
     }
+    /// The arguments and the endpoint types of the ICRC-1 index canister.
+    pub mod icrc1_index {
+        pub use crate::icrc1_index::*;
+    }
+    /// The ICRC-3 block log schema.
+    pub mod icrc3_blocks {
+        pub use crate::icrc3_blocks::*;
+    }
 }
 
 #[cfg(feature = "pocket-ic-tests")]
@@ -0,0 +1,230 @@
+//! The timers of a canister behind a trait, so that the components using the timers can be
+//! driven by the [`MockTimers`] in the tests.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// The id of a timer, to clear it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+/// The timers of a canister.
+pub trait Timers {
+    /// Call the function once after the delay.
+    fn set_timer(&self, delay: Duration, func: Box<dyn FnOnce()>) -> TimerId;
+
+    /// Call the function every interval, until the timer is cleared.
+    fn set_timer_interval(&self, interval: Duration, func: Box<dyn FnMut()>) -> TimerId;
+
+    /// Cancel the timer. Does nothing if the timer already fired or was cleared.
+    fn clear_timer(&self, id: TimerId);
+}
+
+thread_local! {
+    static IC_TIMERS: RefCell<IcTimersState> = RefCell::default();
+}
+
+#[derive(Default)]
+struct IcTimersState {
+    next_id: u64,
+    timers: BTreeMap<u64, ic_cdk_timers::TimerId>,
+}
+
+/// The timers of the canister with `ic-cdk-timers`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IcTimers;
+
+impl IcTimers {
+    fn register(id: u64, timer: ic_cdk_timers::TimerId) {
+        IC_TIMERS.with(|state| state.borrow_mut().timers.insert(id, timer));
+    }
+
+    fn next_id() -> u64 {
+        IC_TIMERS.with(|state| {
+            let mut state = state.borrow_mut();
+            state.next_id += 1;
+            state.next_id
+        })
+    }
+}
+
+impl Timers for IcTimers {
+    fn set_timer(&self, delay: Duration, func: Box<dyn FnOnce()>) -> TimerId {
+        let id = Self::next_id();
+        let timer = ic_cdk_timers::set_timer(delay, move || {
+            IC_TIMERS.with(|state| state.borrow_mut().timers.remove(&id));
+            func();
+        });
+        Self::register(id, timer);
+        TimerId(id)
+    }
+
+    fn set_timer_interval(&self, interval: Duration, func: Box<dyn FnMut()>) -> TimerId {
+        let id = Self::next_id();
+        Self::register(id, ic_cdk_timers::set_timer_interval(interval, func));
+        TimerId(id)
+    }
+
+    fn clear_timer(&self, id: TimerId) {
+        if let Some(timer) = IC_TIMERS.with(|state| state.borrow_mut().timers.remove(&id.0)) {
+            ic_cdk_timers::clear_timer(timer);
+        }
+    }
+}
+
+enum MockTimer {
+    Once(Box<dyn FnOnce()>),
+    Interval(Duration, Box<dyn FnMut()>),
+}
+
+#[derive(Default)]
+struct MockTimersState {
+    now: Duration,
+    next_id: u64,
+    /// The timers by their deadlines and ids.
+    timers: BTreeMap<(Duration, u64), MockTimer>,
+}
+
+/// The timers with a mock time, which fire when the time is advanced.
+///
+/// The clones share the timers.
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::rc::Rc;
+/// use std::time::Duration;
+///
+/// use ic_exports::timers::{MockTimers, Timers};
+///
+/// let timers = MockTimers::default();
+/// let ticks = Rc::new(Cell::new(0));
+/// let counter = ticks.clone();
+/// timers.set_timer_interval(
+///     Duration::from_secs(10),
+///     Box::new(move || counter.set(counter.get() + 1)),
+/// );
+///
+/// timers.advance(Duration::from_secs(25));
+/// assert_eq!(ticks.get(), 2);
+/// ```
+#[derive(Default, Clone)]
+pub struct MockTimers(Rc<RefCell<MockTimersState>>);
+
+impl MockTimers {
+    /// Returns the time elapsed since the timers were created.
+    pub fn now(&self) -> Duration {
+        self.0.borrow().now
+    }
+
+    /// Returns the number of the active timers.
+    pub fn len(&self) -> usize {
+        self.0.borrow().timers.len()
+    }
+
+    /// Returns `true` if there are no active timers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Move the time forward, firing the due timers in the order of their deadlines. The
+    /// timers set by the fired timers fire too if they are due.
+    pub fn advance(&self, by: Duration) {
+        let until = self.now() + by;
+        loop {
+            let due = {
+                let mut state = self.0.borrow_mut();
+                match state.timers.first_key_value() {
+                    Some((&(deadline, _), _)) if deadline <= until => {
+                        state.now = deadline;
+                        state.timers.pop_first()
+                    }
+                    _ => None,
+                }
+            };
+
+            // The timer is called without the borrow, so that it can set or clear timers.
+            match due {
+                Some((_, MockTimer::Once(func))) => func(),
+                Some(((deadline, id), MockTimer::Interval(interval, mut func))) => {
+                    func();
+                    self.0.borrow_mut().timers.insert(
+                        (deadline + interval.max(Duration::from_nanos(1)), id),
+                        MockTimer::Interval(interval, func),
+                    );
+                }
+                None => break,
+            }
+        }
+
+        self.0.borrow_mut().now = until;
+    }
+
+    fn insert(&self, delay: Duration, timer: MockTimer) -> TimerId {
+        let mut state = self.0.borrow_mut();
+        state.next_id += 1;
+        let id = state.next_id;
+        let deadline = state.now + delay;
+        state.timers.insert((deadline, id), timer);
+        TimerId(id)
+    }
+}
+
+impl Timers for MockTimers {
+    fn set_timer(&self, delay: Duration, func: Box<dyn FnOnce()>) -> TimerId {
+        self.insert(delay, MockTimer::Once(func))
+    }
+
+    fn set_timer_interval(&self, interval: Duration, func: Box<dyn FnMut()>) -> TimerId {
+        self.insert(interval, MockTimer::Interval(interval, func))
+    }
+
+    fn clear_timer(&self, id: TimerId) {
+        self.0
+            .borrow_mut()
+            .timers
+            .retain(|(_, timer_id), _| *timer_id != id.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_timers_fire_in_order() {
+        let timers = MockTimers::default();
+        let fired = Rc::new(RefCell::new(vec![]));
+
+        let record = |name: &'static str| {
+            let fired = fired.clone();
+            move || fired.borrow_mut().push(name)
+        };
+        let interval =
+            timers.set_timer_interval(Duration::from_secs(10), Box::new(record("interval")));
+        timers.set_timer(Duration::from_secs(15), Box::new(record("once")));
+        let cleared = timers.set_timer(Duration::from_secs(5), Box::new(record("cleared")));
+        timers.clear_timer(cleared);
+
+        // A timer can set another timer.
+        let nested_timers = timers.clone();
+        let nested = record("nested");
+        timers.set_timer(
+            Duration::from_secs(1),
+            Box::new(move || {
+                nested_timers.set_timer(Duration::from_secs(1), Box::new(nested));
+            }),
+        );
+
+        timers.advance(Duration::from_secs(20));
+        assert_eq!(*fired.borrow(), ["nested", "interval", "once", "interval"]);
+        assert_eq!(timers.now(), Duration::from_secs(20));
+        assert_eq!(timers.len(), 1);
+
+        timers.clear_timer(interval);
+        timers.advance(Duration::from_secs(20));
+        assert_eq!(fired.borrow().len(), 4);
+        assert!(timers.is_empty());
+    }
+}
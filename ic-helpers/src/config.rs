@@ -0,0 +1,314 @@
+//! The settings of a canister in stable memory, with the history of their changes.
+
+use std::borrow::Cow;
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{Bound, CellStructure, LogStructure, StableCell, StableLog, Storable};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("the principal {0} is not allowed to change the config")]
+    Unauthorized(String),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+}
+
+pub type ConfigResult<T> = Result<T, ConfigError>;
+
+/// A change of the config, recorded in its history.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct ConfigChange<C> {
+    /// The version of the config after the change, starting from 1.
+    pub version: u64,
+    pub value: C,
+    pub updated_by: Principal,
+    pub timestamp: u64,
+}
+
+impl<C: CandidType + DeserializeOwned> Storable for ConfigChange<C> {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("serialization of config change failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("deserialization of config change failed")
+    }
+}
+
+/// The current config and its version, the version of the initial config is 0.
+#[derive(CandidType, Deserialize)]
+struct VersionedConfig<C> {
+    version: u64,
+    value: C,
+}
+
+impl<C: CandidType + DeserializeOwned> Storable for VersionedConfig<C> {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("serialization of config failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("deserialization of config failed")
+    }
+}
+
+type Guard = Box<dyn Fn(&Principal) -> bool>;
+type Validator<C> = Box<dyn Fn(&C) -> Result<(), String>>;
+type ChangeHook<C> = Box<dyn Fn(&ConfigChange<C>)>;
+
+/// Typed settings of the canister kept in stable memory.
+///
+/// The config is changed only by the callers allowed by the guard, the controllers of the
+/// canister by default, and only to the values accepted by the validator. Every change gets the
+/// next version, is recorded in the history and is passed to the change hooks, so the modules
+/// caching a part of the settings can refresh it.
+///
+/// The canister exposes the config with its own endpoints, as the type of the settings is
+/// declared by the canister:
+///
+/// ```ignore
+/// #[update]
+/// fn set_settings(&self, settings: Settings) -> ConfigResult<u64> {
+///     SETTINGS.with(|config| config.borrow_mut().update(ic::caller(), settings, ic::time()))
+/// }
+/// ```
+///
+/// Fields added to the settings must be `Option`s or have a `#[serde(default)]`, so the config
+/// stored by the previous version of the canister can be read after an upgrade.
+pub struct Config<C, M: Memory>
+where
+    C: CandidType + DeserializeOwned,
+{
+    guard: Guard,
+    validator: Validator<C>,
+    hooks: Vec<ChangeHook<C>>,
+    current: StableCell<VersionedConfig<C>, M>,
+    history: StableLog<ConfigChange<C>, M>,
+}
+
+impl<C, M> Config<C, M>
+where
+    C: CandidType + DeserializeOwned + Clone,
+    M: Memory,
+{
+    /// Create the config with the initial value, keeping the current value in `config_memory`
+    /// and the history in `history_index_memory` and `history_data_memory`. If the memories
+    /// contain a config, it is kept.
+    pub fn new(
+        config_memory: M,
+        history_index_memory: M,
+        history_data_memory: M,
+        initial: C,
+    ) -> Self {
+        let initial = VersionedConfig {
+            version: 0,
+            value: initial,
+        };
+        Self {
+            guard: Box::new(is_controller),
+            validator: Box::new(|_| Ok(())),
+            hooks: vec![],
+            current: StableCell::new(config_memory, initial).expect("failed to init config cell"),
+            history: StableLog::new(history_index_memory, history_data_memory)
+                .expect("failed to init config history"),
+        }
+    }
+
+    /// Allow only the callers accepted by the guard to change the config.
+    pub fn with_guard(mut self, guard: impl Fn(&Principal) -> bool + 'static) -> Self {
+        self.guard = Box::new(guard);
+        self
+    }
+
+    /// Reject the values for which the validator returns an error.
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&C) -> Result<(), String> + 'static,
+    ) -> Self {
+        self.validator = Box::new(validator);
+        self
+    }
+
+    /// Call the hook after every change of the config.
+    pub fn on_change(mut self, hook: impl Fn(&ConfigChange<C>) + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    pub fn get(&self) -> &C {
+        &self.current.get().value
+    }
+
+    pub fn version(&self) -> u64 {
+        self.current.get().version
+    }
+
+    /// Replace the config with the value on behalf of the caller. Returns the new version.
+    pub fn update(&mut self, caller: Principal, value: C, timestamp: u64) -> ConfigResult<u64> {
+        if !(self.guard)(&caller) {
+            return Err(ConfigError::Unauthorized(caller.to_string()));
+        }
+        (self.validator)(&value).map_err(ConfigError::InvalidConfig)?;
+
+        let change = ConfigChange {
+            version: self.version() + 1,
+            value,
+            updated_by: caller,
+            timestamp,
+        };
+        self.current
+            .set(VersionedConfig {
+                version: change.version,
+                value: change.value.clone(),
+            })
+            .expect("failed to write config cell");
+        self.history
+            .append(change.clone())
+            .expect("failed to append to config history");
+
+        for hook in &self.hooks {
+            hook(&change);
+        }
+
+        Ok(change.version)
+    }
+
+    /// Change a part of the config on behalf of the caller. Returns the new version.
+    pub fn modify(
+        &mut self,
+        caller: Principal,
+        timestamp: u64,
+        f: impl FnOnce(&mut C),
+    ) -> ConfigResult<u64> {
+        let mut value = self.get().clone();
+        f(&mut value);
+        self.update(caller, value, timestamp)
+    }
+
+    /// The number of the changes in the history.
+    pub fn history_len(&self) -> u64 {
+        self.history.len()
+    }
+
+    /// Up to `limit` changes of the history, starting from the `start` one.
+    pub fn history(&self, start: u64, limit: u64) -> Vec<ConfigChange<C>> {
+        (start..self.history.len().min(start.saturating_add(limit)))
+            .filter_map(|index| self.history.get(index))
+            .collect()
+    }
+}
+
+/// The default guard of the config, which allows only the controllers of the canister.
+fn is_controller(caller: &Principal) -> bool {
+    #[cfg(target_family = "wasm")]
+    {
+        ic_exports::ic_cdk::api::is_controller(caller)
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    {
+        let _ = caller;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    #[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+    struct Settings {
+        fee: u64,
+        paused: bool,
+    }
+
+    const ADMIN: Principal = Principal::from_slice(&[1]);
+    const USER: Principal = Principal::from_slice(&[2]);
+
+    fn settings(
+        memories: &[VectorMemory; 3],
+        last_version: Rc<Cell<u64>>,
+    ) -> Config<Settings, VectorMemory> {
+        Config::new(
+            memories[0].clone(),
+            memories[1].clone(),
+            memories[2].clone(),
+            Settings {
+                fee: 10,
+                paused: false,
+            },
+        )
+        .with_guard(|caller| *caller == ADMIN)
+        .with_validator(|settings| match settings.fee {
+            0..=1000 => Ok(()),
+            fee => Err(format!("fee {fee} is above 1000")),
+        })
+        .on_change(move |change| last_version.set(change.version))
+    }
+
+    #[test]
+    fn changes_are_guarded_and_recorded() {
+        let memories = [
+            VectorMemory::default(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+        ];
+        let last_version = Rc::new(Cell::new(0));
+        let mut config = settings(&memories, last_version.clone());
+        assert_eq!(config.version(), 0);
+
+        assert_eq!(
+            config.modify(USER, 1, |settings| settings.paused = true),
+            Err(ConfigError::Unauthorized(USER.to_string()))
+        );
+        assert!(matches!(
+            config.modify(ADMIN, 1, |settings| settings.fee = 5000),
+            Err(ConfigError::InvalidConfig(_))
+        ));
+        assert_eq!(
+            config.modify(ADMIN, 2, |settings| settings.paused = true),
+            Ok(1)
+        );
+        assert_eq!(last_version.get(), 1);
+
+        // The config and its history are restored from the memory.
+        let mut config = settings(&memories, last_version.clone());
+        assert_eq!(
+            config.get(),
+            &Settings {
+                fee: 10,
+                paused: true
+            }
+        );
+        let fee_change = Settings {
+            fee: 20,
+            paused: true,
+        };
+        assert_eq!(config.update(ADMIN, fee_change.clone(), 3), Ok(2));
+        assert_eq!(last_version.get(), 2);
+        assert_eq!(config.history_len(), 2);
+        assert_eq!(
+            config.history(1, 10),
+            [ConfigChange {
+                version: 2,
+                value: fee_change,
+                updated_by: ADMIN,
+                timestamp: 3,
+            }]
+        );
+    }
+}
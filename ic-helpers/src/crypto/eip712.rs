@@ -0,0 +1,346 @@
+//! Hashing of the EIP-712 typed structured data, and the ERC-2612 style permits signed with it.
+//!
+//! See <https://eips.ethereum.org/EIPS/eip-712>.
+
+use candid::{CandidType, Deserialize};
+
+use super::{keccak256, recover_eth_address, CryptoError, CryptoResult, EthAddress};
+
+/// The domain of the signed messages, which prevents a signature for one application from
+/// being used by another one. The fields which are `None` are not included in the domain.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Eip712Domain {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub chain_id: Option<u64>,
+    pub verifying_contract: Option<EthAddress>,
+    pub salt: Option<[u8; 32]>,
+}
+
+impl Eip712Domain {
+    pub fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            version: Some(version.to_string()),
+            chain_id: None,
+            verifying_contract: None,
+            salt: None,
+        }
+    }
+
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    pub fn with_verifying_contract(mut self, verifying_contract: EthAddress) -> Self {
+        self.verifying_contract = Some(verifying_contract);
+        self
+    }
+
+    pub fn with_salt(mut self, salt: [u8; 32]) -> Self {
+        self.salt = Some(salt);
+        self
+    }
+
+    /// The hash of the domain, included into the hashes of all the messages.
+    pub fn separator(&self) -> [u8; 32] {
+        let mut fields = vec![];
+        let mut values = vec![];
+        if let Some(name) = &self.name {
+            fields.push("string name");
+            values.push(encode_string(name));
+        }
+        if let Some(version) = &self.version {
+            fields.push("string version");
+            values.push(encode_string(version));
+        }
+        if let Some(chain_id) = self.chain_id {
+            fields.push("uint256 chainId");
+            values.push(encode_uint(chain_id as u128));
+        }
+        if let Some(verifying_contract) = &self.verifying_contract {
+            fields.push("address verifyingContract");
+            values.push(encode_address(verifying_contract));
+        }
+        if let Some(salt) = self.salt {
+            fields.push("bytes32 salt");
+            values.push(salt);
+        }
+
+        let type_hash = keccak256(format!("EIP712Domain({})", fields.join(",")).as_bytes());
+        hash_struct(type_hash, &values)
+    }
+
+    /// The hash of the message to sign.
+    pub fn signing_hash(&self, message: &impl Eip712Struct) -> [u8; 32] {
+        let mut data = Vec::with_capacity(66);
+        data.extend_from_slice(b"\x19\x01");
+        data.extend_from_slice(&self.separator());
+        data.extend_from_slice(&message.struct_hash());
+        keccak256(&data)
+    }
+
+    /// Recover the address of the account which signed the message in the domain.
+    pub fn recover_signer(
+        &self,
+        message: &impl Eip712Struct,
+        signature: &[u8],
+    ) -> CryptoResult<EthAddress> {
+        recover_eth_address(&self.signing_hash(message), signature)
+    }
+}
+
+/// A struct, which can be signed as EIP-712 typed data.
+pub trait Eip712Struct {
+    /// The encoded type of the struct, followed by the types it references sorted by name, e.g.
+    /// `Mail(Person from,Person to,string contents)Person(string name,address wallet)`.
+    const TYPE: &'static str;
+
+    /// The encoded values of the fields in the order of the type.
+    fn encode_fields(&self) -> Vec<[u8; 32]>;
+
+    fn struct_hash(&self) -> [u8; 32] {
+        hash_struct(keccak256(Self::TYPE.as_bytes()), &self.encode_fields())
+    }
+}
+
+fn hash_struct(type_hash: [u8; 32], values: &[[u8; 32]]) -> [u8; 32] {
+    let mut data = Vec::with_capacity(32 * (values.len() + 1));
+    data.extend_from_slice(&type_hash);
+    for value in values {
+        data.extend_from_slice(value);
+    }
+    keccak256(&data)
+}
+
+/// Encode a `uint256` value.
+pub fn encode_uint(value: u128) -> [u8; 32] {
+    let mut encoded = [0; 32];
+    encoded[16..].copy_from_slice(&value.to_be_bytes());
+    encoded
+}
+
+pub fn encode_address(address: &EthAddress) -> [u8; 32] {
+    let mut encoded = [0; 32];
+    encoded[12..].copy_from_slice(address);
+    encoded
+}
+
+pub fn encode_bool(value: bool) -> [u8; 32] {
+    encode_uint(value as u128)
+}
+
+/// Encode a `string` value, which is hashed.
+pub fn encode_string(value: &str) -> [u8; 32] {
+    keccak256(value.as_bytes())
+}
+
+/// Encode a dynamic `bytes` value, which is hashed.
+pub fn encode_bytes(value: &[u8]) -> [u8; 32] {
+    keccak256(value)
+}
+
+/// An approval of the owner for the spender to transfer the value, signed off-chain by the owner
+/// as in ERC-2612.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Permit {
+    pub owner: EthAddress,
+    pub spender: EthAddress,
+    /// The big-endian `uint256` value, so that any value signed by the owner can be verified.
+    /// Use [`encode_uint`] for the values which fit into `u128`.
+    pub value: [u8; 32],
+    /// Must be the next nonce of the owner, so a permit can't be used twice.
+    pub nonce: u64,
+    /// The time in seconds after which the permit can't be used.
+    pub deadline: u64,
+}
+
+impl Eip712Struct for Permit {
+    const TYPE: &'static str =
+        "Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)";
+
+    fn encode_fields(&self) -> Vec<[u8; 32]> {
+        vec![
+            encode_address(&self.owner),
+            encode_address(&self.spender),
+            self.value,
+            encode_uint(self.nonce as u128),
+            encode_uint(self.deadline as u128),
+        ]
+    }
+}
+
+impl Permit {
+    /// Verify that the permit is signed by its owner in the domain and is not expired at
+    /// `now_secs`.
+    ///
+    /// The nonce is checked by the caller, which must also mark it used.
+    pub fn verify(
+        &self,
+        domain: &Eip712Domain,
+        signature: &[u8],
+        now_secs: u64,
+    ) -> CryptoResult<()> {
+        if now_secs > self.deadline {
+            return Err(CryptoError::PermitExpired(self.deadline));
+        }
+
+        let signer = domain.recover_signer(self, signature)?;
+        if signer != self.owner {
+            return Err(CryptoError::WrongSigner {
+                expected: hex_address(&self.owner),
+                actual: hex_address(&signer),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn hex_address(address: &EthAddress) -> String {
+    address
+        .iter()
+        .fold(String::from("0x"), |hex, byte| hex + &format!("{byte:02x}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use k256::ecdsa::SigningKey;
+
+    use super::*;
+    use crate::crypto::eth_address;
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    struct Person {
+        name: &'static str,
+        wallet: EthAddress,
+    }
+
+    impl Eip712Struct for Person {
+        const TYPE: &'static str = "Person(string name,address wallet)";
+
+        fn encode_fields(&self) -> Vec<[u8; 32]> {
+            vec![encode_string(self.name), encode_address(&self.wallet)]
+        }
+    }
+
+    struct Mail {
+        from: Person,
+        to: Person,
+        contents: &'static str,
+    }
+
+    impl Eip712Struct for Mail {
+        const TYPE: &'static str =
+            "Mail(Person from,Person to,string contents)Person(string name,address wallet)";
+
+        fn encode_fields(&self) -> Vec<[u8; 32]> {
+            vec![
+                self.from.struct_hash(),
+                self.to.struct_hash(),
+                encode_string(self.contents),
+            ]
+        }
+    }
+
+    // The example of the EIP-712 specification.
+    #[test]
+    fn mail_example() {
+        let domain = Eip712Domain::new("Ether Mail", "1")
+            .with_chain_id(1)
+            .with_verifying_contract(bytes("CcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"));
+        let cow = bytes("CD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826");
+        let mail = Mail {
+            from: Person {
+                name: "Cow",
+                wallet: cow,
+            },
+            to: Person {
+                name: "Bob",
+                wallet: bytes("bBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB"),
+            },
+            contents: "Hello, Bob!",
+        };
+
+        assert_eq!(
+            domain.separator(),
+            bytes("f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f")
+        );
+        assert_eq!(
+            mail.struct_hash(),
+            bytes("c52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e")
+        );
+        assert_eq!(
+            domain.signing_hash(&mail),
+            bytes("be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2")
+        );
+
+        let signature: [u8; 65] = bytes(concat!(
+            "4355c47d63924e8a72e509b65029052eb6c299d53a04e167c5775fd466751c9d",
+            "07299936d304c153f6443dfa05f40ff007d72911b6f72307f996231605b91562",
+            "1c"
+        ));
+        assert_eq!(domain.recover_signer(&mail, &signature), Ok(cow));
+    }
+
+    #[test]
+    fn permit_is_verified() {
+        let signing_key = SigningKey::from_bytes(&[9; 32].into()).unwrap();
+        let domain = Eip712Domain::new("Token", "1").with_chain_id(1);
+        let permit = Permit {
+            owner: eth_address(signing_key.verifying_key()),
+            spender: [2; 20],
+            value: encode_uint(1_000),
+            nonce: 0,
+            deadline: 100,
+        };
+        let (signature, recovery_id) = signing_key
+            .sign_prehash_recoverable(&domain.signing_hash(&permit))
+            .unwrap();
+        let mut signature = signature.to_bytes().to_vec();
+        signature.push(recovery_id.to_byte());
+
+        permit.verify(&domain, &signature, 100).unwrap();
+        assert_eq!(
+            permit.verify(&domain, &signature, 101),
+            Err(CryptoError::PermitExpired(100))
+        );
+        assert!(matches!(
+            permit.verify(&domain.clone().with_chain_id(2), &signature, 0),
+            Err(CryptoError::WrongSigner { .. })
+        ));
+
+        let forged = Permit {
+            value: encode_uint(1_000_000),
+            ..permit
+        };
+        assert!(forged.verify(&domain, &signature, 0).is_err());
+    }
+
+    #[test]
+    fn permit_value_is_encoded_as_uint256() {
+        let mut value = [0; 32];
+        value[0] = 1;
+        let permit = Permit {
+            owner: [1; 20],
+            spender: [2; 20],
+            value,
+            nonce: 0,
+            deadline: 100,
+        };
+
+        assert_eq!(permit.encode_fields()[2], value);
+        assert_ne!(
+            permit.struct_hash(),
+            Permit {
+                value: encode_uint(0),
+                ..permit.clone()
+            }
+            .struct_hash()
+        );
+    }
+}
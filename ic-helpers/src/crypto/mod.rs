@@ -0,0 +1,181 @@
+//! Verification of the signatures made off-chain, e.g. for the gasless operations where a user
+//! signs a message and a relayer submits it to the canister.
+//!
+//! All the primitives are implemented in pure Rust, so they work in wasm canisters.
+
+pub mod eip712;
+
+use candid::{CandidType, Deserialize, Principal};
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+use thiserror::Error;
+
+pub use self::eip712::{Eip712Domain, Eip712Struct, Permit};
+
+/// The 20 bytes address of an Ethereum account.
+pub type EthAddress = [u8; 20];
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum CryptoError {
+    #[error("invalid public key: {0}")]
+    InvalidPublicKey(String),
+
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+
+    #[error("signature verification failed")]
+    VerificationFailed,
+
+    #[error("the message is signed by {actual} instead of {expected}")]
+    WrongSigner { expected: String, actual: String },
+
+    #[error("the permit expired at {0}")]
+    PermitExpired(u64),
+}
+
+pub type CryptoResult<T> = Result<T, CryptoError>;
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// Verify the secp256k1 ECDSA `signature` of the message hash.
+///
+/// The public key is SEC1 encoded, compressed or not, and the signature is 64 bytes `r || s`
+/// with the low `s`.
+pub fn verify_secp256k1(
+    public_key: &[u8],
+    message_hash: &[u8; 32],
+    signature: &[u8],
+) -> CryptoResult<()> {
+    let public_key = VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|err| CryptoError::InvalidPublicKey(err.to_string()))?;
+    let signature = Signature::from_slice(signature)
+        .map_err(|err| CryptoError::InvalidSignature(err.to_string()))?;
+
+    public_key
+        .verify_prehash(message_hash, &signature)
+        .map_err(|_| CryptoError::VerificationFailed)
+}
+
+/// Recover the address of the Ethereum account which signed the message hash.
+///
+/// The signature is 65 bytes `r || s || v`, where `v` is either `0`/`1` or `27`/`28`.
+pub fn recover_eth_address(message_hash: &[u8; 32], signature: &[u8]) -> CryptoResult<EthAddress> {
+    if signature.len() != 65 {
+        return Err(CryptoError::InvalidSignature(format!(
+            "expected 65 bytes, got {}",
+            signature.len()
+        )));
+    }
+
+    let recovery_id = match signature[64] {
+        v @ (0 | 1) => v,
+        v @ (27 | 28) => v - 27,
+        v => {
+            return Err(CryptoError::InvalidSignature(format!(
+                "invalid recovery id {v}"
+            )))
+        }
+    };
+    let recovery_id = RecoveryId::from_byte(recovery_id).expect("recovery id is 0 or 1");
+    let signature = Signature::from_slice(&signature[..64])
+        .map_err(|err| CryptoError::InvalidSignature(err.to_string()))?;
+
+    let public_key = VerifyingKey::recover_from_prehash(message_hash, &signature, recovery_id)
+        .map_err(|_| CryptoError::VerificationFailed)?;
+    Ok(eth_address(&public_key))
+}
+
+/// The address of the Ethereum account with the public key.
+pub fn eth_address(public_key: &VerifyingKey) -> EthAddress {
+    let point = public_key.to_encoded_point(false);
+    let hash = keccak256(&point.as_bytes()[1..]);
+    hash[12..].try_into().expect("address is 20 bytes")
+}
+
+/// Verify the ed25519 `signature` of the message.
+///
+/// The signatures with a non canonical `s` or made with a weak public key are rejected.
+pub fn verify_ed25519(public_key: &[u8], message: &[u8], signature: &[u8]) -> CryptoResult<()> {
+    let public_key = public_key
+        .try_into()
+        .ok()
+        .and_then(|bytes| ed25519_dalek::VerifyingKey::from_bytes(bytes).ok())
+        .ok_or_else(|| CryptoError::InvalidPublicKey("expected 32 bytes ed25519 key".into()))?;
+    let signature = ed25519_dalek::Signature::from_slice(signature)
+        .map_err(|err| CryptoError::InvalidSignature(err.to_string()))?;
+
+    public_key
+        .verify_strict(message, &signature)
+        .map_err(|_| CryptoError::VerificationFailed)
+}
+
+/// The self-authenticating principal of the ed25519 public key, which is the caller of the
+/// messages signed with the key.
+pub fn ed25519_principal(public_key: &[u8; 32]) -> Principal {
+    const DER_PREFIX: [u8; 12] = [
+        0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+    ];
+
+    let mut der = DER_PREFIX.to_vec();
+    der.extend_from_slice(public_key);
+    Principal::self_authenticating(der)
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Signer;
+    use k256::ecdsa::SigningKey;
+
+    use super::*;
+
+    #[test]
+    fn secp256k1_signatures() {
+        let signing_key = SigningKey::from_bytes(&[7; 32].into()).unwrap();
+        let hash = keccak256(b"transfer 100");
+        let (signature, recovery_id) = signing_key.sign_prehash_recoverable(&hash).unwrap();
+        let public_key = signing_key.verifying_key().to_sec1_bytes();
+
+        verify_secp256k1(&public_key, &hash, &signature.to_bytes()).unwrap();
+        assert_eq!(
+            verify_secp256k1(
+                &public_key,
+                &keccak256(b"transfer 1000"),
+                &signature.to_bytes()
+            ),
+            Err(CryptoError::VerificationFailed)
+        );
+
+        let mut eth_signature = signature.to_bytes().to_vec();
+        eth_signature.push(27 + recovery_id.to_byte());
+        assert_eq!(
+            recover_eth_address(&hash, &eth_signature),
+            Ok(eth_address(signing_key.verifying_key()))
+        );
+        assert!(recover_eth_address(&hash, &eth_signature[..64]).is_err());
+    }
+
+    #[test]
+    fn ed25519_signatures() {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        let public_key = signing_key.verifying_key().to_bytes();
+        let signature = signing_key.sign(b"approve 10").to_bytes();
+
+        verify_ed25519(&public_key, b"approve 10", &signature).unwrap();
+        assert_eq!(
+            verify_ed25519(&public_key, b"approve 11", &signature),
+            Err(CryptoError::VerificationFailed)
+        );
+        assert!(matches!(
+            verify_ed25519(&public_key[1..], b"approve 10", &signature),
+            Err(CryptoError::InvalidPublicKey(_))
+        ));
+
+        let principal = ed25519_principal(&public_key);
+        assert_eq!(principal.as_slice().len(), 29);
+        // The tag of the self-authenticating principals.
+        assert_eq!(principal.as_slice()[28], 2);
+    }
+}
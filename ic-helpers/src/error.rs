@@ -0,0 +1,205 @@
+//! The error type shared by the crates of the SDK.
+//!
+//! Every crate converts its own errors into [`SdkError`], so that a canister using several
+//! crates can return one error type from its endpoints:
+//!
+//! ```ignore
+//! #[update]
+//! async fn deposit(&self, amount: Nat) -> SdkResult<u32> {
+//!     let (amount, _) = terminal.deposit(from, amount).await?;
+//!     Ok(scheduler.append_task(Task::Credit(amount).into()))
+//! }
+//! ```
+//!
+//! The endpoints with the manual reply reject the calls with the errors instead of trapping, see
+//! [`reply_or_reject`]. The reject message carries the error, so that the clients get it back
+//! with [`SdkError::from_reject`].
+
+use candid::CandidType;
+use ic_exports::ic_cdk::api::call;
+use ic_exports::ic_kit::RejectionCode;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::replication::ReplicationError;
+
+/// The prefix of the reject messages carrying an [`SdkError`].
+const REJECT_MESSAGE_PREFIX: &str = "sdk-error:";
+
+#[derive(Error, CandidType, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SdkError {
+    /// The arguments of the call are invalid, the call should not be retried with them.
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("stable structure error: {0}")]
+    Structure(String),
+
+    #[error("scheduler error: {0}")]
+    Scheduler(String),
+
+    /// The call was rejected, the code is the one of the [`RejectionCode`].
+    #[error("canister call rejected with code {code}: {message}")]
+    CallRejected { code: i32, message: String },
+
+    /// The call failed without a reject, e.g. because of a time out or of an invalid reply.
+    #[error("canister call failed: {0}")]
+    CallFailed(String),
+
+    #[error("payment error: {0}")]
+    Payment(String),
+
+    #[error("{0}")]
+    Other(String),
+}
+
+pub type SdkResult<T> = Result<T, SdkError>;
+
+impl SdkError {
+    /// The code to reject the call with.
+    ///
+    /// The errors of the caller and of the business logic are [`RejectionCode::CanisterReject`],
+    /// the errors of the stable memory are [`RejectionCode::CanisterError`] and the rejects of
+    /// the downstream calls keep their codes.
+    pub fn reject_code(&self) -> RejectionCode {
+        match self {
+            Self::Structure(_) => RejectionCode::CanisterError,
+            Self::CallRejected { code, .. } => rejection_code(*code),
+            Self::CallFailed(_) => RejectionCode::SysTransient,
+            Self::InvalidArgument(_) | Self::Scheduler(_) | Self::Payment(_) | Self::Other(_) => {
+                RejectionCode::CanisterReject
+            }
+        }
+    }
+
+    /// The message to reject the call with, carrying the error as JSON.
+    pub fn reject_message(&self) -> String {
+        let error = serde_json::to_string(self).expect("failed to serialize the error");
+        format!("{REJECT_MESSAGE_PREFIX}{error}")
+    }
+
+    /// Returns the error carried by the reject message, or [`SdkError::CallRejected`] if the
+    /// message doesn't carry an error.
+    ///
+    /// The message may be the one of a trap, e.g. `Canister ... trapped explicitly: sdk-error:..`.
+    pub fn from_reject(code: RejectionCode, message: &str) -> Self {
+        Self::from_reject_message(message).unwrap_or_else(|| Self::CallRejected {
+            code: code as i32,
+            message: message.to_string(),
+        })
+    }
+
+    /// Returns the error carried by the reject message, if any.
+    pub fn from_reject_message(message: &str) -> Option<Self> {
+        let (_, error) = message.split_once(REJECT_MESSAGE_PREFIX)?;
+        serde_json::from_str(error).ok()
+    }
+
+    /// Reject the current call with the error. Only for the endpoints with the manual reply.
+    pub fn reject(&self) {
+        call::reject(&self.reject_message());
+    }
+}
+
+/// Reply to the current call with the value, or reject it with the error.
+/// Only for the endpoints with the manual reply.
+pub fn reply_or_reject<T: CandidType>(result: SdkResult<T>) {
+    match result {
+        Ok(value) => call::reply((value,)),
+        Err(error) => error.reject(),
+    }
+}
+
+fn rejection_code(code: i32) -> RejectionCode {
+    match code {
+        0 => RejectionCode::NoError,
+        1 => RejectionCode::SysFatal,
+        2 => RejectionCode::SysTransient,
+        3 => RejectionCode::DestinationInvalid,
+        4 => RejectionCode::CanisterReject,
+        5 => RejectionCode::CanisterError,
+        _ => RejectionCode::Unknown,
+    }
+}
+
+impl From<ic_stable_structures::Error> for SdkError {
+    fn from(error: ic_stable_structures::Error) -> Self {
+        Self::Structure(error.to_string())
+    }
+}
+
+impl From<(RejectionCode, String)> for SdkError {
+    fn from((code, message): (RejectionCode, String)) -> Self {
+        Self::CallRejected {
+            code: code as i32,
+            message,
+        }
+    }
+}
+
+impl From<ReplicationError> for SdkError {
+    fn from(error: ReplicationError) -> Self {
+        match error {
+            ReplicationError::CallFailed(message) => Self::CallFailed(message),
+            error => Self::Other(error.to_string()),
+        }
+    }
+}
+
+impl From<candid::Error> for SdkError {
+    fn from(error: candid::Error) -> Self {
+        Self::CallFailed(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_converted_and_encoded() {
+        let error = SdkError::from((RejectionCode::CanisterReject, "no funds".to_string()));
+        assert_eq!(
+            error,
+            SdkError::CallRejected {
+                code: 4,
+                message: "no funds".to_string()
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "canister call rejected with code 4: no funds"
+        );
+
+        let error = SdkError::from(ic_stable_structures::Error::OutOfStableMemory);
+        let encoded = candid::encode_one(&error).unwrap();
+        assert_eq!(candid::decode_one::<SdkError>(&encoded).unwrap(), error);
+    }
+
+    #[test]
+    fn errors_are_carried_by_rejects() {
+        let error = SdkError::InvalidArgument("amount is zero".to_string());
+        assert_eq!(error.reject_code(), RejectionCode::CanisterReject);
+
+        let message = error.reject_message();
+        assert_eq!(
+            SdkError::from_reject(RejectionCode::CanisterReject, &message),
+            error
+        );
+        let trap = format!("Canister aaaaa-aa trapped explicitly: {message}");
+        assert_eq!(
+            SdkError::from_reject(RejectionCode::CanisterError, &trap),
+            error
+        );
+
+        let rejected = SdkError::from_reject(RejectionCode::SysTransient, "out of cycles");
+        assert_eq!(rejected.reject_code(), RejectionCode::SysTransient);
+        assert_eq!(
+            rejected,
+            SdkError::CallRejected {
+                code: 2,
+                message: "out of cycles".to_string()
+            }
+        );
+    }
+}
@@ -9,3 +9,28 @@ pub mod types;
 pub use types::*;
 
 pub mod tokens;
+
+pub mod config;
+
+pub mod error;
+pub use error::{SdkError, SdkResult};
+
+pub mod crypto;
+
+pub mod nonce;
+
+pub mod randomness;
+
+pub mod rate_limit;
+
+pub mod read_replica;
+
+pub mod replication;
+
+pub mod retry;
+
+pub mod schema;
+
+pub mod state_machine;
+
+pub mod upgrade;
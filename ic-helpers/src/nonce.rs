@@ -0,0 +1,237 @@
+//! Replay protection of the signed messages and of the cross-chain relays, with the nonces of
+//! the callers kept in stable memory.
+
+use std::borrow::Cow;
+
+use candid::Principal;
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, IterableSortedMapStructure, StableBTreeMap, Storable,
+};
+use thiserror::Error;
+
+/// The number of the nonces below the highest nonce of a caller which are still accepted.
+pub const NONCE_WINDOW: u64 = 128;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum NonceError {
+    #[error("the nonce {0} was already used")]
+    Replayed(u64),
+
+    #[error("the nonce {nonce} is out of the window, the lowest accepted nonce is {lowest}")]
+    TooOld { nonce: u64, lowest: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct CallerKey(Principal);
+
+impl Storable for CallerKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(Principal::from_slice(&bytes))
+    }
+}
+
+/// The nonces of a caller seen in the window below the highest one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NonceWindow {
+    highest: u64,
+    /// The bit `i` is set if the nonce `highest - i` was seen.
+    seen: u128,
+    last_seen_secs: u64,
+}
+
+impl Storable for NonceWindow {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(32);
+        bytes.extend_from_slice(&self.highest.to_le_bytes());
+        bytes.extend_from_slice(&self.seen.to_le_bytes());
+        bytes.extend_from_slice(&self.last_seen_secs.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self {
+            highest: u64::from_le_bytes(bytes[0..8].try_into().expect("window has a nonce")),
+            seen: u128::from_le_bytes(bytes[8..24].try_into().expect("window has a bitmap")),
+            last_seen_secs: u64::from_le_bytes(
+                bytes[24..32].try_into().expect("window has a timestamp"),
+            ),
+        }
+    }
+}
+
+/// Rejects the nonces used before by the same caller.
+///
+/// The store keeps a sliding window of the last [`NONCE_WINDOW`] nonces of every caller in a
+/// bitmap, so the nonces may arrive out of order within the window, and the older nonces are
+/// rejected. The memory used per caller is fixed, and the callers not seen for the TTL are
+/// removed by [`NonceStore::prune`]. A removed caller can use any nonce again, so the messages
+/// must expire before the TTL, e.g. with an expiry signed together with the nonce.
+///
+/// ```
+/// use candid::Principal;
+/// use ic_helpers::nonce::{NonceError, NonceStore};
+/// use ic_stable_structures::VectorMemory;
+///
+/// let mut nonces = NonceStore::new(VectorMemory::default(), 3600);
+/// let caller = Principal::anonymous();
+///
+/// assert_eq!(nonces.check_and_record(caller, 5, 0), Ok(()));
+/// assert_eq!(nonces.check_and_record(caller, 3, 0), Ok(()));
+/// assert_eq!(nonces.check_and_record(caller, 5, 0), Err(NonceError::Replayed(5)));
+/// ```
+pub struct NonceStore<M: Memory> {
+    windows: StableBTreeMap<CallerKey, NonceWindow, M>,
+    ttl_secs: u64,
+}
+
+impl<M: Memory> NonceStore<M> {
+    /// Create the store in the memory. If the memory contains a store, its nonces are kept.
+    pub fn new(memory: M, ttl_secs: u64) -> Self {
+        Self {
+            windows: StableBTreeMap::new(memory),
+            ttl_secs,
+        }
+    }
+
+    /// Record the nonce of the caller. Returns an error without recording it if the nonce was
+    /// already used or is below the window of the caller.
+    pub fn check_and_record(
+        &mut self,
+        caller: Principal,
+        nonce: u64,
+        now_secs: u64,
+    ) -> Result<(), NonceError> {
+        let key = CallerKey(caller);
+        let window = match self.windows.get(&key) {
+            None => NonceWindow {
+                highest: nonce,
+                seen: 1,
+                last_seen_secs: now_secs,
+            },
+            Some(window) if nonce > window.highest => {
+                let shift = nonce - window.highest;
+                let seen = if shift >= NONCE_WINDOW {
+                    0
+                } else {
+                    window.seen << shift
+                };
+                NonceWindow {
+                    highest: nonce,
+                    seen: seen | 1,
+                    last_seen_secs: now_secs,
+                }
+            }
+            Some(window) => {
+                let offset = window.highest - nonce;
+                if offset >= NONCE_WINDOW {
+                    return Err(NonceError::TooOld {
+                        nonce,
+                        lowest: window.highest - (NONCE_WINDOW - 1),
+                    });
+                }
+                if window.seen & (1u128 << offset) != 0 {
+                    return Err(NonceError::Replayed(nonce));
+                }
+                NonceWindow {
+                    seen: window.seen | (1u128 << offset),
+                    last_seen_secs: now_secs,
+                    ..window
+                }
+            }
+        };
+
+        self.windows.insert(key, window);
+        Ok(())
+    }
+
+    /// Returns the highest nonce recorded for the caller.
+    pub fn highest_nonce(&self, caller: Principal) -> Option<u64> {
+        self.windows
+            .get(&CallerKey(caller))
+            .map(|window| window.highest)
+    }
+
+    /// Remove at most `max_callers` callers not seen for the TTL, and return their number.
+    pub fn prune(&mut self, now_secs: u64, max_callers: usize) -> usize {
+        let expired = self
+            .windows
+            .iter()
+            .filter(|(_, window)| window.last_seen_secs.saturating_add(self.ttl_secs) < now_secs)
+            .map(|(key, _)| key)
+            .take(max_callers)
+            .collect::<Vec<_>>();
+        for key in &expired {
+            self.windows.remove(key);
+        }
+        expired.len()
+    }
+
+    /// The number of the callers in the store.
+    pub fn len(&self) -> u64 {
+        self.windows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    #[test]
+    fn replays_are_rejected_in_the_window() {
+        let mut nonces = NonceStore::new(VectorMemory::default(), 100);
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2; 29]);
+
+        assert_eq!(nonces.check_and_record(alice, 10, 0), Ok(()));
+        assert_eq!(nonces.check_and_record(bob, 10, 0), Ok(()));
+        assert_eq!(
+            nonces.check_and_record(alice, 10, 1),
+            Err(NonceError::Replayed(10))
+        );
+
+        assert_eq!(nonces.check_and_record(alice, 200, 2), Ok(()));
+        assert_eq!(nonces.check_and_record(alice, 73, 3), Ok(()));
+        assert_eq!(
+            nonces.check_and_record(alice, 73, 3),
+            Err(NonceError::Replayed(73))
+        );
+        assert_eq!(
+            nonces.check_and_record(alice, 72, 4),
+            Err(NonceError::TooOld {
+                nonce: 72,
+                lowest: 73
+            })
+        );
+        assert_eq!(nonces.check_and_record(alice, 201, 5), Ok(()));
+        assert_eq!(
+            nonces.check_and_record(alice, 200, 5),
+            Err(NonceError::Replayed(200))
+        );
+        assert_eq!(nonces.highest_nonce(alice), Some(201));
+
+        assert_eq!(nonces.prune(101, 10), 1);
+        assert_eq!(nonces.highest_nonce(bob), None);
+        assert_eq!(nonces.len(), 1);
+    }
+}
@@ -0,0 +1,213 @@
+//! Random values for the canister, generated from the entropy of the management canister
+//! `raw_rand` method, fetched once per round and kept in stable memory.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use ic_exports::ic_kit::rand::{raw_rand, Rand};
+use ic_exports::ic_kit::{CallResult, RejectionCode};
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{Bound, CellStructure, StableCell, Storable};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RandomnessError {
+    #[error("the entropy was never fetched")]
+    NoEntropy,
+}
+
+/// The entropy of a round, stretched by the generator.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Entropy {
+    round: u64,
+    /// The state of the generator, `None` until the entropy is fetched.
+    state: Option<[u8; 32]>,
+}
+
+impl Storable for Entropy {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 41,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(41);
+        bytes.push(self.state.is_some() as u8);
+        bytes.extend_from_slice(&self.round.to_le_bytes());
+        bytes.extend_from_slice(&self.state.unwrap_or_default());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self {
+            round: u64::from_le_bytes(bytes[1..9].try_into().expect("entropy has a round")),
+            state: (bytes[0] == 1).then(|| bytes[9..41].try_into().expect("entropy has a state")),
+        }
+    }
+}
+
+/// Fetch 32 bytes of entropy from the `raw_rand` method of the management canister.
+pub async fn fetch_entropy() -> CallResult<[u8; 32]> {
+    raw_rand().await?.try_into().map_err(|bytes: Vec<u8>| {
+        (
+            RejectionCode::CanisterError,
+            format!("raw_rand returned {} bytes instead of 32", bytes.len()),
+        )
+    })
+}
+
+/// A random number generator seeded with the entropy of the current round, e.g. an hour.
+///
+/// Instead of calling the management canister for every random value, the canister fetches
+/// the entropy when the round changes, and the generator stretches it into any number of
+/// values. The state of the generator is kept in a stable cell, so the generator continues
+/// its sequence after an upgrade. The values are not cryptographically secure, and until the
+/// entropy of the new round is fetched, they are generated from the entropy of the previous
+/// one.
+///
+/// ```
+/// use ic_helpers::randomness::Randomness;
+/// use ic_stable_structures::VectorMemory;
+///
+/// const HOUR: u64 = 3_600_000_000_000;
+///
+/// let mut randomness = Randomness::new(VectorMemory::default(), HOUR);
+/// assert!(randomness.needs_entropy(0));
+///
+/// // let entropy = fetch_entropy().await?;
+/// randomness.set_entropy(0, [7; 32]);
+/// assert!(!randomness.needs_entropy(HOUR - 1));
+///
+/// let dice = randomness.gen_range(1..7).unwrap();
+/// assert!((1..7).contains(&dice));
+/// ```
+pub struct Randomness<M: Memory> {
+    round_nanos: u64,
+    entropy: StableCell<Entropy, M>,
+}
+
+impl<M: Memory> Randomness<M> {
+    /// Create the generator in the memory, with the entropy fetched every `round_nanos`. If the
+    /// memory contains a generator, it is kept.
+    ///
+    /// # Panics
+    /// If the round is zero.
+    pub fn new(memory: M, round_nanos: u64) -> Self {
+        assert!(round_nanos > 0, "Randomness round must not be zero.");
+        Self {
+            round_nanos,
+            entropy: StableCell::new(memory, Entropy::default())
+                .expect("failed to init randomness cell"),
+        }
+    }
+
+    /// Whether the entropy of the round of `now` must be fetched.
+    pub fn needs_entropy(&self, now: u64) -> bool {
+        let entropy = self.entropy.get();
+        entropy.state.is_none() || entropy.round != now / self.round_nanos
+    }
+
+    /// Seed the generator with the entropy of the round of `now`, returned by
+    /// [`fetch_entropy`].
+    pub fn set_entropy(&mut self, now: u64, entropy: [u8; 32]) {
+        self.set(Entropy {
+            round: now / self.round_nanos,
+            state: Some(Rand::from_seed(entropy).to_seed()),
+        });
+    }
+
+    /// Return a random number uniformly distributed in the range.
+    ///
+    /// # Panics
+    /// If the range is empty.
+    pub fn gen_range(&mut self, range: Range<u64>) -> Result<u64, RandomnessError> {
+        self.with_rand(|rand| rand.gen_range(range))
+    }
+
+    /// Shuffle the slice in place.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) -> Result<(), RandomnessError> {
+        self.with_rand(|rand| rand.shuffle(items))
+    }
+
+    /// Return `amount` distinct random elements of the slice in a random order, or all of them
+    /// if the slice is shorter.
+    pub fn sample<T: Clone>(
+        &mut self,
+        items: &[T],
+        amount: usize,
+    ) -> Result<Vec<T>, RandomnessError> {
+        self.with_rand(|rand| {
+            let mut indices: Vec<usize> = (0..items.len()).collect();
+            let amount = amount.min(items.len());
+            for i in 0..amount {
+                let j = rand.gen_range(i as u64..indices.len() as u64) as usize;
+                indices.swap(i, j);
+            }
+
+            indices[..amount]
+                .iter()
+                .map(|index| items[*index].clone())
+                .collect()
+        })
+    }
+
+    /// Fill the buffer with random bytes.
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) -> Result<(), RandomnessError> {
+        self.with_rand(|rand| rand.fill_bytes(buf))
+    }
+
+    fn with_rand<R>(&mut self, f: impl FnOnce(&mut Rand) -> R) -> Result<R, RandomnessError> {
+        let entropy = *self.entropy.get();
+        let mut rand = Rand::from_seed(entropy.state.ok_or(RandomnessError::NoEntropy)?);
+        let result = f(&mut rand);
+        self.set(Entropy {
+            state: Some(rand.to_seed()),
+            ..entropy
+        });
+        Ok(result)
+    }
+
+    fn set(&mut self, entropy: Entropy) {
+        self.entropy
+            .set(entropy)
+            .expect("failed to write randomness cell");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    #[test]
+    fn generator_is_kept_in_memory() {
+        let memory = VectorMemory::default();
+        let mut randomness = Randomness::new(memory.clone(), 10);
+        assert_eq!(randomness.gen_range(0..10), Err(RandomnessError::NoEntropy));
+
+        randomness.set_entropy(25, [1; 32]);
+        assert!(!randomness.needs_entropy(29));
+        assert!(randomness.needs_entropy(30));
+
+        let first = randomness.gen_range(0..1_000_000).unwrap();
+        let mut expected = Rand::from_seed([1; 32]);
+        assert_eq!(first, expected.gen_range(0..1_000_000));
+
+        // The reopened generator continues the sequence.
+        let mut randomness = Randomness::new(memory, 10);
+        assert!(!randomness.needs_entropy(29));
+        assert_eq!(
+            randomness.gen_range(0..1_000_000),
+            Ok(expected.gen_range(0..1_000_000))
+        );
+
+        let items: Vec<u32> = (0..20).collect();
+        let mut sample = randomness.sample(&items, 5).unwrap();
+        assert_eq!(sample.len(), 5);
+        sample.sort();
+        sample.dedup();
+        assert_eq!(sample.len(), 5);
+        assert_eq!(randomness.sample(&items[..3], 5).unwrap().len(), 3);
+    }
+}
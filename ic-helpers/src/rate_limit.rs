@@ -0,0 +1,487 @@
+//! The rate limiting algorithms, shared by the components of the canister so that they throttle
+//! consistently.
+//!
+//! A [`RateLimit`] updates the [`LimiterState`] of a key, wherever the state is kept. The
+//! [`RateLimiter`] keeps the states of the keys in stable memory, so the limits continue after
+//! an upgrade, while e.g. the triggers of the task scheduler keep the states in the heap.
+
+use std::borrow::Cow;
+
+use candid::CandidType;
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, IterableSortedMapStructure, StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum RateLimitError {
+    #[error("the rate limit is exceeded, retry after {retry_after_secs} seconds")]
+    Limited { retry_after_secs: u64 },
+
+    #[error("{requested} permits exceed the limit of {max} permits")]
+    ExceedsLimit { requested: u32, max: u32 },
+}
+
+/// A rate limiting algorithm.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimit {
+    /// At most `capacity` permits at once, refilled by `refill` permits every `interval_secs`.
+    TokenBucket {
+        capacity: u32,
+        refill: u32,
+        interval_secs: u64,
+    },
+    /// At most `max_permits` permits in any `window_secs`, estimated from the permits of the
+    /// current and of the previous window.
+    SlidingWindow { max_permits: u32, window_secs: u64 },
+}
+
+/// The state of a [`RateLimit`] for a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimiterState {
+    TokenBucket {
+        tokens: u32,
+        last_refill_secs: u64,
+    },
+    SlidingWindow {
+        window_start_secs: u64,
+        current: u32,
+        previous: u32,
+    },
+}
+
+impl Storable for LimiterState {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 17,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let (tag, timestamp, first, second) = match *self {
+            Self::TokenBucket {
+                tokens,
+                last_refill_secs,
+            } => (0, last_refill_secs, tokens, 0),
+            Self::SlidingWindow {
+                window_start_secs,
+                current,
+                previous,
+            } => (1, window_start_secs, current, previous),
+        };
+        let mut bytes = Vec::with_capacity(17);
+        bytes.push(tag);
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes.extend_from_slice(&first.to_le_bytes());
+        bytes.extend_from_slice(&second.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let timestamp = u64::from_le_bytes(bytes[1..9].try_into().expect("state has a timestamp"));
+        let first = u32::from_le_bytes(bytes[9..13].try_into().expect("state has a counter"));
+        let second = u32::from_le_bytes(bytes[13..17].try_into().expect("state has a counter"));
+        match bytes[0] {
+            0 => Self::TokenBucket {
+                tokens: first,
+                last_refill_secs: timestamp,
+            },
+            _ => Self::SlidingWindow {
+                window_start_secs: timestamp,
+                current: first,
+                previous: second,
+            },
+        }
+    }
+}
+
+impl RateLimit {
+    /// The state of a key without the acquired permits.
+    pub fn initial_state(&self, now_secs: u64) -> LimiterState {
+        match *self {
+            Self::TokenBucket { capacity, .. } => LimiterState::TokenBucket {
+                tokens: capacity,
+                last_refill_secs: now_secs,
+            },
+            Self::SlidingWindow { .. } => LimiterState::SlidingWindow {
+                window_start_secs: now_secs,
+                current: 0,
+                previous: 0,
+            },
+        }
+    }
+
+    /// Acquire the permits, updating the state. The state of another algorithm, e.g. after the
+    /// limit was changed, starts over.
+    pub fn try_acquire(
+        &self,
+        state: &mut LimiterState,
+        permits: u32,
+        now_secs: u64,
+    ) -> Result<(), RateLimitError> {
+        self.advance(state, now_secs);
+        match (*self, state) {
+            (
+                Self::TokenBucket {
+                    capacity,
+                    refill,
+                    interval_secs,
+                },
+                LimiterState::TokenBucket {
+                    tokens,
+                    last_refill_secs,
+                },
+            ) => {
+                if permits > capacity {
+                    return Err(RateLimitError::ExceedsLimit {
+                        requested: permits,
+                        max: capacity,
+                    });
+                }
+                if *tokens < permits {
+                    let refills = (permits - *tokens).div_ceil(refill.max(1)) as u64;
+                    return Err(RateLimitError::Limited {
+                        retry_after_secs: last_refill_secs
+                            .saturating_add(refills.saturating_mul(interval_secs))
+                            .saturating_sub(now_secs),
+                    });
+                }
+                *tokens -= permits;
+                Ok(())
+            }
+            (
+                Self::SlidingWindow {
+                    max_permits,
+                    window_secs,
+                },
+                LimiterState::SlidingWindow {
+                    window_start_secs,
+                    current,
+                    previous,
+                },
+            ) => {
+                if permits > max_permits {
+                    return Err(RateLimitError::ExceedsLimit {
+                        requested: permits,
+                        max: max_permits,
+                    });
+                }
+                let window_end = window_start_secs.saturating_add(window_secs);
+                let Some(free) = current
+                    .checked_add(permits)
+                    .and_then(|acquired| max_permits.checked_sub(acquired))
+                else {
+                    return Err(RateLimitError::Limited {
+                        retry_after_secs: window_end.saturating_sub(now_secs),
+                    });
+                };
+
+                // The permits of the previous window are weighted by its part still covered
+                // by the sliding window.
+                let remaining_secs = window_end.saturating_sub(now_secs);
+                let (previous, free) = (*previous as u128, free as u128);
+                let (remaining_secs, window_secs) = (remaining_secs as u128, window_secs as u128);
+                if previous * remaining_secs > free * window_secs {
+                    let allowed_remaining_secs = free * window_secs / previous;
+                    return Err(RateLimitError::Limited {
+                        retry_after_secs: (remaining_secs - allowed_remaining_secs) as u64,
+                    });
+                }
+                *current += permits;
+                Ok(())
+            }
+            (_, state) => {
+                *state = self.initial_state(now_secs);
+                self.try_acquire(state, permits, now_secs)
+            }
+        }
+    }
+
+    /// Returns the permits which can be acquired now.
+    pub fn available(&self, state: &LimiterState, now_secs: u64) -> u32 {
+        let mut state = *state;
+        self.advance(&mut state, now_secs);
+        match (*self, state) {
+            (Self::TokenBucket { .. }, LimiterState::TokenBucket { tokens, .. }) => tokens,
+            (
+                Self::SlidingWindow {
+                    max_permits,
+                    window_secs,
+                },
+                LimiterState::SlidingWindow {
+                    window_start_secs,
+                    current,
+                    previous,
+                },
+            ) => {
+                let remaining_secs = window_start_secs
+                    .saturating_add(window_secs)
+                    .saturating_sub(now_secs);
+                let weighted = (previous as u128 * remaining_secs as u128)
+                    .div_ceil(window_secs.max(1) as u128);
+                let weighted = weighted.min(u32::MAX as u128) as u32;
+                max_permits.saturating_sub(current.saturating_add(weighted))
+            }
+            _ => self.max_permits(),
+        }
+    }
+
+    /// Returns `true` if the state is the same as the initial one, so it can be removed.
+    pub fn is_idle(&self, state: &LimiterState, now_secs: u64) -> bool {
+        self.available(state, now_secs) == self.max_permits()
+    }
+
+    fn max_permits(&self) -> u32 {
+        match *self {
+            Self::TokenBucket { capacity, .. } => capacity,
+            Self::SlidingWindow { max_permits, .. } => max_permits,
+        }
+    }
+
+    /// Refill the bucket or move the window to the current time.
+    fn advance(&self, state: &mut LimiterState, now_secs: u64) {
+        match (*self, state) {
+            (
+                Self::TokenBucket {
+                    capacity,
+                    refill,
+                    interval_secs,
+                },
+                LimiterState::TokenBucket {
+                    tokens,
+                    last_refill_secs,
+                },
+            ) => {
+                let refills = now_secs.saturating_sub(*last_refill_secs) / interval_secs.max(1);
+                let refilled = (refills.saturating_mul(refill as u64)).min(capacity as u64) as u32;
+                *tokens = (*tokens).saturating_add(refilled).min(capacity);
+                *last_refill_secs += refills * interval_secs;
+            }
+            (
+                Self::SlidingWindow { window_secs, .. },
+                LimiterState::SlidingWindow {
+                    window_start_secs,
+                    current,
+                    previous,
+                },
+            ) => {
+                let window_secs = window_secs.max(1);
+                let elapsed_windows = now_secs.saturating_sub(*window_start_secs) / window_secs;
+                match elapsed_windows {
+                    0 => return,
+                    1 => *previous = *current,
+                    _ => *previous = 0,
+                }
+                *current = 0;
+                *window_start_secs += elapsed_windows * window_secs;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The rate limit of every key, e.g. a caller, a destination canister or a host, with the
+/// state of the limits in stable memory.
+///
+/// ```
+/// use ic_helpers::rate_limit::{RateLimit, RateLimitError, RateLimiter};
+/// use ic_stable_structures::VectorMemory;
+///
+/// let limit = RateLimit::TokenBucket {
+///     capacity: 2,
+///     refill: 1,
+///     interval_secs: 10,
+/// };
+/// let mut limiter = RateLimiter::<u64, _>::new(VectorMemory::default(), limit);
+///
+/// assert_eq!(limiter.try_acquire(1, 0), Ok(()));
+/// assert_eq!(limiter.try_acquire(1, 0), Ok(()));
+/// assert_eq!(
+///     limiter.try_acquire(1, 5),
+///     Err(RateLimitError::Limited { retry_after_secs: 5 })
+/// );
+/// assert_eq!(limiter.try_acquire(2, 5), Ok(()));
+/// ```
+pub struct RateLimiter<K, M>
+where
+    K: Storable + Ord + Clone,
+    M: Memory,
+{
+    limit: RateLimit,
+    states: StableBTreeMap<K, LimiterState, M>,
+}
+
+impl<K, M> RateLimiter<K, M>
+where
+    K: Storable + Ord + Clone,
+    M: Memory,
+{
+    /// Create the limiter in the memory. If the memory contains the states of the keys, they
+    /// are kept, so the limits continue after an upgrade.
+    pub fn new(memory: M, limit: RateLimit) -> Self {
+        Self {
+            limit,
+            states: StableBTreeMap::new(memory),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Acquire a permit for the key.
+    pub fn try_acquire(&mut self, key: K, now_secs: u64) -> Result<(), RateLimitError> {
+        self.try_acquire_many(key, 1, now_secs)
+    }
+
+    /// Acquire the permits for the key, all of them or none.
+    pub fn try_acquire_many(
+        &mut self,
+        key: K,
+        permits: u32,
+        now_secs: u64,
+    ) -> Result<(), RateLimitError> {
+        let mut state = self
+            .states
+            .get(&key)
+            .unwrap_or_else(|| self.limit.initial_state(now_secs));
+        let result = self.limit.try_acquire(&mut state, permits, now_secs);
+        if result.is_ok() {
+            self.states.insert(key, state);
+        }
+        result
+    }
+
+    /// Returns the permits which the key can acquire now.
+    pub fn available(&self, key: &K, now_secs: u64) -> u32 {
+        match self.states.get(key) {
+            Some(state) => self.limit.available(&state, now_secs),
+            None => self.limit.max_permits(),
+        }
+    }
+
+    /// Forget the permits acquired by the key.
+    pub fn reset(&mut self, key: &K) {
+        self.states.remove(key);
+    }
+
+    /// Remove at most `max_keys` states of the keys which didn't acquire permits recently, and
+    /// return their number.
+    pub fn prune(&mut self, now_secs: u64, max_keys: usize) -> usize {
+        let idle = self
+            .states
+            .iter()
+            .filter(|(_, state)| self.limit.is_idle(state, now_secs))
+            .map(|(key, _)| key)
+            .take(max_keys)
+            .collect::<Vec<_>>();
+        for key in &idle {
+            self.states.remove(key);
+        }
+        idle.len()
+    }
+
+    /// The number of the keys with a state.
+    pub fn len(&self) -> u64 {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    #[test]
+    fn sliding_window_counts_the_previous_window() {
+        let limit = RateLimit::SlidingWindow {
+            max_permits: 4,
+            window_secs: 10,
+        };
+        let mut limiter = RateLimiter::<u64, _>::new(VectorMemory::default(), limit);
+
+        assert_eq!(limiter.try_acquire_many(1, 4, 0), Ok(()));
+        assert_eq!(
+            limiter.try_acquire(1, 3),
+            Err(RateLimitError::Limited {
+                retry_after_secs: 7
+            })
+        );
+        assert_eq!(limiter.try_acquire(2, 3), Ok(()));
+
+        // Half of the previous window is still covered.
+        assert_eq!(limiter.available(&1, 15), 2);
+        assert_eq!(limiter.try_acquire_many(1, 2, 15), Ok(()));
+        assert_eq!(
+            limiter.try_acquire(1, 15),
+            Err(RateLimitError::Limited {
+                retry_after_secs: 3
+            })
+        );
+        assert_eq!(limiter.try_acquire(1, 18), Ok(()));
+        assert_eq!(
+            limiter.try_acquire_many(1, 5, 18),
+            Err(RateLimitError::ExceedsLimit {
+                requested: 5,
+                max: 4
+            })
+        );
+
+        assert_eq!(limiter.prune(25, 10), 1);
+        assert_eq!(limiter.len(), 1);
+        assert_eq!(limiter.prune(40, 10), 1);
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn large_limits_do_not_overflow() {
+        let limit = RateLimit::SlidingWindow {
+            max_permits: u32::MAX,
+            window_secs: u64::MAX,
+        };
+        let mut state = limit.initial_state(10);
+        assert_eq!(limit.try_acquire(&mut state, u32::MAX, 10), Ok(()));
+        assert!(matches!(
+            limit.try_acquire(&mut state, u32::MAX, 20),
+            Err(RateLimitError::Limited { .. })
+        ));
+        assert_eq!(limit.available(&state, 20), 0);
+
+        // The permits of the previous window are weighted by the whole window.
+        let mut state = LimiterState::SlidingWindow {
+            window_start_secs: 0,
+            current: 0,
+            previous: u32::MAX,
+        };
+        assert_eq!(limit.available(&state, 0), 0);
+        assert!(matches!(
+            limit.try_acquire(&mut state, 1, 0),
+            Err(RateLimitError::Limited { .. })
+        ));
+    }
+
+    #[test]
+    fn state_is_stored_in_fixed_size() {
+        for state in [
+            LimiterState::TokenBucket {
+                tokens: 3,
+                last_refill_secs: 100,
+            },
+            LimiterState::SlidingWindow {
+                window_start_secs: 100,
+                current: 2,
+                previous: 7,
+            },
+        ] {
+            let bytes = state.to_bytes();
+            assert_eq!(bytes.len(), 17);
+            assert_eq!(LimiterState::from_bytes(bytes), state);
+        }
+    }
+}
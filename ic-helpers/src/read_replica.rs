@@ -0,0 +1,266 @@
+//! The snapshots of the state of a primary canister pushed to its read replicas, so that the
+//! read-heavy workloads can be spread over several canisters.
+//!
+//! The primary canister periodically takes a [`StateSnapshot`] of the selected structures with
+//! the [`SnapshotPublisher`] and pushes it to the replicas. Every replica applies the snapshots
+//! with a [`SnapshotReceiver`], which ignores the snapshots older than the applied one, and
+//! serves the queries from the applied state. The clients send the queries to the replicas and
+//! the updates to the primary with `ic_canister_client::router::RouterClient`.
+//!
+//! Unlike the [`replication`](crate::replication) to a backup canister, the snapshots contain the
+//! whole selected structures, so they fit the structures which are small enough to be sent in
+//! one call.
+//!
+//! ```ignore
+//! // The primary canister.
+//! let mut publisher = SnapshotPublisher::new()
+//!     .with_structure("prices", || PRICES.with(|prices| prices.borrow().iter().collect::<Vec<_>>()))
+//!     .with_replica(replica_canister, "apply_snapshot");
+//!
+//! ic_cdk_timers::set_timer_interval(Duration::from_secs(30), move || {
+//!     let push = publisher.publish(time_secs());
+//!     ic::spawn(async move {
+//!         for (replica, result) in push.await {
+//!             if let Err(err) = result {
+//!                 log::warn!("Snapshot push to {replica} failed: {err}");
+//!             }
+//!         }
+//!     });
+//! });
+//!
+//! // The replica canister.
+//! #[update]
+//! fn apply_snapshot(snapshot: StateSnapshot) -> ReplicationResult<u64> {
+//!     RECEIVER.with(|receiver| {
+//!         receiver.borrow_mut().apply(&snapshot, |name, state| restore(name, state))
+//!     })
+//! }
+//! ```
+
+use std::future::Future;
+
+use candid::{CandidType, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use serde::Deserialize;
+
+use crate::replication::{ReplicationError, ReplicationResult};
+
+/// The state of the selected structures of the primary canister.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StateSnapshot {
+    /// Increasing version of the snapshot, starting from 1.
+    pub version: u64,
+    pub taken_at_secs: u64,
+    /// The names and the candid encoded states of the structures.
+    pub structures: Vec<(String, Vec<u8>)>,
+}
+
+impl StateSnapshot {
+    /// Returns the candid encoded state of the structure.
+    pub fn structure(&self, name: &str) -> Option<&[u8]> {
+        self.structures
+            .iter()
+            .find(|(structure, _)| structure == name)
+            .map(|(_, state)| state.as_slice())
+    }
+}
+
+/// The update method of a replica canister taking [`StateSnapshot`] and returning
+/// `ReplicationResult<u64>` with the applied version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadReplica {
+    pub canister: Principal,
+    pub method: String,
+}
+
+type StructureState = Box<dyn Fn() -> Vec<u8>>;
+
+/// Takes the snapshots of the selected structures and pushes them to the replicas.
+///
+/// The publisher is kept in the heap. The replicas reject the versions they already applied,
+/// so after an upgrade the last pushed version must be restored with
+/// [`SnapshotPublisher::with_version`].
+#[derive(Default)]
+pub struct SnapshotPublisher {
+    structures: Vec<(String, StructureState)>,
+    replicas: Vec<ReadReplica>,
+    version: u64,
+}
+
+impl SnapshotPublisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Include the state returned by the closure in the snapshots.
+    pub fn with_structure<T: CandidType>(
+        mut self,
+        name: &str,
+        state: impl Fn() -> T + 'static,
+    ) -> Self {
+        self.structures.push((
+            name.to_string(),
+            Box::new(move || Encode!(&state()).expect("serialization of snapshot state failed")),
+        ));
+        self
+    }
+
+    /// Push the snapshots to the method of the replica canister.
+    pub fn with_replica(mut self, canister: Principal, method: &str) -> Self {
+        self.replicas.push(ReadReplica {
+            canister,
+            method: method.to_string(),
+        });
+        self
+    }
+
+    /// Continue the versions after the given one.
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn replicas(&self) -> &[ReadReplica] {
+        &self.replicas
+    }
+
+    /// The version of the last snapshot.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Take the snapshot of the structures.
+    pub fn snapshot(&mut self, now_secs: u64) -> StateSnapshot {
+        self.version += 1;
+        StateSnapshot {
+            version: self.version,
+            taken_at_secs: now_secs,
+            structures: self
+                .structures
+                .iter()
+                .map(|(name, state)| (name.clone(), state()))
+                .collect(),
+        }
+    }
+
+    /// Take the snapshot and push it to all the replicas. The returned future doesn't borrow
+    /// the publisher, and resolves to the results of the replicas in their order.
+    pub fn publish(
+        &mut self,
+        now_secs: u64,
+    ) -> impl Future<Output = Vec<(Principal, ReplicationResult<u64>)>> + 'static {
+        let snapshot = self.snapshot(now_secs);
+        let replicas = self.replicas.clone();
+        async move {
+            let calls = replicas.into_iter().map(|replica| {
+                let snapshot = snapshot.clone();
+                async move {
+                    let result = ic::call::<_, (ReplicationResult<u64>,), _>(
+                        replica.canister,
+                        replica.method,
+                        (snapshot,),
+                    )
+                    .await
+                    .map_err(|(code, message)| {
+                        ReplicationError::CallFailed(format!("{code:?}: {message}"))
+                    })
+                    .and_then(|(result,)| result);
+                    (replica.canister, result)
+                }
+            });
+            futures::future::join_all(calls).await
+        }
+    }
+}
+
+/// Applies the snapshots pushed to a replica canister.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SnapshotReceiver {
+    version: u64,
+    taken_at_secs: u64,
+}
+
+impl SnapshotReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply the states of the structures of the snapshot, and return its version.
+    ///
+    /// Returns [`ReplicationError::StaleSnapshot`] without applying the snapshot if a newer
+    /// snapshot was applied.
+    pub fn apply(
+        &mut self,
+        snapshot: &StateSnapshot,
+        mut apply: impl FnMut(&str, &[u8]),
+    ) -> ReplicationResult<u64> {
+        if snapshot.version <= self.version {
+            return Err(ReplicationError::StaleSnapshot {
+                version: snapshot.version,
+                applied: self.version,
+            });
+        }
+
+        for (name, state) in &snapshot.structures {
+            apply(name, state);
+        }
+        self.version = snapshot.version;
+        self.taken_at_secs = snapshot.taken_at_secs;
+        Ok(self.version)
+    }
+
+    /// The version of the applied snapshot, zero before the first one.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The age of the applied state, `None` before the first snapshot.
+    pub fn staleness_secs(&self, now_secs: u64) -> Option<u64> {
+        (self.version > 0).then(|| now_secs.saturating_sub(self.taken_at_secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use candid::Decode;
+
+    use super::*;
+
+    #[test]
+    fn snapshots_are_applied_in_order() {
+        let prices = Rc::new(RefCell::new(BTreeMap::from([("ICP".to_string(), 10u64)])));
+        let source = prices.clone();
+        let mut publisher = SnapshotPublisher::new()
+            .with_structure("prices", move || source.borrow().clone())
+            .with_version(4);
+
+        let first = publisher.snapshot(100);
+        prices.borrow_mut().insert("BTC".to_string(), 60_000);
+        let second = publisher.snapshot(130);
+        assert_eq!((first.version, second.version), (5, 6));
+
+        let mut receiver = SnapshotReceiver::new();
+        let mut replica_prices = BTreeMap::new();
+        let mut restore = |name: &str, state: &[u8]| {
+            assert_eq!(name, "prices");
+            replica_prices = Decode!(state, BTreeMap<String, u64>).unwrap();
+        };
+        assert_eq!(receiver.staleness_secs(130), None);
+        assert_eq!(receiver.apply(&second, &mut restore), Ok(6));
+        assert_eq!(
+            receiver.apply(&first, &mut restore),
+            Err(ReplicationError::StaleSnapshot {
+                version: 5,
+                applied: 6
+            })
+        );
+        assert_eq!(receiver.staleness_secs(145), Some(15));
+        assert_eq!(replica_prices, *prices.borrow());
+        assert!(second.structure("prices").is_some());
+        assert!(second.structure("balances").is_none());
+    }
+}
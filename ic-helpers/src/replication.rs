@@ -0,0 +1,512 @@
+//! The replication of the changes of the stable structures to a backup canister, e.g. to keep
+//! a hot standby of the canister in another subnet.
+//!
+//! The primary canister records the changes of the observed structures in the
+//! [`ReplicationLog`], with increasing sequence numbers, in the same message as the changes.
+//! A timer streams the recorded changes in batches to the backup canister with [`replicate`],
+//! and the changes are removed from the log when the backup acknowledges them.
+//!
+//! The backup canister applies the batches with a [`Replica`], which skips the changes it
+//! already applied and rejects a batch with a gap, so the changes are applied exactly once and
+//! in order.
+//!
+//! ```ignore
+//! // The primary canister.
+//! let log = Rc::new(RefCell::new(
+//!     ReplicationLog::new(
+//!         MEMORY_MANAGER.with(|mm| mm.get(CHANGES_MEMORY_ID)),
+//!         MEMORY_MANAGER.with(|mm| mm.get(SEQ_MEMORY_ID)),
+//!     )
+//!         .with_backup(backup_canister, "apply_changes"),
+//! ));
+//! let balances = ReplicationLog::observe(&log, "balances", Observed::new(balances));
+//!
+//! ic_cdk_timers::set_timer_interval(Duration::from_secs(5), move || {
+//!     let log = log.clone();
+//!     ic::spawn(async move {
+//!         if let Err(err) = replicate(log, 500).await {
+//!             log::warn!("Replication failed: {err}");
+//!         }
+//!     });
+//! });
+//!
+//! // The backup canister.
+//! #[update]
+//! fn apply_changes(batch: ReplicationBatch) -> ReplicationResult<ReplicaAck> {
+//!     REPLICA.with(|replica| replica.borrow_mut().apply(&batch, |change| apply(change)))
+//! }
+//! ```
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_exports::ic_kit::ic;
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, CellStructure, IterableSortedMapStructure, Observed, StableBTreeMap,
+    StableCell, Storable,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum ReplicationError {
+    #[error("no backup canister is configured")]
+    NoBackup,
+
+    #[error("the replica expected the change {expected} but received {received}")]
+    Gap { expected: u64, received: u64 },
+
+    #[error("the replica applied the changes up to {replica_seq}, the changes up to {acked_seq} were already acknowledged")]
+    ReplicaBehind { replica_seq: u64, acked_seq: u64 },
+
+    #[error("the snapshot {version} is older than the applied snapshot {applied}")]
+    StaleSnapshot { version: u64, applied: u64 },
+
+    #[error("the call to the replica canister failed: {0}")]
+    CallFailed(String),
+}
+
+pub type ReplicationResult<T> = Result<T, ReplicationError>;
+
+/// A change of a replicated structure.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChangeOp {
+    /// The candid encoded key and value.
+    Insert {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Clear,
+}
+
+/// A change recorded in the log.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChangeRecord {
+    pub seq: u64,
+    pub structure: String,
+    pub op: ChangeOp,
+    pub timestamp_secs: u64,
+}
+
+impl Storable for ChangeRecord {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("serialization of change record failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("deserialization of change record failed")
+    }
+}
+
+/// The consecutive changes sent to the backup canister.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationBatch {
+    /// The sequence number of the first change of the batch.
+    pub from_seq: u64,
+    pub changes: Vec<ChangeRecord>,
+}
+
+/// The reply of the backup canister to a batch.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicaAck {
+    pub last_applied_seq: u64,
+}
+
+/// The update method of the backup canister taking [`ReplicationBatch`] and returning
+/// `ReplicationResult<ReplicaAck>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupTarget {
+    pub canister: Principal,
+    pub method: String,
+}
+
+/// The changes of the primary canister waiting to be acknowledged by the backup canister.
+pub struct ReplicationLog<M: Memory> {
+    changes: StableBTreeMap<u64, ChangeRecord, M>,
+    /// The sequence number of the next change, starting from 1.
+    next_seq: StableCell<u64, M>,
+    backup: Option<BackupTarget>,
+    in_flight: bool,
+}
+
+impl<M: Memory> ReplicationLog<M> {
+    /// Create the log in the memories of the changes and of the sequence numbers. If the
+    /// memories contain a log, its changes are kept.
+    pub fn new(changes_memory: M, seq_memory: M) -> Self {
+        Self {
+            changes: StableBTreeMap::new(changes_memory),
+            next_seq: StableCell::new(seq_memory, 1).expect("failed to init replication seq cell"),
+            backup: None,
+            in_flight: false,
+        }
+    }
+
+    /// Send the changes to the method of the backup canister.
+    pub fn with_backup(mut self, canister: Principal, method: &str) -> Self {
+        self.backup = Some(BackupTarget {
+            canister,
+            method: method.to_string(),
+        });
+        self
+    }
+
+    /// Record the change of the structure and return its sequence number.
+    pub fn record(&mut self, structure: &str, op: ChangeOp, now: u64) -> u64 {
+        let seq = *self.next_seq.get();
+        self.next_seq
+            .set(seq + 1)
+            .expect("failed to write replication seq cell");
+        self.changes.insert(
+            seq,
+            ChangeRecord {
+                seq,
+                structure: structure.to_string(),
+                op,
+                timestamp_secs: now,
+            },
+        );
+        seq
+    }
+
+    /// Record the changes of the map in the log.
+    pub fn observe<S, K, V>(
+        log: &Rc<RefCell<Self>>,
+        structure: &str,
+        map: Observed<S, K, V>,
+    ) -> Observed<S, K, V>
+    where
+        M: 'static,
+        K: 'static + CandidType,
+        V: 'static + CandidType,
+    {
+        let (insert_log, remove_log, clear_log) = (log.clone(), log.clone(), log.clone());
+        let (insert_name, remove_name, clear_name) = (
+            structure.to_string(),
+            structure.to_string(),
+            structure.to_string(),
+        );
+
+        map.on_insert(move |key: &K, _, value: &V| {
+            let op = ChangeOp::Insert {
+                key: encode(key),
+                value: encode(value),
+            };
+            insert_log.borrow_mut().record(&insert_name, op, now_secs());
+        })
+        .on_remove(move |key: &K, _: &V| {
+            let op = ChangeOp::Remove { key: encode(key) };
+            remove_log.borrow_mut().record(&remove_name, op, now_secs());
+        })
+        .on_clear(move || {
+            clear_log
+                .borrow_mut()
+                .record(&clear_name, ChangeOp::Clear, now_secs());
+        })
+    }
+
+    /// The sequence number of the last change acknowledged by the backup canister.
+    pub fn acked_seq(&self) -> u64 {
+        match self.changes.iter().next() {
+            Some((seq, _)) => seq - 1,
+            None => *self.next_seq.get() - 1,
+        }
+    }
+
+    /// The number of the changes waiting to be acknowledged.
+    pub fn len(&self) -> u64 {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns at most `max_changes` first changes waiting to be acknowledged.
+    pub fn next_batch(&self, max_changes: usize) -> ReplicationBatch {
+        ReplicationBatch {
+            from_seq: self.acked_seq() + 1,
+            changes: self
+                .changes
+                .iter()
+                .take(max_changes)
+                .map(|(_, change)| change)
+                .collect(),
+        }
+    }
+
+    /// Remove the changes applied by the backup canister.
+    pub fn acknowledge(&mut self, last_applied_seq: u64) {
+        while let Some((seq, _)) = self.changes.iter().next() {
+            if seq > last_applied_seq {
+                break;
+            }
+            self.changes.remove(&seq);
+        }
+    }
+}
+
+/// Marks the batch of the log in flight until it's dropped. If the callback of the call traps,
+/// the future of the call is dropped by the cleanup of the callback, so the next batch can be
+/// sent.
+struct InFlight<M: Memory>(Rc<RefCell<ReplicationLog<M>>>);
+
+impl<M: Memory> InFlight<M> {
+    fn new(log: &Rc<RefCell<ReplicationLog<M>>>) -> Self {
+        log.borrow_mut().in_flight = true;
+        Self(log.clone())
+    }
+}
+
+impl<M: Memory> Drop for InFlight<M> {
+    fn drop(&mut self) {
+        self.0.borrow_mut().in_flight = false;
+    }
+}
+
+/// Send the next batch of at most `max_changes` changes to the backup canister, and return
+/// the number of the acknowledged changes. Does nothing if a batch is already being sent.
+pub async fn replicate<M: Memory>(
+    log: Rc<RefCell<ReplicationLog<M>>>,
+    max_changes: usize,
+) -> ReplicationResult<u64> {
+    let (backup, batch) = {
+        let log = log.borrow();
+        let backup = log.backup.clone().ok_or(ReplicationError::NoBackup)?;
+        if log.in_flight || log.is_empty() {
+            return Ok(0);
+        }
+        (backup, log.next_batch(max_changes))
+    };
+
+    let in_flight = InFlight::new(&log);
+    let result = ic::call::<_, (ReplicationResult<ReplicaAck>,), _>(
+        backup.canister,
+        backup.method,
+        (batch,),
+    )
+    .await;
+    drop(in_flight);
+
+    let mut log = log.borrow_mut();
+    match result {
+        Ok((Ok(ack),)) => {
+            let acked = ack.last_applied_seq.saturating_sub(log.acked_seq());
+            log.acknowledge(ack.last_applied_seq);
+            Ok(acked)
+        }
+        // The backup canister lost the changes which were already removed from the log.
+        Ok((Err(ReplicationError::Gap { expected, .. }),)) => {
+            Err(ReplicationError::ReplicaBehind {
+                replica_seq: expected - 1,
+                acked_seq: log.acked_seq(),
+            })
+        }
+        Ok((Err(err),)) => Err(err),
+        Err((code, message)) => Err(ReplicationError::CallFailed(format!("{code:?}: {message}"))),
+    }
+}
+
+/// The changes applied by the backup canister.
+pub struct Replica<M: Memory> {
+    last_applied_seq: StableCell<u64, M>,
+}
+
+impl<M: Memory> Replica<M> {
+    /// Create the replica in the memory of the last applied sequence number. If the memory
+    /// contains a replica, its sequence number is kept.
+    pub fn new(memory: M) -> Self {
+        Self {
+            last_applied_seq: StableCell::new(memory, 0).expect("failed to init replica seq cell"),
+        }
+    }
+
+    pub fn last_applied_seq(&self) -> u64 {
+        *self.last_applied_seq.get()
+    }
+
+    /// Apply the changes of the batch which were not applied yet, in order.
+    ///
+    /// Returns [`ReplicationError::Gap`] without applying the batch if it doesn't continue the
+    /// applied changes.
+    pub fn apply(
+        &mut self,
+        batch: &ReplicationBatch,
+        mut apply: impl FnMut(&ChangeRecord),
+    ) -> ReplicationResult<ReplicaAck> {
+        let last_applied_seq = self.last_applied_seq();
+        let mut expected = last_applied_seq + 1;
+        if batch.from_seq > expected {
+            return Err(ReplicationError::Gap {
+                expected,
+                received: batch.from_seq,
+            });
+        }
+
+        // The changes applied before are skipped, e.g. if the previous ack was lost.
+        let new_changes = || {
+            batch
+                .changes
+                .iter()
+                .filter(move |change| change.seq > last_applied_seq)
+        };
+        for change in new_changes() {
+            if change.seq != expected {
+                return Err(ReplicationError::Gap {
+                    expected,
+                    received: change.seq,
+                });
+            }
+            expected += 1;
+        }
+
+        new_changes().for_each(&mut apply);
+        self.last_applied_seq
+            .set(expected - 1)
+            .expect("failed to write replica seq cell");
+
+        Ok(ReplicaAck {
+            last_applied_seq: expected - 1,
+        })
+    }
+}
+
+impl ChangeRecord {
+    /// Decode the key of the insert or the remove.
+    pub fn decode_key<K: CandidType + for<'de> Deserialize<'de>>(&self) -> Option<K> {
+        match &self.op {
+            ChangeOp::Insert { key, .. } | ChangeOp::Remove { key } => Decode!(key, K).ok(),
+            ChangeOp::Clear => None,
+        }
+    }
+
+    /// Decode the value of the insert.
+    pub fn decode_value<V: CandidType + for<'de> Deserialize<'de>>(&self) -> Option<V> {
+        match &self.op {
+            ChangeOp::Insert { value, .. } => Decode!(value, V).ok(),
+            ChangeOp::Remove { .. } | ChangeOp::Clear => None,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    ic::time() / 1_000_000_000
+}
+
+fn encode<T: CandidType>(value: &T) -> Vec<u8> {
+    Encode!(value).expect("serialization of replicated entry failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use ic_exports::ic_kit::MockContext;
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    fn apply_to(map: &mut BTreeMap<u32, u64>) -> impl FnMut(&ChangeRecord) + '_ {
+        |change| match change.op {
+            ChangeOp::Insert { .. } => {
+                map.insert(change.decode_key().unwrap(), change.decode_value().unwrap());
+            }
+            ChangeOp::Remove { .. } => {
+                map.remove(&change.decode_key().unwrap());
+            }
+            ChangeOp::Clear => map.clear(),
+        }
+    }
+
+    #[test]
+    fn changes_are_replicated_in_order() {
+        MockContext::new().with_time(5_000_000_000).inject();
+        let log = Rc::new(RefCell::new(ReplicationLog::new(
+            VectorMemory::default(),
+            VectorMemory::default(),
+        )));
+        let mut balances = ReplicationLog::observe(
+            &log,
+            "balances",
+            Observed::new(StableBTreeMap::<u32, u64, _>::new(VectorMemory::default())),
+        );
+        balances.insert(1, 100);
+        balances.insert(2, 200);
+        balances.remove(&1);
+        balances.insert(3, 300);
+
+        let mut replica = Replica::new(VectorMemory::default());
+        let mut backup = BTreeMap::new();
+
+        let batch = log.borrow().next_batch(2);
+        assert_eq!(batch.from_seq, 1);
+        assert_eq!(batch.changes.len(), 2);
+        assert_eq!(batch.changes[0].timestamp_secs, 5);
+        let ack = replica.apply(&batch, apply_to(&mut backup)).unwrap();
+        assert_eq!(ack.last_applied_seq, 2);
+
+        // The ack was lost, the batch is sent again with the next change.
+        let batch = log.borrow().next_batch(3);
+        let ack = replica.apply(&batch, apply_to(&mut backup)).unwrap();
+        assert_eq!(ack.last_applied_seq, 3);
+        log.borrow_mut().acknowledge(ack.last_applied_seq);
+        assert_eq!(log.borrow().acked_seq(), 3);
+        assert_eq!(log.borrow().len(), 1);
+
+        let batch = log.borrow().next_batch(10);
+        assert_eq!(batch.from_seq, 4);
+        replica.apply(&batch, apply_to(&mut backup)).unwrap();
+        log.borrow_mut().acknowledge(4);
+        assert!(log.borrow().is_empty());
+        assert_eq!(backup, BTreeMap::from([(2, 200), (3, 300)]));
+
+        // The batch is no longer in flight when the call is dropped, e.g. its callback trapped.
+        drop(InFlight::new(&log));
+        assert!(!log.borrow().in_flight);
+    }
+
+    #[test]
+    fn gaps_are_rejected() {
+        let change = |seq| ChangeRecord {
+            seq,
+            structure: "balances".to_string(),
+            op: ChangeOp::Clear,
+            timestamp_secs: 0,
+        };
+        let mut replica = Replica::new(VectorMemory::default());
+        let mut applied = vec![];
+
+        let batch = ReplicationBatch {
+            from_seq: 3,
+            changes: vec![change(3)],
+        };
+        assert_eq!(
+            replica.apply(&batch, |change| applied.push(change.seq)),
+            Err(ReplicationError::Gap {
+                expected: 1,
+                received: 3
+            })
+        );
+
+        let batch = ReplicationBatch {
+            from_seq: 1,
+            changes: vec![change(1), change(2), change(4)],
+        };
+        assert_eq!(
+            replica.apply(&batch, |change| applied.push(change.seq)),
+            Err(ReplicationError::Gap {
+                expected: 3,
+                received: 4
+            })
+        );
+        assert!(applied.is_empty());
+        assert_eq!(replica.last_applied_seq(), 0);
+    }
+}
@@ -0,0 +1,226 @@
+//! The registry of the stable structures of the canister, describing what the canister stores.
+//!
+//! Every structure registers its name, memory id, types and schema version when it's created.
+//! The registry is returned by the `__schema` query of [`SchemaCanister`], so that the tooling
+//! can inspect a deployed canister and compare the layouts of two versions with
+//! [`schema_drift`] before an upgrade.
+//!
+//! ```ignore
+//! thread_local! {
+//!     static BALANCES: RefCell<StableBTreeMap<Principal, u64, VirtualMemory<DefaultMemoryImpl>>> = {
+//!         register_schema(StructureSchema::map::<Principal, u64>("balances", 1, 1))
+//!             .expect("conflicting structure");
+//!         RefCell::new(StableBTreeMap::new(MEMORY_MANAGER.with(|mm| mm.get(MemoryId::new(1)))))
+//!     };
+//! }
+//! ```
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::CandidType;
+use ic_canister::{generate_exports, generate_idl, query, Canister, Idl, PreUpdate};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// The description of a stable structure.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StructureSchema {
+    pub name: String,
+    pub memory_id: u8,
+    /// The type of the keys, `None` for the structures without keys, e.g. the cells and the logs.
+    pub key_type: Option<String>,
+    pub value_type: String,
+    /// The version of the layout of the values, increased when their encoding changes.
+    pub version: u32,
+}
+
+impl StructureSchema {
+    /// The schema of a map from `K` to `V`.
+    pub fn map<K, V>(name: &str, memory_id: u8, version: u32) -> Self {
+        Self {
+            key_type: Some(std::any::type_name::<K>().to_string()),
+            ..Self::value::<V>(name, memory_id, version)
+        }
+    }
+
+    /// The schema of a structure of the values of `V` without keys.
+    pub fn value<V>(name: &str, memory_id: u8, version: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            memory_id,
+            key_type: None,
+            value_type: std::any::type_name::<V>().to_string(),
+            version,
+        }
+    }
+}
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum SchemaError {
+    #[error("the structure {0} is already registered")]
+    DuplicateName(String),
+
+    #[error("the memory {memory_id} is already used by the structure {name}")]
+    MemoryInUse { memory_id: u8, name: String },
+}
+
+/// A difference between the schemas of two versions of the canister.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SchemaDrift {
+    Added { name: String },
+    Removed { name: String },
+    MemoryChanged { name: String, from: u8, to: u8 },
+    TypesChanged { name: String },
+    VersionChanged { name: String, from: u32, to: u32 },
+}
+
+thread_local! {
+    static SCHEMAS: RefCell<BTreeMap<String, StructureSchema>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Register the structure. Registering the same schema again does nothing, so the structures
+/// can register themselves when they are created lazily.
+pub fn register_schema(schema: StructureSchema) -> Result<(), SchemaError> {
+    SCHEMAS.with(|schemas| {
+        let mut schemas = schemas.borrow_mut();
+        if let Some(registered) = schemas.get(&schema.name) {
+            if registered == &schema {
+                return Ok(());
+            }
+            return Err(SchemaError::DuplicateName(schema.name));
+        }
+        if let Some(registered) = schemas.values().find(|s| s.memory_id == schema.memory_id) {
+            return Err(SchemaError::MemoryInUse {
+                memory_id: schema.memory_id,
+                name: registered.name.clone(),
+            });
+        }
+
+        schemas.insert(schema.name.clone(), schema);
+        Ok(())
+    })
+}
+
+/// Returns the registered structures, ordered by their memory ids.
+pub fn schemas() -> Vec<StructureSchema> {
+    let mut schemas =
+        SCHEMAS.with(|schemas| schemas.borrow().values().cloned().collect::<Vec<_>>());
+    schemas.sort_by_key(|schema| schema.memory_id);
+    schemas
+}
+
+/// Returns the differences of the `current` schemas from the `previous` ones, matched by the
+/// names of the structures.
+pub fn schema_drift(previous: &[StructureSchema], current: &[StructureSchema]) -> Vec<SchemaDrift> {
+    let mut drift = vec![];
+    for old in previous {
+        let Some(new) = current.iter().find(|schema| schema.name == old.name) else {
+            drift.push(SchemaDrift::Removed {
+                name: old.name.clone(),
+            });
+            continue;
+        };
+
+        if new.memory_id != old.memory_id {
+            drift.push(SchemaDrift::MemoryChanged {
+                name: old.name.clone(),
+                from: old.memory_id,
+                to: new.memory_id,
+            });
+        }
+        if new.key_type != old.key_type || new.value_type != old.value_type {
+            drift.push(SchemaDrift::TypesChanged {
+                name: old.name.clone(),
+            });
+        }
+        if new.version != old.version {
+            drift.push(SchemaDrift::VersionChanged {
+                name: old.name.clone(),
+                from: old.version,
+                to: new.version,
+            });
+        }
+    }
+
+    for new in current {
+        if !previous.iter().any(|schema| schema.name == new.name) {
+            drift.push(SchemaDrift::Added {
+                name: new.name.clone(),
+            });
+        }
+    }
+
+    drift
+}
+
+/// The API describing the stable structures of the canister.
+pub trait SchemaCanister: Canister + Sized {
+    /// Returns the registered stable structures.
+    #[query(trait = true)]
+    fn __schema(&self) -> Vec<StructureSchema> {
+        schemas()
+    }
+
+    // Important: This function *must* be defined to be the
+    // last one in the trait because it depends on the order
+    // of expansion of update/query(trait = true) methods.
+    fn get_idl() -> Idl {
+        generate_idl!()
+    }
+}
+
+generate_exports!(SchemaCanister);
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::*;
+
+    #[test]
+    fn structures_are_registered_and_compared() {
+        let balances = StructureSchema::map::<Principal, u64>("balances", 1, 1);
+        let log = StructureSchema::value::<String>("log", 2, 1);
+        register_schema(log.clone()).unwrap();
+        register_schema(balances.clone()).unwrap();
+        register_schema(balances.clone()).unwrap();
+
+        assert_eq!(
+            register_schema(StructureSchema::value::<u64>("log", 3, 1)),
+            Err(SchemaError::DuplicateName("log".to_string()))
+        );
+        assert_eq!(
+            register_schema(StructureSchema::value::<u64>("fees", 1, 1)),
+            Err(SchemaError::MemoryInUse {
+                memory_id: 1,
+                name: "balances".to_string()
+            })
+        );
+        assert_eq!(schemas(), [balances.clone(), log.clone()]);
+
+        let current = [
+            StructureSchema::map::<Principal, u128>("balances", 1, 2),
+            StructureSchema::value::<u64>("fees", 3, 1),
+        ];
+        assert_eq!(
+            schema_drift(&schemas(), &current),
+            [
+                SchemaDrift::TypesChanged {
+                    name: "balances".to_string()
+                },
+                SchemaDrift::VersionChanged {
+                    name: "balances".to_string(),
+                    from: 1,
+                    to: 2
+                },
+                SchemaDrift::Removed {
+                    name: "log".to_string()
+                },
+                SchemaDrift::Added {
+                    name: "fees".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! A finite state machine for the workflows of the canisters, e.g. orders, withdrawals or bridge
+//! transfers, with its state and the log of its transitions kept in stable memory.
+
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+use candid::CandidType;
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{Bound, CellStructure, LogStructure, StableCell, StableLog, Storable};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum StateMachineError {
+    #[error("no transition from {state} on {event}")]
+    InvalidTransition { state: String, event: String },
+}
+
+/// A transition of the machine, recorded in its log.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Transition<S, E> {
+    pub from: S,
+    pub event: E,
+    pub to: S,
+    pub timestamp: u64,
+}
+
+impl<S, E> Storable for Transition<S, E>
+where
+    S: CandidType + DeserializeOwned,
+    E: CandidType + DeserializeOwned,
+{
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("serialization of transition failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("deserialization of transition failed")
+    }
+}
+
+/// The state stored in the cell.
+struct StoredState<S>(S);
+
+impl<S: CandidType + DeserializeOwned> Storable for StoredState<S> {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(&self.0).expect("serialization of state failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(candid::decode_one(&bytes).expect("deserialization of state failed"))
+    }
+}
+
+type Hook<S, E> = Box<dyn Fn(&Transition<S, E>)>;
+
+/// A state machine, which moves between the states `S` on the events `E` only by the
+/// transitions declared with [`StateMachine::with_transition`].
+///
+/// The hooks declared with [`StateMachine::on_exit`] and [`StateMachine::on_entry`] are called
+/// when the machine leaves and enters their states. As the state and the transition table are
+/// kept separately, the table and the hooks must be declared again after an upgrade, while the
+/// state and the log are restored from the memory.
+///
+/// ```
+/// use candid::{CandidType, Deserialize};
+/// use ic_helpers::state_machine::StateMachine;
+/// use ic_stable_structures::VectorMemory;
+///
+/// #[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+/// enum Withdrawal {
+///     Pending,
+///     Sent,
+///     Failed,
+/// }
+///
+/// #[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+/// enum Event {
+///     Send,
+///     Fail,
+///     Retry,
+/// }
+///
+/// let mut withdrawal = StateMachine::new(
+///     VectorMemory::default(),
+///     VectorMemory::default(),
+///     VectorMemory::default(),
+///     Withdrawal::Pending,
+/// )
+/// .with_transition(Withdrawal::Pending, Event::Send, Withdrawal::Sent)
+/// .with_transition(Withdrawal::Pending, Event::Fail, Withdrawal::Failed)
+/// .with_transition(Withdrawal::Failed, Event::Retry, Withdrawal::Pending);
+///
+/// assert!(withdrawal.apply(Event::Retry, 0).is_err());
+/// assert_eq!(withdrawal.apply(Event::Fail, 1), Ok(&Withdrawal::Failed));
+/// assert_eq!(withdrawal.history_len(), 1);
+/// ```
+pub struct StateMachine<S, E, M: Memory>
+where
+    S: CandidType + DeserializeOwned,
+    E: CandidType + DeserializeOwned,
+{
+    transitions: Vec<(S, E, S)>,
+    entry_hooks: Vec<(S, Hook<S, E>)>,
+    exit_hooks: Vec<(S, Hook<S, E>)>,
+    state: StableCell<StoredState<S>, M>,
+    log: StableLog<Transition<S, E>, M>,
+}
+
+impl<S, E, M> StateMachine<S, E, M>
+where
+    S: CandidType + DeserializeOwned + Clone + PartialEq + Debug,
+    E: CandidType + DeserializeOwned + Clone + PartialEq + Debug,
+    M: Memory,
+{
+    /// Create the machine in the initial state, keeping the state in `state_memory` and the
+    /// log of the transitions in `log_index_memory` and `log_data_memory`. If the memories
+    /// contain a machine, its state and log are kept.
+    pub fn new(state_memory: M, log_index_memory: M, log_data_memory: M, initial: S) -> Self {
+        Self {
+            transitions: vec![],
+            entry_hooks: vec![],
+            exit_hooks: vec![],
+            state: StableCell::new(state_memory, StoredState(initial))
+                .expect("failed to init state machine cell"),
+            log: StableLog::new(log_index_memory, log_data_memory)
+                .expect("failed to init state machine log"),
+        }
+    }
+
+    /// Allow the transition from the state to the other one on the event.
+    pub fn with_transition(mut self, from: S, event: E, to: S) -> Self {
+        self.transitions.push((from, event, to));
+        self
+    }
+
+    /// Call the hook after the machine enters the state.
+    pub fn on_entry(mut self, state: S, hook: impl Fn(&Transition<S, E>) + 'static) -> Self {
+        self.entry_hooks.push((state, Box::new(hook)));
+        self
+    }
+
+    /// Call the hook before the machine leaves the state.
+    pub fn on_exit(mut self, state: S, hook: impl Fn(&Transition<S, E>) + 'static) -> Self {
+        self.exit_hooks.push((state, Box::new(hook)));
+        self
+    }
+
+    /// The current state.
+    pub fn state(&self) -> &S {
+        &self.state.get().0
+    }
+
+    /// The state the machine moves to on the event, if the transition is allowed.
+    pub fn next_state(&self, event: &E) -> Option<&S> {
+        let state = self.state();
+        self.transitions
+            .iter()
+            .find(|(from, on, _)| from == state && on == event)
+            .map(|(_, _, to)| to)
+    }
+
+    /// Move the machine to the next state on the event at the `timestamp`, recording the
+    /// transition in the log. Returns the new state.
+    pub fn apply(&mut self, event: E, timestamp: u64) -> Result<&S, StateMachineError> {
+        let to = self
+            .next_state(&event)
+            .ok_or_else(|| StateMachineError::InvalidTransition {
+                state: format!("{:?}", self.state()),
+                event: format!("{event:?}"),
+            })?
+            .clone();
+        let transition = Transition {
+            from: self.state().clone(),
+            event,
+            to,
+            timestamp,
+        };
+
+        Self::call_hooks(&self.exit_hooks, &transition.from, &transition);
+        self.state
+            .set(StoredState(transition.to.clone()))
+            .expect("failed to write state machine cell");
+        self.log
+            .append(transition.clone())
+            .expect("failed to append to state machine log");
+        Self::call_hooks(&self.entry_hooks, &transition.to, &transition);
+
+        Ok(self.state())
+    }
+
+    /// The number of the transitions in the log.
+    pub fn history_len(&self) -> u64 {
+        self.log.len()
+    }
+
+    /// Up to `limit` transitions of the log, starting from the `start` one.
+    pub fn history(&self, start: u64, limit: u64) -> Vec<Transition<S, E>> {
+        (start..self.log.len().min(start.saturating_add(limit)))
+            .filter_map(|index| self.log.get(index))
+            .collect()
+    }
+
+    fn call_hooks(hooks: &[(S, Hook<S, E>)], state: &S, transition: &Transition<S, E>) {
+        for (_, hook) in hooks.iter().filter(|(hooked, _)| hooked == state) {
+            hook(transition);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    #[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+    enum Order {
+        Created,
+        Paid,
+        Shipped,
+        Cancelled,
+    }
+
+    #[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+    enum Event {
+        Pay,
+        Ship,
+        Cancel,
+    }
+
+    fn order_machine(
+        memories: &[VectorMemory; 3],
+        hooks: Rc<RefCell<Vec<String>>>,
+    ) -> StateMachine<Order, Event, VectorMemory> {
+        let exit_hooks = hooks.clone();
+        StateMachine::new(
+            memories[0].clone(),
+            memories[1].clone(),
+            memories[2].clone(),
+            Order::Created,
+        )
+        .with_transition(Order::Created, Event::Pay, Order::Paid)
+        .with_transition(Order::Created, Event::Cancel, Order::Cancelled)
+        .with_transition(Order::Paid, Event::Ship, Order::Shipped)
+        .with_transition(Order::Paid, Event::Cancel, Order::Cancelled)
+        .on_exit(Order::Created, move |transition| {
+            exit_hooks
+                .borrow_mut()
+                .push(format!("exit {:?}", transition.from))
+        })
+        .on_entry(Order::Paid, move |transition| {
+            hooks.borrow_mut().push(format!(
+                "enter {:?} at {}",
+                transition.to, transition.timestamp
+            ))
+        })
+    }
+
+    #[test]
+    fn transitions_are_validated_and_logged() {
+        let memories = [
+            VectorMemory::default(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+        ];
+        let hooks = Rc::new(RefCell::new(vec![]));
+        let mut order = order_machine(&memories, hooks.clone());
+
+        assert_eq!(
+            order.apply(Event::Ship, 1),
+            Err(StateMachineError::InvalidTransition {
+                state: "Created".to_string(),
+                event: "Ship".to_string()
+            })
+        );
+        assert_eq!(order.apply(Event::Pay, 2), Ok(&Order::Paid));
+        assert_eq!(*hooks.borrow(), ["exit Created", "enter Paid at 2"]);
+        assert_eq!(order.next_state(&Event::Pay), None);
+
+        // The state and the log are restored from the memory.
+        let mut order = order_machine(&memories, hooks);
+        assert_eq!(order.state(), &Order::Paid);
+        assert_eq!(order.apply(Event::Cancel, 3), Ok(&Order::Cancelled));
+        assert_eq!(order.history_len(), 2);
+        assert_eq!(
+            order.history(1, 10),
+            [Transition {
+                from: Order::Paid,
+                event: Event::Cancel,
+                to: Order::Cancelled,
+                timestamp: 3,
+            }]
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use ic_exports::candid::{CandidType, Deserialize};
+use serde::Serialize;
 
 #[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Pubkey(Vec<u8>);
@@ -22,3 +23,51 @@ impl Pubkey {
         &self.0
     }
 }
+
+/// Requested page of a paginated query.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    /// Number of items to skip.
+    pub offset: u64,
+    /// Max number of items in the page.
+    pub count: u64,
+}
+
+impl Pagination {
+    pub fn new(offset: u64, count: u64) -> Self {
+        Self { offset, count }
+    }
+
+    /// Returns the pagination of the following page of the same size.
+    pub fn next(&self) -> Self {
+        Self {
+            offset: self.offset.saturating_add(self.count),
+            count: self.count,
+        }
+    }
+}
+
+/// A page of a paginated query result.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    /// Items of the page.
+    pub items: Vec<T>,
+    /// Total number of items available.
+    pub total: u64,
+}
+
+impl<T> Page<T> {
+    /// Creates a page from an iterator over all the items.
+    pub fn paginate<I>(iter: I, pagination: Pagination) -> Self
+    where
+        I: ExactSizeIterator<Item = T>,
+    {
+        let total = iter.len() as u64;
+        let items = iter
+            .skip(pagination.offset.try_into().unwrap_or(usize::MAX))
+            .take(pagination.count.try_into().unwrap_or(usize::MAX))
+            .collect();
+
+        Self { items, total }
+    }
+}
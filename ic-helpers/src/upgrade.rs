@@ -0,0 +1,488 @@
+//! Controlled self-upgrades of a canister, which is its own controller.
+//!
+//! An operator uploads the new wasm module in chunks, then signs the upgrade with the admin
+//! ed25519 key. The canister verifies the hash of the module and the signature, requests the
+//! upgrade of itself from the management canister and records it in the history, with the
+//! hash of the module before the upgrade to roll back to.
+//!
+//! The module is sent to the management canister in one `install_code` call, so it must fit the
+//! payload of an inter-canister call, see [`MAX_WASM_SIZE`]. The larger modules must be gzipped.
+//!
+//! ```ignore
+//! #[update]
+//! async fn upgrade(&self, signature: Vec<u8>) -> Result<(), UpgradeError> {
+//!     let before = current_module_hash().await?;
+//!     let wasm = UPGRADE.with(|upgrade| {
+//!         upgrade.borrow_mut().prepare(ic::id(), &signature, before, ic::time())
+//!     })?;
+//!     let result = install_self(wasm, ()).await;
+//!     if let Err(err) = &result {
+//!         UPGRADE.with(|upgrade| upgrade.borrow_mut().fail(err, ic::time()));
+//!     }
+//!     result
+//! }
+//!
+//! #[post_upgrade]
+//! fn post_upgrade(&self) {
+//!     UPGRADE.with(|upgrade| upgrade.borrow_mut().complete(ic::time()));
+//! }
+//! ```
+
+use std::borrow::Cow;
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, CellStructure, IterableSortedMapStructure, StableBTreeMap,
+    StableCell, Storable,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::crypto::{verify_ed25519, CryptoError};
+
+/// The max size of the payload of an inter-canister call.
+pub const MAX_CALL_PAYLOAD_SIZE: u64 = 2 * 1024 * 1024;
+
+/// The max size of the wasm module installed by the canister itself. The module is sent in one
+/// `install_code` call, so the rest of the call payload is left to the upgrade argument, which
+/// must not exceed 64 KiB with the candid encoding of the call.
+pub const MAX_WASM_SIZE: u64 = MAX_CALL_PAYLOAD_SIZE - 64 * 1024;
+
+/// The number of the upgrades kept in the history.
+pub const MAX_UPGRADE_HISTORY: usize = 32;
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum UpgradeError {
+    #[error("no wasm module is staged for the upgrade")]
+    NoStagedWasm,
+
+    #[error("the upgrade {0} is in progress")]
+    UpgradeInProgress(u64),
+
+    #[error("the wasm module is larger than {0} bytes")]
+    WasmTooLarge(u64),
+
+    #[error("received {received} bytes of the wasm module out of {expected}")]
+    IncompleteWasm { received: u64, expected: u64 },
+
+    #[error("the hash of the wasm module is {actual} instead of {expected}")]
+    HashMismatch { expected: String, actual: String },
+
+    #[error("the admin key is not set")]
+    AdminKeyNotSet,
+
+    #[error("invalid upgrade signature: {0}")]
+    InvalidSignature(CryptoError),
+
+    #[error("management canister call failed: {0}")]
+    ManagementCallFailed(String),
+}
+
+pub type UpgradeResult<T> = Result<T, UpgradeError>;
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum UpgradeStatus {
+    /// The upgrade was requested from the management canister.
+    Requested,
+    /// The new module is running.
+    Completed,
+    Failed(String),
+}
+
+/// An upgrade of the canister in its history.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct UpgradeRecord {
+    pub id: u64,
+    /// The hash of the module before the upgrade, which is the module to roll back to.
+    pub before_module_hash: Option<Vec<u8>>,
+    /// The hash of the module installed by the upgrade.
+    pub after_module_hash: [u8; 32],
+    pub requested_at: u64,
+    pub finished_at: Option<u64>,
+    pub status: UpgradeStatus,
+}
+
+/// The wasm module being uploaded.
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct StagedWasm {
+    pub hash: [u8; 32],
+    pub len: u64,
+    pub received: u64,
+}
+
+#[derive(CandidType, Debug, Default, Clone, Deserialize)]
+struct UpgradeState {
+    admin_key: Option<[u8; 32]>,
+    staged: Option<StagedWasm>,
+    /// The id of the next upgrade, included into the signed message, so a signature can't be
+    /// used twice.
+    next_id: u64,
+    history: Vec<UpgradeRecord>,
+}
+
+impl Storable for UpgradeState {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("serialization of upgrade state failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).expect("deserialization of upgrade state failed")
+    }
+}
+
+/// The message the admin signs to approve the upgrade of the canister to the module.
+pub fn upgrade_message(canister: Principal, upgrade_id: u64, wasm_hash: &[u8; 32]) -> Vec<u8> {
+    let mut message = b"canister-upgrade".to_vec();
+    message.extend_from_slice(canister.as_slice());
+    message.extend_from_slice(&upgrade_id.to_be_bytes());
+    message.extend_from_slice(wasm_hash);
+    message
+}
+
+/// The uploaded wasm module and the history of the upgrades in stable memory.
+pub struct SelfUpgrade<M: Memory> {
+    chunks: StableBTreeMap<u32, Vec<u8>, M>,
+    state: StableCell<UpgradeState, M>,
+}
+
+impl<M: Memory> SelfUpgrade<M> {
+    /// Create the component in the memories. If the memories contain the state, it is kept.
+    pub fn new(chunks_memory: M, state_memory: M) -> Self {
+        Self {
+            chunks: StableBTreeMap::new(chunks_memory),
+            state: StableCell::new(state_memory, UpgradeState::default())
+                .expect("failed to init upgrade cell"),
+        }
+    }
+
+    /// Set the ed25519 public key, which signs the upgrades.
+    pub fn set_admin_key(&mut self, admin_key: [u8; 32]) {
+        self.update(|state| state.admin_key = Some(admin_key));
+    }
+
+    /// Start uploading the wasm module with the hash and the length, discarding the previously
+    /// uploaded module.
+    pub fn stage(&mut self, hash: [u8; 32], len: u64) -> UpgradeResult<()> {
+        self.check_not_in_progress()?;
+        if len > MAX_WASM_SIZE {
+            return Err(UpgradeError::WasmTooLarge(MAX_WASM_SIZE));
+        }
+
+        self.chunks.clear();
+        self.update(|state| {
+            state.staged = Some(StagedWasm {
+                hash,
+                len,
+                received: 0,
+            })
+        });
+        Ok(())
+    }
+
+    /// Append the next chunk of the staged module. Returns the number of the bytes received.
+    ///
+    /// The module can't be changed while its upgrade is in progress, and the chunks beyond the
+    /// staged length or [`MAX_WASM_SIZE`] are rejected, so the assembled module always fits the
+    /// `install_code` call.
+    pub fn append_chunk(&mut self, chunk: Vec<u8>) -> UpgradeResult<u64> {
+        self.check_not_in_progress()?;
+        let mut staged = self.staged().ok_or(UpgradeError::NoStagedWasm)?;
+        let received = staged.received + chunk.len() as u64;
+        if received > MAX_WASM_SIZE {
+            return Err(UpgradeError::WasmTooLarge(MAX_WASM_SIZE));
+        }
+        if received > staged.len {
+            return Err(UpgradeError::WasmTooLarge(staged.len));
+        }
+
+        self.chunks.insert(self.chunks.len() as u32, chunk);
+        staged.received = received;
+        self.update(|state| state.staged = Some(staged));
+        Ok(received)
+    }
+
+    pub fn staged(&self) -> Option<StagedWasm> {
+        self.state.get().staged.clone()
+    }
+
+    /// The id of the next upgrade, which must be signed with the module hash.
+    pub fn next_upgrade_id(&self) -> u64 {
+        self.state.get().next_id
+    }
+
+    /// Verify the staged module and the admin signature of the [`upgrade_message`], and record
+    /// the requested upgrade. Returns the module to install with `install_self`.
+    pub fn prepare(
+        &mut self,
+        canister: Principal,
+        signature: &[u8],
+        before_module_hash: Option<Vec<u8>>,
+        now: u64,
+    ) -> UpgradeResult<Vec<u8>> {
+        self.check_not_in_progress()?;
+        let staged = self.staged().ok_or(UpgradeError::NoStagedWasm)?;
+        if staged.received != staged.len {
+            return Err(UpgradeError::IncompleteWasm {
+                received: staged.received,
+                expected: staged.len,
+            });
+        }
+
+        let wasm = self
+            .chunks
+            .iter()
+            .flat_map(|(_, chunk)| chunk)
+            .collect::<Vec<_>>();
+        let actual: [u8; 32] = Sha256::digest(&wasm).into();
+        if actual != staged.hash {
+            return Err(UpgradeError::HashMismatch {
+                expected: hex(&staged.hash),
+                actual: hex(&actual),
+            });
+        }
+
+        let state = self.state.get();
+        let admin_key = state.admin_key.ok_or(UpgradeError::AdminKeyNotSet)?;
+        let message = upgrade_message(canister, state.next_id, &staged.hash);
+        verify_ed25519(&admin_key, &message, signature).map_err(UpgradeError::InvalidSignature)?;
+
+        self.update(|state| {
+            state.history.push(UpgradeRecord {
+                id: state.next_id,
+                before_module_hash,
+                after_module_hash: staged.hash,
+                requested_at: now,
+                finished_at: None,
+                status: UpgradeStatus::Requested,
+            });
+            if state.history.len() > MAX_UPGRADE_HISTORY {
+                state.history.remove(0);
+            }
+            state.next_id += 1;
+        });
+        Ok(wasm)
+    }
+
+    /// Mark the requested upgrade completed and discard the installed module. Must be called in
+    /// the `post_upgrade` of the canister.
+    pub fn complete(&mut self, now: u64) -> Option<UpgradeRecord> {
+        let record = self.finish(UpgradeStatus::Completed, now)?;
+        self.chunks.clear();
+        self.update(|state| state.staged = None);
+        Some(record)
+    }
+
+    /// Mark the requested upgrade failed, keeping the staged module to retry.
+    pub fn fail(&mut self, error: &UpgradeError, now: u64) -> Option<UpgradeRecord> {
+        self.finish(UpgradeStatus::Failed(error.to_string()), now)
+    }
+
+    /// The upgrades, from the oldest to the latest.
+    pub fn history(&self) -> &[UpgradeRecord] {
+        &self.state.get().history
+    }
+
+    fn finish(&mut self, status: UpgradeStatus, now: u64) -> Option<UpgradeRecord> {
+        let mut state = self.state.get().clone();
+        let record = state
+            .history
+            .last_mut()
+            .filter(|record| record.status == UpgradeStatus::Requested)?;
+        record.status = status;
+        record.finished_at = Some(now);
+        let record = record.clone();
+
+        self.set(state);
+        Some(record)
+    }
+
+    fn check_not_in_progress(&self) -> UpgradeResult<()> {
+        match self.history().last() {
+            Some(record) if record.status == UpgradeStatus::Requested => {
+                Err(UpgradeError::UpgradeInProgress(record.id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update(&mut self, f: impl FnOnce(&mut UpgradeState)) {
+        let mut state = self.state.get().clone();
+        f(&mut state);
+        self.set(state);
+    }
+
+    fn set(&mut self, state: UpgradeState) {
+        self.state.set(state).expect("failed to write upgrade cell");
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(feature = "management_canister")]
+mod management {
+    use candid::utils::ArgumentEncoder;
+    use ic_exports::ic_kit::ic;
+
+    use super::{UpgradeError, UpgradeResult};
+    use crate::principal::management::{InstallCodeMode, ManagementPrincipalExt};
+
+    /// The hash of the module of the canister, which must be its own controller.
+    pub async fn current_module_hash() -> UpgradeResult<Option<Vec<u8>>> {
+        let status = ic::id().status().await.map_err(|(code, message)| {
+            UpgradeError::ManagementCallFailed(format!("{code:?}: {message}"))
+        })?;
+        Ok(status.module_hash)
+    }
+
+    /// Request the upgrade of the canister, which must be its own controller, to the module.
+    ///
+    /// The module and the argument are sent in one call, so they must fit
+    /// [`MAX_CALL_PAYLOAD_SIZE`](super::MAX_CALL_PAYLOAD_SIZE).
+    pub async fn install_self<T: ArgumentEncoder + Send>(
+        wasm: Vec<u8>,
+        arg: T,
+    ) -> UpgradeResult<()> {
+        ic::id()
+            .install_code(InstallCodeMode::Upgrade, wasm, arg)
+            .await
+            .map_err(|(code, message)| {
+                UpgradeError::ManagementCallFailed(format!("{code:?}: {message}"))
+            })
+    }
+}
+
+#[cfg(feature = "management_canister")]
+pub use management::{current_module_hash, install_self};
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    const CANISTER: Principal = Principal::from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    #[test]
+    fn upgrade_is_verified_and_recorded() {
+        let admin = SigningKey::from_bytes(&[5; 32]);
+        let memories = (VectorMemory::default(), VectorMemory::default());
+        let mut upgrade = SelfUpgrade::new(memories.0.clone(), memories.1.clone());
+        upgrade.set_admin_key(admin.verifying_key().to_bytes());
+
+        let wasm = vec![7u8; 100];
+        let hash: [u8; 32] = Sha256::digest(&wasm).into();
+        assert_eq!(
+            upgrade.stage(hash, MAX_WASM_SIZE + 1),
+            Err(UpgradeError::WasmTooLarge(MAX_WASM_SIZE))
+        );
+        upgrade.stage(hash, 100).unwrap();
+        assert_eq!(upgrade.append_chunk(wasm[..60].to_vec()), Ok(60));
+
+        let signature = admin.sign(&upgrade_message(CANISTER, 0, &hash)).to_bytes();
+        assert_eq!(
+            upgrade.prepare(CANISTER, &signature, None, 1),
+            Err(UpgradeError::IncompleteWasm {
+                received: 60,
+                expected: 100
+            })
+        );
+        assert_eq!(upgrade.append_chunk(wasm[60..].to_vec()), Ok(100));
+        assert_eq!(
+            upgrade.append_chunk(vec![0]),
+            Err(UpgradeError::WasmTooLarge(100))
+        );
+        assert!(matches!(
+            upgrade.prepare(CANISTER, &[0; 64], None, 1),
+            Err(UpgradeError::InvalidSignature(_))
+        ));
+
+        let before = Some(vec![1; 32]);
+        assert_eq!(
+            upgrade.prepare(CANISTER, &signature, before.clone(), 2),
+            Ok(wasm)
+        );
+        assert_eq!(
+            upgrade.stage(hash, 100),
+            Err(UpgradeError::UpgradeInProgress(0))
+        );
+
+        // The new module completes the upgrade in `post_upgrade`.
+        let mut upgrade = SelfUpgrade::new(memories.0, memories.1);
+        let record = upgrade.complete(3).unwrap();
+        assert_eq!(
+            record,
+            UpgradeRecord {
+                id: 0,
+                before_module_hash: before,
+                after_module_hash: hash,
+                requested_at: 2,
+                finished_at: Some(3),
+                status: UpgradeStatus::Completed,
+            }
+        );
+        assert_eq!(upgrade.history(), [record]);
+        assert_eq!(upgrade.staged(), None);
+        assert_eq!(upgrade.next_upgrade_id(), 1);
+
+        // The signature of the completed upgrade can't be used again.
+        upgrade.stage(hash, 100).unwrap();
+        upgrade.append_chunk(vec![7; 100]).unwrap();
+        assert!(matches!(
+            upgrade.prepare(CANISTER, &signature, None, 4),
+            Err(UpgradeError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn chunks_are_rejected_during_upgrade() {
+        let admin = SigningKey::from_bytes(&[5; 32]);
+        let mut upgrade = SelfUpgrade::new(VectorMemory::default(), VectorMemory::default());
+        upgrade.set_admin_key(admin.verifying_key().to_bytes());
+
+        let wasm = vec![7u8; 100];
+        let hash: [u8; 32] = Sha256::digest(&wasm).into();
+        upgrade.stage(hash, 100).unwrap();
+        upgrade.append_chunk(wasm.clone()).unwrap();
+        let signature = admin.sign(&upgrade_message(CANISTER, 0, &hash)).to_bytes();
+        upgrade.prepare(CANISTER, &signature, None, 1).unwrap();
+
+        assert_eq!(
+            upgrade.append_chunk(vec![0]),
+            Err(UpgradeError::UpgradeInProgress(0))
+        );
+        assert_eq!(upgrade.staged().unwrap().received, 100);
+
+        // The failed upgrade can be retried with the same module.
+        upgrade.fail(&UpgradeError::ManagementCallFailed("rejected".into()), 2);
+        assert_eq!(
+            upgrade.append_chunk(vec![0]),
+            Err(UpgradeError::WasmTooLarge(100))
+        );
+    }
+
+    #[test]
+    fn module_larger_than_max_is_rejected_on_append() {
+        let mut upgrade = SelfUpgrade::new(VectorMemory::default(), VectorMemory::default());
+        // A module staged with a larger limit, e.g. by an older version of the canister.
+        upgrade.update(|state| {
+            state.staged = Some(StagedWasm {
+                hash: [0; 32],
+                len: MAX_WASM_SIZE + 10,
+                received: MAX_WASM_SIZE - 10,
+            })
+        });
+
+        assert_eq!(
+            upgrade.append_chunk(vec![0; 20]),
+            Err(UpgradeError::WasmTooLarge(MAX_WASM_SIZE))
+        );
+        assert_eq!(upgrade.append_chunk(vec![0; 10]), Ok(MAX_WASM_SIZE));
+    }
+}
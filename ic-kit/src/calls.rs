@@ -0,0 +1,242 @@
+//! Assertions on the inter-canister calls recorded by the [`Watcher`].
+
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, Principal};
+
+use crate::{Watcher, WatcherCall};
+
+/// A filter over the calls recorded by the [`Watcher`], created by [`Watcher::call`].
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::*;
+///
+/// let ledger = Principal::from_text("ai7t5-aibaq-aaaaa-aaaaa-c").unwrap();
+/// let ctx = MockContext::new().with_constant_return_handler(()).inject();
+/// let watcher = ctx.watch();
+///
+/// let call = ic::call_with_payment::<_, (), _>(ledger, "transfer", (100u64,), 5);
+/// futures::executor::block_on(call).unwrap();
+///
+/// watcher.call(&ledger, "transfer").with_args((100u64,)).with_cycles(5).assert_called_once();
+/// watcher.call(&ledger, "transfer").with_args((200u64,)).assert_not_called();
+/// ```
+pub struct CallQuery<'a> {
+    watcher: &'a Watcher,
+    canister_id: Principal,
+    method_name: String,
+    args_raw: Option<Vec<u8>>,
+    cycles: Option<u64>,
+    is_notify: Option<bool>,
+}
+
+impl<'a> CallQuery<'a> {
+    fn new(watcher: &'a Watcher, canister_id: Principal, method_name: String) -> Self {
+        Self {
+            watcher,
+            canister_id,
+            method_name,
+            args_raw: None,
+            cycles: None,
+            is_notify: None,
+        }
+    }
+
+    /// Only match the calls made with the given arguments.
+    pub fn with_args<T: ArgumentEncoder>(mut self, args: T) -> Self {
+        self.args_raw = Some(encode_args(args).expect("Failed to encode arguments."));
+        self
+    }
+
+    /// Only match the calls which sent the given amount of cycles.
+    pub fn with_cycles(mut self, cycles: u64) -> Self {
+        self.cycles = Some(cycles);
+        self
+    }
+
+    /// Only match the one-way calls.
+    pub fn notify(mut self) -> Self {
+        self.is_notify = Some(true);
+        self
+    }
+
+    /// Return the matching calls in the order they were made.
+    pub fn calls(&self) -> impl Iterator<Item = &'a WatcherCall> + '_ {
+        self.watcher
+            .calls()
+            .iter()
+            .filter(move |call| self.matches(call))
+    }
+
+    /// Return the number of the matching calls.
+    pub fn count(&self) -> usize {
+        self.calls().count()
+    }
+
+    /// Assert that there is a matching call.
+    #[track_caller]
+    pub fn assert_called(&self) {
+        assert!(
+            self.count() > 0,
+            "Expected a call {}, but none was made.",
+            self
+        );
+    }
+
+    /// Assert that there is exactly one matching call.
+    #[track_caller]
+    pub fn assert_called_once(&self) {
+        self.assert_called_times(1);
+    }
+
+    /// Assert that there are `times` matching calls.
+    #[track_caller]
+    pub fn assert_called_times(&self, times: usize) {
+        let count = self.count();
+        assert_eq!(
+            count, times,
+            "Expected {} calls {}, but {} calls were made.",
+            times, self, count
+        );
+    }
+
+    /// Assert that there are no matching calls.
+    #[track_caller]
+    pub fn assert_not_called(&self) {
+        self.assert_called_times(0);
+    }
+
+    fn matches(&self, call: &WatcherCall) -> bool {
+        call.canister_id() == self.canister_id
+            && call.method_name() == self.method_name
+            && self
+                .args_raw
+                .as_ref()
+                .map_or(true, |args| args.as_slice() == call.args_raw())
+            && self
+                .cycles
+                .map_or(true, |cycles| cycles == call.cycles_sent())
+            && self
+                .is_notify
+                .map_or(true, |is_notify| is_notify == call.is_notify())
+    }
+}
+
+impl std::fmt::Display for CallQuery<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "to {}.{}", self.canister_id, self.method_name)?;
+        if self.args_raw.is_some() {
+            write!(f, " with the given arguments")?;
+        }
+        if let Some(cycles) = self.cycles {
+            write!(f, " with {} cycles", cycles)?;
+        }
+        Ok(())
+    }
+}
+
+impl Watcher {
+    /// Return a query over the calls made to the given method of the canister.
+    pub fn call<S: Into<String>>(&self, canister_id: &Principal, method_name: S) -> CallQuery<'_> {
+        CallQuery::new(self, *canister_id, method_name.into())
+    }
+
+    /// Assert that the given method of the canister was called exactly once, with the given
+    /// arguments.
+    #[track_caller]
+    pub fn assert_called_once_with<T: ArgumentEncoder>(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        args: T,
+    ) {
+        self.assert_call_count(canister_id, method_name, 1);
+        self.assert_called_with(canister_id, method_name, args);
+    }
+
+    /// Assert that the given methods were called in the given order. Other calls could be
+    /// made between them.
+    ///
+    /// # Panics
+    /// If one of the methods was not called after the previous one.
+    #[track_caller]
+    pub fn assert_call_order(&self, expected: &[(Principal, &str)]) {
+        let mut calls = self.calls().iter();
+        for (canister_id, method_name) in expected {
+            let found = calls.by_ref().any(|call| {
+                &call.canister_id() == canister_id && call.method_name() == *method_name
+            });
+            assert!(
+                found,
+                "Expected a call to {}.{} after the previous calls, but none was made.",
+                canister_id, method_name
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ic, mock_principals, MockContext};
+
+    #[tokio::test]
+    async fn call_assertions() {
+        let ctx = MockContext::new().with_consume_cycles_handler(10).inject();
+        let watcher = ctx.watch();
+        let ledger = mock_principals::xtc();
+
+        ic::call::<_, (), _>(ledger, "approve", (mock_principals::bob(),))
+            .await
+            .unwrap();
+        ic::call_with_payment::<_, (), _>(ledger, "transfer", (mock_principals::bob(), 10u64), 20)
+            .await
+            .unwrap();
+        ic::call::<_, (), _>(ledger, "transfer", (mock_principals::john(), 5u64))
+            .await
+            .unwrap();
+        ctx.record_call(WatcherCall::new(ledger, "log", vec![]).with_notify());
+
+        watcher.assert_called_once_with(&ledger, "approve", (mock_principals::bob(),));
+        watcher.call(&ledger, "transfer").assert_called_times(2);
+        watcher
+            .call(&ledger, "transfer")
+            .with_args((mock_principals::bob(), 10u64))
+            .with_cycles(20)
+            .assert_called_once();
+        watcher
+            .call(&ledger, "transfer")
+            .with_cycles(30)
+            .assert_not_called();
+        watcher.call(&ledger, "log").notify().assert_called();
+        watcher
+            .call(&ledger, "approve")
+            .notify()
+            .assert_not_called();
+        assert_eq!(
+            watcher
+                .call(&ledger, "transfer")
+                .calls()
+                .next()
+                .unwrap()
+                .cycles_consumed(),
+            10
+        );
+
+        watcher.assert_call_order(&[(ledger, "approve"), (ledger, "transfer"), (ledger, "log")]);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Expected a call")]
+    async fn wrong_call_order_panics() {
+        let ctx = MockContext::new().with_constant_return_handler(()).inject();
+        let watcher = ctx.watch();
+        let ledger = mock_principals::xtc();
+
+        ic::call::<_, (), _>(ledger, "approve", ()).await.unwrap();
+        ic::call::<_, (), _>(ledger, "transfer", ()).await.unwrap();
+
+        watcher.assert_call_order(&[(ledger, "transfer"), (ledger, "approve")]);
+    }
+}
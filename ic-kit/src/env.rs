@@ -0,0 +1,326 @@
+//! Simulate several canisters calling each other in the tests.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::{Rc, Weak};
+
+use candid::utils::ArgumentDecoder;
+use candid::{decode_args, encode_args};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+
+use crate::candid::CandidType;
+use crate::inject::{get_context, replace};
+use crate::{CallHandler, Context, MockContext, Principal};
+
+type MethodFn = Rc<dyn Fn(&[u8]) -> Vec<u8>>;
+
+/// An environment of several mock canisters, each one with its own [`MockContext`], and so
+/// its own id, balance, storage and stable storage.
+///
+/// One of the canisters is the current one, its context is injected as the context of the
+/// current thread. The calls made by a canister to the methods registered in the environment
+/// are executed in the context of the called canister.
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::*;
+///
+/// let counter = Principal::from_text("ai7t5-aibaq-aaaaa-aaaaa-c").unwrap();
+/// let client = Principal::from_text("hozae-racaq-aaaaa-aaaaa-c").unwrap();
+///
+/// let env = TestEnv::new();
+/// env.add_canister(MockContext::new().with_id(counter));
+/// env.add_canister(MockContext::new().with_id(client));
+/// env.add_method(counter, "increment", |(value,): (u64,)| {
+///     let count = ic::get_mut::<u64>();
+///     *count += value;
+///     *count
+/// });
+///
+/// env.switch_to(client);
+/// let call = ic::call::<_, (u64,), _>(counter, "increment", (5u64,));
+/// assert_eq!(futures::executor::block_on(call), Ok((5,)));
+///
+/// env.with_canister(counter, || assert_eq!(*ic::get::<u64>(), 5));
+/// assert_eq!(ic::id(), client);
+/// ```
+#[derive(Clone, Default)]
+pub struct TestEnv {
+    inner: Rc<RefCell<EnvInner>>,
+}
+
+#[derive(Default)]
+struct EnvInner {
+    canisters: BTreeMap<Principal, MockCanister>,
+    /// The canister which context is injected in the current thread.
+    current: Option<Principal>,
+}
+
+struct MockCanister {
+    /// The context of the canister, `None` while it is the current context.
+    context: Option<Box<MockContext>>,
+    /// The methods of the canister which could be called by other canisters.
+    methods: HashMap<String, MethodFn>,
+}
+
+/// A call handler which routes the calls to the canisters of the environment.
+struct EnvRouter {
+    env: Weak<RefCell<EnvInner>>,
+}
+
+impl TestEnv {
+    /// Create an empty environment.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the canister with the given context to the environment, and return its id.
+    /// The first added canister becomes the current one.
+    ///
+    /// # Panics
+    /// If a canister with the same id is already added.
+    pub fn add_canister(&self, mut context: MockContext) -> Principal {
+        let id = context.id();
+        context.use_handler(EnvRouter {
+            env: Rc::downgrade(&self.inner),
+        });
+
+        let is_first = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.canisters.contains_key(&id) {
+                panic!("Canister {} is already added to the environment.", id);
+            }
+
+            inner.canisters.insert(
+                id,
+                MockCanister {
+                    context: Some(Box::new(context)),
+                    methods: HashMap::new(),
+                },
+            );
+            inner.current.is_none()
+        };
+
+        if is_first {
+            self.switch_to(id);
+        }
+
+        id
+    }
+
+    /// Register the method of the canister which could be called by the other canisters.
+    /// The method is executed with the context of the canister injected, with the calling
+    /// canister as the caller. A panic in the method rejects the call with
+    /// [`RejectionCode::CanisterError`].
+    ///
+    /// # Panics
+    /// If the canister is not in the environment.
+    pub fn add_method<T, R, F>(&self, canister_id: Principal, name: &str, method: F)
+    where
+        T: for<'de> ArgumentDecoder<'de>,
+        R: CandidType,
+        F: 'static + Fn(T) -> R,
+    {
+        let method: MethodFn = Rc::new(move |bytes| {
+            let args = decode_args(bytes).expect("Failed to decode arguments.");
+            encode_args((method(args),)).expect("Failed to encode response.")
+        });
+
+        self.inner
+            .borrow_mut()
+            .canister_mut(&canister_id)
+            .methods
+            .insert(name.to_string(), method);
+    }
+
+    /// Make the given canister the current one by injecting its context into the current
+    /// thread, and return the context.
+    ///
+    /// # Panics
+    /// If the canister is not in the environment.
+    pub fn switch_to(&self, canister_id: Principal) -> &'static mut MockContext {
+        self.inner.borrow_mut().switch_to(canister_id);
+        get_context()
+    }
+
+    /// Run the closure with the given canister as the current one, and switch back to the
+    /// previous one after that.
+    pub fn with_canister<R>(&self, canister_id: Principal, f: impl FnOnce() -> R) -> R {
+        let previous = self.inner.borrow_mut().switch_to(canister_id);
+        let result = f();
+        if let Some(previous) = previous {
+            self.inner.borrow_mut().switch_to(previous);
+        }
+
+        result
+    }
+
+    /// Return the id of the current canister.
+    #[inline]
+    pub fn current(&self) -> Option<Principal> {
+        self.inner.borrow().current
+    }
+
+    /// Return the ids of the canisters in the environment.
+    pub fn canisters(&self) -> Vec<Principal> {
+        self.inner.borrow().canisters.keys().copied().collect()
+    }
+}
+
+impl EnvInner {
+    fn canister_mut(&mut self, canister_id: &Principal) -> &mut MockCanister {
+        self.canisters
+            .get_mut(canister_id)
+            .unwrap_or_else(|| panic!("Canister {} is not in the environment.", canister_id))
+    }
+
+    /// Inject the context of the canister and return the previous current canister.
+    fn switch_to(&mut self, canister_id: Principal) -> Option<Principal> {
+        let previous = self.current;
+        if previous == Some(canister_id) {
+            return previous;
+        }
+
+        let context = self
+            .canister_mut(&canister_id)
+            .context
+            .take()
+            .expect("The context of the canister is injected.");
+        let previous_context = replace(Some(context));
+
+        if let Some(previous) = previous {
+            let previous_context = previous_context.unwrap_or_else(|| {
+                panic!(
+                    "The context of canister {} was removed from the thread.",
+                    previous
+                )
+            });
+            self.canister_mut(&previous).context = Some(previous_context);
+        }
+
+        self.current = Some(canister_id);
+        previous
+    }
+}
+
+impl CallHandler for EnvRouter {
+    #[inline]
+    fn accept(&self, canister_id: &Principal, method: &str) -> bool {
+        self.env.upgrade().is_some_and(|env| {
+            env.borrow()
+                .canisters
+                .get(canister_id)
+                .is_some_and(|canister| canister.methods.contains_key(method))
+        })
+    }
+
+    fn perform(
+        &self,
+        caller: &Principal,
+        cycles: u64,
+        canister_id: &Principal,
+        method: &str,
+        args_raw: &[u8],
+        _ctx: Option<&mut MockContext>,
+    ) -> (CallResult<Vec<u8>>, u64) {
+        let env = self.env.upgrade().expect("The environment is dropped.");
+        let method = env.borrow_mut().canister_mut(canister_id).methods[method].clone();
+
+        let previous = env.borrow_mut().switch_to(*canister_id);
+        let ctx = get_context();
+        ctx.call_state_reset();
+        ctx.update_caller(*caller);
+        ctx.update_msg_cycles(cycles);
+
+        let result = catch_unwind(AssertUnwindSafe(|| method(args_raw))).map_err(|err| {
+            let message = err
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| err.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            (RejectionCode::CanisterError, message)
+        });
+
+        let refunded = ctx.msg_cycles_available();
+        ctx.update_msg_cycles(0);
+
+        if let Some(previous) = previous {
+            env.borrow_mut().switch_to(previous);
+        }
+
+        (result, refunded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ic, mock_principals};
+
+    fn env() -> TestEnv {
+        let env = TestEnv::new();
+        env.add_canister(MockContext::new().with_id(mock_principals::alice()));
+        env.add_canister(
+            MockContext::new()
+                .with_id(mock_principals::bob())
+                .with_balance(0),
+        );
+
+        env.add_method(mock_principals::bob(), "store", |(value,): (String,)| {
+            ic::store(value);
+            ic::msg_cycles_accept(100);
+            ic::caller()
+        });
+        env.add_method(mock_principals::bob(), "fail", |(): ()| -> u64 {
+            ic::trap("failed")
+        });
+
+        env
+    }
+
+    #[test]
+    fn calls_are_executed_in_the_context_of_the_callee() {
+        let env = env();
+        assert_eq!(env.current(), Some(mock_principals::alice()));
+        let alice = get_context();
+        let balance = alice.balance();
+
+        let call = ic::call_with_payment::<_, (Principal,), _>(
+            mock_principals::bob(),
+            "store",
+            ("hello".to_string(),),
+            300,
+        );
+        let (caller,) = futures::executor::block_on(call).unwrap();
+
+        assert_eq!(caller, mock_principals::alice());
+        assert_eq!(ic::id(), mock_principals::alice());
+        assert!(ic::get_maybe::<String>().is_none());
+        assert_eq!(alice.balance(), balance - 100);
+
+        let bob = env.switch_to(mock_principals::bob());
+        assert_eq!(ic::id(), mock_principals::bob());
+        assert_eq!(bob.get::<String>(), "hello");
+        assert_eq!(bob.balance(), 100);
+
+        env.with_canister(mock_principals::alice(), || {
+            assert_eq!(ic::id(), mock_principals::alice())
+        });
+        assert_eq!(ic::id(), mock_principals::bob());
+    }
+
+    #[test]
+    fn panic_rejects_the_call() {
+        let _env = env();
+
+        let call = ic::call::<_, (), _>(mock_principals::bob(), "fail", ());
+        let (code, message) = futures::executor::block_on(call).unwrap_err();
+
+        assert_eq!(code, RejectionCode::CanisterError);
+        assert!(message.contains("failed"));
+        assert_eq!(ic::id(), mock_principals::alice());
+    }
+}
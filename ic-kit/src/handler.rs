@@ -6,7 +6,7 @@ use std::collections::HashMap;
 
 use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{decode_args, encode_args};
-use ic_cdk::api::call::CallResult;
+use ic_cdk::api::call::{CallResult, RejectionCode};
 
 use crate::candid::CandidType;
 use crate::{Context, MockContext, Principal};
@@ -56,6 +56,25 @@ pub struct RawHandler {
     handler: Box<dyn Fn(&mut MockContext, &[u8], &Principal, &str) -> CallResult<Vec<u8>>>,
 }
 
+/// A handler of the calls to a single method of a single canister, it responds with a
+/// constant value, a reject or the value computed by a closure. All of the cycles sent
+/// to the method are refunded.
+pub struct Endpoint {
+    /// ID of the canister handled by the endpoint.
+    canister_id: Principal,
+    /// Name of the method handled by the endpoint.
+    method: String,
+    /// The response of the endpoint. By default `()` is returned.
+    response: EndpointResponse,
+}
+
+enum EndpointResponse {
+    Reply(Vec<u8>),
+    Reject(RejectionCode, String),
+    #[allow(clippy::type_complexity)]
+    Dynamic(Box<dyn Fn(&[u8]) -> CallResult<Vec<u8>>>),
+}
+
 /// Can be used to represent a canister and different method on the canister.
 pub struct Canister {
     /// ID of the canister, makes the CallHandler skip the call to this canister if it's trying
@@ -158,6 +177,49 @@ impl Method {
     }
 }
 
+impl Endpoint {
+    /// Create an endpoint handling the calls to the `method` of the given canister.
+    #[inline]
+    pub fn new<S: Into<String>>(canister_id: Principal, method: S) -> Self {
+        Self {
+            canister_id,
+            method: method.into(),
+            response: EndpointResponse::Reply(encode_args(()).unwrap()),
+        }
+    }
+
+    /// Make the endpoint return the given constant value every time.
+    #[inline]
+    pub fn response<T: CandidType>(mut self, value: T) -> Self {
+        let response = encode_args((value,)).expect("Failed to encode response.");
+        self.response = EndpointResponse::Reply(response);
+        self
+    }
+
+    /// Make the endpoint reject every call with the given code and message.
+    #[inline]
+    pub fn reject<S: Into<String>>(mut self, code: RejectionCode, message: S) -> Self {
+        self.response = EndpointResponse::Reject(code, message.into());
+        self
+    }
+
+    /// Make the endpoint respond with the result of the closure called with the decoded
+    /// arguments of the call.
+    #[inline]
+    pub fn handler<T, R, F>(mut self, handler: F) -> Self
+    where
+        T: for<'de> ArgumentDecoder<'de>,
+        R: CandidType,
+        F: 'static + Fn(T) -> CallResult<R>,
+    {
+        self.response = EndpointResponse::Dynamic(Box::new(move |bytes| {
+            let args = decode_args(bytes).expect("Failed to decode arguments.");
+            handler(args).map(|r| encode_args((r,)).expect("Failed to encode response."))
+        }));
+        self
+    }
+}
+
 impl Canister {
     /// Create a new canister with the given principal id, this handler rejects any call to a
     /// different canister id.
@@ -343,6 +405,32 @@ impl CallHandler for RawHandler {
     }
 }
 
+impl CallHandler for Endpoint {
+    #[inline]
+    fn accept(&self, canister_id: &Principal, method: &str) -> bool {
+        &self.canister_id == canister_id && self.method == method
+    }
+
+    #[inline]
+    fn perform(
+        &self,
+        _caller: &Principal,
+        cycles: u64,
+        _canister_id: &Principal,
+        _method: &str,
+        args_raw: &[u8],
+        _ctx: Option<&mut MockContext>,
+    ) -> (CallResult<Vec<u8>>, u64) {
+        let res = match &self.response {
+            EndpointResponse::Reply(bytes) => Ok(bytes.clone()),
+            EndpointResponse::Reject(code, message) => Err((*code, message.clone())),
+            EndpointResponse::Dynamic(handler) => handler(args_raw),
+        };
+
+        (res, cycles)
+    }
+}
+
 impl CallHandler for Canister {
     #[inline]
     fn accept(&self, canister_id: &Principal, method: &str) -> bool {
@@ -543,6 +631,31 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn endpoint() {
+        let alice = Principal::from_text("ai7t5-aibaq-aaaaa-aaaaa-c").unwrap();
+        let canister = Principal::management_canister();
+
+        let endpoint = Endpoint::new(canister, "balance").response(42u64);
+        assert!(endpoint.accept(&canister, "balance"));
+        assert!(!endpoint.accept(&canister, "deposit"));
+        assert!(!endpoint.accept(&alice, "balance"));
+
+        let (res, refunded) = endpoint.perform(&alice, 100, &canister, "balance", &[], None);
+        assert_eq!(decode_args::<(u64,)>(&res.unwrap()).unwrap(), (42,));
+        assert_eq!(refunded, 100);
+
+        let endpoint =
+            Endpoint::new(canister, "balance").reject(RejectionCode::CanisterReject, "no");
+        let (res, _) = endpoint.perform(&alice, 0, &canister, "balance", &[], None);
+        assert_eq!(res, Err((RejectionCode::CanisterReject, "no".to_string())));
+
+        let endpoint = Endpoint::new(canister, "double").handler(|(value,): (u64,)| Ok(value * 2));
+        let bytes = encode_args((21u64,)).unwrap();
+        let (res, _) = endpoint.perform(&alice, 0, &canister, "double", &bytes, None);
+        assert_eq!(decode_args::<(u64,)>(&res.unwrap()).unwrap(), (42,));
+    }
+
     #[test]
     fn expect_arguments() {
         let method = Method::new().expect_arguments((17u64,));
@@ -0,0 +1,207 @@
+//! HTTPS outcalls from the canisters.
+//!
+//! On the IC the requests are made by the management canister. In the tests the responses are
+//! mocked in the [`crate::MockContext`] by the URL of the request, so the code making outcalls
+//! can be tested offline.
+
+pub use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext, TransformFunc,
+};
+
+use crate::{CallResult, RejectionCode};
+
+/// The max size of a response when the `max_response_bytes` of the request is not set.
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+/// Make an HTTPS outcall with the given amount of cycles attached.
+///
+/// In tests the response is produced by the injected [`crate::MockContext`], the cycles are
+/// ignored.
+pub async fn http_request(
+    arg: CanisterHttpRequestArgument,
+    cycles: u128,
+) -> CallResult<(HttpResponse,)> {
+    #[cfg(target_family = "wasm")]
+    return ic_cdk::api::management_canister::http_request::http_request(arg, cycles).await;
+
+    #[cfg(not(target_family = "wasm"))]
+    {
+        let _ = cycles;
+        crate::inject::get_context()
+            .http_request(arg)
+            .map(|response| (response,))
+    }
+}
+
+pub(crate) type HttpHandlerFn =
+    Box<dyn Fn(&CanisterHttpRequestArgument) -> CallResult<HttpResponse>>;
+pub(crate) type HttpTransformFn = Box<dyn Fn(TransformArgs) -> HttpResponse>;
+
+/// A mocked response to the HTTPS outcalls with matching URLs.
+pub(crate) struct HttpMock {
+    /// The URL of the requests, a trailing `*` matches any suffix.
+    pattern: String,
+    handler: HttpHandlerFn,
+}
+
+impl HttpMock {
+    pub(crate) fn new(pattern: String, handler: HttpHandlerFn) -> Self {
+        Self { pattern, handler }
+    }
+
+    /// Whether the mock responds to the request with the given URL.
+    pub(crate) fn matches(&self, url: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => url.starts_with(prefix),
+            None => url == self.pattern,
+        }
+    }
+
+    pub(crate) fn respond(&self, arg: &CanisterHttpRequestArgument) -> CallResult<HttpResponse> {
+        (self.handler)(arg)
+    }
+}
+
+/// Return the size of the response as counted against the `max_response_bytes` of the request.
+pub(crate) fn response_size(response: &HttpResponse) -> u64 {
+    let headers_size: usize = response
+        .headers
+        .iter()
+        .map(|header| header.name.len() + header.value.len())
+        .sum();
+    (headers_size + response.body.len()) as u64
+}
+
+/// Check that the size of the response does not exceed the limit of the request.
+pub(crate) fn check_response_size(
+    arg: &CanisterHttpRequestArgument,
+    response: &HttpResponse,
+) -> CallResult<()> {
+    let max_response_bytes = arg.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+    if response_size(response) > max_response_bytes {
+        return Err((
+            RejectionCode::SysFatal,
+            format!(
+                "Http body exceeds size limit of {} bytes.",
+                max_response_bytes
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_principals, MockContext};
+
+    fn request(url: &str) -> CanisterHttpRequestArgument {
+        CanisterHttpRequestArgument {
+            url: url.to_string(),
+            max_response_bytes: None,
+            method: HttpMethod::GET,
+            headers: vec![],
+            body: None,
+            transform: None,
+        }
+    }
+
+    fn response(body: &str) -> HttpResponse {
+        HttpResponse {
+            status: 200u64.into(),
+            headers: vec![HttpHeader {
+                name: "date".to_string(),
+                value: "today".to_string(),
+            }],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn mocked_responses() {
+        let ctx = MockContext::new()
+            .with_http_response("https://example.com/*", response("any"))
+            .with_http_response("https://example.com/price", response("42"))
+            .with_http_handler("https://echo.com", |arg| {
+                Ok(response(
+                    std::str::from_utf8(arg.body.as_deref().unwrap_or_default()).unwrap(),
+                ))
+            })
+            .inject();
+        let watcher = ctx.watch();
+
+        let (res,) = http_request(request("https://example.com/price"), 0)
+            .await
+            .unwrap();
+        assert_eq!(res.body, b"42");
+        let (res,) = http_request(request("https://example.com/volume"), 0)
+            .await
+            .unwrap();
+        assert_eq!(res.body, b"any");
+
+        let mut echo = request("https://echo.com");
+        echo.method = HttpMethod::POST;
+        echo.body = Some(b"hello".to_vec());
+        let (res,) = http_request(echo, 0).await.unwrap();
+        assert_eq!(res.body, b"hello");
+
+        let (code, _) = http_request(request("https://unknown.com"), 0)
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::SysFatal);
+
+        let urls: Vec<_> = watcher
+            .http_requests()
+            .iter()
+            .map(|arg| arg.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/price",
+                "https://example.com/volume",
+                "https://echo.com",
+                "https://unknown.com"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn transform_and_max_response_bytes() {
+        MockContext::new()
+            .with_http_response("https://example.com", response("1234567890"))
+            .with_http_transform("strip_headers", |args: TransformArgs| HttpResponse {
+                headers: vec![],
+                body: [args.response.body, args.context].concat(),
+                ..args.response
+            })
+            .inject();
+
+        let mut arg = request("https://example.com");
+        arg.transform = Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: mock_principals::alice(),
+                method: "strip_headers".to_string(),
+            }),
+            context: b"!".to_vec(),
+        });
+        let (res,) = http_request(arg.clone(), 0).await.unwrap();
+        assert!(res.headers.is_empty());
+        assert_eq!(res.body, b"1234567890!");
+
+        // The limit is checked before the transform, with the headers included.
+        arg.max_response_bytes = Some(19);
+        http_request(arg.clone(), 0).await.unwrap();
+        arg.max_response_bytes = Some(18);
+        let (code, message) = http_request(arg.clone(), 0).await.unwrap_err();
+        assert_eq!(code, RejectionCode::SysFatal);
+        assert!(message.contains("18 bytes"));
+
+        arg.max_response_bytes = None;
+        arg.transform.as_mut().unwrap().function.0.method = "unknown".to_string();
+        let (code, _) = http_request(arg, 0).await.unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+    }
+}
@@ -1,4 +1,11 @@
+use std::time::Duration;
+
+#[cfg(target_family = "wasm")]
+pub use ic_cdk_timers::TimerId;
+
 use crate::candid::utils::{ArgumentDecoder, ArgumentEncoder};
+#[cfg(not(target_family = "wasm"))]
+pub use crate::mock::TimerId;
 use crate::{candid, CallResponse, Context, Principal};
 
 #[inline(always)]
@@ -170,3 +177,34 @@ pub fn data_certificate() -> Option<Vec<u8>> {
 pub fn spawn<F: 'static + std::future::Future<Output = ()>>(future: F) {
     get_context().spawn(future)
 }
+
+/// Set a timer which runs the callback once after the given delay.
+///
+/// In tests the timers are run by [`crate::MockContext::advance_time`].
+#[inline(always)]
+pub fn set_timer(delay: Duration, func: impl FnOnce() + 'static) -> TimerId {
+    #[cfg(not(target_family = "wasm"))]
+    return crate::inject::get_context().set_timer(delay, func);
+    #[cfg(target_family = "wasm")]
+    return ic_cdk_timers::set_timer(delay, func);
+}
+
+/// Set a timer which runs the callback every `interval`.
+///
+/// In tests the timers are run by [`crate::MockContext::advance_time`].
+#[inline(always)]
+pub fn set_timer_interval(interval: Duration, func: impl FnMut() + 'static) -> TimerId {
+    #[cfg(not(target_family = "wasm"))]
+    return crate::inject::get_context().set_timer_interval(interval, func);
+    #[cfg(target_family = "wasm")]
+    return ic_cdk_timers::set_timer_interval(interval, func);
+}
+
+/// Cancel the timer.
+#[inline(always)]
+pub fn clear_timer(id: TimerId) {
+    #[cfg(not(target_family = "wasm"))]
+    crate::inject::get_context().clear_timer(id);
+    #[cfg(target_family = "wasm")]
+    ic_cdk_timers::clear_timer(id);
+}
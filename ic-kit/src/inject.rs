@@ -2,14 +2,19 @@ use std::cell::RefCell;
 
 use crate::MockContext;
 
-thread_local!(static CONTEXT: RefCell<Option<MockContext>> = const { RefCell::new(None) });
+// The context is boxed so references to it stay valid when it's replaced by another one.
+thread_local!(static CONTEXT: RefCell<Option<Box<MockContext>>> = const { RefCell::new(None) });
 
 /// Inject the given context to be used in the current thread.
 #[inline]
 pub fn inject(ctx: MockContext) {
-    CONTEXT.with(|f| {
-        *f.borrow_mut() = Some(ctx);
-    });
+    replace(Some(Box::new(ctx)));
+}
+
+/// Replace the context of the current thread, returning the previous one.
+#[inline]
+pub(crate) fn replace(ctx: Option<Box<MockContext>>) -> Option<Box<MockContext>> {
+    CONTEXT.with(|f| std::mem::replace(&mut *f.borrow_mut(), ctx))
 }
 
 /// Return the mutable reference to the context of the current thread.
@@ -23,13 +28,21 @@ pub fn get_context() -> &'static mut MockContext {
             Are you using .inject() on the MockContext?",
         );
         unsafe {
-            let const_ptr = ctx as *const MockContext;
+            let const_ptr = &**ctx as *const MockContext;
             let mut_ptr = const_ptr as *mut MockContext;
             ::std::mem::transmute::<*mut MockContext, &mut MockContext>(mut_ptr)
         }
     })
 }
 
+/// Return the mutable reference to the context of the current thread, or `None` if no context
+/// is injected.
+#[inline]
+pub fn try_get_context() -> Option<&'static mut MockContext> {
+    let is_injected = CONTEXT.with(|cell| cell.borrow().is_some());
+    is_injected.then(get_context)
+}
+
 #[cfg(test)]
 mod tests {
     use std::thread;
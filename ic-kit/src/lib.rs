@@ -1,17 +1,25 @@
+pub use calls::*;
+pub use env::*;
 pub use handler::*;
 pub use interface::*;
+pub use management::*;
 pub use mock::*;
+pub use snapshot::*;
 
+mod calls;
+mod env;
 mod handler;
 pub mod inject;
 mod interface;
+mod management;
 mod mock;
+mod snapshot;
 #[cfg(target_family = "wasm")]
 mod wasm;
 
-pub use candid::Principal;
+pub use candid::{self, Principal};
 pub use ic_cdk::api::call::{CallResult, RejectionCode};
-pub use {candid, ic_cdk_macros as macros};
+pub use ic_cdk_macros as macros;
 
 /// A set of mock principal IDs useful for testing.
 #[cfg(not(target_family = "wasm"))]
@@ -39,7 +47,19 @@ pub mod mock_principals {
     }
 }
 
+pub mod http;
+/// Assert that the expression traps with a message containing the given string, and roll back
+/// the changes made to the injected context. See [`MockContext::assert_traps_with`].
+#[cfg(not(target_family = "wasm"))]
+#[macro_export]
+macro_rules! assert_traps_with {
+    ($expr:expr, $message:expr $(,)?) => {
+        $crate::inject::get_context().assert_traps_with(|| $expr, $message)
+    };
+}
+
 /// APIs/Methods to work with the Internet Computer.
 pub mod ic;
 /// The type definition of common canisters on the Internet Computer.
 pub mod interfaces;
+pub mod rand;
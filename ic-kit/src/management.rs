@@ -0,0 +1,380 @@
+//! Simulate the management canister in the tests.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, Nat};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use sha2::{Digest, Sha256};
+
+use crate::interfaces::management::{
+    CanisterSettings, CanisterStatusResponse, CreateCanisterArgument, DefiniteCanisterSettings,
+    InstallCodeArgument, InstallMode, Status, WithCanisterId,
+};
+use crate::{CallHandler, MockContext, Principal};
+
+/// A canister created by the [`MockManagementCanister`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManagedCanister {
+    /// The settings of the canister.
+    pub settings: DefiniteCanisterSettings,
+    /// The status of the canister.
+    pub status: Status,
+    /// The installed WASM module, `None` for an empty canister.
+    pub wasm_module: Option<Vec<u8>>,
+    /// The argument passed to the last installation of the code.
+    pub arg: Vec<u8>,
+    /// The mode of the last installation of the code.
+    pub install_mode: Option<InstallMode>,
+    /// The balance of the canister.
+    pub cycles: u64,
+}
+
+/// A call handler which simulates the `create_canister`, `install_code`, `canister_status` and
+/// `deposit_cycles` methods of the management canister, with an in-memory registry of the
+/// created canisters.
+///
+/// The clones of the handler share the registry, so the canisters could be inspected after
+/// the handler is added to the context.
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::*;
+/// use ic_kit::interfaces::Method;
+/// use ic_kit::interfaces::management::*;
+///
+/// let management = MockManagementCanister::new();
+/// MockContext::new().with_handler(management.clone()).inject();
+///
+/// let call = CreateCanister::perform_with_payment(
+///     Principal::management_canister(),
+///     (CreateCanisterArgument { settings: None },),
+///     1_000,
+/// );
+/// let (WithCanisterId { canister_id },) = futures::executor::block_on(call).unwrap();
+///
+/// let canister = management.canister(&canister_id).unwrap();
+/// assert_eq!(canister.cycles, 1_000);
+/// assert_eq!(canister.settings.controllers, vec![ic::id()]);
+/// ```
+#[derive(Clone, Default)]
+pub struct MockManagementCanister {
+    inner: Rc<RefCell<Registry>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    canisters: BTreeMap<Principal, ManagedCanister>,
+    /// The number of the created canisters, used to generate their ids.
+    created: u64,
+}
+
+impl MockManagementCanister {
+    const METHODS: [&'static str; 4] = [
+        "create_canister",
+        "install_code",
+        "canister_status",
+        "deposit_cycles",
+    ];
+
+    /// Create a management canister without canisters.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the canister with the given id.
+    pub fn canister(&self, canister_id: &Principal) -> Option<ManagedCanister> {
+        self.inner.borrow().canisters.get(canister_id).cloned()
+    }
+
+    /// Return the ids of the created canisters.
+    pub fn canister_ids(&self) -> Vec<Principal> {
+        self.inner.borrow().canisters.keys().copied().collect()
+    }
+
+    fn create_canister(
+        &self,
+        caller: &Principal,
+        cycles: u64,
+        (arg,): (CreateCanisterArgument,),
+    ) -> CallResult<(WithCanisterId,)> {
+        let settings = arg.settings.unwrap_or(CanisterSettings {
+            controllers: None,
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+        });
+
+        let mut registry = self.inner.borrow_mut();
+        registry.created += 1;
+        let canister_id = canister_id(registry.created);
+        registry.canisters.insert(
+            canister_id,
+            ManagedCanister {
+                settings: DefiniteCanisterSettings {
+                    controllers: settings.controllers.unwrap_or_else(|| vec![*caller]),
+                    compute_allocation: settings.compute_allocation.unwrap_or_else(|| 0u64.into()),
+                    memory_allocation: settings.memory_allocation.unwrap_or_else(|| 0u64.into()),
+                    freezing_threshold: settings
+                        .freezing_threshold
+                        .unwrap_or_else(|| Nat::from(2_592_000u64)),
+                },
+                status: Status::Running,
+                wasm_module: None,
+                arg: vec![],
+                install_mode: None,
+                cycles,
+            },
+        );
+
+        Ok((WithCanisterId { canister_id },))
+    }
+
+    fn install_code(&self, caller: &Principal, (arg,): (InstallCodeArgument,)) -> CallResult<()> {
+        self.with_controlled_canister(caller, &arg.canister_id, |canister| {
+            let error = match (&arg.mode, &canister.wasm_module) {
+                (InstallMode::Install, Some(_)) => Some("installed because it is not empty"),
+                (InstallMode::Upgrade, None) => Some("upgraded because it has no code installed"),
+                _ => None,
+            };
+            if let Some(error) = error {
+                return Err((
+                    RejectionCode::CanisterError,
+                    format!("Canister {} cannot be {}.", arg.canister_id, error),
+                ));
+            }
+
+            canister.wasm_module = Some(arg.wasm_module);
+            canister.arg = arg.arg;
+            canister.install_mode = Some(arg.mode);
+            Ok(())
+        })
+    }
+
+    fn canister_status(
+        &self,
+        caller: &Principal,
+        (arg,): (WithCanisterId,),
+    ) -> CallResult<(CanisterStatusResponse,)> {
+        self.with_controlled_canister(caller, &arg.canister_id, |canister| {
+            let memory_size = canister.wasm_module.as_ref().map_or(0, Vec::len) as u64;
+            Ok((CanisterStatusResponse {
+                status: canister.status.clone(),
+                settings: canister.settings.clone(),
+                module_hash: canister
+                    .wasm_module
+                    .as_ref()
+                    .map(|module| Sha256::digest(module).to_vec()),
+                memory_size: Nat::from(memory_size),
+                cycles: Nat::from(canister.cycles),
+            },))
+        })
+    }
+
+    fn deposit_cycles(&self, cycles: u64, (arg,): (WithCanisterId,)) -> CallResult<()> {
+        let mut registry = self.inner.borrow_mut();
+        let canister = registry
+            .canisters
+            .get_mut(&arg.canister_id)
+            .ok_or_else(|| not_found(&arg.canister_id))?;
+        canister.cycles += cycles;
+        Ok(())
+    }
+
+    fn with_controlled_canister<R>(
+        &self,
+        caller: &Principal,
+        canister_id: &Principal,
+        f: impl FnOnce(&mut ManagedCanister) -> CallResult<R>,
+    ) -> CallResult<R> {
+        let mut registry = self.inner.borrow_mut();
+        let canister = registry
+            .canisters
+            .get_mut(canister_id)
+            .ok_or_else(|| not_found(canister_id))?;
+
+        if !canister.settings.controllers.contains(caller) {
+            return Err((
+                RejectionCode::CanisterError,
+                format!(
+                    "Only the controllers of the canister {} can control it.",
+                    canister_id
+                ),
+            ));
+        }
+
+        f(canister)
+    }
+}
+
+/// Generate the id of the n-th created canister.
+fn canister_id(n: u64) -> Principal {
+    let mut bytes = [1; 10];
+    bytes[..8].copy_from_slice(&(0x0100_0000 + n).to_be_bytes());
+    Principal::from_slice(&bytes)
+}
+
+fn not_found(canister_id: &Principal) -> (RejectionCode, String) {
+    (
+        RejectionCode::DestinationInvalid,
+        format!("Canister {} not found", canister_id),
+    )
+}
+
+/// Decode the arguments, perform the method and encode the response.
+fn handle<T, R>(args_raw: &[u8], method: impl FnOnce(T) -> CallResult<R>) -> CallResult<Vec<u8>>
+where
+    T: for<'de> ArgumentDecoder<'de>,
+    R: ArgumentEncoder,
+{
+    let args = decode_args(args_raw).map_err(|e| {
+        (
+            RejectionCode::CanisterError,
+            format!("Failed to decode arguments: {}", e),
+        )
+    })?;
+    let response = method(args)?;
+    Ok(encode_args(response).expect("Failed to encode response."))
+}
+
+impl CallHandler for MockManagementCanister {
+    #[inline]
+    fn accept(&self, canister_id: &Principal, method: &str) -> bool {
+        canister_id == &Principal::management_canister() && Self::METHODS.contains(&method)
+    }
+
+    fn perform(
+        &self,
+        caller: &Principal,
+        cycles: u64,
+        _canister_id: &Principal,
+        method: &str,
+        args_raw: &[u8],
+        _ctx: Option<&mut MockContext>,
+    ) -> (CallResult<Vec<u8>>, u64) {
+        let result = match method {
+            "create_canister" => {
+                handle(args_raw, |args| self.create_canister(caller, cycles, args))
+            }
+            "install_code" => handle(args_raw, |args| self.install_code(caller, args)),
+            "canister_status" => handle(args_raw, |args| self.canister_status(caller, args)),
+            "deposit_cycles" => handle(args_raw, |args| self.deposit_cycles(cycles, args)),
+            _ => unreachable!("The method is not accepted by the handler."),
+        };
+
+        // The cycles are kept by the created canister or deposited, the other methods refund
+        // them.
+        let keeps_cycles = matches!(method, "create_canister" | "deposit_cycles");
+        let refunded = if result.is_ok() && keeps_cycles {
+            0
+        } else {
+            cycles
+        };
+
+        (result, refunded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces::management::{
+        CanisterStatus, CreateCanister, DepositCycles, InstallCode,
+    };
+    use crate::interfaces::Method;
+    use crate::mock_principals;
+
+    #[tokio::test]
+    async fn canister_lifecycle() {
+        let management = MockManagementCanister::new();
+        let ctx = MockContext::new().with_handler(management.clone()).inject();
+        let balance = ctx.balance();
+        let management_id = Principal::management_canister();
+
+        let (WithCanisterId { canister_id },) = CreateCanister::perform_with_payment(
+            management_id,
+            (CreateCanisterArgument { settings: None },),
+            1_000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(ctx.balance(), balance - 1_000);
+        assert_eq!(management.canister_ids(), vec![canister_id]);
+
+        let install = |mode| InstallCodeArgument {
+            mode,
+            canister_id,
+            wasm_module: b"wasm".to_vec(),
+            arg: b"arg".to_vec(),
+        };
+        let (code, _) = InstallCode::perform(management_id, (install(InstallMode::Upgrade),))
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+        InstallCode::perform(management_id, (install(InstallMode::Install),))
+            .await
+            .unwrap();
+        InstallCode::perform(management_id, (install(InstallMode::Install),))
+            .await
+            .unwrap_err();
+        InstallCode::perform(management_id, (install(InstallMode::Upgrade),))
+            .await
+            .unwrap();
+
+        DepositCycles::perform_with_payment(management_id, (WithCanisterId { canister_id },), 500)
+            .await
+            .unwrap();
+        assert_eq!(ctx.balance(), balance - 1_500);
+
+        let (status,) = CanisterStatus::perform(management_id, (WithCanisterId { canister_id },))
+            .await
+            .unwrap();
+        assert_eq!(status.status, Status::Running);
+        assert_eq!(status.cycles, Nat::from(1_500u64));
+        assert_eq!(status.module_hash, Some(Sha256::digest(b"wasm").to_vec()));
+        assert_eq!(status.settings.controllers, vec![ctx.id()]);
+
+        let canister = management.canister(&canister_id).unwrap();
+        assert_eq!(canister.install_mode, Some(InstallMode::Upgrade));
+        assert_eq!(canister.arg, b"arg");
+    }
+
+    #[tokio::test]
+    async fn only_controllers_control_the_canister() {
+        let management = MockManagementCanister::new();
+        let ctx = MockContext::new().with_handler(management.clone()).inject();
+        let management_id = Principal::management_canister();
+
+        let (WithCanisterId { canister_id },) = CreateCanister::perform(
+            management_id,
+            (CreateCanisterArgument {
+                settings: Some(CanisterSettings {
+                    controllers: Some(vec![mock_principals::alice()]),
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                }),
+            },),
+        )
+        .await
+        .unwrap();
+
+        ctx.update_id(mock_principals::bob());
+        let (code, _) = CanisterStatus::perform(management_id, (WithCanisterId { canister_id },))
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::CanisterError);
+
+        let unknown = WithCanisterId {
+            canister_id: mock_principals::john(),
+        };
+        let (code, _) = DepositCycles::perform_with_payment(management_id, (unknown,), 100)
+            .await
+            .unwrap_err();
+        assert_eq!(code, RejectionCode::DestinationInvalid);
+    }
+}
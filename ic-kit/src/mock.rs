@@ -1,7 +1,7 @@
 use std::any::{Any, TypeId};
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::hash::Hasher;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{self, decode_args, encode_args, Principal};
@@ -9,9 +9,15 @@ use futures::executor::LocalPool;
 use serde::Serialize;
 
 use crate::candid::CandidType;
+use crate::http::{
+    check_response_size, CanisterHttpRequestArgument, HttpHandlerFn, HttpMock, HttpResponse,
+    HttpTransformFn, TransformArgs,
+};
 use crate::inject::{get_context, inject};
 use crate::interface::{CallResponse, Context};
-use crate::{CallHandler, Method};
+use crate::rand::Rand;
+use crate::snapshot::{ContextDiff, ContextSnapshot, StorageMarker};
+use crate::{CallHandler, CallResult, Endpoint, Method, RejectionCode};
 
 /// A context that could be used to fake/control the behaviour of the IC when testing the canister.
 pub struct MockContext {
@@ -21,6 +27,8 @@ pub struct MockContext {
     id: Principal,
     /// The balance of the canister. By default set to 100TC.
     balance: u64,
+    /// The balance below which the canister can not send cycles. By default zero.
+    freezing_threshold: u64,
     /// The caller principal passed to the calls, by default `anonymous` is used.
     caller: Principal,
     /// Determines if a call was made or not.
@@ -33,19 +41,102 @@ pub struct MockContext {
     cycles_refunded: u64,
     /// The storage tree for the current context.
     storage: BTreeMap<TypeId, Box<dyn Any>>,
+    /// The markers of the storage values, updated every time a value is modified.
+    storage_markers: BTreeMap<TypeId, StorageMarker>,
+    /// The number of modifications of the storage values, used as the marker version.
+    storage_version: u64,
     /// The stable storage data.
     stable: Vec<u8>,
     /// The certified data.
     certified_data: Option<Vec<u8>>,
     /// The certificate certifying the certified_data.
     certificate: Option<Vec<u8>>,
+    /// Whether the context simulates an update call, in which the data certificate is not
+    /// available.
+    is_update_call: bool,
     /// The handlers used to handle inter-canister calls.
     handlers: Vec<Box<dyn CallHandler>>,
     time: u64,
+    /// The timers set by the canister.
+    timers: BTreeMap<TimerId, MockTimer>,
+    /// The id to be given to the next timer.
+    next_timer_id: u64,
+    /// The interval timer which callback is being executed.
+    running_timer: Option<TimerId>,
+    /// The generator of the `raw_rand` responses.
+    rand: Rand,
+    /// The number of times the heap of each canister was wiped by an upgrade.
+    heap_generations: BTreeMap<Principal, u64>,
+    /// The mocked responses to the HTTPS outcalls, the first matching one is used.
+    http_mocks: Vec<HttpMock>,
+    /// The transform functions of the HTTPS outcalls by the method name.
+    http_transforms: HashMap<String, HttpTransformFn>,
     /// All of the spawned futures.
     pool: LocalPool,
 }
 
+/// The part of the [`MockContext`] state which is restored when an execution traps.
+struct StateBackup {
+    stable: Vec<u8>,
+    balance: u64,
+    cycles: u64,
+    certified_data: Option<Vec<u8>>,
+    certificate: Option<Vec<u8>>,
+    next_timer_id: u64,
+    calls: usize,
+    http_requests: usize,
+    cycles_accepted: u64,
+}
+
+impl StateBackup {
+    fn new(ctx: &MockContext) -> Self {
+        Self {
+            stable: ctx.stable.clone(),
+            balance: ctx.balance,
+            cycles: ctx.cycles,
+            certified_data: ctx.certified_data.clone(),
+            certificate: ctx.certificate.clone(),
+            next_timer_id: ctx.next_timer_id,
+            calls: ctx.watcher.calls.len(),
+            http_requests: ctx.watcher.http_requests.len(),
+            cycles_accepted: ctx.watcher.cycles_accepted,
+        }
+    }
+
+    fn restore(self, ctx: &MockContext) {
+        let ctx = ctx.as_mut();
+        ctx.stable = self.stable;
+        ctx.balance = self.balance;
+        ctx.cycles = self.cycles;
+        ctx.certified_data = self.certified_data;
+        ctx.certificate = self.certificate;
+        ctx.timers.retain(|id, _| id.0 < self.next_timer_id);
+        ctx.watcher.calls.truncate(self.calls);
+        ctx.watcher.http_requests.truncate(self.http_requests);
+        ctx.watcher.cycles_accepted = self.cycles_accepted;
+    }
+}
+
+/// Identifier of a timer set in the [`MockContext`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+/// A timer set in the [`MockContext`].
+struct MockTimer {
+    /// The time the timer is due at.
+    due: u64,
+    /// The callback to run when the timer is due.
+    callback: TimerCallback,
+}
+
+enum TimerCallback {
+    Once(Box<dyn FnOnce()>),
+    Interval {
+        interval: u64,
+        func: Box<dyn FnMut()>,
+    },
+}
+
 /// A watcher can be used to inspect the calls made in a call.
 pub struct Watcher {
     /// True if the `context.id()` was called during execution.
@@ -72,16 +163,22 @@ pub struct Watcher {
     pub called_data_certificate: bool,
     /// Storage items that were mutated.
     storage_modified: BTreeSet<TypeId>,
+    /// The amount of cycles accepted from the callers.
+    cycles_accepted: u64,
     /// List of all the inter canister calls that took place.
     calls: Vec<WatcherCall>,
+    /// List of all the HTTPS outcalls that took place.
+    http_requests: Vec<CanisterHttpRequestArgument>,
 }
 
+/// An inter-canister call recorded by the [`Watcher`].
 pub struct WatcherCall {
     canister_id: Principal,
     method_name: String,
     args_raw: Vec<u8>,
     cycles_sent: u64,
     cycles_refunded: u64,
+    is_notify: bool,
 }
 
 impl MockContext {
@@ -97,17 +194,28 @@ impl MockContext {
             watcher: Watcher::default(),
             id: Principal::from_text("sgymv-uiaaa-aaaaa-aaaia-cai").unwrap(),
             balance: 100_000_000_000_000,
+            freezing_threshold: 0,
             caller: Principal::anonymous(),
             is_reply_callback_mode: false,
             trapped: false,
             cycles: 0,
             cycles_refunded: 0,
             storage: BTreeMap::new(),
+            storage_markers: BTreeMap::new(),
+            storage_version: 0,
             stable: Vec::new(),
             certified_data: None,
             certificate: None,
+            is_update_call: false,
             handlers: vec![],
             time,
+            timers: BTreeMap::new(),
+            next_timer_id: 0,
+            running_timer: None,
+            rand: Rand::seed_from_u64(0),
+            heap_generations: BTreeMap::new(),
+            http_mocks: vec![],
+            http_transforms: HashMap::new(),
             pool: LocalPool::new(),
         }
     }
@@ -163,6 +271,31 @@ impl MockContext {
         self
     }
 
+    /// Set the freezing threshold of the canister. Inter-canister calls which would make the
+    /// balance drop below the threshold fail with [`RejectionCode::SysTransient`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_kit::*;
+    ///
+    /// let ctx = MockContext::new()
+    ///     .with_balance(1000)
+    ///     .with_freezing_threshold(800)
+    ///     .with_consume_cycles_handler(300)
+    ///     .inject();
+    ///
+    /// let call = ic::call_with_payment(Principal::management_canister(), "deposit_cycles", (), 300);
+    /// let res: CallResult<()> = futures::executor::block_on(call);
+    /// assert_eq!(res.unwrap_err().0, RejectionCode::SysTransient);
+    /// assert_eq!(ctx.balance(), 1000);
+    /// ```
+    #[inline]
+    pub fn with_freezing_threshold(mut self, cycles: u64) -> Self {
+        self.freezing_threshold = cycles;
+        self
+    }
+
     /// Set the caller for the current call.
     ///
     /// # Example
@@ -230,6 +363,7 @@ impl MockContext {
     pub fn with_data<T: 'static>(mut self, data: T) -> Self {
         let type_id = std::any::TypeId::of::<T>();
         self.storage.insert(type_id, Box::new(data));
+        self.track_storage::<T>();
         self
     }
 
@@ -258,6 +392,97 @@ impl MockContext {
         self
     }
 
+    /// Set the current time in nanoseconds, by default the system time is used.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_kit::*;
+    /// use ic_kit::inject::get_context;
+    ///
+    /// MockContext::new()
+    ///     .with_time(1_000)
+    ///     .inject();
+    ///
+    /// let ic = get_context();
+    /// assert_eq!(ic.time(), 1_000);
+    /// ```
+    #[inline]
+    pub fn with_time(mut self, time: u64) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Seed the generator of the random bytes returned by [`crate::rand::raw_rand`]. The same
+    /// seed gives the same bytes, by default zero is used.
+    #[inline]
+    pub fn with_rand_seed(mut self, seed: u64) -> Self {
+        self.rand = Rand::seed_from_u64(seed);
+        self
+    }
+
+    /// Respond to the HTTPS outcalls to the given URL with the response. A `*` at the end of
+    /// the URL matches any suffix. The responses added later take precedence.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_kit::*;
+    /// use ic_kit::http::*;
+    ///
+    /// let response = HttpResponse {
+    ///     status: 200u64.into(),
+    ///     headers: vec![],
+    ///     body: b"42".to_vec(),
+    /// };
+    ///
+    /// MockContext::new()
+    ///     .with_http_response("https://example.com/api/*", response)
+    ///     .inject();
+    ///
+    /// let request = CanisterHttpRequestArgument {
+    ///     url: "https://example.com/api/price".to_string(),
+    ///     max_response_bytes: None,
+    ///     method: HttpMethod::GET,
+    ///     headers: vec![],
+    ///     body: None,
+    ///     transform: None,
+    /// };
+    /// let (response,) = futures::executor::block_on(http_request(request, 0)).unwrap();
+    /// assert_eq!(response.body, b"42");
+    /// ```
+    #[inline]
+    pub fn with_http_response<S: Into<String>>(self, url: S, response: HttpResponse) -> Self {
+        self.with_http_handler(url, move |_| Ok(response.clone()))
+    }
+
+    /// Respond to the HTTPS outcalls to the given URL with the result of the handler. A `*`
+    /// at the end of the URL matches any suffix. The handlers added later take precedence.
+    #[inline]
+    pub fn with_http_handler<S, F>(mut self, url: S, handler: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&CanisterHttpRequestArgument) -> CallResult<HttpResponse> + 'static,
+    {
+        let handler: HttpHandlerFn = Box::new(handler);
+        self.http_mocks
+            .insert(0, HttpMock::new(url.into(), handler));
+        self
+    }
+
+    /// Register the transform function of the HTTPS outcalls. It is applied to the responses
+    /// of the requests which transform context refers to the method with the given name.
+    #[inline]
+    pub fn with_http_transform<S, F>(mut self, method: S, transform: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(TransformArgs) -> HttpResponse + 'static,
+    {
+        self.http_transforms
+            .insert(method.into(), Box::new(transform));
+        self
+    }
+
     /// Set the certified data of the canister.
     #[inline]
     pub fn with_certified_data(mut self, data: Vec<u8>) -> Self {
@@ -267,6 +492,29 @@ impl MockContext {
         self
     }
 
+    /// Simulate an update call. Like on the IC, the data certificate is only available in
+    /// queries, so [`Context::data_certificate`] returns `None` in an update call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_kit::*;
+    ///
+    /// let ctx = MockContext::new().with_update_call().inject();
+    ///
+    /// ic::set_certified_data(&[1, 2, 3]);
+    /// assert_eq!(ic::data_certificate(), None);
+    ///
+    /// ctx.set_update_call(false);
+    /// let certificate = ic::data_certificate().unwrap();
+    /// assert!(MockContext::verify_certificate(&certificate, &[1, 2, 3]));
+    /// ```
+    #[inline]
+    pub fn with_update_call(mut self) -> Self {
+        self.is_update_call = true;
+        self
+    }
+
     /// Creates a mock context with a default handler that accepts the given amount of cycles
     /// on every request.
     #[inline]
@@ -301,6 +549,29 @@ impl MockContext {
         self
     }
 
+    /// Add the given endpoint to the handlers pipeline. The endpoint takes precedence over
+    /// the handlers added before it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_kit::*;
+    ///
+    /// let canister = Principal::from_text("ai7t5-aibaq-aaaaa-aaaaa-c").unwrap();
+    ///
+    /// MockContext::new()
+    ///     .with_endpoint(Endpoint::new(canister, "get_balance").response(100u64))
+    ///     .with_endpoint(
+    ///         Endpoint::new(canister, "transfer").reject(RejectionCode::CanisterReject, "no funds"),
+    ///     )
+    ///     .inject();
+    /// ```
+    #[inline]
+    pub fn with_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.use_endpoint(endpoint);
+        self
+    }
+
     /// Use this context as the default context for this thread.
     #[inline]
     pub fn inject(self) -> &'static mut Self {
@@ -334,6 +605,13 @@ impl MockContext {
         certificate
     }
 
+    /// Verify that the certificate returned by the [`Context::data_certificate`] of a mock
+    /// context certifies the given data, like a client of the canister would do with the
+    /// certificate of the IC.
+    pub fn verify_certificate(certificate: &[u8], certified_data: &[u8]) -> bool {
+        certified_data.len() <= 32 && MockContext::sign(certified_data) == certificate
+    }
+
     /// This is how we do interior mutability for MockContext. Since the context is only accessible
     /// by only one thread, it is safe to do it here.
     #[inline]
@@ -360,14 +638,300 @@ impl MockContext {
     /// Clear the storage.
     #[inline]
     pub fn clear_storage(&self) {
+        self.mark_all_storage_modified();
         self.as_mut().storage.clear()
     }
 
+    /// Simulate an upgrade of the current canister. Runs `pre_upgrade`, wipes the heap of the
+    /// canister, keeping its stable storage, and then runs `post_upgrade`.
+    ///
+    /// Wiping the heap clears the storage, the timers and the spawned futures of the context,
+    /// and increments the [`MockContext::heap_generation`] of the canister, so the state
+    /// which is not saved to the stable storage in `pre_upgrade` is lost, like on the IC.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_kit::*;
+    ///
+    /// let ctx = MockContext::new().with_data(42u64).inject();
+    ///
+    /// ctx.upgrade(
+    ///     || ic::stable_store((*ic::get::<u64>(),)).unwrap(),
+    ///     || {
+    ///         let (value,): (u64,) = ic::stable_restore().unwrap();
+    ///         ic::store(value);
+    ///     },
+    /// );
+    ///
+    /// assert_eq!(*ctx.get::<u64>(), 42);
+    /// ```
+    pub fn upgrade(&self, pre_upgrade: impl FnOnce(), post_upgrade: impl FnOnce()) {
+        self.call_state_reset();
+        pre_upgrade();
+        self.wipe_heap();
+        self.call_state_reset();
+        post_upgrade();
+    }
+
+    /// Clear all of the state of the current canister except the stable storage.
+    pub fn wipe_heap(&self) {
+        self.mark_all_storage_modified();
+        let mut_ref = self.as_mut();
+        mut_ref.storage.clear();
+        mut_ref.timers.clear();
+        mut_ref.running_timer = None;
+        mut_ref.pool = LocalPool::new();
+        *mut_ref.heap_generations.entry(self.id).or_default() += 1;
+    }
+
+    /// Return the number of times the heap of the canister was wiped. The state stored
+    /// outside of the context must be keyed with the generation to be wiped as well.
+    #[inline]
+    pub fn heap_generation(&self, canister_id: &Principal) -> u64 {
+        self.heap_generations
+            .get(canister_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Run the closure and assert that it traps (or panics) with a message containing the given
+    /// string. Like on the IC, the changes made by the trapped execution are rolled back: the
+    /// stable storage, the balance, the cycles of the message, the certified data, the timers
+    /// set and the calls recorded by the watcher are restored.
+    ///
+    /// The values in the storage can not be copied, so the changes made to them before the
+    /// trap are not rolled back.
+    ///
+    /// # Panics
+    /// If the closure does not trap, or traps with a different message.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_kit::*;
+    ///
+    /// let ctx = MockContext::new().with_balance(1000).with_msg_cycles(500).inject();
+    ///
+    /// ctx.assert_traps_with(
+    ///     || {
+    ///         ic::msg_cycles_accept(500);
+    ///         ic::trap("not allowed");
+    ///     },
+    ///     "not allowed",
+    /// );
+    ///
+    /// assert_eq!(ic::balance(), 1000);
+    /// assert_eq!(ic::msg_cycles_available(), 500);
+    /// ```
+    #[track_caller]
+    pub fn assert_traps_with<R>(&self, f: impl FnOnce() -> R, message: &str) {
+        let backup = StateBackup::new(self);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        self.call_state_reset();
+
+        let panic = match result {
+            Ok(_) => panic!(
+                "Expected a trap with message containing {:?}, but the execution succeeded.",
+                message
+            ),
+            Err(panic) => panic,
+        };
+        backup.restore(self);
+
+        let trap_message = panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        assert!(
+            trap_message.contains(message),
+            "Expected a trap with message containing {:?}, but it trapped with {:?}.",
+            message,
+            trap_message
+        );
+    }
+
+    /// Take a snapshot of the state of the context, to find out later what was changed with
+    /// [`MockContext::diff`].
+    pub fn snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            stable: self.stable.clone(),
+            balance: self.balance,
+            certified_data: self.certified_data.clone(),
+            storage: self.storage_markers.clone(),
+        }
+    }
+
+    /// Return the changes of the state of the context since the snapshot was taken.
+    pub fn diff(&self, snapshot: &ContextSnapshot) -> ContextDiff {
+        ContextDiff::new(snapshot, &self.snapshot())
+    }
+
+    fn mark_storage_modified<T: 'static>(&self) {
+        let mut_ref = self.as_mut();
+        mut_ref.storage_version += 1;
+        mut_ref.storage_markers.insert(
+            TypeId::of::<T>(),
+            StorageMarker {
+                type_name: std::any::type_name::<T>(),
+                version: mut_ref.storage_version,
+            },
+        );
+    }
+
+    /// Add the marker of the storage value, without marking it as modified.
+    fn track_storage<T: 'static>(&self) {
+        self.as_mut()
+            .storage_markers
+            .entry(TypeId::of::<T>())
+            .or_insert(StorageMarker {
+                type_name: std::any::type_name::<T>(),
+                version: 0,
+            });
+    }
+
+    fn mark_all_storage_modified(&self) {
+        let mut_ref = self.as_mut();
+        for type_id in mut_ref.storage.keys() {
+            if let Some(marker) = mut_ref.storage_markers.get_mut(type_id) {
+                mut_ref.storage_version += 1;
+                marker.version = mut_ref.storage_version;
+            }
+        }
+    }
+
+    /// Move the time forward by the given amount of nanoseconds without running the timers.
     #[inline]
     pub fn add_time(&self, time: u64) {
         self.as_mut().time += time;
     }
 
+    /// Move the time forward by the given duration, running the callbacks of the timers
+    /// which become due, in the order of their due time.
+    ///
+    /// When a callback runs, the time of the context is equal to the due time of its
+    /// timer. Timers set by the callbacks are run in the same call if they become due
+    /// before the end of the duration. An interval timer with zero interval runs
+    /// at most once per call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use ic_kit::*;
+    ///
+    /// let ctx = MockContext::new().with_data(0u32).inject();
+    /// ic::set_timer_interval(Duration::from_secs(10), || *ic::get_mut::<u32>() += 1);
+    ///
+    /// ctx.advance_time(Duration::from_secs(35));
+    /// assert_eq!(*ic::get::<u32>(), 3);
+    /// ```
+    pub fn advance_time(&self, duration: Duration) {
+        let mut_ref = self.as_mut();
+        let target = mut_ref
+            .time
+            .saturating_add(duration.as_nanos().try_into().unwrap_or(u64::MAX));
+        // Zero interval timers which have already run during this call.
+        let mut ran_zero_interval = BTreeSet::new();
+
+        while let Some(id) = mut_ref.next_due_timer(target, &ran_zero_interval) {
+            let timer = mut_ref.timers.remove(&id).expect("timer exists");
+            mut_ref.time = mut_ref.time.max(timer.due);
+
+            match timer.callback {
+                TimerCallback::Once(func) => func(),
+                TimerCallback::Interval { interval, mut func } => {
+                    mut_ref.running_timer = Some(id);
+                    func();
+
+                    // The timer could be cleared by its own callback.
+                    if mut_ref.running_timer.take() == Some(id) {
+                        if interval == 0 {
+                            ran_zero_interval.insert(id);
+                        }
+                        let timer = MockTimer {
+                            due: timer.due.saturating_add(interval),
+                            callback: TimerCallback::Interval { interval, func },
+                        };
+                        mut_ref.timers.insert(id, timer);
+                    }
+                }
+            }
+        }
+
+        mut_ref.time = mut_ref.time.max(target);
+    }
+
+    /// Run the callbacks of the timers which are due at the current time.
+    #[inline]
+    pub fn run_due_timers(&self) {
+        self.advance_time(Duration::ZERO)
+    }
+
+    /// Set a timer which runs the callback once after the given delay.
+    pub fn set_timer(&self, delay: Duration, func: impl FnOnce() + 'static) -> TimerId {
+        self.add_timer(delay, TimerCallback::Once(Box::new(func)))
+    }
+
+    /// Set a timer which runs the callback every `interval`.
+    pub fn set_timer_interval(&self, interval: Duration, func: impl FnMut() + 'static) -> TimerId {
+        let interval_nanos = interval.as_nanos().try_into().unwrap_or(u64::MAX);
+        self.add_timer(
+            interval,
+            TimerCallback::Interval {
+                interval: interval_nanos,
+                func: Box::new(func),
+            },
+        )
+    }
+
+    /// Cancel the timer. Does nothing if the timer has already run or has been cleared.
+    pub fn clear_timer(&self, id: TimerId) {
+        let mut_ref = self.as_mut();
+        mut_ref.timers.remove(&id);
+        if mut_ref.running_timer == Some(id) {
+            mut_ref.running_timer = None;
+        }
+    }
+
+    /// Return the number of timers which are set and have not run yet. Interval timers
+    /// are counted until they are cleared.
+    #[inline]
+    pub fn pending_timers(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Return the time remaining until the next timer is due, if any.
+    pub fn next_timer_in(&self) -> Option<Duration> {
+        self.timers
+            .values()
+            .map(|timer| Duration::from_nanos(timer.due.saturating_sub(self.time)))
+            .min()
+    }
+
+    fn add_timer(&self, delay: Duration, callback: TimerCallback) -> TimerId {
+        let mut_ref = self.as_mut();
+        let id = TimerId(mut_ref.next_timer_id);
+        mut_ref.next_timer_id += 1;
+
+        let delay = delay.as_nanos().try_into().unwrap_or(u64::MAX);
+        let due = mut_ref.time.saturating_add(delay);
+        mut_ref.timers.insert(id, MockTimer { due, callback });
+        id
+    }
+
+    /// Return the id of the earliest timer which is due not later than `time`. Timers
+    /// with the same due time are returned in the order they were set.
+    fn next_due_timer(&self, time: u64, skip: &BTreeSet<TimerId>) -> Option<TimerId> {
+        self.timers
+            .iter()
+            .filter(|(id, timer)| timer.due <= time && !skip.contains(id))
+            .min_by_key(|(id, timer)| (timer.due, **id))
+            .map(|(id, _)| *id)
+    }
+
     /// Update the balance of the canister.
     #[inline]
     pub fn update_balance(&self, cycles: u64) {
@@ -392,6 +956,66 @@ impl MockContext {
         self.as_mut().id = canister_id;
     }
 
+    /// Generate 32 random bytes, like the `raw_rand` method of the management canister.
+    #[inline]
+    pub fn raw_rand(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 32];
+        self.as_mut().rand.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    /// Respond to the HTTPS outcall with the mocked response, like the management canister.
+    ///
+    /// The request is rejected if there is no response mocked for its URL, or if the response
+    /// is larger than the `max_response_bytes` of the request. The transform function of the
+    /// request is applied to the response.
+    pub fn http_request(&self, arg: CanisterHttpRequestArgument) -> CallResult<HttpResponse> {
+        self.as_mut().watcher.http_requests.push(arg.clone());
+
+        let mock = self
+            .http_mocks
+            .iter()
+            .find(|mock| mock.matches(&arg.url))
+            .ok_or_else(|| {
+                (
+                    RejectionCode::SysFatal,
+                    format!("No response is mocked for the HTTPS outcall to {}", arg.url),
+                )
+            })?;
+        let response = mock.respond(&arg)?;
+        check_response_size(&arg, &response)?;
+
+        let Some(transform) = &arg.transform else {
+            return Ok(response);
+        };
+        let method = &transform.function.0.method;
+        let transform_fn = self.http_transforms.get(method).ok_or_else(|| {
+            (
+                RejectionCode::CanisterError,
+                format!("The transform function {} is not registered", method),
+            )
+        })?;
+
+        Ok(transform_fn(TransformArgs {
+            response,
+            context: transform.context.clone(),
+        }))
+    }
+
+    /// Record the given call in the watcher. Used by the tools making inter-canister calls
+    /// without going through the context.
+    #[inline]
+    pub fn record_call(&self, call: WatcherCall) {
+        self.as_mut().watcher.record_call(call);
+    }
+
+    /// Set whether the context simulates an update call, in which the data certificate is not
+    /// available, or a query.
+    #[inline]
+    pub fn set_update_call(&self, is_update_call: bool) {
+        self.as_mut().is_update_call = is_update_call;
+    }
+
     /// Return the certified data set on the canister.
     #[inline]
     pub fn get_certified_data(&self) -> Option<Vec<u8>> {
@@ -404,6 +1028,13 @@ impl MockContext {
         self.handlers.push(Box::new(handler));
     }
 
+    /// Add the given endpoint to the call handlers pipeline. The endpoint takes precedence
+    /// over the handlers added before it.
+    #[inline]
+    pub fn use_endpoint(&mut self, endpoint: Endpoint) {
+        self.handlers.insert(0, Box::new(endpoint));
+    }
+
     /// Remove all of the call handlers that are already registered to this context.
     #[inline]
     pub fn clear_handlers(&mut self) {
@@ -477,16 +1108,11 @@ impl Context for MockContext {
     fn msg_cycles_accept(&self, cycles: u64) -> u64 {
         self.as_mut().watcher.called_msg_cycles_accept = true;
         let mut_ref = self.as_mut();
-        if cycles > mut_ref.cycles {
-            let r = mut_ref.cycles;
-            mut_ref.cycles = 0;
-            mut_ref.balance += r;
-            r
-        } else {
-            mut_ref.cycles -= cycles;
-            mut_ref.balance += cycles;
-            cycles
-        }
+        let accepted = cycles.min(mut_ref.cycles);
+        mut_ref.cycles -= accepted;
+        mut_ref.balance += accepted;
+        mut_ref.watcher.cycles_accepted += accepted;
+        accepted
     }
 
     #[inline]
@@ -500,6 +1126,7 @@ impl Context for MockContext {
         let type_id = TypeId::of::<T>();
         let mut_ref = self.as_mut();
         mut_ref.watcher.storage_modified.insert(type_id);
+        self.mark_storage_modified::<T>();
         mut_ref.storage.insert(type_id, Box::new(data));
     }
 
@@ -514,6 +1141,7 @@ impl Context for MockContext {
     #[inline]
     fn get<T: 'static + Default>(&self) -> &T {
         let type_id = std::any::TypeId::of::<T>();
+        self.track_storage::<T>();
         self.as_mut()
             .storage
             .entry(type_id)
@@ -527,6 +1155,7 @@ impl Context for MockContext {
         let type_id = std::any::TypeId::of::<T>();
         let mut_ref = self.as_mut();
         mut_ref.watcher.storage_modified.insert(type_id);
+        self.mark_storage_modified::<T>();
         mut_ref
             .storage
             .entry(type_id)
@@ -540,6 +1169,7 @@ impl Context for MockContext {
         let type_id = std::any::TypeId::of::<T>();
         let mut_ref = self.as_mut();
         mut_ref.watcher.storage_modified.insert(type_id);
+        self.mark_storage_modified::<T>();
         mut_ref.storage.remove(&type_id).is_some()
     }
 
@@ -578,10 +1208,21 @@ impl Context for MockContext {
         cycles: u64,
     ) -> CallResponse<Vec<u8>> {
         if cycles > self.balance {
-            panic!(
+            self.trap(&format!(
                 "Calling canister {} with {} cycles when there is only {} cycles available.",
                 id, cycles, self.balance
-            );
+            ));
+        }
+
+        if self.balance - cycles < self.freezing_threshold {
+            // The message is not sent, so all of the cycles are refunded.
+            self.as_mut().cycles_refunded = cycles;
+            return Box::pin(async move {
+                Err((
+                    RejectionCode::SysTransient,
+                    "Couldn't send message: the canister is frozen".to_string(),
+                ))
+            });
         }
 
         let method = method.into();
@@ -612,6 +1253,7 @@ impl Context for MockContext {
             args_raw,
             cycles_sent: cycles,
             cycles_refunded: refunded,
+            is_notify: false,
         });
 
         Box::pin(async move { res })
@@ -632,6 +1274,10 @@ impl Context for MockContext {
     #[inline]
     fn data_certificate(&self) -> Option<Vec<u8>> {
         self.as_mut().watcher.called_data_certificate = true;
+        if self.is_update_call {
+            return None;
+        }
+
         self.certificate.as_ref().cloned()
     }
 
@@ -658,7 +1304,9 @@ impl Default for Watcher {
             called_set_certified_data: false,
             called_data_certificate: false,
             storage_modified: Default::default(),
+            cycles_accepted: 0,
             calls: Vec::with_capacity(3),
+            http_requests: vec![],
         }
     }
 }
@@ -670,6 +1318,104 @@ impl Watcher {
         self.calls.push(call);
     }
 
+    /// Return all of the calls made during the last execution in the order they were made.
+    #[inline]
+    pub fn calls(&self) -> &[WatcherCall] {
+        &self.calls
+    }
+
+    /// Return all of the HTTPS outcalls made during the last execution in the order they were
+    /// made.
+    #[inline]
+    pub fn http_requests(&self) -> &[CanisterHttpRequestArgument] {
+        &self.http_requests
+    }
+
+    /// Return the calls made to the given method of the canister during the last execution.
+    #[inline]
+    pub fn calls_to<'a>(
+        &'a self,
+        canister_id: &'a Principal,
+        method_name: &'a str,
+    ) -> impl Iterator<Item = &'a WatcherCall> + 'a {
+        self.calls
+            .iter()
+            .filter(move |call| &call.canister_id == canister_id && call.method_name == method_name)
+    }
+
+    /// Assert that the given method of the canister was called `count` times.
+    ///
+    /// # Panics
+    /// If the method was called a different number of times.
+    #[track_caller]
+    pub fn assert_call_count(&self, canister_id: &Principal, method_name: &str, count: usize) {
+        let actual = self.calls_to(canister_id, method_name).count();
+        assert_eq!(
+            actual, count,
+            "Expected {} calls to {}.{}, but {} calls were made.",
+            count, canister_id, method_name, actual
+        );
+    }
+
+    /// Assert that the given method of the canister was called.
+    ///
+    /// # Panics
+    /// If the method was not called.
+    #[track_caller]
+    pub fn assert_called(&self, canister_id: &Principal, method_name: &str) {
+        assert!(
+            self.is_called(canister_id, method_name),
+            "Expected a call to {}.{}, but it was not called.",
+            canister_id,
+            method_name
+        );
+    }
+
+    /// Assert that the given method of the canister was not called.
+    ///
+    /// # Panics
+    /// If the method was called.
+    #[track_caller]
+    pub fn assert_not_called(&self, canister_id: &Principal, method_name: &str) {
+        self.assert_call_count(canister_id, method_name, 0);
+    }
+
+    /// Assert that the last call to the given method of the canister was made with the
+    /// given arguments.
+    ///
+    /// # Panics
+    /// If the method was not called or the arguments of the last call are different.
+    #[track_caller]
+    pub fn assert_called_with<T: ArgumentEncoder>(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        args: T,
+    ) {
+        let call = self
+            .calls_to(canister_id, method_name)
+            .last()
+            .unwrap_or_else(|| {
+                panic!(
+                    "Expected a call to {}.{}, but it was not called.",
+                    canister_id, method_name
+                )
+            });
+        let expected = encode_args(args).expect("Failed to encode arguments.");
+        assert!(
+            call.args_raw == expected,
+            "The call to {}.{} was made with unexpected arguments.",
+            canister_id,
+            method_name
+        );
+    }
+
+    /// Returns the amount of cycles accepted from the callers during the last execution.
+    #[inline]
+    pub fn cycles_accepted(&self) -> u64 {
+        self.cycles_accepted
+    }
+
     /// Return the number of calls made during the last execution.
     #[inline]
     pub fn call_count(&self) -> usize {
@@ -759,6 +1505,46 @@ impl Watcher {
 }
 
 impl WatcherCall {
+    /// Create a call to the method of the canister with the given candid encoded arguments.
+    #[inline]
+    pub fn new<S: Into<String>>(canister_id: Principal, method_name: S, args_raw: Vec<u8>) -> Self {
+        Self {
+            canister_id,
+            method_name: method_name.into(),
+            args_raw,
+            cycles_sent: 0,
+            cycles_refunded: 0,
+            is_notify: false,
+        }
+    }
+
+    /// Set the cycles sent with the call and refunded to the caller.
+    #[inline]
+    pub fn with_cycles(mut self, sent: u64, refunded: u64) -> Self {
+        self.cycles_sent = sent;
+        self.cycles_refunded = refunded;
+        self
+    }
+
+    /// Mark the call as a one-way call, which response is not awaited.
+    #[inline]
+    pub fn with_notify(mut self) -> Self {
+        self.is_notify = true;
+        self
+    }
+
+    /// Whether the call was a one-way call.
+    #[inline]
+    pub fn is_notify(&self) -> bool {
+        self.is_notify
+    }
+
+    /// Return the candid encoded arguments passed to the call.
+    #[inline]
+    pub fn args_raw(&self) -> &[u8] {
+        &self.args_raw
+    }
+
     /// The amount of cycles consumed by this call.
     #[inline]
     pub fn cycles_consumed(&self) -> u64 {
@@ -1059,6 +1845,30 @@ mod tests {
         assert_eq!(canister::decrement(1), 26);
     }
 
+    #[test]
+    fn upgrade() {
+        use std::time::Duration;
+
+        use crate::ic;
+
+        let ctx = MockContext::new().with_data(1000u64).inject();
+        assert_eq!(canister::increment(0), 1);
+        assert_eq!(canister::increment(0), 2);
+        ic::set_timer(Duration::from_secs(1), || {
+            panic!("timer survived the upgrade")
+        });
+        assert_eq!(ctx.heap_generation(&ctx.id()), 0);
+
+        ctx.upgrade(canister::pre_upgrade, canister::post_upgrade);
+
+        assert_eq!(ctx.heap_generation(&ctx.id()), 1);
+        assert_eq!(ctx.heap_generation(&users::bob()), 0);
+        assert_eq!(ctx.pending_timers(), 0);
+        // The user balance is not saved in the stable storage.
+        assert_eq!(canister::user_balance(), 0);
+        assert_eq!(canister::increment(0), 3);
+    }
+
     #[test]
     fn certified_data() {
         let ctx = MockContext::new()
@@ -1081,6 +1891,21 @@ mod tests {
         assert!(watcher.called_data_certificate);
     }
 
+    #[test]
+    fn data_certificate_is_available_in_queries() {
+        let ctx = MockContext::new().with_update_call().inject();
+
+        canister::set_certified_data(&[1, 2, 3]);
+        assert_eq!(ctx.get_certified_data(), Some(vec![1, 2, 3]));
+        assert_eq!(canister::data_certificate(), None);
+
+        ctx.set_update_call(false);
+        let certificate = canister::data_certificate().unwrap();
+        assert!(MockContext::verify_certificate(&certificate, &[1, 2, 3]));
+        assert!(!MockContext::verify_certificate(&certificate, &[1, 2, 4]));
+        assert!(!MockContext::verify_certificate(&certificate, &[1, 2]));
+    }
+
     #[tokio::test]
     async fn withdraw_accept() {
         let ctx = MockContext::new()
@@ -1171,6 +1996,90 @@ mod tests {
         assert_eq!(canister::balance(), 1930);
     }
 
+    #[tokio::test]
+    async fn endpoints() {
+        use crate::{ic, Endpoint, RejectionCode};
+
+        let ctx = MockContext::new()
+            .with_constant_return_handler(0u64)
+            .with_endpoint(Endpoint::new(users::bob(), "balance").response(100u64))
+            .with_endpoint(
+                Endpoint::new(users::bob(), "transfer")
+                    .reject(RejectionCode::CanisterReject, "no funds"),
+            )
+            .with_endpoint(
+                Endpoint::new(users::bob(), "double").handler(|(value,): (u64,)| Ok(value * 2)),
+            )
+            .inject();
+        let watcher = ctx.watch();
+
+        let (balance,): (u64,) = ic::call(users::bob(), "balance", ()).await.unwrap();
+        assert_eq!(balance, 100);
+        let (balance,): (u64,) = ic::call(users::john(), "balance", ()).await.unwrap();
+        assert_eq!(balance, 0);
+
+        let res: Result<(), _> = ic::call(users::bob(), "transfer", (users::john(), 10u64)).await;
+        assert_eq!(
+            res,
+            Err((RejectionCode::CanisterReject, "no funds".to_string()))
+        );
+
+        let (doubled,): (u64,) = ic::call(users::bob(), "double", (21u64,)).await.unwrap();
+        assert_eq!(doubled, 42);
+
+        watcher.assert_call_count(&users::bob(), "balance", 1);
+        watcher.assert_called(&users::john(), "balance");
+        watcher.assert_called_with(&users::bob(), "transfer", (users::john(), 10u64));
+        watcher.assert_called_with(&users::bob(), "double", (21u64,));
+        watcher.assert_not_called(&users::john(), "transfer");
+        assert_eq!(watcher.calls().len(), 4);
+        assert_eq!(watcher.calls()[1].canister_id(), users::john());
+    }
+
+    #[tokio::test]
+    async fn frozen_canister_can_not_send_cycles() {
+        let ctx = MockContext::new()
+            .with_consume_cycles_handler(200)
+            .with_data(1000u64)
+            .with_balance(2000)
+            .with_freezing_threshold(1950)
+            .inject();
+        let watcher = ctx.watch();
+
+        let res = canister::withdraw(users::bob(), 100).await;
+        assert!(res.unwrap_err().contains("frozen"));
+        assert_eq!(watcher.call_count(), 0);
+        assert_eq!(canister::user_balance(), 1000);
+        assert_eq!(canister::balance(), 2000);
+
+        canister::withdraw(users::bob(), 50).await.unwrap();
+        assert_eq!(canister::user_balance(), 950);
+        assert_eq!(canister::balance(), 1950);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "trapped")]
+    async fn sending_more_cycles_than_balance_traps() {
+        MockContext::new()
+            .with_consume_cycles_handler(200)
+            .with_data(5000u64)
+            .with_balance(2000)
+            .inject();
+
+        let _ = canister::withdraw(users::bob(), 3000).await;
+    }
+
+    #[test]
+    fn accepted_cycles() {
+        let ctx = MockContext::new().with_msg_cycles(1000).inject();
+        let watcher = ctx.watch();
+
+        canister::msg_cycles_accept(300);
+        canister::msg_cycles_accept(1000);
+        assert_eq!(watcher.cycles_accepted(), 1000);
+        assert_eq!(ctx.msg_cycles_available(), 0);
+    }
+
     #[test]
     #[should_panic]
     fn trap_should_panic() {
@@ -1178,6 +2087,54 @@ mod tests {
         ctx.trap("Unreachable");
     }
 
+    #[test]
+    fn trap_rolls_back_the_state() {
+        use std::time::Duration;
+
+        let ctx = MockContext::new()
+            .with_balance(1000)
+            .with_msg_cycles(300)
+            .with_constant_return_handler(())
+            .inject();
+        ctx.stable_store((1u64,)).unwrap();
+        ctx.set_timer(Duration::from_secs(1), || {});
+        let watcher = ctx.watch();
+
+        crate::assert_traps_with!(
+            {
+                ctx.msg_cycles_accept(300);
+                ctx.stable_store((2u64,)).unwrap();
+                ctx.set_certified_data(&[7]);
+                ctx.set_timer(Duration::from_secs(2), || {});
+                let _ = ctx.call_raw(Principal::management_canister(), "raw_rand", vec![], 100);
+                ctx.trap("unexpected state")
+            },
+            "unexpected state"
+        );
+
+        assert_eq!(ctx.balance(), 1000);
+        assert_eq!(ctx.msg_cycles_available(), 300);
+        assert_eq!(ctx.stable_restore::<(u64,)>(), Ok((1,)));
+        assert!(ctx.get_certified_data().is_none());
+        assert_eq!(ctx.pending_timers(), 1);
+        assert_eq!(watcher.call_count(), 0);
+        assert_eq!(watcher.cycles_accepted(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "but it trapped with")]
+    fn trap_with_other_message_fails_the_assertion() {
+        let ctx = MockContext::new().inject();
+        ctx.assert_traps_with(|| ctx.trap("one"), "other");
+    }
+
+    #[test]
+    #[should_panic(expected = "but the execution succeeded")]
+    fn no_trap_fails_the_assertion() {
+        let ctx = MockContext::new().inject();
+        ctx.assert_traps_with(|| ctx.id(), "trap");
+    }
+
     #[test]
     #[should_panic]
     fn large_certificate_should_panic() {
@@ -1185,4 +2142,74 @@ mod tests {
         let bytes = vec![0; 33];
         ctx.set_certified_data(bytes.as_slice());
     }
+
+    #[test]
+    fn timers_run_in_due_order() {
+        use std::time::Duration;
+
+        use crate::ic;
+
+        let ctx = MockContext::new()
+            .with_time(0)
+            .with_data(Vec::<(u64, &str)>::new())
+            .inject();
+
+        ic::set_timer(Duration::from_nanos(20), || {
+            ic::get_mut::<Vec<(u64, &str)>>().push((ic::time(), "second"))
+        });
+        ic::set_timer(Duration::from_nanos(10), || {
+            ic::get_mut::<Vec<(u64, &str)>>().push((ic::time(), "first"));
+            ic::set_timer(Duration::from_nanos(5), || {
+                ic::get_mut::<Vec<(u64, &str)>>().push((ic::time(), "nested"))
+            });
+        });
+        let cleared = ic::set_timer(Duration::from_nanos(15), || panic!("cleared timer run"));
+        ic::clear_timer(cleared);
+
+        ctx.advance_time(Duration::from_nanos(9));
+        assert!(ctx.get::<Vec<(u64, &str)>>().is_empty());
+        assert_eq!(ctx.pending_timers(), 2);
+        assert_eq!(ctx.next_timer_in(), Some(Duration::from_nanos(1)));
+
+        ctx.advance_time(Duration::from_nanos(100));
+        assert_eq!(
+            ctx.get::<Vec<(u64, &str)>>(),
+            &vec![(10, "first"), (15, "nested"), (20, "second")]
+        );
+        assert_eq!(ctx.time(), 109);
+        assert_eq!(ctx.pending_timers(), 0);
+    }
+
+    #[test]
+    fn interval_timers() {
+        use std::time::Duration;
+
+        use super::TimerId;
+        use crate::ic;
+
+        let ctx = MockContext::new().with_data(0u32).with_data(0u64).inject();
+
+        let id = ic::set_timer_interval(Duration::from_secs(10), || {
+            *ic::get_mut::<u32>() += 1;
+            if *ic::get::<u32>() == 2 {
+                ic::clear_timer(*ic::get_maybe::<TimerId>().unwrap());
+            }
+        });
+        ctx.store(id);
+        let poll = ic::set_timer_interval(Duration::ZERO, || *ic::get_mut::<u64>() += 1);
+
+        ctx.advance_time(Duration::from_secs(25));
+        assert_eq!(*ctx.get::<u32>(), 2);
+        assert_eq!(*ctx.get::<u64>(), 1);
+        assert_eq!(ctx.pending_timers(), 1);
+
+        ctx.advance_time(Duration::from_secs(25));
+        assert_eq!(*ctx.get::<u32>(), 2);
+        assert_eq!(*ctx.get::<u64>(), 2);
+
+        ic::clear_timer(poll);
+        ctx.run_due_timers();
+        assert_eq!(*ctx.get::<u64>(), 2);
+        assert_eq!(ctx.pending_timers(), 0);
+    }
 }
@@ -0,0 +1,226 @@
+//! Randomness for the canisters.
+//!
+//! On the IC the randomness comes from the `raw_rand` method of the management canister. In the
+//! tests it is generated by the [`crate::MockContext`] from a seed, so the tests are reproducible.
+
+use std::ops::Range;
+
+use crate::CallResult;
+
+/// A pseudo random number generator (xoshiro256**), seeded from the `raw_rand` method of the
+/// management canister.
+///
+/// A single call to `raw_rand` gives enough entropy to generate a lot of random values, so it
+/// is cheaper to seed the generator once and keep it in the canister state than to call
+/// `raw_rand` every time a random value is needed. The generator is not cryptographically
+/// secure.
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::rand::Rand;
+/// use ic_kit::MockContext;
+///
+/// MockContext::new().with_rand_seed(42).inject();
+///
+/// let mut rand = futures::executor::block_on(Rand::from_raw_rand()).unwrap();
+/// let mut items = vec![1, 2, 3, 4, 5];
+/// rand.shuffle(&mut items);
+/// let dice = rand.gen_range(1..7);
+/// assert!((1..7).contains(&dice));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rand {
+    state: [u64; 4],
+}
+
+impl Rand {
+    /// Create a generator seeded with 32 bytes returned by the `raw_rand` method of the
+    /// management canister.
+    pub async fn from_raw_rand() -> CallResult<Self> {
+        let bytes = raw_rand().await?;
+        let seed = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            (
+                crate::RejectionCode::CanisterError,
+                format!("raw_rand returned {} bytes instead of 32", bytes.len()),
+            )
+        })?;
+
+        Ok(Self::from_seed(seed))
+    }
+
+    /// Create a generator from the given seed.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        let mut state = [0; 4];
+        for (word, bytes) in state.iter_mut().zip(seed.chunks_exact(8)) {
+            *word = u64::from_le_bytes(bytes.try_into().expect("chunk of 8 bytes"));
+        }
+
+        // The all-zero state is the only invalid state of the generator.
+        if state == [0; 4] {
+            return Self::seed_from_u64(0);
+        }
+
+        Self { state }
+    }
+
+    /// Return the state of the generator as a seed, which creates a generator continuing the
+    /// same sequence, e.g. to keep the generator in stable memory.
+    pub fn to_seed(&self) -> [u8; 32] {
+        let mut seed = [0; 32];
+        for (bytes, word) in seed.chunks_exact_mut(8).zip(self.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        seed
+    }
+
+    /// Create a generator from a number, different numbers give generators with unrelated
+    /// sequences.
+    pub fn seed_from_u64(seed: u64) -> Self {
+        // The state is expanded from the number with SplitMix64, as recommended by the
+        // authors of xoshiro.
+        let mut seed = seed;
+        let mut state = [0; 4];
+        for word in &mut state {
+            seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            *word = z ^ (z >> 31);
+        }
+
+        Self { state }
+    }
+
+    /// Return the next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+
+        result
+    }
+
+    /// Fill the buffer with random bytes.
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    /// Return a random number uniformly distributed in the range.
+    ///
+    /// # Panics
+    /// If the range is empty.
+    pub fn gen_range(&mut self, range: Range<u64>) -> u64 {
+        assert!(!range.is_empty(), "Cannot sample from an empty range.");
+        let len = range.end - range.start;
+
+        // Reject the values from the incomplete last interval to avoid the modulo bias.
+        let zone = u64::MAX - (u64::MAX - len + 1) % len;
+        loop {
+            let value = self.next_u64();
+            if value <= zone {
+                return range.start + value % len;
+            }
+        }
+    }
+
+    /// Shuffle the slice in place.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.gen_range(0..i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+
+    /// Return a random element of the slice, or `None` if it is empty.
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        match items.len() {
+            0 => None,
+            len => items.get(self.gen_range(0..len as u64) as usize),
+        }
+    }
+}
+
+/// Return 32 random bytes from the `raw_rand` method of the management canister.
+///
+/// In tests the bytes are generated by the injected [`crate::MockContext`], without making
+/// a call.
+pub async fn raw_rand() -> CallResult<Vec<u8>> {
+    #[cfg(target_family = "wasm")]
+    return <crate::interfaces::management::RawRand as crate::interfaces::Method>::perform(
+        crate::Principal::management_canister(),
+        (),
+    )
+    .await
+    .map(|(bytes,)| bytes);
+
+    #[cfg(not(target_family = "wasm"))]
+    return Ok(crate::inject::get_context().raw_rand());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockContext;
+
+    #[test]
+    fn same_seed_gives_same_sequence() {
+        let mut a = Rand::seed_from_u64(7);
+        let mut b = Rand::seed_from_u64(7);
+        let mut c = Rand::seed_from_u64(8);
+
+        let a_values: Vec<_> = (0..10).map(|_| a.next_u64()).collect();
+        let b_values: Vec<_> = (0..10).map(|_| b.next_u64()).collect();
+        let c_values: Vec<_> = (0..10).map(|_| c.next_u64()).collect();
+        assert_eq!(a_values, b_values);
+        assert_ne!(a_values, c_values);
+
+        assert_eq!(Rand::from_seed([0; 32]), Rand::seed_from_u64(0));
+        assert_eq!(Rand::from_seed(a.to_seed()), a);
+    }
+
+    #[test]
+    fn gen_range_and_shuffle() {
+        let mut rand = Rand::seed_from_u64(1);
+        for _ in 0..1000 {
+            assert!((10..13).contains(&rand.gen_range(10..13)));
+        }
+        assert_eq!(rand.gen_range(5..6), 5);
+
+        let mut items: Vec<u32> = (0..50).collect();
+        rand.shuffle(&mut items);
+        assert_ne!(items, (0..50).collect::<Vec<_>>());
+        items.sort();
+        assert_eq!(items, (0..50).collect::<Vec<_>>());
+
+        assert!(rand.choose::<u32>(&[]).is_none());
+        assert!(items.contains(rand.choose(&items).unwrap()));
+
+        let mut buf = [0u8; 13];
+        rand.fill_bytes(&mut buf);
+        assert_ne!(buf, [0u8; 13]);
+    }
+
+    #[tokio::test]
+    async fn mock_raw_rand_is_reproducible() {
+        MockContext::new().with_rand_seed(42).inject();
+        let first = raw_rand().await.unwrap();
+        let second = raw_rand().await.unwrap();
+        assert_eq!(first.len(), 32);
+        assert_ne!(first, second);
+
+        MockContext::new().with_rand_seed(42).inject();
+        assert_eq!(raw_rand().await.unwrap(), first);
+        let rand = Rand::from_raw_rand().await.unwrap();
+        assert_eq!(rand, Rand::from_seed(second.try_into().unwrap()));
+    }
+}
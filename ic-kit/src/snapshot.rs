@@ -0,0 +1,283 @@
+//! Snapshots of the state, to assert which parts of it were changed by an operation.
+
+use std::any::TypeId;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+/// A snapshot of the state of a [`crate::MockContext`], taken by
+/// [`crate::MockContext::snapshot`].
+///
+/// The values in the storage can not be copied, so instead of the values the snapshot keeps
+/// markers which change every time a value is stored, mutably accessed or deleted.
+#[derive(Debug, Clone)]
+pub struct ContextSnapshot {
+    pub(crate) stable: Vec<u8>,
+    pub(crate) balance: u64,
+    pub(crate) certified_data: Option<Vec<u8>>,
+    pub(crate) storage: BTreeMap<TypeId, StorageMarker>,
+}
+
+/// The marker of a value in the storage.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StorageMarker {
+    pub(crate) type_name: &'static str,
+    pub(crate) version: u64,
+}
+
+/// The changes of the state of a [`crate::MockContext`] since a [`ContextSnapshot`], returned
+/// by [`crate::MockContext::diff`].
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::*;
+///
+/// let ctx = MockContext::new().with_data(1u32).with_data(String::new()).inject();
+/// let snapshot = ctx.snapshot();
+///
+/// *ic::get_mut::<u32>() += 1;
+///
+/// let diff = ctx.diff(&snapshot);
+/// assert!(diff.is_storage_modified::<u32>());
+/// diff.assert_storage_unchanged::<String>();
+/// diff.assert_stable_unchanged();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContextDiff {
+    /// The byte ranges of the stable storage which were changed, the growth of the stable
+    /// storage is a changed range too.
+    pub stable: Vec<Range<usize>>,
+    /// The balance before and after, if it was changed.
+    pub balance: Option<(u64, u64)>,
+    /// Whether the certified data was changed.
+    pub certified_data: bool,
+    /// The types of the storage values which were stored, mutably accessed or deleted.
+    storage: BTreeMap<TypeId, &'static str>,
+}
+
+impl ContextDiff {
+    pub(crate) fn new(before: &ContextSnapshot, after: &ContextSnapshot) -> Self {
+        // A value without a marker was never modified.
+        let version = |snapshot: &ContextSnapshot, type_id| {
+            snapshot
+                .storage
+                .get(type_id)
+                .map_or(0, |marker| marker.version)
+        };
+        let storage = before
+            .storage
+            .iter()
+            .chain(&after.storage)
+            .filter(|(type_id, _)| version(before, type_id) != version(after, type_id))
+            .map(|(type_id, marker)| (*type_id, marker.type_name))
+            .collect();
+
+        Self {
+            stable: changed_ranges(&before.stable, &after.stable),
+            balance: (before.balance != after.balance).then_some((before.balance, after.balance)),
+            certified_data: before.certified_data != after.certified_data,
+            storage,
+        }
+    }
+
+    /// Whether nothing was changed.
+    pub fn is_empty(&self) -> bool {
+        self.stable.is_empty()
+            && self.balance.is_none()
+            && !self.certified_data
+            && self.storage.is_empty()
+    }
+
+    /// Whether the storage value of the given type was stored, mutably accessed or deleted.
+    pub fn is_storage_modified<T: 'static>(&self) -> bool {
+        self.storage.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Return the names of the types of the modified storage values.
+    pub fn modified_storage(&self) -> Vec<&'static str> {
+        self.storage.values().copied().collect()
+    }
+
+    /// Assert that the storage value of the given type was not modified.
+    #[track_caller]
+    pub fn assert_storage_unchanged<T: 'static>(&self) {
+        assert!(
+            !self.is_storage_modified::<T>(),
+            "Expected the storage value of type {} to be unchanged.",
+            std::any::type_name::<T>()
+        );
+    }
+
+    /// Assert that the stable storage was not changed.
+    #[track_caller]
+    pub fn assert_stable_unchanged(&self) {
+        assert!(
+            self.stable.is_empty(),
+            "Expected the stable storage to be unchanged, but the bytes {:?} were changed.",
+            self.stable
+        );
+    }
+}
+
+/// Return the ranges of the bytes which differ, the bytes present only in one of the slices
+/// differ.
+fn changed_ranges(before: &[u8], after: &[u8]) -> Vec<Range<usize>> {
+    let len = before.len().max(after.len());
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for i in (0..len).filter(|&i| before.get(i) != after.get(i)) {
+        match ranges.last_mut() {
+            Some(range) if range.end == i => range.end += 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+
+    ranges
+}
+
+/// The difference between two snapshots of a map, for example the entries of a stable
+/// structure collected before and after an operation.
+///
+/// # Example
+///
+/// ```
+/// use std::collections::BTreeMap;
+/// use ic_kit::MapDiff;
+///
+/// let mut map = BTreeMap::from([(1, "a"), (2, "b"), (3, "c")]);
+/// let before = map.clone();
+///
+/// map.insert(2, "B");
+/// map.remove(&3);
+/// map.insert(4, "d");
+///
+/// let diff = MapDiff::new(before, map);
+/// assert_eq!(diff.added, vec![4]);
+/// assert_eq!(diff.removed, vec![3]);
+/// assert_eq!(diff.changed, vec![2]);
+/// diff.assert_untouched(&1);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapDiff<K> {
+    /// The keys which are present only after.
+    pub added: Vec<K>,
+    /// The keys which are present only before.
+    pub removed: Vec<K>,
+    /// The keys which values were changed.
+    pub changed: Vec<K>,
+}
+
+impl<K: Ord + Clone> MapDiff<K> {
+    /// Compare the entries of the map before and after.
+    pub fn new<V: PartialEq>(
+        before: impl IntoIterator<Item = (K, V)>,
+        after: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        let before: BTreeMap<K, V> = before.into_iter().collect();
+        let after: BTreeMap<K, V> = after.into_iter().collect();
+
+        let mut diff = Self {
+            added: vec![],
+            removed: vec![],
+            changed: vec![],
+        };
+        for (key, value) in &before {
+            match after.get(key) {
+                None => diff.removed.push(key.clone()),
+                Some(after_value) if after_value != value => diff.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.added = after
+            .keys()
+            .filter(|key| !before.contains_key(key))
+            .cloned()
+            .collect();
+
+        diff
+    }
+
+    /// Whether the maps are equal.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Return all of the added, removed and changed keys in order.
+    pub fn keys(&self) -> BTreeSet<&K> {
+        self.added
+            .iter()
+            .chain(&self.removed)
+            .chain(&self.changed)
+            .collect()
+    }
+
+    /// Whether the entry with the given key was added, removed or changed.
+    pub fn is_touched(&self, key: &K) -> bool {
+        self.added.contains(key) || self.removed.contains(key) || self.changed.contains(key)
+    }
+}
+
+impl<K: Ord + Clone + std::fmt::Debug> MapDiff<K> {
+    /// Assert that the entry with the given key was not added, removed or changed.
+    #[track_caller]
+    pub fn assert_untouched(&self, key: &K) {
+        assert!(
+            !self.is_touched(key),
+            "Expected the entry {:?} to be untouched, but it was changed.",
+            key
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, MockContext};
+
+    #[test]
+    fn context_diff() {
+        let ctx = MockContext::new()
+            .with_data(1u32)
+            .with_data(2u64)
+            .with_stable((1u8, 2u8))
+            .inject();
+        let snapshot = ctx.snapshot();
+        assert!(ctx.diff(&snapshot).is_empty());
+
+        let _ = ctx.get::<u32>();
+        ctx.delete::<u64>();
+        ctx.store(String::from("new"));
+        ctx.stable_store((1u8, 3u8)).unwrap();
+        ctx.set_certified_data(&[1]);
+
+        let diff = ctx.diff(&snapshot);
+        diff.assert_storage_unchanged::<u32>();
+        assert!(diff.is_storage_modified::<u64>());
+        assert!(diff.is_storage_modified::<String>());
+        assert_eq!(diff.modified_storage().len(), 2);
+        assert_eq!(diff.stable.len(), 1);
+        assert!(diff.certified_data);
+        assert_eq!(diff.balance, None);
+
+        ctx.clear_storage();
+        assert!(ctx.diff(&snapshot).is_storage_modified::<u32>());
+    }
+
+    #[test]
+    fn changed_byte_ranges() {
+        assert_eq!(changed_ranges(&[1, 2, 3], &[1, 2, 3]), vec![]);
+        assert_eq!(
+            changed_ranges(&[1, 2, 3, 4, 5], &[0, 2, 0, 0]),
+            vec![0..1, 2..5]
+        );
+        assert_eq!(changed_ranges(&[], &[1, 2]), vec![0..2]);
+    }
+
+    #[test]
+    fn map_diff() {
+        let diff = MapDiff::new([(1, 'a'), (2, 'b')], [(1, 'a'), (2, 'c'), (3, 'd')]);
+        assert_eq!(diff.keys().into_iter().collect::<Vec<_>>(), vec![&2, &3]);
+        assert!(diff.is_touched(&3));
+        diff.assert_untouched(&1);
+        assert!(MapDiff::new([(1, 'a')], [(1, 'a')]).is_empty());
+    }
+}
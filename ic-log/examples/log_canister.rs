@@ -23,6 +23,10 @@ impl LogCanister {
             in_memory_records: Some(128),
             log_filter: Some("info".to_string()),
             enable_console: true,
+            stable_log: None,
+            ring_buffer_records: None,
+            format_json: false,
+            filter_memory_id: None,
         };
         match init_log(&settings) {
             Ok(logger_config) => LoggerConfigService::default().init(logger_config),
@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use ic_canister::{generate_exports, generate_idl, query, update, Canister, Idl, PreUpdate};
+use ic_helpers::types::{Page, Pagination};
+use log::LevelFilter;
+
+use crate::error::{LogError, LogResult};
+use crate::filters::LogFilters;
+use crate::stable_log::{StableLogRecord, StableLogWriter};
+use crate::{logger_config, LoggerConfig};
+
+/// The max number of the records returned by [`LogCanister::get_logs`].
+pub const MAX_LOGS_PAGE: u64 = 1024;
+
+/// The API to configure the logger of the canister at runtime and to read its records.
+pub trait LogCanister: Canister + Sized {
+    /// Returns the current filters of the logger.
+    #[query(trait = true)]
+    fn get_logger_filters(&self) -> LogResult<LogFilters> {
+        Ok(initialized_config()?.filters())
+    }
+
+    /// Replaces the filters of the logger.
+    ///
+    /// Only the controllers of the canister are allowed to call this method.
+    #[update(trait = true)]
+    fn set_logger_filters(&self, filters: LogFilters) -> LogResult<()> {
+        let config = authorized_config()?;
+        let parsed = LogFilters::from_str(&filters.to_string())?;
+        if !parsed.targets.keys().eq(filters.targets.keys()) {
+            return Err(LogError::InvalidFilter(filters.to_string()));
+        }
+        config.set_filters(&parsed);
+        Ok(())
+    }
+
+    /// Sets the max level of the records of the target, e.g. `debug` for `scheduler`. Without
+    /// the target, sets the max level of all the records.
+    ///
+    /// Only the controllers of the canister are allowed to call this method.
+    #[update(trait = true)]
+    fn set_logger_level(&self, target: Option<String>, level: String) -> LogResult<()> {
+        let config = authorized_config()?;
+        let level = LevelFilter::from_str(&level).map_err(|_| LogError::InvalidFilter(level))?;
+        match target {
+            Some(target) => config.set_target_level(&target, level),
+            None => config.set_level(level),
+        }
+        Ok(())
+    }
+
+    /// Removes the max level of the target, so its records are filtered by the max level of
+    /// all the records.
+    ///
+    /// Only the controllers of the canister are allowed to call this method.
+    #[update(trait = true)]
+    fn remove_logger_target(&self, target: String) -> LogResult<()> {
+        authorized_config()?.remove_target_level(&target);
+        Ok(())
+    }
+
+    /// Returns a page of the records of the log in stable memory, up to [`MAX_LOGS_PAGE`].
+    ///
+    /// The records can be filtered by the max level, e.g. `warn` for the warnings and the
+    /// errors, and by the prefix of the target, e.g. `scheduler`. The offset and the total of
+    /// the page count the matching records only.
+    #[query(trait = true)]
+    fn get_logs(
+        &self,
+        pagination: Pagination,
+        level_filter: Option<String>,
+        target_filter: Option<String>,
+    ) -> LogResult<Page<StableLogRecord>> {
+        let level_filter = match level_filter {
+            Some(level) => {
+                LevelFilter::from_str(&level).map_err(|_| LogError::InvalidFilter(level))?
+            }
+            None => LevelFilter::Trace,
+        };
+        let pagination = Pagination::new(pagination.offset, pagination.count.min(MAX_LOGS_PAGE));
+        Ok(StableLogWriter::filter_records(
+            pagination,
+            level_filter,
+            target_filter.as_deref(),
+        ))
+    }
+
+    // Important: This function *must* be defined to be the
+    // last one in the trait because it depends on the order
+    // of expansion of update/query(trait = true) methods.
+    fn get_idl() -> Idl {
+        generate_idl!()
+    }
+}
+
+fn initialized_config() -> LogResult<LoggerConfig> {
+    logger_config().ok_or(LogError::NotInitialized)
+}
+
+fn authorized_config() -> LogResult<LoggerConfig> {
+    #[cfg(target_family = "wasm")]
+    {
+        let caller = ic_exports::ic_cdk::caller();
+        if !ic_exports::ic_cdk::api::is_controller(&caller) {
+            return Err(LogError::Unauthorized(caller.to_string()));
+        }
+    }
+
+    initialized_config()
+}
+
+generate_exports!(LogCanister);
@@ -0,0 +1,96 @@
+//! The fields of the current message attached to the log records.
+
+use ic_canister::{message_context, MessageContext};
+use log::kv::{self, Source, Value, VisitSource};
+use log::Record;
+
+/// The key-value fields of the record followed by the fields of the message context.
+struct ContextFields<'a> {
+    record: &'a dyn Source,
+    context: &'a MessageContext,
+}
+
+impl Source for ContextFields<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        self.record.visit(visitor)?;
+        visitor.visit_pair("caller".into(), Value::from_display(&self.context.caller))?;
+        visitor.visit_pair("method".into(), self.context.method.as_str().into())?;
+        visitor.visit_pair(
+            "correlation_id".into(),
+            self.context.correlation_id.as_str().into(),
+        )
+    }
+}
+
+/// Call the closure with the record, extended with the caller, the method and the correlation
+/// id of the current message if the context is enabled and set.
+pub(crate) fn with_context_fields<R>(
+    enabled: bool,
+    record: &Record,
+    f: impl FnOnce(&Record) -> R,
+) -> R {
+    match message_context().filter(|_| enabled) {
+        Some(context) => {
+            let fields = ContextFields {
+                record: record.key_values(),
+                context: &context,
+            };
+            f(&record.to_builder().key_values(&fields).build())
+        }
+        None => f(record),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use ic_canister::with_message_context;
+    use log::Level;
+
+    use super::*;
+
+    struct Fields(Vec<String>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields {
+        fn visit_pair(&mut self, key: kv::Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            self.0.push(format!("{key}={value}"));
+            Ok(())
+        }
+    }
+
+    fn fields(record: &Record) -> Vec<String> {
+        let mut fields = Fields(vec![]);
+        record.key_values().visit(&mut fields).unwrap();
+        fields.0
+    }
+
+    #[test]
+    fn context_fields_are_attached() {
+        let context = MessageContext {
+            caller: Principal::anonymous(),
+            method: "transfer".to_string(),
+            correlation_id: "1-2".to_string(),
+            incoming: None,
+        };
+        let key_values = [("amount", 10)];
+        let record = Record::builder()
+            .args(format_args!("done"))
+            .level(Level::Info)
+            .key_values(&key_values)
+            .build();
+
+        assert_eq!(with_context_fields(true, &record, fields), ["amount=10"]);
+        with_message_context(context, || {
+            assert_eq!(
+                with_context_fields(true, &record, fields),
+                [
+                    "amount=10",
+                    "caller=2vxsx-fae",
+                    "method=transfer",
+                    "correlation_id=1-2"
+                ]
+            );
+            assert_eq!(with_context_fields(false, &record, fields), ["amount=10"]);
+        });
+    }
+}
@@ -0,0 +1,16 @@
+use ic_exports::candid::{CandidType, Deserialize};
+use thiserror::Error;
+
+#[derive(Error, CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub enum LogError {
+    #[error("invalid log filter directive: {0}")]
+    InvalidFilter(String),
+
+    #[error("the logger is not initialized")]
+    NotInitialized,
+
+    #[error("the principal {0} is not a controller of the canister")]
+    Unauthorized(String),
+}
+
+pub type LogResult<T> = std::result::Result<T, LogError>;
@@ -0,0 +1,199 @@
+//! The filters of the logger in a structured form, which can be changed at runtime and kept in
+//! stable memory, so they survive the upgrades.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use candid::CandidType;
+use ic_stable_structures::stable_structures::DefaultMemoryImpl;
+use ic_stable_structures::{CellStructure, StableCell, VirtualMemory};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LogError, LogResult};
+
+/// The filters of the logger: the max level of the records, and the max levels overriding it
+/// for some targets.
+///
+/// It's the structured form of the filters like `info,scheduler=debug`.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct LogFilters {
+    /// The max level of the records, e.g. `info`.
+    pub level: String,
+    /// The max levels of the records of the targets starting with the keys, e.g. `scheduler`.
+    pub targets: BTreeMap<String, String>,
+}
+
+impl Default for LogFilters {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Off.to_string().to_lowercase(),
+            targets: BTreeMap::new(),
+        }
+    }
+}
+
+impl LogFilters {
+    /// Parse the filters, skipping the invalid directives as the logger does.
+    pub fn parse_lossy(filters: &str) -> Self {
+        let mut result = Self::default();
+        for directive in directives(filters) {
+            if let Ok(directive) = parse_directive(directive) {
+                result.apply(directive);
+            }
+        }
+        result
+    }
+
+    /// Set the max level of the records.
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level_name(level);
+        self
+    }
+
+    /// Set the max level of the records of the target.
+    pub fn with_target(mut self, target: &str, level: LevelFilter) -> Self {
+        self.targets.insert(target.to_string(), level_name(level));
+        self
+    }
+
+    /// Remove the max level of the target, so its records are filtered by the max level of all
+    /// the records.
+    pub fn without_target(mut self, target: &str) -> Self {
+        self.targets.remove(target);
+        self
+    }
+
+    fn apply(&mut self, (target, level): (Option<&str>, LevelFilter)) {
+        let level = level_name(level);
+        match target {
+            Some(target) => {
+                self.targets.insert(target.to_string(), level);
+            }
+            None => self.level = level,
+        }
+    }
+}
+
+impl FromStr for LogFilters {
+    type Err = LogError;
+
+    /// Parse the filters in the same form as the `RUST_LOG` environment variable, failing on
+    /// the invalid directives.
+    fn from_str(filters: &str) -> LogResult<Self> {
+        let mut result = Self::default();
+        for directive in directives(filters) {
+            result.apply(parse_directive(directive)?);
+        }
+        Ok(result)
+    }
+}
+
+impl fmt::Display for LogFilters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.level)?;
+        for (target, level) in &self.targets {
+            write!(f, ",{target}={level}")?;
+        }
+        Ok(())
+    }
+}
+
+fn directives(filters: &str) -> impl Iterator<Item = &str> {
+    filters
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+}
+
+/// Parse a directive like `info`, `scheduler=debug` or `scheduler`, which enables all the
+/// records of the target.
+fn parse_directive(directive: &str) -> LogResult<(Option<&str>, LevelFilter)> {
+    let invalid = || LogError::InvalidFilter(directive.to_string());
+    match directive.split_once('=') {
+        Some((target, level)) if !target.trim().is_empty() && !target.contains('/') => {
+            let level = LevelFilter::from_str(level.trim()).map_err(|_| invalid())?;
+            Ok((Some(target.trim()), level))
+        }
+        Some(_) => Err(invalid()),
+        None => match LevelFilter::from_str(directive) {
+            Ok(level) => Ok((None, level)),
+            Err(_) if !directive.contains('/') => Ok((Some(directive), LevelFilter::Trace)),
+            Err(_) => Err(invalid()),
+        },
+    }
+}
+
+fn level_name(level: LevelFilter) -> String {
+    level.to_string().to_lowercase()
+}
+
+thread_local! {
+    static STORED_FILTERS: RefCell<Option<StableCell<String, VirtualMemory<DefaultMemoryImpl>>>> =
+        const { RefCell::new(None) };
+}
+
+/// Open the filters kept in the memory. The memory keeps the `filters` if it's empty, otherwise
+/// the filters kept before the upgrade are returned.
+pub(crate) fn init_stored_filters(
+    memory: VirtualMemory<DefaultMemoryImpl>,
+    filters: &str,
+) -> String {
+    let cell =
+        StableCell::new(memory, filters.to_string()).expect("failed to init log filters cell");
+    let stored = cell.get().clone();
+    STORED_FILTERS.with(|stored| *stored.borrow_mut() = Some(cell));
+    stored
+}
+
+/// Keep the filters in the memory, if it was opened.
+pub(crate) fn store_filters(filters: &str) {
+    STORED_FILTERS.with(|stored| {
+        if let Some(cell) = &mut *stored.borrow_mut() {
+            cell.set(filters.to_string())
+                .expect("failed to store log filters");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::{default_ic_memory_manager, MemoryId};
+
+    use super::*;
+
+    #[test]
+    fn filters_are_parsed_and_displayed() {
+        let filters: LogFilters = "info, scheduler=DEBUG,ic_log".parse().unwrap();
+        assert_eq!(
+            filters,
+            LogFilters::default()
+                .with_level(LevelFilter::Info)
+                .with_target("scheduler", LevelFilter::Debug)
+                .with_target("ic_log", LevelFilter::Trace)
+        );
+        assert_eq!(filters.to_string(), "info,ic_log=trace,scheduler=debug");
+        assert_eq!(filters.to_string().parse::<LogFilters>().unwrap(), filters);
+
+        assert_eq!(
+            "info,scheduler=loud".parse::<LogFilters>(),
+            Err(LogError::InvalidFilter("scheduler=loud".to_string()))
+        );
+        assert_eq!(
+            LogFilters::parse_lossy("info,scheduler=loud,=debug"),
+            LogFilters::default().with_level(LevelFilter::Info)
+        );
+        assert_eq!(LogFilters::parse_lossy(""), LogFilters::default());
+    }
+
+    #[test]
+    fn stored_filters_survive_reinit() {
+        let memory = default_ic_memory_manager().get(MemoryId::new(20));
+
+        assert_eq!(init_stored_filters(memory.clone(), "info"), "info");
+        store_filters("info,scheduler=debug");
+        assert_eq!(init_stored_filters(memory, "warn"), "info,scheduler=debug");
+    }
+}
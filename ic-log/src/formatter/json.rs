@@ -0,0 +1,101 @@
+//! The records formatted as JSON lines, with their key-value fields, e.g.:
+//!
+//! ```text
+//! {"fields":{"caller":"aaaaa-aa","request_id":42},"level":"INFO","message":"transfer done","target":"ledger","timestamp":"2024-01-01T00:00:00Z"}
+//! ```
+
+use std::io::{self, Write};
+
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{json, Map};
+
+use super::humantime::Rfc3339Timestamp;
+use super::Formatter;
+
+pub(crate) struct JsonFormat<'a> {
+    pub timestamp: bool,
+    pub suffix: &'a str,
+    pub formatter: &'a mut Formatter,
+}
+
+impl<'a> JsonFormat<'a> {
+    pub fn write(self, record: &Record) -> io::Result<()> {
+        let mut fields = JsonFields::default();
+        record
+            .key_values()
+            .visit(&mut fields)
+            .map_err(io::Error::other)?;
+
+        let mut line = json!({
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+            "fields": fields.0,
+        });
+        if self.timestamp {
+            line["timestamp"] = Rfc3339Timestamp::now().to_string().into();
+        }
+
+        serde_json::to_writer(&mut *self.formatter, &line)?;
+        write!(self.formatter, "{}", self.suffix)
+    }
+}
+
+/// The key-value fields of a record as a JSON object. The values that can't be serialized are
+/// written as strings.
+#[derive(Default)]
+struct JsonFields(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| value.to_string().into());
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn record_is_written_with_fields() {
+        let mut formatter = Formatter::default();
+        let buf = formatter.buf.clone();
+        let fields = [
+            ("request_id", Value::from(42u64)),
+            ("caller", "aaaaa-aa".into()),
+        ];
+
+        JsonFormat {
+            timestamp: false,
+            suffix: "\n",
+            formatter: &mut formatter,
+        }
+        .write(
+            &Record::builder()
+                .args(format_args!("transfer \"done\""))
+                .level(Level::Info)
+                .target("ledger")
+                .key_values(&fields)
+                .build(),
+        )
+        .unwrap();
+
+        let buf = buf.borrow();
+        let line = std::str::from_utf8(buf.bytes()).unwrap();
+        assert!(line.ends_with('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(line).unwrap(),
+            json!({
+                "level": "INFO",
+                "target": "ledger",
+                "message": "transfer \"done\"",
+                "fields": { "request_id": 42, "caller": "aaaaa-aa" },
+            })
+        );
+    }
+}
@@ -34,10 +34,14 @@ use std::{fmt, io};
 
 pub mod buffer;
 mod humantime;
+mod json;
+use log::kv::{self, Key, Value, VisitSource};
 use log::Record;
 
 use self::buffer::Buffer;
 use self::humantime::Rfc3339Timestamp;
+use self::json::JsonFormat;
+use crate::context::with_context_fields;
 use crate::writer::Writer;
 
 /// A formatter to write logs into.
@@ -56,8 +60,8 @@ pub struct Formatter {
 }
 
 impl Formatter {
-    pub(crate) fn print(&self, writer: &dyn Writer) -> io::Result<()> {
-        writer.print(&self.buf.borrow())
+    pub(crate) fn print(&self, writer: &dyn Writer, record: &Record) -> io::Result<()> {
+        writer.write_record(record, &self.buf.borrow())
     }
 
     pub(crate) fn clear(&mut self) {
@@ -89,6 +93,8 @@ pub(crate) struct Builder {
     pub format_target: bool,
     pub format_level: bool,
     pub format_indent: Option<usize>,
+    pub format_json: bool,
+    pub format_message_context: bool,
     pub custom_format: Option<FormatFn>,
     pub format_suffix: &'static str,
 }
@@ -101,6 +107,8 @@ impl Default for Builder {
             format_target: true,
             format_level: true,
             format_indent: Some(4),
+            format_json: false,
+            format_message_context: true,
             custom_format: None,
             format_suffix: "\n",
         }
@@ -111,11 +119,26 @@ impl Builder {
     /// Convert the format into a callable function.
     ///
     /// If the `custom_format` is `Some`, then any `default_format` switches are ignored.
+    /// If the `format_json` is `true`, then the JSON format is returned.
+    /// If the `format_message_context` is `true`, then the caller, the method and the
+    /// correlation id of the current message are written as the fields of the records.
     /// If the `custom_format` is `None`, then a default format is returned.
     /// Any `default_format` switches set to `false` won't be written by the format.
     pub fn build(self) -> FormatFn {
         if let Some(fmt) = self.custom_format {
             fmt
+        } else if self.format_json {
+            Box::new(move |buf, record| {
+                let fmt = JsonFormat {
+                    timestamp: self.timestamp,
+                    suffix: self.format_suffix,
+                    formatter: buf,
+                };
+
+                with_context_fields(self.format_message_context, record, |record| {
+                    fmt.write(record)
+                })
+            })
         } else {
             Box::new(move |buf, record| {
                 let fmt = DefaultFormat {
@@ -129,7 +152,9 @@ impl Builder {
                     formatter: buf,
                 };
 
-                fmt.write(record)
+                with_context_fields(self.format_message_context, record, |record| {
+                    fmt.write(record)
+                })
             })
         }
     }
@@ -162,6 +187,23 @@ impl<'a> DefaultFormat<'a> {
         self.write_args(record)
     }
 
+    /// Write the key-value fields of the record as ` key=value`.
+    fn write_fields(&mut self, record: &Record) -> io::Result<()> {
+        struct FieldsWriter<'a>(&'a mut Formatter);
+
+        impl<'kvs> VisitSource<'kvs> for FieldsWriter<'_> {
+            fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+                write!(self.0, " {key}={value}")
+                    .map_err(|_| kv::Error::msg("failed to write the log field"))
+            }
+        }
+
+        record
+            .key_values()
+            .visit(&mut FieldsWriter(&mut *self.formatter))
+            .map_err(io::Error::other)
+    }
+
     fn subtle_style(&self, text: &'static str) -> SubtleStyle {
         {
             text
@@ -240,7 +282,11 @@ impl<'a> DefaultFormat<'a> {
     fn write_args(&mut self, record: &Record) -> io::Result<()> {
         match self.indent {
             // Fast path for no indentation
-            None => write!(self.formatter, "{}{}", record.args(), self.suffix),
+            None => {
+                write!(self.formatter, "{}", record.args())?;
+                self.write_fields(record)?;
+                write!(self.formatter, "{}", self.suffix)
+            }
 
             Some(indent_count) => {
                 // Create a wrapper around the buffer only if we have to actually indent the message
@@ -284,6 +330,7 @@ impl<'a> DefaultFormat<'a> {
                     write!(wrapper, "{}", record.args())?;
                 }
 
+                self.write_fields(record)?;
                 write!(self.formatter, "{}", self.suffix)?;
 
                 Ok(())
@@ -325,6 +372,32 @@ mod tests {
         write_target("", fmt)
     }
 
+    #[test]
+    fn format_fields() {
+        let mut f = Formatter::default();
+        let fields = [("request_id", 42)];
+
+        let written = write_record(
+            Record::builder()
+                .args(format_args!("log\nmessage"))
+                .level(Level::Info)
+                .key_values(&fields)
+                .build(),
+            DefaultFormat {
+                timestamp: false,
+                module_path: false,
+                target: false,
+                level: true,
+                written_header_value: false,
+                indent: Some(4),
+                suffix: "\n",
+                formatter: &mut f,
+            },
+        );
+
+        assert_eq!("[INFO ] log\n    message request_id=42\n", written);
+    }
+
     #[test]
     fn format_with_header() {
         let mut f = Formatter::default();
@@ -0,0 +1,256 @@
+//! Recent log records served by the `http_request` query of the canister, so they can be read
+//! with a browser or `curl` through the HTTP gateway.
+
+use std::str::FromStr;
+
+use ic_exports::candid::{CandidType, Deserialize};
+use ic_helpers::types::Pagination;
+use log::LevelFilter;
+
+use crate::canister::MAX_LOGS_PAGE;
+use crate::stable_log::StableLogWriter;
+use crate::writer::InMemoryWriter;
+
+/// The path of the logs served by [`logs_http_handler`].
+pub const LOGS_PATH: &str = "/logs";
+
+const DEFAULT_LIMIT: u64 = 100;
+
+/// A request to the `http_request` query of the canister, as sent by the HTTP gateway.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+/// A response of the `http_request` query of the canister.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn text(status_code: u16, content_type: &str, body: String) -> Self {
+        Self {
+            status_code,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into_bytes(),
+        }
+    }
+}
+
+/// Serve the log records for the requests to [`LOGS_PATH`], other requests are left to the
+/// caller.
+///
+/// The records are taken from the log in stable memory if it's enabled, otherwise from the
+/// in-memory buffer. The query parameters select the records:
+/// - `offset` - the number of the matching records to skip, `0` by default;
+/// - `limit` - the max number of the records, `100` by default;
+/// - `level` - the max level of the records, e.g. `warn`, only for the log in stable memory;
+/// - `target` - the prefix of the targets, only for the log in stable memory;
+/// - `format=json` - return the records as JSON instead of the plain text lines.
+///
+/// If the `token` is set, the request must carry it either in the `Authorization: Bearer`
+/// header or in the `token` query parameter.
+///
+/// ```ignore
+/// #[query]
+/// fn http_request(&self, request: HttpRequest) -> HttpResponse {
+///     logs_http_handler(&request, Some("secret")).unwrap_or_else(|| not_found())
+/// }
+/// ```
+pub fn logs_http_handler(request: &HttpRequest, token: Option<&str>) -> Option<HttpResponse> {
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    if path != LOGS_PATH {
+        return None;
+    }
+
+    if !request.method.eq_ignore_ascii_case("GET") {
+        return Some(HttpResponse::text(
+            405,
+            "text/plain",
+            "Method not allowed".to_string(),
+        ));
+    }
+
+    let params: Vec<(&str, String)> = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| (name, percent_decode(value)))
+        .collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    if let Some(token) = token {
+        let mut bearer = request
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            .filter_map(|(_, value)| value.strip_prefix("Bearer "));
+        if !bearer.any(|value| value == token) && param("token") != Some(token) {
+            return Some(HttpResponse::text(
+                401,
+                "text/plain",
+                "Unauthorized".to_string(),
+            ));
+        }
+    }
+
+    let bad_request = |message: String| Some(HttpResponse::text(400, "text/plain", message));
+    let offset = match param("offset").map(u64::from_str).transpose() {
+        Ok(offset) => offset.unwrap_or(0),
+        Err(_) => return bad_request("Invalid offset".to_string()),
+    };
+    let limit = match param("limit").map(u64::from_str).transpose() {
+        Ok(limit) => limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LOGS_PAGE),
+        Err(_) => return bad_request("Invalid limit".to_string()),
+    };
+    let level = match param("level").map(LevelFilter::from_str).transpose() {
+        Ok(level) => level.unwrap_or(LevelFilter::Trace),
+        Err(_) => return bad_request("Invalid level".to_string()),
+    };
+    let pagination = Pagination::new(offset, limit);
+    let json = param("format") == Some("json");
+
+    let response = if StableLogWriter::is_initialized() {
+        let page = StableLogWriter::filter_records(pagination, level, param("target"));
+        if json {
+            serde_json::to_string(&page).map(|body| ("application/json", body))
+        } else {
+            let lines = page
+                .items
+                .iter()
+                .map(|record| record.message.clone() + "\n");
+            Ok(("text/plain; charset=utf-8", lines.collect()))
+        }
+    } else {
+        let logs =
+            InMemoryWriter::take_records(pagination.count as usize, pagination.offset as usize);
+        if json {
+            serde_json::to_string(&logs).map(|body| ("application/json", body))
+        } else {
+            let lines = logs.logs.into_iter().map(|log| log.log);
+            Ok(("text/plain; charset=utf-8", lines.collect()))
+        }
+    };
+
+    Some(match response {
+        Ok((content_type, body)) => HttpResponse::text(200, content_type, body),
+        Err(err) => HttpResponse::text(500, "text/plain", err.to_string()),
+    })
+}
+
+/// Decode the `%XX` escapes and the `+` spaces of a query parameter.
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let escape = input.clone().take(2).collect::<Vec<_>>();
+                match std::str::from_utf8(&escape)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(decoded) if escape.len() == 2 => {
+                        bytes.push(decoded);
+                        input.nth(1);
+                    }
+                    _ => bytes.push(byte),
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::default_ic_memory_manager;
+    use log::{Level, Record};
+
+    use super::*;
+    use crate::stable_log::StableLogSettings;
+    use crate::writer::Writer;
+
+    fn request(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn handler_serves_filtered_records() {
+        StableLogWriter::init(
+            &StableLogSettings {
+                first_memory_id: 40,
+                max_bytes: 1_000_000,
+            },
+            &default_ic_memory_manager(),
+        );
+        let writer = StableLogWriter {};
+        for (level, target, message) in [
+            (Level::Info, "ic_log::span", "span"),
+            (Level::Error, "api", "failed"),
+            (Level::Warn, "ic_log::span", "slow span"),
+        ] {
+            let record = Record::builder()
+                .args(format_args!("{message}"))
+                .level(level)
+                .target(target)
+                .build();
+            writer
+                .write_record(&record, &format!("{message}\n").into())
+                .unwrap();
+        }
+
+        assert_eq!(logs_http_handler(&request("/metrics"), None), None);
+
+        let response = logs_http_handler(&request("/logs?target=ic_log%3A%3Aspan"), None).unwrap();
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"span\nslow span\n");
+
+        let response = logs_http_handler(&request("/logs?level=warn&offset=1"), None).unwrap();
+        assert_eq!(response.body, b"slow span\n");
+
+        let response = logs_http_handler(&request("/logs?limit=1&format=json"), None).unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["items"][0]["message"], "span");
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+
+        let status = |url| {
+            logs_http_handler(&request(url), Some("secret"))
+                .unwrap()
+                .status_code
+        };
+        assert_eq!(status("/logs?limit=x&token=secret"), 400);
+        assert_eq!(status("/logs?token=wrong"), 401);
+        assert_eq!(status("/logs?token=secret"), 200);
+    }
+
+    #[test]
+    fn query_parameters_are_decoded() {
+        assert_eq!(percent_decode("ic_log%3a%3Aspan+x"), "ic_log::span x");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
@@ -1,10 +1,25 @@
+pub use canister::{LogCanister, MAX_LOGS_PAGE};
 use env_filter::Filter;
+pub use error::{LogError, LogResult};
+use filters::LogFilters;
 use formatter::FormatFn;
 use ic_exports::candid::{CandidType, Deserialize};
+use ic_stable_structures::stable_structures::DefaultMemoryImpl;
+use ic_stable_structures::{IcMemoryManager, MemoryId};
+use ring_buffer::RingBufferWriter;
+use stable_log::{StableLogSettings, StableLogWriter, StableLogs};
 use writer::{ConsoleWriter, InMemoryWriter, Logs, MultiWriter, Writer};
 
+mod canister;
+mod context;
+mod error;
+pub mod filters;
 mod formatter;
+pub mod http;
 mod platform;
+pub mod ring_buffer;
+pub mod span;
+pub mod stable_log;
 pub mod writer;
 
 use std::cell::RefCell;
@@ -65,6 +80,7 @@ pub struct Logger {
 #[derive(Default)]
 pub struct Builder {
     filter: env_filter::Builder,
+    directives: Vec<String>,
     writer: MultiWriter,
     format: formatter::Builder,
 }
@@ -100,6 +116,23 @@ impl Builder {
         self
     }
 
+    /// Whether or not to write the records as JSON lines with their key-value fields, instead
+    /// of the default format.
+    ///
+    /// The fields are added with the `log` macros, e.g.
+    /// `info!(request_id = 42, caller:% = caller; "transfer done")`.
+    pub fn format_json(mut self, json: bool) -> Self {
+        self.format.format_json = json;
+        self
+    }
+
+    /// Whether or not to write the caller, the method and the correlation id of the current
+    /// message as the fields of the records. They are written by default.
+    pub fn format_message_context(mut self, write: bool) -> Self {
+        self.format.format_message_context = write;
+        self
+    }
+
     /// Configures the end of line suffix.
     pub fn format_suffix(mut self, suffix: &'static str) -> Self {
         self.format.format_suffix = suffix;
@@ -122,6 +155,7 @@ impl Builder {
     /// ```
     pub fn filter_module(mut self, module: &str, level: LevelFilter) -> Self {
         self.filter.filter_module(module, level);
+        self.directives.push(format!("{module}={level}"));
         self
     }
 
@@ -141,6 +175,7 @@ impl Builder {
     /// ```
     pub fn filter_level(mut self, level: LevelFilter) -> Self {
         self.filter.filter_level(level);
+        self.directives.push(level.to_string());
         self
     }
 
@@ -163,6 +198,10 @@ impl Builder {
     /// ```
     pub fn filter(mut self, module: Option<&str>, level: LevelFilter) -> Self {
         self.filter.filter(module, level);
+        self.directives.push(match module {
+            Some(module) => format!("{module}={level}"),
+            None => level.to_string(),
+        });
         self
     }
 
@@ -173,6 +212,7 @@ impl Builder {
     /// - debug,crate1::mod1=error,crate1::mod2,crate2=debug
     pub fn parse_filters(mut self, filters: &str) -> Self {
         self.filter.parse(filters);
+        self.directives.push(filters.to_string());
         self
     }
 
@@ -197,6 +237,7 @@ impl Builder {
         let max_level = logger.filter();
         log::set_boxed_logger(Box::new(logger))?;
         log::set_max_level(max_level);
+        LOGGER_CONFIG.with(|config| *config.borrow_mut() = Some(filter.clone()));
         Ok(filter)
     }
 
@@ -219,13 +260,27 @@ impl Builder {
                 filter: filter.clone(),
                 format: self.format.build(),
             },
-            LoggerConfig { filter },
+            LoggerConfig {
+                filter,
+                directives: Arc::new(ArcSwap::from_pointee(self.directives.join(","))),
+            },
         )
     }
 }
 
+thread_local! {
+    static LOGGER_CONFIG: RefCell<Option<LoggerConfig>> = const { RefCell::new(None) };
+}
+
+/// Returns the configuration of the global logger, if it was initialized by this crate.
+pub fn logger_config() -> Option<LoggerConfig> {
+    LOGGER_CONFIG.with(|config| config.borrow().clone())
+}
+
+#[derive(Clone)]
 pub struct LoggerConfig {
     filter: Arc<ArcSwapAny<Arc<Filter>>>,
+    directives: Arc<ArcSwapAny<Arc<String>>>,
 }
 
 impl LoggerConfig {
@@ -234,11 +289,41 @@ impl LoggerConfig {
     /// Example of valid filters:
     /// - info
     /// - debug,crate1::mod1=error,crate1::mod2,crate2=debug
+    ///
+    /// If the logger keeps its filters in stable memory, the new filters are stored there too.
     pub fn update_filters(&self, filters: &str) {
         let new_filter = env_filter::Builder::default().parse(filters).build();
         let max_level = new_filter.filter();
         self.filter.swap(Arc::new(new_filter));
+        self.directives.store(Arc::new(filters.to_string()));
         log::set_max_level(max_level);
+        crate::filters::store_filters(filters);
+    }
+
+    /// Returns the current filters of the logger.
+    pub fn filters(&self) -> LogFilters {
+        LogFilters::parse_lossy(&self.directives.load())
+    }
+
+    /// Replaces the filters of the logger.
+    pub fn set_filters(&self, filters: &LogFilters) {
+        self.update_filters(&filters.to_string());
+    }
+
+    /// Sets the max level of the records, keeping the levels of the targets.
+    pub fn set_level(&self, level: LevelFilter) {
+        self.set_filters(&self.filters().with_level(level));
+    }
+
+    /// Sets the max level of the records of the target, e.g. `scheduler=debug`.
+    pub fn set_target_level(&self, target: &str, level: LevelFilter) {
+        self.set_filters(&self.filters().with_target(target, level));
+    }
+
+    /// Removes the max level of the target, so its records are filtered by the max level of
+    /// all the records.
+    pub fn remove_target_level(&self, target: &str) {
+        self.set_filters(&self.filters().without_target(target));
     }
 }
 
@@ -277,7 +362,7 @@ impl Log for Logger {
 
             let print = |formatter: &mut Formatter, record: &Record| {
                 let _ = (self.format)(formatter, record)
-                    .and_then(|_| formatter.print(self.writer.as_ref()));
+                    .and_then(|_| formatter.print(self.writer.as_ref(), record));
 
                 // Always clear the buffer afterwards
                 formatter.clear();
@@ -333,7 +418,9 @@ mod std_fmt_impls {
 /// Log settings to initialize the logger
 #[derive(Default, Debug, Clone, CandidType, Deserialize)]
 pub struct LogSettings {
-    /// Enable logging to console (`ic::print` when running in IC)
+    /// Enable logging to console (`ic::print` when running in IC). In the IC, the printed
+    /// records are kept in the canister logs, which the controllers can fetch with
+    /// `dfx canister logs`.
     pub enable_console: bool,
     /// Number of records to be stored in the circular memory buffer.
     /// If None - storing records will be disable.
@@ -344,12 +431,61 @@ pub struct LogSettings {
     /// - info
     /// - debug,crate1::mod1=error,crate1::mod2,crate2=debug
     pub log_filter: Option<String>,
+    /// Store the records in stable memory too, so they survive the upgrades. Requires the
+    /// memory manager of the canister, see [`init_log_with_memory_manager`].
+    pub stable_log: Option<StableLogSettings>,
+    /// Number of the newest records kept in a heap buffer until they are flushed to the log in
+    /// stable memory with [`flush_log_buffer`], e.g. in `pre_upgrade`.
+    /// If None - the buffer is disabled.
+    /// If Some - the records are written to stable memory only by flushing the buffer.
+    pub ring_buffer_records: Option<usize>,
+    /// Write the records as JSON lines with their key-value fields, so they can be indexed by
+    /// the fields like the request id, the caller or the task id.
+    pub format_json: bool,
+    /// Keep the filters in the memory with this id of the memory manager of the canister, so
+    /// the filters changed at runtime survive the upgrades. Once stored, they take precedence
+    /// over `log_filter`. Requires the memory manager, see [`init_log_with_memory_manager`].
+    pub filter_memory_id: Option<u8>,
 }
 
 /// Builds and initialize a logger based on the settings
+///
+/// # Panics
+/// If the settings keep the records or the filters in stable memory, which requires the memory
+/// manager of the canister, see [`init_log_with_memory_manager`].
 pub fn init_log(settings: &LogSettings) -> Result<LoggerConfig, SetLoggerError> {
-    let mut builder =
-        Builder::default().parse_filters(settings.log_filter.as_deref().unwrap_or("off"));
+    assert!(
+        settings.stable_log.is_none() && settings.filter_memory_id.is_none(),
+        "the stable log and the stored filters require the memory manager of the canister, use init_log_with_memory_manager"
+    );
+    init_logger(settings, None)
+}
+
+/// Builds and initialize a logger based on the settings, taking the memories of the stable log
+/// and of the stored filters from the memory manager of the canister.
+pub fn init_log_with_memory_manager(
+    settings: &LogSettings,
+    memory_manager: &IcMemoryManager<DefaultMemoryImpl>,
+) -> Result<LoggerConfig, SetLoggerError> {
+    init_logger(settings, Some(memory_manager))
+}
+
+fn init_logger(
+    settings: &LogSettings,
+    memory_manager: Option<&IcMemoryManager<DefaultMemoryImpl>>,
+) -> Result<LoggerConfig, SetLoggerError> {
+    let mut log_filter = settings
+        .log_filter
+        .clone()
+        .unwrap_or_else(|| "off".to_string());
+    if let (Some(memory_id), Some(memory_manager)) = (settings.filter_memory_id, memory_manager) {
+        let memory = memory_manager.get(MemoryId::new(memory_id));
+        log_filter = filters::init_stored_filters(memory, &log_filter);
+    }
+
+    let mut builder = Builder::default()
+        .parse_filters(&log_filter)
+        .format_json(settings.format_json);
 
     if settings.enable_console {
         builder = builder.add_writer(Box::new(ConsoleWriter {}));
@@ -360,6 +496,19 @@ pub fn init_log(settings: &LogSettings) -> Result<LoggerConfig, SetLoggerError>
         builder = builder.add_writer(Box::new(InMemoryWriter {}));
     }
 
+    if let (Some(stable_log), Some(memory_manager)) = (&settings.stable_log, memory_manager) {
+        StableLogWriter::init(stable_log, memory_manager);
+        // With the heap buffer, the records get to stable memory when the buffer is flushed
+        if settings.ring_buffer_records.is_none() {
+            builder = builder.add_writer(Box::new(StableLogWriter {}));
+        }
+    }
+
+    if let Some(count) = settings.ring_buffer_records {
+        RingBufferWriter::init(count);
+        builder = builder.add_writer(Box::new(RingBufferWriter {}));
+    }
+
     builder.try_init()
 }
 
@@ -368,6 +517,17 @@ pub fn take_memory_records(max_count: usize, from_offset: usize) -> Logs {
     writer::InMemoryWriter::take_records(max_count, from_offset)
 }
 
+/// Take the log records from stable memory.
+pub fn take_stable_records(max_count: usize, from_offset: u64) -> StableLogs {
+    StableLogWriter::take_records(max_count, from_offset)
+}
+
+/// Move the records of the heap buffer to the log in stable memory. Returns the number of the
+/// flushed records.
+pub fn flush_log_buffer() -> std::io::Result<usize> {
+    RingBufferWriter::flush()
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -381,6 +541,10 @@ mod tests {
             enable_console: true,
             in_memory_records: None,
             log_filter: Some("debug".to_string()),
+            stable_log: None,
+            ring_buffer_records: None,
+            format_json: false,
+            filter_memory_id: None,
         })
         .unwrap();
 
@@ -396,5 +560,13 @@ mod tests {
 
         debug!("This one should NOT be printed");
         info!("This one should be printed");
+
+        config.set_target_level("ic_log", LevelFilter::Debug);
+        assert_eq!(config.filters().to_string(), "info,ic_log=debug");
+        assert!(log_enabled!(Level::Debug));
+
+        config.remove_target_level("ic_log");
+        assert_eq!(logger_config().unwrap().filters().to_string(), "info");
+        assert!(!log_enabled!(Level::Debug));
     }
 }
@@ -15,7 +15,8 @@ pub fn current_system_time() -> SystemTime {
     }
 }
 
-/// Prints to the standard out
+/// Prints to the standard out. In the IC every print is a separate record of the canister logs,
+/// so the trailing new lines are dropped.
 #[inline]
 pub fn print(data: &[u8]) {
     #[cfg(not(target_family = "wasm"))]
@@ -25,6 +26,6 @@ pub fn print(data: &[u8]) {
 
     #[cfg(target_family = "wasm")]
     {
-        ic_exports::ic_cdk::print(String::from_utf8_lossy(data))
+        ic_exports::ic_cdk::print(String::from_utf8_lossy(data).trim_end_matches('\n'))
     }
 }
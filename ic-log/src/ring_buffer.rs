@@ -0,0 +1,178 @@
+//! A bounded buffer in the heap keeping the newest records, which can be flushed to the log in
+//! stable memory.
+//!
+//! Writing to the buffer is much cheaper than writing to stable memory, so it suits the high
+//! volume debug logging. The records which were not flushed are lost on a trap or an upgrade,
+//! so the buffer should be flushed on demand and in the `pre_upgrade` hook.
+
+use std::cell::RefCell;
+
+use candid::CandidType;
+use log::Record;
+use ringbuffer::{AllocRingBuffer, RingBuffer};
+use serde::{Deserialize, Serialize};
+
+use crate::formatter::buffer::Buffer;
+use crate::stable_log::{StableLogRecord, StableLogWriter};
+use crate::writer::Writer;
+
+/// The counters of the ring buffer.
+#[derive(Debug, Default, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct RingBufferStats {
+    /// The number of the records in the buffer.
+    pub len: u64,
+    pub capacity: u64,
+    /// The number of the records written to the buffer.
+    pub written: u64,
+    /// The number of the records overwritten by the newer ones before being flushed.
+    pub dropped: u64,
+    /// The number of the records flushed to the log in stable memory.
+    pub flushed: u64,
+}
+
+struct RingBufferState {
+    records: AllocRingBuffer<StableLogRecord>,
+    stats: RingBufferStats,
+}
+
+thread_local! {
+    static RING_BUFFER: RefCell<Option<RingBufferState>> = const { RefCell::new(None) };
+}
+
+/// Writer that keeps the newest records in a bounded buffer in the heap, counting the records
+/// dropped when it's full.
+pub struct RingBufferWriter {}
+
+impl RingBufferWriter {
+    /// Create the buffer for the `capacity` newest records, dropping the buffered ones.
+    pub fn init(capacity: usize) {
+        let state = RingBufferState {
+            records: AllocRingBuffer::new(capacity),
+            stats: RingBufferStats {
+                capacity: capacity as u64,
+                ..Default::default()
+            },
+        };
+        RING_BUFFER.with(|buffer| *buffer.borrow_mut() = Some(state));
+    }
+
+    pub fn stats() -> RingBufferStats {
+        RING_BUFFER.with(|buffer| match &*buffer.borrow() {
+            Some(state) => RingBufferStats {
+                len: state.records.len() as u64,
+                ..state.stats.clone()
+            },
+            None => RingBufferStats::default(),
+        })
+    }
+
+    /// Move the buffered records to the log in stable memory, the older ones first. Returns
+    /// the number of the flushed records.
+    ///
+    /// The records are kept in the buffer if the log in stable memory is not initialized.
+    pub fn flush() -> std::io::Result<usize> {
+        if !StableLogWriter::is_initialized() {
+            return Ok(0);
+        }
+
+        RING_BUFFER.with(|buffer| {
+            let Some(state) = &mut *buffer.borrow_mut() else {
+                return Ok(0);
+            };
+
+            let mut flushed = 0;
+            while let Some(record) = state.records.dequeue() {
+                StableLogWriter::append(record)?;
+                flushed += 1;
+                state.stats.flushed += 1;
+            }
+            Ok(flushed)
+        })
+    }
+}
+
+impl Writer for RingBufferWriter {
+    fn print(&self, _buf: &Buffer) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_record(&self, record: &Record, buf: &Buffer) -> std::io::Result<()> {
+        RING_BUFFER.with(|buffer| {
+            if let Some(state) = &mut *buffer.borrow_mut() {
+                if state.records.is_full() {
+                    state.stats.dropped += 1;
+                }
+                state.records.push(StableLogRecord::new(record, buf));
+                state.stats.written += 1;
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::default_ic_memory_manager;
+    use log::Level;
+
+    use super::*;
+    use crate::stable_log::StableLogSettings;
+
+    fn write(writer: &RingBufferWriter, message: &str) {
+        let record = Record::builder()
+            .args(format_args!("{message}"))
+            .level(Level::Debug)
+            .target("test")
+            .build();
+        writer
+            .write_record(&record, &format!("{message}\n").into())
+            .unwrap();
+    }
+
+    #[test]
+    fn newest_records_are_flushed() {
+        RingBufferWriter::init(4);
+        let writer = RingBufferWriter {};
+        for i in 0..6 {
+            write(&writer, &format!("record {i}"));
+        }
+
+        assert_eq!(
+            RingBufferWriter::stats(),
+            RingBufferStats {
+                len: 4,
+                capacity: 4,
+                written: 6,
+                dropped: 2,
+                flushed: 0,
+            }
+        );
+        assert_eq!(RingBufferWriter::flush().unwrap(), 0);
+
+        StableLogWriter::init(
+            &StableLogSettings {
+                first_memory_id: 50,
+                max_bytes: 1_000_000,
+            },
+            &default_ic_memory_manager(),
+        );
+        assert_eq!(RingBufferWriter::flush().unwrap(), 4);
+        assert_eq!(RingBufferWriter::stats().len, 0);
+        assert_eq!(RingBufferWriter::stats().flushed, 4);
+
+        let logs = StableLogWriter::take_records(10, 0);
+        assert_eq!(
+            logs.records
+                .iter()
+                .map(|record| (record.offset, record.message.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (0, "record 2"),
+                (1, "record 3"),
+                (2, "record 4"),
+                (3, "record 5")
+            ]
+        );
+        assert_eq!(logs.records[0].level, "DEBUG");
+    }
+}
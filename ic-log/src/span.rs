@@ -0,0 +1,191 @@
+//! Spans measuring the instructions executed by the parts of an endpoint.
+//!
+//! A span is entered with the [`span!`](crate::span!) macro and exits when the returned guard
+//! is dropped. The spans entered while another one is active are nested under it, so their
+//! paths look like `transfer/sync_block`. On exit, the span is written to the logger with the
+//! [`SPAN_TARGET`] target and passed to the span observers, e.g. to collect it into metrics.
+//!
+//! ```
+//! fn sync_block(height: u64) {
+//!     let _span = ic_log::span!("sync_block", height);
+//!     // ...
+//! }
+//! ```
+//!
+//! The instructions are counted from the start of the current message, so a span must not be
+//! held across an `await`.
+
+use std::cell::RefCell;
+
+use ic_exports::instructions::instruction_counter;
+use log::kv::Value;
+use log::{Level, Record};
+
+/// The target of the log records of the spans.
+pub const SPAN_TARGET: &str = "ic_log::span";
+
+/// A span which exited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanRecord {
+    pub name: &'static str,
+    /// The names of the enclosing spans and of this one, separated by `/`.
+    pub path: String,
+    pub fields: Vec<(&'static str, String)>,
+    /// The instructions executed while the span was active, including the nested spans.
+    pub instructions: u64,
+}
+
+type SpanObserver = Box<dyn Fn(&SpanRecord)>;
+
+thread_local! {
+    static ACTIVE_SPANS: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+    static SPAN_OBSERVERS: RefCell<Vec<SpanObserver>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Add an observer, which is called with every span on its exit.
+///
+/// The observer must not enter spans.
+pub fn add_span_observer(observer: impl Fn(&SpanRecord) + 'static) {
+    SPAN_OBSERVERS.with(|observers| observers.borrow_mut().push(Box::new(observer)));
+}
+
+/// An active span, which exits when dropped.
+#[must_use = "the span exits when it's dropped"]
+pub struct Span {
+    name: &'static str,
+    path: String,
+    fields: Vec<(&'static str, String)>,
+    start_instructions: u64,
+}
+
+impl Span {
+    /// Enter the span, nested under the active one. Use the [`span!`](crate::span!) macro to
+    /// enter a span with the fields taken from the variables.
+    pub fn enter(name: &'static str, fields: Vec<(&'static str, String)>) -> Self {
+        let path = ACTIVE_SPANS.with(|spans| {
+            let mut spans = spans.borrow_mut();
+            spans.push(name);
+            spans.join("/")
+        });
+
+        let span = Self {
+            name,
+            path,
+            fields,
+            start_instructions: instruction_counter(),
+        };
+        span.log(Level::Trace, format_args!("enter {}", span.path), None);
+        span
+    }
+
+    /// The instructions executed since the span was entered.
+    pub fn instructions(&self) -> u64 {
+        instruction_counter().saturating_sub(self.start_instructions)
+    }
+
+    fn log(&self, level: Level, args: std::fmt::Arguments, instructions: Option<u64>) {
+        if level > log::max_level() {
+            return;
+        }
+
+        let mut key_values = vec![("span", Value::from(self.path.as_str()))];
+        key_values.extend(instructions.map(|count| ("instructions", Value::from(count))));
+        key_values.extend(
+            self.fields
+                .iter()
+                .map(|(key, value)| (*key, Value::from(value.as_str()))),
+        );
+        let key_values = key_values.as_slice();
+
+        log::logger().log(
+            &Record::builder()
+                .args(args)
+                .level(level)
+                .target(SPAN_TARGET)
+                .key_values(&key_values)
+                .build(),
+        );
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let instructions = self.instructions();
+        ACTIVE_SPANS.with(|spans| spans.borrow_mut().pop());
+
+        self.log(
+            Level::Debug,
+            format_args!("exit {} after {instructions} instructions", self.path),
+            Some(instructions),
+        );
+
+        let record = SpanRecord {
+            name: self.name,
+            path: std::mem::take(&mut self.path),
+            fields: std::mem::take(&mut self.fields),
+            instructions,
+        };
+        SPAN_OBSERVERS.with(|observers| {
+            for observer in observers.borrow().iter() {
+                observer(&record);
+            }
+        });
+    }
+}
+
+/// Enter a span with the fields taken from the variables or the expressions:
+///
+/// ```
+/// let height = 42;
+/// let _span = ic_log::span!("sync_block", height, hash = "ab01");
+/// ```
+#[macro_export]
+macro_rules! span {
+    (@value $key:ident) => {
+        $key.to_string()
+    };
+    (@value $key:ident = $value:expr) => {
+        $value.to_string()
+    };
+    ($name:expr $(, $key:ident $(= $value:expr)?)* $(,)?) => {
+        $crate::span::Span::enter(
+            $name,
+            vec![$((stringify!($key), $crate::span!(@value $key $(= $value)?))),*],
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn spans_are_nested_and_observed() {
+        let records = Rc::new(RefCell::new(vec![]));
+        let observed = records.clone();
+        add_span_observer(move |record| observed.borrow_mut().push(record.clone()));
+
+        let height = 7u64;
+        {
+            let _outer = crate::span!("transfer");
+            let _inner = crate::span!("sync_block", height, hash = "ab01");
+        }
+
+        let records = records.borrow();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.path.as_str())
+                .collect::<Vec<_>>(),
+            ["transfer/sync_block", "transfer"]
+        );
+        assert_eq!(records[0].name, "sync_block");
+        assert_eq!(
+            records[0].fields,
+            [("height", "7".to_string()), ("hash", "ab01".to_string())]
+        );
+        assert!(records[1].fields.is_empty());
+    }
+}
@@ -0,0 +1,492 @@
+//! Log records kept in stable memory, so they survive the upgrades and the traps of the
+//! canister.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use candid::{CandidType, Decode, Encode};
+use ic_helpers::types::{Page, Pagination};
+use ic_stable_structures::stable_structures::DefaultMemoryImpl;
+use ic_stable_structures::{
+    Bound, IcMemoryManager, LogStructure, MemoryId, StableLog, Storable, VirtualMemory,
+};
+use log::{Level, LevelFilter, Record};
+use serde::{Deserialize, Serialize};
+
+use crate::formatter::buffer::Buffer;
+use crate::platform;
+use crate::writer::Writer;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// The settings of the log in stable memory.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct StableLogSettings {
+    /// The log uses four memories of the memory manager of the canister, with consecutive ids
+    /// starting from this one.
+    pub first_memory_id: u8,
+    /// The max size of the records in the log. When it's reached, the oldest half of the
+    /// records is removed.
+    pub max_bytes: u64,
+}
+
+/// A log record stored in stable memory.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct StableLogRecord {
+    /// The number of the records written before this one.
+    pub offset: u64,
+    pub timestamp_nanos: u64,
+    pub level: String,
+    pub target: String,
+    /// The record formatted by the logger.
+    pub message: String,
+}
+
+impl StableLogRecord {
+    /// Create the record of the log line formatted from the record. The offset is set when the
+    /// record is appended to the log.
+    pub(crate) fn new(record: &Record, buf: &Buffer) -> Self {
+        let timestamp_nanos = platform::current_system_time()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Self {
+            offset: 0,
+            timestamp_nanos,
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: String::from_utf8_lossy(buf.bytes()).trim_end().to_string(),
+        }
+    }
+}
+
+impl Storable for StableLogRecord {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode log record"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("failed to decode log record")
+    }
+}
+
+/// The records of the log in stable memory.
+#[derive(Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct StableLogs {
+    pub records: Vec<StableLogRecord>,
+    /// The count of the records written to the log, including the removed ones.
+    pub all_logs_count: u64,
+}
+
+/// The level and the target of a record, kept in the heap to filter the records without
+/// reading them from stable memory.
+#[derive(Debug, Clone)]
+struct RecordKey {
+    level: Option<Level>,
+    target: Rc<str>,
+}
+
+impl RecordKey {
+    /// Create the key of the record, sharing the target with the other keys in `targets`.
+    fn new(record: &StableLogRecord, targets: &mut HashSet<Rc<str>>) -> Self {
+        let target = match targets.get(record.target.as_str()) {
+            Some(target) => target.clone(),
+            None => {
+                let target = Rc::<str>::from(record.target.as_str());
+                targets.insert(target.clone());
+                target
+            }
+        };
+
+        Self {
+            level: Level::from_str(&record.level).ok(),
+            target,
+        }
+    }
+}
+
+/// The records in two segments: when the current segment is full, the previous one is cleared
+/// and becomes the current one.
+///
+/// The keys of the records are kept in the heap in the order of the segments, so a page of the
+/// records is read from stable memory without decoding the records before it.
+struct StableLogStore {
+    segments: [StableLog<StableLogRecord, Memory>; 2],
+    keys: [Vec<RecordKey>; 2],
+    /// The targets of the keys.
+    targets: HashSet<Rc<str>>,
+    current: usize,
+    current_bytes: u64,
+    max_segment_bytes: u64,
+    next_offset: u64,
+}
+
+impl StableLogStore {
+    fn new(
+        settings: &StableLogSettings,
+        memory_manager: &IcMemoryManager<DefaultMemoryImpl>,
+    ) -> Self {
+        let memory = |n: u8| memory_manager.get(MemoryId::new(settings.first_memory_id + n));
+        let segments = [
+            StableLog::new(memory(0), memory(1)).expect("failed to init log segment"),
+            StableLog::new(memory(2), memory(3)).expect("failed to init log segment"),
+        ];
+
+        let last_offset = |segment: &StableLog<StableLogRecord, Memory>| {
+            segment
+                .len()
+                .checked_sub(1)
+                .and_then(|last| segment.get(last))
+                .map(|record| record.offset)
+        };
+        let last_offsets = [last_offset(&segments[0]), last_offset(&segments[1])];
+        let current = match last_offsets {
+            [Some(first), Some(second)] if second > first => 1,
+            [None, Some(_)] => 1,
+            _ => 0,
+        };
+
+        let mut targets = HashSet::new();
+        let mut keys = [vec![], vec![]];
+        let mut current_bytes = 0;
+        for (n, segment) in segments.iter().enumerate() {
+            for record in (0..segment.len()).filter_map(|index| segment.get(index)) {
+                if n == current {
+                    current_bytes += record.to_bytes().len() as u64;
+                }
+                keys[n].push(RecordKey::new(&record, &mut targets));
+            }
+        }
+
+        Self {
+            segments,
+            keys,
+            targets,
+            current,
+            current_bytes,
+            max_segment_bytes: settings.max_bytes / 2,
+            next_offset: last_offsets[current].map_or(0, |offset| offset + 1),
+        }
+    }
+
+    fn append(&mut self, mut record: StableLogRecord) -> std::io::Result<()> {
+        record.offset = self.next_offset;
+        let size = record.to_bytes().len() as u64;
+        if self.current_bytes + size > self.max_segment_bytes
+            && !self.segments[self.current].is_empty()
+        {
+            self.current = 1 - self.current;
+            self.segments[self.current].clear();
+            self.keys[self.current].clear();
+            self.targets.retain(|target| Rc::strong_count(target) > 1);
+            self.current_bytes = 0;
+        }
+
+        let key = RecordKey::new(&record, &mut self.targets);
+        self.segments[self.current]
+            .append(record)
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        self.keys[self.current].push(key);
+        self.current_bytes += size;
+        self.next_offset += 1;
+        Ok(())
+    }
+
+    /// Return the records from the offset, the older ones first.
+    fn records(&self, max_count: usize, from_offset: u64) -> Vec<StableLogRecord> {
+        let mut records = Vec::with_capacity(max_count.min(1024));
+        for segment in [
+            &self.segments[1 - self.current],
+            &self.segments[self.current],
+        ] {
+            let Some(first) = segment.get(0) else {
+                continue;
+            };
+            let start = from_offset.saturating_sub(first.offset);
+            for index in start..segment.len() {
+                if records.len() >= max_count {
+                    return records;
+                }
+                records.extend(segment.get(index));
+            }
+        }
+        records
+    }
+
+    /// Return the page of all the records, the older ones first. The records are consecutive,
+    /// so the page is read starting from the offset of its first record.
+    fn page(&self, pagination: Pagination) -> Page<StableLogRecord> {
+        let total = (self.keys[0].len() + self.keys[1].len()) as u64;
+        let first_offset = self.next_offset - total;
+        let count = pagination.count.min(total) as usize;
+        Page {
+            items: self.records(count, first_offset.saturating_add(pagination.offset)),
+            total,
+        }
+    }
+
+    /// Return the page of the records with the keys accepted by the filter, the older ones
+    /// first. The offset of the pagination counts the accepted records only, and only the
+    /// records of the page are read from stable memory.
+    fn filtered_page(
+        &self,
+        pagination: Pagination,
+        filter: impl Fn(&RecordKey) -> bool,
+    ) -> Page<StableLogRecord> {
+        let mut items = Vec::with_capacity(pagination.count.min(1024) as usize);
+        let mut total = 0;
+        for segment in [1 - self.current, self.current] {
+            for (index, key) in self.keys[segment].iter().enumerate() {
+                if !filter(key) {
+                    continue;
+                }
+                if total >= pagination.offset && (items.len() as u64) < pagination.count {
+                    items.extend(self.segments[segment].get(index as u64));
+                }
+                total += 1;
+            }
+        }
+        Page { items, total }
+    }
+}
+
+thread_local! {
+    static STABLE_LOG: RefCell<Option<StableLogStore>> = const { RefCell::new(None) };
+}
+
+/// Writer that appends the records to a log in stable memory, removing the oldest records when
+/// the log is full.
+pub struct StableLogWriter {}
+
+impl StableLogWriter {
+    /// Open the log in the memories of the settings, taken from the memory manager of the
+    /// canister, keeping the records written to them before the upgrade.
+    pub fn init(settings: &StableLogSettings, memory_manager: &IcMemoryManager<DefaultMemoryImpl>) {
+        let store = StableLogStore::new(settings, memory_manager);
+        STABLE_LOG.with(|log| *log.borrow_mut() = Some(store));
+    }
+
+    /// Whether the log was opened with [`Self::init`].
+    pub fn is_initialized() -> bool {
+        STABLE_LOG.with(|log| log.borrow().is_some())
+    }
+
+    /// Append the record to the log, if it was opened. The offset of the record is replaced
+    /// with the next offset of the log.
+    pub(crate) fn append(record: StableLogRecord) -> std::io::Result<()> {
+        STABLE_LOG.with(|log| match &mut *log.borrow_mut() {
+            Some(store) => store.append(record),
+            None => Ok(()),
+        })
+    }
+
+    /// Return up to `max_count` records starting from the offset. If the record at the offset
+    /// was removed, the records start from the oldest one.
+    pub fn take_records(max_count: usize, from_offset: u64) -> StableLogs {
+        STABLE_LOG.with(|log| match &*log.borrow() {
+            Some(store) => StableLogs {
+                records: store.records(max_count, from_offset),
+                all_logs_count: store.next_offset,
+            },
+            None => StableLogs::default(),
+        })
+    }
+
+    /// Return the page of the records with the level enabled by the level filter and the target
+    /// starting with the target filter. The total of the page is the number of the matching
+    /// records, so the pages can be read with `paginated_query` of `ic-canister-client`.
+    ///
+    /// Without the filters the page is read directly at its offset. With a filter, the levels
+    /// and the targets of the records are matched in the heap, and only the records of the page
+    /// are read from stable memory.
+    pub fn filter_records(
+        pagination: Pagination,
+        level_filter: LevelFilter,
+        target_filter: Option<&str>,
+    ) -> Page<StableLogRecord> {
+        let filter = |key: &RecordKey| {
+            key.level.is_some_and(|level| level <= level_filter)
+                && target_filter.map_or(true, |target| key.target.starts_with(target))
+        };
+
+        STABLE_LOG.with(|log| match &*log.borrow() {
+            // The records written by the writer always have a level.
+            Some(store) if level_filter == LevelFilter::Trace && target_filter.is_none() => {
+                store.page(pagination)
+            }
+            Some(store) => store.filtered_page(pagination, filter),
+            None => Page {
+                items: vec![],
+                total: 0,
+            },
+        })
+    }
+}
+
+impl Writer for StableLogWriter {
+    fn print(&self, _buf: &Buffer) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_record(&self, record: &Record, buf: &Buffer) -> std::io::Result<()> {
+        Self::append(StableLogRecord::new(record, buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::default_ic_memory_manager;
+
+    use super::*;
+
+    fn write(writer: &StableLogWriter, message: &str) {
+        write_with(writer, Level::Info, "test", message);
+    }
+
+    fn write_with(writer: &StableLogWriter, level: Level, target: &str, message: &str) {
+        let record = Record::builder()
+            .args(format_args!("{message}"))
+            .level(level)
+            .target(target)
+            .build();
+        writer
+            .write_record(&record, &format!("{message}\n").into())
+            .unwrap();
+    }
+
+    #[test]
+    fn records_are_rotated_and_kept_after_reinit() {
+        let memory_manager = default_ic_memory_manager();
+        let settings = StableLogSettings {
+            first_memory_id: 10,
+            max_bytes: 2_000,
+        };
+        StableLogWriter::init(&settings, &memory_manager);
+
+        let writer = StableLogWriter {};
+        for i in 0..100 {
+            write(&writer, &format!("record {i}"));
+        }
+
+        let logs = StableLogWriter::take_records(1000, 0);
+        assert_eq!(logs.all_logs_count, 100);
+        assert!(logs.records.len() < 100);
+        assert_eq!(logs.records.last().unwrap().message, "record 99");
+        assert_eq!(logs.records.last().unwrap().level, "INFO");
+        let first = logs.records[0].offset;
+        assert!(logs
+            .records
+            .iter()
+            .map(|record| record.offset)
+            .eq(first..100));
+
+        StableLogWriter::init(&settings, &memory_manager);
+        write(&writer, "after upgrade");
+        let logs = StableLogWriter::take_records(2, 99);
+        assert_eq!(logs.all_logs_count, 101);
+        assert_eq!(
+            logs.records
+                .iter()
+                .map(|record| record.message.as_str())
+                .collect::<Vec<_>>(),
+            ["record 99", "after upgrade"]
+        );
+    }
+
+    #[test]
+    fn records_are_paged_across_segments() {
+        let memory_manager = default_ic_memory_manager();
+        let settings = StableLogSettings {
+            first_memory_id: 50,
+            max_bytes: 2_000,
+        };
+        StableLogWriter::init(&settings, &memory_manager);
+
+        let writer = StableLogWriter {};
+        for i in 0..100 {
+            write(&writer, &format!("record {i}"));
+        }
+
+        let offsets = |page: &Page<StableLogRecord>| {
+            page.items
+                .iter()
+                .map(|record| record.offset)
+                .collect::<Vec<_>>()
+        };
+        let all = StableLogWriter::take_records(1000, 0).records;
+        let first = all[0].offset;
+        for _ in 0..2 {
+            let page =
+                StableLogWriter::filter_records(Pagination::new(3, 5), LevelFilter::Trace, None);
+            assert_eq!(page.total, all.len() as u64);
+            assert_eq!(offsets(&page), (first + 3..first + 8).collect::<Vec<_>>());
+
+            let filtered = StableLogWriter::filter_records(
+                Pagination::new(3, 5),
+                LevelFilter::Info,
+                Some("test"),
+            );
+            assert_eq!(filtered, page);
+
+            let last = Pagination::new(all.len() as u64 - 2, 5);
+            let last = StableLogWriter::filter_records(last, LevelFilter::Trace, None);
+            assert_eq!(offsets(&last), [98, 99]);
+
+            // The keys of the records are restored after an upgrade.
+            StableLogWriter::init(&settings, &memory_manager);
+        }
+    }
+
+    #[test]
+    fn records_are_filtered_by_level_and_target() {
+        StableLogWriter::init(
+            &StableLogSettings {
+                first_memory_id: 30,
+                max_bytes: 1_000_000,
+            },
+            &default_ic_memory_manager(),
+        );
+
+        let writer = StableLogWriter {};
+        write_with(&writer, Level::Debug, "scheduler", "debug 0");
+        write_with(&writer, Level::Error, "scheduler::task", "error 1");
+        write_with(&writer, Level::Warn, "api", "warn 2");
+        write_with(&writer, Level::Error, "scheduler", "error 3");
+        write_with(&writer, Level::Info, "scheduler", "info 4");
+
+        let messages = |page: Page<StableLogRecord>| {
+            page.items
+                .into_iter()
+                .map(|record| record.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(StableLogWriter::filter_records(
+                Pagination::new(0, 10),
+                LevelFilter::Warn,
+                None
+            )),
+            ["error 1", "warn 2", "error 3"]
+        );
+        assert_eq!(
+            messages(StableLogWriter::filter_records(
+                Pagination::new(1, 1),
+                LevelFilter::Info,
+                Some("scheduler")
+            )),
+            ["error 3"]
+        );
+        assert_eq!(
+            StableLogWriter::filter_records(Pagination::new(0, 10), LevelFilter::Info, None).total,
+            4
+        );
+        assert_eq!(
+            StableLogWriter::filter_records(Pagination::new(0, 10), LevelFilter::Off, None).total,
+            0
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use std::cell::RefCell;
 
 use candid::CandidType;
+use log::Record;
 use ringbuffer::{AllocRingBuffer, RingBuffer};
 use serde::{Deserialize, Serialize};
 
@@ -10,6 +11,12 @@ use crate::platform;
 /// A trait for the object that consumes already formatted log line.
 pub trait Writer: Send + Sync {
     fn print(&self, buf: &Buffer) -> std::io::Result<()>;
+
+    /// Consume the log line formatted from the record. The writers which store the level or
+    /// the target of the records can override it, by default the line is printed.
+    fn write_record(&self, _record: &Record, buf: &Buffer) -> std::io::Result<()> {
+        self.print(buf)
+    }
 }
 
 /// Writer implementation that prints the given data to the console
@@ -32,6 +39,13 @@ impl Writer for MultiWriter {
         }
         Ok(())
     }
+
+    fn write_record(&self, record: &Record, buf: &Buffer) -> std::io::Result<()> {
+        for writer in &self.writers {
+            writer.write_record(record, buf)?;
+        }
+        Ok(())
+    }
 }
 
 /// Writer implementation that prints the given data to the console
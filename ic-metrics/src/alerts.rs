@@ -0,0 +1,327 @@
+//! Alerts triggered when the metrics of the registry cross thresholds.
+
+use std::collections::BTreeMap;
+
+use ic_storage::IcStorage;
+
+use crate::{Labels, MetricValue, MetricsRegistry};
+
+/// The threshold of a rule.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Condition {
+    Above(f64),
+    Below(f64),
+}
+
+impl Condition {
+    fn holds(&self, value: f64) -> bool {
+        match self {
+            Condition::Above(threshold) => value > *threshold,
+            Condition::Below(threshold) => value < *threshold,
+        }
+    }
+}
+
+/// What value of the metric is compared with the threshold.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Measure {
+    /// The value of a counter or a gauge, or the sum of a histogram.
+    Value,
+    /// The change of the value per second since the previous evaluation, e.g. an error rate.
+    RatePerSec,
+    /// The estimated percentile of a histogram.
+    Percentile(f64),
+}
+
+/// A threshold on a series of a metric.
+///
+/// ```
+/// use ic_metrics::*;
+///
+/// let low_cycles = AlertRule::new("low_cycles", CYCLES_BALANCE).below(1e12);
+/// let errors = AlertRule::new("errors", "errors_total").rate_per_sec().above(5.0);
+/// let slow = AlertRule::new("slow", MESSAGE_INSTRUCTIONS)
+///     .with_labels(&[("class", "update")])
+///     .percentile(0.99)
+///     .above(1e9);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: String,
+    pub labels: Labels,
+    pub measure: Measure,
+    pub condition: Condition,
+}
+
+impl AlertRule {
+    /// Create a rule on the value of the metric, which fires when the value is above zero.
+    pub fn new(name: &str, metric: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            metric: metric.to_string(),
+            labels: vec![],
+            measure: Measure::Value,
+            condition: Condition::Above(0.0),
+        }
+    }
+
+    pub fn with_labels(mut self, labels: &[(&str, &str)]) -> Self {
+        let mut labels: Labels = labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        labels.sort();
+        self.labels = labels;
+        self
+    }
+
+    pub fn above(mut self, threshold: f64) -> Self {
+        self.condition = Condition::Above(threshold);
+        self
+    }
+
+    pub fn below(mut self, threshold: f64) -> Self {
+        self.condition = Condition::Below(threshold);
+        self
+    }
+
+    pub fn rate_per_sec(mut self) -> Self {
+        self.measure = Measure::RatePerSec;
+        self
+    }
+
+    pub fn percentile(mut self, fraction: f64) -> Self {
+        self.measure = Measure::Percentile(fraction);
+        self
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AlertState {
+    /// The condition of the rule started to hold.
+    Firing,
+    /// The condition of the rule stopped to hold.
+    Resolved,
+}
+
+/// A change of the state of a rule, passed to the handler of the alerts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub state: AlertState,
+    pub value: f64,
+    pub timestamp_nanos: u64,
+}
+
+type AlertHandler = Box<dyn FnMut(&AlertEvent)>;
+
+#[derive(Default)]
+struct RuleState {
+    firing: bool,
+    previous: Option<(u64, f64)>,
+}
+
+/// Evaluates the alert rules against the registry and calls the handler when a rule starts
+/// or stops firing.
+///
+/// The handler can do anything which can be done from the current message, e.g. log the
+/// alert or append a task to the scheduler to notify an ops canister:
+///
+/// ```ignore
+/// let mut alerts = AlertManager::new(move |event| {
+///     scheduler.append_task(OpsTask::Notify(event.rule.clone()).into());
+/// })
+/// .with_rule(AlertRule::new("low_cycles", CYCLES_BALANCE).below(1e12));
+///
+/// ic_cdk_timers::set_timer_interval(Duration::from_secs(60), move || {
+///     alerts.evaluate_registry();
+/// });
+/// ```
+pub struct AlertManager {
+    rules: Vec<AlertRule>,
+    states: BTreeMap<String, RuleState>,
+    handler: AlertHandler,
+}
+
+impl AlertManager {
+    pub fn new(handler: impl FnMut(&AlertEvent) + 'static) -> Self {
+        Self {
+            rules: vec![],
+            states: BTreeMap::new(),
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Add the rule, replacing the rule with the same name.
+    pub fn with_rule(mut self, rule: AlertRule) -> Self {
+        self.add_rule(rule);
+        self
+    }
+
+    /// Add the rule, replacing the rule with the same name.
+    pub fn add_rule(&mut self, rule: AlertRule) {
+        self.remove_rule(&rule.name);
+        self.rules.push(rule);
+    }
+
+    pub fn remove_rule(&mut self, name: &str) -> Option<AlertRule> {
+        self.states.remove(name);
+        let index = self.rules.iter().position(|rule| rule.name == name)?;
+        Some(self.rules.remove(index))
+    }
+
+    pub fn rules(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    /// Return the names of the rules which are firing.
+    pub fn firing(&self) -> Vec<&str> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                self.states
+                    .get(&rule.name)
+                    .is_some_and(|state| state.firing)
+            })
+            .map(|rule| rule.name.as_str())
+            .collect()
+    }
+
+    /// Evaluate the rules against the registry of the canister at the current time.
+    pub fn evaluate_registry(&mut self) -> Vec<AlertEvent> {
+        let registry = MetricsRegistry::get();
+        let registry = registry.borrow();
+        self.evaluate(&registry, ic_exports::ic_kit::ic::time())
+    }
+
+    /// Evaluate the rules, call the handler for every rule which started or stopped firing
+    /// and return the events. Rules on missing metrics don't change their state.
+    pub fn evaluate(
+        &mut self,
+        registry: &MetricsRegistry,
+        timestamp_nanos: u64,
+    ) -> Vec<AlertEvent> {
+        let mut events = vec![];
+        for rule in &self.rules {
+            let state = self.states.entry(rule.name.clone()).or_default();
+            let Some(value) = measure(rule, registry, timestamp_nanos, state) else {
+                continue;
+            };
+
+            let holds = rule.condition.holds(value);
+            if holds != state.firing {
+                state.firing = holds;
+                events.push(AlertEvent {
+                    rule: rule.name.clone(),
+                    state: match holds {
+                        true => AlertState::Firing,
+                        false => AlertState::Resolved,
+                    },
+                    value,
+                    timestamp_nanos,
+                });
+            }
+        }
+
+        for event in &events {
+            (self.handler)(event);
+        }
+
+        events
+    }
+}
+
+fn measure(
+    rule: &AlertRule,
+    registry: &MetricsRegistry,
+    timestamp_nanos: u64,
+    state: &mut RuleState,
+) -> Option<f64> {
+    let value = registry.family(&rule.metric)?.series.get(&rule.labels)?;
+    match rule.measure {
+        Measure::Value => Some(value.as_f64()),
+        Measure::Percentile(fraction) => match value {
+            MetricValue::Histogram(histogram) => histogram.percentile(fraction),
+            _ => None,
+        },
+        Measure::RatePerSec => {
+            let current = match value {
+                MetricValue::Histogram(histogram) => histogram.count as f64,
+                value => value.as_f64(),
+            };
+            let previous = state.previous.replace((timestamp_nanos, current));
+            let (previous_nanos, previous_value) = previous?;
+            if timestamp_nanos <= previous_nanos {
+                return None;
+            }
+            let elapsed_secs = (timestamp_nanos - previous_nanos) as f64 / 1e9;
+            Some((current - previous_value) / elapsed_secs)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::MetricKind;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn alerts_fire_and_resolve() {
+        let mut registry = MetricsRegistry::default();
+        registry.register("cycles", MetricKind::Gauge, "").unwrap();
+        registry
+            .register("errors", MetricKind::Counter, "")
+            .unwrap();
+        registry.set_gauge("cycles", &[], 100.0).unwrap();
+
+        let received = Rc::new(RefCell::new(vec![]));
+        let handler_received = received.clone();
+        let mut alerts = AlertManager::new(move |event: &AlertEvent| {
+            handler_received
+                .borrow_mut()
+                .push((event.rule.clone(), event.state))
+        })
+        .with_rule(AlertRule::new("low_cycles", "cycles").below(50.0))
+        .with_rule(
+            AlertRule::new("errors", "errors")
+                .with_labels(&[("method", "transfer")])
+                .rate_per_sec()
+                .above(1.0),
+        );
+
+        assert!(alerts.evaluate(&registry, 0).is_empty());
+
+        registry.set_gauge("cycles", &[], 10.0).unwrap();
+        registry
+            .increment("errors", &[("method", "transfer")], 1)
+            .unwrap();
+        assert_eq!(alerts.evaluate(&registry, SECOND).len(), 1);
+        assert_eq!(alerts.firing(), vec!["low_cycles"]);
+
+        registry
+            .increment("errors", &[("method", "transfer")], 20)
+            .unwrap();
+        alerts.evaluate(&registry, 11 * SECOND);
+        assert_eq!(alerts.firing(), vec!["low_cycles", "errors"]);
+
+        registry.set_gauge("cycles", &[], 100.0).unwrap();
+        alerts.evaluate(&registry, 21 * SECOND);
+        assert!(alerts.firing().is_empty());
+
+        assert_eq!(
+            *received.borrow(),
+            vec![
+                ("low_cycles".to_string(), AlertState::Firing),
+                ("errors".to_string(), AlertState::Firing),
+                ("low_cycles".to_string(), AlertState::Resolved),
+                ("errors".to_string(), AlertState::Resolved),
+            ]
+        );
+    }
+}
@@ -0,0 +1,351 @@
+//! History of the metrics in stable memory, kept for a limited time at several resolutions.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use ic_exports::candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, IterableSortedMapStructure, StableBTreeMap, Storable,
+};
+
+use crate::{Interval, MetricValue, MetricsRegistry};
+
+/// A resolution of the history: the snapshots recorded within one interval are aggregated
+/// into one, and are removed after the retention period.
+#[derive(Debug, Copy, Clone, CandidType, Deserialize)]
+pub struct RetentionTier {
+    pub interval: Interval,
+    pub retention_nanos: u64,
+}
+
+impl RetentionTier {
+    pub fn new(interval: Interval, retention_nanos: u64) -> Self {
+        Self {
+            interval,
+            retention_nanos,
+        }
+    }
+}
+
+/// The aggregate of the values of a series recorded within an interval.
+#[derive(Debug, Copy, Clone, PartialEq, CandidType, Deserialize)]
+pub struct Aggregate {
+    pub last: f64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Aggregate {
+    fn new(value: f64) -> Self {
+        Self {
+            last: value,
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.last = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// The aggregated values of all the series recorded within an interval.
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct HistorySnapshot {
+    /// The start of the interval.
+    pub timestamp_nanos: u64,
+    /// The values by the series, e.g. `requests_total{method="transfer"}`.
+    pub values: BTreeMap<String, Aggregate>,
+}
+
+impl Storable for HistorySnapshot {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("serialization of metrics snapshot failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("deserialization of metrics snapshot failed")
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct HistoryKey {
+    tier: u8,
+    timestamp_nanos: u64,
+}
+
+impl Storable for HistoryKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 9,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(9);
+        bytes.push(self.tier);
+        bytes.extend_from_slice(&self.timestamp_nanos.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self {
+            tier: bytes[0],
+            timestamp_nanos: u64::from_be_bytes(bytes[1..9].try_into().expect("key is 9 bytes")),
+        }
+    }
+}
+
+/// Snapshots of the metrics in stable memory, at the resolutions of the retention tiers.
+///
+/// Every recorded snapshot is aggregated into the current interval of every tier, so the
+/// history can keep e.g. the values per minute for a day and the hourly rollups for a month.
+///
+/// ```
+/// use ic_metrics::*;
+/// use ic_stable_structures::VectorMemory;
+///
+/// const MINUTE: u64 = 60_000_000_000;
+///
+/// let mut history = MetricsHistory::new(
+///     VectorMemory::default(),
+///     vec![
+///         RetentionTier::new(Interval::PerMinute, 24 * 60 * MINUTE),
+///         RetentionTier::new(Interval::PerHour, 30 * 24 * 60 * MINUTE),
+///     ],
+/// );
+/// for minute in 0..120 {
+///     history.record(minute * MINUTE, [("queue_size".to_string(), minute as f64)].into());
+/// }
+///
+/// assert_eq!(history.range(0, 0, 120 * MINUTE).len(), 120);
+/// let hours = history.range(1, 0, 120 * MINUTE);
+/// assert_eq!(hours.len(), 2);
+/// assert_eq!(hours[1].values["queue_size"].mean(), 89.5);
+/// ```
+pub struct MetricsHistory<M: Memory> {
+    tiers: Vec<RetentionTier>,
+    snapshots: StableBTreeMap<HistoryKey, HistorySnapshot, M>,
+}
+
+impl<M: Memory> MetricsHistory<M> {
+    /// Create a history in the memory, with the tiers ordered from the finest resolution.
+    ///
+    /// If the memory contains a history, it is kept.
+    ///
+    /// # Panics
+    /// If there are no tiers or more than 255 of them.
+    pub fn new(memory: M, tiers: Vec<RetentionTier>) -> Self {
+        assert!(
+            !tiers.is_empty() && tiers.len() <= u8::MAX as usize,
+            "the history needs from 1 to 255 retention tiers"
+        );
+        Self {
+            tiers,
+            snapshots: StableBTreeMap::new(memory),
+        }
+    }
+
+    pub fn tiers(&self) -> &[RetentionTier] {
+        &self.tiers
+    }
+
+    /// Aggregate the values into the intervals of the tiers containing the timestamp, and
+    /// remove the snapshots which are out of retention.
+    pub fn record(&mut self, timestamp_nanos: u64, values: BTreeMap<String, f64>) {
+        for (tier, retention) in self.tiers.iter().enumerate() {
+            let interval = retention.interval.nanos();
+            let key = HistoryKey {
+                tier: tier as u8,
+                timestamp_nanos: timestamp_nanos - timestamp_nanos % interval,
+            };
+            let mut snapshot = self.snapshots.get(&key).unwrap_or(HistorySnapshot {
+                timestamp_nanos: key.timestamp_nanos,
+                values: BTreeMap::new(),
+            });
+            for (series, value) in &values {
+                snapshot
+                    .values
+                    .entry(series.clone())
+                    .and_modify(|aggregate| aggregate.add(*value))
+                    .or_insert_with(|| Aggregate::new(*value));
+            }
+            self.snapshots.insert(key, snapshot);
+
+            let oldest_to_keep = HistoryKey {
+                tier: tier as u8,
+                timestamp_nanos: timestamp_nanos.saturating_sub(retention.retention_nanos),
+            };
+            let first = HistoryKey {
+                tier: tier as u8,
+                timestamp_nanos: 0,
+            };
+            let expired: Vec<_> = self
+                .snapshots
+                .range(first..oldest_to_keep)
+                .map(|(key, _)| key)
+                .collect();
+            for key in expired {
+                self.snapshots.remove(&key);
+            }
+        }
+    }
+
+    /// Record the current values of the registry at the current time.
+    pub fn record_registry(&mut self, registry: &MetricsRegistry) {
+        self.record(ic_exports::ic_kit::ic::time(), registry_values(registry));
+    }
+
+    /// Return the snapshots of the tier with the intervals starting in the time range.
+    pub fn range(&self, tier: usize, from_nanos: u64, to_nanos: u64) -> Vec<HistorySnapshot> {
+        if tier >= self.tiers.len() || from_nanos >= to_nanos {
+            return vec![];
+        }
+
+        let key = |timestamp_nanos| HistoryKey {
+            tier: tier as u8,
+            timestamp_nanos,
+        };
+        self.snapshots
+            .range(key(from_nanos)..key(to_nanos))
+            .map(|(_, snapshot)| snapshot)
+            .collect()
+    }
+
+    /// Return the snapshots in the time range from the finest tier which still retains the
+    /// start of the range, or from the coarsest tier if none does.
+    pub fn query(&self, from_nanos: u64, to_nanos: u64) -> Vec<HistorySnapshot> {
+        let tier = (0..self.tiers.len())
+            .find(|&tier| {
+                self.first_timestamp(tier)
+                    .is_some_and(|first| first <= from_nanos)
+            })
+            .unwrap_or(self.tiers.len() - 1);
+        self.range(tier, from_nanos, to_nanos)
+    }
+
+    fn first_timestamp(&self, tier: usize) -> Option<u64> {
+        self.snapshots
+            .range(
+                HistoryKey {
+                    tier: tier as u8,
+                    timestamp_nanos: 0,
+                }..,
+            )
+            .next()
+            .filter(|(key, _)| key.tier == tier as u8)
+            .map(|(key, _)| key.timestamp_nanos)
+    }
+}
+
+/// Return the values of all the series of the registry by their names with labels. Histograms
+/// are represented by their `_sum` and `_count` series.
+pub fn registry_values(registry: &MetricsRegistry) -> BTreeMap<String, f64> {
+    let mut values = BTreeMap::new();
+    for (name, family) in registry.families() {
+        for (labels, value) in &family.series {
+            let labels = match labels.is_empty() {
+                true => String::new(),
+                false => {
+                    let labels: Vec<_> = labels
+                        .iter()
+                        .map(|(name, value)| format!("{name}=\"{value}\""))
+                        .collect();
+                    format!("{{{}}}", labels.join(","))
+                }
+            };
+            match value {
+                MetricValue::Histogram(histogram) => {
+                    values.insert(format!("{name}_sum{labels}"), histogram.sum);
+                    values.insert(format!("{name}_count{labels}"), histogram.count as f64);
+                }
+                value => {
+                    values.insert(format!("{name}{labels}"), value.as_f64());
+                }
+            }
+        }
+    }
+
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+    use crate::MetricKind;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn history() -> MetricsHistory<VectorMemory> {
+        MetricsHistory::new(
+            VectorMemory::default(),
+            vec![
+                RetentionTier::new(Interval::from_secs(10), 60 * SECOND),
+                RetentionTier::new(Interval::from_secs(60), 600 * SECOND),
+            ],
+        )
+    }
+
+    fn values(value: f64) -> BTreeMap<String, f64> {
+        [("cycles".to_string(), value)].into()
+    }
+
+    #[test]
+    fn retention_and_rollups() {
+        let mut history = history();
+        for second in (0..300).step_by(5) {
+            history.record(second * SECOND, values(second as f64));
+        }
+
+        let raw = history.range(0, 0, 300 * SECOND);
+        assert_eq!(raw.len(), 6);
+        assert_eq!(raw[0].timestamp_nanos, 240 * SECOND);
+        let last = raw.last().unwrap().values["cycles"];
+        assert_eq!(
+            (last.count, last.min, last.max, last.last),
+            (2, 290.0, 295.0, 295.0)
+        );
+
+        let minutes = history.range(1, 0, 300 * SECOND);
+        assert_eq!(minutes.len(), 5);
+        assert_eq!(minutes[0].values["cycles"].count, 12);
+        assert_eq!(minutes[0].values["cycles"].mean(), 27.5);
+
+        assert_eq!(history.query(250 * SECOND, 300 * SECOND).len(), 5);
+        assert_eq!(history.query(0, 300 * SECOND).len(), 5);
+        assert_eq!(history.query(0, 300 * SECOND)[0].timestamp_nanos, 0);
+        assert!(history.range(2, 0, 300 * SECOND).is_empty());
+    }
+
+    #[test]
+    fn values_of_the_registry() {
+        let mut registry = MetricsRegistry::default();
+        registry.register("calls", MetricKind::Counter, "").unwrap();
+        registry.increment("calls", &[("method", "a")], 3).unwrap();
+        registry
+            .register_histogram("latency", "", vec![1.0])
+            .unwrap();
+        registry.observe("latency", &[], 0.5).unwrap();
+
+        let values = registry_values(&registry);
+        assert_eq!(values["calls{method=\"a\"}"], 3.0);
+        assert_eq!(values["latency_sum"], 0.5);
+        assert_eq!(values["latency_count"], 1.0);
+    }
+}
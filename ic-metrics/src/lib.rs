@@ -52,16 +52,32 @@
 //!
 //! For the further example you can refer to the tests in the `canister-b` crate.
 
+mod alerts;
+mod history;
+mod profile;
+mod prometheus;
+mod push;
+mod registry;
+mod sources;
+mod system;
+mod usage;
+
 use std::cell::RefCell;
 use std::rc::Rc;
 
+pub use alerts::*;
 use candid::Principal;
+pub use history::*;
 use ic_canister::{generate_exports, generate_idl, query, state_getter, Canister, Idl, PreUpdate};
 use ic_exports::candid::{CandidType, Deserialize};
 use ic_storage::IcStorage;
-
-#[cfg(target_family = "wasm")]
-const WASM_PAGE_SIZE: u64 = 65536;
+pub use profile::*;
+pub use prometheus::*;
+pub use push::*;
+pub use registry::*;
+pub use sources::*;
+pub use system::*;
+pub use usage::*;
 
 #[derive(CandidType, Deserialize, IcStorage, Default, Clone, Debug)]
 pub struct MetricsStorage {
@@ -89,10 +105,23 @@ pub trait Metrics: Canister {
         MetricsStorage::get().borrow().clone()
     }
 
+    /// Returns the metrics registered at runtime in the [`MetricsRegistry`].
+    #[query(trait = true)]
+    fn get_registered_metrics(&self) -> MetricsRegistry {
+        MetricsRegistry::get().borrow().clone()
+    }
+
+    /// Returns the instructions of the profiled scopes, see [`profile!`].
+    #[query(trait = true)]
+    fn get_profile(&self) -> Vec<ScopeProfile> {
+        profile_snapshot()
+    }
+
     fn update_metrics(&self) {
         let metrics = MetricsStorage::get();
         let mut metrics = metrics.borrow_mut();
         metrics.metrics.insert(curr_values());
+        collect_system_metrics();
     }
 
     /// This function updates the metrics at intervals with the specified timer
@@ -107,6 +136,7 @@ pub trait Metrics: Canister {
 
         ic_cdk_timers::set_timer_interval(timer, move || {
             metrics.borrow_mut().metrics.insert(curr_values());
+            collect_system_metrics();
         });
     }
 
@@ -126,26 +156,8 @@ pub trait Metrics: Canister {
 fn curr_values() -> MetricsData {
     MetricsData {
         cycles: ic_exports::ic_kit::ic::balance(),
-        stable_memory_size: {
-            #[cfg(target_family = "wasm")]
-            {
-                ic_exports::ic_cdk::api::stable::stable64_size()
-            }
-            #[cfg(not(target_family = "wasm"))]
-            {
-                0
-            }
-        },
-        heap_memory_size: {
-            #[cfg(target_family = "wasm")]
-            {
-                (core::arch::wasm32::memory_size(0) as u64) * WASM_PAGE_SIZE
-            }
-            #[cfg(not(target_family = "wasm"))]
-            {
-                0
-            }
-        },
+        stable_memory_size: system::stable_memory_pages(),
+        heap_memory_size: system::heap_memory_bytes(),
     }
 }
 
@@ -0,0 +1,165 @@
+//! Profiling of the instructions executed by the named scopes of the code, e.g. the steps of an
+//! endpoint or of a task.
+//!
+//! The profiling is disabled by default and costs one check of a flag per scope when disabled,
+//! so the scopes can stay in the production code and the profiling can be enabled when needed:
+//!
+//! ```ignore
+//! #[update]
+//! fn swap(&self, args: SwapArgs) -> Result<Nat> {
+//!     profile!("swap");
+//!     let pool = profile!("swap::load_pool", { self.load_pool(&args)? });
+//!     ...
+//! }
+//! ```
+//!
+//! The scopes are inclusive: the instructions of a scope include the ones of the nested scopes.
+//! The instructions are counted within the current message, so a scope must not span an
+//! `await`.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use ic_exports::candid::{CandidType, Deserialize};
+use ic_exports::instructions::instruction_counter;
+
+/// The instructions executed by a scope over all its calls.
+#[derive(Debug, Default, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ScopeProfile {
+    pub name: String,
+    pub calls: u64,
+    pub total_instructions: u64,
+    pub max_instructions: u64,
+}
+
+#[derive(Default)]
+struct Profiler {
+    enabled: bool,
+    scopes: BTreeMap<&'static str, ScopeProfile>,
+}
+
+thread_local! {
+    static PROFILER: RefCell<Profiler> = RefCell::default();
+}
+
+/// Enable or disable the profiling. The collected profile is kept.
+pub fn set_profiling_enabled(enabled: bool) {
+    PROFILER.with(|profiler| profiler.borrow_mut().enabled = enabled);
+}
+
+pub fn is_profiling_enabled() -> bool {
+    PROFILER.with(|profiler| profiler.borrow().enabled)
+}
+
+/// Returns the profiles of the scopes, ordered by their names.
+pub fn profile_snapshot() -> Vec<ScopeProfile> {
+    PROFILER.with(|profiler| profiler.borrow().scopes.values().cloned().collect())
+}
+
+/// Remove the collected profile.
+pub fn reset_profile() {
+    PROFILER.with(|profiler| profiler.borrow_mut().scopes.clear());
+}
+
+/// Counts the instructions until it's dropped, see [`profile!`](crate::profile).
+#[must_use = "the instructions are counted until the scope is dropped"]
+pub struct ProfileScope {
+    name: &'static str,
+    /// The instruction counter at the start, `None` if the profiling is disabled.
+    start: Option<u64>,
+}
+
+impl ProfileScope {
+    pub fn new(name: &'static str) -> Self {
+        let start = is_profiling_enabled().then(instruction_counter);
+        Self { name, start }
+    }
+}
+
+impl Drop for ProfileScope {
+    fn drop(&mut self) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let instructions = instruction_counter().saturating_sub(start);
+        PROFILER.with(|profiler| {
+            let mut profiler = profiler.borrow_mut();
+            let scope = profiler
+                .scopes
+                .entry(self.name)
+                .or_insert_with(|| ScopeProfile {
+                    name: self.name.to_string(),
+                    ..Default::default()
+                });
+            scope.calls += 1;
+            scope.total_instructions += instructions;
+            scope.max_instructions = scope.max_instructions.max(instructions);
+        });
+    }
+}
+
+/// Profile the instructions of the rest of the current block, or of the given block.
+///
+/// ```
+/// use ic_metrics::profile;
+///
+/// fn transfer(amount: u64) -> u64 {
+///     profile!("transfer");
+///     let fee = profile!("transfer::fee", { amount / 100 });
+///     amount - fee
+/// }
+/// # assert_eq!(transfer(100), 99);
+/// ```
+#[macro_export]
+macro_rules! profile {
+    ($name:expr) => {
+        let _profile_scope = $crate::ProfileScope::new($name);
+    };
+    ($name:expr, $body:block) => {{
+        let _profile_scope = $crate::ProfileScope::new($name);
+        $body
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::instructions::burn_mock_instructions as burn;
+
+    use super::*;
+
+    fn handle(amount: u64) {
+        profile!("handle");
+        burn(10);
+        profile!("handle::validate", { burn(amount) });
+    }
+
+    #[test]
+    fn scopes_are_profiled_when_enabled() {
+        reset_profile();
+        handle(5);
+        assert!(profile_snapshot().is_empty());
+
+        set_profiling_enabled(true);
+        handle(5);
+        handle(20);
+        set_profiling_enabled(false);
+
+        assert_eq!(
+            profile_snapshot(),
+            [
+                ScopeProfile {
+                    name: "handle".to_string(),
+                    calls: 2,
+                    total_instructions: 45,
+                    max_instructions: 30,
+                },
+                ScopeProfile {
+                    name: "handle::validate".to_string(),
+                    calls: 2,
+                    total_instructions: 25,
+                    max_instructions: 20,
+                },
+            ]
+        );
+    }
+}
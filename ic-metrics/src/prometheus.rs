@@ -0,0 +1,257 @@
+//! Exposition of the [`MetricsRegistry`] in the Prometheus text format, served by the
+//! `http_request` query of the canister.
+
+use std::fmt::Write;
+
+use ic_exports::candid::{CandidType, Deserialize};
+use ic_storage::IcStorage;
+
+use crate::{HistogramValue, MetricKind, MetricValue, MetricsRegistry};
+
+/// The path of the metrics served by [`metrics_http_handler`].
+pub const METRICS_PATH: &str = "/metrics";
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// A request to the `http_request` query of the canister, as sent by the HTTP gateway.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+/// A response of the `http_request` query of the canister.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn text(status_code: u16, content_type: &str, body: String) -> Self {
+        Self {
+            status_code,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into_bytes(),
+        }
+    }
+}
+
+/// Serve the metrics of the canister for the requests to [`METRICS_PATH`], other requests are
+/// left to the caller.
+///
+/// If the `token` is set, the request must carry it either in the `Authorization: Bearer`
+/// header or in the `token` query parameter.
+///
+/// ```ignore
+/// #[query]
+/// fn http_request(&self, request: HttpRequest) -> HttpResponse {
+///     metrics_http_handler(&request, Some("secret")).unwrap_or_else(|| not_found())
+/// }
+/// ```
+pub fn metrics_http_handler(request: &HttpRequest, token: Option<&str>) -> Option<HttpResponse> {
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    if path != METRICS_PATH {
+        return None;
+    }
+
+    if !request.method.eq_ignore_ascii_case("GET") {
+        return Some(HttpResponse::text(
+            405,
+            "text/plain",
+            "Method not allowed".to_string(),
+        ));
+    }
+
+    if let Some(token) = token {
+        let bearer = request
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            .filter_map(|(_, value)| value.strip_prefix("Bearer "));
+        let param = query
+            .split('&')
+            .filter_map(|param| param.strip_prefix("token="));
+        if !bearer.chain(param).any(|value| value == token) {
+            return Some(HttpResponse::text(
+                401,
+                "text/plain",
+                "Unauthorized".to_string(),
+            ));
+        }
+    }
+
+    let body = render_prometheus(&MetricsRegistry::get().borrow());
+    Some(HttpResponse::text(200, CONTENT_TYPE, body))
+}
+
+/// Render the metrics in the Prometheus text format.
+pub fn render_prometheus(registry: &MetricsRegistry) -> String {
+    let mut out = String::new();
+    for (name, family) in registry.families() {
+        let kind = match family.kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        };
+        if !family.help.is_empty() {
+            let help = family.help.replace('\\', "\\\\").replace('\n', "\\n");
+            let _ = writeln!(out, "# HELP {name} {help}");
+        }
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+
+        for (labels, value) in &family.series {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            match value {
+                MetricValue::Counter(_) | MetricValue::Gauge(_) => {
+                    write_sample(&mut out, name, &labels, value.as_f64())
+                }
+                MetricValue::Histogram(histogram) => {
+                    write_histogram(&mut out, name, &labels, histogram)
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &[(&str, &str)], value: &HistogramValue) {
+    let bucket_name = format!("{name}_bucket");
+    let bounds = value
+        .buckets
+        .iter()
+        .map(|bound| format_value(*bound))
+        .chain(std::iter::once("+Inf".to_string()));
+    for (bound, count) in bounds.zip(value.cumulative_counts()) {
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", &bound));
+        write_sample(out, &bucket_name, &bucket_labels, count as f64);
+    }
+    write_sample(out, &format!("{name}_sum"), labels, value.sum);
+    write_sample(out, &format!("{name}_count"), labels, value.count as f64);
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (label, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            let _ = write!(out, "{label}=\"{value}\"");
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", format_value(value));
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(url: &str, headers: Vec<(&str, &str)>) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn render_text_format() {
+        let mut registry = MetricsRegistry::default();
+        registry
+            .register("calls_total", MetricKind::Counter, "Calls\nmade")
+            .unwrap();
+        registry
+            .increment("calls_total", &[("method", "a\"b")], 2)
+            .unwrap();
+        registry.register("balance", MetricKind::Gauge, "").unwrap();
+        registry.set_gauge("balance", &[], 1.5).unwrap();
+        registry
+            .register_histogram("latency", "Latency", vec![1.0, 2.0])
+            .unwrap();
+        registry.observe("latency", &[("m", "x")], 1.5).unwrap();
+        registry.observe("latency", &[("m", "x")], 5.0).unwrap();
+
+        let expected = "\
+# TYPE balance gauge
+balance 1.5
+# HELP calls_total Calls\\nmade
+# TYPE calls_total counter
+calls_total{method=\"a\\\"b\"} 2
+# HELP latency Latency
+# TYPE latency histogram
+latency_bucket{m=\"x\",le=\"1\"} 0
+latency_bucket{m=\"x\",le=\"2\"} 1
+latency_bucket{m=\"x\",le=\"+Inf\"} 2
+latency_sum{m=\"x\"} 6.5
+latency_count{m=\"x\"} 2
+";
+        assert_eq!(render_prometheus(&registry), expected);
+    }
+
+    #[test]
+    fn handler_checks_path_method_and_token() {
+        MetricsRegistry::get()
+            .borrow_mut()
+            .register("up", MetricKind::Gauge, "")
+            .unwrap();
+
+        assert_eq!(metrics_http_handler(&request("/other", vec![]), None), None);
+
+        let response = metrics_http_handler(&request("/metrics", vec![]), None).unwrap();
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"# TYPE up gauge\n");
+
+        let mut post = request("/metrics", vec![]);
+        post.method = "POST".to_string();
+        assert_eq!(metrics_http_handler(&post, None).unwrap().status_code, 405);
+
+        let token = Some("secret");
+        let status = |request| metrics_http_handler(&request, token).unwrap().status_code;
+        assert_eq!(status(request("/metrics", vec![])), 401);
+        assert_eq!(status(request("/metrics?token=wrong", vec![])), 401);
+        assert_eq!(status(request("/metrics?a=1&token=secret", vec![])), 200);
+        assert_eq!(
+            status(request(
+                "/metrics",
+                vec![("authorization", "Bearer secret")]
+            )),
+            200
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Periodic push of the [`MetricsRegistry`] to an external collector with HTTPS outcalls, for
+//! the observability stacks which can't scrape the canisters.
+
+use ic_exports::candid::Nat;
+use ic_exports::ic_kit::http::{
+    self, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_storage::IcStorage;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    render_prometheus, MetricKind, MetricValue, MetricsError, MetricsRegistry, MetricsResult,
+};
+
+/// The encoding of the pushed metrics.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PushFormat {
+    /// The Prometheus text format, as accepted by the Pushgateway.
+    PrometheusText,
+    /// The snappy-compressed protobuf of the Prometheus remote-write protocol.
+    RemoteWrite,
+    /// A JSON document with the timestamp and all the series of the registry.
+    Json,
+}
+
+impl PushFormat {
+    fn headers(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            PushFormat::PrometheusText => vec![("Content-Type", "text/plain; version=0.0.4")],
+            PushFormat::RemoteWrite => vec![
+                ("Content-Type", "application/x-protobuf"),
+                ("Content-Encoding", "snappy"),
+                ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+            ],
+            PushFormat::Json => vec![("Content-Type", "application/json")],
+        }
+    }
+}
+
+/// The collector to push the metrics to.
+///
+/// Every replica of the subnet makes the outcall, so the collector receives the same push
+/// several times and must tolerate it. The responses must be identical for the replicas to
+/// agree on them, which usually requires a transform function dropping the headers, e.g. one
+/// calling [`transform_push_response`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushConfig {
+    pub url: String,
+    pub format: PushFormat,
+    pub headers: Vec<(String, String)>,
+    pub cycles: u128,
+    pub max_response_bytes: u64,
+    pub transform: Option<TransformContext>,
+}
+
+impl PushConfig {
+    pub fn new(url: &str, format: PushFormat) -> Self {
+        Self {
+            url: url.to_string(),
+            format,
+            headers: vec![],
+            cycles: 1_000_000_000,
+            max_response_bytes: 4096,
+            transform: None,
+        }
+    }
+
+    /// Add a header to the requests, e.g. the authorization of the collector.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the cycles attached to every outcall.
+    pub fn with_cycles(mut self, cycles: u128) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    pub fn with_max_response_bytes(mut self, max_response_bytes: u64) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    pub fn with_transform(mut self, transform: TransformContext) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+}
+
+/// Keep only the status of the response of the collector, so the replicas agree on it.
+pub fn transform_push_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+/// Push the current metrics of the canister to the collector.
+pub async fn push_metrics(config: &PushConfig) -> MetricsResult<()> {
+    let body = encode_push_body(
+        &MetricsRegistry::get().borrow(),
+        config.format,
+        ic_exports::ic_kit::ic::time(),
+    );
+
+    let headers = config
+        .format
+        .headers()
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .chain(config.headers.iter().cloned())
+        .map(|(name, value)| HttpHeader { name, value })
+        .collect();
+    let request = CanisterHttpRequestArgument {
+        url: config.url.clone(),
+        max_response_bytes: Some(config.max_response_bytes),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(body),
+        transform: config.transform.clone(),
+    };
+
+    let (response,) =
+        http::http_request(request, config.cycles)
+            .await
+            .map_err(|(code, message)| {
+                MetricsError::PushFailed(format!("outcall rejected with {code:?}: {message}"))
+            })?;
+    if !(Nat::from(200u64)..Nat::from(300u64)).contains(&response.status) {
+        return Err(MetricsError::PushFailed(format!(
+            "collector responded with status {}",
+            response.status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Push the metrics now and then at the intervals. Failed pushes are not retried.
+#[cfg(target_family = "wasm")]
+pub fn start_metrics_push_timer(
+    config: PushConfig,
+    interval: std::time::Duration,
+) -> ic_exports::ic_cdk_timers::TimerId {
+    let push = move || {
+        let config = config.clone();
+        ic_exports::ic_cdk::spawn(async move {
+            if let Err(err) = push_metrics(&config).await {
+                ic_exports::ic_cdk::println!("{err}");
+            }
+        });
+    };
+    push();
+    ic_exports::ic_cdk_timers::set_timer_interval(interval, push)
+}
+
+/// Encode the metrics of the registry in the format.
+pub fn encode_push_body(
+    registry: &MetricsRegistry,
+    format: PushFormat,
+    timestamp_nanos: u64,
+) -> Vec<u8> {
+    match format {
+        PushFormat::PrometheusText => render_prometheus(registry).into_bytes(),
+        PushFormat::RemoteWrite => snappy_compress(&remote_write_request(
+            registry,
+            (timestamp_nanos / 1_000_000) as i64,
+        )),
+        PushFormat::Json => json_document(registry, timestamp_nanos)
+            .to_string()
+            .into_bytes(),
+    }
+}
+
+fn json_document(registry: &MetricsRegistry, timestamp_nanos: u64) -> Value {
+    let mut metrics = vec![];
+    for (name, family) in registry.families() {
+        for (labels, value) in &family.series {
+            let labels: Map<String, Value> = labels
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                .collect();
+            let mut metric = json!({
+                "name": name,
+                "kind": match family.kind {
+                    MetricKind::Counter => "counter",
+                    MetricKind::Gauge => "gauge",
+                    MetricKind::Histogram => "histogram",
+                },
+                "labels": labels,
+            });
+            match value {
+                MetricValue::Counter(value) => metric["value"] = json!(value),
+                MetricValue::Gauge(value) => metric["value"] = json!(value),
+                MetricValue::Histogram(histogram) => {
+                    metric["buckets"] = json!(histogram.buckets);
+                    metric["counts"] = json!(histogram.counts);
+                    metric["sum"] = json!(histogram.sum);
+                    metric["count"] = json!(histogram.count);
+                }
+            }
+            metrics.push(metric);
+        }
+    }
+
+    json!({
+        "timestamp_nanos": timestamp_nanos,
+        "metrics": metrics,
+    })
+}
+
+/// Encode the `WriteRequest` protobuf message of the remote-write protocol, with one sample
+/// per series. Histograms are sent as their `_bucket`, `_sum` and `_count` series.
+fn remote_write_request(registry: &MetricsRegistry, timestamp_millis: i64) -> Vec<u8> {
+    let mut request = vec![];
+    let mut write_series = |name: &str, labels: &[(String, String)], value: f64| {
+        let mut labels: Vec<(&str, &str)> = labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(std::iter::once(("__name__", name)))
+            .collect();
+        labels.sort();
+
+        let mut series = vec![];
+        for (name, value) in labels {
+            let mut label = vec![];
+            write_bytes_field(&mut label, 1, name.as_bytes());
+            write_bytes_field(&mut label, 2, value.as_bytes());
+            write_bytes_field(&mut series, 1, &label);
+        }
+        let mut sample = vec![];
+        write_key(&mut sample, 1, 1);
+        sample.extend_from_slice(&value.to_le_bytes());
+        write_key(&mut sample, 2, 0);
+        write_varint(&mut sample, timestamp_millis as u64);
+        write_bytes_field(&mut series, 2, &sample);
+
+        write_bytes_field(&mut request, 1, &series);
+    };
+
+    for (name, family) in registry.families() {
+        for (labels, value) in &family.series {
+            match value {
+                MetricValue::Counter(_) | MetricValue::Gauge(_) => {
+                    write_series(name, labels, value.as_f64())
+                }
+                MetricValue::Histogram(histogram) => {
+                    let bounds = histogram
+                        .buckets
+                        .iter()
+                        .map(|bound| bound.to_string())
+                        .chain(std::iter::once("+Inf".to_string()));
+                    for (bound, count) in bounds.zip(histogram.cumulative_counts()) {
+                        let mut bucket_labels = labels.clone();
+                        bucket_labels.push(("le".to_string(), bound));
+                        write_series(&format!("{name}_bucket"), &bucket_labels, count as f64);
+                    }
+                    write_series(&format!("{name}_sum"), labels, histogram.sum);
+                    write_series(&format!("{name}_count"), labels, histogram.count as f64);
+                }
+            }
+        }
+    }
+
+    request
+}
+
+fn write_key(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(out, (field << 3) | wire_type);
+}
+
+fn write_bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(out, field, 2);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Encode the data in the snappy block format without compressing it: the block consists of
+/// literals only, which any snappy decoder accepts.
+fn snappy_compress(data: &[u8]) -> Vec<u8> {
+    const MAX_LITERAL: usize = 1 << 16;
+
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_LITERAL * 3 + 8);
+    write_varint(&mut out, data.len() as u64);
+    for literal in data.chunks(MAX_LITERAL) {
+        let len = literal.len() - 1;
+        if len < 60 {
+            out.push((len as u8) << 2);
+        } else if len < 1 << 8 {
+            out.push(60 << 2);
+            out.push(len as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+        out.extend_from_slice(literal);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::MockContext;
+
+    use super::*;
+
+    fn response(status: u64) -> HttpResponse {
+        HttpResponse {
+            status: status.into(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn snappy_literals() {
+        assert_eq!(snappy_compress(b"abc"), [3, 2 << 2, b'a', b'b', b'c']);
+
+        let data = vec![7; 100];
+        let encoded = snappy_compress(&data);
+        assert_eq!(encoded[..3], [100, 60 << 2, 99]);
+        assert_eq!(encoded[3..], data);
+
+        let data = vec![7; 70_000];
+        let encoded = snappy_compress(&data);
+        assert_eq!(encoded.len(), 3 + 3 + 65536 + 3 + 4464);
+        assert_eq!(encoded[3..6], [61 << 2, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn remote_write_encoding() {
+        let mut registry = MetricsRegistry::default();
+        registry.register("up", MetricKind::Gauge, "").unwrap();
+        registry.set_gauge("up", &[], 1.0).unwrap();
+
+        let label = [&[0x0a, 8][..], &b"__name__"[..], &[0x12, 2][..], &b"up"[..]].concat();
+        let sample = [vec![0x09], 1.0f64.to_le_bytes().to_vec(), vec![0x10, 5]].concat();
+        let series = [
+            vec![0x0a, label.len() as u8],
+            label,
+            vec![0x12, sample.len() as u8],
+            sample,
+        ]
+        .concat();
+        let expected = [vec![0x0a, series.len() as u8], series].concat();
+        assert_eq!(remote_write_request(&registry, 5), expected);
+    }
+
+    #[tokio::test]
+    async fn push_to_collector() {
+        let ctx = MockContext::new()
+            .with_http_response("https://collector.com/ok", response(204))
+            .with_http_response("https://collector.com/fail", response(500))
+            .inject();
+        let watcher = ctx.watch();
+        {
+            let registry = MetricsRegistry::get();
+            let mut registry = registry.borrow_mut();
+            registry.register("calls", MetricKind::Counter, "").unwrap();
+            registry.increment("calls", &[("method", "a")], 2).unwrap();
+        }
+
+        let config = PushConfig::new("https://collector.com/ok", PushFormat::Json)
+            .with_header("Authorization", "Bearer secret");
+        push_metrics(&config).await.unwrap();
+
+        let request = &watcher.http_requests()[0];
+        assert_eq!(request.method, HttpMethod::POST);
+        assert!(request
+            .headers
+            .iter()
+            .any(|header| header.name == "Authorization" && header.value == "Bearer secret"));
+        let body: Value = serde_json::from_slice(request.body.as_ref().unwrap()).unwrap();
+        assert_eq!(
+            body["metrics"][0],
+            json!({"name": "calls", "kind": "counter", "labels": {"method": "a"}, "value": 2})
+        );
+
+        let config = PushConfig::new("https://collector.com/fail", PushFormat::PrometheusText);
+        assert!(matches!(
+            push_metrics(&config).await,
+            Err(MetricsError::PushFailed(_))
+        ));
+    }
+}
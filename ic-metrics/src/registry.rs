@@ -0,0 +1,688 @@
+use std::collections::BTreeMap;
+
+use ic_exports::candid::{CandidType, Deserialize};
+use ic_exports::instructions::instruction_counter;
+use ic_storage::IcStorage;
+use thiserror::Error;
+
+/// Sorted pairs of label names and values, identifying a series of a metric.
+pub type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum MetricsError {
+    #[error("invalid metric name: {0}")]
+    InvalidName(String),
+
+    #[error("invalid label name: {0}")]
+    InvalidLabel(String),
+
+    #[error("invalid histogram buckets: {0}")]
+    InvalidBuckets(String),
+
+    #[error("metric {0} is not registered")]
+    NotRegistered(String),
+
+    #[error("metric {name} is a {actual:?}, not a {expected:?}")]
+    KindMismatch {
+        name: String,
+        expected: MetricKind,
+        actual: MetricKind,
+    },
+
+    #[error("failed to push the metrics: {0}")]
+    PushFailed(String),
+}
+
+pub type MetricsResult<T> = Result<T, MetricsError>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub enum MetricKind {
+    /// A value which only goes up, e.g. the number of the processed requests.
+    Counter,
+    /// A value which goes up and down, e.g. the size of a queue.
+    Gauge,
+    /// The distribution of observed values, e.g. the latencies of the requests.
+    Histogram,
+}
+
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(f64),
+    Histogram(HistogramValue),
+}
+
+impl MetricValue {
+    fn new(kind: MetricKind, buckets: &[f64]) -> Self {
+        match kind {
+            MetricKind::Counter => MetricValue::Counter(0),
+            MetricKind::Gauge => MetricValue::Gauge(0.0),
+            MetricKind::Histogram => MetricValue::Histogram(HistogramValue::new(buckets.to_vec())),
+        }
+    }
+
+    pub fn kind(&self) -> MetricKind {
+        match self {
+            MetricValue::Counter(_) => MetricKind::Counter,
+            MetricValue::Gauge(_) => MetricKind::Gauge,
+            MetricValue::Histogram(_) => MetricKind::Histogram,
+        }
+    }
+
+    /// The value as a float, as it is exported. For a histogram it is the sum of the
+    /// observations.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            MetricValue::Counter(value) => *value as f64,
+            MetricValue::Gauge(value) => *value,
+            MetricValue::Histogram(histogram) => histogram.sum,
+        }
+    }
+}
+
+/// The upper bounds of the buckets used by the histograms registered without buckets.
+pub const DEFAULT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Return `count` upper bounds of buckets, starting from `start` and growing by `factor`.
+pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> Vec<f64> {
+    std::iter::successors(Some(start), |bound| Some(bound * factor))
+        .take(count)
+        .collect()
+}
+
+/// Return `count` upper bounds of buckets, starting from `start` and growing by `width`.
+pub fn linear_buckets(start: f64, width: f64, count: usize) -> Vec<f64> {
+    (0..count).map(|i| start + width * i as f64).collect()
+}
+
+/// The observations of a histogram, counted in buckets.
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct HistogramValue {
+    /// The increasing upper bounds of the buckets, the last bucket is unbounded.
+    pub buckets: Vec<f64>,
+    /// The number of the observations in every bucket, with one more for the last bucket.
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+/// The estimated percentiles of a histogram.
+#[derive(Debug, Clone, Copy, PartialEq, CandidType, Deserialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl HistogramValue {
+    pub fn new(buckets: Vec<f64>) -> Self {
+        let counts = vec![0; buckets.len() + 1];
+        Self {
+            buckets,
+            counts,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.buckets.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Return the numbers of the observations less or equal to the upper bounds of the
+    /// buckets, as they are exported.
+    pub fn cumulative_counts(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            })
+            .collect()
+    }
+
+    /// Estimate the value below which the given fraction of the observations fall, by linear
+    /// interpolation inside the bucket containing it. Values in the last, unbounded, bucket
+    /// are estimated as the largest bound. Returns `None` without observations.
+    pub fn percentile(&self, fraction: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = fraction.clamp(0.0, 1.0) * self.count as f64;
+        let mut below = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            if *count > 0 && (below + count) as f64 >= rank {
+                let Some(upper) = self.buckets.get(bucket) else {
+                    return self
+                        .buckets
+                        .last()
+                        .copied()
+                        .or(Some(self.sum / self.count as f64));
+                };
+                let lower = match bucket {
+                    0 => upper.min(0.0),
+                    _ => self.buckets[bucket - 1],
+                };
+                let position = (rank - below as f64) / *count as f64;
+                return Some(lower + (upper - lower) * position);
+            }
+            below += count;
+        }
+
+        self.buckets.last().copied()
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        Some(Percentiles {
+            p50: self.percentile(0.5)?,
+            p95: self.percentile(0.95)?,
+            p99: self.percentile(0.99)?,
+        })
+    }
+}
+
+/// A named metric with all of its labeled series.
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct MetricFamily {
+    pub kind: MetricKind,
+    pub help: String,
+    /// The buckets of the series of a histogram, empty for other kinds.
+    pub buckets: Vec<f64>,
+    pub series: BTreeMap<Labels, MetricValue>,
+}
+
+/// Metrics registered by the canister at runtime, in addition to the [`crate::MetricsData`]
+/// snapshots.
+///
+/// The registry of the canister is available from any module with `MetricsRegistry::get()`, or
+/// through the [`Counter`] and [`Gauge`] handles.
+///
+/// ```
+/// use ic_metrics::*;
+///
+/// let requests = Counter::register("requests_total", "Processed requests").unwrap();
+/// requests.inc_with(&[("method", "transfer")]);
+/// requests.inc_with(&[("method", "transfer")]);
+///
+/// let queue = Gauge::register("queue_size", "Pending tasks").unwrap();
+/// queue.set(3.0);
+///
+/// assert_eq!(requests.get_with(&[("method", "transfer")]), 2);
+/// assert_eq!(queue.get(), 3.0);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, CandidType, Deserialize, IcStorage)]
+pub struct MetricsRegistry {
+    families: BTreeMap<String, MetricFamily>,
+}
+
+impl MetricsRegistry {
+    /// Register a metric. Registering a metric again with the same kind does nothing.
+    ///
+    /// Histograms are registered with the [`DEFAULT_BUCKETS`].
+    pub fn register(&mut self, name: &str, kind: MetricKind, help: &str) -> MetricsResult<()> {
+        let buckets = match kind {
+            MetricKind::Histogram => DEFAULT_BUCKETS.to_vec(),
+            MetricKind::Counter | MetricKind::Gauge => vec![],
+        };
+        self.register_family(name, kind, help, buckets)
+    }
+
+    /// Register a histogram with the given upper bounds of the buckets, which must be
+    /// increasing. Registering a histogram again does nothing.
+    pub fn register_histogram(
+        &mut self,
+        name: &str,
+        help: &str,
+        buckets: Vec<f64>,
+    ) -> MetricsResult<()> {
+        if buckets.is_empty()
+            || buckets.iter().any(|bound| !bound.is_finite())
+            || buckets.windows(2).any(|pair| pair[0] >= pair[1])
+        {
+            return Err(MetricsError::InvalidBuckets(format!("{buckets:?}")));
+        }
+
+        self.register_family(name, MetricKind::Histogram, help, buckets)
+    }
+
+    fn register_family(
+        &mut self,
+        name: &str,
+        kind: MetricKind,
+        help: &str,
+        buckets: Vec<f64>,
+    ) -> MetricsResult<()> {
+        validate_name(name).map_err(|_| MetricsError::InvalidName(name.to_string()))?;
+        match self.families.get(name) {
+            Some(family) if family.kind != kind => Err(MetricsError::KindMismatch {
+                name: name.to_string(),
+                expected: kind,
+                actual: family.kind,
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.families.insert(
+                    name.to_string(),
+                    MetricFamily {
+                        kind,
+                        help: help.to_string(),
+                        buckets,
+                        series: BTreeMap::new(),
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove the metric with all of its series.
+    pub fn unregister(&mut self, name: &str) -> Option<MetricFamily> {
+        self.families.remove(name)
+    }
+
+    /// Increase the counter.
+    pub fn increment(&mut self, name: &str, labels: &[(&str, &str)], by: u64) -> MetricsResult<()> {
+        match self.series_mut(name, labels, MetricKind::Counter)? {
+            MetricValue::Counter(value) => *value = value.saturating_add(by),
+            _ => unreachable!("the kind of the series is checked"),
+        }
+        Ok(())
+    }
+
+    /// Add the observation to the histogram.
+    pub fn observe(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) -> MetricsResult<()> {
+        match self.series_mut(name, labels, MetricKind::Histogram)? {
+            MetricValue::Histogram(histogram) => histogram.observe(value),
+            _ => unreachable!("the kind of the series is checked"),
+        }
+        Ok(())
+    }
+
+    /// Set the value of the gauge.
+    pub fn set_gauge(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) -> MetricsResult<()> {
+        *self.gauge_mut(name, labels)? = value;
+        Ok(())
+    }
+
+    /// Add the delta, which can be negative, to the value of the gauge.
+    pub fn add_gauge(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        delta: f64,
+    ) -> MetricsResult<()> {
+        *self.gauge_mut(name, labels)? += delta;
+        Ok(())
+    }
+
+    /// Return the value of the series of the metric.
+    pub fn value(&self, name: &str, labels: &[(&str, &str)]) -> Option<&MetricValue> {
+        self.families
+            .get(name)?
+            .series
+            .get(&to_labels(labels).ok()?)
+    }
+
+    pub fn family(&self, name: &str) -> Option<&MetricFamily> {
+        self.families.get(name)
+    }
+
+    /// Return the metrics ordered by their names.
+    pub fn families(&self) -> impl Iterator<Item = (&String, &MetricFamily)> {
+        self.families.iter()
+    }
+
+    fn gauge_mut(&mut self, name: &str, labels: &[(&str, &str)]) -> MetricsResult<&mut f64> {
+        match self.series_mut(name, labels, MetricKind::Gauge)? {
+            MetricValue::Gauge(value) => Ok(value),
+            _ => unreachable!("the kind of the series is checked"),
+        }
+    }
+
+    fn series_mut(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        kind: MetricKind,
+    ) -> MetricsResult<&mut MetricValue> {
+        let family = self
+            .families
+            .get_mut(name)
+            .ok_or_else(|| MetricsError::NotRegistered(name.to_string()))?;
+        if family.kind != kind {
+            return Err(MetricsError::KindMismatch {
+                name: name.to_string(),
+                expected: kind,
+                actual: family.kind,
+            });
+        }
+
+        let buckets = &family.buckets;
+        Ok(family
+            .series
+            .entry(to_labels(labels)?)
+            .or_insert_with(|| MetricValue::new(kind, buckets)))
+    }
+}
+
+/// A handle to a counter in the registry of the canister.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counter {
+    name: String,
+}
+
+impl Counter {
+    /// Register the counter in the registry of the canister and return a handle to it.
+    pub fn register(name: &str, help: &str) -> MetricsResult<Self> {
+        MetricsRegistry::get()
+            .borrow_mut()
+            .register(name, MetricKind::Counter, help)?;
+        Ok(Self {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inc(&self) {
+        self.inc_by_with(1, &[]);
+    }
+
+    pub fn inc_by(&self, by: u64) {
+        self.inc_by_with(by, &[]);
+    }
+
+    pub fn inc_with(&self, labels: &[(&str, &str)]) {
+        self.inc_by_with(1, labels);
+    }
+
+    /// Increase the series with the given labels. Invalid labels are ignored.
+    pub fn inc_by_with(&self, by: u64, labels: &[(&str, &str)]) {
+        let _ = MetricsRegistry::get()
+            .borrow_mut()
+            .increment(&self.name, labels, by);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.get_with(&[])
+    }
+
+    pub fn get_with(&self, labels: &[(&str, &str)]) -> u64 {
+        match MetricsRegistry::get().borrow().value(&self.name, labels) {
+            Some(MetricValue::Counter(value)) => *value,
+            _ => 0,
+        }
+    }
+}
+
+/// A handle to a gauge in the registry of the canister.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gauge {
+    name: String,
+}
+
+impl Gauge {
+    /// Register the gauge in the registry of the canister and return a handle to it.
+    pub fn register(name: &str, help: &str) -> MetricsResult<Self> {
+        MetricsRegistry::get()
+            .borrow_mut()
+            .register(name, MetricKind::Gauge, help)?;
+        Ok(Self {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set(&self, value: f64) {
+        self.set_with(value, &[]);
+    }
+
+    /// Set the series with the given labels. Invalid labels are ignored.
+    pub fn set_with(&self, value: f64, labels: &[(&str, &str)]) {
+        let _ = MetricsRegistry::get()
+            .borrow_mut()
+            .set_gauge(&self.name, labels, value);
+    }
+
+    pub fn add(&self, delta: f64) {
+        self.add_with(delta, &[]);
+    }
+
+    /// Add to the series with the given labels. Invalid labels are ignored.
+    pub fn add_with(&self, delta: f64, labels: &[(&str, &str)]) {
+        let _ = MetricsRegistry::get()
+            .borrow_mut()
+            .add_gauge(&self.name, labels, delta);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.get_with(&[])
+    }
+
+    pub fn get_with(&self, labels: &[(&str, &str)]) -> f64 {
+        MetricsRegistry::get()
+            .borrow()
+            .value(&self.name, labels)
+            .map_or(0.0, MetricValue::as_f64)
+    }
+}
+
+/// A handle to a histogram in the registry of the canister.
+///
+/// ```
+/// use ic_metrics::*;
+///
+/// let latency = Histogram::register("latency_ms", "Latency", vec![100.0, 200.0, 500.0]).unwrap();
+/// for value in [50.0, 150.0, 150.0, 300.0] {
+///     latency.observe(value);
+/// }
+///
+/// let percentiles = latency.percentiles().unwrap();
+/// assert_eq!(percentiles.p50, 150.0);
+/// assert!(percentiles.p99 > 480.0 && percentiles.p99 < 500.0);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    name: String,
+}
+
+impl Histogram {
+    /// Register the histogram in the registry of the canister and return a handle to it.
+    pub fn register(name: &str, help: &str, buckets: Vec<f64>) -> MetricsResult<Self> {
+        MetricsRegistry::get()
+            .borrow_mut()
+            .register_histogram(name, help, buckets)?;
+        Ok(Self {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn observe(&self, value: f64) {
+        self.observe_with(value, &[]);
+    }
+
+    /// Add the observation to the series with the given labels. Invalid labels are ignored.
+    pub fn observe_with(&self, value: f64, labels: &[(&str, &str)]) {
+        let _ = MetricsRegistry::get()
+            .borrow_mut()
+            .observe(&self.name, labels, value);
+    }
+
+    /// Run the closure and observe the number of the instructions it executed, see
+    /// [`instruction_counter`]. The count is zero outside of a canister, unless the tests burn
+    /// the mock instructions.
+    pub fn observe_instructions<R>(&self, labels: &[(&str, &str)], f: impl FnOnce() -> R) -> R {
+        let start = instruction_counter();
+        let result = f();
+        self.observe_with(instruction_counter().saturating_sub(start) as f64, labels);
+        result
+    }
+
+    /// Return the observations of the series with the given labels.
+    pub fn get_with(&self, labels: &[(&str, &str)]) -> Option<HistogramValue> {
+        match MetricsRegistry::get().borrow().value(&self.name, labels) {
+            Some(MetricValue::Histogram(histogram)) => Some(histogram.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        self.percentiles_with(&[])
+    }
+
+    pub fn percentiles_with(&self, labels: &[(&str, &str)]) -> Option<Percentiles> {
+        self.get_with(labels)?.percentiles()
+    }
+}
+
+/// Names of the metrics and the labels follow the Prometheus rules, so they can be exported
+/// unchanged.
+fn validate_name(name: &str) -> Result<(), ()> {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {}
+        _ => return Err(()),
+    }
+    if chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+fn to_labels(labels: &[(&str, &str)]) -> MetricsResult<Labels> {
+    let mut result = Labels::with_capacity(labels.len());
+    for (name, value) in labels {
+        if validate_name(name).is_err() || name.contains(':') {
+            return Err(MetricsError::InvalidLabel(name.to_string()));
+        }
+        result.push((name.to_string(), value.to_string()));
+    }
+    result.sort();
+    result.dedup_by(|a, b| a.0 == b.0);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_and_gauges() {
+        let mut registry = MetricsRegistry::default();
+        registry
+            .register("calls_total", MetricKind::Counter, "Calls")
+            .unwrap();
+        registry
+            .register("balance", MetricKind::Gauge, "Balance")
+            .unwrap();
+
+        registry
+            .increment("calls_total", &[("method", "a"), ("caller", "x")], 2)
+            .unwrap();
+        registry
+            .increment("calls_total", &[("caller", "x"), ("method", "a")], 1)
+            .unwrap();
+        registry.add_gauge("balance", &[], -1.5).unwrap();
+
+        assert_eq!(
+            registry.value("calls_total", &[("method", "a"), ("caller", "x")]),
+            Some(&MetricValue::Counter(3))
+        );
+        assert_eq!(
+            registry.value("balance", &[]),
+            Some(&MetricValue::Gauge(-1.5))
+        );
+        assert_eq!(registry.family("calls_total").unwrap().series.len(), 1);
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut registry = MetricsRegistry::default();
+        registry
+            .register_histogram("instructions", "", linear_buckets(10.0, 10.0, 10))
+            .unwrap();
+        for value in 1..=100 {
+            registry.observe("instructions", &[], value as f64).unwrap();
+        }
+        registry.observe("instructions", &[], 1000.0).unwrap();
+
+        let Some(MetricValue::Histogram(histogram)) = registry.value("instructions", &[]) else {
+            panic!("histogram is not found");
+        };
+        assert_eq!(histogram.count, 101);
+        assert_eq!(histogram.counts[0], 10);
+        assert_eq!(histogram.counts[10], 1);
+        assert_eq!(histogram.cumulative_counts()[9], 100);
+
+        let percentiles = histogram.percentiles().unwrap();
+        assert_eq!(percentiles.p50, 50.5);
+        assert!((95.0..=96.0).contains(&percentiles.p95));
+        assert_eq!(histogram.percentile(1.0), Some(100.0));
+        assert_eq!(HistogramValue::new(vec![1.0]).percentile(0.5), None);
+
+        assert!(matches!(
+            registry.register_histogram("empty", "", vec![]),
+            Err(MetricsError::InvalidBuckets(_))
+        ));
+        assert!(matches!(
+            registry.register_histogram("unordered", "", vec![2.0, 1.0]),
+            Err(MetricsError::InvalidBuckets(_))
+        ));
+        assert_eq!(exponential_buckets(1.0, 2.0, 4), vec![1.0, 2.0, 4.0, 8.0]);
+    }
+
+    #[test]
+    fn registration_errors() {
+        let mut registry = MetricsRegistry::default();
+        registry.register("up", MetricKind::Gauge, "").unwrap();
+        registry.register("up", MetricKind::Gauge, "").unwrap();
+
+        assert!(matches!(
+            registry.register("up", MetricKind::Counter, ""),
+            Err(MetricsError::KindMismatch { .. })
+        ));
+        assert!(matches!(
+            registry.increment("up", &[], 1),
+            Err(MetricsError::KindMismatch { .. })
+        ));
+        assert_eq!(
+            registry.register("1up", MetricKind::Gauge, ""),
+            Err(MetricsError::InvalidName("1up".to_string()))
+        );
+        assert_eq!(
+            registry.set_gauge("down", &[], 1.0),
+            Err(MetricsError::NotRegistered("down".to_string()))
+        );
+        assert_eq!(
+            registry.set_gauge("up", &[("a-b", "")], 1.0),
+            Err(MetricsError::InvalidLabel("a-b".to_string()))
+        );
+    }
+}
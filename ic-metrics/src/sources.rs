@@ -0,0 +1,232 @@
+//! Metrics of the other subsystems of the canister, collected into the [`MetricsRegistry`]
+//! together with the system metrics.
+
+use std::cell::RefCell;
+
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::CacheStats;
+
+use crate::{MetricKind, MetricValue, MetricsRegistry, MetricsResult};
+
+pub const CACHE_HITS: &str = "stable_structure_cache_hits_total";
+pub const CACHE_MISSES: &str = "stable_structure_cache_misses_total";
+pub const CACHE_ITEMS: &str = "stable_structure_cache_items";
+pub const MEMORY_PAGES: &str = "stable_structure_memory_pages";
+pub const SCHEDULER_RUNS: &str = "scheduler_runs_total";
+pub const SCHEDULER_TASKS: &str = "scheduler_tasks_total";
+pub const SCHEDULER_PENDING_TASKS: &str = "scheduler_pending_tasks";
+pub const SPAN_INSTRUCTIONS: &str = "span_instructions";
+
+type MetricsSource = Box<dyn Fn(&mut MetricsRegistry) -> MetricsResult<()>>;
+
+thread_local! {
+    static SOURCES: RefCell<Vec<MetricsSource>> = RefCell::new(vec![]);
+}
+
+/// Add a source of metrics, which is called with the registry of the canister whenever the
+/// system metrics are collected, e.g. by [`crate::collect_system_metrics`].
+///
+/// The source must not add other sources.
+pub fn add_metrics_source(source: impl Fn(&mut MetricsRegistry) -> MetricsResult<()> + 'static) {
+    SOURCES.with(|sources| sources.borrow_mut().push(Box::new(source)));
+}
+
+/// Collect the metrics of all the sources into the registry. The errors of a source don't
+/// prevent the other sources from being collected.
+pub(crate) fn collect_sources(registry: &mut MetricsRegistry) {
+    SOURCES.with(|sources| {
+        for source in sources.borrow().iter() {
+            let _ = source(registry);
+        }
+    });
+}
+
+/// Collect the statistics of the cache of a structure, e.g. of a `CachedStableBTreeMap`:
+///
+/// ```ignore
+/// register_cache_metrics("balances", || BALANCES.with(|map| map.borrow().cache_stats()));
+/// ```
+pub fn register_cache_metrics(structure: &str, stats: impl Fn() -> CacheStats + 'static) {
+    let structure = structure.to_string();
+    add_metrics_source(move |registry| {
+        registry.register(
+            CACHE_HITS,
+            MetricKind::Counter,
+            "Lookups served by the caches",
+        )?;
+        registry.register(
+            CACHE_MISSES,
+            MetricKind::Counter,
+            "Lookups missing the caches",
+        )?;
+        registry.register(CACHE_ITEMS, MetricKind::Gauge, "Items in the caches")?;
+
+        let stats = stats();
+        let labels = [("structure", structure.as_str())];
+        set_counter(registry, CACHE_HITS, &labels, stats.hits)?;
+        set_counter(registry, CACHE_MISSES, &labels, stats.misses)?;
+        registry.set_gauge(CACHE_ITEMS, &labels, stats.len as f64)
+    });
+}
+
+/// Collect the size of the memory of a structure, e.g. a virtual memory of the memory manager
+/// of the canister.
+pub fn register_memory_metrics(structure: &str, memory: impl Memory + 'static) {
+    let structure = structure.to_string();
+    add_metrics_source(move |registry| {
+        registry.register(
+            MEMORY_PAGES,
+            MetricKind::Gauge,
+            "Size of the memories of the structures in 64KiB pages",
+        )?;
+        registry.set_gauge(
+            MEMORY_PAGES,
+            &[("structure", structure.as_str())],
+            memory.size() as f64,
+        )
+    });
+}
+
+/// Collect the counters of the scheduler and the number of its pending tasks.
+#[cfg(feature = "scheduler")]
+pub fn register_scheduler_metrics<T, P>(
+    name: &str,
+    scheduler: &ic_task_scheduler::scheduler::Scheduler<T, P>,
+) where
+    T: 'static + ic_task_scheduler::task::Task,
+    P: 'static
+        + ic_stable_structures::IterableUnboundedMapStructure<
+            u32,
+            ic_task_scheduler::task::InnerScheduledTask<T>,
+        >,
+{
+    let name = name.to_string();
+    let scheduler = scheduler.clone();
+    add_metrics_source(move |registry| {
+        registry.register(
+            SCHEDULER_RUNS,
+            MetricKind::Counter,
+            "Runs of the schedulers",
+        )?;
+        registry.register(
+            SCHEDULER_TASKS,
+            MetricKind::Counter,
+            "Tasks of the schedulers, by the event",
+        )?;
+        registry.register(
+            SCHEDULER_PENDING_TASKS,
+            MetricKind::Gauge,
+            "Tasks waiting, scheduled or running",
+        )?;
+
+        let stats = scheduler.stats();
+        let scheduler = name.as_str();
+        set_counter(
+            registry,
+            SCHEDULER_RUNS,
+            &[("scheduler", scheduler)],
+            stats.runs,
+        )?;
+        for (event, total) in [
+            ("appended", stats.tasks_appended),
+            ("scheduled", stats.tasks_scheduled),
+            ("completed", stats.tasks_completed),
+            ("failed", stats.tasks_failed),
+            ("retried", stats.tasks_retried),
+            ("timed_out", stats.tasks_timed_out),
+        ] {
+            let labels = [("scheduler", scheduler), ("event", event)];
+            set_counter(registry, SCHEDULER_TASKS, &labels, total)?;
+        }
+        registry.set_gauge(
+            SCHEDULER_PENDING_TASKS,
+            &[("scheduler", scheduler)],
+            stats.pending_tasks as f64,
+        )
+    });
+}
+
+/// Observe the instructions of the spans of the logger in a histogram labeled by the paths
+/// of the spans. Unlike the other sources, the spans are observed as they exit.
+#[cfg(feature = "spans")]
+pub fn register_span_metrics() {
+    use ic_storage::IcStorage;
+
+    ic_log::span::add_span_observer(|span| {
+        let registry = MetricsRegistry::get();
+        let mut registry = registry.borrow_mut();
+        let _ = registry
+            .register_histogram(
+                SPAN_INSTRUCTIONS,
+                "Instructions executed by the spans",
+                crate::exponential_buckets(10_000.0, 10.0, 8),
+            )
+            .and_then(|_| {
+                registry.observe(
+                    SPAN_INSTRUCTIONS,
+                    &[("span", span.path.as_str())],
+                    span.instructions as f64,
+                )
+            });
+    });
+}
+
+/// Bring the counter up to the total counted by the source. A total below the counter means
+/// the source was reset, e.g. by an upgrade, and is added to the counter.
+fn set_counter(
+    registry: &mut MetricsRegistry,
+    name: &str,
+    labels: &[(&str, &str)],
+    total: u64,
+) -> MetricsResult<()> {
+    let current = match registry.value(name, labels) {
+        Some(MetricValue::Counter(value)) => *value,
+        _ => 0,
+    };
+    registry.increment(name, labels, total.checked_sub(current).unwrap_or(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::{
+        default_ic_memory_manager, BTreeMapStructure, CachedStableBTreeMap, MemoryId,
+    };
+
+    use super::*;
+
+    #[test]
+    fn sources_are_collected() {
+        let memory_manager = default_ic_memory_manager();
+        let memory_id = MemoryId::new(3);
+        let mut map = CachedStableBTreeMap::<u64, u64, _>::new(memory_manager.get(memory_id), 10);
+        map.insert(1, 1);
+        map.get(&1);
+        map.get(&1);
+        let stats = map.cache_stats();
+
+        register_cache_metrics("balances", move || stats);
+        register_memory_metrics("balances", memory_manager.get(memory_id));
+
+        let mut registry = MetricsRegistry::default();
+        collect_sources(&mut registry);
+        collect_sources(&mut registry);
+
+        let labels = [("structure", "balances")];
+        assert_eq!(
+            registry.value(CACHE_HITS, &labels),
+            Some(&MetricValue::Counter(1))
+        );
+        assert_eq!(
+            registry.value(CACHE_MISSES, &labels),
+            Some(&MetricValue::Counter(1))
+        );
+        assert_eq!(
+            registry.value(CACHE_ITEMS, &labels),
+            Some(&MetricValue::Gauge(1.0))
+        );
+        assert!(matches!(
+            registry.value(MEMORY_PAGES, &labels),
+            Some(MetricValue::Gauge(pages)) if *pages >= 1.0
+        ));
+    }
+}
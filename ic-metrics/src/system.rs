@@ -0,0 +1,185 @@
+//! Baseline metrics of the canister resources, collected into the [`MetricsRegistry`].
+
+use candid::Principal;
+use ic_exports::instructions::instruction_counter;
+use ic_storage::IcStorage;
+
+use crate::sources::collect_sources;
+use crate::{exponential_buckets, MetricKind, MetricsRegistry, MetricsResult};
+
+pub const HEAP_BYTES: &str = "canister_heap_memory_bytes";
+pub const STABLE_MEMORY_PAGES: &str = "canister_stable_memory_pages";
+pub const CYCLES_BALANCE: &str = "canister_cycles_balance";
+pub const MESSAGE_INSTRUCTIONS: &str = "canister_message_instructions";
+pub const OUTGOING_CALLS: &str = "canister_outgoing_calls_total";
+
+#[cfg(target_family = "wasm")]
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// The kind of the message executed by the canister.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageClass {
+    Update,
+    Query,
+    Heartbeat,
+    Timer,
+    Init,
+    Upgrade,
+}
+
+impl MessageClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageClass::Update => "update",
+            MessageClass::Query => "query",
+            MessageClass::Heartbeat => "heartbeat",
+            MessageClass::Timer => "timer",
+            MessageClass::Init => "init",
+            MessageClass::Upgrade => "upgrade",
+        }
+    }
+}
+
+/// Register the system metrics in the registry. Registering them again does nothing.
+pub fn register_system_metrics(registry: &mut MetricsRegistry) -> MetricsResult<()> {
+    registry.register(
+        HEAP_BYTES,
+        MetricKind::Gauge,
+        "Size of the heap memory in bytes",
+    )?;
+    registry.register(
+        STABLE_MEMORY_PAGES,
+        MetricKind::Gauge,
+        "Size of the stable memory in 64KiB pages",
+    )?;
+    registry.register(CYCLES_BALANCE, MetricKind::Gauge, "Cycles balance")?;
+    registry.register_histogram(
+        MESSAGE_INSTRUCTIONS,
+        "Instructions executed by the messages, by the class of the message",
+        exponential_buckets(100_000.0, 4.0, 10),
+    )?;
+    registry.register(
+        OUTGOING_CALLS,
+        MetricKind::Counter,
+        "Calls made to other canisters, by the callee and the method",
+    )
+}
+
+/// Update the gauges of the memory and the cycles in the registry of the canister, and collect
+/// the metrics of the sources added with [`crate::add_metrics_source`].
+pub fn collect_system_metrics() {
+    let registry = MetricsRegistry::get();
+    let mut registry = registry.borrow_mut();
+    if register_system_metrics(&mut registry).is_err() {
+        return;
+    }
+
+    let _ = registry.set_gauge(HEAP_BYTES, &[], heap_memory_bytes() as f64);
+    let _ = registry.set_gauge(STABLE_MEMORY_PAGES, &[], stable_memory_pages() as f64);
+    let _ = registry.set_gauge(
+        CYCLES_BALANCE,
+        &[],
+        ic_exports::ic_kit::ic::balance() as f64,
+    );
+
+    collect_sources(&mut registry);
+}
+
+/// Observe the instructions executed by the current call so far, see [`instruction_counter`].
+/// Should be called at the end of the message.
+pub fn record_message_instructions(class: MessageClass) {
+    let registry = MetricsRegistry::get();
+    let mut registry = registry.borrow_mut();
+    if register_system_metrics(&mut registry).is_ok() {
+        let _ = registry.observe(
+            MESSAGE_INSTRUCTIONS,
+            &[("class", class.as_str())],
+            instruction_counter() as f64,
+        );
+    }
+}
+
+/// Count a call made to another canister.
+pub fn record_outgoing_call(canister: Principal, method: &str) {
+    let registry = MetricsRegistry::get();
+    let mut registry = registry.borrow_mut();
+    if register_system_metrics(&mut registry).is_ok() {
+        let _ = registry.increment(
+            OUTGOING_CALLS,
+            &[("canister", &canister.to_text()), ("method", method)],
+            1,
+        );
+    }
+}
+
+/// Collect the system metrics now and then at the intervals.
+#[cfg(target_family = "wasm")]
+pub fn start_system_metrics_timer(
+    interval: std::time::Duration,
+) -> ic_exports::ic_cdk_timers::TimerId {
+    collect_system_metrics();
+    ic_exports::ic_cdk_timers::set_timer_interval(interval, collect_system_metrics)
+}
+
+pub(crate) fn heap_memory_bytes() -> u64 {
+    #[cfg(target_family = "wasm")]
+    {
+        (core::arch::wasm32::memory_size(0) as u64) * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        0
+    }
+}
+
+pub(crate) fn stable_memory_pages() -> u64 {
+    #[cfg(target_family = "wasm")]
+    {
+        ic_exports::ic_cdk::api::stable::stable64_size()
+    }
+    #[cfg(not(target_family = "wasm"))]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::ic_kit::{mock_principals, MockContext};
+
+    use super::*;
+    use crate::MetricValue;
+
+    #[test]
+    fn system_metrics_are_collected() {
+        MockContext::new().with_balance(1_000).inject();
+
+        collect_system_metrics();
+        record_message_instructions(MessageClass::Update);
+        record_outgoing_call(mock_principals::alice(), "transfer");
+        record_outgoing_call(mock_principals::alice(), "transfer");
+
+        let registry = MetricsRegistry::get();
+        let registry = registry.borrow();
+        assert_eq!(
+            registry.value(CYCLES_BALANCE, &[]),
+            Some(&MetricValue::Gauge(1_000.0))
+        );
+        assert_eq!(
+            registry.value(HEAP_BYTES, &[]),
+            Some(&MetricValue::Gauge(0.0))
+        );
+        assert!(matches!(
+            registry.value(MESSAGE_INSTRUCTIONS, &[("class", "update")]),
+            Some(MetricValue::Histogram(histogram)) if histogram.count == 1
+        ));
+        let alice = mock_principals::alice().to_text();
+        assert_eq!(
+            registry.value(
+                OUTGOING_CALLS,
+                &[("canister", &alice), ("method", "transfer")]
+            ),
+            Some(&MetricValue::Counter(2))
+        );
+    }
+}
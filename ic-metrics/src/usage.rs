@@ -0,0 +1,405 @@
+//! Accounting of the usage of the canister by the callers and the endpoints in stable memory,
+//! e.g. for usage-based billing or finding the abusive callers of a multi-tenant canister.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use candid::Principal;
+use ic_exports::candid::{CandidType, Deserialize};
+use ic_exports::instructions::instruction_counter;
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, IterableSortedMapStructure, StableBTreeMap, Storable,
+};
+
+use crate::Interval;
+
+/// The max length of the endpoint names in bytes, longer names are truncated.
+pub const MAX_ENDPOINT_LEN: usize = 64;
+
+const MAX_PRINCIPAL_LEN: u32 = 29;
+
+const PERIOD_TIER: u8 = 0;
+const TOTAL_TIER: u8 = 1;
+
+/// The usage summed over some calls.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct UsageStats {
+    pub calls: u64,
+    pub instructions: u64,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+}
+
+impl UsageStats {
+    /// The usage of one call.
+    pub fn call(instructions: u64, request_bytes: u64, response_bytes: u64) -> Self {
+        Self {
+            calls: 1,
+            instructions,
+            request_bytes,
+            response_bytes,
+        }
+    }
+
+    pub fn add(&mut self, other: &UsageStats) {
+        self.calls += other.calls;
+        self.instructions += other.instructions;
+        self.request_bytes += other.request_bytes;
+        self.response_bytes += other.response_bytes;
+    }
+}
+
+impl Storable for UsageStats {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(32);
+        for value in [
+            self.calls,
+            self.instructions,
+            self.request_bytes,
+            self.response_bytes,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let value = |i: usize| {
+            u64::from_le_bytes(
+                bytes[i * 8..(i + 1) * 8]
+                    .try_into()
+                    .expect("stats are 32 bytes"),
+            )
+        };
+        Self {
+            calls: value(0),
+            instructions: value(1),
+            request_bytes: value(2),
+            response_bytes: value(3),
+        }
+    }
+}
+
+/// The usage of an endpoint by a caller within a period.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct UsageRecord {
+    /// The start of the period.
+    pub period_nanos: u64,
+    pub caller: Principal,
+    pub endpoint: String,
+    pub stats: UsageStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct UsageKey {
+    tier: u8,
+    period_nanos: u64,
+    caller: Principal,
+    endpoint: String,
+}
+
+impl UsageKey {
+    fn first_of_period(tier: u8, period_nanos: u64) -> Self {
+        Self {
+            tier,
+            period_nanos,
+            caller: Principal::management_canister(),
+            endpoint: String::new(),
+        }
+    }
+}
+
+impl Storable for UsageKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1 + 8 + 1 + MAX_PRINCIPAL_LEN + MAX_ENDPOINT_LEN as u32,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let caller = self.caller.as_slice();
+        let mut bytes = Vec::with_capacity(10 + caller.len() + self.endpoint.len());
+        bytes.push(self.tier);
+        bytes.extend_from_slice(&self.period_nanos.to_be_bytes());
+        bytes.push(caller.len() as u8);
+        bytes.extend_from_slice(caller);
+        bytes.extend_from_slice(self.endpoint.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let caller_end = 10 + bytes[9] as usize;
+        Self {
+            tier: bytes[0],
+            period_nanos: u64::from_be_bytes(bytes[1..9].try_into().expect("key has a period")),
+            caller: Principal::from_slice(&bytes[10..caller_end]),
+            endpoint: String::from_utf8_lossy(&bytes[caller_end..]).into_owned(),
+        }
+    }
+}
+
+/// The calls, instructions and payload bytes by the caller and the endpoint, in stable memory.
+///
+/// The usage is kept by periods for the retention time, and in total for all the time.
+///
+/// ```
+/// use candid::Principal;
+/// use ic_metrics::*;
+/// use ic_stable_structures::VectorMemory;
+///
+/// const HOUR: u64 = 3_600_000_000_000;
+///
+/// let mut usage = UsageAccounting::new(VectorMemory::default(), Interval::PerHour, 24 * HOUR);
+/// let alice = Principal::from_slice(&[1]);
+/// usage.record(0, alice, "transfer", UsageStats::call(1_000, 100, 10));
+/// usage.record(HOUR, alice, "transfer", UsageStats::call(3_000, 100, 10));
+///
+/// assert_eq!(usage.total(&alice, "transfer").calls, 2);
+/// assert_eq!(usage.by_endpoint(HOUR, 2 * HOUR)["transfer"].instructions, 3_000);
+/// ```
+pub struct UsageAccounting<M: Memory> {
+    interval: Interval,
+    retention_nanos: u64,
+    usage: StableBTreeMap<UsageKey, UsageStats, M>,
+}
+
+impl<M: Memory> UsageAccounting<M> {
+    /// Create the accounting in the memory, keeping the usage by the periods of the interval
+    /// for the retention time. If the memory contains the accounting, it is kept.
+    pub fn new(memory: M, interval: Interval, retention_nanos: u64) -> Self {
+        Self {
+            interval,
+            retention_nanos,
+            usage: StableBTreeMap::new(memory),
+        }
+    }
+
+    /// Add the usage to the period containing the timestamp and to the total, and remove the
+    /// periods which are out of retention.
+    pub fn record(
+        &mut self,
+        timestamp_nanos: u64,
+        caller: Principal,
+        endpoint: &str,
+        stats: UsageStats,
+    ) {
+        let interval = self.interval.nanos();
+        let endpoint = truncate(endpoint);
+        for (tier, period_nanos) in [
+            (PERIOD_TIER, timestamp_nanos - timestamp_nanos % interval),
+            (TOTAL_TIER, 0),
+        ] {
+            let key = UsageKey {
+                tier,
+                period_nanos,
+                caller,
+                endpoint: endpoint.to_string(),
+            };
+            let mut total = self.usage.get(&key).unwrap_or_default();
+            total.add(&stats);
+            self.usage.insert(key, total);
+        }
+
+        let oldest_to_keep = timestamp_nanos.saturating_sub(self.retention_nanos);
+        let expired: Vec<_> = self
+            .usage
+            .range(
+                UsageKey::first_of_period(PERIOD_TIER, 0)
+                    ..UsageKey::first_of_period(PERIOD_TIER, oldest_to_keep),
+            )
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            self.usage.remove(&key);
+        }
+    }
+
+    /// Record the usage of the current call by its caller at the current time, with the
+    /// instructions executed so far.
+    pub fn record_call(&mut self, endpoint: &str, request_bytes: u64, response_bytes: u64) {
+        let stats = UsageStats::call(instruction_counter(), request_bytes, response_bytes);
+        self.record(
+            ic_exports::ic_kit::ic::time(),
+            ic_exports::ic_kit::ic::caller(),
+            endpoint,
+            stats,
+        );
+    }
+
+    /// Return the usage within the periods starting in the time range.
+    pub fn records(&self, from_nanos: u64, to_nanos: u64) -> Vec<UsageRecord> {
+        if from_nanos >= to_nanos {
+            return vec![];
+        }
+
+        self.usage
+            .range(
+                UsageKey::first_of_period(PERIOD_TIER, from_nanos)
+                    ..UsageKey::first_of_period(PERIOD_TIER, to_nanos),
+            )
+            .map(|(key, stats)| UsageRecord {
+                period_nanos: key.period_nanos,
+                caller: key.caller,
+                endpoint: key.endpoint,
+                stats,
+            })
+            .collect()
+    }
+
+    /// Return the usage within the time range rolled up by the callers.
+    pub fn by_caller(&self, from_nanos: u64, to_nanos: u64) -> BTreeMap<Principal, UsageStats> {
+        let mut rollup = BTreeMap::<_, UsageStats>::new();
+        for record in self.records(from_nanos, to_nanos) {
+            rollup.entry(record.caller).or_default().add(&record.stats);
+        }
+        rollup
+    }
+
+    /// Return the usage within the time range rolled up by the endpoints.
+    pub fn by_endpoint(&self, from_nanos: u64, to_nanos: u64) -> BTreeMap<String, UsageStats> {
+        let mut rollup = BTreeMap::<_, UsageStats>::new();
+        for record in self.records(from_nanos, to_nanos) {
+            rollup
+                .entry(record.endpoint)
+                .or_default()
+                .add(&record.stats);
+        }
+        rollup
+    }
+
+    /// Return the callers with the most instructions executed within the time range.
+    pub fn top_callers(
+        &self,
+        from_nanos: u64,
+        to_nanos: u64,
+        count: usize,
+    ) -> Vec<(Principal, UsageStats)> {
+        let mut callers: Vec<_> = self.by_caller(from_nanos, to_nanos).into_iter().collect();
+        callers.sort_by(|(_, a), (_, b)| b.instructions.cmp(&a.instructions));
+        callers.truncate(count);
+        callers
+    }
+
+    /// Return the usage of the endpoint by the caller for all the time.
+    pub fn total(&self, caller: &Principal, endpoint: &str) -> UsageStats {
+        self.usage
+            .get(&UsageKey {
+                tier: TOTAL_TIER,
+                period_nanos: 0,
+                caller: *caller,
+                endpoint: truncate(endpoint).to_string(),
+            })
+            .unwrap_or_default()
+    }
+
+    /// Return the usage of all the endpoints by the caller for all the time.
+    pub fn caller_total(&self, caller: &Principal) -> UsageStats {
+        let first = UsageKey {
+            tier: TOTAL_TIER,
+            period_nanos: 0,
+            caller: *caller,
+            endpoint: String::new(),
+        };
+        let mut total = UsageStats::default();
+        for (_, stats) in self
+            .usage
+            .range(first..)
+            .take_while(|(key, _)| key.tier == TOTAL_TIER && key.caller == *caller)
+        {
+            total.add(&stats);
+        }
+        total
+    }
+
+    /// Remove all the usage of the caller, e.g. after it was billed.
+    pub fn reset_caller(&mut self, caller: &Principal) {
+        let keys: Vec<_> = self
+            .usage
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.caller == *caller)
+            .collect();
+        for key in keys {
+            self.usage.remove(&key);
+        }
+    }
+}
+
+fn truncate(endpoint: &str) -> &str {
+    if endpoint.len() <= MAX_ENDPOINT_LEN {
+        return endpoint;
+    }
+    let mut end = MAX_ENDPOINT_LEN;
+    while !endpoint.is_char_boundary(end) {
+        end -= 1;
+    }
+    &endpoint[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    const HOUR: u64 = 3_600_000_000_000;
+
+    #[test]
+    fn usage_rollups_and_retention() {
+        let mut usage = UsageAccounting::new(VectorMemory::default(), Interval::PerHour, 2 * HOUR);
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2, 2]);
+
+        usage.record(0, alice, "transfer", UsageStats::call(10, 1, 1));
+        usage.record(HOUR, alice, "transfer", UsageStats::call(20, 1, 1));
+        usage.record(HOUR + 1, alice, "balance", UsageStats::call(5, 1, 1));
+        usage.record(HOUR + 2, bob, "transfer", UsageStats::call(100, 1, 1));
+
+        assert_eq!(usage.records(0, 2 * HOUR).len(), 4);
+        assert_eq!(usage.by_caller(HOUR, 2 * HOUR)[&alice].instructions, 25);
+        assert_eq!(usage.by_endpoint(0, 2 * HOUR)["transfer"].calls, 3);
+        assert_eq!(
+            usage
+                .top_callers(0, 2 * HOUR, 1)
+                .into_iter()
+                .map(|(caller, _)| caller)
+                .collect::<Vec<_>>(),
+            vec![bob]
+        );
+
+        usage.record(3 * HOUR, bob, "transfer", UsageStats::call(1, 1, 1));
+        let records = usage.records(0, 4 * HOUR);
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].period_nanos, HOUR);
+
+        assert_eq!(usage.total(&alice, "transfer").instructions, 30);
+        assert_eq!(usage.caller_total(&alice).calls, 3);
+        assert_eq!(usage.caller_total(&bob).calls, 2);
+
+        usage.reset_caller(&alice);
+        assert_eq!(usage.caller_total(&alice), UsageStats::default());
+        assert_eq!(usage.records(0, 4 * HOUR).len(), 2);
+    }
+
+    #[test]
+    fn long_endpoints_are_truncated() {
+        let mut usage = UsageAccounting::new(VectorMemory::default(), Interval::PerHour, HOUR);
+        let endpoint = "é".repeat(MAX_ENDPOINT_LEN);
+        usage.record(
+            0,
+            Principal::anonymous(),
+            &endpoint,
+            UsageStats::call(1, 0, 0),
+        );
+        assert_eq!(usage.total(&Principal::anonymous(), &endpoint).calls, 1);
+        assert_eq!(
+            usage.records(0, HOUR)[0].endpoint,
+            "é".repeat(MAX_ENDPOINT_LEN / 2)
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use candid::{CandidType, Deserialize, Nat};
 use ic_exports::ic_cdk::api::call::RejectionCode;
 use ic_exports::icrc_types::icrc1::transfer::TransferError;
+use ic_helpers::SdkError;
 use thiserror::Error;
 
 use crate::BalanceError;
@@ -169,3 +170,9 @@ impl From<BalanceError> for PaymentError {
         }
     }
 }
+
+impl From<PaymentError> for SdkError {
+    fn from(error: PaymentError) -> Self {
+        Self::Payment(error.to_string())
+    }
+}
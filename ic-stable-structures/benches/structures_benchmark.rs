@@ -0,0 +1,57 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ic_stable_structures::bench::{BenchConfig, BenchMap, BenchValue, Workload};
+use ic_stable_structures::*;
+
+const MAX_CACHE_ITEMS: u32 = 100;
+
+fn bench_structure<M: BenchMap>(c: &mut Criterion, structure: &str, new_map: impl Fn() -> M) {
+    let config = BenchConfig::default();
+    let mut group = c.benchmark_group(structure);
+
+    for workload in Workload::ALL {
+        group.bench_function(workload.name(), |b| match workload {
+            Workload::SequentialInsert => b.iter_batched(
+                &new_map,
+                |mut map| workload.run(&mut map, &config),
+                BatchSize::LargeInput,
+            ),
+            Workload::RandomGet | Workload::RangeScan => {
+                let mut map = new_map();
+                workload.prepare(&mut map, &config);
+                b.iter(|| workload.run(&mut map, &config))
+            }
+        });
+    }
+
+    group.finish();
+}
+
+fn btreemap_benchmark(c: &mut Criterion) {
+    bench_structure(c, "heap_btreemap", || {
+        HeapBTreeMap::<u64, BenchValue, _>::new(VectorMemory::default())
+    });
+    bench_structure(c, "stable_btreemap", || {
+        StableBTreeMap::<u64, BenchValue, _>::new(VectorMemory::default())
+    });
+    bench_structure(c, "cached_btreemap", || {
+        CachedStableBTreeMap::<u64, BenchValue, _>::new(VectorMemory::default(), MAX_CACHE_ITEMS)
+    });
+}
+
+fn unboundedmap_benchmark(c: &mut Criterion) {
+    bench_structure(c, "heap_unboundedmap", || {
+        HeapUnboundedMap::<u64, BenchValue, _>::new(VectorMemory::default())
+    });
+    bench_structure(c, "stable_unboundedmap", || {
+        StableUnboundedMap::<u64, BenchValue, _>::new(VectorMemory::default())
+    });
+    bench_structure(c, "cached_unboundedmap", || {
+        CachedStableUnboundedMap::<u64, BenchValue, _>::new(
+            VectorMemory::default(),
+            MAX_CACHE_ITEMS,
+        )
+    });
+}
+
+criterion_group!(benches, btreemap_benchmark, unboundedmap_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use ic_stable_structures::fuzzing::check_key_pair;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Input {
+    Small((u32, u64), (u32, u64)),
+    Wide((u128, u8), (u128, u8)),
+}
+
+fuzz_target!(|input: Input| {
+    match input {
+        Input::Small(left, right) => check_key_pair(left, right),
+        Input::Wide(left, right) => check_key_pair(left, right),
+    }
+});
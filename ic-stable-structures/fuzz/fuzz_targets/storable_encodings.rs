@@ -0,0 +1,32 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use ic_stable_structures::fuzzing::{check_storable, FuzzKey, FuzzValue};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Input {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    String(String),
+    Bytes(Vec<u8>),
+    Key(Vec<u8>),
+    Value(Vec<u8>),
+}
+
+fuzz_target!(|input: Input| {
+    match input {
+        Input::U8(value) => check_storable(&value),
+        Input::U16(value) => check_storable(&value),
+        Input::U32(value) => check_storable(&value),
+        Input::U64(value) => check_storable(&value),
+        Input::U128(value) => check_storable(&value),
+        Input::String(value) => check_storable(&value),
+        Input::Bytes(value) => check_storable(&value),
+        Input::Key(bytes) => check_storable(&FuzzKey::new(bytes)),
+        Input::Value(bytes) => check_storable(&FuzzValue(bytes)),
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use ic_stable_structures::fuzzing::{check_chunk_key, FuzzKey};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Input {
+    Fixed(u64, u64),
+    Variable(Vec<u8>, Vec<u8>),
+}
+
+fuzz_target!(|input: Input| {
+    match input {
+        Input::Fixed(left, right) => check_chunk_key(&left, &right),
+        Input::Variable(left, right) => check_chunk_key(&FuzzKey::new(left), &FuzzKey::new(right)),
+    }
+});
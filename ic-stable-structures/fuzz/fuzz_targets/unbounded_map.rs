@@ -0,0 +1,13 @@
+#![no_main]
+
+use ic_stable_structures::fuzzing::{check_unbounded_map, FuzzKey, FuzzValue};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|operations: Vec<(Vec<u8>, Option<Vec<u8>>)>| {
+    check_unbounded_map(
+        operations
+            .into_iter()
+            .map(|(key, value)| (FuzzKey::new(key), value.map(FuzzValue)))
+            .collect(),
+    );
+});
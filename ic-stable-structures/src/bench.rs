@@ -0,0 +1,460 @@
+//! Standard workloads to compare the structure variants before choosing one.
+//!
+//! The same workloads run natively, for example with criterion, and in a canister, where
+//! [`measure`] counts the executed instructions instead of the elapsed time.
+//!
+//! The module is only compiled with the `bench` feature, so the canisters using the structures
+//! don't carry the workloads: `cargo bench --features bench`.
+//!
+//! # Example
+//!
+//! ```
+//! use ic_stable_structures::bench::{measure, BenchConfig, BenchValue, Workload};
+//! use ic_stable_structures::{HeapBTreeMap, StableBTreeMap, VectorMemory};
+//!
+//! let config = BenchConfig::default().with_entries(100);
+//! for workload in Workload::ALL {
+//!     let mut heap = HeapBTreeMap::<u64, BenchValue, _>::new(VectorMemory::default());
+//!     let mut stable = StableBTreeMap::<u64, BenchValue, _>::new(VectorMemory::default());
+//!     let heap = measure("heap", &mut heap, workload, &config);
+//!     let stable = measure("stable", &mut stable, workload, &config);
+//!     assert_eq!(heap.operations, stable.operations);
+//! }
+//! ```
+
+use std::borrow::Cow;
+
+use dfinity_stable_structures::storable::Bound;
+use dfinity_stable_structures::{Memory, Storable};
+
+use crate::structure::*;
+use crate::{ChunkSize, SlicedStorable};
+
+/// The unit of the [`Measurement::cost`] on the current target.
+#[cfg(target_family = "wasm")]
+pub const COST_UNIT: &str = "instructions";
+/// The unit of the [`Measurement::cost`] on the current target.
+#[cfg(not(target_family = "wasm"))]
+pub const COST_UNIT: &str = "ns";
+
+/// The value stored by the workloads, its size is set by [`BenchConfig::value_size`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchValue(pub Vec<u8>);
+
+impl Storable for BenchValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl SlicedStorable for BenchValue {
+    const CHUNK_SIZE: ChunkSize = 64;
+}
+
+/// A map the workloads run against.
+pub trait BenchMap {
+    /// Add or replace the value associated with `key`.
+    fn bench_insert(&mut self, key: u64, value: &BenchValue);
+
+    /// Return the value associated with `key`.
+    fn bench_get(&self, key: u64) -> Option<BenchValue>;
+
+    /// Visit at most `len` entries in the order of the keys, starting from `from`.
+    /// Returns the number of the visited entries.
+    fn bench_scan(&self, from: u64, len: usize) -> usize;
+}
+
+/// The parameters of the workloads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchConfig {
+    /// The number of entries in the map.
+    pub entries: u64,
+    /// The size of every value in bytes.
+    pub value_size: usize,
+    /// The number of entries visited by every scan of [`Workload::RangeScan`].
+    pub scan_len: usize,
+    /// The seed of the random keys.
+    pub seed: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            entries: 1_000,
+            value_size: 128,
+            scan_len: 100,
+            seed: 42,
+        }
+    }
+}
+
+impl BenchConfig {
+    pub fn with_entries(mut self, entries: u64) -> Self {
+        self.entries = entries;
+        self
+    }
+
+    pub fn with_value_size(mut self, value_size: usize) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    pub fn with_scan_len(mut self, scan_len: usize) -> Self {
+        self.scan_len = scan_len.max(1);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    fn value(&self, key: u64) -> BenchValue {
+        BenchValue(
+            (0..self.value_size)
+                .map(|i| (key as usize + i) as u8)
+                .collect(),
+        )
+    }
+
+    /// Random keys of the entries present in the map.
+    fn random_keys(&self) -> impl Iterator<Item = u64> {
+        let entries = self.entries.max(1);
+        let mut state = self.seed | 1;
+        std::iter::repeat_with(move || {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % entries
+        })
+    }
+}
+
+/// A standard workload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Workload {
+    /// Insert `entries` values with increasing keys into an empty map.
+    SequentialInsert,
+    /// Get `entries` values by random keys.
+    RandomGet,
+    /// Scan `scan_len` entries from random keys, until `entries` entries are visited.
+    RangeScan,
+}
+
+impl Workload {
+    pub const ALL: [Workload; 3] = [
+        Workload::SequentialInsert,
+        Workload::RandomGet,
+        Workload::RangeScan,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Workload::SequentialInsert => "sequential_insert",
+            Workload::RandomGet => "random_get",
+            Workload::RangeScan => "range_scan",
+        }
+    }
+
+    /// Fill the map with the entries read by the workload. Must be called on an empty map
+    /// before [`Workload::run`], it is not a part of the measured cost.
+    pub fn prepare(&self, map: &mut impl BenchMap, config: &BenchConfig) {
+        if *self != Workload::SequentialInsert {
+            for key in 0..config.entries {
+                map.bench_insert(key, &config.value(key));
+            }
+        }
+    }
+
+    /// Run the workload and return the number of the performed operations.
+    pub fn run(&self, map: &mut impl BenchMap, config: &BenchConfig) -> u64 {
+        match self {
+            Workload::SequentialInsert => {
+                for key in 0..config.entries {
+                    map.bench_insert(key, &config.value(key));
+                }
+                config.entries
+            }
+            Workload::RandomGet => {
+                for key in config.random_keys().take(config.entries as usize) {
+                    assert!(map.bench_get(key).is_some(), "missing entry {key}");
+                }
+                config.entries
+            }
+            Workload::RangeScan => {
+                let mut visited = 0;
+                for from in config.random_keys() {
+                    if visited >= config.entries {
+                        break;
+                    }
+                    visited += map.bench_scan(from, config.scan_len).max(1) as u64;
+                }
+                visited
+            }
+        }
+    }
+}
+
+/// The cost of a workload run against a structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Measurement {
+    pub structure: String,
+    pub workload: Workload,
+    pub operations: u64,
+    /// The cost of the run in [`COST_UNIT`]s.
+    pub cost: u64,
+}
+
+impl Measurement {
+    pub fn cost_per_operation(&self) -> f64 {
+        self.cost as f64 / self.operations.max(1) as f64
+    }
+}
+
+impl std::fmt::Display for Measurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}: {} operations, {} {}, {:.1} {} per operation",
+            self.structure,
+            self.workload.name(),
+            self.operations,
+            self.cost,
+            COST_UNIT,
+            self.cost_per_operation(),
+            COST_UNIT
+        )
+    }
+}
+
+/// Return the current value of the cost counter: the number of the instructions executed in
+/// the current message on the IC, or the nanoseconds since the first call natively.
+pub fn cost_counter() -> u64 {
+    #[cfg(target_family = "wasm")]
+    return ic_cdk::api::performance_counter(0);
+
+    #[cfg(not(target_family = "wasm"))]
+    {
+        use std::sync::OnceLock;
+        use std::time::Instant;
+
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+}
+
+/// Prepare the empty map for the workload, run it and measure its cost.
+pub fn measure(
+    structure: &str,
+    map: &mut impl BenchMap,
+    workload: Workload,
+    config: &BenchConfig,
+) -> Measurement {
+    workload.prepare(map, config);
+    let start = cost_counter();
+    let operations = workload.run(map, config);
+    let cost = cost_counter() - start;
+
+    Measurement {
+        structure: structure.to_string(),
+        workload,
+        operations,
+        cost,
+    }
+}
+
+impl<M> BenchMap for HeapBTreeMap<u64, BenchValue, M> {
+    fn bench_insert(&mut self, key: u64, value: &BenchValue) {
+        self.insert(key, value.clone());
+    }
+
+    fn bench_get(&self, key: u64) -> Option<BenchValue> {
+        self.get(&key)
+    }
+
+    fn bench_scan(&self, from: u64, len: usize) -> usize {
+        self.range(from..).take(len).count()
+    }
+}
+
+impl<M: Memory> BenchMap for StableBTreeMap<u64, BenchValue, M> {
+    fn bench_insert(&mut self, key: u64, value: &BenchValue) {
+        self.insert(key, value.clone());
+    }
+
+    fn bench_get(&self, key: u64) -> Option<BenchValue> {
+        BTreeMapStructure::get(self, &key)
+    }
+
+    fn bench_scan(&self, from: u64, len: usize) -> usize {
+        self.range(from..).take(len).count()
+    }
+}
+
+impl<M: Memory> BenchMap for CachedStableBTreeMap<u64, BenchValue, M> {
+    fn bench_insert(&mut self, key: u64, value: &BenchValue) {
+        self.insert(key, value.clone());
+    }
+
+    fn bench_get(&self, key: u64) -> Option<BenchValue> {
+        BTreeMapStructure::get(self, &key)
+    }
+
+    fn bench_scan(&self, from: u64, len: usize) -> usize {
+        self.range(from..).take(len).count()
+    }
+}
+
+impl<M> BenchMap for HeapUnboundedMap<u64, BenchValue, M> {
+    fn bench_insert(&mut self, key: u64, value: &BenchValue) {
+        self.insert(&key, value);
+    }
+
+    fn bench_get(&self, key: u64) -> Option<BenchValue> {
+        self.get(&key)
+    }
+
+    fn bench_scan(&self, from: u64, len: usize) -> usize {
+        self.range(from..).take(len).count()
+    }
+}
+
+impl<M: Memory> BenchMap for StableUnboundedMap<u64, BenchValue, M> {
+    fn bench_insert(&mut self, key: u64, value: &BenchValue) {
+        self.insert(&key, value);
+    }
+
+    fn bench_get(&self, key: u64) -> Option<BenchValue> {
+        self.get(&key)
+    }
+
+    fn bench_scan(&self, from: u64, len: usize) -> usize {
+        // The iterator starts from the last key below the bound, which is `from`.
+        self.iter_upper_bound(&(from + 1)).take(len).count()
+    }
+}
+
+impl<M: Memory> BenchMap for CachedStableUnboundedMap<u64, BenchValue, M> {
+    fn bench_insert(&mut self, key: u64, value: &BenchValue) {
+        self.insert(&key, value);
+    }
+
+    fn bench_get(&self, key: u64) -> Option<BenchValue> {
+        self.get(&key)
+    }
+
+    fn bench_scan(&self, from: u64, len: usize) -> usize {
+        // Scans bypass the cache, like the ranges of the cached maps.
+        self.inner().bench_scan(from, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VectorMemory;
+
+    fn run_all(map: impl Fn() -> Box<dyn BenchMap>) -> Vec<u64> {
+        let config = BenchConfig::default().with_entries(50).with_scan_len(7);
+        Workload::ALL
+            .iter()
+            .map(|workload| {
+                let mut map = map();
+                workload.prepare(&mut map, &config);
+                workload.run(&mut map, &config)
+            })
+            .collect()
+    }
+
+    impl BenchMap for Box<dyn BenchMap> {
+        fn bench_insert(&mut self, key: u64, value: &BenchValue) {
+            (**self).bench_insert(key, value)
+        }
+
+        fn bench_get(&self, key: u64) -> Option<BenchValue> {
+            (**self).bench_get(key)
+        }
+
+        fn bench_scan(&self, from: u64, len: usize) -> usize {
+            (**self).bench_scan(from, len)
+        }
+    }
+
+    #[test]
+    fn workloads_run_against_every_variant() {
+        let expected = run_all(|| {
+            Box::new(HeapBTreeMap::<u64, BenchValue, _>::new(
+                VectorMemory::default(),
+            ))
+        });
+        assert_eq!(expected[0], 50);
+        assert_eq!(expected[1], 50);
+        assert!(expected[2] >= 50);
+
+        assert_eq!(
+            run_all(|| Box::new(StableBTreeMap::<u64, BenchValue, _>::new(
+                VectorMemory::default()
+            ))),
+            expected
+        );
+        assert_eq!(
+            run_all(|| Box::new(CachedStableBTreeMap::<u64, BenchValue, _>::new(
+                VectorMemory::default(),
+                10
+            ))),
+            expected
+        );
+        assert_eq!(
+            run_all(|| Box::new(HeapUnboundedMap::<u64, BenchValue, _>::new(
+                VectorMemory::default()
+            ))),
+            expected
+        );
+        assert_eq!(
+            run_all(|| Box::new(StableUnboundedMap::<u64, BenchValue, _>::new(
+                VectorMemory::default()
+            ))),
+            expected
+        );
+        assert_eq!(
+            run_all(
+                || Box::new(CachedStableUnboundedMap::<u64, BenchValue, _>::new(
+                    VectorMemory::default(),
+                    10
+                ))
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn scans_start_from_the_key() {
+        let config = BenchConfig::default().with_entries(10);
+        let mut map = StableUnboundedMap::new(VectorMemory::default());
+        Workload::RandomGet.prepare(&mut map, &config);
+        assert_eq!(map.bench_scan(0, 3), 3);
+        assert_eq!(map.bench_scan(8, 3), 2);
+
+        let mut map = HeapBTreeMap::new(VectorMemory::default());
+        Workload::RandomGet.prepare(&mut map, &config);
+        assert_eq!(map.bench_scan(8, 3), 2);
+
+        let measurement = measure(
+            "heap",
+            &mut HeapBTreeMap::new(VectorMemory::default()),
+            Workload::SequentialInsert,
+            &config,
+        );
+        assert_eq!(measurement.operations, 10);
+        assert!(measurement
+            .to_string()
+            .starts_with("heap/sequential_insert"));
+    }
+}
@@ -1,4 +1,5 @@
-use dfinity_stable_structures::{cell, log, vec, GrowFailed};
+use dfinity_stable_structures::storable::Bound;
+use dfinity_stable_structures::{cell, log, vec, GrowFailed, Storable};
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -7,8 +8,12 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     #[error("stable memory can't grow anymore")]
     OutOfStableMemory,
-    #[error("value bytes interpretation is too large for stable structure: {0}")]
-    ValueTooLarge(u64),
+    #[error("{type_name} takes {actual} bytes, but its max size is {max} bytes")]
+    ValueTooLarge {
+        type_name: &'static str,
+        actual: u64,
+        max: u64,
+    },
     #[error("memory manager and stable structure has incompatible versions")]
     IncompatibleVersions,
     #[error("the vector type is not compatible with the current vector")]
@@ -17,11 +22,47 @@ pub enum Error {
     BadMagic { actual: [u8; 3], expected: [u8; 3] },
 }
 
+/// Returns [`Error::ValueTooLarge`] if the value doesn't fit into the bound of `T`, so that the
+/// structures can reject the value instead of trapping in the underlying library. The value is
+/// encoded only if `T` is bounded.
+pub(crate) fn check_size<T: Storable>(value: &T) -> Result<()> {
+    match T::BOUND {
+        Bound::Bounded { .. } => check_encoded_size::<T>(&value.to_bytes()),
+        Bound::Unbounded => Ok(()),
+    }
+}
+
+/// Same as [`check_size`] for the value already encoded by the structure.
+pub(crate) fn check_encoded_size<T: Storable>(bytes: &[u8]) -> Result<()> {
+    match T::BOUND {
+        Bound::Bounded { max_size, .. } if bytes.len() as u64 > max_size as u64 => {
+            Err(Error::ValueTooLarge {
+                type_name: std::any::type_name::<T>(),
+                actual: bytes.len() as u64,
+                max: max_size as u64,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Panics with the message and the error. With the `static-panic-messages` feature the error is
+/// not formatted, so that its formatting code is not linked into the wasm module.
+#[track_caller]
+pub(crate) fn panic_with_error(message: &'static str, error: Error) -> ! {
+    if cfg!(feature = "static-panic-messages") {
+        panic!("{message}")
+    } else {
+        panic!("{message}: {error}")
+    }
+}
+
 impl From<cell::InitError> for Error {
     fn from(e: cell::InitError) -> Self {
         match e {
             cell::InitError::IncompatibleVersion { .. } => Self::IncompatibleVersions,
-            cell::InitError::ValueTooLarge { value_size } => Self::ValueTooLarge(value_size),
+            // The cell reports a value as too large when the memory can't grow to fit it.
+            cell::InitError::ValueTooLarge { .. } => Self::OutOfStableMemory,
         }
     }
 }
@@ -29,7 +70,7 @@ impl From<cell::InitError> for Error {
 impl From<cell::ValueError> for Error {
     fn from(e: cell::ValueError) -> Self {
         match e {
-            cell::ValueError::ValueTooLarge { value_size } => Self::ValueTooLarge(value_size),
+            cell::ValueError::ValueTooLarge { .. } => Self::OutOfStableMemory,
         }
     }
 }
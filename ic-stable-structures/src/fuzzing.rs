@@ -0,0 +1,196 @@
+//! Invariants of the encodings of the structures, checked by the fuzz targets in the `fuzz`
+//! directory. Every check panics if the invariant doesn't hold for the given input.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+use dfinity_stable_structures::storable::Bound;
+use dfinity_stable_structures::{Storable, VectorMemory};
+
+use crate::structure::stable_storage::multimap::KeyPair;
+use crate::structure::stable_storage::unbounded::Key;
+use crate::{ChunkSize, SlicedStorable, StableUnboundedMap, UnboundedMapStructure};
+
+/// A key of variable size, up to [`FuzzKey::MAX_SIZE`] bytes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FuzzKey(Vec<u8>);
+
+impl FuzzKey {
+    pub const MAX_SIZE: usize = 16;
+
+    /// Create a key from the bytes, truncated to the max size.
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        bytes.truncate(Self::MAX_SIZE);
+        Self(bytes)
+    }
+}
+
+impl Storable for FuzzKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: Self::MAX_SIZE as u32,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+/// A value split into small chunks, so that short inputs span several chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzValue(pub Vec<u8>);
+
+impl Storable for FuzzValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl SlicedStorable for FuzzValue {
+    const CHUNK_SIZE: ChunkSize = 8;
+}
+
+/// Check that the value is encoded within its bound and decoded back unchanged.
+pub fn check_storable<T: Storable + PartialEq + Debug>(value: &T) {
+    let bytes = value.to_bytes();
+    if let Bound::Bounded {
+        max_size,
+        is_fixed_size,
+    } = T::BOUND
+    {
+        assert!(
+            bytes.len() <= max_size as usize,
+            "{value:?} exceeds its bound"
+        );
+        if is_fixed_size {
+            assert_eq!(
+                bytes.len(),
+                max_size as usize,
+                "{value:?} is not fixed size"
+            );
+        }
+    }
+
+    assert_eq!(&T::from_bytes(bytes), value);
+}
+
+/// Check that the key pairs of a multimap are decoded back to the keys, are ordered by the
+/// bytes of the first keys and then of the second keys, and lie within the bounds of the
+/// range of their first key.
+pub fn check_key_pair<K1, K2>(left: (K1, K2), right: (K1, K2))
+where
+    K1: Storable + PartialEq + Debug,
+    K2: Storable + PartialEq + Debug,
+{
+    let left_pair = KeyPair::new(&left.0, &left.1);
+    let right_pair = KeyPair::new(&right.0, &right.1);
+
+    for (pair, (first_key, second_key)) in [(&left_pair, &left), (&right_pair, &right)] {
+        assert_eq!(&pair.first_key(), first_key);
+        assert_eq!(&pair.second_key(), second_key);
+        assert!(KeyPair::min_key(first_key) <= *pair);
+        assert!(*pair <= KeyPair::max_key(first_key));
+
+        let decoded = KeyPair::<K1, K2>::from_bytes(pair.to_bytes().into_owned().into());
+        assert!(decoded == *pair);
+    }
+
+    let bytes_order =
+        (left.0.to_bytes(), left.1.to_bytes()).cmp(&(right.0.to_bytes(), right.1.to_bytes()));
+    assert_eq!(left_pair.cmp(&right_pair), bytes_order);
+}
+
+/// Check that the chunk keys of an unbounded map are decoded back to the key, and that the
+/// chunks of one key are never interleaved with the chunks of another one.
+pub fn check_chunk_key<K: Storable + PartialEq + Debug>(left: &K, right: &K) {
+    let left_key = Key::new(left);
+    assert_eq!(left_key.key_data(), left.to_bytes().as_ref());
+    assert_eq!(&K::from_bytes(left_key.key_data().to_vec().into()), left);
+
+    if left.to_bytes() != right.to_bytes() {
+        let first_chunk = Key::new(left);
+        let last_chunk = first_chunk.clone().with_max_chunk_index();
+        let other_first_chunk = Key::new(right);
+        let other_last_chunk = other_first_chunk.clone().with_max_chunk_index();
+
+        for other in [&other_first_chunk, &other_last_chunk] {
+            assert!(
+                !(first_chunk <= *other && *other <= last_chunk),
+                "chunks of {right:?} are within the chunks of {left:?}"
+            );
+        }
+    }
+}
+
+/// Apply the operations to an unbounded map and check it against a model after each of them.
+/// An operation with a value inserts it, without a value removes the key.
+pub fn check_unbounded_map(operations: Vec<(FuzzKey, Option<FuzzValue>)>) {
+    let mut map = StableUnboundedMap::new(VectorMemory::default());
+    let mut model = BTreeMap::new();
+
+    for (key, value) in operations {
+        match value {
+            Some(value) => assert_eq!(map.insert(&key, &value), model.insert(key.clone(), value)),
+            None => assert_eq!(map.remove(&key), model.remove(&key)),
+        }
+
+        assert_eq!(map.len(), model.len() as u64);
+        assert_eq!(map.get(&key), model.get(&key).cloned());
+
+        let chunks: u64 = model
+            .values()
+            .map(|value| {
+                value
+                    .0
+                    .len()
+                    .div_ceil(FuzzValue::CHUNK_SIZE as usize)
+                    .max(1) as u64
+            })
+            .sum();
+        assert_eq!(map.total_chunks_number(), chunks);
+    }
+
+    let entries: Vec<_> = map.iter().collect();
+    assert_eq!(entries.len(), model.len());
+    assert_eq!(entries.into_iter().collect::<BTreeMap<_, _>>(), model);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings_invariants() {
+        check_storable(&42u64);
+        check_storable(&FuzzKey::new(vec![1; 20]));
+        check_key_pair((1u32, 2u64), (1u32, 1u64));
+        check_key_pair((u32::MAX, 0u64), (0u32, u64::MAX));
+        check_chunk_key(&FuzzKey::new(vec![1]), &FuzzKey::new(vec![1, 0]));
+        check_chunk_key(&FuzzKey::new(vec![]), &FuzzKey::new(vec![0xFF; 16]));
+    }
+
+    #[test]
+    fn unbounded_map_matches_the_model() {
+        let key = |bytes: &[u8]| FuzzKey::new(bytes.to_vec());
+        let value = |len: usize| Some(FuzzValue(vec![7; len]));
+        check_unbounded_map(vec![
+            (key(&[1]), value(20)),
+            (key(&[1, 0]), value(0)),
+            (key(&[]), value(8)),
+            (key(&[1]), value(3)),
+            (key(&[1, 0]), None),
+            (key(&[2]), None),
+        ]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Checking the header of the memory manager before it's created on a stable memory.
+//!
+//! A memory manager created on an unexpected layout traps, or reinitializes the memory and loses
+//! the data. The functions of this module read the header of the memory manager and reject an
+//! unknown layout with an error, so that the upgrade can be rejected before any data is changed.
+//!
+//! Only the layout version 1 of the memory manager is supported, the one written by all the
+//! versions of the SDK so far. Nothing is converted: the structures in the virtual memories are
+//! read by the current structures as is, and a memory in any other format is rejected.
+//!
+//! ```ignore
+//! #[post_upgrade]
+//! fn post_upgrade() {
+//!     let layout = inspect_memory_manager(&DefaultMemoryImpl::default())
+//!         .expect("unsupported stable memory layout");
+//!     log::info!("Upgrading the stable memory: {layout:?}");
+//!     let memory_manager = open_memory_manager(DefaultMemoryImpl::default()).unwrap();
+//! }
+//! ```
+
+use dfinity_stable_structures::Memory;
+
+use crate::{Error, IcMemoryManager, Result};
+
+const MAGIC: [u8; 3] = *b"MGR";
+const LAYOUT_VERSION: u8 = 1;
+const MAX_NUM_MEMORIES: usize = 255;
+
+/// The offset of the sizes of the memories in the header.
+const MEMORY_SIZES_OFFSET: u64 = 3 + 1 + 2 + 2 + 32;
+
+/// The layout of the memory manager stored in a memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryManagerLayout {
+    pub version: u8,
+    pub num_allocated_buckets: u16,
+    pub bucket_size_in_pages: u16,
+    /// The sizes in pages of the virtual memories by their ids, without the empty memories.
+    pub memory_sizes_in_pages: Vec<(u8, u64)>,
+}
+
+/// Reads the layout of the memory manager stored in the memory, `None` if the memory is empty.
+///
+/// Returns [`Error::BadMagic`] if the memory was not written by a memory manager and
+/// [`Error::IncompatibleVersions`] if the layout is not supported.
+pub fn inspect_memory_manager<M: Memory>(memory: &M) -> Result<Option<MemoryManagerLayout>> {
+    if memory.size() == 0 {
+        return Ok(None);
+    }
+
+    let mut header = [0; 8];
+    memory.read(0, &mut header);
+    let actual = [header[0], header[1], header[2]];
+    if actual != MAGIC {
+        return Err(Error::BadMagic {
+            actual,
+            expected: MAGIC,
+        });
+    }
+    if header[3] != LAYOUT_VERSION {
+        return Err(Error::IncompatibleVersions);
+    }
+
+    let mut sizes = vec![0; MAX_NUM_MEMORIES * 8];
+    memory.read(MEMORY_SIZES_OFFSET, &mut sizes);
+    let memory_sizes_in_pages = sizes
+        .chunks_exact(8)
+        .enumerate()
+        .map(|(id, size)| (id as u8, u64::from_le_bytes(size.try_into().unwrap())))
+        .filter(|(_, size)| *size > 0)
+        .collect();
+
+    Ok(Some(MemoryManagerLayout {
+        version: header[3],
+        num_allocated_buckets: u16::from_le_bytes([header[4], header[5]]),
+        bucket_size_in_pages: u16::from_le_bytes([header[6], header[7]]),
+        memory_sizes_in_pages,
+    }))
+}
+
+/// Creates the memory manager on the memory, keeping the virtual memories already stored in it.
+/// A new memory manager is created if the memory is empty.
+///
+/// Unlike [`IcMemoryManager::init`], returns an error instead of trapping if the header of the
+/// memory is not a supported memory manager header, or if the memory is smaller than the
+/// buckets allocated by the header.
+pub fn open_memory_manager<M: Memory>(memory: M) -> Result<IcMemoryManager<M>> {
+    if let Some(layout) = inspect_memory_manager(&memory)? {
+        let allocated_pages =
+            layout.num_allocated_buckets as u64 * layout.bucket_size_in_pages as u64;
+        // The buckets start after the first page.
+        if memory.size() < 1 + allocated_pages {
+            return Err(Error::IncompatibleVersions);
+        }
+    }
+
+    Ok(IcMemoryManager::init(memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use dfinity_stable_structures::VectorMemory;
+
+    use super::*;
+    use crate::MemoryId;
+
+    const WASM_PAGE_SIZE: u64 = 65536;
+
+    #[test]
+    fn memory_written_by_memory_manager_is_reopened() {
+        let memory = VectorMemory::default();
+        assert_eq!(inspect_memory_manager(&memory).unwrap(), None);
+
+        let virtual_memory = IcMemoryManager::init(memory.clone()).get(MemoryId::new(3));
+        virtual_memory.grow(2);
+        virtual_memory.write(WASM_PAGE_SIZE, b"legacy");
+
+        let layout = inspect_memory_manager(&memory).unwrap().unwrap();
+        assert_eq!(layout.version, 1);
+        assert_eq!(layout.num_allocated_buckets, 1);
+        assert_eq!(layout.memory_sizes_in_pages, [(3, 2)]);
+
+        let reopened = open_memory_manager(memory).unwrap().get(MemoryId::new(3));
+        let mut data = [0; 6];
+        reopened.read(WASM_PAGE_SIZE, &mut data);
+        assert_eq!(&data, b"legacy");
+    }
+
+    #[test]
+    fn unknown_layout_is_rejected() {
+        let memory = VectorMemory::default();
+        memory.grow(1);
+        memory.write(0, b"XYZ\x01");
+        assert!(matches!(
+            open_memory_manager(memory.clone()),
+            Err(Error::BadMagic { .. })
+        ));
+
+        memory.write(0, b"MGR\x07");
+        assert!(matches!(
+            open_memory_manager(memory),
+            Err(Error::IncompatibleVersions)
+        ));
+    }
+
+    #[test]
+    fn truncated_memory_is_rejected() {
+        let memory = VectorMemory::default();
+        IcMemoryManager::init(memory.clone())
+            .get(MemoryId::new(0))
+            .grow(1);
+
+        let truncated = VectorMemory::default();
+        truncated.grow(1);
+        let mut header = vec![0; WASM_PAGE_SIZE as usize];
+        memory.read(0, &mut header);
+        truncated.write(0, &header);
+
+        assert!(matches!(
+            open_memory_manager(truncated),
+            Err(Error::IncompatibleVersions)
+        ));
+    }
+}
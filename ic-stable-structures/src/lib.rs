@@ -1,7 +1,14 @@
+#[cfg(feature = "bench")]
+pub mod bench;
 mod structure;
 
 mod error;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzzing;
+pub mod layout;
 mod memory;
+pub mod persistent;
 #[cfg(feature = "memory-mapped-files-memory")]
 mod memory_mapped_files;
 
@@ -1,3 +1,7 @@
+use std::cell::RefCell;
+#[cfg(not(target_family = "wasm"))]
+use std::thread::LocalKey;
+
 use dfinity_stable_structures::memory_manager::{
     MemoryId, MemoryManager as IcMemoryManager, VirtualMemory,
 };
@@ -25,3 +29,128 @@ impl<M: Memory> MemoryManager<VirtualMemory<M>, MemoryId> for IcMemoryManager<M>
 pub fn default_ic_memory_manager() -> IcMemoryManager<DefaultMemoryImpl> {
     IcMemoryManager::init(DefaultMemoryImpl::default())
 }
+
+/// The thread-local memory manager of a canister, which the tests can swap with a
+/// [`MemorySandbox`].
+pub type MemoryManagerCell = RefCell<IcMemoryManager<DefaultMemoryImpl>>;
+
+/// Replaces the memory manager of the canister in the current thread with a fresh one over an
+/// empty memory, and restores the original memory manager when dropped.
+///
+/// The tests may run one after another on the same thread, so without the sandbox the
+/// structures of a test would see the data written by the previous tests to the thread-local
+/// memory manager. Note that the structures must be created after the sandbox to use its memory.
+///
+/// ```
+/// use std::cell::RefCell;
+///
+/// use ic_stable_structures::stable_structures::DefaultMemoryImpl;
+/// use ic_stable_structures::{
+///     default_ic_memory_manager, CellStructure, MemoryId, MemoryManagerCell, MemorySandbox,
+///     StableCell, VirtualMemory,
+/// };
+///
+/// thread_local! {
+///     static MEMORY_MANAGER: MemoryManagerCell = RefCell::new(default_ic_memory_manager());
+/// }
+///
+/// fn counter() -> StableCell<u64, VirtualMemory<DefaultMemoryImpl>> {
+///     let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(0)));
+///     StableCell::new(memory, 0).unwrap()
+/// }
+///
+/// let _sandbox = MemorySandbox::new(&MEMORY_MANAGER);
+/// assert_eq!(*counter().get(), 0);
+/// counter().set(42).unwrap();
+/// ```
+#[cfg(not(target_family = "wasm"))]
+#[must_use = "the original memory manager is restored when the sandbox is dropped"]
+pub struct MemorySandbox {
+    key: &'static LocalKey<MemoryManagerCell>,
+    previous: Option<IcMemoryManager<DefaultMemoryImpl>>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl MemorySandbox {
+    /// Swap the memory manager of the key with a fresh one over an empty memory.
+    pub fn new(key: &'static LocalKey<MemoryManagerCell>) -> Self {
+        let previous = key.with(|mm| mm.replace(default_ic_memory_manager()));
+        Self {
+            key,
+            previous: Some(previous),
+        }
+    }
+
+    /// Returns the memory with the given id from the memory manager of the sandbox.
+    pub fn get(&self, id: MemoryId) -> VirtualMemory<DefaultMemoryImpl> {
+        self.key.with(|mm| mm.borrow().get(id))
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Drop for MemorySandbox {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            self.key.with(|mm| mm.replace(previous));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        static MEMORY_MANAGER: MemoryManagerCell = RefCell::new(default_ic_memory_manager());
+    }
+
+    fn memory() -> VirtualMemory<DefaultMemoryImpl> {
+        MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(0)))
+    }
+
+    fn read_first_byte(memory: &impl Memory) -> Option<u8> {
+        if memory.size() == 0 {
+            return None;
+        }
+
+        let mut buf = [0];
+        memory.read(0, &mut buf);
+        Some(buf[0])
+    }
+
+    fn write_first_byte(memory: &impl Memory, value: u8) {
+        if memory.size() == 0 {
+            memory.grow(1);
+        }
+        memory.write(0, &[value]);
+    }
+
+    /// The body of a test, which expects an empty memory and writes to it.
+    fn sandboxed_test(value: u8) {
+        let sandbox = MemorySandbox::new(&MEMORY_MANAGER);
+        assert_eq!(read_first_byte(&memory()), None);
+        write_first_byte(&memory(), value);
+        assert_eq!(read_first_byte(&sandbox.get(MemoryId::new(0))), Some(value));
+    }
+
+    #[test]
+    fn sandboxed_tests_do_not_see_each_other_data() {
+        write_first_byte(&memory(), 1);
+
+        sandboxed_test(2);
+        sandboxed_test(3);
+
+        assert_eq!(read_first_byte(&memory()), Some(1));
+    }
+
+    #[test]
+    fn nested_sandboxes_are_restored_in_order() {
+        let outer = MemorySandbox::new(&MEMORY_MANAGER);
+        write_first_byte(&memory(), 1);
+        {
+            let _inner = MemorySandbox::new(&MEMORY_MANAGER);
+            assert_eq!(read_first_byte(&memory()), None);
+        }
+        assert_eq!(read_first_byte(&outer.get(MemoryId::new(0))), Some(1));
+    }
+}
@@ -0,0 +1,79 @@
+//! The structures of the canister state, stored in stable memory or, with the `heap-only`
+//! feature, in the heap.
+//!
+//! The heap structures have the same constructors and implement the same traits as the stable
+//! ones, ignoring the memory. A canister declaring its state with these aliases can be
+//! prototyped, fuzzed and checked with miri without simulating the stable memory, and becomes
+//! persistent by disabling the feature:
+//!
+//! ```
+//! use ic_stable_structures::persistent::{BTreeMap, Cell};
+//! use ic_stable_structures::{BTreeMapStructure, CellStructure, VectorMemory};
+//!
+//! let mut balances = BTreeMap::<u32, u64, _>::new(VectorMemory::default());
+//! balances.insert(1, 100);
+//! let mut total = Cell::new(VectorMemory::default(), 0u64).unwrap();
+//! total.set(100).unwrap();
+//!
+//! assert_eq!(balances.get(&1), Some(*total.get()));
+//! ```
+//!
+//! The state in the heap is lost on upgrade, so the `heap-only` feature must not be enabled in
+//! the production builds.
+
+#[cfg(not(feature = "heap-only"))]
+mod selected {
+    pub type BTreeMap<K, V, M> = crate::StableBTreeMap<K, V, M>;
+    pub type Cell<T, M> = crate::StableCell<T, M>;
+    pub type Log<T, M> = crate::StableLog<T, M>;
+    pub type Multimap<K1, K2, V, M> = crate::StableMultimap<K1, K2, V, M>;
+    pub type UnboundedMap<K, V, M> = crate::StableUnboundedMap<K, V, M>;
+    pub type Vec<T, M> = crate::StableVec<T, M>;
+}
+
+#[cfg(feature = "heap-only")]
+mod selected {
+    pub type BTreeMap<K, V, M> = crate::HeapBTreeMap<K, V, M>;
+    pub type Cell<T, M> = crate::HeapCell<T, M>;
+    pub type Log<T, M> = crate::HeapLog<T, M>;
+    pub type Multimap<K1, K2, V, M> = crate::HeapMultimap<K1, K2, V, M>;
+    pub type UnboundedMap<K, V, M> = crate::HeapUnboundedMap<K, V, M>;
+    pub type Vec<T, M> = crate::HeapVec<T, M>;
+}
+
+pub use selected::*;
+
+/// Returns `true` if the structures of this module are stored in stable memory.
+pub const fn is_persistent() -> bool {
+    cfg!(not(feature = "heap-only"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{str_val, StringValue};
+    use crate::{
+        LogStructure, MultimapStructure, UnboundedMapStructure, VecStructure, VectorMemory,
+    };
+
+    #[test]
+    fn structures_are_built_with_the_same_constructors() {
+        let mut log = Log::<u64, _>::new(VectorMemory::default(), VectorMemory::default()).unwrap();
+        log.append(1).unwrap();
+        assert_eq!(log.get(0), Some(1));
+
+        let mut multimap = Multimap::<u32, u32, u64, _>::new(VectorMemory::default());
+        multimap.insert(&1, &2, &3);
+        assert_eq!(multimap.get(&1, &2), Some(3));
+
+        let mut unbounded = UnboundedMap::<u32, StringValue, _>::new(VectorMemory::default());
+        unbounded.insert(&1, &str_val(100));
+        assert_eq!(unbounded.get(&1), Some(str_val(100)));
+
+        let mut vec = Vec::<u64, _>::new(VectorMemory::default()).unwrap();
+        vec.push(&7).unwrap();
+        assert_eq!(vec.get(0), Some(7));
+
+        assert_eq!(is_persistent(), cfg!(not(feature = "heap-only")));
+    }
+}
@@ -34,10 +34,32 @@ where
         }
     }
 
+    /// Returns the hits and misses of the cache and its size.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     /// Returns the inner collection so that the caller can have a readonly access to it that bypasses the cache.
     pub fn inner(&self) -> &StableBTreeMap<K, V, M> {
         &self.inner
     }
+
+    /// Returns a read-only view of the inner collection, which bypasses the cache, so the
+    /// reads through the view don't change the cache.
+    pub fn read_only(&self) -> ReadOnly<'_, StableBTreeMap<K, V, M>> {
+        ReadOnly::new(&self.inner)
+    }
+
+    /// Iterate over all the pairs from the greatest key to the least one, bypassing the cache.
+    pub fn iter_rev(&self) -> StableBTreeMapRevIter<'_, K, V, M> {
+        self.inner.iter_rev()
+    }
+
+    /// Iterate over the pairs with the keys in the range from the greatest key to the least one,
+    /// bypassing the cache.
+    pub fn range_rev(&self, key_range: impl RangeBounds<K>) -> StableBTreeMapRevIter<'_, K, V, M> {
+        self.inner.range_rev(key_range)
+    }
 }
 
 impl<K, V, M> BTreeMapStructure<K, V> for CachedStableBTreeMap<K, V, M>
@@ -102,7 +124,7 @@ where
     V: Storable + Clone + Send + Sync,
     M: Memory,
 {
-    type Iterator<'a> = dfinity_stable_structures::btreemap::Iter<'a, K, V, M> where Self: 'a;
+    type Iterator<'a> = StableBTreeMapIter<'a, K, V, M> where Self: 'a;
 
     fn iter(&self) -> Self::Iterator<'_> {
         self.inner.iter()
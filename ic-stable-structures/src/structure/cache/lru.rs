@@ -1,13 +1,45 @@
 use std::convert::Infallible;
 use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use parking_lot::Mutex;
 use schnellru::{ByLength, LruMap};
 
+/// The usage of a cache since it was created.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The lookups which found the value in the cache.
+    pub hits: u64,
+    /// The lookups which had to load the value.
+    pub misses: u64,
+    /// The number of the cached items.
+    pub len: u64,
+    /// The max number of the cached items.
+    pub capacity: u64,
+}
+
+impl CacheStats {
+    /// Return the share of the lookups which found the value in the cache, or 0 if there were
+    /// no lookups.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
 /// A wrapper around `LruCache`. This struct is thread safe, doesn't return any references to any
 /// elements inside.
+///
+/// The lock of the cache is held only while the cache itself is read or changed: the loaders of
+/// the values run and the replaced, evicted or removed values are dropped without the lock, so
+/// they can use the cache again, e.g. to load the related values.
 pub struct SyncLruCache<K, V> {
     inner: Mutex<LruMap<K, V>>,
+    capacity: u32,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
 impl<K, V> SyncLruCache<K, V>
@@ -18,8 +50,26 @@ where
     /// Creats a new `LRU` cache that holds at most `cap` items.
     pub fn new(cap: u32) -> Self {
         Self {
-            // Creating an inner LruMap with a fixed hasher
-            inner: Mutex::new(LruMap::<K, V>::with_seed(ByLength::new(cap), [0, 1, 3, 4])),
+            inner: Mutex::new(Self::new_map(cap)),
+            capacity: cap,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn new_map(cap: u32) -> LruMap<K, V> {
+        // Creating an inner LruMap with a fixed hasher
+        LruMap::with_seed(ByLength::new(cap), [0, 1, 3, 4])
+    }
+
+    /// Returns the hits and misses of the lookups with [`Self::get_or_try_insert_with`] and the
+    /// size of the cache.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            len: self.len() as u64,
+            capacity: self.capacity as u64,
         }
     }
 
@@ -58,12 +108,13 @@ where
         F: FnOnce(&K) -> Result<Option<V>, E>,
     {
         if let Some(result) = self.get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
             return Ok(Some(result));
         }
+        self.misses.fetch_add(1, Ordering::Relaxed);
         let val = f(key)?;
         if let Some(val) = val.as_ref() {
-            let val_clone = val.clone();
-            self.inner.lock().insert(key.clone(), val_clone);
+            self.insert(key.clone(), val.clone());
         }
         Ok(val)
     }
@@ -71,7 +122,18 @@ where
     /// Puts a key-value pair into cache. If the key already exists in the cache,
     /// then it updates the key's value.
     pub fn insert(&self, key: K, value: V) {
-        self.inner.lock().insert(key, value);
+        // The replaced and the evicted values are dropped after the lock is released.
+        let _dropped = {
+            let mut inner = self.inner.lock();
+            let replaced = inner.remove(&key);
+            let evicted = if inner.len() >= self.capacity as usize {
+                inner.pop_oldest()
+            } else {
+                None
+            };
+            inner.insert(key, value);
+            (replaced, evicted)
+        };
     }
 
     /// Returns the value of the key in the cache or None if it is not present in the cache.
@@ -88,7 +150,8 @@ where
     /// Puts a key-value pair into cache. If the key already exists in the cache,
     /// then it updates the key's value.
     pub fn clear(&self) {
-        self.inner.lock().clear()
+        let cleared = std::mem::replace(&mut *self.inner.lock(), Self::new_map(self.capacity));
+        drop(cleared);
     }
 }
 
@@ -110,4 +173,70 @@ mod tests {
         assert_eq!(cache.get_or_insert_with(&127u64, |_| None), None);
         assert_eq!(cache.get(&0u64), None);
     }
+
+    #[test]
+    fn loader_can_use_the_cache() {
+        let cache = SyncLruCache::<u64, u64>::new(2);
+
+        let value = cache.get_or_insert_with(&1, |key| {
+            cache
+                .get_or_insert_with(&(key + 1), |key| Some(key * 10))
+                .map(|value| value + 1)
+        });
+
+        assert_eq!(value, Some(21));
+        assert_eq!(cache.get(&1), Some(21));
+        assert_eq!(cache.get(&2), Some(20));
+    }
+
+    #[test]
+    fn dropped_values_can_use_the_cache() {
+        #[derive(Clone)]
+        struct Value;
+
+        impl Drop for Value {
+            fn drop(&mut self) {
+                // Would deadlock if the value was dropped while the cache is locked.
+                let _ = CACHE.try_with(|cache| cache.len());
+            }
+        }
+
+        thread_local! {
+            static CACHE: SyncLruCache<u64, Value> = SyncLruCache::new(1);
+        }
+
+        CACHE.with(|cache| {
+            cache.insert(1, Value);
+            // Replaced.
+            cache.insert(1, Value);
+            // Evicts the first key.
+            cache.insert(2, Value);
+            assert!(cache.remove(&2).is_some());
+            cache.insert(3, Value);
+            cache.clear();
+
+            assert!(cache.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let cache = SyncLruCache::<u64, u64>::new(2);
+
+        cache.get_or_insert_with(&1, |key| Some(*key));
+        cache.get_or_insert_with(&1, |key| Some(*key));
+        cache.get_or_insert_with(&2, |_| None);
+
+        let stats = cache.stats();
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                len: 1,
+                capacity: 2,
+            }
+        );
+        assert_eq!(stats.hit_ratio(), 1.0 / 3.0);
+    }
 }
@@ -4,6 +4,6 @@ pub mod multimap;
 pub mod unbounded;
 
 pub use btreemap::CachedStableBTreeMap;
-pub use lru::SyncLruCache;
+pub use lru::{CacheStats, SyncLruCache};
 pub use multimap::CachedStableMultimap;
 pub use unbounded::CachedStableUnboundedMap;
@@ -36,10 +36,33 @@ where
         }
     }
 
+    /// Returns the hits and misses of the cache and its size.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     /// Returns the inner collection so that the caller can have a readonly access to it that bypasses the cache.
     pub fn inner(&self) -> &StableMultimap<K1, K2, V, M> {
         &self.inner
     }
+
+    /// Returns a read-only view of the inner collection, which bypasses the cache, so the
+    /// reads through the view don't change the cache.
+    pub fn read_only(&self) -> ReadOnly<'_, StableMultimap<K1, K2, V, M>> {
+        ReadOnly::new(&self.inner)
+    }
+
+    /// Iterator over all items from the greatest pair of keys to the least one, bypassing the
+    /// cache.
+    pub fn iter_rev(&self) -> StableMultimapRevIter<'_, K1, K2, V, M> {
+        self.inner.iter_rev()
+    }
+
+    /// Iterator over the entries that correspond to the `first_key` from the greatest second key
+    /// to the least one, bypassing the cache.
+    pub fn range_rev(&self, first_key: &K1) -> StableMultimapRangeRevIter<'_, K1, K2, V, M> {
+        self.inner.range_rev(first_key)
+    }
 }
 
 impl<K1, K2, V, M> MultimapStructure<K1, K2, V> for CachedStableMultimap<K1, K2, V, M>
@@ -226,6 +249,30 @@ mod test {
         assert_eq!(iter.next(), Some((3, 1, Array([3u8, 1]))));
     }
 
+    #[test]
+    fn get_while_iterating() {
+        let cache_items = 2;
+
+        let mut map = CachedStableMultimap::<u32, u32, Array<2>, _>::new(
+            VectorMemory::default(),
+            cache_items,
+        );
+
+        map.insert(&1, &1, &Array([1u8, 1]));
+        map.insert(&1, &2, &Array([2u8, 1]));
+        map.insert(&3, &1, &Array([3u8, 1]));
+
+        for (first_key, second_key, value) in map.iter() {
+            assert_eq!(map.get(&first_key, &second_key), Some(value));
+        }
+        for (second_key, value) in map.range_rev(&1) {
+            assert_eq!(map.get(&1, &second_key), Some(value));
+        }
+
+        let stats = map.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (1, 4, 2));
+    }
+
     #[test]
     fn range_iter() {
         let cache_items = 2;
@@ -244,6 +291,11 @@ mod test {
         assert_eq!(iter.next(), Some((2, Array([2u8, 1]))));
         assert_eq!(iter.next(), None);
 
+        let mut iter = map.range_rev(&1);
+        assert_eq!(iter.next(), Some((2, Array([2u8, 1]))));
+        assert_eq!(iter.next(), Some((1, Array([1u8, 1]))));
+        assert_eq!(iter.next(), None);
+
         let mut iter = map.range(&2);
         assert_eq!(iter.next(), None);
 
@@ -2,8 +2,8 @@ use std::hash::Hash;
 
 use dfinity_stable_structures::{Memory, Storable};
 
-use crate::structure::stable_storage::StableUnboundedMap;
-use crate::{SlicedStorable, SyncLruCache, UnboundedMapStructure};
+use crate::structure::stable_storage::{CompactionProgress, StableUnboundedMap};
+use crate::{CacheStats, ReadOnly, SlicedStorable, SyncLruCache, UnboundedMapStructure};
 
 /// A LRU Cache for StableUnboundedMaps
 pub struct CachedStableUnboundedMap<K, V, M>
@@ -35,10 +35,32 @@ where
         }
     }
 
+    /// Returns the hits and misses of the cache and its size.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     /// Returns the inner collection so that the caller can have a readonly access to it that bypasses the cache.
     pub fn inner(&self) -> &StableUnboundedMap<K, V, M> {
         &self.inner
     }
+
+    /// Returns a read-only view of the inner collection, which bypasses the cache, so the
+    /// reads through the view don't change the cache.
+    pub fn read_only(&self) -> ReadOnly<'_, StableUnboundedMap<K, V, M>> {
+        ReadOnly::new(&self.inner)
+    }
+
+    /// Start the compaction of the inner map, see [`StableUnboundedMap::start_compaction`].
+    pub fn start_compaction(&mut self, scratch: M) -> bool {
+        self.inner.start_compaction(scratch)
+    }
+
+    /// Continue the compaction of the inner map, see [`StableUnboundedMap::compact`]. The
+    /// compaction doesn't change the values, so the cache is kept.
+    pub fn compact(&mut self, max_items_per_call: usize) -> CompactionProgress {
+        self.inner.compact(max_items_per_call)
+    }
 }
 
 impl<K, V, M> UnboundedMapStructure<K, V> for CachedStableUnboundedMap<K, V, M>
@@ -0,0 +1,467 @@
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+use dfinity_stable_structures::storable::Bound;
+use dfinity_stable_structures::{Memory, Storable};
+use sha2::{Digest, Sha256};
+
+use crate::structure::{
+    BTreeMapStructure, IterableSortedMapStructure, IterableUnboundedMapStructure, ReadOnly,
+    StableRingBuffer, UnboundedMapStructure,
+};
+
+/// Max size of the keys of the maps wrapped by [`Audited`].
+pub const MAX_AUDIT_KEY_SIZE: u32 = 128;
+
+const MAX_CALLER_SIZE: usize = 29;
+const HASH_LEN: usize = 32;
+
+/// The kind of a change recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOp {
+    /// A value was inserted or replaced.
+    Insert,
+    Remove,
+    Clear,
+}
+
+impl AuditOp {
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            0 => Self::Insert,
+            1 => Self::Remove,
+            2 => Self::Clear,
+            _ => panic!("invalid audit op {byte}"),
+        }
+    }
+}
+
+/// A change of the audited map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub op: AuditOp,
+    /// The encoded key, empty for [`AuditOp::Clear`].
+    pub key: Vec<u8>,
+    /// SHA-256 of the encoded value before the change.
+    pub old_value_hash: Option<[u8; HASH_LEN]>,
+    /// SHA-256 of the encoded value after the change.
+    pub new_value_hash: Option<[u8; HASH_LEN]>,
+    pub timestamp: u64,
+    /// The bytes of the principal of the caller.
+    pub caller: Vec<u8>,
+}
+
+/// The time and the caller of the changes, by default taken from the system API.
+pub struct AuditContext {
+    pub timestamp: u64,
+    pub caller: Vec<u8>,
+}
+
+impl AuditContext {
+    fn system() -> Self {
+        #[cfg(target_family = "wasm")]
+        {
+            Self {
+                timestamp: ic_cdk::api::time(),
+                caller: ic_cdk::api::caller().as_slice().to_vec(),
+            }
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        {
+            Self {
+                timestamp: 0,
+                caller: vec![],
+            }
+        }
+    }
+}
+
+// Record memory layout:
+//
+// |- op -|- timestamp -|- caller len -|- caller -|- key len -|- key -|- hashes flags -|- hashes -|
+//
+// The timestamp and the key length are stored in the big-endian format, the flags tell which
+// of the hashes of the old and the new values follow.
+impl Storable for AuditRecord {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: (1 + 8 + 1 + MAX_CALLER_SIZE + 2 + 1 + 2 * HASH_LEN) as u32 + MAX_AUDIT_KEY_SIZE,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(Self::BOUND.max_size() as usize);
+        bytes.push(self.op as u8);
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.push(self.caller.len() as u8);
+        bytes.extend_from_slice(&self.caller);
+        bytes.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.key);
+        bytes
+            .push(self.old_value_hash.is_some() as u8 | (self.new_value_hash.is_some() as u8) << 1);
+        for hash in self.old_value_hash.iter().chain(&self.new_value_hash) {
+            bytes.extend_from_slice(hash);
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let mut rest = bytes.as_ref();
+        let mut take = |len: usize| {
+            let (taken, tail) = rest.split_at(len);
+            rest = tail;
+            taken
+        };
+
+        let op = AuditOp::from_byte(take(1)[0]);
+        let timestamp = u64::from_be_bytes(take(8).try_into().expect("timestamp is 8 bytes"));
+        let caller_len = take(1)[0] as usize;
+        let caller = take(caller_len).to_vec();
+        let key_len = u16::from_be_bytes(take(2).try_into().expect("key length is 2 bytes"));
+        let key = take(key_len as usize).to_vec();
+        let flags = take(1)[0];
+        let mut hash =
+            |present: bool| present.then(|| take(HASH_LEN).try_into().expect("hash is 32 bytes"));
+        let old_value_hash = hash(flags & 1 != 0);
+        let new_value_hash = hash(flags & 2 != 0);
+
+        Self {
+            op,
+            key,
+            old_value_hash,
+            new_value_hash,
+            timestamp,
+            caller,
+        }
+    }
+}
+
+type ContextProvider = Box<dyn Fn() -> AuditContext>;
+
+/// A map which records every change into a bounded audit log in stable memory before the
+/// change is applied.
+///
+/// The log keeps the keys and the hashes of the old and the new values, so that the history of
+/// a value can be verified against its snapshots. When the log is full, the oldest records are
+/// replaced.
+///
+/// ```
+/// use std::num::NonZeroU64;
+///
+/// use ic_stable_structures::{
+///     AuditOp, Audited, BTreeMapStructure, StableBTreeMap, StableRingBuffer, VectorMemory,
+/// };
+///
+/// let log = StableRingBuffer::new(
+///     VectorMemory::default(),
+///     VectorMemory::default(),
+///     NonZeroU64::new(1000).unwrap(),
+/// )
+/// .unwrap();
+/// let mut balances = Audited::new(StableBTreeMap::new(VectorMemory::default()), log);
+///
+/// balances.insert(1u32, 100u64);
+/// balances.remove(&1);
+/// assert_eq!(balances.audit_log().last().unwrap().op, AuditOp::Remove);
+/// ```
+pub struct Audited<S, K, V, DataMemory: Memory, IndicesMemory: Memory> {
+    inner: S,
+    log: StableRingBuffer<AuditRecord, DataMemory, IndicesMemory>,
+    context: ContextProvider,
+    _p: PhantomData<(K, V)>,
+}
+
+impl<S, K, V, DataMemory, IndicesMemory> Audited<S, K, V, DataMemory, IndicesMemory>
+where
+    K: Storable,
+    V: Storable,
+    DataMemory: Memory,
+    IndicesMemory: Memory,
+{
+    /// Record the changes of the map in the log.
+    ///
+    /// # Panics
+    ///   - if the max size of `K` exceeds [`MAX_AUDIT_KEY_SIZE`].
+    pub fn new(inner: S, log: StableRingBuffer<AuditRecord, DataMemory, IndicesMemory>) -> Self {
+        match K::BOUND {
+            Bound::Bounded { max_size, .. } if max_size <= MAX_AUDIT_KEY_SIZE => {}
+            _ => panic!("audited map keys must be bounded by {MAX_AUDIT_KEY_SIZE} bytes"),
+        }
+
+        Self {
+            inner,
+            log,
+            context: Box::new(AuditContext::system),
+            _p: PhantomData,
+        }
+    }
+
+    /// Take the time and the caller of the changes from the `context` instead of the system API.
+    pub fn with_context(mut self, context: impl Fn() -> AuditContext + 'static) -> Self {
+        self.context = Box::new(context);
+        self
+    }
+
+    /// Returns the audited map.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the audit log.
+    pub fn audit_log(&self) -> &StableRingBuffer<AuditRecord, DataMemory, IndicesMemory> {
+        &self.log
+    }
+
+    /// Returns a read-only view of the audited structure.
+    pub fn read_only(&self) -> ReadOnly<'_, S> {
+        ReadOnly::new(&self.inner)
+    }
+
+    fn record(
+        &mut self,
+        op: AuditOp,
+        key: Option<&K>,
+        old_value: Option<&V>,
+        new_value: Option<&V>,
+    ) {
+        let hash = |value: &V| -> [u8; HASH_LEN] { Sha256::digest(value.to_bytes()).into() };
+        let AuditContext { timestamp, caller } = (self.context)();
+        let mut caller = caller;
+        caller.truncate(MAX_CALLER_SIZE);
+
+        self.log.push(&AuditRecord {
+            op,
+            key: key
+                .map(|key| key.to_bytes().into_owned())
+                .unwrap_or_default(),
+            old_value_hash: old_value.map(hash),
+            new_value_hash: new_value.map(hash),
+            timestamp,
+            caller,
+        });
+    }
+}
+
+impl<S, K, V, DataMemory, IndicesMemory> BTreeMapStructure<K, V>
+    for Audited<S, K, V, DataMemory, IndicesMemory>
+where
+    S: BTreeMapStructure<K, V>,
+    K: Storable,
+    V: Storable,
+    DataMemory: Memory,
+    IndicesMemory: Memory,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        self.inner.get(key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old_value = self.inner.get(&key);
+        self.record(
+            AuditOp::Insert,
+            Some(&key),
+            old_value.as_ref(),
+            Some(&value),
+        );
+        self.inner.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let old_value = self.inner.get(key)?;
+        self.record(AuditOp::Remove, Some(key), Some(&old_value), None);
+        self.inner.remove(key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    fn last_key_value(&self) -> Option<(K, V)> {
+        self.inner.last_key_value()
+    }
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.record(AuditOp::Clear, None, None, None);
+        self.inner.clear();
+    }
+}
+
+impl<S, K, V, DataMemory, IndicesMemory> IterableSortedMapStructure<K, V>
+    for Audited<S, K, V, DataMemory, IndicesMemory>
+where
+    S: IterableSortedMapStructure<K, V>,
+    DataMemory: Memory,
+    IndicesMemory: Memory,
+{
+    type Iterator<'a> = S::Iterator<'a> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.inner.iter()
+    }
+
+    fn range(&self, key_range: impl RangeBounds<K>) -> Self::Iterator<'_> {
+        self.inner.range(key_range)
+    }
+
+    fn iter_upper_bound(&self, bound: &K) -> Self::Iterator<'_> {
+        self.inner.iter_upper_bound(bound)
+    }
+}
+
+impl<S, K, V, DataMemory, IndicesMemory> UnboundedMapStructure<K, V>
+    for Audited<S, K, V, DataMemory, IndicesMemory>
+where
+    S: UnboundedMapStructure<K, V>,
+    K: Storable,
+    V: Storable,
+    DataMemory: Memory,
+    IndicesMemory: Memory,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        self.inner.get(key)
+    }
+
+    fn first_key(&self) -> Option<K> {
+        self.inner.first_key()
+    }
+
+    fn first_key_value(&self) -> Option<(K, V)> {
+        self.inner.first_key_value()
+    }
+
+    fn last_key(&self) -> Option<K> {
+        self.inner.last_key()
+    }
+
+    fn last_key_value(&self) -> Option<(K, V)> {
+        self.inner.last_key_value()
+    }
+
+    fn insert(&mut self, key: &K, value: &V) -> Option<V> {
+        let old_value = self.inner.get(key);
+        self.record(AuditOp::Insert, Some(key), old_value.as_ref(), Some(value));
+        self.inner.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let old_value = self.inner.get(key)?;
+        self.record(AuditOp::Remove, Some(key), Some(&old_value), None);
+        self.inner.remove(key)
+    }
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn total_chunks_number(&self) -> u64 {
+        self.inner.total_chunks_number()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.record(AuditOp::Clear, None, None, None);
+        self.inner.clear();
+    }
+}
+
+impl<S, K, V, DataMemory, IndicesMemory> IterableUnboundedMapStructure<K, V>
+    for Audited<S, K, V, DataMemory, IndicesMemory>
+where
+    S: IterableUnboundedMapStructure<K, V>,
+    K: Storable,
+    V: Storable,
+    DataMemory: Memory,
+    IndicesMemory: Memory,
+{
+    type Iterator<'a> = S::Iterator<'a> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.inner.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::num::NonZeroU64;
+    use std::rc::Rc;
+
+    use dfinity_stable_structures::VectorMemory;
+
+    use super::*;
+    use crate::structure::StableBTreeMap;
+
+    fn hash(value: u64) -> Option<[u8; HASH_LEN]> {
+        Some(Sha256::digest(value.to_bytes()).into())
+    }
+
+    #[test]
+    fn changes_are_recorded_before_they_are_applied() {
+        let log = StableRingBuffer::new(
+            VectorMemory::default(),
+            VectorMemory::default(),
+            NonZeroU64::new(4).unwrap(),
+        )
+        .unwrap();
+        let time = Rc::new(Cell::new(0));
+        let clock = time.clone();
+        let mut balances = Audited::new(StableBTreeMap::new(VectorMemory::default()), log)
+            .with_context(move || AuditContext {
+                timestamp: clock.get(),
+                caller: vec![7; 40],
+            });
+
+        for (timestamp, balance) in [(1, 100u64), (2, 50)] {
+            time.set(timestamp);
+            balances.insert(1u32, balance);
+        }
+        time.set(3);
+        assert_eq!(balances.remove(&1), Some(50));
+        assert_eq!(balances.remove(&1), None);
+        balances.insert(2, 10);
+        balances.clear();
+
+        let log = balances.audit_log();
+        assert_eq!(log.len(), 4);
+        assert_eq!(
+            log.first(),
+            Some(AuditRecord {
+                op: AuditOp::Insert,
+                key: 1u32.to_bytes().into_owned(),
+                old_value_hash: hash(100),
+                new_value_hash: hash(50),
+                timestamp: 2,
+                caller: vec![7; MAX_CALLER_SIZE],
+            })
+        );
+        assert_eq!(
+            log.nth_element(1)
+                .map(|record| (record.op, record.old_value_hash)),
+            Some((AuditOp::Remove, hash(50)))
+        );
+        assert_eq!(
+            log.last(),
+            Some(AuditRecord {
+                op: AuditOp::Clear,
+                key: vec![],
+                old_value_hash: None,
+                new_value_hash: None,
+                timestamp: 3,
+                caller: vec![7; MAX_CALLER_SIZE],
+            })
+        );
+        assert!(balances.is_empty());
+    }
+}
@@ -1,6 +1,12 @@
+pub mod audited;
+pub mod observed;
+pub mod read_only;
 pub mod ring_buffer;
 
+pub use audited::{AuditContext, AuditOp, AuditRecord, Audited, MAX_AUDIT_KEY_SIZE};
 use dfinity_stable_structures::Storable;
+pub use observed::Observed;
+pub use read_only::ReadOnly;
 pub use ring_buffer::{StableRingBuffer, StableRingBufferIndices};
 
 pub type ChunkSize = u16;
@@ -0,0 +1,369 @@
+use std::ops::RangeBounds;
+
+use crate::structure::{
+    BTreeMapStructure, IterableSortedMapStructure, IterableUnboundedMapStructure,
+    MultimapStructure, ReadOnly, UnboundedMapStructure,
+};
+
+type InsertObserver<K, V> = Box<dyn Fn(&K, Option<&V>, &V)>;
+type RemoveObserver<K, V> = Box<dyn Fn(&K, &V)>;
+type ClearObserver = Box<dyn Fn()>;
+
+/// A map which calls the observers after its entries are changed, e.g. to maintain an index in
+/// another structure, to invalidate a cache of another layer or to push the changes to an outbox.
+///
+/// The insert observers get the key, the replaced value if any and the new value, the remove
+/// observers get the key and the removed value. The entries of a multimap are observed with the
+/// `(first_key, second_key)` pairs as the keys.
+///
+/// Clearing the map doesn't report the removed entries, only calls the clear observers.
+/// The observers are kept in the heap, so they must be registered again after an upgrade.
+///
+/// ```
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// use ic_stable_structures::{BTreeMapStructure, Observed, StableBTreeMap, VectorMemory};
+///
+/// let removed = Rc::new(RefCell::new(vec![]));
+/// let removed_keys = removed.clone();
+/// let mut map = Observed::new(StableBTreeMap::new(VectorMemory::default()))
+///     .on_remove(move |key: &u32, _: &u64| removed_keys.borrow_mut().push(*key));
+///
+/// map.insert(1, 10);
+/// map.remove(&1);
+/// assert_eq!(*removed.borrow(), [1]);
+/// ```
+pub struct Observed<S, K, V> {
+    inner: S,
+    insert_observers: Vec<InsertObserver<K, V>>,
+    remove_observers: Vec<RemoveObserver<K, V>>,
+    clear_observers: Vec<ClearObserver>,
+}
+
+impl<S, K, V> Observed<S, K, V> {
+    /// Observe the changes of the structure.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            insert_observers: vec![],
+            remove_observers: vec![],
+            clear_observers: vec![],
+        }
+    }
+
+    /// Call the observer after an entry is inserted or replaced.
+    pub fn on_insert(mut self, observer: impl Fn(&K, Option<&V>, &V) + 'static) -> Self {
+        self.insert_observers.push(Box::new(observer));
+        self
+    }
+
+    /// Call the observer after an entry is removed.
+    pub fn on_remove(mut self, observer: impl Fn(&K, &V) + 'static) -> Self {
+        self.remove_observers.push(Box::new(observer));
+        self
+    }
+
+    /// Call the observer after the structure is cleared.
+    pub fn on_clear(mut self, observer: impl Fn() + 'static) -> Self {
+        self.clear_observers.push(Box::new(observer));
+        self
+    }
+
+    /// Returns the observed structure so that the caller can have a readonly access to it.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the observed structure without the observers.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns a read-only view of the observed structure.
+    pub fn read_only(&self) -> ReadOnly<'_, S> {
+        ReadOnly::new(&self.inner)
+    }
+
+    fn inserted(&self, key: &K, old_value: Option<&V>, value: &V) {
+        for observer in &self.insert_observers {
+            observer(key, old_value, value);
+        }
+    }
+
+    fn removed(&self, key: &K, value: &V) {
+        for observer in &self.remove_observers {
+            observer(key, value);
+        }
+    }
+
+    fn cleared(&self) {
+        for observer in &self.clear_observers {
+            observer();
+        }
+    }
+}
+
+impl<S, K, V> BTreeMapStructure<K, V> for Observed<S, K, V>
+where
+    S: BTreeMapStructure<K, V>,
+    K: Clone,
+    V: Clone,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        self.inner.get(key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if self.insert_observers.is_empty() {
+            return self.inner.insert(key, value);
+        }
+
+        let old_value = self.inner.insert(key.clone(), value.clone());
+        self.inserted(&key, old_value.as_ref(), &value);
+        old_value
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.inner.remove(key)?;
+        self.removed(key, &value);
+        Some(value)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    fn last_key_value(&self) -> Option<(K, V)> {
+        self.inner.last_key_value()
+    }
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.cleared();
+    }
+}
+
+impl<S, K, V> IterableSortedMapStructure<K, V> for Observed<S, K, V>
+where
+    S: IterableSortedMapStructure<K, V>,
+{
+    type Iterator<'a> = S::Iterator<'a> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.inner.iter()
+    }
+
+    fn range(&self, key_range: impl RangeBounds<K>) -> Self::Iterator<'_> {
+        self.inner.range(key_range)
+    }
+
+    fn iter_upper_bound(&self, bound: &K) -> Self::Iterator<'_> {
+        self.inner.iter_upper_bound(bound)
+    }
+}
+
+impl<S, K, V> UnboundedMapStructure<K, V> for Observed<S, K, V>
+where
+    S: UnboundedMapStructure<K, V>,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        self.inner.get(key)
+    }
+
+    fn first_key(&self) -> Option<K> {
+        self.inner.first_key()
+    }
+
+    fn first_key_value(&self) -> Option<(K, V)> {
+        self.inner.first_key_value()
+    }
+
+    fn last_key(&self) -> Option<K> {
+        self.inner.last_key()
+    }
+
+    fn last_key_value(&self) -> Option<(K, V)> {
+        self.inner.last_key_value()
+    }
+
+    fn insert(&mut self, key: &K, value: &V) -> Option<V> {
+        let old_value = self.inner.insert(key, value);
+        self.inserted(key, old_value.as_ref(), value);
+        old_value
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.inner.remove(key)?;
+        self.removed(key, &value);
+        Some(value)
+    }
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn total_chunks_number(&self) -> u64 {
+        self.inner.total_chunks_number()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.cleared();
+    }
+}
+
+impl<S, K, V> IterableUnboundedMapStructure<K, V> for Observed<S, K, V>
+where
+    S: IterableUnboundedMapStructure<K, V>,
+{
+    type Iterator<'a> = S::Iterator<'a> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.inner.iter()
+    }
+}
+
+impl<S, K1, K2, V> MultimapStructure<K1, K2, V> for Observed<S, (K1, K2), V>
+where
+    S: MultimapStructure<K1, K2, V>,
+    K1: Clone,
+    K2: Clone,
+{
+    type Iterator<'a> = S::Iterator<'a> where Self: 'a;
+
+    type RangeIterator<'a> = S::RangeIterator<'a> where Self: 'a;
+
+    fn get(&self, first_key: &K1, second_key: &K2) -> Option<V> {
+        self.inner.get(first_key, second_key)
+    }
+
+    fn insert(&mut self, first_key: &K1, second_key: &K2, value: &V) -> Option<V> {
+        let old_value = self.inner.insert(first_key, second_key, value);
+        let key = (first_key.clone(), second_key.clone());
+        self.inserted(&key, old_value.as_ref(), value);
+        old_value
+    }
+
+    fn remove(&mut self, first_key: &K1, second_key: &K2) -> Option<V> {
+        let value = self.inner.remove(first_key, second_key)?;
+        self.removed(&(first_key.clone(), second_key.clone()), &value);
+        Some(value)
+    }
+
+    fn remove_partial(&mut self, first_key: &K1) -> bool {
+        if self.remove_observers.is_empty() {
+            return self.inner.remove_partial(first_key);
+        }
+
+        let removed: Vec<_> = self.inner.range(first_key).collect();
+        let found = self.inner.remove_partial(first_key);
+        for (second_key, value) in removed {
+            self.removed(&(first_key.clone(), second_key), &value);
+        }
+        found
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn range(&self, first_key: &K1) -> Self::RangeIterator<'_> {
+        self.inner.range(first_key)
+    }
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.inner.iter()
+    }
+
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.cleared();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use dfinity_stable_structures::VectorMemory;
+
+    use super::*;
+    use crate::{StableBTreeMap, StableMultimap};
+
+    #[test]
+    fn observers_maintain_an_index() {
+        // The index of the owners by the balances.
+        let index = Rc::new(RefCell::new(BTreeMap::new()));
+        let (insert_index, remove_index, clear_index) =
+            (index.clone(), index.clone(), index.clone());
+        let mut balances = Observed::new(StableBTreeMap::new(VectorMemory::default()))
+            .on_insert(move |owner: &u32, old_balance: Option<&u64>, balance| {
+                let mut index = insert_index.borrow_mut();
+                if let Some(old_balance) = old_balance {
+                    index.remove(&(*old_balance, *owner));
+                }
+                index.insert((*balance, *owner), ());
+            })
+            .on_remove(move |owner, balance| {
+                remove_index.borrow_mut().remove(&(*balance, *owner));
+            })
+            .on_clear(move || clear_index.borrow_mut().clear());
+
+        balances.insert(1, 100);
+        balances.insert(2, 50);
+        balances.insert(1, 10);
+        balances.insert(3, 70);
+        assert_eq!(balances.remove(&2), Some(50));
+        assert_eq!(balances.remove(&2), None);
+        assert_eq!(
+            index.borrow().keys().copied().collect::<Vec<_>>(),
+            [(10, 1), (70, 3)]
+        );
+        assert_eq!(balances.iter().count(), 2);
+
+        balances.clear();
+        assert!(index.borrow().is_empty());
+    }
+
+    #[test]
+    fn multimap_entries_are_observed_by_pairs() {
+        let removed = Rc::new(RefCell::new(vec![]));
+        let removed_entries = removed.clone();
+        let mut map = Observed::new(StableMultimap::new(VectorMemory::default())).on_remove(
+            move |keys: &(u32, u32), value: &u64| {
+                removed_entries.borrow_mut().push((*keys, *value))
+            },
+        );
+
+        map.insert(&1, &1, &10);
+        map.insert(&1, &2, &20);
+        map.insert(&2, &1, &30);
+        assert_eq!(map.remove(&2, &1), Some(30));
+        assert!(map.remove_partial(&1));
+
+        assert_eq!(
+            *removed.borrow(),
+            [((2, 1), 30), ((1, 1), 10), ((1, 2), 20)]
+        );
+        assert!(map.is_empty());
+    }
+}
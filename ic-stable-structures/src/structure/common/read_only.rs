@@ -0,0 +1,77 @@
+use std::ops::Deref;
+
+/// A read-only view of a structure, returned by the `read_only()` methods of the structures.
+///
+/// The view gives access only to the `&self` methods of the structure, so a query method
+/// written against the view can't change the structure by mistake. The view of a cached
+/// structure reads its inner structure and doesn't change the cache. The view is `Copy` and
+/// can be freely shared within a message.
+///
+/// ```
+/// use ic_stable_structures::{BTreeMapStructure, ReadOnly, StableBTreeMap, VectorMemory};
+///
+/// fn balance(balances: ReadOnly<'_, StableBTreeMap<u32, u64, VectorMemory>>, owner: u32) -> u64 {
+///     balances.get(&owner).unwrap_or_default()
+/// }
+///
+/// let mut balances = StableBTreeMap::new(VectorMemory::default());
+/// balances.insert(1, 100);
+/// assert_eq!(balance(balances.read_only(), 1), 100);
+/// ```
+pub struct ReadOnly<'a, S: ?Sized>(&'a S);
+
+impl<'a, S: ?Sized> ReadOnly<'a, S> {
+    pub(crate) fn new(structure: &'a S) -> Self {
+        Self(structure)
+    }
+
+    /// Returns the reference to the structure with the lifetime of the view, e.g. to return an
+    /// iterator over the structure from a function taking the view.
+    pub fn into_ref(self) -> &'a S {
+        self.0
+    }
+}
+
+impl<S: ?Sized> Clone for ReadOnly<'_, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: ?Sized> Copy for ReadOnly<'_, S> {}
+
+impl<S: ?Sized> Deref for ReadOnly<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfinity_stable_structures::VectorMemory;
+
+    use super::*;
+    use crate::structure::{BTreeMapStructure, CachedStableBTreeMap, StableBTreeMap};
+
+    fn sum(values: ReadOnly<'_, StableBTreeMap<u32, u64, VectorMemory>>) -> u64 {
+        values.into_ref().iter().map(|(_, value)| value).sum()
+    }
+
+    #[test]
+    fn cached_view_does_not_change_cache() {
+        let mut map = CachedStableBTreeMap::new(VectorMemory::default(), 10);
+        map.insert(1u32, 10u64);
+        map.insert(2, 20);
+
+        let view = map.read_only();
+        let copy = view;
+        assert_eq!(view.get(&1), Some(10));
+        assert_eq!(copy.get(&2), Some(20));
+        assert_eq!(sum(view), 30);
+
+        let stats = map.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (0, 0, 0));
+    }
+}
@@ -5,7 +5,7 @@ use std::num::NonZeroU64;
 use dfinity_stable_structures::storable::Bound;
 use dfinity_stable_structures::{Memory, Storable};
 
-use crate::structure::{CellStructure, StableCell, StableVec, VecStructure};
+use crate::structure::{CellStructure, ReadOnly, StableCell, StableVec, VecStructure};
 use crate::Result;
 
 /// Ring buffer indices state
@@ -144,6 +144,11 @@ impl<T: Storable + Clone, DataMemory: Memory, IndicesMemory: Memory>
         Self { data, indices }
     }
 
+    /// Returns a read-only view of the buffer, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     /// Removes all elements in the buffer
     pub fn clear(&mut self) {
         self.with_indices_data_mut(|indices, data| {
@@ -1,9 +1,10 @@
-use std::collections::BTreeMap;
+use std::collections::{btree_map, BTreeMap};
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
 use dfinity_stable_structures::Storable;
 
-use crate::structure::BTreeMapStructure;
+use crate::structure::{BTreeMapStructure, IterableSortedMapStructure, ReadOnly};
 
 /// Stores key-value data in heap memory.
 pub struct HeapBTreeMap<K, V, M>(BTreeMap<K, V>, PhantomData<M>)
@@ -21,9 +22,55 @@ where
         Self(BTreeMap::new(), Default::default())
     }
 
+    /// Returns a read-only view of the map, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     /// Iterate over all currently stored key-value pairs.
-    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
-        self.0.iter().map(|(k, v)| (k.clone(), v.clone()))
+    pub fn iter(&self) -> HeapBTreeMapIter<'_, K, V> {
+        HeapBTreeMapIter(self.0.range(..))
+    }
+
+    /// Iterate over the key-value pairs with the keys in the given range.
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> HeapBTreeMapIter<'_, K, V> {
+        HeapBTreeMapIter(self.0.range(key_range))
+    }
+}
+
+impl<K, V, M> IterableSortedMapStructure<K, V> for HeapBTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Clone,
+{
+    type Iterator<'a> = HeapBTreeMapIter<'a, K, V> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        HeapBTreeMap::iter(self)
+    }
+
+    fn range(&self, key_range: impl RangeBounds<K>) -> Self::Iterator<'_> {
+        HeapBTreeMap::range(self, key_range)
+    }
+
+    fn iter_upper_bound(&self, bound: &K) -> Self::Iterator<'_> {
+        let range = match self.0.range::<K, _>(..bound).next_back() {
+            Some((start, _)) => (Bound::Included(start), Bound::Unbounded),
+            // An empty range.
+            None => (Bound::Included(bound), Bound::Excluded(bound)),
+        };
+        HeapBTreeMapIter(self.0.range::<K, _>(range))
+    }
+}
+
+/// Iterator over the entries of [`HeapBTreeMap`].
+pub struct HeapBTreeMapIter<'a, K, V>(btree_map::Range<'a, K, V>);
+
+impl<K: Clone, V: Clone> Iterator for HeapBTreeMapIter<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(k, v)| (k.clone(), v.clone()))
     }
 }
 
@@ -91,4 +138,21 @@ mod tests {
 
         assert_eq!(map.len(), 1);
     }
+
+    #[test]
+    fn iter_upper_bound_starts_below_the_bound() {
+        let mut map = HeapBTreeMap::new(());
+        for key in [10u32, 20, 30] {
+            map.insert(key, key * 10);
+        }
+
+        let from = |bound| {
+            IterableSortedMapStructure::iter_upper_bound(&map, &bound)
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(from(25), [20, 30]);
+        assert_eq!(from(20), [10, 20, 30]);
+        assert_eq!(from(10), Vec::<u32>::new());
+    }
 }
@@ -2,7 +2,7 @@ use std::marker::PhantomData;
 
 use dfinity_stable_structures::Storable;
 
-use crate::structure::CellStructure;
+use crate::structure::{CellStructure, ReadOnly};
 use crate::Result;
 
 /// Stores value in heap memory, providing `get()/set()` API.
@@ -13,6 +13,11 @@ impl<T: Storable, M> HeapCell<T, M> {
     pub fn new(_memory: M, value: T) -> Result<Self> {
         Ok(Self(value, Default::default()))
     }
+
+    /// Returns a read-only view of the cell, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
 }
 
 impl<T: Storable, M> CellStructure<T> for HeapCell<T, M> {
@@ -2,7 +2,7 @@ use std::marker::PhantomData;
 
 use dfinity_stable_structures::Storable;
 
-use crate::structure::LogStructure;
+use crate::structure::{LogStructure, ReadOnly};
 use crate::Result;
 
 /// Stores list of immutable values in heap memory.
@@ -14,6 +14,11 @@ impl<T: Storable + Clone, M> HeapLog<T, M> {
     pub fn new(_index_memory: M, _data_memory: M) -> Result<Self> {
         Ok(Self(vec![], Default::default()))
     }
+
+    /// Returns a read-only view of the log, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
 }
 
 impl<T: Storable + Clone, M> LogStructure<T> for HeapLog<T, M> {
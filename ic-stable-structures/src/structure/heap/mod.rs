@@ -5,7 +5,7 @@ mod multimap;
 mod unbounded;
 mod vec;
 
-pub use btreemap::HeapBTreeMap;
+pub use btreemap::{HeapBTreeMap, HeapBTreeMapIter};
 pub use cell::HeapCell;
 pub use log::HeapLog;
 pub use multimap::{HeapMultimap, HeapMultimapIter};
@@ -6,7 +6,7 @@ use std::marker::PhantomData;
 
 use dfinity_stable_structures::Storable;
 
-use crate::structure::MultimapStructure;
+use crate::structure::{MultimapStructure, ReadOnly};
 
 /// `HeapMultimap` stores two keys against a single value, making it possible
 /// to fetch all values by the root key, or a single value by specifying both keys.
@@ -29,6 +29,11 @@ where
     pub fn new(_memory: M) -> Self {
         Self(BTreeMap::new(), Default::default())
     }
+
+    /// Returns a read-only view of the map, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
 }
 
 impl<K1, K2, V, M> MultimapStructure<K1, K2, V> for HeapMultimap<K1, K2, V, M>
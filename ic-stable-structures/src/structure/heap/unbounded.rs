@@ -2,11 +2,12 @@ use std::collections::btree_map::Iter as BTreeMapIter;
 use std::collections::BTreeMap;
 use std::hash::Hash;
 use std::marker::PhantomData;
+use std::ops::RangeBounds;
 
 use dfinity_stable_structures::Storable;
 
 use crate::structure::common::SlicedStorable;
-use crate::structure::UnboundedMapStructure;
+use crate::structure::{ReadOnly, UnboundedMapStructure};
 
 /// Stores key-value data in heap memory.
 pub struct HeapUnboundedMap<K, V, M>(BTreeMap<K, V>, PhantomData<M>)
@@ -27,10 +28,20 @@ where
         Self(BTreeMap::new(), Default::default())
     }
 
+    /// Returns a read-only view of the map, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     /// List all currently stored key-value pairs.
     pub fn iter(&self) -> HeapUnboundedIter<'_, K, V> {
         HeapUnboundedIter(self.0.iter())
     }
+
+    /// List the key-value pairs with the keys in the given range.
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> impl Iterator<Item = (K, V)> + '_ {
+        self.0.range(key_range).map(|(k, v)| (k.clone(), v.clone()))
+    }
 }
 
 impl<K, V, M> UnboundedMapStructure<K, V> for HeapUnboundedMap<K, V, M>
@@ -2,7 +2,7 @@ use std::marker::PhantomData;
 
 use dfinity_stable_structures::Storable;
 
-use crate::structure::VecStructure;
+use crate::structure::{ReadOnly, VecStructure};
 use crate::Result;
 
 pub struct HeapVec<T: Storable + Clone, M>(Vec<T>, PhantomData<M>);
@@ -15,6 +15,11 @@ impl<T: Storable + Clone, M> HeapVec<T, M> {
         Ok(Self(vec![], Default::default()))
     }
 
+    /// Returns a read-only view of the vector, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     /// Returns iterator over the elements in the vector
     pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
         self.0.iter().cloned()
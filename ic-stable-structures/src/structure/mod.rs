@@ -1,11 +1,11 @@
-use std::ops::RangeBounds;
+use std::ops::{Bound, RangeBounds};
 
 use crate::Result;
 
 mod cache;
 mod common;
 mod heap;
-mod stable_storage;
+pub(crate) mod stable_storage;
 
 pub use cache::*;
 pub use common::*;
@@ -64,6 +64,118 @@ pub trait IterableSortedMapStructure<K, V> {
     fn iter_upper_bound(&self, bound: &K) -> Self::Iterator<'_>;
 }
 
+/// An object-safe sorted map, implemented for all the sorted maps, so that the implementation of
+/// a map can be chosen at runtime, e.g. a heap map in the tests and a cached stable map in
+/// production.
+///
+/// `Box<dyn MapStructure<K, V>>` implements [`BTreeMapStructure`] and
+/// [`IterableSortedMapStructure`] itself, so it can be used with the generic code and wrappers.
+///
+/// ```
+/// use ic_stable_structures::{
+///     BTreeMapStructure, CachedStableBTreeMap, HeapBTreeMap, IterableSortedMapStructure,
+///     MapStructure, VectorMemory,
+/// };
+///
+/// fn balances(cached: bool) -> Box<dyn MapStructure<u32, u64>> {
+///     if cached {
+///         Box::new(CachedStableBTreeMap::new(VectorMemory::default(), 100))
+///     } else {
+///         Box::new(HeapBTreeMap::new(()))
+///     }
+/// }
+///
+/// for cached in [true, false] {
+///     let mut balances = balances(cached);
+///     balances.insert(1, 100);
+///     balances.insert(2, 50);
+///     assert_eq!(balances.get(&1), Some(100));
+///     assert_eq!(balances.iter().map(|(_, balance)| balance).sum::<u64>(), 150);
+/// }
+/// ```
+pub trait MapStructure<K, V>: BTreeMapStructure<K, V> {
+    /// Returns iterator over the whole collection.
+    fn dyn_iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_>;
+
+    /// Returns an iterator over the entries in the map where keys belong to the specified range.
+    fn dyn_range(&self, key_range: (Bound<K>, Bound<K>)) -> Box<dyn Iterator<Item = (K, V)> + '_>;
+
+    /// Returns an iterator pointing to the first element below the given bound.
+    fn dyn_iter_upper_bound(&self, bound: &K) -> Box<dyn Iterator<Item = (K, V)> + '_>;
+}
+
+impl<K, V, S> MapStructure<K, V> for S
+where
+    S: BTreeMapStructure<K, V> + IterableSortedMapStructure<K, V>,
+{
+    fn dyn_iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(self.iter())
+    }
+
+    fn dyn_range(&self, key_range: (Bound<K>, Bound<K>)) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(self.range(key_range))
+    }
+
+    fn dyn_iter_upper_bound(&self, bound: &K) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(self.iter_upper_bound(bound))
+    }
+}
+
+impl<K, V, S> BTreeMapStructure<K, V> for Box<S>
+where
+    S: BTreeMapStructure<K, V> + ?Sized,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        (**self).get(key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        (**self).insert(key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        (**self).remove(key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        (**self).contains_key(key)
+    }
+
+    fn last_key_value(&self) -> Option<(K, V)> {
+        (**self).last_key_value()
+    }
+
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    fn clear(&mut self) {
+        (**self).clear()
+    }
+}
+
+impl<'m, K: Clone, V> IterableSortedMapStructure<K, V> for Box<dyn MapStructure<K, V> + 'm> {
+    type Iterator<'a> = Box<dyn Iterator<Item = (K, V)> + 'a> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.dyn_iter()
+    }
+
+    fn range(&self, key_range: impl RangeBounds<K>) -> Self::Iterator<'_> {
+        let start = key_range.start_bound().cloned();
+        let end = key_range.end_bound().cloned();
+        self.dyn_range((start, end))
+    }
+
+    fn iter_upper_bound(&self, bound: &K) -> Self::Iterator<'_> {
+        self.dyn_iter_upper_bound(bound)
+    }
+}
+
 pub trait CellStructure<T> {
     /// Returns reference to value stored in stable memory.
     fn get(&self) -> &T;
@@ -1,12 +1,16 @@
-use std::ops::RangeBounds;
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::ops::{Bound as RangeBound, RangeBounds};
 
+use dfinity_stable_structures::storable::Bound;
 use dfinity_stable_structures::{btreemap, Memory, Storable};
 
-use crate::structure::BTreeMapStructure;
-use crate::IterableSortedMapStructure;
+use crate::error::{check_encoded_size, check_size, panic_with_error};
+use crate::structure::{BTreeMapStructure, ReadOnly};
+use crate::{IterableSortedMapStructure, Result};
 
 /// Stores key-value data in stable memory.
-pub struct StableBTreeMap<K, V, M: Memory>(btreemap::BTreeMap<K, V, M>)
+pub struct StableBTreeMap<K, V, M: Memory>(btreemap::BTreeMap<K, LazyValue<V>, M>)
 where
     K: Storable + Ord + Clone,
     V: Storable;
@@ -22,9 +26,52 @@ where
         Self(btreemap::BTreeMap::init(memory))
     }
 
+    /// Returns a read-only view of the map, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     /// Iterate over all currently stored key-value pairs.
-    pub fn iter(&self) -> btreemap::Iter<'_, K, V, M> {
-        self.0.iter()
+    pub fn iter(&self) -> StableBTreeMapIter<'_, K, V, M> {
+        StableBTreeMapIter(self.iter_lazy())
+    }
+
+    /// Iterate over all currently stored key-value pairs, decoding the values only on
+    /// [`LazyValue::decode`], e.g. to collect the keys or to filter the entries by the keys.
+    pub fn iter_lazy(&self) -> StableBTreeMapLazyIter<'_, K, V, M> {
+        StableBTreeMapLazyIter(self.0.iter())
+    }
+
+    /// Iterate over the pairs with the keys in the range, decoding the values only on
+    /// [`LazyValue::decode`].
+    pub fn range_lazy(
+        &self,
+        key_range: impl RangeBounds<K>,
+    ) -> StableBTreeMapLazyIter<'_, K, V, M> {
+        StableBTreeMapLazyIter(self.0.range(key_range))
+    }
+
+    /// Iterate over all currently stored key-value pairs from the greatest key to the least one.
+    pub fn iter_rev(&self) -> StableBTreeMapRevIter<'_, K, V, M> {
+        self.range_rev(..)
+    }
+
+    /// Iterate over the pairs with the keys in the range from the greatest key to the least one,
+    /// e.g. to list the most recent entries of a map with time-ordered keys.
+    pub fn range_rev(&self, key_range: impl RangeBounds<K>) -> StableBTreeMapRevIter<'_, K, V, M> {
+        StableBTreeMapRevIter(RevIter::new(&self.0, key_range))
+    }
+
+    /// Add or replace the value associated with the key, or return [`crate::Error::ValueTooLarge`]
+    /// if the key or the value exceeds the max size of its type.
+    ///
+    /// The [`BTreeMapStructure::insert`] panics with the same error.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        check_size(&key)?;
+        let value = LazyValue::new(&value);
+        check_encoded_size::<V>(value.bytes())?;
+
+        Ok(self.0.insert(key, value).map(LazyValue::into_value))
     }
 }
 
@@ -35,15 +82,16 @@ where
     M: Memory,
 {
     fn get(&self, key: &K) -> Option<V> {
-        self.0.get(key)
+        self.0.get(key).map(LazyValue::into_value)
     }
 
     fn insert(&mut self, key: K, value: V) -> Option<V> {
-        self.0.insert(key, value)
+        self.try_insert(key, value)
+            .unwrap_or_else(|err| panic_with_error("failed to insert into the map", err))
     }
 
     fn remove(&mut self, key: &K) -> Option<V> {
-        self.0.remove(key)
+        self.0.remove(key).map(LazyValue::into_value)
     }
 
     fn len(&self) -> u64 {
@@ -68,7 +116,9 @@ where
     }
 
     fn last_key_value(&self) -> Option<(K, V)> {
-        self.0.last_key_value()
+        self.0
+            .last_key_value()
+            .map(|(key, value)| (key, value.into_value()))
     }
 }
 
@@ -78,18 +128,190 @@ where
     V: Storable,
     M: Memory,
 {
-    type Iterator<'a> = btreemap::Iter<'a, K, V, M> where Self: 'a;
+    type Iterator<'a> = StableBTreeMapIter<'a, K, V, M> where Self: 'a;
 
     fn iter(&self) -> Self::Iterator<'_> {
-        self.0.iter()
+        StableBTreeMapIter(self.iter_lazy())
     }
 
     fn range(&self, key_range: impl RangeBounds<K>) -> Self::Iterator<'_> {
-        self.0.range(key_range)
+        StableBTreeMapIter(self.range_lazy(key_range))
     }
 
     fn iter_upper_bound(&self, bound: &K) -> Self::Iterator<'_> {
-        self.0.iter_upper_bound(bound)
+        StableBTreeMapIter(StableBTreeMapLazyIter(self.0.iter_upper_bound(bound)))
+    }
+}
+
+/// The encoded value of a map entry, decoded on demand.
+///
+/// The value is stored in the same encoding as `V`, so the map can be read with `V` values by
+/// the structures not aware of the lazy decoding.
+pub struct LazyValue<V> {
+    bytes: Vec<u8>,
+    _value: PhantomData<V>,
+}
+
+impl<V: Storable> LazyValue<V> {
+    fn new(value: &V) -> Self {
+        Self {
+            bytes: value.to_bytes().into_owned(),
+            _value: PhantomData,
+        }
+    }
+
+    /// The encoded value.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn decode(&self) -> V {
+        V::from_bytes(Cow::Borrowed(&self.bytes))
+    }
+
+    pub fn into_value(self) -> V {
+        V::from_bytes(Cow::Owned(self.bytes))
+    }
+}
+
+impl<V: Storable> Storable for LazyValue<V> {
+    const BOUND: Bound = V::BOUND;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self {
+            bytes: bytes.into_owned(),
+            _value: PhantomData,
+        }
+    }
+}
+
+/// Iterator over the entries of [`StableBTreeMap`] with the values decoded on demand.
+pub struct StableBTreeMapLazyIter<'a, K, V, M>(btreemap::Iter<'a, K, LazyValue<V>, M>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory;
+
+impl<K, V, M> Iterator for StableBTreeMapLazyIter<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    type Item = (K, LazyValue<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+/// Iterator over the entries of [`StableBTreeMap`].
+pub struct StableBTreeMapIter<'a, K, V, M>(StableBTreeMapLazyIter<'a, K, V, M>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory;
+
+impl<K, V, M> Iterator for StableBTreeMapIter<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, value)| (key, value.into_value()))
+    }
+}
+
+/// Iterator over the entries of [`StableBTreeMap`] in the reverse order of the keys.
+pub struct StableBTreeMapRevIter<'a, K, V, M>(RevIter<'a, K, LazyValue<V>, M>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory;
+
+impl<K, V, M> Iterator for StableBTreeMapRevIter<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, value)| (key, value.into_value()))
+    }
+}
+
+/// Iterator over the entries of the inner map in the reverse order of the keys.
+///
+/// Every step finds the entry below the previous key, so the entries are read from the memory
+/// one by one, as by the forward iterators.
+pub(crate) struct RevIter<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    map: &'a btreemap::BTreeMap<K, V, M>,
+    start: RangeBound<K>,
+    /// The bound of the next entry, `None` when the iterator is exhausted.
+    end: Option<RangeBound<K>>,
+}
+
+impl<'a, K, V, M> RevIter<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    pub(crate) fn new(
+        map: &'a btreemap::BTreeMap<K, V, M>,
+        key_range: impl RangeBounds<K>,
+    ) -> Self {
+        Self {
+            map,
+            start: key_range.start_bound().cloned(),
+            end: Some(key_range.end_bound().cloned()),
+        }
+    }
+}
+
+impl<K, V, M> Iterator for RevIter<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match self.end.take()? {
+            RangeBound::Unbounded => self.map.last_key_value(),
+            RangeBound::Included(end) => match self.map.get(&end) {
+                Some(value) => Some((end, value)),
+                None => self.map.iter_upper_bound(&end).next(),
+            },
+            RangeBound::Excluded(end) => self.map.iter_upper_bound(&end).next(),
+        }?;
+
+        let in_range = match &self.start {
+            RangeBound::Included(start) => key >= *start,
+            RangeBound::Excluded(start) => key > *start,
+            RangeBound::Unbounded => true,
+        };
+        if !in_range {
+            return None;
+        }
+
+        self.end = Some(RangeBound::Excluded(key.clone()));
+        Some((key, value))
     }
 }
 
@@ -99,6 +321,37 @@ mod tests {
     use dfinity_stable_structures::VectorMemory;
 
     use super::*;
+    use crate::test_utils::BoundedString;
+    use crate::Error;
+
+    #[test]
+    fn oversized_entries_are_rejected() {
+        let mut map = StableBTreeMap::new(VectorMemory::default());
+        let name = BoundedString::<4>("name".to_string());
+
+        assert!(matches!(map.try_insert(1u32, name.clone()), Ok(None)));
+        let err = map
+            .try_insert(2, BoundedString("too long".to_string()))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ValueTooLarge {
+                type_name,
+                actual: 8,
+                max: 4,
+            } if type_name.ends_with("BoundedString<4>")
+        ));
+
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(&1), Some(name));
+    }
+
+    #[test]
+    #[should_panic(expected = "takes 8 bytes, but its max size is 4 bytes")]
+    fn insert_panics_with_the_size_error() {
+        let mut map = StableBTreeMap::new(VectorMemory::default());
+        map.insert(1u32, BoundedString::<4>("too long".to_string()));
+    }
 
     #[test]
     fn btreemap_works() {
@@ -127,6 +380,54 @@ mod tests {
         assert_eq!(map.len(), 1);
     }
 
+    #[test]
+    fn values_are_decoded_lazily() {
+        let memory = VectorMemory::default();
+        let mut map = StableBTreeMap::new(memory.clone());
+        for i in 0u32..10 {
+            map.insert(i, format!("value {i}"));
+        }
+
+        let keys: Vec<_> = map
+            .range_lazy(3..6)
+            .filter(|(key, _)| key % 2 == 1)
+            .map(|(key, value)| (key, value.decode()))
+            .collect();
+        assert_eq!(
+            keys,
+            [(3, "value 3".to_string()), (5, "value 5".to_string())]
+        );
+        assert_eq!(map.iter_lazy().nth(9).unwrap().1.bytes(), b"value 9");
+
+        // The values are stored in the encoding of `V`.
+        let map: dfinity_stable_structures::BTreeMap<u32, String, _> =
+            dfinity_stable_structures::BTreeMap::init(memory);
+        assert_eq!(map.get(&7), Some("value 7".to_string()));
+    }
+
+    #[test]
+    fn iterates_in_reverse() {
+        let mut map = StableBTreeMap::new(VectorMemory::default());
+        for i in 0u32..10 {
+            map.insert(i * 10, i);
+        }
+
+        assert_eq!(map.iter_rev().count(), 10);
+        assert_eq!(map.iter_rev().next(), Some((90, 9)));
+        assert_eq!(map.iter_rev().last(), Some((0, 0)));
+
+        let keys: Vec<_> = map.range_rev(15..=50).map(|(key, _)| key).collect();
+        assert_eq!(keys, [50, 40, 30, 20]);
+        let keys: Vec<_> = map.range_rev(..45).map(|(key, _)| key).take(2).collect();
+        assert_eq!(keys, [40, 30]);
+        let keys: Vec<_> = map
+            .range_rev((RangeBound::Excluded(70), RangeBound::Unbounded))
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, [90, 80]);
+        assert_eq!(map.range_rev(41..49).next(), None);
+    }
+
     #[test]
     fn test_last_key_value() {
         let mut map = StableBTreeMap::new(VectorMemory::default());
@@ -0,0 +1,444 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+use std::ops::{Bound as RangeBound, RangeBounds};
+use std::rc::Rc;
+
+use dfinity_stable_structures::storable::Bound;
+use dfinity_stable_structures::{btreemap, Memory, Storable};
+
+use crate::structure::{BTreeMapStructure, CellStructure, IterableSortedMapStructure};
+use crate::Result;
+
+/// Max size of the keys of the maps kept in [`MemoryBuckets`].
+pub const MAX_BUCKET_KEY_SIZE: u32 = 128;
+
+type BucketId = u16;
+const BUCKET_ID_LEN: usize = std::mem::size_of::<BucketId>();
+
+/// Several small structures kept in one memory, so that they use one `MemoryId` of the memory
+/// manager instead of a `MemoryId` per structure.
+///
+/// Every structure is kept in its bucket, identified by an id chosen by the canister like a
+/// `MemoryId`. The entries of all the buckets are stored in one map, with the keys prefixed by
+/// the id of their bucket, so a bucket can't be used by two structures.
+///
+/// ```
+/// use ic_stable_structures::{BTreeMapStructure, CellStructure, MemoryBuckets, VectorMemory};
+///
+/// let buckets = MemoryBuckets::new(VectorMemory::default());
+/// let mut fee = buckets.cell(0, 10u64);
+/// let mut balances = buckets.btreemap::<u32, u64>(1);
+///
+/// fee.set(20).unwrap();
+/// balances.insert(1, 100);
+/// assert_eq!(*fee.get(), 20);
+/// assert_eq!(balances.get(&1), Some(100));
+/// ```
+pub struct MemoryBuckets<M: Memory> {
+    entries: Rc<RefCell<btreemap::BTreeMap<BucketKey, Vec<u8>, M>>>,
+    used: Rc<RefCell<BTreeSet<BucketId>>>,
+}
+
+impl<M: Memory> Clone for MemoryBuckets<M> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            used: self.used.clone(),
+        }
+    }
+}
+
+impl<M: Memory> MemoryBuckets<M> {
+    /// Create the buckets in the memory. If the memory contains buckets, their data is kept.
+    pub fn new(memory: M) -> Self {
+        Self {
+            entries: Rc::new(RefCell::new(btreemap::BTreeMap::init(memory))),
+            used: Rc::default(),
+        }
+    }
+
+    /// Map in the bucket.
+    ///
+    /// The entries are ordered by the bytes of the keys, which is the order of `K` for the
+    /// integers and the strings.
+    ///
+    /// # Panics
+    ///   - if the bucket is already used by another structure;
+    ///   - if the max size of `K` exceeds [`MAX_BUCKET_KEY_SIZE`].
+    pub fn btreemap<K: Storable, V: Storable>(&self, bucket: BucketId) -> BucketBTreeMap<K, V, M> {
+        match K::BOUND {
+            Bound::Bounded { max_size, .. } if max_size <= MAX_BUCKET_KEY_SIZE => {}
+            _ => panic!("bucket map keys must be bounded by {MAX_BUCKET_KEY_SIZE} bytes"),
+        }
+        self.use_bucket(bucket);
+
+        BucketBTreeMap {
+            bucket,
+            entries: self.entries.clone(),
+            _p: PhantomData,
+        }
+    }
+
+    /// Cell in the bucket, containing the `value` if the bucket is empty.
+    ///
+    /// # Panics
+    ///   - if the bucket is already used by another structure.
+    pub fn cell<T: Storable>(&self, bucket: BucketId, value: T) -> BucketCell<T, M> {
+        self.use_bucket(bucket);
+
+        let key = BucketKey::new(bucket, &[]);
+        let value = match self.entries.borrow().get(&key) {
+            Some(bytes) => T::from_bytes(Cow::Owned(bytes)),
+            None => value,
+        };
+        let mut cell = BucketCell {
+            key,
+            value,
+            entries: self.entries.clone(),
+        };
+        cell.write();
+        cell
+    }
+
+    fn use_bucket(&self, bucket: BucketId) {
+        if !self.used.borrow_mut().insert(bucket) {
+            panic!("bucket {bucket} is already used");
+        }
+    }
+}
+
+/// Key of an entry of [`MemoryBuckets`].
+///
+/// # Memory layout
+/// ```ignore
+/// |-- bucket_id --|-- key_bytes --|
+/// ```
+///
+/// where `bucket_id` is stored in the big-endian format to keep the entries of a bucket together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct BucketKey(Vec<u8>);
+
+impl BucketKey {
+    fn new(bucket: BucketId, key: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(BUCKET_ID_LEN + key.len());
+        data.extend_from_slice(&bucket.to_be_bytes());
+        data.extend_from_slice(key);
+        Self(data)
+    }
+
+    fn from_key<K: Storable>(bucket: BucketId, key: &K) -> Self {
+        Self::new(bucket, &key.to_bytes())
+    }
+
+    fn bucket(&self) -> BucketId {
+        BucketId::from_be_bytes([self.0[0], self.0[1]])
+    }
+
+    fn key<K: Storable>(&self) -> K {
+        K::from_bytes(Cow::Borrowed(&self.0[BUCKET_ID_LEN..]))
+    }
+
+    /// The bound below all the keys of the next buckets.
+    fn bucket_end(bucket: BucketId) -> RangeBound<Self> {
+        match bucket.checked_add(1) {
+            Some(next) => RangeBound::Excluded(Self::new(next, &[])),
+            None => RangeBound::Unbounded,
+        }
+    }
+}
+
+impl Storable for BucketKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: BUCKET_ID_LEN as u32 + MAX_BUCKET_KEY_SIZE,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+/// Map kept in a bucket of [`MemoryBuckets`].
+///
+/// The map doesn't keep the count of its entries, so `len()` iterates over the bucket.
+pub struct BucketBTreeMap<K, V, M: Memory> {
+    bucket: BucketId,
+    entries: Rc<RefCell<btreemap::BTreeMap<BucketKey, Vec<u8>, M>>>,
+    _p: PhantomData<(K, V)>,
+}
+
+impl<K: Storable, V: Storable, M: Memory> BucketBTreeMap<K, V, M> {
+    fn iter_range(
+        &self,
+        start: RangeBound<BucketKey>,
+        end: RangeBound<BucketKey>,
+    ) -> BucketBTreeMapIter<K, V, M> {
+        BucketBTreeMapIter {
+            entries: self.entries.clone(),
+            start: Some(start),
+            end,
+            _p: PhantomData,
+        }
+    }
+
+    fn bucket_start(&self) -> RangeBound<BucketKey> {
+        RangeBound::Included(BucketKey::new(self.bucket, &[]))
+    }
+
+    fn keys(&self) -> Vec<BucketKey> {
+        self.entries
+            .borrow()
+            .range((self.bucket_start(), BucketKey::bucket_end(self.bucket)))
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+impl<K: Storable, V: Storable, M: Memory> BTreeMapStructure<K, V> for BucketBTreeMap<K, V, M> {
+    fn get(&self, key: &K) -> Option<V> {
+        self.entries
+            .borrow()
+            .get(&BucketKey::from_key(self.bucket, key))
+            .map(|bytes| V::from_bytes(Cow::Owned(bytes)))
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.entries
+            .borrow_mut()
+            .insert(
+                BucketKey::from_key(self.bucket, &key),
+                value.to_bytes().into_owned(),
+            )
+            .map(|bytes| V::from_bytes(Cow::Owned(bytes)))
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries
+            .borrow_mut()
+            .remove(&BucketKey::from_key(self.bucket, key))
+            .map(|bytes| V::from_bytes(Cow::Owned(bytes)))
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.entries
+            .borrow()
+            .contains_key(&BucketKey::from_key(self.bucket, key))
+    }
+
+    fn last_key_value(&self) -> Option<(K, V)> {
+        let entries = self.entries.borrow();
+        let (key, value) = match BucketKey::bucket_end(self.bucket) {
+            RangeBound::Excluded(end) => entries.iter_upper_bound(&end).next(),
+            _ => entries.last_key_value(),
+        }?;
+
+        (key.bucket() == self.bucket).then(|| (key.key(), V::from_bytes(Cow::Owned(value))))
+    }
+
+    fn len(&self) -> u64 {
+        self.entries
+            .borrow()
+            .range((self.bucket_start(), BucketKey::bucket_end(self.bucket)))
+            .count() as u64
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries
+            .borrow()
+            .range((self.bucket_start(), BucketKey::bucket_end(self.bucket)))
+            .next()
+            .is_none()
+    }
+
+    fn clear(&mut self) {
+        let keys = self.keys();
+        let mut entries = self.entries.borrow_mut();
+        for key in keys {
+            entries.remove(&key);
+        }
+    }
+}
+
+impl<K: Storable, V: Storable, M: Memory> IterableSortedMapStructure<K, V>
+    for BucketBTreeMap<K, V, M>
+{
+    type Iterator<'a> = BucketBTreeMapIter<K, V, M> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        self.iter_range(self.bucket_start(), BucketKey::bucket_end(self.bucket))
+    }
+
+    fn range(&self, key_range: impl RangeBounds<K>) -> Self::Iterator<'_> {
+        let start = match key_range.start_bound() {
+            RangeBound::Included(key) => {
+                RangeBound::Included(BucketKey::from_key(self.bucket, key))
+            }
+            RangeBound::Excluded(key) => {
+                RangeBound::Excluded(BucketKey::from_key(self.bucket, key))
+            }
+            RangeBound::Unbounded => self.bucket_start(),
+        };
+        let end = match key_range.end_bound() {
+            RangeBound::Included(key) => {
+                RangeBound::Included(BucketKey::from_key(self.bucket, key))
+            }
+            RangeBound::Excluded(key) => {
+                RangeBound::Excluded(BucketKey::from_key(self.bucket, key))
+            }
+            RangeBound::Unbounded => BucketKey::bucket_end(self.bucket),
+        };
+        self.iter_range(start, end)
+    }
+
+    fn iter_upper_bound(&self, bound: &K) -> Self::Iterator<'_> {
+        let below = self
+            .entries
+            .borrow()
+            .iter_upper_bound(&BucketKey::from_key(self.bucket, bound))
+            .next()
+            .map(|(key, _)| key)
+            .filter(|key| key.bucket() == self.bucket);
+
+        match below {
+            Some(key) => self.iter_range(
+                RangeBound::Included(key),
+                BucketKey::bucket_end(self.bucket),
+            ),
+            None => BucketBTreeMapIter {
+                entries: self.entries.clone(),
+                start: None,
+                end: RangeBound::Unbounded,
+                _p: PhantomData,
+            },
+        }
+    }
+}
+
+/// Iterator over the entries of [`BucketBTreeMap`].
+///
+/// The iterator doesn't borrow the map between the `next()` calls, every call finds the entry
+/// after the previous one.
+pub struct BucketBTreeMapIter<K, V, M: Memory> {
+    entries: Rc<RefCell<btreemap::BTreeMap<BucketKey, Vec<u8>, M>>>,
+    /// The bound of the next entry, `None` when the iterator is exhausted.
+    start: Option<RangeBound<BucketKey>>,
+    end: RangeBound<BucketKey>,
+    _p: PhantomData<(K, V)>,
+}
+
+impl<K: Storable, V: Storable, M: Memory> Iterator for BucketBTreeMapIter<K, V, M> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.start.take()?;
+        let (key, value) = self
+            .entries
+            .borrow()
+            .range((start, self.end.clone()))
+            .next()?;
+
+        let item = (key.key(), V::from_bytes(Cow::Owned(value)));
+        self.start = Some(RangeBound::Excluded(key));
+        Some(item)
+    }
+}
+
+/// Cell kept in a bucket of [`MemoryBuckets`].
+pub struct BucketCell<T: Storable, M: Memory> {
+    key: BucketKey,
+    value: T,
+    entries: Rc<RefCell<btreemap::BTreeMap<BucketKey, Vec<u8>, M>>>,
+}
+
+impl<T: Storable, M: Memory> BucketCell<T, M> {
+    fn write(&mut self) {
+        self.entries
+            .borrow_mut()
+            .insert(self.key.clone(), self.value.to_bytes().into_owned());
+    }
+}
+
+impl<T: Storable, M: Memory> CellStructure<T> for BucketCell<T, M> {
+    fn get(&self) -> &T {
+        &self.value
+    }
+
+    fn set(&mut self, value: T) -> Result<()> {
+        self.value = value;
+        self.write();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfinity_stable_structures::VectorMemory;
+
+    use super::*;
+
+    #[test]
+    fn buckets_are_separated() {
+        let memory = VectorMemory::default();
+        let buckets = MemoryBuckets::new(memory.clone());
+        let mut first = buckets.btreemap::<u32, String>(1);
+        let mut second = buckets.btreemap::<u32, String>(2);
+        let mut last = buckets.btreemap::<u32, u64>(u16::MAX);
+        let mut counter = buckets.cell(0, 0u64);
+
+        for i in 0..5 {
+            first.insert(i, format!("first {i}"));
+            second.insert(i * 10, format!("second {i}"));
+            last.insert(i, i as u64);
+        }
+        counter.set(5).unwrap();
+        assert_eq!(second.remove(&40), Some("second 4".to_string()));
+
+        assert_eq!(first.len(), 5);
+        assert_eq!(second.len(), 4);
+        assert_eq!(first.get(&3), Some("first 3".to_string()));
+        assert!(!first.contains_key(&30));
+        assert_eq!(first.last_key_value(), Some((4, "first 4".to_string())));
+        assert_eq!(last.last_key_value(), Some((4, 4)));
+        assert_eq!(
+            second.range(5..=20).map(|(key, _)| key).collect::<Vec<_>>(),
+            [10, 20]
+        );
+        assert_eq!(
+            second
+                .iter_upper_bound(&15)
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>(),
+            [10, 20, 30]
+        );
+        assert_eq!(second.iter_upper_bound(&0).next(), None);
+
+        // The iterator doesn't borrow the map between the steps.
+        for (key, _) in first.iter() {
+            first.remove(&key);
+        }
+        assert!(first.is_empty());
+        assert_eq!(first.last_key_value(), None);
+        second.clear();
+        assert!(second.is_empty());
+        assert_eq!(last.len(), 5);
+
+        // The buckets are restored from the memory.
+        let buckets = MemoryBuckets::new(memory);
+        assert_eq!(*buckets.cell(0, 0u64).get(), 5);
+        assert_eq!(buckets.btreemap::<u32, u64>(u16::MAX).get(&2), Some(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket 1 is already used")]
+    fn bucket_is_used_once() {
+        let buckets = MemoryBuckets::new(VectorMemory::default());
+        let _map = buckets.btreemap::<u32, u32>(1);
+        let _cell = buckets.cell(1, 0u32);
+    }
+}
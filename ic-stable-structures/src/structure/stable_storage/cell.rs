@@ -1,6 +1,7 @@
 use dfinity_stable_structures::{cell, Memory, Storable};
 
-use crate::structure::CellStructure;
+use crate::error::check_size;
+use crate::structure::{CellStructure, ReadOnly};
 use crate::Result;
 
 /// Stores value in stable memory, providing `get()/set()` API.
@@ -9,8 +10,14 @@ pub struct StableCell<T: Storable, M: Memory>(cell::Cell<T, M>);
 impl<T: Storable, M: Memory> StableCell<T, M> {
     /// Create new storage for values with `T` type.
     pub fn new(memory: M, value: T) -> Result<Self> {
+        check_size(&value)?;
         Ok(Self(cell::Cell::init(memory, value)?))
     }
+
+    /// Returns a read-only view of the cell, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
 }
 
 impl<T: Storable, M: Memory> CellStructure<T> for StableCell<T, M> {
@@ -19,6 +26,7 @@ impl<T: Storable, M: Memory> CellStructure<T> for StableCell<T, M> {
     }
 
     fn set(&mut self, value: T) -> Result<()> {
+        check_size(&value)?;
         self.0.set(value)?;
         Ok(())
     }
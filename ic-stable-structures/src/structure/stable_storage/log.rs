@@ -1,6 +1,6 @@
 use dfinity_stable_structures::{log, Memory, Storable};
 
-use crate::structure::LogStructure;
+use crate::structure::{LogStructure, ReadOnly};
 use crate::{Error, Result};
 
 /// Stores list of immutable values in stable memory.
@@ -14,6 +14,11 @@ impl<T: Storable, M: Memory> StableLog<T, M> {
         Ok(Self(Some(log::Log::init(index_memory, data_memory)?)))
     }
 
+    /// Returns a read-only view of the log, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     fn get_inner(&self) -> &log::Log<T, M, M> {
         self.0.as_ref().expect("inner log is always present")
     }
@@ -1,13 +1,25 @@
 mod btreemap;
+mod bucket;
 mod cell;
 mod log;
-mod multimap;
-mod unbounded;
+pub(crate) mod multimap;
+pub(crate) mod unbounded;
 mod vec;
 
-pub use btreemap::StableBTreeMap;
+pub use btreemap::{
+    LazyValue, StableBTreeMap, StableBTreeMapIter, StableBTreeMapLazyIter, StableBTreeMapRevIter,
+};
+pub use bucket::{
+    BucketBTreeMap, BucketBTreeMapIter, BucketCell, MemoryBuckets, MAX_BUCKET_KEY_SIZE,
+};
 pub use cell::StableCell;
 pub use log::StableLog;
-pub use multimap::{StableMultimap, StableMultimapIter, StableMultimapRangeIter};
-pub use unbounded::{StableUnboundedIter, StableUnboundedMap};
+pub use multimap::{
+    StableMultimap, StableMultimapIter, StableMultimapRangeIter, StableMultimapRangeRevIter,
+    StableMultimapRevIter,
+};
+pub use unbounded::{
+    CompactionProgress, CompactionStats, StableUnboundedIter, StableUnboundedMap,
+    StableUnboundedValueReader,
+};
 pub use vec::StableVec;
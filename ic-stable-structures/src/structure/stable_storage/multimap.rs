@@ -4,8 +4,10 @@ use std::marker::PhantomData;
 use dfinity_stable_structures::storable::Bound;
 use dfinity_stable_structures::{btreemap, Memory, StableBTreeMap, Storable};
 
-use crate::structure::MultimapStructure;
-use crate::Bounds;
+use super::btreemap::RevIter;
+use crate::error::{check_encoded_size, check_size, panic_with_error};
+use crate::structure::{MultimapStructure, ReadOnly};
+use crate::{Bounds, Result};
 
 // Keys memory layout:
 //
@@ -51,6 +53,11 @@ where
         Self(StableBTreeMap::init(memory))
     }
 
+    /// Returns a read-only view of the map, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     /// Returns upper bound iterator for the given pair of keys.
     pub fn iter_upper_bound(
         &self,
@@ -60,6 +67,34 @@ where
         let bound = KeyPair::new(first_key, second_key);
         StableMultimapIter::new(self.0.iter_upper_bound(&bound))
     }
+
+    /// Iterator over all items in the map from the greatest pair of keys to the least one.
+    pub fn iter_rev(&self) -> StableMultimapRevIter<'_, K1, K2, V, M> {
+        StableMultimapRevIter(RevIter::new(&self.0, ..))
+    }
+
+    /// Iterator over the entries that correspond to the `first_key` from the greatest second key
+    /// to the least one.
+    pub fn range_rev(&self, first_key: &K1) -> StableMultimapRangeRevIter<'_, K1, K2, V, M> {
+        let min_key = KeyPair::<K1, K2>::min_key(first_key);
+        let max_key = KeyPair::<K1, K2>::max_key(first_key);
+
+        StableMultimapRangeRevIter(RevIter::new(&self.0, min_key..=max_key))
+    }
+
+    /// Insert a new value into the map, or return [`crate::Error::ValueTooLarge`] if one of the
+    /// keys or the value exceeds the max size of its type.
+    ///
+    /// The [`MultimapStructure::insert`] panics with the same error.
+    pub fn try_insert(&mut self, first_key: &K1, second_key: &K2, value: &V) -> Result<Option<V>> {
+        check_size(first_key)?;
+        check_size(second_key)?;
+        let value = Value::from(value);
+        check_encoded_size::<V>(&value.0)?;
+
+        let key = KeyPair::new(first_key, second_key);
+        Ok(self.0.insert(key, value).map(|v| v.into_inner()))
+    }
 }
 
 impl<K1, K2, V, M> MultimapStructure<K1, K2, V> for StableMultimap<K1, K2, V, M>
@@ -74,8 +109,8 @@ where
     type RangeIterator<'a> = StableMultimapRangeIter<'a, K1, K2, V, M> where Self: 'a;
 
     fn insert(&mut self, first_key: &K1, second_key: &K2, value: &V) -> Option<V> {
-        let key = KeyPair::new(first_key, second_key);
-        self.0.insert(key, value.into()).map(|v| v.into_inner())
+        self.try_insert(first_key, second_key, value)
+            .unwrap_or_else(|err| panic_with_error("failed to insert into the multimap", err))
     }
 
     fn get(&self, first_key: &K1, second_key: &K2) -> Option<V> {
@@ -134,7 +169,7 @@ where
     }
 }
 
-struct KeyPair<K1, K2> {
+pub(crate) struct KeyPair<K1, K2> {
     encoded: Vec<u8>,
     _p: PhantomData<(K1, K2)>,
 }
@@ -376,6 +411,54 @@ where
     }
 }
 
+/// Range iterator in the reverse order of the second keys.
+pub struct StableMultimapRangeRevIter<'a, K1, K2, V, M>(RevIter<'a, KeyPair<K1, K2>, Value<V>, M>)
+where
+    K1: Storable,
+    K2: Storable,
+    V: Storable,
+    M: Memory;
+
+impl<K1, K2, V, M> Iterator for StableMultimapRangeRevIter<'_, K1, K2, V, M>
+where
+    K1: Storable,
+    K2: Storable,
+    V: Storable,
+    M: Memory,
+{
+    type Item = (K2, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next()
+            .map(|(keys, v)| (keys.second_key(), v.into_inner()))
+    }
+}
+
+/// Iterator over all items in the reverse order of the keys.
+pub struct StableMultimapRevIter<'a, K1, K2, V, M>(RevIter<'a, KeyPair<K1, K2>, Value<V>, M>)
+where
+    K1: Storable,
+    K2: Storable,
+    V: Storable,
+    M: Memory;
+
+impl<K1, K2, V, M> Iterator for StableMultimapRevIter<'_, K1, K2, V, M>
+where
+    K1: Storable,
+    K2: Storable,
+    V: Storable,
+    M: Memory,
+{
+    type Item = (K1, K2, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next()
+            .map(|(keys, val)| (keys.first_key(), keys.second_key(), val.into_inner()))
+    }
+}
+
 impl<'a, K1, K2, V, M> IntoIterator for &'a StableMultimap<K1, K2, V, M>
 where
     K1: Storable,
@@ -623,6 +706,17 @@ mod test {
         assert!(!map.remove_partial(&0));
         assert_eq!(map.len(), 2);
 
+        let mut iter = map.iter_rev();
+        assert_eq!(iter.next(), Some((1, 1, 20)));
+        assert_eq!(iter.next(), Some((1, 0, 10)));
+        assert_eq!(iter.next(), None);
+
+        let mut range = map.range_rev(&1);
+        assert_eq!(range.next(), Some((1, 20)));
+        assert_eq!(range.next(), Some((0, 10)));
+        assert_eq!(range.next(), None);
+        assert_eq!(map.range_rev(&0).next(), None);
+
         assert_eq!(map.remove(&1, &0), Some(10));
         assert_eq!(map.iter().next(), Some((1, 1, 20)));
         assert_eq!(map.len(), 1);
@@ -1,29 +1,41 @@
 use std::borrow::Cow;
 use std::iter::Peekable;
 use std::marker::PhantomData;
-use std::mem;
+use std::ops::Bound as RangeBound;
+use std::rc::Rc;
+use std::{io, mem};
 
 use dfinity_stable_structures::storable::Bound;
 use dfinity_stable_structures::{btreemap, Memory, StableBTreeMap, Storable};
 
-use crate::structure::{IterableUnboundedMapStructure, UnboundedMapStructure};
+use crate::structure::{IterableUnboundedMapStructure, ReadOnly, UnboundedMapStructure};
 use crate::{Bounds, SlicedStorable};
 
 type ChunkIndex = u16;
 const CHUNK_INDEX_LEN: usize = mem::size_of::<ChunkIndex>();
 
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// The chunks of the values, in the memory of the map or in the scratch memory of a compaction.
+type Chunks<K, V, M> = StableBTreeMap<Key<K>, Chunk<V>, MapMemory<M>>;
+
 /// Map that allows to store values with arbitrary size in stable memory.
 ///
 /// Current implementation stores values in chunks with fixed size.
 /// Size of chunk should be set using the [`SlicedStorable`] trait.
+///
+/// After many removals the chunks of the live values are scattered over the memory of the map,
+/// which can be compacted with [`StableUnboundedMap::start_compaction`] and
+/// [`StableUnboundedMap::compact`].
 pub struct StableUnboundedMap<K, V, M>
 where
     K: Storable,
     V: SlicedStorable,
     M: Memory,
 {
-    inner: StableBTreeMap<Key<K>, Chunk<V>, M>,
+    inner: Chunks<K, V, M>,
     items_count: u64,
+    compaction: Option<Compaction<K, V, M>>,
 }
 
 impl<K, V, M> StableUnboundedMap<K, V, M>
@@ -36,25 +48,164 @@ where
     ///
     /// If the `memory` contains data of the map, the map reads it, and the instance
     /// will contain the data from the `memory`.
+    ///
+    /// A map which is compacted must be created with [`StableUnboundedMap::with_scratch`]
+    /// instead, otherwise the values are lost if a compaction was interrupted by an upgrade.
     pub fn new(memory: M) -> Self {
         let _ = Key::<K>::BOUNDS;
         Self {
-            inner: StableBTreeMap::init(memory),
+            inner: StableBTreeMap::init(MapMemory::new(memory)),
             items_count: 0,
+            compaction: None,
         }
     }
 
-    fn insert_data(&mut self, key: &mut Key<K>, value: &V) {
-        let value_bytes = value.to_bytes();
-        let chunks = value_bytes.chunks(V::CHUNK_SIZE as _);
+    /// Create new instance of the map like [`StableUnboundedMap::new`], and resume the
+    /// compaction which was in progress in the `scratch` memory, e.g. before an upgrade.
+    pub fn with_scratch(memory: M, scratch: M) -> Self {
+        let _ = Key::<K>::BOUNDS;
+        let memory = MapMemory::new(memory);
+        let scratch = Rc::new(scratch);
+        let Some(state) = CompactionState::load(scratch.as_ref()) else {
+            return Self {
+                inner: StableBTreeMap::init(memory),
+                items_count: 0,
+                compaction: None,
+            };
+        };
+
+        let scratch_map = StableBTreeMap::init(MapMemory::scratch(scratch.clone()));
+        // In the second pass the map is read from the scratch memory, while its own memory
+        // is rewritten.
+        let (inner, target) = match state.pages_before {
+            None => (StableBTreeMap::init(memory), scratch_map),
+            Some(_) => (scratch_map, StableBTreeMap::init(memory)),
+        };
 
-        for chunk in chunks {
-            let chunk = Chunk::new(chunk.to_vec());
-            self.inner.insert(key.clone(), chunk);
-            key.increase_chunk_index();
+        Self {
+            inner,
+            items_count: 0,
+            compaction: Some(Compaction {
+                target,
+                cursor: state.cursor.map(|cursor| Key::from_bytes(cursor.into())),
+                copied_chunks: state.copied_chunks,
+                pages_before: state.pages_before,
+                scratch,
+            }),
         }
+    }
 
-        self.items_count += 1;
+    /// Returns a read-only view of the map, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
+    /// Start the compaction of the map, using the `scratch` memory for a copy of the live values.
+    /// Returns `false` if a compaction is already in progress.
+    ///
+    /// The compaction is done by the [`StableUnboundedMap::compact`] calls in two passes: the
+    /// live values are copied to the scratch memory, and then back to the memory of the map,
+    /// which is rewritten from its start. The map can be read and changed between the calls.
+    ///
+    /// The progress of the compaction is kept in the first page of the scratch memory, so the
+    /// map must be created with [`StableUnboundedMap::with_scratch`] after an upgrade to resume
+    /// the compaction.
+    pub fn start_compaction(&mut self, scratch: M) -> bool {
+        if self.compaction.is_some() {
+            return false;
+        }
+
+        let bounds = Key::<K>::BOUNDS;
+        assert!(
+            bounds.size_prefix_len + bounds.max_size + CHUNK_INDEX_LEN
+                <= CompactionState::MAX_CURSOR_LEN,
+            "the keys of the map are too big for the compaction"
+        );
+
+        let scratch = Rc::new(scratch);
+        let compaction = Compaction {
+            target: StableBTreeMap::new(MapMemory::scratch(scratch.clone())),
+            cursor: None,
+            copied_chunks: 0,
+            pages_before: None,
+            scratch,
+        };
+        compaction.save();
+        self.compaction = Some(compaction);
+        true
+    }
+
+    /// Returns `true` if the map is being compacted.
+    pub fn is_compacting(&self) -> bool {
+        self.compaction.is_some()
+    }
+
+    /// Copy up to `max_items_per_call` values of the compaction started with
+    /// [`StableUnboundedMap::start_compaction`], so that the compaction can be done by a
+    /// periodic task without hitting the instructions limit.
+    pub fn compact(&mut self, max_items_per_call: usize) -> CompactionProgress {
+        let Some(compaction) = self.compaction.as_mut() else {
+            return CompactionProgress::Idle;
+        };
+
+        let copied = compaction.copy_batch(&self.inner, max_items_per_call);
+        compaction.save();
+        if !copied {
+            return CompactionProgress::InProgress {
+                pass: compaction.pass(),
+                copied_chunks: compaction.copied_chunks,
+            };
+        }
+
+        let mut compaction = self.compaction.take().expect("compaction is in progress");
+        mem::swap(&mut self.inner, &mut compaction.target);
+        let Compaction {
+            target: previous,
+            pages_before,
+            scratch,
+            ..
+        } = compaction;
+
+        match pages_before {
+            // The map is read from the scratch memory, while its own memory is rewritten.
+            None => {
+                let memory = previous.into_memory();
+                let compaction = Compaction {
+                    pages_before: Some(memory.size()),
+                    target: StableBTreeMap::new(memory),
+                    cursor: None,
+                    copied_chunks: 0,
+                    scratch,
+                };
+                compaction.save();
+                self.compaction = Some(compaction);
+                CompactionProgress::InProgress {
+                    pass: 2,
+                    copied_chunks: 0,
+                }
+            }
+            Some(pages_before) => {
+                let previous_scratch = scratch;
+                let scratch = previous.into_memory();
+                let pages_after = scratch.size();
+                // Leave an empty map in the scratch memory.
+                StableBTreeMap::<Key<K>, Chunk<V>, _>::new(scratch);
+                CompactionState::clear(previous_scratch.as_ref());
+
+                CompactionProgress::Done(CompactionStats {
+                    chunks: self.inner.len(),
+                    pages_before,
+                    pages_after,
+                })
+            }
+        }
+    }
+
+    /// The copy of the compaction, if it already contains the value of the key.
+    fn compaction_target(&mut self, key: &K) -> Option<&mut Chunks<K, V, M>> {
+        let compaction = self.compaction.as_mut()?;
+        let cursor = compaction.cursor.as_ref()?;
+        (Key::new(key) <= *cursor).then_some(&mut compaction.target)
     }
 
     /// Iterator for all stored key-value pairs.
@@ -80,6 +231,67 @@ where
             }
         }
     }
+
+    /// Iterator for all stored keys with the encoded values, e.g. to find the values which
+    /// can't be decoded without trapping.
+    pub fn iter_encoded(&self) -> impl Iterator<Item = (K, Vec<u8>)> + '_ {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || iter.next_encoded())
+    }
+
+    /// Removes the value without decoding it, and returns its encoded bytes, e.g. to remove a
+    /// value which can't be decoded.
+    pub fn remove_encoded(&mut self, key: &K) -> Option<Vec<u8>> {
+        let value_bytes = remove_chunks(&mut self.inner, key)?;
+        self.items_count -= 1;
+
+        if let Some(target) = self.compaction_target(key) {
+            remove_chunks(target, key);
+        }
+
+        Some(value_bytes)
+    }
+
+    /// Pass the chunks of the value to the `visitor` one by one, without loading the whole
+    /// value in the heap. Returns `false` if there is no value for the key.
+    pub fn get_chunked(&self, key: &K, mut visitor: impl FnMut(&[u8])) -> bool {
+        let first_chunk_key = Key::new(key);
+        let max_chunk_key = first_chunk_key.clone().with_max_chunk_index();
+
+        let mut item_present = false;
+        for (_, chunk) in self.inner.range(first_chunk_key..=max_chunk_key) {
+            visitor(chunk.data());
+            item_present = true;
+        }
+        item_present
+    }
+
+    /// Returns the length of the encoded value.
+    pub fn value_len(&self, key: &K) -> Option<u64> {
+        let max_chunk_key = Key::new(key).with_max_chunk_index();
+        let (last_key, last_chunk) = match self.inner.get(&max_chunk_key) {
+            Some(chunk) => (max_chunk_key, chunk),
+            None => self
+                .inner
+                .iter_upper_bound(&max_chunk_key)
+                .next()
+                .filter(|(last_key, _)| last_key.prefix() == max_chunk_key.prefix())?,
+        };
+
+        Some(last_key.chunk_index() as u64 * V::CHUNK_SIZE as u64 + last_chunk.data().len() as u64)
+    }
+
+    /// Returns a reader of the encoded value, which loads one chunk at a time, e.g. to serve a
+    /// big value with the streaming callbacks of the HTTP gateway.
+    pub fn reader(&self, key: &K) -> Option<StableUnboundedValueReader<'_, K, V, M>> {
+        let len = self.value_len(key)?;
+        Some(StableUnboundedValueReader {
+            map: &self.inner,
+            key: Key::new(key),
+            len,
+            position: 0,
+        })
+    }
 }
 
 impl<K, V, M> UnboundedMapStructure<K, V> for StableUnboundedMap<K, V, M>
@@ -132,35 +344,19 @@ where
         // remove old data before insert new();
         let previous_value = self.remove(key);
 
-        self.insert_data(&mut Key::new(key), value);
+        insert_chunks(&mut self.inner, key, value);
+        self.items_count += 1;
+
+        if let Some(target) = self.compaction_target(key) {
+            insert_chunks(target, key, value);
+        }
 
         previous_value
     }
 
     fn remove(&mut self, key: &K) -> Option<V> {
-        let first_chunk_key = Key::new(key);
-        let max_chunk_key = first_chunk_key.clone().with_max_chunk_index();
-        let keys: Vec<Key<K>> = self
-            .inner
-            .range(first_chunk_key..=max_chunk_key)
-            .map(|(k, _)| k)
-            .collect();
-
-        if keys.is_empty() {
-            return None;
-        }
-
-        let mut value_bytes = Vec::new();
-        for key in &keys {
-            // We have got keys from the map, so they are present.
-            // If something goes wrong, panic will help to avoid partly-removed items.
-            let chunk = self.inner.remove(key).expect("the key present");
-            value_bytes.extend_from_slice(chunk.data());
-        }
-
-        self.items_count -= 1;
-
-        Some(V::from_bytes(value_bytes.into()))
+        self.remove_encoded(key)
+            .map(|value_bytes| V::from_bytes(value_bytes.into()))
     }
 
     fn len(&self) -> u64 {
@@ -176,14 +372,307 @@ where
     }
 
     fn clear(&mut self) {
-        let keys: Vec<_> = self.inner.iter().map(|(k, _)| k).collect();
-        for key in keys {
-            self.inner.remove(&key);
+        clear_chunks(&mut self.inner);
+        if let Some(compaction) = &mut self.compaction {
+            clear_chunks(&mut compaction.target);
         }
         self.items_count = 0;
     }
 }
 
+fn insert_chunks<K, V, M>(map: &mut Chunks<K, V, M>, key: &K, value: &V)
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    let mut key = Key::new(key);
+    let value_bytes = value.to_bytes();
+    let mut chunks = value_bytes.chunks(V::CHUNK_SIZE as _);
+
+    // An empty value is stored as an empty chunk, otherwise the item would not be found.
+    let first_chunk = chunks.next().unwrap_or_default();
+    for chunk in std::iter::once(first_chunk).chain(chunks) {
+        let chunk = Chunk::new(chunk.to_vec());
+        map.insert(key.clone(), chunk);
+        key.increase_chunk_index();
+    }
+}
+
+/// Removes the chunks of the value and returns its bytes.
+fn remove_chunks<K, V, M>(map: &mut Chunks<K, V, M>, key: &K) -> Option<Vec<u8>>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    let first_chunk_key = Key::new(key);
+    let max_chunk_key = first_chunk_key.clone().with_max_chunk_index();
+    let keys: Vec<Key<K>> = map
+        .range(first_chunk_key..=max_chunk_key)
+        .map(|(k, _)| k)
+        .collect();
+
+    if keys.is_empty() {
+        return None;
+    }
+
+    let mut value_bytes = Vec::new();
+    for key in &keys {
+        // We have got keys from the map, so they are present.
+        // If something goes wrong, panic will help to avoid partly-removed items.
+        let chunk = map.remove(key).expect("the key present");
+        value_bytes.extend_from_slice(chunk.data());
+    }
+
+    Some(value_bytes)
+}
+
+fn clear_chunks<K, V, M>(map: &mut Chunks<K, V, M>)
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    let keys: Vec<_> = map.iter().map(|(k, _)| k).collect();
+    for key in keys {
+        map.remove(&key);
+    }
+}
+
+/// Progress of the compaction of [`StableUnboundedMap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionProgress {
+    /// No compaction is in progress.
+    Idle,
+    /// The live values are being copied, to the scratch memory in the first pass and back to
+    /// the memory of the map in the second one.
+    InProgress { pass: u8, copied_chunks: u64 },
+    /// The compaction is completed.
+    Done(CompactionStats),
+}
+
+/// Stats of a completed compaction.
+///
+/// Stable memory is never shrunk, so the map keeps its pages, but its live chunks are written
+/// contiguously from the start of the memory and the rest of the pages are reused by new values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// The number of the live chunks.
+    pub chunks: u64,
+    /// The size of the memory of the map in pages.
+    pub pages_before: u64,
+    /// The size of the scratch memory in pages, i.e. the pages used by the compacted map, if
+    /// the scratch memory was not used for bigger maps before.
+    pub pages_after: u64,
+}
+
+/// A compaction pass, which copies the values of the map to the `target` in the key order.
+struct Compaction<K, V, M>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    /// Contains the values up to the `cursor`, which are kept in sync with the map.
+    target: Chunks<K, V, M>,
+    /// The key of the last chunk of the last copied value.
+    cursor: Option<Key<K>>,
+    copied_chunks: u64,
+    /// The size of the memory of the map, set in the second pass.
+    pages_before: Option<u64>,
+    /// The scratch memory, whose first page keeps the state of the compaction.
+    scratch: Rc<M>,
+}
+
+impl<K, V, M> Compaction<K, V, M>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    fn pass(&self) -> u8 {
+        if self.pages_before.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Writes the progress to the scratch memory, so the compaction is resumed after an upgrade.
+    fn save(&self) {
+        CompactionState {
+            pages_before: self.pages_before,
+            copied_chunks: self.copied_chunks,
+            cursor: self
+                .cursor
+                .as_ref()
+                .map(|cursor| cursor.to_bytes().into_owned()),
+        }
+        .save(self.scratch.as_ref());
+    }
+
+    /// Copies up to `max_items` values after the cursor. Returns `true` if all the values are
+    /// copied.
+    fn copy_batch(&mut self, source: &Chunks<K, V, M>, max_items: usize) -> bool {
+        let mut chunks = match &self.cursor {
+            Some(cursor) => {
+                source.range((RangeBound::Excluded(cursor.clone()), RangeBound::Unbounded))
+            }
+            None => source.iter(),
+        }
+        .peekable();
+
+        let mut copied_items = 0;
+        while copied_items < max_items {
+            let Some((key, chunk)) = chunks.next() else {
+                return true;
+            };
+            let last_chunk = chunks
+                .peek()
+                .map_or(true, |(next_key, _)| next_key.prefix() != key.prefix());
+
+            self.target.insert(key.clone(), chunk);
+            self.copied_chunks += 1;
+            if last_chunk {
+                copied_items += 1;
+                self.cursor = Some(key);
+            }
+        }
+
+        chunks.peek().is_none()
+    }
+}
+
+/// The state of a compaction, kept in the first page of the scratch memory.
+///
+/// # Memory layout
+/// ```ignore
+/// |-- magic --|-- pass --|-- pages_before --|-- copied_chunks --|-- cursor_len --|-- cursor --|
+/// ```
+///
+/// where `pass` is `0` if no compaction is in progress, and `cursor_len` is `0` if no value was
+/// copied in the current pass.
+struct CompactionState {
+    pages_before: Option<u64>,
+    copied_chunks: u64,
+    cursor: Option<Vec<u8>>,
+}
+
+impl CompactionState {
+    const MAGIC: &'static [u8; 4] = b"UMC\x01";
+    const HEADER_LEN: usize = 4 + 1 + 8 + 8 + 4;
+    const MAX_CURSOR_LEN: usize = WASM_PAGE_SIZE as usize - Self::HEADER_LEN;
+
+    fn load(memory: &impl Memory) -> Option<Self> {
+        if memory.size() == 0 {
+            return None;
+        }
+
+        let mut header = [0; Self::HEADER_LEN];
+        memory.read(0, &mut header);
+        let pass = header[4];
+        if &header[..4] != Self::MAGIC || pass == 0 {
+            return None;
+        }
+
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(header[offset..offset + 8].try_into().expect("8 bytes"))
+        };
+        let cursor_len = u32::from_le_bytes(header[21..25].try_into().expect("4 bytes")) as usize;
+        let cursor = (cursor_len > 0).then(|| {
+            let mut cursor = vec![0; cursor_len];
+            memory.read(Self::HEADER_LEN as u64, &mut cursor);
+            cursor
+        });
+
+        Some(Self {
+            pages_before: (pass == 2).then(|| u64_at(5)),
+            copied_chunks: u64_at(13),
+            cursor,
+        })
+    }
+
+    fn save(&self, memory: &impl Memory) {
+        let cursor = self.cursor.as_deref().unwrap_or_default();
+        let pass: u8 = if self.pages_before.is_some() { 2 } else { 1 };
+
+        let mut data = Vec::with_capacity(Self::HEADER_LEN + cursor.len());
+        data.extend_from_slice(Self::MAGIC);
+        data.push(pass);
+        data.extend_from_slice(&self.pages_before.unwrap_or_default().to_le_bytes());
+        data.extend_from_slice(&self.copied_chunks.to_le_bytes());
+        data.extend_from_slice(&(cursor.len() as u32).to_le_bytes());
+        data.extend_from_slice(cursor);
+        Self::write(memory, &data);
+    }
+
+    /// Marks the compaction as completed.
+    fn clear(memory: &impl Memory) {
+        let mut data = Self::MAGIC.to_vec();
+        data.push(0);
+        Self::write(memory, &data);
+    }
+
+    fn write(memory: &impl Memory, data: &[u8]) {
+        if memory.size() == 0 && memory.grow(1) < 0 {
+            panic!("failed to allocate the page of the compaction state");
+        }
+        memory.write(0, data);
+    }
+}
+
+/// The memory of the chunks, which starts after the page of the compaction state in the scratch
+/// memory.
+struct MapMemory<M> {
+    memory: Rc<M>,
+    header_pages: u64,
+}
+
+impl<M: Memory> MapMemory<M> {
+    fn new(memory: M) -> Self {
+        Self {
+            memory: Rc::new(memory),
+            header_pages: 0,
+        }
+    }
+
+    fn scratch(memory: Rc<M>) -> Self {
+        Self {
+            memory,
+            header_pages: 1,
+        }
+    }
+
+    fn offset(&self) -> u64 {
+        self.header_pages * WASM_PAGE_SIZE
+    }
+}
+
+impl<M: Memory> Memory for MapMemory<M> {
+    fn size(&self) -> u64 {
+        self.memory.size().saturating_sub(self.header_pages)
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let missing_header_pages = self.header_pages.saturating_sub(self.memory.size());
+        let previous = self.memory.grow(pages + missing_header_pages);
+        if previous < 0 {
+            return previous;
+        }
+
+        (previous as u64).saturating_sub(self.header_pages) as i64
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        self.memory.read(self.offset() + offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        self.memory.write(self.offset() + offset, src)
+    }
+}
+
 impl<K, V, M> IterableUnboundedMapStructure<K, V> for StableUnboundedMap<K, V, M>
 where
     K: Storable,
@@ -212,7 +701,7 @@ where
 /// - `chunk_index` is an index of chunk associated with a key instance. If inserted value split to `N`
 /// chunks, then they stored as several entries. Each entry has unique key, with difference only in `chunk_index`.
 /// In `get()` operation the value constructing from it's chunks. The `chunk_index` takes [`CHUNK_INDEX_LEN`] bytes.
-struct Key<K: Storable> {
+pub(crate) struct Key<K: Storable> {
     data: Vec<u8>,
     _p: PhantomData<K>,
 }
@@ -313,6 +802,15 @@ impl<K: Storable> Key<K> {
         chunk_index_bytes.copy_from_slice(&chunk_index.to_be_bytes())
     }
 
+    pub fn chunk_index(&self) -> ChunkIndex {
+        let chunk_index_bytes = &self.data[(self.data.len() - CHUNK_INDEX_LEN)..];
+        ChunkIndex::from_be_bytes(
+            chunk_index_bytes
+                .try_into()
+                .expect("the slice is always CHUNK_INDEX_LEN length"),
+        )
+    }
+
     /// Prefix of key data, which is same for all chunks of the same value.
     pub fn prefix(&self) -> &[u8] {
         &self.data[..self.data.len() - CHUNK_INDEX_LEN]
@@ -388,7 +886,9 @@ impl<V: SlicedStorable> Storable for Chunk<V> {
 
 /// Iterator over values in unbounded map.
 /// Constructs a value from chunks on each `next()` call.
-pub struct StableUnboundedIter<'a, K, V, M>(Peekable<btreemap::Iter<'a, Key<K>, Chunk<V>, M>>)
+pub struct StableUnboundedIter<'a, K, V, M>(
+    Peekable<btreemap::Iter<'a, Key<K>, Chunk<V>, MapMemory<M>>>,
+)
 where
     K: Storable,
     V: SlicedStorable,
@@ -403,6 +903,19 @@ where
     type Item = (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
+        let (key, value_data) = self.next_encoded()?;
+        Some((key, V::from_bytes(value_data.into())))
+    }
+}
+
+impl<K, V, M> StableUnboundedIter<'_, K, V, M>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    /// Returns the next key with the encoded value, joined from its chunks.
+    fn next_encoded(&mut self) -> Option<(K, Vec<u8>)> {
         let (key, chunk) = self.0.next()?;
         let mut value_data = chunk.into_data();
 
@@ -415,10 +928,93 @@ where
             value_data.extend_from_slice(new_chunk.data());
         }
 
-        Some((
-            K::from_bytes(key.key_data().into()),
-            V::from_bytes(value_data.into()),
-        ))
+        Some((K::from_bytes(key.key_data().into()), value_data))
+    }
+}
+
+/// Reader of a value of [`StableUnboundedMap`], which loads one chunk at a time.
+pub struct StableUnboundedValueReader<'a, K, V, M>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    map: &'a Chunks<K, V, M>,
+    key: Key<K>,
+    len: u64,
+    position: u64,
+}
+
+impl<K, V, M> StableUnboundedValueReader<'_, K, V, M>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    /// Returns the length of the encoded value.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<K, V, M> io::Read for StableUnboundedValueReader<'_, K, V, M>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() && self.position < self.len {
+            let chunk_size = V::CHUNK_SIZE as u64;
+            let chunk_index = (self.position / chunk_size) as ChunkIndex;
+            self.key.set_chunk_index(chunk_index);
+            let Some(chunk) = self.map.get(&self.key) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the value was changed while reading",
+                ));
+            };
+
+            let offset = (self.position % chunk_size) as usize;
+            let data = chunk.data().get(offset..).unwrap_or_default();
+            let count = data.len().min(buf.len() - read);
+            buf[read..read + count].copy_from_slice(&data[..count]);
+            read += count;
+            self.position += count as u64;
+            if count == 0 {
+                break;
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+impl<K, V, M> io::Seek for StableUnboundedValueReader<'_, K, V, M>
+where
+    K: Storable,
+    V: SlicedStorable,
+    M: Memory,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(position) => Some(position),
+            io::SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
     }
 }
 
@@ -666,4 +1262,177 @@ mod tests {
             (expected_chunks_number as u64 + 1) * 2
         );
     }
+
+    #[test]
+    fn compaction_keeps_values_changed_between_calls() {
+        let memory = VectorMemory::default();
+        let scratch = VectorMemory::default();
+        let mut map = StableUnboundedMap::new(memory.clone());
+        let mut expected = HashMap::new();
+        for i in 0..40u32 {
+            let value = str_val(50 + i as usize * 300);
+            map.insert(&i, &value);
+            expected.insert(i, value);
+        }
+        for i in (0..40u32).step_by(2) {
+            map.remove(&i);
+            expected.remove(&i);
+        }
+
+        assert_eq!(map.compact(10), CompactionProgress::Idle);
+        assert!(map.start_compaction(scratch.clone()));
+        assert!(!map.start_compaction(scratch.clone()));
+
+        let mut calls = 0;
+        let stats = loop {
+            match map.compact(3) {
+                CompactionProgress::Done(stats) => break stats,
+                CompactionProgress::InProgress { .. } => {}
+                CompactionProgress::Idle => panic!("compaction is not completed"),
+            }
+
+            // Change the values before and after the cursor.
+            let (copied, pending) = (calls % 40, 39 - calls % 40);
+            for key in [copied, pending] {
+                let value = str_val(calls as usize * 100);
+                map.insert(&key, &value);
+                expected.insert(key, value);
+            }
+            if map.remove(&(calls * 7 % 40)).is_some() {
+                expected.remove(&(calls * 7 % 40));
+            }
+
+            calls += 1;
+        };
+
+        assert!(!map.is_compacting());
+        assert_eq!(stats.chunks, map.total_chunks_number());
+        assert_eq!(map.iter().collect::<HashMap<_, _>>(), expected);
+
+        // The map is rewritten in its own memory, and the scratch memory is left empty.
+        let restored = StableUnboundedMap::<u32, StringValue, _>::new(memory.clone());
+        assert_eq!(restored.iter().collect::<HashMap<_, _>>(), expected);
+        let restored = StableUnboundedMap::<u32, StringValue, _>::with_scratch(memory, scratch);
+        assert!(!restored.is_compacting());
+        assert_eq!(restored.iter().collect::<HashMap<_, _>>(), expected);
+    }
+
+    #[test]
+    fn compaction_is_resumed_after_upgrade() {
+        let memory = VectorMemory::default();
+        let scratch = VectorMemory::default();
+        let mut map = StableUnboundedMap::new(memory.clone());
+        let mut expected = HashMap::new();
+        for i in 0..40u32 {
+            let value = str_val(50 + i as usize * 300);
+            map.insert(&i, &value);
+            expected.insert(i, value);
+        }
+        for i in (0..40u32).step_by(3) {
+            map.remove(&i);
+            expected.remove(&i);
+        }
+
+        assert!(map.start_compaction(scratch.clone()));
+        let mut upgrades = 0;
+        let stats = loop {
+            let progress = map.compact(4);
+            if let CompactionProgress::Done(stats) = progress {
+                break stats;
+            }
+
+            // Upgrade the canister after every call, in both passes.
+            drop(map);
+            map = StableUnboundedMap::with_scratch(memory.clone(), scratch.clone());
+            upgrades += 1;
+            assert!(map.is_compacting());
+            assert_eq!(map.iter().collect::<HashMap<_, _>>(), expected);
+            assert_eq!(map.compact(0), progress);
+
+            let key = 100 + upgrades;
+            let value = str_val(upgrades as usize * 10);
+            map.insert(&key, &value);
+            expected.insert(key, value);
+        };
+
+        assert!(upgrades > 10);
+        assert_eq!(stats.chunks, map.total_chunks_number());
+        assert_eq!(map.iter().collect::<HashMap<_, _>>(), expected);
+
+        let restored = StableUnboundedMap::<u32, StringValue, _>::with_scratch(memory, scratch);
+        assert!(!restored.is_compacting());
+        assert_eq!(restored.iter().collect::<HashMap<_, _>>(), expected);
+    }
+
+    #[test]
+    fn values_are_streamed_by_chunks() {
+        let mut map = StableUnboundedMap::new(VectorMemory::default());
+        let value = StringValue(
+            (0..1000)
+                .map(|i| char::from(b'a' + (i % 26) as u8))
+                .collect(),
+        );
+        let bytes = value.to_bytes().into_owned();
+        map.insert(&1u32, &value);
+        map.insert(&2u32, &str_val(10));
+
+        let mut chunks = vec![];
+        assert!(map.get_chunked(&1, |chunk| chunks.push(chunk.to_vec())));
+        assert_eq!(chunks.len(), 1000 / StringValue::CHUNK_SIZE as usize + 1);
+        assert_eq!(chunks.concat(), bytes);
+        assert!(!map.get_chunked(&3, |_| panic!("no chunks")));
+
+        assert_eq!(map.value_len(&1), Some(1000));
+        assert_eq!(map.value_len(&3), None);
+        assert!(map.reader(&3).is_none());
+
+        let mut reader = map.reader(&1).unwrap();
+        let mut read = vec![];
+        io::Read::read_to_end(&mut reader, &mut read).unwrap();
+        assert_eq!(read, bytes);
+
+        let mut part = [0; 100];
+        io::Seek::seek(&mut reader, io::SeekFrom::Start(60)).unwrap();
+        io::Read::read_exact(&mut reader, &mut part).unwrap();
+        assert_eq!(part, bytes[60..160]);
+
+        let mut tail = vec![];
+        io::Seek::seek(&mut reader, io::SeekFrom::End(-5)).unwrap();
+        io::Read::read_to_end(&mut reader, &mut tail).unwrap();
+        assert_eq!(tail, bytes[995..]);
+        assert!(io::Seek::seek(&mut reader, io::SeekFrom::Current(-1000)).is_err());
+    }
+
+    #[test]
+    fn empty_value_is_stored() {
+        let mut map = StableUnboundedMap::new(VectorMemory::default());
+        let empty = str_val(0);
+
+        assert!(map.insert(&1u64, &empty).is_none());
+        map.insert(&2u64, &str_val(5));
+        assert_eq!(map.total_chunks_number(), 2);
+        assert_eq!(map.get(&1u64), Some(empty.clone()));
+        assert_eq!(map.iter().count(), 2);
+
+        assert_eq!(map.remove(&1u64), Some(empty));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.total_chunks_number(), 1);
+    }
+
+    #[test]
+    fn values_are_read_and_removed_encoded() {
+        let mut map = StableUnboundedMap::new(VectorMemory::default());
+        let long_str = str_val(1000);
+        map.insert(&1u32, &long_str);
+        map.insert(&2u32, &str_val(10));
+
+        let encoded = map.iter_encoded().collect::<Vec<_>>();
+        assert_eq!(encoded.len(), 2);
+        assert_eq!(encoded[0], (1, long_str.to_bytes().into_owned()));
+
+        assert_eq!(map.remove_encoded(&1), Some(encoded[0].1.clone()));
+        assert_eq!(map.remove_encoded(&1), None);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.iter().map(|(key, _)| key).collect::<Vec<_>>(), [2]);
+    }
 }
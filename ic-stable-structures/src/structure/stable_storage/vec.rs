@@ -1,6 +1,7 @@
 use dfinity_stable_structures::{vec, Memory, Storable};
 
-use crate::structure::VecStructure;
+use crate::error::check_size;
+use crate::structure::{ReadOnly, VecStructure};
 use crate::Result;
 
 pub struct StableVec<T: Storable, M: Memory>(Option<vec::Vec<T, M>>);
@@ -13,6 +14,11 @@ impl<T: Storable, M: Memory> StableVec<T, M> {
         Ok(Self(Some(vec::Vec::init(memory)?)))
     }
 
+    /// Returns a read-only view of the vector, e.g. for the query methods.
+    pub fn read_only(&self) -> ReadOnly<'_, Self> {
+        ReadOnly::new(self)
+    }
+
     /// Returns iterator over the elements in the vector
     pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
         self.get_inner().iter()
@@ -47,6 +53,7 @@ impl<T: Storable, M: Memory> VecStructure<T> for StableVec<T, M> {
     }
 
     fn set(&mut self, index: u64, item: &T) -> Result<()> {
+        check_size(item)?;
         self.mut_inner().set(index, item);
         Ok(())
     }
@@ -56,6 +63,7 @@ impl<T: Storable, M: Memory> VecStructure<T> for StableVec<T, M> {
     }
 
     fn push(&mut self, item: &T) -> Result<()> {
+        check_size(item)?;
         self.mut_inner().push(item).map_err(Into::into)
     }
 
@@ -30,6 +30,25 @@ pub fn str_val(len: usize) -> StringValue {
     StringValue(s)
 }
 
+/// A string which is not allowed to take more than `N` bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoundedString<const N: u32>(pub String);
+
+impl<const N: u32> Storable for BoundedString<N> {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: N,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(String::from_bytes(bytes))
+    }
+}
+
 /// New type pattern used to implement `Storable` trait for all arrays.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Array<const N: usize>(pub [u8; N]);
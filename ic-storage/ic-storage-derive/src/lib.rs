@@ -30,13 +30,15 @@ pub fn derive_ic_storage(input: TokenStream) -> TokenStream {
                 use ::ic_exports::candid::Principal;
 
                 thread_local! {
-                    static store: RefCell<HashMap<Principal, Rc<RefCell<#ident>>>> = RefCell::new(HashMap::default());
+                    static store: RefCell<HashMap<(Principal, u64), Rc<RefCell<#ident>>>> = RefCell::new(HashMap::default());
                 }
 
+                // The state is lost when the heap of the canister is wiped by an upgrade.
                 let id = ::ic_exports::ic_kit::ic::id();
+                let generation = ::ic_exports::ic_kit::inject::get_context().heap_generation(&id);
                 store.with(|v| {
                     let mut borrowed_store = v.borrow_mut();
-                    (*borrowed_store.entry(id).or_default()).clone()
+                    (*borrowed_store.entry((id, generation)).or_default()).clone()
                 })
             }
         }
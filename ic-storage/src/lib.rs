@@ -81,6 +81,27 @@
 //! other then `wasm32`. In this case you need to use `ic_exports::ic_kit` to set up the
 //! `MockingContext` and set the current `id` in that context. For each `id` different storage will
 //! be returned by `IcStorage::get()` method even in the same test case.
+//!
+//! The storage is also reset when the heap of the canister is wiped by `MockContext::upgrade()`,
+//! so the upgrade methods of the canister can be tested:
+//!
+//! ```
+//! use ic_exports::ic_kit::{ic, MockContext};
+//! use ic_storage::IcStorage;
+//!
+//! #[derive(IcStorage, Default)]
+//! struct MyCanisterState {
+//!     value: u32,
+//! }
+//!
+//! let ctx = MockContext::new().inject();
+//! MyCanisterState::get().borrow_mut().value = 42;
+//!
+//! ctx.upgrade(
+//!     || ic::stable_store((MyCanisterState::get().borrow().value,)).unwrap(),
+//!     || assert_eq!(MyCanisterState::get().borrow().value, 0),
+//! );
+//! ```
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -127,13 +148,15 @@ macro_rules! generic_derive {
                 use ::ic_exports::candid::Principal;
 
                 thread_local! {
-                    static store: RefCell<HashMap<Principal, Rc<RefCell<$storage>>>> = RefCell::new(HashMap::default());
+                    static store: RefCell<HashMap<(Principal, u64), Rc<RefCell<$storage>>>> = RefCell::new(HashMap::default());
                 }
 
+                // The state is lost when the heap of the canister is wiped by an upgrade.
                 let id = ::ic_exports::ic_kit::ic::id();
+                let generation = ::ic_exports::ic_kit::inject::get_context().heap_generation(&id);
                 store.with(|v| {
                     let mut borrowed_store = v.borrow_mut();
-                    (*borrowed_store.entry(id).or_default()).clone()
+                    (*borrowed_store.entry((id, generation)).or_default()).clone()
                 })
             }
         }
@@ -0,0 +1,191 @@
+//! The archive of the finished tasks of the scheduler.
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, LogStructure, StableBTreeMap, StableLog, Storable,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::task::{InnerScheduledTask, Task};
+
+/// An append-only archive of the finished tasks, compressed in stable memory.
+///
+/// The archive keeps the hot map of the pending tasks small, so that the scans of the due tasks
+/// and the upgrades stay fast, while the finished tasks can still be queried by their keys.
+/// The scheduler moves the finished tasks to the archive when they are older than the
+/// retention period, see [`Scheduler::set_archive`](crate::scheduler::Scheduler::set_archive).
+pub struct TaskArchive<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    index: StableBTreeMap<u32, u64, M>,
+    log: StableLog<ArchivedTask, M>,
+    _task: PhantomData<T>,
+}
+
+impl<T, M> TaskArchive<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    /// Create the archive in the memories of the index by the keys and of the log of the tasks.
+    /// If the memories contain the tasks, they are kept.
+    pub fn new(
+        index_memory: M,
+        log_index_memory: M,
+        log_data_memory: M,
+    ) -> ic_stable_structures::Result<Self> {
+        Ok(Self {
+            index: StableBTreeMap::new(index_memory),
+            log: StableLog::new(log_index_memory, log_data_memory)?,
+            _task: PhantomData,
+        })
+    }
+
+    /// Append the task to the archive.
+    pub fn archive(&mut self, task: &InnerScheduledTask<T>) -> ic_stable_structures::Result<()> {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder
+            .write_all(&task.to_bytes())
+            .expect("failed to compress the task");
+        let compressed = encoder.finish().expect("failed to compress the task");
+
+        let position = self.log.append(ArchivedTask(compressed))?;
+        self.index.insert(task.id(), position);
+        Ok(())
+    }
+
+    /// Returns the archived task with the key.
+    pub fn get(&self, task_id: u32) -> Option<InnerScheduledTask<T>> {
+        let position = self.index.get(&task_id)?;
+        let compressed = self.log.get(position)?;
+
+        let mut bytes = vec![];
+        DeflateDecoder::new(compressed.0.as_slice())
+            .read_to_end(&mut bytes)
+            .expect("failed to decompress the task");
+        Some(InnerScheduledTask::from_bytes(Cow::Owned(bytes)))
+    }
+
+    /// Returns the greatest key of the archived tasks.
+    pub fn last_key(&self) -> Option<u32> {
+        self.index.last_key_value().map(|(key, _)| key)
+    }
+
+    /// Returns the number of the archived tasks.
+    pub fn len(&self) -> u64 {
+        self.index.len()
+    }
+
+    /// Returns `true` if no task is archived.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
+
+/// The archive as seen by the scheduler, without the memory type.
+pub(crate) trait TaskArchiveStore<T> {
+    fn archive(&mut self, task: &InnerScheduledTask<T>) -> ic_stable_structures::Result<()>;
+
+    fn get(&self, task_id: u32) -> Option<InnerScheduledTask<T>>;
+
+    fn last_key(&self) -> Option<u32>;
+}
+
+impl<T, M> TaskArchiveStore<T> for TaskArchive<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    fn archive(&mut self, task: &InnerScheduledTask<T>) -> ic_stable_structures::Result<()> {
+        TaskArchive::archive(self, task)
+    }
+
+    fn get(&self, task_id: u32) -> Option<InnerScheduledTask<T>> {
+        TaskArchive::get(self, task_id)
+    }
+
+    fn last_key(&self) -> Option<u32> {
+        TaskArchive::last_key(self)
+    }
+}
+
+/// A compressed encoded task.
+struct ArchivedTask(Vec<u8>);
+
+impl Storable for ArchivedTask {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+
+    use ic_stable_structures::VectorMemory;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::harness::SchedulerTestHarness;
+    use crate::scheduler::TaskScheduler;
+    use crate::task::TaskStatus;
+    use crate::SchedulerError;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct NoopTask;
+
+    impl Task for NoopTask {
+        fn execute(
+            &self,
+            _task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn finished_tasks_are_archived_after_retention() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let archive = TaskArchive::new(
+            VectorMemory::default(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+        )
+        .unwrap();
+        harness.scheduler_mut().set_archive(archive, 10);
+        harness.append_task(NoopTask.into());
+        harness.append_task(NoopTask.into());
+
+        // The finished tasks are kept during the retention period.
+        assert_eq!(harness.tick(1), 2);
+        assert_eq!(harness.pending_tasks(), [0, 1]);
+        assert_eq!(harness.status(1), Some(TaskStatus::completed(100)));
+
+        harness.advance_time(11);
+        assert_eq!(harness.tick(1), 0);
+        assert!(harness.pending_tasks().is_empty());
+
+        let archived = harness.scheduler().get_task(1).unwrap();
+        assert_eq!(archived.task(), &NoopTask);
+        assert_eq!(archived.status(), &TaskStatus::completed(100));
+        // The keys of the archived tasks are not reused.
+        assert_eq!(harness.append_task(NoopTask.into()), 2);
+    }
+}
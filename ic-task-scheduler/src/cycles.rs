@@ -0,0 +1,563 @@
+//! Top-ups of the cycles of the canister and its children, executed by the task scheduler.
+//!
+//! A timer periodically appends a check task for every target with
+//! [`CyclesTopUp::schedule_checks`]. The check task reads the cycles balance of the target, and
+//! if it's below the threshold, appends the top-up tasks, which either:
+//! - convert ICP of the canister to cycles: transfer the ICP to the cycles minting canister and
+//!   call its `notify_top_up` method;
+//! - or withdraw the cycles of the canister from the cycles ledger to the target.
+//!
+//! The transfers are retried with the same `created_at_time`, so the ledgers deduplicate the
+//! retries. The checks of a target are skipped while its top-up is pending, so a top-up being
+//! retried is not started again by the next check, see [`pending_top_ups`].
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use candid::{CandidType, Nat, Principal};
+use ic_exports::ledger::{
+    AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs, TransferError, DEFAULT_FEE,
+};
+use ic_kit::{ic, RejectionCode};
+use serde::{Deserialize, Serialize};
+
+use crate::retry::{BackoffPolicy, RetryPolicy};
+use crate::scheduler::TaskScheduler;
+use crate::task::{ScheduledTask, Task, TaskOptions};
+use crate::SchedulerError;
+
+/// The memo of the ICP transfers to the cycles minting canister, which top up a canister.
+pub const TOP_UP_MEMO: Memo = Memo(0x50555054);
+
+/// The max time a top-up is pending, which is the deduplication window of the ledgers.
+pub const TOP_UP_DEADLINE_NANOS: u64 = 24 * 3600 * 1_000_000_000;
+
+thread_local! {
+    /// The targets with a pending top-up and the deadlines of the top-ups.
+    static PENDING_TOP_UPS: RefCell<BTreeMap<Principal, u64>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+/// The targets with a pending top-up and the deadlines of the top-ups in nanoseconds.
+///
+/// A top-up is pending from the check which started it until its last task succeeds or the
+/// deadline passes. The top-ups are tracked in the heap, so they must be saved in `pre_upgrade`
+/// and restored with [`restore_pending_top_ups`] in `post_upgrade`, otherwise a check can start
+/// a second top-up of a target while the first one is retried.
+pub fn pending_top_ups() -> Vec<(Principal, u64)> {
+    PENDING_TOP_UPS.with(|pending| pending.borrow().clone().into_iter().collect())
+}
+
+/// Restore the pending top-ups saved before the upgrade.
+pub fn restore_pending_top_ups(top_ups: Vec<(Principal, u64)>) {
+    PENDING_TOP_UPS.with(|pending| *pending.borrow_mut() = top_ups.into_iter().collect());
+}
+
+fn is_top_up_pending(canister: Principal, now: u64) -> bool {
+    PENDING_TOP_UPS.with(|pending| {
+        pending
+            .borrow()
+            .get(&canister)
+            .is_some_and(|deadline| *deadline > now)
+    })
+}
+
+fn start_top_up(canister: Principal, now: u64) {
+    PENDING_TOP_UPS.with(|pending| {
+        pending
+            .borrow_mut()
+            .insert(canister, now.saturating_add(TOP_UP_DEADLINE_NANOS))
+    });
+}
+
+fn finish_top_up(canister: Principal) {
+    PENDING_TOP_UPS.with(|pending| pending.borrow_mut().remove(&canister));
+}
+
+/// The canister, which is topped up when its cycles balance falls below the threshold.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopUpTarget {
+    /// The canister itself or a child, controlled by the canister.
+    pub canister: Principal,
+    pub threshold_cycles: u128,
+    /// The ICP e8s converted to cycles or the cycles withdrawn from the cycles ledger.
+    pub amount: u128,
+}
+
+/// Where the cycles come from.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TopUpSource {
+    /// Convert the ICP of the canister in the ledger with the cycles minting canister.
+    Icp { ledger: Principal, cmc: Principal },
+    /// Withdraw the cycles of the canister in the cycles ledger.
+    CyclesLedger { ledger: Principal },
+}
+
+/// The argument of the `notify_top_up` method of the cycles minting canister.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NotifyTopUpArg {
+    pub block_index: u64,
+    pub canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    Processing,
+    TransactionTooOld(u64),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+/// The argument of the `withdraw` method of the cycles ledger.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawArgs {
+    pub amount: Nat,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Principal,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WithdrawError {
+    GenericError {
+        message: String,
+        error_code: Nat,
+    },
+    TemporarilyUnavailable,
+    FailedToWithdraw {
+        fee_block: Option<Nat>,
+        rejection_code: RejectionCode,
+        rejection_reason: String,
+    },
+    Duplicate {
+        duplicate_of: Nat,
+    },
+    BadFee {
+        expected_fee: Nat,
+    },
+    InvalidReceiver {
+        receiver: Principal,
+    },
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TooOld,
+    InsufficientFunds {
+        balance: Nat,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+/// The part of the `canister_status` response of the management canister with the balance.
+#[derive(CandidType, Deserialize)]
+struct CanisterStatusCycles {
+    cycles: Nat,
+}
+
+/// The tasks of the cycles top-ups.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CyclesTask {
+    /// Check the balance of the target and top it up if needed.
+    Check {
+        target: TopUpTarget,
+        source: TopUpSource,
+    },
+    /// Transfer the ICP to the subaccount of the target in the cycles minting canister.
+    TransferIcp {
+        ledger: Principal,
+        cmc: Principal,
+        canister: Principal,
+        e8s: u64,
+        created_at_time: u64,
+    },
+    /// Notify the cycles minting canister about the transfer to top up the canister.
+    NotifyTopUp {
+        cmc: Principal,
+        canister: Principal,
+        block_index: u64,
+    },
+    /// Withdraw the cycles from the cycles ledger to the canister.
+    WithdrawCycles {
+        ledger: Principal,
+        canister: Principal,
+        amount: u128,
+        created_at_time: u64,
+    },
+}
+
+impl CyclesTask {
+    fn scheduled(self) -> ScheduledTask<Self> {
+        // The transfers are retried within the 24 hours deduplication window of the ledgers.
+        let options = TaskOptions::new()
+            .with_max_retries_policy(10)
+            .with_backoff_policy(BackoffPolicy::Variable {
+                secs: vec![5, 30, 300, 3600],
+            });
+        ScheduledTask::with_options(self, options)
+    }
+
+    async fn check(
+        target: TopUpTarget,
+        source: TopUpSource,
+        scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+    ) -> Result<(), SchedulerError> {
+        if is_top_up_pending(target.canister, ic::time()) {
+            return Ok(());
+        }
+
+        let balance = if target.canister == ic::id() {
+            Nat::from(ic::balance128())
+        } else {
+            let (status,): (CanisterStatusCycles,) = ic::call(
+                Principal::management_canister(),
+                "canister_status",
+                (CanisterIdRecord {
+                    canister_id: target.canister,
+                },),
+            )
+            .await
+            .map_err(|(code, message)| {
+                failed(format!(
+                    "canister_status of {} was rejected: {code:?}: {message}",
+                    target.canister
+                ))
+            })?;
+            status.cycles
+        };
+
+        if balance >= Nat::from(target.threshold_cycles) {
+            return Ok(());
+        }
+
+        // The balance may have been read while a top-up of the target was completing.
+        let created_at_time = ic::time();
+        if is_top_up_pending(target.canister, created_at_time) {
+            return Ok(());
+        }
+
+        let task = match source {
+            TopUpSource::Icp { ledger, cmc } => CyclesTask::TransferIcp {
+                ledger,
+                cmc,
+                canister: target.canister,
+                e8s: target.amount.try_into().unwrap_or(u64::MAX),
+                created_at_time,
+            },
+            TopUpSource::CyclesLedger { ledger } => CyclesTask::WithdrawCycles {
+                ledger,
+                canister: target.canister,
+                amount: target.amount,
+                created_at_time,
+            },
+        };
+        start_top_up(target.canister, created_at_time);
+        scheduler.append_task(task.scheduled());
+        Ok(())
+    }
+
+    async fn transfer_icp(
+        ledger: Principal,
+        cmc: Principal,
+        canister: Principal,
+        e8s: u64,
+        created_at_time: u64,
+        scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+    ) -> Result<(), SchedulerError> {
+        let args = TransferArgs {
+            memo: TOP_UP_MEMO,
+            amount: Tokens::from_e8s(e8s.saturating_sub(DEFAULT_FEE.e8s())),
+            fee: DEFAULT_FEE,
+            from_subaccount: None,
+            to: AccountIdentifier::new(&cmc, &Subaccount::from(&canister)),
+            created_at_time: Some(ic_exports::ledger::Timestamp {
+                timestamp_nanos: created_at_time,
+            }),
+        };
+        let (result,): (Result<u64, TransferError>,) = ic::call(ledger, "transfer", (args,))
+            .await
+            .map_err(|(code, message)| {
+                failed(format!("ICP transfer was rejected: {code:?}: {message}"))
+            })?;
+
+        let block_index = match result {
+            Ok(block_index) => block_index,
+            // The previous attempt succeeded, but its response was lost.
+            Err(TransferError::TxDuplicate { duplicate_of }) => duplicate_of,
+            Err(err) => return Err(failed(format!("ICP transfer failed: {err}"))),
+        };
+        scheduler.append_task(
+            CyclesTask::NotifyTopUp {
+                cmc,
+                canister,
+                block_index,
+            }
+            .scheduled(),
+        );
+        Ok(())
+    }
+
+    async fn notify_top_up(
+        cmc: Principal,
+        canister: Principal,
+        block_index: u64,
+    ) -> Result<(), SchedulerError> {
+        let arg = NotifyTopUpArg {
+            block_index,
+            canister_id: canister,
+        };
+        let (result,): (Result<Nat, NotifyError>,) = ic::call(cmc, "notify_top_up", (arg,))
+            .await
+            .map_err(|(code, message)| {
+            failed(format!("notify_top_up was rejected: {code:?}: {message}"))
+        })?;
+
+        result.map_err(|err| {
+            failed(format!(
+                "top-up of {canister} with block {block_index} failed: {err:?}"
+            ))
+        })?;
+        finish_top_up(canister);
+        Ok(())
+    }
+
+    async fn withdraw_cycles(
+        ledger: Principal,
+        canister: Principal,
+        amount: u128,
+        created_at_time: u64,
+    ) -> Result<(), SchedulerError> {
+        let args = WithdrawArgs {
+            amount: Nat::from(amount),
+            from_subaccount: None,
+            to: canister,
+            created_at_time: Some(created_at_time),
+        };
+        let (result,): (Result<Nat, WithdrawError>,) = ic::call(ledger, "withdraw", (args,))
+            .await
+            .map_err(|(code, message)| {
+                failed(format!("withdraw was rejected: {code:?}: {message}"))
+            })?;
+
+        match result {
+            Ok(_) | Err(WithdrawError::Duplicate { .. }) => {
+                finish_top_up(canister);
+                Ok(())
+            }
+            Err(err) => Err(failed(format!(
+                "withdrawal of {amount} cycles to {canister} failed: {err:?}"
+            ))),
+        }
+    }
+}
+
+impl Task for CyclesTask {
+    fn execute(
+        &self,
+        task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>> {
+        match self.clone() {
+            CyclesTask::Check { target, source } => {
+                Box::pin(Self::check(target, source, task_scheduler))
+            }
+            CyclesTask::TransferIcp {
+                ledger,
+                cmc,
+                canister,
+                e8s,
+                created_at_time,
+            } => Box::pin(Self::transfer_icp(
+                ledger,
+                cmc,
+                canister,
+                e8s,
+                created_at_time,
+                task_scheduler,
+            )),
+            CyclesTask::NotifyTopUp {
+                cmc,
+                canister,
+                block_index,
+            } => Box::pin(Self::notify_top_up(cmc, canister, block_index)),
+            CyclesTask::WithdrawCycles {
+                ledger,
+                canister,
+                amount,
+                created_at_time,
+            } => Box::pin(Self::withdraw_cycles(
+                ledger,
+                canister,
+                amount,
+                created_at_time,
+            )),
+        }
+    }
+}
+
+fn failed(message: String) -> SchedulerError {
+    SchedulerError::TaskExecutionFailed(message)
+}
+
+/// The targets of the top-ups and the source of the cycles.
+///
+/// ```ignore
+/// let top_up = CyclesTopUp::new(TopUpSource::CyclesLedger { ledger: CYCLES_LEDGER })
+///     .with_target(ic::id(), 2_000_000_000_000, 1_000_000_000_000)
+///     .with_target(child, 500_000_000_000, 500_000_000_000);
+///
+/// ic_cdk_timers::set_timer_interval(Duration::from_secs(3600), move || {
+///     top_up.schedule_checks(&scheduler);
+/// });
+///
+/// #[pre_upgrade]
+/// fn pre_upgrade() {
+///     ic::stable_store((pending_top_ups(),)).unwrap();
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     let (pending,) = ic::stable_restore().unwrap();
+///     restore_pending_top_ups(pending);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CyclesTopUp {
+    source: TopUpSource,
+    targets: Vec<TopUpTarget>,
+}
+
+impl CyclesTopUp {
+    pub fn new(source: TopUpSource) -> Self {
+        Self {
+            source,
+            targets: vec![],
+        }
+    }
+
+    /// Top up the canister with the `amount` when its balance is below the threshold. The
+    /// amount is in ICP e8s or in cycles, depending on the source.
+    pub fn with_target(
+        mut self,
+        canister: Principal,
+        threshold_cycles: u128,
+        amount: u128,
+    ) -> Self {
+        self.targets.push(TopUpTarget {
+            canister,
+            threshold_cycles,
+            amount,
+        });
+        self
+    }
+
+    pub fn targets(&self) -> &[TopUpTarget] {
+        &self.targets
+    }
+
+    /// Append the check tasks of the targets to the scheduler. Returns the keys of the tasks.
+    pub fn schedule_checks(&self, scheduler: &dyn TaskScheduler<CyclesTask>) -> Vec<u32> {
+        let tasks = self
+            .targets
+            .iter()
+            .map(|target| {
+                ScheduledTask::with_options(
+                    CyclesTask::Check {
+                        target: target.clone(),
+                        source: self.source.clone(),
+                    },
+                    // A failed check is repeated by the next scheduled one.
+                    TaskOptions::new().with_retry_policy(RetryPolicy::None),
+                )
+            })
+            .collect();
+        scheduler.append_tasks(tasks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::task::InnerScheduledTask;
+
+    #[derive(Default)]
+    struct TestScheduler(Rc<RefCell<Vec<ScheduledTask<CyclesTask>>>>);
+
+    impl TaskScheduler<CyclesTask> for TestScheduler {
+        fn append_task(&self, task: ScheduledTask<CyclesTask>) -> u32 {
+            self.append_tasks(vec![task])[0]
+        }
+
+        fn append_tasks(&self, tasks: Vec<ScheduledTask<CyclesTask>>) -> Vec<u32> {
+            let mut appended = self.0.borrow_mut();
+            let first = appended.len() as u32;
+            appended.extend(tasks);
+            (first..appended.len() as u32).collect()
+        }
+
+        fn get_task(&self, _task_id: u32) -> Option<InnerScheduledTask<CyclesTask>> {
+            None
+        }
+    }
+
+    #[test]
+    fn checks_are_scheduled_for_targets() {
+        let ledger = Principal::from_slice(&[1]);
+        let child = Principal::from_slice(&[2]);
+        let top_up = CyclesTopUp::new(TopUpSource::CyclesLedger { ledger })
+            .with_target(Principal::anonymous(), 2_000, 1_000)
+            .with_target(child, 500, 700);
+
+        let scheduler = TestScheduler::default();
+        assert_eq!(top_up.schedule_checks(&scheduler), [0, 1]);
+
+        let tasks = scheduler.0.borrow();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(
+            tasks[1].task,
+            CyclesTask::Check {
+                target: TopUpTarget {
+                    canister: child,
+                    threshold_cycles: 500,
+                    amount: 700,
+                },
+                source: TopUpSource::CyclesLedger { ledger },
+            }
+        );
+        assert_eq!(
+            tasks[1].options.retry_strategy.retry_policy,
+            RetryPolicy::None
+        );
+    }
+
+    #[test]
+    fn pending_top_ups_expire() {
+        let child = Principal::from_slice(&[2]);
+        start_top_up(child, 100);
+        assert!(is_top_up_pending(child, 100 + TOP_UP_DEADLINE_NANOS - 1));
+        assert!(!is_top_up_pending(child, 100 + TOP_UP_DEADLINE_NANOS));
+
+        let saved = pending_top_ups();
+        restore_pending_top_ups(vec![]);
+        assert!(!is_top_up_pending(child, 100));
+        restore_pending_top_ups(saved);
+        assert!(is_top_up_pending(child, 100));
+
+        finish_top_up(child);
+        assert_eq!(pending_top_ups(), []);
+    }
+}
@@ -1,12 +1,78 @@
 use candid::CandidType;
+use ic_helpers::SdkError;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(CandidType, Debug, Error, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Error, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum SchedulerError {
     #[error("TaskExecutionFailed: {0}")]
     TaskExecutionFailed(String),
+    #[error("TaskPanicked: {0}")]
+    TaskPanicked(String),
+    #[error("TaskQuarantined: {0}")]
+    TaskQuarantined(String),
 }
 
 /// Result type for the scheduler
 pub type Result<T> = std::result::Result<T, SchedulerError>;
+
+#[derive(CandidType, Debug, Error, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub enum PubSubError {
+    #[error("subscriptions are not initialized")]
+    NotInitialized,
+
+    #[error("the topic {0} is not available for subscription")]
+    UnknownTopic(String),
+
+    #[error("invalid name: {0}")]
+    InvalidName(String),
+
+    #[error("the principal {0} is not a canister")]
+    Unauthorized(String),
+
+    #[error("the subscriber has reached the limit of {0} subscriptions")]
+    SubscriberLimitReached(u64),
+
+    #[error("the limit of {0} subscriptions of the canister is reached")]
+    SubscriptionsLimitReached(u64),
+}
+
+pub type PubSubResult<T> = std::result::Result<T, PubSubError>;
+
+#[derive(CandidType, Debug, Error, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub enum TriggerError {
+    #[error("triggers are not initialized")]
+    NotInitialized,
+
+    #[error("unknown trigger {0}")]
+    UnknownTrigger(String),
+
+    #[error("the principal {0} is not allowed to call the trigger")]
+    Unauthorized(String),
+
+    #[error("too many calls of the trigger, retry after {retry_after_secs} seconds")]
+    RateLimited { retry_after_secs: u64 },
+
+    #[error("invalid arguments: {0}")]
+    InvalidArguments(String),
+}
+
+pub type TriggerResult<T> = std::result::Result<T, TriggerError>;
+
+impl From<SchedulerError> for SdkError {
+    fn from(error: SchedulerError) -> Self {
+        Self::Scheduler(error.to_string())
+    }
+}
+
+impl From<PubSubError> for SdkError {
+    fn from(error: PubSubError) -> Self {
+        Self::Scheduler(error.to_string())
+    }
+}
+
+impl From<TriggerError> for SdkError {
+    fn from(error: TriggerError) -> Self {
+        Self::Scheduler(error.to_string())
+    }
+}
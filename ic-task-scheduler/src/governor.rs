@@ -0,0 +1,301 @@
+//! A governor of the optional background work of the canister, e.g. the scheduler iterations,
+//! the metric snapshots or the compactions, which throttles the work when the cycles run low.
+//!
+//! The governor measures the instructions of the background jobs and the cycles burned over a
+//! sliding window. The work is reduced when the balance falls below a threshold or the jobs
+//! exceed their instruction budget in the window, and stopped below a lower threshold, so that
+//! the remaining cycles are left to the endpoints of the canister.
+//!
+//! ```ignore
+//! let governor = Rc::new(RefCell::new(
+//!     Governor::new(3600)
+//!         .with_cycles_thresholds(2_000_000_000_000, 500_000_000_000)
+//!         .with_instruction_budget(50_000_000_000)
+//!         .with_reduced_interval(600)
+//!         .with_scheduler(&scheduler),
+//! ));
+//!
+//! ic_cdk_timers::set_timer_interval(Duration::from_secs(10), move || {
+//!     let mut governor = governor.borrow_mut();
+//!     governor.observe_balance(ic::balance128(), time_secs());
+//!     governor.run("metrics_snapshot", time_secs(), collect_metrics);
+//! });
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+
+use candid::CandidType;
+use ic_exports::instructions::instruction_counter;
+use ic_stable_structures::IterableUnboundedMapStructure;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::scheduler::Scheduler;
+use crate::task::{InnerScheduledTask, Task};
+
+/// The background work allowed by the governor.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WorkLevel {
+    /// The jobs run every time.
+    Full,
+    /// Every job runs at most once per the reduced interval.
+    Reduced,
+    /// The jobs don't run.
+    Stopped,
+}
+
+/// The state of the governor.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct GovernorReport {
+    pub level: WorkLevel,
+    /// The last observed balance, `None` before the first observation.
+    pub balance_cycles: Option<u128>,
+    /// The cycles burned per second in the window, zero if the balance grew.
+    pub burn_rate_per_sec: f64,
+    /// The instructions of the jobs in the window.
+    pub window_instructions: u64,
+}
+
+struct WorkSample {
+    timestamp_secs: u64,
+    instructions: u64,
+}
+
+/// Throttles the optional background work of the canister, see the module docs.
+///
+/// The governor is kept in the heap, so after an upgrade the window starts over.
+pub struct Governor {
+    window_secs: u64,
+    reduce_below_cycles: u128,
+    stop_below_cycles: u128,
+    instruction_budget: Option<u64>,
+    reduced_interval_secs: u64,
+    level: WorkLevel,
+    samples: VecDeque<WorkSample>,
+    balances: VecDeque<(u64, u128)>,
+    last_runs: BTreeMap<String, u64>,
+    callbacks: Vec<Box<dyn Fn(WorkLevel)>>,
+}
+
+impl Governor {
+    /// Create the governor measuring the work over the last `window_secs`, without thresholds.
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs,
+            reduce_below_cycles: 0,
+            stop_below_cycles: 0,
+            instruction_budget: None,
+            reduced_interval_secs: window_secs,
+            level: WorkLevel::Full,
+            samples: VecDeque::new(),
+            balances: VecDeque::new(),
+            last_runs: BTreeMap::new(),
+            callbacks: vec![],
+        }
+    }
+
+    /// Reduce the work when the balance is below `reduce_below` and stop it below `stop_below`.
+    pub fn with_cycles_thresholds(mut self, reduce_below: u128, stop_below: u128) -> Self {
+        self.reduce_below_cycles = reduce_below;
+        self.stop_below_cycles = stop_below;
+        self
+    }
+
+    /// Reduce the work when the jobs executed more than `instructions` in the window.
+    pub fn with_instruction_budget(mut self, instructions: u64) -> Self {
+        self.instruction_budget = Some(instructions);
+        self
+    }
+
+    /// Run every job at most once per `interval_secs` when the work is reduced. Defaults to
+    /// the window.
+    pub fn with_reduced_interval(mut self, interval_secs: u64) -> Self {
+        self.reduced_interval_secs = interval_secs;
+        self
+    }
+
+    /// Call the closure when the level of the work changes.
+    pub fn with_callback(mut self, callback: Box<dyn Fn(WorkLevel)>) -> Self {
+        self.callbacks.push(callback);
+        self
+    }
+
+    /// Pause the scheduler when the work is stopped and resume it when the work is allowed
+    /// again.
+    pub fn with_scheduler<T, P>(self, scheduler: &Scheduler<T, P>) -> Self
+    where
+        T: 'static + Task,
+        P: 'static + IterableUnboundedMapStructure<u32, InnerScheduledTask<T>>,
+    {
+        let scheduler = scheduler.clone();
+        self.with_callback(Box::new(move |level| match level {
+            WorkLevel::Stopped => scheduler.pause(),
+            WorkLevel::Full | WorkLevel::Reduced => scheduler.resume(),
+        }))
+    }
+
+    /// Record the cycles balance of the canister. Returns the level of the work.
+    pub fn observe_balance(&mut self, balance_cycles: u128, now_secs: u64) -> WorkLevel {
+        self.balances.push_back((now_secs, balance_cycles));
+        self.update_level(now_secs);
+        self.level
+    }
+
+    /// Record the instructions executed by a run of the job.
+    pub fn record(&mut self, job: &str, instructions: u64, now_secs: u64) {
+        self.samples.push_back(WorkSample {
+            timestamp_secs: now_secs,
+            instructions,
+        });
+        self.last_runs.insert(job.to_string(), now_secs);
+        self.update_level(now_secs);
+    }
+
+    /// Returns `true` if the job is allowed to run now.
+    pub fn should_run(&self, job: &str, now_secs: u64) -> bool {
+        match self.level {
+            WorkLevel::Full => true,
+            WorkLevel::Reduced => self.last_runs.get(job).map_or(true, |last_run| {
+                now_secs >= last_run.saturating_add(self.reduced_interval_secs)
+            }),
+            WorkLevel::Stopped => false,
+        }
+    }
+
+    /// Run the job if it's allowed, recording its instructions. Returns `None` if the job was
+    /// throttled.
+    pub fn run<R>(&mut self, job: &str, now_secs: u64, f: impl FnOnce() -> R) -> Option<R> {
+        if !self.should_run(job, now_secs) {
+            return None;
+        }
+
+        let start = instruction_counter();
+        let result = f();
+        self.record(job, instruction_counter().saturating_sub(start), now_secs);
+        Some(result)
+    }
+
+    pub fn level(&self) -> WorkLevel {
+        self.level
+    }
+
+    pub fn report(&self) -> GovernorReport {
+        GovernorReport {
+            level: self.level,
+            balance_cycles: self.balances.back().map(|(_, balance)| *balance),
+            burn_rate_per_sec: self.burn_rate_per_sec(),
+            window_instructions: self.window_instructions(),
+        }
+    }
+
+    fn window_instructions(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|sample| sample.instructions)
+            .fold(0, u64::saturating_add)
+    }
+
+    fn burn_rate_per_sec(&self) -> f64 {
+        match (self.balances.front(), self.balances.back()) {
+            (Some((first_secs, first)), Some((last_secs, last))) if last_secs > first_secs => {
+                first.saturating_sub(*last) as f64 / (last_secs - first_secs) as f64
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn update_level(&mut self, now_secs: u64) {
+        let window_start = now_secs.saturating_sub(self.window_secs);
+        while matches!(self.samples.front(), Some(sample) if sample.timestamp_secs < window_start) {
+            self.samples.pop_front();
+        }
+        // The latest balance is kept even if it's out of the window.
+        while self.balances.len() > 1
+            && matches!(self.balances.front(), Some((timestamp, _)) if *timestamp < window_start)
+        {
+            self.balances.pop_front();
+        }
+
+        let balance = self.balances.back().map(|(_, balance)| *balance);
+        let level = match balance {
+            Some(balance) if balance < self.stop_below_cycles => WorkLevel::Stopped,
+            Some(balance) if balance < self.reduce_below_cycles => WorkLevel::Reduced,
+            _ if self
+                .instruction_budget
+                .is_some_and(|budget| self.window_instructions() > budget) =>
+            {
+                WorkLevel::Reduced
+            }
+            _ => WorkLevel::Full,
+        };
+
+        if level != self.level {
+            if level > self.level {
+                warn!("Governor - Background work throttled to {level:?}, balance {balance:?}");
+            } else {
+                info!("Governor - Background work restored to {level:?}, balance {balance:?}");
+            }
+            self.level = level;
+            for callback in &self.callbacks {
+                callback(level);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::instructions::burn_mock_instructions;
+    use ic_stable_structures::{StableUnboundedMap, VectorMemory};
+
+    use super::*;
+    use crate::outbox::DeliveryTask;
+
+    #[test]
+    fn work_is_throttled_by_balance_and_budget() {
+        let scheduler: Scheduler<DeliveryTask, _> =
+            Scheduler::new(StableUnboundedMap::new(VectorMemory::default()));
+        let mut governor = Governor::new(100)
+            .with_cycles_thresholds(1_000, 100)
+            .with_instruction_budget(50)
+            .with_reduced_interval(30)
+            .with_scheduler(&scheduler);
+
+        assert_eq!(governor.observe_balance(5_000, 0), WorkLevel::Full);
+        assert_eq!(
+            governor.run("snapshot", 0, || burn_mock_instructions(40)),
+            Some(())
+        );
+        assert!(governor.should_run("snapshot", 1));
+
+        // Over the instruction budget of the window.
+        governor.run("snapshot", 1, || burn_mock_instructions(20));
+        assert_eq!(governor.level(), WorkLevel::Reduced);
+        assert!(!governor.should_run("snapshot", 30));
+        assert!(governor.should_run("compaction", 30));
+        assert!(governor.should_run("snapshot", 31));
+
+        // The samples leave the window.
+        assert_eq!(governor.observe_balance(4_000, 102), WorkLevel::Full);
+        assert_eq!(
+            governor.report(),
+            GovernorReport {
+                level: WorkLevel::Full,
+                balance_cycles: Some(4_000),
+                burn_rate_per_sec: 0.0,
+                window_instructions: 0,
+            }
+        );
+
+        assert_eq!(governor.observe_balance(500, 152), WorkLevel::Reduced);
+        assert_eq!(governor.report().burn_rate_per_sec, 70.0);
+        assert!(!scheduler.is_paused());
+
+        assert_eq!(governor.observe_balance(50, 160), WorkLevel::Stopped);
+        assert_eq!(governor.run("snapshot", 500, || ()), None);
+        assert!(scheduler.is_paused());
+
+        assert_eq!(governor.observe_balance(10_000, 170), WorkLevel::Full);
+        assert!(!scheduler.is_paused());
+    }
+}
@@ -0,0 +1,579 @@
+//! A driver of the scheduler for the tests.
+
+use std::collections::BTreeMap;
+
+use ic_stable_structures::{StableUnboundedMap, VectorMemory};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::scheduler::{Scheduler, TaskScheduler};
+use crate::task::{InnerScheduledTask, ScheduledTask, Task, TaskStatus};
+use crate::time::set_mock_time_secs;
+
+type HarnessScheduler<T> =
+    Scheduler<T, StableUnboundedMap<u32, InnerScheduledTask<T>, VectorMemory>>;
+
+/// Drives a [`Scheduler`] step by step with a mock time, without a heartbeat or timers.
+///
+/// Every [`tick`](Self::tick) is an iteration of the scheduler: the tasks due at the current
+/// mock time are executed to completion, one after another in the order of their keys. The
+/// tasks appended during the iteration are executed in the next one.
+///
+/// While the harness exists, the scheduler of the current thread uses the mock time of the
+/// harness instead of the system time.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut harness = SchedulerTestHarness::new(1_000);
+/// let key = harness.append_task(MyTask::Transfer { amount: 10 }.into());
+///
+/// harness.tick(1);
+/// assert_eq!(harness.execution_order(), &[key]);
+/// assert!(matches!(harness.status(key), Some(TaskStatus::Completed { .. })));
+/// ```
+pub struct SchedulerTestHarness<T: 'static + Task + Serialize + DeserializeOwned> {
+    scheduler: HarnessScheduler<T>,
+    now_secs: u64,
+    execution_order: Vec<u32>,
+    finished: BTreeMap<u32, TaskStatus>,
+}
+
+impl<T: 'static + Task + Serialize + DeserializeOwned> SchedulerTestHarness<T> {
+    /// Creates a harness with an empty scheduler and the mock time set to the given timestamp.
+    pub fn new(now_secs: u64) -> Self {
+        set_mock_time_secs(Some(now_secs));
+        Self {
+            scheduler: Scheduler::new(StableUnboundedMap::new(VectorMemory::default())),
+            now_secs,
+            execution_order: vec![],
+            finished: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the driven scheduler.
+    pub fn scheduler(&self) -> &HarnessScheduler<T> {
+        &self.scheduler
+    }
+
+    /// Returns the driven scheduler, to configure it.
+    pub fn scheduler_mut(&mut self) -> &mut HarnessScheduler<T> {
+        &mut self.scheduler
+    }
+
+    /// Returns the mock time in seconds.
+    pub fn now_secs(&self) -> u64 {
+        self.now_secs
+    }
+
+    /// Moves the mock time forward.
+    pub fn advance_time(&mut self, secs: u64) {
+        self.now_secs += secs;
+        set_mock_time_secs(Some(self.now_secs));
+    }
+
+    /// Appends a task to the scheduler and returns its key.
+    pub fn append_task(&self, task: ScheduledTask<T>) -> u32 {
+        self.scheduler.append_task(task)
+    }
+
+    /// Runs `iterations` iterations of the scheduler at the current mock time.
+    /// Returns the number of the executed tasks.
+    pub fn tick(&mut self, iterations: usize) -> usize {
+        let mut executed = 0;
+        for _ in 0..iterations {
+            let scheduled_tasks = self.scheduler.schedule_due_tasks(self.now_secs);
+            for task_key in scheduled_tasks {
+                self.execution_order.push(task_key);
+                executed += 1;
+                if let Some(status) =
+                    futures::executor::block_on(self.scheduler.execute_task(task_key))
+                {
+                    self.finished.insert(task_key, status);
+                }
+            }
+        }
+
+        executed
+    }
+
+    /// Runs iterations of the scheduler until no task is executed, at most `max_iterations`.
+    /// Returns the number of the executed tasks.
+    pub fn run_until_idle(&mut self, max_iterations: usize) -> usize {
+        let mut executed = 0;
+        for _ in 0..max_iterations {
+            match self.tick(1) {
+                0 => break,
+                count => executed += count,
+            }
+        }
+
+        executed
+    }
+
+    /// Returns the keys of the executed tasks in the order of the executions. A retried task
+    /// appears once for every execution.
+    pub fn execution_order(&self) -> &[u32] {
+        &self.execution_order
+    }
+
+    /// Returns the status of the task, either pending in the scheduler or finished.
+    pub fn status(&self, task_key: u32) -> Option<TaskStatus> {
+        self.scheduler
+            .get_task(task_key)
+            .map(|task| task.status().clone())
+            .or_else(|| self.finished.get(&task_key).cloned())
+    }
+
+    /// Returns the final statuses of the completed or failed tasks, by their keys.
+    pub fn finished(&self) -> &BTreeMap<u32, TaskStatus> {
+        &self.finished
+    }
+
+    /// Returns the keys of the tasks pending in the scheduler.
+    pub fn pending_tasks(&self) -> Vec<u32> {
+        use ic_stable_structures::IterableUnboundedMapStructure;
+
+        self.scheduler
+            .pending_tasks
+            .lock()
+            .iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+impl<T: 'static + Task + Serialize + DeserializeOwned> Drop for SchedulerTestHarness<T> {
+    fn drop(&mut self) {
+        set_mock_time_secs(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    use futures::FutureExt;
+    use ic_exports::instructions::burn_mock_instructions;
+    use ic_stable_structures::UnboundedMapStructure;
+    use parking_lot::Mutex;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::scheduler::{TaskProblem, TaskTypeStats, MESSAGE_INSTRUCTION_LIMIT};
+    use crate::task::{TaskFailure, TaskOptions};
+    use crate::SchedulerError;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    enum TestTask {
+        Spawn {
+            children: u32,
+        },
+        FollowUp {
+            delay_secs: u64,
+        },
+        Leaf,
+        Fail,
+        Panic,
+        /// Waits until [`release_slow_tasks`] is called, e.g. for an inter-canister call.
+        Slow,
+    }
+
+    thread_local! {
+        static SLOW_TASKS_RELEASED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+
+    fn release_slow_tasks() {
+        SLOW_TASKS_RELEASED.with(|released| released.set(true));
+    }
+
+    impl Task for TestTask {
+        fn execute(
+            &self,
+            task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>> {
+            let result = match self {
+                TestTask::Spawn { children } => {
+                    for _ in 0..*children {
+                        task_scheduler.append_task(TestTask::Leaf.into());
+                    }
+                    Ok(())
+                }
+                TestTask::FollowUp { delay_secs } => {
+                    task_scheduler.append_delayed(TestTask::Leaf, *delay_secs);
+                    Ok(())
+                }
+                TestTask::Leaf => {
+                    burn_mock_instructions(100);
+                    Ok(())
+                }
+                TestTask::Fail => {
+                    burn_mock_instructions(300);
+                    Err(SchedulerError::TaskExecutionFailed("failed".into()))
+                }
+                // Panics during the execution.
+                TestTask::Panic => Ok(()),
+                TestTask::Slow => Ok(()),
+            };
+            let panics = *self == TestTask::Panic;
+            let slow = *self == TestTask::Slow;
+            Box::pin(async move {
+                if panics {
+                    panic!("broken invariant");
+                }
+                if slow {
+                    futures::future::poll_fn(|_| match SLOW_TASKS_RELEASED.with(|r| r.get()) {
+                        true => std::task::Poll::Ready(()),
+                        false => std::task::Poll::Pending,
+                    })
+                    .await;
+                }
+                result
+            })
+        }
+
+        fn task_type(&self) -> &'static str {
+            match self {
+                TestTask::Spawn { .. } => "spawn",
+                TestTask::FollowUp { .. } => "follow_up",
+                TestTask::Leaf => "leaf",
+                TestTask::Fail => "fail",
+                TestTask::Panic => "panic",
+                TestTask::Slow => "slow",
+            }
+        }
+    }
+
+    #[test]
+    fn tick_runs_the_tasks_in_order() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let spawn = harness.append_task(TestTask::Spawn { children: 2 }.into());
+        let delayed = harness.append_task(
+            (
+                TestTask::Leaf,
+                TaskOptions::new().with_execute_after_timestamp_in_secs(110),
+            )
+                .into(),
+        );
+
+        assert_eq!(harness.tick(1), 1);
+        assert_eq!(harness.execution_order(), &[spawn]);
+        assert!(matches!(
+            harness.status(spawn),
+            Some(TaskStatus::Completed {
+                timestamp_secs: 100
+            })
+        ));
+        assert_eq!(harness.pending_tasks(), vec![delayed, 2, 3]);
+
+        assert_eq!(harness.run_until_idle(10), 2);
+        assert_eq!(harness.execution_order(), &[spawn, 2, 3]);
+        assert!(matches!(
+            harness.status(delayed),
+            Some(TaskStatus::Waiting { .. })
+        ));
+
+        harness.advance_time(10);
+        assert_eq!(harness.tick(1), 1);
+        assert!(harness.pending_tasks().is_empty());
+        assert_eq!(harness.finished().len(), 4);
+    }
+
+    #[test]
+    fn tasks_append_follow_ups_without_boilerplate() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let follow_up = harness.append_task(TestTask::FollowUp { delay_secs: 30 }.into());
+        let failing = harness.scheduler().append_task_with_options(
+            TestTask::Fail,
+            TaskOptions::new()
+                .with_max_retries_policy(1)
+                .with_fixed_backoff_policy(0),
+        );
+
+        assert_eq!(harness.tick(1), 2);
+        assert_eq!(harness.pending_tasks(), [failing, 2]);
+        assert_eq!(
+            harness.scheduler().get_task(2).unwrap().options(),
+            &TaskOptions::new().with_execute_after_timestamp_in_secs(130)
+        );
+
+        // The failed task is retried, the follow-up waits for its delay.
+        assert_eq!(harness.tick(1), 1);
+        harness.advance_time(30);
+        assert_eq!(harness.tick(1), 1);
+        assert_eq!(harness.execution_order(), &[follow_up, failing, failing, 2]);
+    }
+
+    #[test]
+    fn validation_reports_and_quarantines_inconsistent_tasks() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let quarantined = Arc::new(Mutex::new(vec![]));
+        let recorded = quarantined.clone();
+        harness
+            .scheduler_mut()
+            .on_completion_callback(move |task| recorded.lock().push(task.id()));
+        harness.append_task(TestTask::Leaf.into());
+
+        {
+            let mut pending_tasks = harness.scheduler().pending_tasks.lock();
+            let task =
+                |id, status| InnerScheduledTask::with_status(id, TestTask::Leaf.into(), status);
+            pending_tasks.insert(&1, &task(5, TaskStatus::waiting(100)));
+            pending_tasks.insert(&2, &task(2, TaskStatus::completed(90)));
+            let mut exhausted = task(3, TaskStatus::waiting(90));
+            exhausted.options.failures = 2;
+            pending_tasks.insert(&3, &exhausted);
+            pending_tasks.insert(&4, &task(4, TaskStatus::waiting(200)));
+            pending_tasks.insert(&6, &task(7, TaskStatus::waiting(100)));
+            pending_tasks.insert(&7, &task(7, TaskStatus::waiting(100)));
+        }
+
+        let report = harness.scheduler().validate(false);
+        assert_eq!(report.checked, 7);
+        assert_eq!(
+            report
+                .issues
+                .iter()
+                .map(|issue| (issue.key, issue.problem.clone()))
+                .collect::<Vec<_>>(),
+            [
+                (1, TaskProblem::KeyMismatch { id: 5 }),
+                (2, TaskProblem::FinishedNotRemoved),
+                (3, TaskProblem::RetriesExhausted { failures: 2 }),
+                (
+                    4,
+                    TaskProblem::StatusInFuture {
+                        timestamp_secs: 200
+                    }
+                ),
+                (6, TaskProblem::DuplicateId { id: 7 }),
+            ]
+        );
+        assert_eq!(report.quarantined, 0);
+        assert_eq!(harness.pending_tasks().len(), 7);
+
+        let report = harness.scheduler().validate(true);
+        assert_eq!(report.quarantined, 5);
+        assert_eq!(harness.pending_tasks(), [0, 7]);
+        assert_eq!(*quarantined.lock(), [5, 2, 3, 4, 7]);
+        assert!(harness.scheduler().validate(true).is_ok());
+    }
+
+    #[test]
+    fn failed_tasks_are_retried_after_the_backoff() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let key = harness.append_task(
+            (
+                TestTask::Fail,
+                TaskOptions::new()
+                    .with_max_retries_policy(1)
+                    .with_fixed_backoff_policy(5),
+            )
+                .into(),
+        );
+
+        harness.tick(3);
+        assert_eq!(harness.execution_order(), &[key]);
+        assert!(matches!(
+            harness.status(key),
+            Some(TaskStatus::Waiting { .. })
+        ));
+        assert_eq!(
+            harness.scheduler().get_task(key).unwrap().failure_history(),
+            &[TaskFailure {
+                timestamp_secs: 100,
+                error: "TaskExecutionFailed: failed".to_string(),
+            }]
+        );
+
+        harness.advance_time(5);
+        harness.tick(1);
+        assert_eq!(harness.execution_order(), &[key, key]);
+        assert!(matches!(
+            harness.status(key),
+            Some(TaskStatus::Failed {
+                timestamp_secs: 105,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn panicking_task_is_a_failed_attempt() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let panicking = harness.append_task(
+            (
+                TestTask::Panic,
+                TaskOptions::new()
+                    .with_max_retries_policy(1)
+                    .with_fixed_backoff_policy(0),
+            )
+                .into(),
+        );
+        let sibling = harness.append_task(TestTask::Leaf.into());
+
+        assert_eq!(harness.tick(1), 2);
+        assert!(matches!(
+            harness.status(sibling),
+            Some(TaskStatus::Completed { .. })
+        ));
+        let task = harness.scheduler().get_task(panicking).unwrap();
+        assert!(matches!(task.status(), TaskStatus::Waiting { .. }));
+        assert_eq!(
+            task.failure_history()[0].error,
+            "TaskPanicked: broken invariant"
+        );
+
+        harness.tick(1);
+        assert_eq!(
+            harness.status(panicking),
+            Some(TaskStatus::failed(
+                100,
+                SchedulerError::TaskPanicked("broken invariant".into())
+            ))
+        );
+    }
+
+    #[test]
+    fn timed_out_task_is_retried() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let key = harness.append_task(
+            (
+                TestTask::Leaf,
+                TaskOptions::new()
+                    .with_max_retries_policy(1)
+                    .with_fixed_backoff_policy(0),
+            )
+                .into(),
+        );
+
+        // The execution trapped after the task was set running.
+        {
+            let mut pending_tasks = harness.scheduler().pending_tasks.lock();
+            let mut task = pending_tasks.get(&key).unwrap();
+            task.status = TaskStatus::running(100);
+            pending_tasks.insert(&key, &task);
+        }
+
+        harness.advance_time(200);
+        assert_eq!(harness.tick(1), 0);
+        let task = harness.scheduler().get_task(key).unwrap();
+        assert_eq!(task.status(), &TaskStatus::waiting(300));
+        assert_eq!(task.options().failures, 1);
+
+        assert_eq!(harness.tick(1), 1);
+        assert!(matches!(
+            harness.status(key),
+            Some(TaskStatus::Completed { .. })
+        ));
+        assert_eq!(harness.scheduler().stats().tasks_retried, 1);
+    }
+
+    #[test]
+    fn slow_task_is_not_retried_while_running() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let key = harness.append_task(
+            (
+                TestTask::Slow,
+                TaskOptions::new()
+                    .with_max_retries_policy(1)
+                    .with_fixed_backoff_policy(0),
+            )
+                .into(),
+        );
+        let scheduler = harness.scheduler().clone();
+        assert_eq!(scheduler.schedule_due_tasks(100), [key]);
+        let mut execution = Box::pin(scheduler.execute_task(key));
+        assert_eq!((&mut execution).now_or_never(), None);
+
+        harness.advance_time(200);
+        assert_eq!(harness.tick(1), 0);
+        let task = harness.scheduler().get_task(key).unwrap();
+        assert_eq!(task.status(), &TaskStatus::running(100));
+        assert_eq!((task.attempt(), task.options().failures), (1, 0));
+
+        release_slow_tasks();
+        assert_eq!(
+            (&mut execution).now_or_never(),
+            Some(Some(TaskStatus::completed(100)))
+        );
+        assert!(harness.pending_tasks().is_empty());
+        assert_eq!(harness.scheduler().stats().tasks_retried, 0);
+    }
+
+    #[test]
+    fn superseded_attempt_is_ignored() {
+        let harness = SchedulerTestHarness::new(100);
+        let key = harness.append_task(TestTask::Slow.into());
+        let scheduler = harness.scheduler().clone();
+        scheduler.schedule_due_tasks(100);
+        let mut execution = Box::pin(scheduler.execute_task(key));
+        assert_eq!((&mut execution).now_or_never(), None);
+
+        // The task was requeued and started again, e.g. after an upgrade dropped the execution.
+        let mut requeued = scheduler.get_task(key).unwrap();
+        requeued.status = TaskStatus::running(100);
+        requeued.attempt = 2;
+        scheduler.pending_tasks.lock().insert(&key, &requeued);
+
+        release_slow_tasks();
+        assert_eq!((&mut execution).now_or_never(), Some(None));
+        assert_eq!(scheduler.get_task(key), Some(requeued));
+        assert_eq!(scheduler.stats().tasks_completed, 0);
+    }
+
+    #[test]
+    fn instructions_are_aggregated_by_task_type() {
+        let mut harness = SchedulerTestHarness::new(100);
+        let executions = Arc::new(Mutex::new(vec![]));
+        let recorded = executions.clone();
+        harness
+            .scheduler_mut()
+            .on_execution_callback(move |task, instructions| {
+                recorded.lock().push((task.id(), instructions))
+            });
+        harness.append_task(TestTask::Leaf.into());
+        harness.append_task(TestTask::Fail.into());
+        harness.append_task(TestTask::Leaf.into());
+
+        harness.tick(1);
+
+        let stats = harness.scheduler().task_type_stats();
+        assert_eq!(
+            stats["leaf"],
+            TaskTypeStats {
+                executions: 2,
+                total_instructions: 200,
+                max_instructions: 100,
+            }
+        );
+        assert_eq!(stats["leaf"].avg_instructions(), 100);
+        assert_eq!(stats["fail"].max_instructions, 300);
+        assert_eq!(*executions.lock(), [(0, 100), (1, 300), (2, 100)]);
+    }
+
+    #[test]
+    fn tasks_are_left_for_next_run_when_instruction_budget_is_exhausted() {
+        let mut harness = SchedulerTestHarness::new(100);
+        harness.scheduler_mut().set_run_instructions_fraction(0.25);
+        let keys = [
+            harness.append_task(TestTask::Leaf.into()),
+            harness.append_task(TestTask::Leaf.into()),
+        ];
+
+        // The message has already used a quarter of the instruction limit.
+        burn_mock_instructions(MESSAGE_INSTRUCTION_LIMIT / 4);
+        assert_eq!(harness.tick(1), 0);
+        assert_eq!(harness.pending_tasks(), keys);
+        assert!(matches!(
+            harness.status(keys[0]),
+            Some(TaskStatus::Waiting { .. })
+        ));
+
+        harness.scheduler_mut().set_run_instructions_fraction(1.0);
+        assert_eq!(harness.tick(1), 2);
+        assert!(harness.pending_tasks().is_empty());
+    }
+}
@@ -1,7 +1,20 @@
+pub mod archive;
+#[cfg(feature = "cycles")]
+pub mod cycles;
 mod error;
+pub mod governor;
+#[cfg(not(target_family = "wasm"))]
+pub mod harness;
+pub mod outbox;
+pub mod pubsub;
+pub mod retention;
 pub mod retry;
+pub mod runtime;
 pub mod scheduler;
+pub mod storage;
 pub mod task;
 mod time;
+pub mod trigger;
+pub mod watchdog;
 
-pub use error::{Result, SchedulerError};
+pub use error::{PubSubError, PubSubResult, Result, SchedulerError, TriggerError, TriggerResult};
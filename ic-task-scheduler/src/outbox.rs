@@ -0,0 +1,437 @@
+//! The outbox of the events to deliver to other canisters.
+//!
+//! The domain code appends an event to the outbox in the same message as the state change it
+//! describes, so the event is kept if and only if the change is committed. The dispatcher, run
+//! periodically e.g. by a timer, moves the events from the outbox to the delivery tasks of the
+//! task scheduler, which calls the subscriber canisters and retries the failed calls.
+//!
+//! The delivery is at least once: a call which was executed by the subscriber, but whose
+//! response was lost, is retried. The subscribers deduplicate the events by their
+//! [`EventDelivery::dedup_key`], e.g. with [`DeliveryDeduplicator`].
+
+use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_kit::ic;
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, CellStructure, IterableSortedMapStructure, StableBTreeMap,
+    StableCell, Storable,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::retry::{BackoffPolicy, RetryPolicy, RetryStrategy};
+use crate::scheduler::TaskScheduler;
+use crate::task::{ScheduledTask, Task, TaskOptions};
+use crate::SchedulerError;
+
+/// An event waiting in the outbox.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEvent {
+    /// Increasing id of the event in the outbox.
+    pub id: u64,
+    pub topic: String,
+    /// The candid encoded event.
+    pub payload: Vec<u8>,
+    pub created_at: u64,
+}
+
+impl Storable for OutboxEvent {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("serialization of outbox event failed"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(&bytes, Self).expect("deserialization of outbox event failed")
+    }
+}
+
+/// A canister receiving the events of the topics with an update method taking
+/// [`EventDelivery`].
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Subscriber {
+    pub canister: Principal,
+    pub method: String,
+    /// The topics of the events, all the events are delivered if empty.
+    pub topics: Vec<String>,
+}
+
+impl Subscriber {
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.topics.is_empty() || self.topics.iter().any(|subscribed| subscribed == topic)
+    }
+}
+
+/// The events appended by the domain code, waiting in stable memory to be dispatched.
+///
+/// ```
+/// use candid::Principal;
+/// use ic_stable_structures::VectorMemory;
+/// use ic_task_scheduler::outbox::{Outbox, Subscriber};
+///
+/// let mut outbox = Outbox::new(VectorMemory::default(), VectorMemory::default())
+///     .with_subscriber(Subscriber {
+///         canister: Principal::from_slice(&[1]),
+///         method: "on_event".to_string(),
+///         topics: vec!["transfer".to_string()],
+///     });
+///
+/// outbox.append("transfer", &(42u64, "alice".to_string()), 0);
+/// assert_eq!(outbox.len(), 1);
+/// // outbox.dispatch(&scheduler, ic::id(), 100) is called from a timer.
+/// ```
+pub struct Outbox<M: Memory> {
+    events: StableBTreeMap<u64, OutboxEvent, M>,
+    /// The id of the next event, kept apart from the events so the ids are not reused when
+    /// the outbox is empty.
+    next_id: StableCell<u64, M>,
+    subscribers: Vec<Subscriber>,
+    retry_strategy: RetryStrategy,
+}
+
+impl<M: Memory> Outbox<M> {
+    /// Create the outbox in the memories of the events and of the event ids. If the memories
+    /// contain an outbox, its events are kept.
+    ///
+    /// The failed deliveries are retried infinitely, with a growing delay up to an hour.
+    pub fn new(events_memory: M, id_memory: M) -> Self {
+        Self {
+            events: StableBTreeMap::new(events_memory),
+            next_id: StableCell::new(id_memory, 0).expect("failed to init outbox id cell"),
+            subscribers: vec![],
+            retry_strategy: RetryStrategy {
+                retry_policy: RetryPolicy::Infinite,
+                backoff_policy: BackoffPolicy::Variable {
+                    secs: vec![2, 10, 60, 600, 3600],
+                },
+            },
+        }
+    }
+
+    /// Add the subscriber, which receives the events dispatched from now on.
+    pub fn with_subscriber(mut self, subscriber: Subscriber) -> Self {
+        self.subscribers.push(subscriber);
+        self
+    }
+
+    /// Set the strategy of retrying the failed deliveries.
+    pub fn with_retry_strategy(mut self, retry_strategy: RetryStrategy) -> Self {
+        self.retry_strategy = retry_strategy;
+        self
+    }
+
+    pub fn subscribers(&self) -> &[Subscriber] {
+        &self.subscribers
+    }
+
+    /// Append the event of the topic to the outbox, and return its id.
+    ///
+    /// # Panics
+    /// If the event can't be encoded.
+    pub fn append<E: CandidType>(&mut self, topic: &str, event: &E, now: u64) -> u64 {
+        let id = *self.next_id.get();
+        self.next_id
+            .set(id + 1)
+            .expect("failed to write outbox id cell");
+        self.events.insert(
+            id,
+            OutboxEvent {
+                id,
+                topic: topic.to_string(),
+                payload: Encode!(event).expect("serialization of outbox event failed"),
+                created_at: now,
+            },
+        );
+        id
+    }
+
+    /// The number of the events waiting to be dispatched.
+    pub fn len(&self) -> u64 {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Move up to `max_events` oldest events to the scheduler, as a delivery task for each of
+    /// their subscribers. Returns the number of the dispatched events.
+    ///
+    /// The events without subscribers are dropped. `source` is the canister sending the events,
+    /// usually `ic::id()`.
+    pub fn dispatch(
+        &mut self,
+        scheduler: &dyn TaskScheduler<DeliveryTask>,
+        source: Principal,
+        max_events: usize,
+    ) -> usize {
+        let subscribers = std::mem::take(&mut self.subscribers);
+        let dispatched = self.dispatch_with(scheduler, source, max_events, |topic| {
+            subscribers
+                .iter()
+                .filter(|subscriber| subscriber.is_subscribed(topic))
+                .cloned()
+                .collect()
+        });
+        self.subscribers = subscribers;
+        dispatched
+    }
+
+    /// Same as [`Outbox::dispatch`], but the subscribers of the topics are returned by the
+    /// closure instead of the ones added to the outbox, e.g. from a subscription registry.
+    pub fn dispatch_with(
+        &mut self,
+        scheduler: &dyn TaskScheduler<DeliveryTask>,
+        source: Principal,
+        max_events: usize,
+        subscribers: impl Fn(&str) -> Vec<Subscriber>,
+    ) -> usize {
+        let events: Vec<_> = self.events.iter().take(max_events).collect();
+        for (id, event) in &events {
+            let tasks = subscribers(&event.topic)
+                .into_iter()
+                .map(|subscriber| {
+                    let options = TaskOptions::new()
+                        .with_retry_policy(self.retry_strategy.retry_policy.clone())
+                        .with_backoff_policy(self.retry_strategy.backoff_policy.clone());
+                    let task = DeliveryTask {
+                        subscriber,
+                        delivery: EventDelivery {
+                            source,
+                            event: event.clone(),
+                        },
+                    };
+                    ScheduledTask::with_options(task, options)
+                })
+                .collect::<Vec<_>>();
+
+            if !tasks.is_empty() {
+                scheduler.append_tasks(tasks);
+            }
+            self.events.remove(id);
+        }
+
+        events.len()
+    }
+}
+
+/// The argument of the subscriber method.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EventDelivery {
+    /// The canister which sent the event.
+    pub source: Principal,
+    pub event: OutboxEvent,
+}
+
+impl EventDelivery {
+    /// The key which is the same for all the deliveries of the event.
+    pub fn dedup_key(&self) -> DedupKey {
+        DedupKey {
+            source: self.source,
+            event_id: self.event.id,
+        }
+    }
+
+    /// Decode the event.
+    pub fn decode<E: CandidType + for<'de> Deserialize<'de>>(&self) -> candid::Result<E> {
+        Decode!(&self.event.payload, E)
+    }
+}
+
+/// The task delivering an event to a subscriber.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryTask {
+    pub subscriber: Subscriber,
+    pub delivery: EventDelivery,
+}
+
+impl Task for DeliveryTask {
+    fn execute(
+        &self,
+        _task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>> {
+        let task = self.clone();
+        Box::pin(async move {
+            ic::call::<_, (), _>(
+                task.subscriber.canister,
+                task.subscriber.method,
+                (task.delivery,),
+            )
+            .await
+            .map_err(|(code, message)| {
+                SchedulerError::TaskExecutionFailed(format!(
+                    "event delivery was rejected: {code:?}: {message}"
+                ))
+            })
+        })
+    }
+}
+
+/// Identifies an event among the events of all the sources.
+#[derive(
+    CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct DedupKey {
+    pub source: Principal,
+    pub event_id: u64,
+}
+
+impl Storable for DedupKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1 + 29 + 8,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let source = self.source.as_slice();
+        let mut bytes = Vec::with_capacity(1 + source.len() + 8);
+        bytes.push(source.len() as u8);
+        bytes.extend_from_slice(source);
+        bytes.extend_from_slice(&self.event_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let source_end = 1 + bytes[0] as usize;
+        Self {
+            source: Principal::from_slice(&bytes[1..source_end]),
+            event_id: u64::from_be_bytes(
+                bytes[source_end..]
+                    .try_into()
+                    .expect("dedup key has an event id"),
+            ),
+        }
+    }
+}
+
+/// The keys of the events received by a subscriber, in stable memory, to process every event
+/// once.
+pub struct DeliveryDeduplicator<M: Memory> {
+    received: StableBTreeMap<DedupKey, u64, M>,
+}
+
+impl<M: Memory> DeliveryDeduplicator<M> {
+    pub fn new(memory: M) -> Self {
+        Self {
+            received: StableBTreeMap::new(memory),
+        }
+    }
+
+    /// Remember the delivery received at `now`. Returns `false` if its event was already
+    /// received, so it must be skipped.
+    pub fn accept(&mut self, delivery: &EventDelivery, now: u64) -> bool {
+        let key = delivery.dedup_key();
+        if self.received.contains_key(&key) {
+            return false;
+        }
+
+        self.received.insert(key, now);
+        true
+    }
+
+    /// Forget the events received before the timestamp. Their deliveries must not be retried
+    /// anymore.
+    pub fn prune(&mut self, received_before: u64) {
+        let expired: Vec<_> = self
+            .received
+            .iter()
+            .filter(|(_, received_at)| *received_at < received_before)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            self.received.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+    use crate::task::InnerScheduledTask;
+
+    #[derive(Default, Clone)]
+    pub(crate) struct TestScheduler(pub(crate) Rc<RefCell<Vec<DeliveryTask>>>);
+
+    impl TaskScheduler<DeliveryTask> for TestScheduler {
+        fn append_task(&self, task: ScheduledTask<DeliveryTask>) -> u32 {
+            self.append_tasks(vec![task])[0]
+        }
+
+        fn append_tasks(&self, tasks: Vec<ScheduledTask<DeliveryTask>>) -> Vec<u32> {
+            let mut appended = self.0.borrow_mut();
+            tasks
+                .into_iter()
+                .map(|task| {
+                    appended.push(task.task);
+                    appended.len() as u32 - 1
+                })
+                .collect()
+        }
+
+        fn get_task(&self, _task_id: u32) -> Option<InnerScheduledTask<DeliveryTask>> {
+            None
+        }
+    }
+
+    fn subscriber(id: u8, topics: &[&str]) -> Subscriber {
+        Subscriber {
+            canister: Principal::from_slice(&[id]),
+            method: "on_event".to_string(),
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn events_are_dispatched_to_subscribers() {
+        let source = Principal::from_slice(&[9]);
+        let mut outbox = Outbox::new(VectorMemory::default(), VectorMemory::default())
+            .with_subscriber(subscriber(1, &["transfer"]))
+            .with_subscriber(subscriber(2, &[]));
+        outbox.append("transfer", &100u64, 1);
+        outbox.append("mint", &200u64, 2);
+        outbox.append("transfer", &300u64, 3);
+
+        let scheduler = TestScheduler::default();
+        assert_eq!(outbox.dispatch(&scheduler, source, 2), 2);
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.dispatch(&scheduler, source, 2), 1);
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.append("mint", &400u64, 4), 3);
+
+        let tasks = scheduler.0.borrow();
+        assert_eq!(
+            tasks
+                .iter()
+                .map(|task| (
+                    task.subscriber.canister.as_slice()[0],
+                    task.delivery.event.id,
+                    task.delivery.decode::<u64>().unwrap()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (1, 0, 100),
+                (2, 0, 100),
+                (2, 1, 200),
+                (1, 2, 300),
+                (2, 2, 300)
+            ]
+        );
+
+        let mut deduplicator = DeliveryDeduplicator::new(VectorMemory::default());
+        assert!(deduplicator.accept(&tasks[0].delivery, 10));
+        assert!(!deduplicator.accept(&tasks[0].delivery, 11));
+        assert!(deduplicator.accept(&tasks[3].delivery, 20));
+        deduplicator.prune(15);
+        assert!(deduplicator.accept(&tasks[0].delivery, 30));
+        assert!(!deduplicator.accept(&tasks[3].delivery, 31));
+    }
+}
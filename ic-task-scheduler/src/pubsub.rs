@@ -0,0 +1,403 @@
+//! The registry of the canisters subscribed to the topics of the events of the canister.
+//!
+//! Other canisters subscribe with the `subscribe` endpoint of [`PubSubCanister`], naming the
+//! update method receiving the [`EventDelivery`](crate::outbox::EventDelivery). The events
+//! appended to the [`Outbox`] are delivered to the subscribers of their topics by
+//! [`dispatch_to_subscriptions`].
+//!
+//! ```ignore
+//! // In `init` and `post_upgrade`.
+//! let memory = MEMORY_MANAGER.with(|mm| mm.get(SUBSCRIPTIONS_MEMORY_ID));
+//! init_subscriptions(memory, &["transfer", "mint"]);
+//!
+//! // Or with the limits of the subscriptions other than the default ones.
+//! set_subscriptions(
+//!     Subscriptions::new(memory)
+//!         .with_topics(&["transfer", "mint"])
+//!         .with_max_subscriptions(1_000)
+//!         .with_max_subscriptions_per_subscriber(2),
+//! );
+//!
+//! // In a timer.
+//! OUTBOX.with(|outbox| {
+//!     dispatch_to_subscriptions(&mut outbox.borrow_mut(), &scheduler, ic::id(), 100)
+//! });
+//! ```
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Principal};
+use ic_canister::{generate_exports, generate_idl, query, update, Canister, Idl, PreUpdate};
+use ic_kit::ic;
+use ic_stable_structures::stable_structures::{DefaultMemoryImpl, Memory};
+use ic_stable_structures::{Bound, MultimapStructure, StableMultimap, Storable, VirtualMemory};
+use serde::Deserialize;
+
+use crate::error::{PubSubError, PubSubResult};
+use crate::outbox::{DeliveryTask, Outbox, Subscriber};
+use crate::scheduler::TaskScheduler;
+
+/// The max length of the topics and the method names in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+const MAX_PRINCIPAL_LEN: usize = 29;
+
+/// The default max number of the subscriptions of a canister to the topics.
+pub const DEFAULT_MAX_SUBSCRIPTIONS_PER_SUBSCRIBER: u64 = 32;
+
+/// The default max number of the subscriptions of all the canisters.
+pub const DEFAULT_MAX_SUBSCRIPTIONS: u64 = 10_000;
+
+/// A subscription of the caller of [`PubSubCanister::get_subscriptions`].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub topic: String,
+    pub method: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Name(String);
+
+impl Storable for Name {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_NAME_LEN as u32,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct SubscriberKey(Principal);
+
+impl Storable for SubscriberKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_PRINCIPAL_LEN as u32,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(Principal::from_slice(&bytes))
+    }
+}
+
+/// The subscribers of the topics and their methods receiving the events, in stable memory.
+///
+/// The number of the subscriptions is limited, both for a subscriber and in total, so the
+/// subscribers can't exhaust the memory of the canister, and the scans of the subscriptions,
+/// e.g. by [`Subscriptions::subscriptions_of`], are bounded.
+pub struct Subscriptions<M: Memory> {
+    /// The topics allowed for subscription, any topic if empty.
+    topics: Vec<String>,
+    max_subscriptions: u64,
+    max_subscriptions_per_subscriber: u64,
+    subscriptions: StableMultimap<Name, SubscriberKey, Name, M>,
+}
+
+impl<M: Memory> Subscriptions<M> {
+    /// Create the registry in the memory. If the memory contains subscriptions, they are kept.
+    pub fn new(memory: M) -> Self {
+        Self {
+            topics: vec![],
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            max_subscriptions_per_subscriber: DEFAULT_MAX_SUBSCRIPTIONS_PER_SUBSCRIBER,
+            subscriptions: StableMultimap::new(memory),
+        }
+    }
+
+    /// Allow subscribing only to the topics.
+    pub fn with_topics(mut self, topics: &[&str]) -> Self {
+        self.topics = topics.iter().map(|topic| topic.to_string()).collect();
+        self
+    }
+
+    /// Set the max number of the subscriptions of all the canisters. The subscriptions made
+    /// before the limit was lowered are kept.
+    pub fn with_max_subscriptions(mut self, max_subscriptions: u64) -> Self {
+        self.max_subscriptions = max_subscriptions;
+        self
+    }
+
+    /// Set the max number of the subscriptions of a canister.
+    pub fn with_max_subscriptions_per_subscriber(mut self, max_subscriptions: u64) -> Self {
+        self.max_subscriptions_per_subscriber = max_subscriptions;
+        self
+    }
+
+    /// Subscribe the canister to the topic, replacing the method of its previous subscription
+    /// to the topic.
+    pub fn subscribe(
+        &mut self,
+        topic: &str,
+        subscriber: Principal,
+        method: &str,
+    ) -> PubSubResult<()> {
+        if !self.topics.is_empty() && !self.topics.iter().any(|allowed| allowed == topic) {
+            return Err(PubSubError::UnknownTopic(topic.to_string()));
+        }
+        if topic.is_empty() || topic.len() > MAX_NAME_LEN || method.len() > MAX_NAME_LEN {
+            return Err(PubSubError::InvalidName(format!(
+                "topic and method names must be 1 to {MAX_NAME_LEN} bytes"
+            )));
+        }
+
+        let topic = Name(topic.to_string());
+        let subscriber = SubscriberKey(subscriber);
+        // Replacing the method of a subscription doesn't add a subscription.
+        if self.subscriptions.get(&topic, &subscriber).is_none() {
+            if self.subscriptions.len() as u64 >= self.max_subscriptions {
+                return Err(PubSubError::SubscriptionsLimitReached(
+                    self.max_subscriptions,
+                ));
+            }
+            if self.subscriptions_of(subscriber.0).len() as u64
+                >= self.max_subscriptions_per_subscriber
+            {
+                return Err(PubSubError::SubscriberLimitReached(
+                    self.max_subscriptions_per_subscriber,
+                ));
+            }
+        }
+
+        self.subscriptions
+            .insert(&topic, &subscriber, &Name(method.to_string()));
+        Ok(())
+    }
+
+    /// Unsubscribe the canister from the topic. Returns `false` if it was not subscribed.
+    pub fn unsubscribe(&mut self, topic: &str, subscriber: Principal) -> bool {
+        topic.len() <= MAX_NAME_LEN
+            && self
+                .subscriptions
+                .remove(&Name(topic.to_string()), &SubscriberKey(subscriber))
+                .is_some()
+    }
+
+    /// The subscribers of the topic.
+    pub fn subscribers(&self, topic: &str) -> Vec<Subscriber> {
+        if topic.len() > MAX_NAME_LEN {
+            return vec![];
+        }
+
+        self.subscriptions
+            .range(&Name(topic.to_string()))
+            .map(|(subscriber, method)| Subscriber {
+                canister: subscriber.0,
+                method: method.0,
+                topics: vec![topic.to_string()],
+            })
+            .collect()
+    }
+
+    /// The subscriptions of the canister.
+    ///
+    /// All the subscriptions are scanned, which is bounded by the max number of the
+    /// subscriptions, see [`Subscriptions::with_max_subscriptions`].
+    pub fn subscriptions_of(&self, subscriber: Principal) -> Vec<Subscription> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, key, _)| key.0 == subscriber)
+            .map(|(topic, _, method)| Subscription {
+                topic: topic.0,
+                method: method.0,
+            })
+            .collect()
+    }
+}
+
+thread_local! {
+    static SUBSCRIPTIONS: RefCell<Option<Subscriptions<VirtualMemory<DefaultMemoryImpl>>>> =
+        const { RefCell::new(None) };
+}
+
+/// Open the registry of the canister in the memory, taken from the memory manager of the
+/// canister, allowing the subscriptions to the topics, or to any topic if the list is empty.
+/// Must be called in `init` and `post_upgrade`.
+pub fn init_subscriptions(memory: VirtualMemory<DefaultMemoryImpl>, topics: &[&str]) {
+    set_subscriptions(Subscriptions::new(memory).with_topics(topics));
+}
+
+/// Set the registry of the canister, e.g. created with the limits of the subscriptions other
+/// than the default ones. Must be called in `init` and `post_upgrade`.
+pub fn set_subscriptions(subscriptions: Subscriptions<VirtualMemory<DefaultMemoryImpl>>) {
+    SUBSCRIPTIONS.with(|cell| *cell.borrow_mut() = Some(subscriptions));
+}
+
+/// Call the closure with the registry of the canister.
+pub fn with_subscriptions<R>(
+    f: impl FnOnce(&mut Subscriptions<VirtualMemory<DefaultMemoryImpl>>) -> R,
+) -> PubSubResult<R> {
+    SUBSCRIPTIONS.with(|cell| match &mut *cell.borrow_mut() {
+        Some(subscriptions) => Ok(f(subscriptions)),
+        None => Err(PubSubError::NotInitialized),
+    })
+}
+
+/// Dispatch up to `max_events` events of the outbox to the subscribers of their topics in the
+/// registry of the canister. Returns the number of the dispatched events.
+pub fn dispatch_to_subscriptions<M: Memory>(
+    outbox: &mut Outbox<M>,
+    scheduler: &dyn TaskScheduler<DeliveryTask>,
+    source: Principal,
+    max_events: usize,
+) -> PubSubResult<usize> {
+    with_subscriptions(|subscriptions| {
+        outbox.dispatch_with(scheduler, source, max_events, |topic| {
+            subscriptions.subscribers(topic)
+        })
+    })
+}
+
+/// Whether the principal is a canister id rather than a user.
+fn is_canister(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+    bytes.len() == 10 && bytes[9] == 0x01
+}
+
+/// The API for other canisters to subscribe to the events of the canister.
+pub trait PubSubCanister: Canister + Sized {
+    /// Subscribes the calling canister to the topic. The events of the topic are delivered by
+    /// calling its update `method` with an `EventDelivery` argument.
+    ///
+    /// Only canisters are allowed to call this method.
+    #[update(trait = true)]
+    fn subscribe(&self, topic: String, method: String) -> PubSubResult<()> {
+        let caller = ic::caller();
+        if !is_canister(&caller) {
+            return Err(PubSubError::Unauthorized(caller.to_string()));
+        }
+
+        with_subscriptions(|subscriptions| subscriptions.subscribe(&topic, caller, &method))?
+    }
+
+    /// Unsubscribes the calling canister from the topic. Returns `false` if it was not
+    /// subscribed.
+    #[update(trait = true)]
+    fn unsubscribe(&self, topic: String) -> PubSubResult<bool> {
+        with_subscriptions(|subscriptions| subscriptions.unsubscribe(&topic, ic::caller()))
+    }
+
+    /// Returns the subscriptions of the calling canister.
+    #[query(trait = true)]
+    fn get_subscriptions(&self) -> PubSubResult<Vec<Subscription>> {
+        with_subscriptions(|subscriptions| subscriptions.subscriptions_of(ic::caller()))
+    }
+
+    // Important: This function *must* be defined to be the
+    // last one in the trait because it depends on the order
+    // of expansion of update/query(trait = true) methods.
+    fn get_idl() -> Idl {
+        generate_idl!()
+    }
+}
+
+generate_exports!(PubSubCanister);
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+    use crate::outbox::tests::TestScheduler;
+
+    const ALICE: Principal = Principal::from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    const BOB: Principal = Principal::from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    #[test]
+    fn events_are_delivered_to_subscribers() {
+        let mut subscriptions =
+            Subscriptions::new(VectorMemory::default()).with_topics(&["transfer", "mint"]);
+        subscriptions
+            .subscribe("transfer", ALICE, "on_transfer")
+            .unwrap();
+        subscriptions
+            .subscribe("transfer", BOB, "on_event")
+            .unwrap();
+        subscriptions.subscribe("mint", BOB, "on_event").unwrap();
+        assert_eq!(
+            subscriptions.subscribe("burn", BOB, "on_event"),
+            Err(PubSubError::UnknownTopic("burn".to_string()))
+        );
+        assert!(subscriptions.unsubscribe("mint", BOB));
+        assert!(!subscriptions.unsubscribe("mint", BOB));
+
+        assert_eq!(
+            subscriptions.subscriptions_of(BOB),
+            [Subscription {
+                topic: "transfer".to_string(),
+                method: "on_event".to_string()
+            }]
+        );
+
+        let mut outbox = Outbox::new(VectorMemory::default(), VectorMemory::default());
+        outbox.append("transfer", &1u64, 0);
+        outbox.append("mint", &2u64, 0);
+
+        let scheduler = TestScheduler::default();
+        let source = Principal::from_slice(&[9]);
+        outbox.dispatch_with(&scheduler, source, 10, |topic| {
+            subscriptions.subscribers(topic)
+        });
+
+        let tasks = scheduler.0.borrow();
+        assert_eq!(
+            tasks
+                .iter()
+                .map(|task| (
+                    task.subscriber.canister,
+                    task.subscriber.method.as_str(),
+                    task.delivery.event.topic.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (ALICE, "on_transfer", "transfer"),
+                (BOB, "on_event", "transfer")
+            ]
+        );
+        assert!(outbox.is_empty());
+        assert!(is_canister(&ALICE));
+        assert!(!is_canister(&Principal::anonymous()));
+    }
+
+    #[test]
+    fn subscriptions_are_limited() {
+        let mut subscriptions = Subscriptions::new(VectorMemory::default())
+            .with_max_subscriptions(3)
+            .with_max_subscriptions_per_subscriber(2);
+
+        subscriptions
+            .subscribe("transfer", ALICE, "on_event")
+            .unwrap();
+        subscriptions.subscribe("mint", ALICE, "on_event").unwrap();
+        assert_eq!(
+            subscriptions.subscribe("burn", ALICE, "on_event"),
+            Err(PubSubError::SubscriberLimitReached(2))
+        );
+        // The method of a subscription can be replaced at the limit.
+        subscriptions.subscribe("mint", ALICE, "on_mint").unwrap();
+
+        subscriptions
+            .subscribe("transfer", BOB, "on_event")
+            .unwrap();
+        assert_eq!(
+            subscriptions.subscribe("mint", BOB, "on_event"),
+            Err(PubSubError::SubscriptionsLimitReached(3))
+        );
+
+        assert!(subscriptions.unsubscribe("transfer", ALICE));
+        subscriptions.subscribe("mint", BOB, "on_event").unwrap();
+        assert_eq!(subscriptions.subscriptions_of(ALICE).len(), 1);
+        assert_eq!(subscriptions.subscriptions_of(BOB).len(), 2);
+    }
+}
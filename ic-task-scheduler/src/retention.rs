@@ -0,0 +1,362 @@
+//! The retention rules of the structures of the canister, enforced incrementally by one sweeper
+//! instead of a cleanup task in every canister.
+//!
+//! Every map registers its [`RetentionRule`] in the [`RetentionManager`]: the max age of the
+//! entries, the max number of the entries and a predicate of the entries to remove. The sweeper,
+//! run periodically e.g. by a timer or by the [`Governor`](crate::governor::Governor), scans a
+//! batch of the entries of the structures in every run, and continues from the same key in the
+//! next run, so that the cleanup of the large maps is spread across the rounds.
+//!
+//! ```ignore
+//! let mut retention = RetentionManager::new().with_batch_size(500);
+//! retention.register(
+//!     "events",
+//!     events.clone(),
+//!     RetentionRule::new()
+//!         .with_max_age(30 * 24 * 3600, |_, event: &Event| event.timestamp_secs)
+//!         .with_max_entries(100_000),
+//! );
+//!
+//! ic_cdk_timers::set_timer_interval(Duration::from_secs(60), move || {
+//!     retention.sweep(time_secs());
+//! });
+//! ```
+
+use std::cell::RefCell;
+use std::ops::Bound;
+use std::rc::Rc;
+
+use candid::CandidType;
+use ic_exports::instructions::instruction_counter;
+use ic_stable_structures::{BTreeMapStructure, IterableSortedMapStructure};
+use log::debug;
+use serde::Deserialize;
+
+/// The instructions a sweep may execute by default, leaving the rest of the message limit to
+/// the caller.
+pub const DEFAULT_SWEEP_INSTRUCTION_BUDGET: u64 = 1_000_000_000;
+
+type Timestamp<K, V> = Box<dyn Fn(&K, &V) -> u64>;
+type Predicate<K, V> = Box<dyn Fn(&K, &V) -> bool>;
+
+/// The entries of a map to remove.
+pub struct RetentionRule<K, V> {
+    max_age: Option<(u64, Timestamp<K, V>)>,
+    max_entries: Option<u64>,
+    predicate: Option<Predicate<K, V>>,
+}
+
+impl<K, V> Default for RetentionRule<K, V> {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            max_entries: None,
+            predicate: None,
+        }
+    }
+}
+
+impl<K, V> RetentionRule<K, V> {
+    /// Create the rule keeping all the entries.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove the entries older than `max_age_secs`, by the timestamp of the entry in seconds.
+    pub fn with_max_age(
+        mut self,
+        max_age_secs: u64,
+        timestamp_secs: impl Fn(&K, &V) -> u64 + 'static,
+    ) -> Self {
+        self.max_age = Some((max_age_secs, Box::new(timestamp_secs)));
+        self
+    }
+
+    /// Keep at most `max_entries` entries, removing the entries with the lowest keys first, so
+    /// the keys must increase with the age of the entries, e.g. the ids or the timestamps.
+    pub fn with_max_entries(mut self, max_entries: u64) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Remove the entries for which the predicate returns `true`.
+    pub fn with_predicate(mut self, predicate: impl Fn(&K, &V) -> bool + 'static) -> Self {
+        self.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    fn is_expired(&self, key: &K, value: &V, now_secs: u64) -> bool {
+        self.max_age.as_ref().is_some_and(|(max_age, timestamp)| {
+            timestamp(key, value).saturating_add(*max_age) < now_secs
+        }) || self
+            .predicate
+            .as_ref()
+            .is_some_and(|predicate| predicate(key, value))
+    }
+}
+
+/// The progress of the sweeps of a structure.
+#[derive(CandidType, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RetentionProgress {
+    pub name: String,
+    /// `true` if a pass over the structure is in progress.
+    pub in_progress: bool,
+    /// The entries scanned and removed in the current pass.
+    pub scanned: u64,
+    pub removed: u64,
+    /// The number of the finished passes over the structure.
+    pub passes: u64,
+    /// The entries removed over all the passes.
+    pub total_removed: u64,
+    /// The time of the last finished pass.
+    pub last_pass_secs: Option<u64>,
+}
+
+trait Sweep {
+    /// Scan at most `max_entries` entries of the current pass. Returns `true` if the pass is
+    /// finished.
+    fn sweep(&mut self, now_secs: u64, max_entries: usize) -> bool;
+
+    fn progress(&self) -> &RetentionProgress;
+}
+
+struct RetainedMap<S, K, V> {
+    map: Rc<RefCell<S>>,
+    rule: RetentionRule<K, V>,
+    /// The last key scanned in the current pass.
+    cursor: Option<K>,
+    progress: RetentionProgress,
+}
+
+impl<S, K, V> Sweep for RetainedMap<S, K, V>
+where
+    S: BTreeMapStructure<K, V> + IterableSortedMapStructure<K, V>,
+    K: Clone,
+{
+    fn sweep(&mut self, now_secs: u64, max_entries: usize) -> bool {
+        let mut map = self.map.borrow_mut();
+        if !self.progress.in_progress {
+            self.progress.in_progress = true;
+            self.progress.scanned = 0;
+            self.progress.removed = 0;
+        }
+
+        // The oldest entries over the limit are removed first, they are scanned at the start
+        // of the map anyway.
+        let mut excess = self
+            .rule
+            .max_entries
+            .map_or(0, |max_entries| map.len().saturating_sub(max_entries));
+
+        let start = match self.cursor.take() {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let batch = map
+            .range((start, Bound::Unbounded))
+            .take(max_entries)
+            .collect::<Vec<_>>();
+        let finished = batch.len() < max_entries;
+
+        for (key, value) in &batch {
+            if excess > 0 || self.rule.is_expired(key, value, now_secs) {
+                map.remove(key);
+                excess = excess.saturating_sub(1);
+                self.progress.removed += 1;
+                self.progress.total_removed += 1;
+            }
+        }
+        self.progress.scanned += batch.len() as u64;
+        self.cursor = batch.into_iter().last().map(|(key, _)| key);
+
+        if finished {
+            self.cursor = None;
+            self.progress.in_progress = false;
+            self.progress.passes += 1;
+            self.progress.last_pass_secs = Some(now_secs);
+        }
+        finished
+    }
+
+    fn progress(&self) -> &RetentionProgress {
+        &self.progress
+    }
+}
+
+/// Enforces the retention rules of the registered structures, see the module docs.
+///
+/// The manager is kept in the heap, so after an upgrade the structures must be registered again
+/// and the passes start over.
+pub struct RetentionManager {
+    structures: Vec<Box<dyn Sweep>>,
+    /// The structure swept next.
+    next: usize,
+    batch_size: usize,
+    instruction_budget: u64,
+}
+
+impl Default for RetentionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetentionManager {
+    pub fn new() -> Self {
+        Self {
+            structures: vec![],
+            next: 0,
+            batch_size: 100,
+            instruction_budget: DEFAULT_SWEEP_INSTRUCTION_BUDGET,
+        }
+    }
+
+    /// Scan at most `batch_size` entries of a structure at once. Defaults to 100.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Stop the sweep when the instruction counter of the message reaches the budget.
+    pub fn with_instruction_budget(mut self, instructions: u64) -> Self {
+        self.instruction_budget = instructions;
+        self
+    }
+
+    /// Enforce the rule on the map.
+    pub fn register<S, K, V>(&mut self, name: &str, map: Rc<RefCell<S>>, rule: RetentionRule<K, V>)
+    where
+        S: 'static + BTreeMapStructure<K, V> + IterableSortedMapStructure<K, V>,
+        K: 'static + Clone,
+        V: 'static,
+    {
+        self.structures.push(Box::new(RetainedMap {
+            map,
+            rule,
+            cursor: None,
+            progress: RetentionProgress {
+                name: name.to_string(),
+                ..Default::default()
+            },
+        }));
+    }
+
+    /// Sweep the structures in turns, a batch at a time, until every structure finished its
+    /// pass or the instruction budget is exhausted. Returns the number of the removed entries.
+    pub fn sweep(&mut self, now_secs: u64) -> u64 {
+        let removed_before = self.total_removed();
+        let mut finished = vec![false; self.structures.len()];
+        while !finished.iter().all(|finished| *finished) {
+            if instruction_counter() >= self.instruction_budget {
+                debug!(
+                    "Retention - Instruction budget exhausted, the sweep continues in the next run"
+                );
+                break;
+            }
+
+            let index = self.next;
+            self.next = (self.next + 1) % self.structures.len();
+            if !finished[index] {
+                finished[index] = self.structures[index].sweep(now_secs, self.batch_size);
+            }
+        }
+
+        self.total_removed() - removed_before
+    }
+
+    /// Returns the progress of the structures in the order of their registration.
+    pub fn progress(&self) -> Vec<RetentionProgress> {
+        self.structures
+            .iter()
+            .map(|structure| structure.progress().clone())
+            .collect()
+    }
+
+    fn total_removed(&self) -> u64 {
+        self.structures
+            .iter()
+            .map(|structure| structure.progress().total_removed)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_exports::instructions::burn_mock_instructions;
+    use ic_stable_structures::{StableBTreeMap, VectorMemory};
+
+    use super::*;
+
+    #[test]
+    fn rules_are_enforced_across_runs() {
+        // The values are the timestamps of the entries.
+        let events = Rc::new(RefCell::new(StableBTreeMap::new(VectorMemory::default())));
+        for id in 0..10u64 {
+            events.borrow_mut().insert(id, id * 10);
+        }
+        let sessions = Rc::new(RefCell::new(StableBTreeMap::new(VectorMemory::default())));
+        for id in 0..4u32 {
+            sessions.borrow_mut().insert(id, id % 2 == 0);
+        }
+
+        let mut retention = RetentionManager::new()
+            .with_batch_size(3)
+            .with_instruction_budget(100);
+        retention.register(
+            "events",
+            events.clone(),
+            RetentionRule::new()
+                .with_max_age(50, |_, timestamp: &u64| *timestamp)
+                .with_max_entries(8),
+        );
+        retention.register(
+            "sessions",
+            sessions.clone(),
+            RetentionRule::new().with_predicate(|_, expired: &bool| {
+                burn_mock_instructions(40);
+                *expired
+            }),
+        );
+
+        // The events 0 and 1 are over the max entries, 2 is too old. The budget is exhausted
+        // by the first batch of the sessions.
+        assert_eq!(retention.sweep(100), 3 + 2);
+        let progress = retention.progress();
+        assert!(progress[0].in_progress && progress[1].in_progress);
+        assert_eq!((progress[0].scanned, progress[0].removed), (3, 3));
+        assert_eq!((progress[1].scanned, progress[1].removed), (3, 2));
+
+        assert_eq!(retention.sweep(100), 0);
+
+        retention = retention.with_instruction_budget(u64::MAX);
+        assert_eq!(retention.sweep(100), 2);
+        assert_eq!(
+            events.borrow().iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            [5, 6, 7, 8, 9]
+        );
+        assert_eq!(
+            sessions
+                .borrow()
+                .iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            [1, 3]
+        );
+
+        let progress = retention.progress();
+        assert_eq!(
+            progress[0],
+            RetentionProgress {
+                name: "events".to_string(),
+                in_progress: false,
+                scanned: 10,
+                removed: 5,
+                passes: 1,
+                total_removed: 5,
+                last_pass_secs: Some(100),
+            }
+        );
+        assert_eq!(progress[1].passes, 1);
+        assert_eq!(progress[1].total_removed, 2);
+    }
+}
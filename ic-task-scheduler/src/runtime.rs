@@ -0,0 +1,143 @@
+//! The async runtime executing the tasks of the scheduler.
+//!
+//! By default the tasks are spawned with `ic::spawn`, so in a canister a task starts in the
+//! message of [`Scheduler::run`] and runs until its first `await` before the next task starts.
+//!
+//! With the `timer-spawn` feature, every task of a canister is spawned in a dedicated timer
+//! instead, so it's executed in its own message: a trapping task doesn't roll back the run,
+//! and the instructions of the tasks don't count against the message of the run. This was the
+//! behaviour of all the builds before the feature was introduced, so the canisters relying on it
+//! must enable the feature. It only takes effect on the wasm target, the native builds always
+//! use `ic::spawn`.
+//!
+//! With the `tokio-runtime` feature the tasks are spawned on the tokio [`LocalSet`] of the current
+//! thread instead, so the same scheduler and the same tasks run natively, e.g. for the off-chain
+//! simulations and the load tests:
+//!
+//! ```ignore
+//! let local = tokio::task::LocalSet::new();
+//! local
+//!     .run_until(run_every(scheduler, Duration::from_millis(100)))
+//!     .await;
+//! ```
+//!
+//! [`LocalSet`]: https://docs.rs/tokio/latest/tokio/task/struct.LocalSet.html
+//! [`Scheduler::run`]: crate::scheduler::Scheduler::run
+
+use std::future::Future;
+
+#[cfg(any(test, feature = "tokio-runtime"))]
+pub use native::run_every;
+
+/// Spawn the future on the tokio `LocalSet` of the current thread.
+///
+/// We use tokio for testing instead of ic_kit::ic::spawn because the latter blocks the current
+/// thread waiting for the spawned futures to complete. This makes impossible to test concurrent
+/// behavior.
+#[cfg(any(test, feature = "tokio-runtime"))]
+pub(crate) fn spawn<F: 'static + Future<Output = ()>>(future: F) {
+    tokio::task::spawn_local(future);
+}
+
+/// Spawn the future in a new timer, so that it's executed in its own message.
+#[cfg(all(
+    not(any(test, feature = "tokio-runtime")),
+    target_family = "wasm",
+    feature = "timer-spawn"
+))]
+#[inline(always)]
+pub(crate) fn spawn<F: 'static + Future<Output = ()>>(future: F) {
+    use ic_exports::timers::{IcTimers, Timers};
+
+    IcTimers.set_timer(
+        std::time::Duration::ZERO,
+        Box::new(move || ic_kit::ic::spawn(future)),
+    );
+}
+
+/// Spawn the future with the IC executor, in the current message.
+#[cfg(all(
+    not(any(test, feature = "tokio-runtime")),
+    not(all(target_family = "wasm", feature = "timer-spawn"))
+))]
+#[inline(always)]
+pub(crate) fn spawn<F: 'static + Future<Output = ()>>(future: F) {
+    ic_kit::ic::spawn(future);
+}
+
+#[cfg(any(test, feature = "tokio-runtime"))]
+mod native {
+    use std::time::Duration;
+
+    use ic_stable_structures::IterableUnboundedMapStructure;
+    use log::error;
+
+    use crate::scheduler::Scheduler;
+    use crate::task::{InnerScheduledTask, Task};
+
+    /// Run the scheduler every `interval`, like a timer of a canister. Must be called within a
+    /// tokio `LocalSet`.
+    pub async fn run_every<T, P>(scheduler: Scheduler<T, P>, interval: Duration)
+    where
+        T: 'static + Task,
+        P: 'static + IterableUnboundedMapStructure<u32, InnerScheduledTask<T>>,
+    {
+        loop {
+            if let Err(err) = scheduler.run() {
+                error!("Scheduler - Failed to run: {err}");
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::pin::Pin;
+    use std::time::Duration;
+
+    use ic_stable_structures::{StableUnboundedMap, VectorMemory};
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::scheduler::{Scheduler, TaskScheduler};
+    use crate::task::Task;
+    use crate::SchedulerError;
+
+    thread_local! {
+        static EXECUTED: Cell<u32> = const { Cell::new(0) };
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct CountTask;
+
+    impl Task for CountTask {
+        fn execute(
+            &self,
+            _task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>> {
+            Box::pin(async {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                EXECUTED.with(|executed| executed.set(executed.get() + 1));
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduler_runs_on_tokio() {
+        let scheduler = Scheduler::new(StableUnboundedMap::new(VectorMemory::default()));
+        scheduler.append_tasks(vec![CountTask.into(), CountTask.into()]);
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                tokio::task::spawn_local(run_every(scheduler.clone(), Duration::from_millis(10)));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert_eq!(EXECUTED.with(Cell::get), 2);
+                assert_eq!(scheduler.stats().tasks_completed, 2);
+            })
+            .await;
+    }
+}
@@ -1,27 +1,192 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 
+use candid::CandidType;
+use ic_exports::instructions::instruction_counter;
+use ic_stable_structures::stable_structures::Memory;
 use ic_stable_structures::IterableUnboundedMapStructure;
-use log::{debug, warn};
+use log::{debug, error, warn};
 use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
-use crate::task::{InnerScheduledTask, ScheduledTask, Task, TaskStatus};
+use crate::archive::{TaskArchive, TaskArchiveStore};
+use crate::storage::TaskStorage;
+use crate::task::{InnerScheduledTask, ScheduledTask, Task, TaskOptions, TaskStatus};
 use crate::time::time_secs;
-use crate::SchedulerError;
+use crate::{runtime, SchedulerError};
 
 type TaskCompletionCallback<T> = Box<dyn 'static + Fn(InnerScheduledTask<T>) + Send>;
+type TaskExecutionCallback<T> = Box<dyn 'static + Fn(&InnerScheduledTask<T>, u64) + Send>;
+
+/// The archive of the scheduler with the retention period of the finished tasks.
+struct ArchiveSettings<T> {
+    archive: Box<dyn TaskArchiveStore<T>>,
+    retention_secs: u64,
+}
 
 const DEFAULT_RUNNING_TASK_TIMEOUT_SECS: u64 = 120;
 
+/// The max number of the instructions of an update message, including the timers.
+pub const MESSAGE_INSTRUCTION_LIMIT: u64 = 40_000_000_000;
+
+const DEFAULT_RUN_INSTRUCTION_BUDGET: u64 = MESSAGE_INSTRUCTION_LIMIT / 2;
+
+/// The counters of the scheduler since the canister was installed or upgraded, and the number
+/// of the tasks in the scheduler.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SchedulerStats {
+    /// The calls of [`Scheduler::run`].
+    pub runs: u64,
+    pub tasks_appended: u64,
+    pub tasks_scheduled: u64,
+    pub tasks_completed: u64,
+    pub tasks_failed: u64,
+    pub tasks_retried: u64,
+    pub tasks_timed_out: u64,
+    /// The tasks waiting, scheduled or running, and the finished tasks not archived yet.
+    pub pending_tasks: u64,
+}
+
+/// The instructions used by the executions of a type of the tasks, see [`Task::task_type`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TaskTypeStats {
+    pub executions: u64,
+    pub total_instructions: u64,
+    pub max_instructions: u64,
+}
+
+impl TaskTypeStats {
+    /// Returns the average instructions used by an execution, or 0 if there were no executions.
+    pub fn avg_instructions(&self) -> u64 {
+        self.total_instructions
+            .checked_div(self.executions)
+            .unwrap_or_default()
+    }
+
+    fn record(&mut self, instructions: u64) {
+        self.executions += 1;
+        self.total_instructions = self.total_instructions.saturating_add(instructions);
+        self.max_instructions = self.max_instructions.max(instructions);
+    }
+}
+
+/// A problem of a stored task found by [`Scheduler::validate`].
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TaskProblem {
+    /// The task can't be decoded, e.g. after an incompatible change of the task type.
+    Undecodable,
+    /// The task is stored with the key of another task.
+    KeyMismatch { id: u32 },
+    /// The task is stored with the key of another task, and there are other records of the
+    /// task with the same id, i.e. a duplicated record.
+    DuplicateId { id: u32 },
+    /// The timestamp of the status is later than the current time.
+    StatusInFuture { timestamp_secs: u64 },
+    /// The task is finished, but it's kept in the pending tasks of a scheduler without an
+    /// archive.
+    FinishedNotRemoved,
+    /// The task is waiting for a retry which its retry policy doesn't allow.
+    RetriesExhausted { failures: u32 },
+}
+
+/// A stored task with a problem.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TaskIssue {
+    pub key: u32,
+    pub problem: TaskProblem,
+}
+
+/// The result of [`Scheduler::validate`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ValidationReport {
+    /// The number of the checked tasks.
+    pub checked: u64,
+    pub issues: Vec<TaskIssue>,
+    /// The number of the tasks removed from the scheduler because of their problems.
+    pub quarantined: u64,
+    /// The keys and the encoded bytes of the removed tasks which can't be decoded, so that they
+    /// can be inspected or stored elsewhere.
+    pub undecodable_records: Vec<(u32, Vec<u8>)>,
+}
+
+impl ValidationReport {
+    /// Returns `true` if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// The counters shared by the clones of the scheduler.
+#[derive(Default)]
+struct SchedulerCounters {
+    runs: AtomicU64,
+    tasks_appended: AtomicU64,
+    tasks_scheduled: AtomicU64,
+    tasks_completed: AtomicU64,
+    tasks_failed: AtomicU64,
+    tasks_retried: AtomicU64,
+    tasks_timed_out: AtomicU64,
+}
+
+impl SchedulerCounters {
+    fn inc(counter: &AtomicU64, by: u64) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+}
+
+/// The attempts of the tasks with an execution in flight, by the keys of the tasks.
+type RunningAttempts = Arc<Mutex<BTreeMap<u32, u32>>>;
+
+/// Registers the attempt of a task as running while its execution is in flight.
+///
+/// The registry is in the heap: the guard is dropped when the execution completes or when its
+/// future is cleaned up after a trap, and the registry is empty after an upgrade. So a task
+/// running for longer than the timeout without a registered attempt is known to be dead, while
+/// a task with a registered attempt is only slow, e.g. waiting for an inter-canister call.
+struct RunningAttempt {
+    running: RunningAttempts,
+    task_key: u32,
+    attempt: u32,
+}
+
+impl RunningAttempt {
+    fn new(running: RunningAttempts, task_key: u32, attempt: u32) -> Self {
+        running.lock().insert(task_key, attempt);
+        Self {
+            running,
+            task_key,
+            attempt,
+        }
+    }
+}
+
+impl Drop for RunningAttempt {
+    fn drop(&mut self) {
+        let mut running = self.running.lock();
+        if running.get(&self.task_key) == Some(&self.attempt) {
+            running.remove(&self.task_key);
+        }
+    }
+}
+
 /// A scheduler is responsible for executing tasks.
 pub struct Scheduler<
     T: 'static + Task,
     P: 'static + IterableUnboundedMapStructure<u32, InnerScheduledTask<T>>,
 > {
-    pending_tasks: Arc<Mutex<P>>,
+    pub(crate) pending_tasks: Arc<Mutex<P>>,
     phantom: std::marker::PhantomData<T>,
     on_completion_callback: Arc<Option<TaskCompletionCallback<T>>>,
+    on_execution_callback: Arc<Option<TaskExecutionCallback<T>>>,
     running_task_timeout_secs: AtomicU64,
+    run_instruction_budget: AtomicU64,
+    counters: Arc<SchedulerCounters>,
+    task_type_stats: Arc<Mutex<BTreeMap<&'static str, TaskTypeStats>>>,
+    paused: Arc<AtomicBool>,
+    archive: Arc<Mutex<Option<ArchiveSettings<T>>>>,
+    running: RunningAttempts,
 }
 
 impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerScheduledTask<T>>>
@@ -33,12 +198,20 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
             pending_tasks: Arc::new(Mutex::new(pending_tasks)),
             phantom: std::marker::PhantomData,
             on_completion_callback: Arc::new(None),
+            on_execution_callback: Arc::new(None),
             running_task_timeout_secs: AtomicU64::new(DEFAULT_RUNNING_TASK_TIMEOUT_SECS),
+            run_instruction_budget: AtomicU64::new(DEFAULT_RUN_INSTRUCTION_BUDGET),
+            counters: Arc::default(),
+            task_type_stats: Arc::default(),
+            paused: Arc::default(),
+            archive: Arc::new(Mutex::new(None)),
+            running: Arc::default(),
         }
     }
 
-    /// Set the timeout of a running task. If a task is running for more time the timeout, it will be
-    /// considered as stuck or panicked.
+    /// Set the timeout of a running task. If a task is running for more time the timeout and its
+    /// execution is not in flight anymore, e.g. it trapped or the canister was upgraded, it is
+    /// handled as a failed execution. A task which is only slow is left running.
     /// The default value is 120 seconds.
     pub fn set_running_task_timeout(&mut self, timeout_secs: u64) {
         debug!("Setting running task timeout to {} seconds", timeout_secs);
@@ -46,24 +219,147 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
             .store(timeout_secs, Ordering::Relaxed);
     }
 
+    /// Set the fraction of [`MESSAGE_INSTRUCTION_LIMIT`] which a run of the scheduler can use
+    /// to select the tasks. When the instructions used by the message exceed the fraction, the
+    /// remaining due tasks are left waiting until the next run instead of trapping the message.
+    /// The default value is 0.5.
+    pub fn set_run_instructions_fraction(&mut self, fraction: f64) {
+        let budget = (MESSAGE_INSTRUCTION_LIMIT as f64 * fraction.clamp(0.0, 1.0)) as u64;
+        debug!("Setting run instruction budget to {} instructions", budget);
+        self.run_instruction_budget.store(budget, Ordering::Relaxed);
+    }
+
+    /// Set the archive of the finished tasks. Affects all the clones of the scheduler.
+    ///
+    /// With the archive, the completed and failed tasks stay in the pending tasks with their
+    /// final status, so they can be queried with [`TaskScheduler::get_task`], and the runs of
+    /// the scheduler move them to the archive when they are older than `retention_secs`.
+    /// After that [`TaskScheduler::get_task`] returns them from the archive, and their keys are
+    /// not reused.
+    pub fn set_archive<M>(&mut self, archive: TaskArchive<T, M>, retention_secs: u64)
+    where
+        T: Serialize + DeserializeOwned,
+        M: 'static + Memory,
+    {
+        *self.archive.lock() = Some(ArchiveSettings {
+            archive: Box::new(archive),
+            retention_secs,
+        });
+    }
+
     /// Set a callback to be called when a task execution completes.
     pub fn on_completion_callback<F: 'static + Send + Fn(InnerScheduledTask<T>)>(&mut self, cb: F) {
         self.on_completion_callback = Arc::new(Some(Box::new(cb)));
     }
 
+    /// Set a callback to be called after every execution of a task with the number of the
+    /// instructions used by the execution, including the failed executions.
+    pub fn on_execution_callback<F: 'static + Send + Fn(&InnerScheduledTask<T>, u64)>(
+        &mut self,
+        cb: F,
+    ) {
+        self.on_execution_callback = Arc::new(Some(Box::new(cb)));
+    }
+
     /// Execute all pending tasks.
-    /// Each task is executed asynchronously in a dedicated ic_cdk::spawn call.
+    /// Each task is executed asynchronously by the [`runtime`](crate::runtime): in a dedicated
+    /// ic_cdk::spawn call, in a timer of its own with the `timer-spawn` feature, or on tokio
+    /// with the `tokio-runtime` feature.
     /// This function does not wait for the tasks to complete.
     /// Returns the number of tasks that have been launched.
     pub fn run(&self) -> Result<usize, SchedulerError> {
         self.run_with_timestamp(time_secs())
     }
 
+    /// Stop executing the tasks until [`Scheduler::resume`] is called. The tasks can still be
+    /// appended, and the tasks already running complete. Affects all the clones of the scheduler.
+    pub fn pause(&self) {
+        warn!("Scheduler - Paused");
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resume executing the tasks after [`Scheduler::pause`].
+    pub fn resume(&self) {
+        debug!("Scheduler - Resumed");
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Return the counters of the scheduler, shared by all its clones.
+    pub fn stats(&self) -> SchedulerStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let counters = &self.counters;
+        SchedulerStats {
+            runs: load(&counters.runs),
+            tasks_appended: load(&counters.tasks_appended),
+            tasks_scheduled: load(&counters.tasks_scheduled),
+            tasks_completed: load(&counters.tasks_completed),
+            tasks_failed: load(&counters.tasks_failed),
+            tasks_retried: load(&counters.tasks_retried),
+            tasks_timed_out: load(&counters.tasks_timed_out),
+            pending_tasks: self.pending_tasks.lock().len(),
+        }
+    }
+
+    /// Return the instructions used by the executions of the tasks by their types, shared by
+    /// all the clones of the scheduler.
+    ///
+    /// The instructions are counted in the call context of the execution, so they include the
+    /// instructions used before the `await` points of the task.
+    pub fn task_type_stats(&self) -> BTreeMap<String, TaskTypeStats> {
+        self.task_type_stats
+            .lock()
+            .iter()
+            .map(|(task_type, stats)| (task_type.to_string(), *stats))
+            .collect()
+    }
+
     fn run_with_timestamp(&self, now_timestamp_secs: u64) -> Result<usize, SchedulerError> {
+        if self.is_paused() {
+            debug!("Scheduler - Paused, not running tasks");
+            return Ok(0);
+        }
+
+        let scheduled_tasks = self.schedule_due_tasks(now_timestamp_secs);
+        SchedulerCounters::inc(&self.counters.runs, 1);
+        SchedulerCounters::inc(&self.counters.tasks_scheduled, scheduled_tasks.len() as u64);
+        for task_key in scheduled_tasks.iter().copied() {
+            let task_scheduler = self.clone();
+            runtime::spawn(async move {
+                task_scheduler.execute_task(task_key).await;
+            });
+        }
+
+        Ok(scheduled_tasks.len())
+    }
+
+    /// Set the status of the tasks which are due at the given time to `Scheduled`, and remove the
+    /// tasks which are running for too long. Returns the keys of the scheduled tasks.
+    ///
+    /// The instruction counter is checked between the tasks: when the run instruction budget is
+    /// exhausted, the remaining tasks are left for the next run.
+    pub(crate) fn schedule_due_tasks(&self, now_timestamp_secs: u64) -> Vec<u32> {
         debug!("Scheduler - Running tasks");
         let mut to_be_scheduled_tasks = Vec::new();
         let mut out_of_time_tasks = Vec::new();
+        let mut to_be_archived_tasks = Vec::new();
+        let archive_retention_secs = self
+            .archive
+            .lock()
+            .as_ref()
+            .map(|settings| settings.retention_secs);
         let running_task_timeout_secs = self.running_task_timeout_secs.load(Ordering::Relaxed);
+        let run_instruction_budget = self.run_instruction_budget.load(Ordering::Relaxed);
+        let budget_exhausted = || {
+            let exhausted = instruction_counter() >= run_instruction_budget;
+            if exhausted {
+                warn!("Scheduler - Instruction budget exhausted, the remaining tasks are left for the next run");
+            }
+            exhausted
+        };
 
         {
             let lock = self.pending_tasks.lock();
@@ -76,33 +372,75 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
                         }
                     }
                     TaskStatus::Running { timestamp_secs }
-                    | TaskStatus::Scheduled { timestamp_secs } => {
-                        warn!(
-                            "Scheduler - Task {} was in Scheduled or Running status for more than {} seconds, it could be stuck or panicked. Removing it from the scheduler.",
-                            task_key, running_task_timeout_secs
-                        );
-                        if timestamp_secs + running_task_timeout_secs < now_timestamp_secs {
+                    | TaskStatus::Scheduled { timestamp_secs }
+                        if timestamp_secs + running_task_timeout_secs < now_timestamp_secs =>
+                    {
+                        if self.running.lock().get(&task_key) == Some(&task.attempt) {
+                            warn!(
+                                "Scheduler - Task {} is running for more than {} seconds, waiting for its execution to finish",
+                                task_key, running_task_timeout_secs
+                            );
+                        } else {
+                            warn!(
+                                "Scheduler - Task {} was in Scheduled or Running status for more than {} seconds and its execution is not in flight, it trapped or was dropped",
+                                task_key, running_task_timeout_secs
+                            );
                             out_of_time_tasks.push(task_key);
                         }
                     }
-                    TaskStatus::Completed { .. }
-                    | TaskStatus::TimeoutOrPanic { .. }
-                    | TaskStatus::Failed { .. } => (),
+                    TaskStatus::Running { .. } | TaskStatus::Scheduled { .. } => {}
+                    TaskStatus::Completed { timestamp_secs }
+                    | TaskStatus::TimeoutOrPanic { timestamp_secs }
+                    | TaskStatus::Failed { timestamp_secs, .. } => {
+                        if archive_retention_secs.is_some_and(|retention_secs| {
+                            timestamp_secs + retention_secs < now_timestamp_secs
+                        }) {
+                            to_be_archived_tasks.push(task_key);
+                        }
+                    }
                 }
             }
         }
 
-        // Process the tasks that are ready to be scheduled
-        for task_key in to_be_scheduled_tasks.iter() {
-            self.process_pending_task(*task_key, now_timestamp_secs);
+        // Set the tasks that are ready as scheduled
+        let mut scheduled = 0;
+        while scheduled < to_be_scheduled_tasks.len() && !budget_exhausted() {
+            self.set_scheduled(to_be_scheduled_tasks[scheduled], now_timestamp_secs);
+            scheduled += 1;
         }
+        to_be_scheduled_tasks.truncate(scheduled);
 
-        // Remove the tasks that are out of time
+        // The tasks that are out of time without an execution in flight have trapped, which
+        // rolled back their state, or were dropped by an upgrade. They are failed attempts:
+        // retried if the retry policy allows, removed otherwise.
         {
             let mut lock = self.pending_tasks.lock();
             for task_key in out_of_time_tasks.into_iter() {
-                if let Some(mut task) = lock.remove(&task_key) {
+                if budget_exhausted() {
+                    break;
+                }
+                let Some(mut task) = lock.get(&task_key) else {
+                    continue;
+                };
+
+                let error = SchedulerError::TaskPanicked("the task timed out or trapped".into());
+                task.options.failures += 1;
+                task.record_failure(now_timestamp_secs, &error);
+                let (should_retry, retry_delay) = task
+                    .options
+                    .retry_strategy
+                    .should_retry(task.options.failures);
+
+                if should_retry {
+                    SchedulerCounters::inc(&self.counters.tasks_retried, 1);
+                    task.options.execute_after_timestamp_in_secs =
+                        now_timestamp_secs + (retry_delay as u64);
+                    task.status = TaskStatus::waiting(now_timestamp_secs);
+                    lock.insert(&task_key, &task);
+                } else {
+                    SchedulerCounters::inc(&self.counters.tasks_timed_out, 1);
                     task.status = TaskStatus::timeout_or_panic(now_timestamp_secs);
+                    self.finish(&mut lock, &task);
                     if let Some(cb) = &*self.on_completion_callback {
                         cb(task);
                     }
@@ -110,101 +448,353 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
             }
         }
 
-        Ok(to_be_scheduled_tasks.len())
+        // The finished tasks older than the retention period are moved to the archive.
+        if !to_be_archived_tasks.is_empty() {
+            let mut lock = self.pending_tasks.lock();
+            if let Some(settings) = &mut *self.archive.lock() {
+                for task_key in to_be_archived_tasks {
+                    if budget_exhausted() {
+                        break;
+                    }
+                    let Some(task) = lock.get(&task_key) else {
+                        continue;
+                    };
+
+                    if let Err(err) = settings.archive.archive(&task) {
+                        warn!("Scheduler - Failed to archive task {}: {}", task_key, err);
+                        break;
+                    }
+                    debug!("Scheduler - Task {} archived", task_key);
+                    lock.remove(&task_key);
+                }
+            }
+        }
+
+        to_be_scheduled_tasks
+    }
+
+    /// Keep the finished task until it's archived if the scheduler has an archive, or remove
+    /// it otherwise.
+    fn finish(&self, pending_tasks: &mut P, task: &InnerScheduledTask<T>) {
+        if self.archive.lock().is_some() {
+            pending_tasks.insert(&task.id(), task);
+        } else {
+            pending_tasks.remove(&task.id());
+        }
     }
 
-    fn process_pending_task(&self, task_key: u32, now_timestamp_secs: u64) {
-        let task_scheduler = self.clone();
+    /// Returns the key of the next appended task, not used by the pending or archived tasks.
+    fn next_key(&self, pending_tasks: &P) -> u32 {
+        let archived = self
+            .archive
+            .lock()
+            .as_ref()
+            .and_then(|settings| settings.archive.last_key());
+        pending_tasks
+            .last_key()
+            .max(archived)
+            .map(|key| key + 1)
+            .unwrap_or_default()
+    }
 
-        // Set the task as scheduled
-        {
-            let mut lock = task_scheduler.pending_tasks.lock();
-            let task = lock.get(&task_key);
-            if let Some(mut task) = task {
-                if let TaskStatus::Waiting { .. } = task.status {
+    fn set_scheduled(&self, task_key: u32, now_timestamp_secs: u64) {
+        let mut lock = self.pending_tasks.lock();
+        let task = lock.get(&task_key);
+        if let Some(mut task) = task {
+            if let TaskStatus::Waiting { .. } = task.status {
+                debug!(
+                    "Scheduler - Task {} status changed: Waiting -> Scheduled",
+                    task_key
+                );
+                task.status = TaskStatus::scheduled(now_timestamp_secs);
+                lock.insert(&task_key, &task);
+            }
+        }
+    }
+
+    /// Execute the scheduled task. Returns the final status of the task if the execution
+    /// completed or failed without retries, and `None` if the task will be retried, was not
+    /// scheduled, or its attempt was superseded while it was executed.
+    pub(crate) async fn execute_task(&self, task_key: u32) -> Option<TaskStatus> {
+        let now_timestamp_secs = time_secs();
+
+        let mut task = self.pending_tasks.lock().get(&task_key)?;
+        let TaskStatus::Scheduled { .. } = task.status else {
+            return None;
+        };
+
+        debug!(
+            "Scheduler - Task {} status changed: Scheduled -> Running",
+            task_key
+        );
+        task.status = TaskStatus::running(now_timestamp_secs);
+        task.attempt += 1;
+        let attempt = task.attempt;
+        self.pending_tasks.lock().insert(&task_key, &task);
+        let _running = RunningAttempt::new(self.running.clone(), task_key, attempt);
+
+        let instructions_before = instruction_counter();
+        let result = Self::execute_isolated(&task.task, Box::new(self.clone())).await;
+        let instructions = instruction_counter().saturating_sub(instructions_before);
+        self.task_type_stats
+            .lock()
+            .entry(task.task.task_type())
+            .or_default()
+            .record(instructions);
+        if let Some(cb) = &*self.on_execution_callback {
+            cb(&task, instructions);
+        }
+
+        let mut lock = self.pending_tasks.lock();
+        let Some(mut task) = lock.get(&task_key).filter(|task| task.attempt == attempt) else {
+            warn!(
+                "Scheduler - Task {} attempt {} was superseded, its result is ignored",
+                task_key, attempt
+            );
+            return None;
+        };
+        let completed_task = match result {
+            Ok(()) => {
+                debug!(
+                    "Scheduler - Task {} execution succeeded. Status changed: Running -> Completed",
+                    task_key
+                );
+                SchedulerCounters::inc(&self.counters.tasks_completed, 1);
+                task.status = TaskStatus::completed(now_timestamp_secs);
+                self.finish(&mut lock, &task);
+                Some(task)
+            }
+            Err(err) => {
+                task.options.failures += 1;
+                task.record_failure(now_timestamp_secs, &err);
+                let (should_retry, retry_delay) = task
+                    .options
+                    .retry_strategy
+                    .should_retry(task.options.failures);
+
+                if should_retry {
+                    SchedulerCounters::inc(&self.counters.tasks_retried, 1);
+                    debug!("Scheduler - Task {} execution failed. Execution will be retried. Status changed: Running -> Waiting", task_key);
+                    task.options.execute_after_timestamp_in_secs =
+                        now_timestamp_secs + (retry_delay as u64);
+                    task.status = TaskStatus::waiting(now_timestamp_secs);
+                    lock.insert(&task_key, &task);
+                    None
+                } else {
                     debug!(
-                        "Scheduler - Task {} status changed: Waiting -> Scheduled",
+                        "Scheduler - Task {} execution failed. Status changed: Running -> Failed",
                         task_key
                     );
-                    task.status = TaskStatus::scheduled(now_timestamp_secs);
-                    lock.insert(&task_key, &task);
+                    SchedulerCounters::inc(&self.counters.tasks_failed, 1);
+                    task.status = TaskStatus::failed(now_timestamp_secs, err);
+                    self.finish(&mut lock, &task);
+                    Some(task)
                 }
             }
+        };
+        drop(lock);
+
+        let task = completed_task?;
+        let status = task.status.clone();
+        if let Some(cb) = &*self.on_completion_callback {
+            cb(task);
         }
 
-        Self::spawn(async move {
-            let now_timestamp_secs = time_secs();
+        Some(status)
+    }
 
-            let task = task_scheduler.pending_tasks.lock().get(&task_key);
-            if let Some(mut task) = task {
-                if let TaskStatus::Scheduled { .. } = task.status {
-                    debug!(
-                        "Scheduler - Task {} status changed: Scheduled -> Running",
-                        task_key
-                    );
-                    task.status = TaskStatus::running(now_timestamp_secs);
-                    task_scheduler.pending_tasks.lock().insert(&task_key, &task);
+    /// Execute the task, converting a panic into a failed execution.
+    #[cfg(not(target_family = "wasm"))]
+    async fn execute_isolated(
+        task: &T,
+        task_scheduler: Box<dyn 'static + TaskScheduler<T>>,
+    ) -> Result<(), SchedulerError> {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        use futures::FutureExt;
+
+        let panicked = |panic: Box<dyn std::any::Any + Send>| -> Result<(), SchedulerError> {
+            let message = match panic.downcast::<String>() {
+                Ok(message) => *message,
+                Err(panic) => panic.downcast_ref::<&str>().map_or_else(
+                    || "unknown panic".to_string(),
+                    |message| message.to_string(),
+                ),
+            };
+            Err(SchedulerError::TaskPanicked(message))
+        };
 
-                    let completed_task = match task
-                        .task
-                        .execute(Box::new(task_scheduler.clone()))
-                        .await
-                    {
-                        Ok(()) => {
-                            debug!("Scheduler - Task {} execution succeeded. Status changed: Running -> Completed", task_key);
-                            let mut lock = task_scheduler.pending_tasks.lock();
-                            let mut task = lock.remove(&task_key).unwrap();
-                            task.status = TaskStatus::completed(now_timestamp_secs);
-                            Some(task)
-                        }
-                        Err(err) => {
-                            let mut lock = task_scheduler.pending_tasks.lock();
-                            task.options.failures += 1;
-                            let (should_retry, retry_delay) = task
-                                .options
-                                .retry_strategy
-                                .should_retry(task.options.failures);
-
-                            if should_retry {
-                                debug!("Scheduler - Task {} execution failed. Execution will be retried. Status changed: Running -> Waiting", task_key);
-                                task.options.execute_after_timestamp_in_secs =
-                                    now_timestamp_secs + (retry_delay as u64);
-                                task.status = TaskStatus::waiting(now_timestamp_secs);
-                                lock.insert(&task_key, &task);
-                                None
-                            } else {
-                                debug!("Scheduler - Task {} execution failed. Status changed: Running -> Failed", task_key);
-                                let mut task = lock.remove(&task_key).unwrap();
-                                task.status = TaskStatus::failed(now_timestamp_secs, err);
-                                Some(task)
-                            }
-                        }
-                    };
+        match catch_unwind(AssertUnwindSafe(|| task.execute(task_scheduler))) {
+            Ok(execution) => AssertUnwindSafe(execution)
+                .catch_unwind()
+                .await
+                .unwrap_or_else(panicked),
+            Err(panic) => panicked(panic),
+        }
+    }
 
-                    if let Some(task) = completed_task {
-                        if let Some(cb) = &*task_scheduler.on_completion_callback {
-                            cb(task);
-                        }
+    /// Execute the task. A panic traps and rolls back the state of the current message, so the
+    /// task stays scheduled or running until it times out, and then it's handled as a failed
+    /// execution.
+    #[cfg(target_family = "wasm")]
+    async fn execute_isolated(
+        task: &T,
+        task_scheduler: Box<dyn 'static + TaskScheduler<T>>,
+    ) -> Result<(), SchedulerError> {
+        task.execute(task_scheduler).await
+    }
+}
+
+impl<T, P> Scheduler<T, P>
+where
+    T: 'static + Task,
+    P: 'static + TaskStorage<T>,
+{
+    /// Check the consistency of the stored tasks, e.g. in `post_upgrade` to detect a corruption
+    /// early. With `quarantine`, the tasks with problems are removed from the scheduler and
+    /// passed to the completion callback as failed with [`SchedulerError::TaskQuarantined`].
+    ///
+    /// The tasks are decoded without trapping, so the tasks which can't be decoded are reported
+    /// as [`TaskProblem::Undecodable`], and with `quarantine` their encoded bytes are returned
+    /// in [`ValidationReport::undecodable_records`] instead of the completion callback.
+    ///
+    /// See [`Scheduler::post_upgrade`] for the check run after an upgrade.
+    pub fn validate(&self, quarantine: bool) -> ValidationReport {
+        let now_timestamp_secs = time_secs();
+        let has_archive = self.archive.lock().is_some();
+        let mut report = ValidationReport::default();
+
+        {
+            let lock = self.pending_tasks.lock();
+            // The number of the stored records of each task id.
+            let mut records = BTreeMap::<u32, u32>::new();
+            let mut problems = vec![];
+            for (task_key, task) in lock.try_iter() {
+                report.checked += 1;
+                let Some(task) = task else {
+                    problems.push((task_key, TaskProblem::Undecodable));
+                    continue;
+                };
+
+                *records.entry(task.id).or_default() += 1;
+                if let Some(problem) =
+                    Self::check_task(task_key, &task, now_timestamp_secs, has_archive)
+                {
+                    problems.push((task_key, problem));
+                }
+            }
+
+            for (task_key, mut problem) in problems {
+                if let TaskProblem::KeyMismatch { id } = problem {
+                    if records.get(&id).copied().unwrap_or_default() > 1 {
+                        problem = TaskProblem::DuplicateId { id };
                     }
                 }
+
+                warn!(
+                    "Scheduler - Task {} is inconsistent: {:?}",
+                    task_key, problem
+                );
+                report.issues.push(TaskIssue {
+                    key: task_key,
+                    problem,
+                });
             }
-        });
+        }
+
+        if !quarantine {
+            return report;
+        }
+
+        let mut quarantined_tasks = Vec::with_capacity(report.issues.len());
+        {
+            let mut lock = self.pending_tasks.lock();
+            for issue in &report.issues {
+                if issue.problem == TaskProblem::Undecodable {
+                    if let Some(bytes) = lock.remove_encoded(issue.key) {
+                        report.undecodable_records.push((issue.key, bytes));
+                    }
+                    continue;
+                }
+
+                let Some(mut task) = lock.remove(&issue.key) else {
+                    continue;
+                };
+
+                let error = SchedulerError::TaskQuarantined(format!("{:?}", issue.problem));
+                task.status = TaskStatus::failed(now_timestamp_secs, error);
+                quarantined_tasks.push(task);
+            }
+        }
+
+        report.quarantined = (quarantined_tasks.len() + report.undecodable_records.len()) as u64;
+        if let Some(cb) = &*self.on_completion_callback {
+            for task in quarantined_tasks {
+                cb(task);
+            }
+        }
+
+        report
     }
 
-    // We use tokio for testing instead of ic_kit::ic::spawn because the latter blocks the current thread
-    // waiting for the spawned futures to complete.
-    // This makes impossible to test concurrent behavior.
-    #[cfg(test)]
-    fn spawn<F: 'static + std::future::Future<Output = ()>>(future: F) {
-        tokio::task::spawn_local(future);
+    /// Check the stored tasks after an upgrade of the canister, and quarantine the tasks with
+    /// problems, see [`Scheduler::validate`]. The problems are logged as errors.
+    ///
+    /// ```ignore
+    /// #[post_upgrade]
+    /// fn post_upgrade() {
+    ///     SCHEDULER.with(|scheduler| scheduler.post_upgrade());
+    /// }
+    /// ```
+    pub fn post_upgrade(&self) -> ValidationReport {
+        let report = self.validate(true);
+        if !report.is_ok() {
+            error!(
+                "Scheduler - {} inconsistent tasks quarantined after the upgrade: {:?}",
+                report.quarantined, report.issues
+            );
+        }
+
+        report
     }
 
-    #[cfg(not(test))]
-    #[inline(always)]
-    fn spawn<F: 'static + std::future::Future<Output = ()>>(future: F) {
-        ic_cdk_timers::set_timer(std::time::Duration::from_millis(0), || {
-            ic_kit::ic::spawn(future);
-        });
+    fn check_task(
+        task_key: u32,
+        task: &InnerScheduledTask<T>,
+        now_timestamp_secs: u64,
+        has_archive: bool,
+    ) -> Option<TaskProblem> {
+        if task.id != task_key {
+            return Some(TaskProblem::KeyMismatch { id: task.id });
+        }
+
+        let timestamp_secs = task.status.timestamp_secs();
+        if timestamp_secs > now_timestamp_secs {
+            return Some(TaskProblem::StatusInFuture { timestamp_secs });
+        }
+
+        match task.status {
+            TaskStatus::Completed { .. }
+            | TaskStatus::Failed { .. }
+            | TaskStatus::TimeoutOrPanic { .. }
+                if !has_archive =>
+            {
+                Some(TaskProblem::FinishedNotRemoved)
+            }
+            TaskStatus::Waiting { .. }
+                if task.options.failures > 0
+                    && !task
+                        .options
+                        .retry_strategy
+                        .should_retry(task.options.failures)
+                        .0 =>
+            {
+                Some(TaskProblem::RetriesExhausted {
+                    failures: task.options.failures,
+                })
+            }
+            _ => None,
+        }
     }
 }
 
@@ -215,6 +805,19 @@ pub trait TaskScheduler<T: 'static + Task> {
     fn append_tasks(&self, tasks: Vec<ScheduledTask<T>>) -> Vec<u32>;
     /// Get a task by its key.
     fn get_task(&self, task_id: u32) -> Option<InnerScheduledTask<T>>;
+
+    /// Append a task with the scheduling options and return the key of the task.
+    fn append_task_with_options(&self, task: T, options: TaskOptions) -> u32 {
+        self.append_task(ScheduledTask::with_options(task, options))
+    }
+
+    /// Append a task to be executed after `delay_secs` seconds from now and return the key of
+    /// the task.
+    fn append_delayed(&self, task: T, delay_secs: u64) -> u32 {
+        let options =
+            TaskOptions::new().with_execute_after_timestamp_in_secs(time_secs() + delay_secs);
+        self.append_task_with_options(task, options)
+    }
 }
 
 impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerScheduledTask<T>>>
@@ -225,9 +828,18 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
             pending_tasks: self.pending_tasks.clone(),
             phantom: self.phantom,
             on_completion_callback: self.on_completion_callback.clone(),
+            on_execution_callback: self.on_execution_callback.clone(),
             running_task_timeout_secs: AtomicU64::new(
                 self.running_task_timeout_secs.load(Ordering::Relaxed),
             ),
+            run_instruction_budget: AtomicU64::new(
+                self.run_instruction_budget.load(Ordering::Relaxed),
+            ),
+            counters: self.counters.clone(),
+            task_type_stats: self.task_type_stats.clone(),
+            paused: self.paused.clone(),
+            archive: self.archive.clone(),
+            running: self.running.clone(),
         }
     }
 }
@@ -238,7 +850,7 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
     fn append_task(&self, task: ScheduledTask<T>) -> u32 {
         let time_secs = time_secs();
         let mut lock = self.pending_tasks.lock();
-        let key = lock.last_key().map(|val| val + 1).unwrap_or_default();
+        let key = self.next_key(&lock);
         lock.insert(
             &key,
             &InnerScheduledTask::with_status(
@@ -249,6 +861,7 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
                 },
             ),
         );
+        SchedulerCounters::inc(&self.counters.tasks_appended, 1);
         key
     }
 
@@ -259,7 +872,7 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
 
         let time_secs = time_secs();
         let mut lock = self.pending_tasks.lock();
-        let mut key = lock.last_key().map(|val| val + 1).unwrap_or_default();
+        let mut key = self.next_key(&lock);
 
         let mut keys = Vec::with_capacity(tasks.len());
         for task in tasks {
@@ -276,11 +889,17 @@ impl<T: 'static + Task, P: 'static + IterableUnboundedMapStructure<u32, InnerSch
             keys.push(key);
             key += 1;
         }
+        SchedulerCounters::inc(&self.counters.tasks_appended, keys.len() as u64);
         keys
     }
 
     fn get_task(&self, task_id: u32) -> Option<InnerScheduledTask<T>> {
-        self.pending_tasks.lock().get(&task_id)
+        self.pending_tasks.lock().get(&task_id).or_else(|| {
+            self.archive
+                .lock()
+                .as_ref()
+                .and_then(|settings| settings.archive.get(task_id))
+        })
     }
 }
 
@@ -663,6 +1282,20 @@ mod test {
                         );
                         assert_eq!(scheduler.pending_tasks.lock().len(), 0);
                     });
+
+                    assert_eq!(
+                        scheduler.stats(),
+                        SchedulerStats {
+                            runs: 4,
+                            tasks_appended: 1,
+                            tasks_scheduled: 4,
+                            tasks_completed: 0,
+                            tasks_failed: 1,
+                            tasks_retried: 3,
+                            tasks_timed_out: 0,
+                            pending_tasks: 0,
+                        }
+                    );
                 })
                 .await;
         }
@@ -0,0 +1,447 @@
+//! The storage of the pending tasks of the scheduler.
+
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::marker::PhantomData;
+
+use ic_stable_structures::stable_structures::Memory;
+use ic_stable_structures::{
+    BTreeMapStructure, Bound, ChunkSize, IterableUnboundedMapStructure, SlicedStorable,
+    StableBTreeMap, StableBTreeMapIter, StableUnboundedIter, StableUnboundedMap, Storable,
+    UnboundedMapStructure,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::task::{InnerScheduledTask, Task};
+
+/// The max size of the encoded task stored inline.
+pub const INLINE_TASK_MAX_SIZE: u32 = 512;
+
+/// The size of the chunks of the large tasks.
+pub const LARGE_TASK_CHUNK_SIZE: ChunkSize = 1024;
+
+/// The storage of the pending tasks which selects the storage tier by the size of the task.
+///
+/// The tasks encoded into at most [`INLINE_TASK_MAX_SIZE`] bytes are stored inline, as single
+/// entries of a map. The larger tasks are split into the chunks of [`LARGE_TASK_CHUNK_SIZE`]
+/// bytes in an unbounded map. Compared with a [`StableUnboundedMap`] of the tasks, which
+/// splits every task into 128 bytes chunks, a task takes less entries, so less memory and
+/// less writes.
+///
+/// # Example
+///
+/// ```ignore
+/// let pending_tasks = TieredTaskStorage::<MyTask, _>::new(
+///     memory_manager.get(INLINE_TASKS_MEMORY_ID),
+///     memory_manager.get(LARGE_TASKS_MEMORY_ID),
+/// );
+/// let scheduler = Scheduler::new(pending_tasks);
+/// ```
+pub struct TieredTaskStorage<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    inline: StableBTreeMap<u32, InlineTask, M>,
+    large: StableUnboundedMap<u32, LargeTask, M>,
+    _task: PhantomData<T>,
+}
+
+impl<T, M> TieredTaskStorage<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    /// Create the storage in the memories of the inline and of the large tasks. If the
+    /// memories contain the tasks, they are kept.
+    pub fn new(inline_memory: M, large_memory: M) -> Self {
+        Self {
+            inline: StableBTreeMap::new(inline_memory),
+            large: StableUnboundedMap::new(large_memory),
+            _task: PhantomData,
+        }
+    }
+
+    /// Returns the number of the tasks stored inline.
+    pub fn inline_len(&self) -> u64 {
+        self.inline.len()
+    }
+
+    /// Returns the number of the tasks split into chunks.
+    pub fn large_len(&self) -> u64 {
+        self.large.len()
+    }
+}
+
+/// The storage of the pending tasks which can be read without trapping on the tasks which
+/// can't be decoded, e.g. after an incompatible change of the task type, so that they can be
+/// found and removed by [`Scheduler::validate`].
+///
+/// [`Scheduler::validate`]: crate::scheduler::Scheduler::validate
+pub trait TaskStorage<T>: IterableUnboundedMapStructure<u32, InnerScheduledTask<T>> {
+    /// Iterator for all stored tasks in the order of their keys, with `None` for the tasks which
+    /// can't be decoded.
+    fn try_iter(&self) -> Box<dyn Iterator<Item = (u32, Option<InnerScheduledTask<T>>)> + '_>;
+
+    /// Removes the task without decoding it, and returns its encoded bytes.
+    fn remove_encoded(&mut self, key: u32) -> Option<Vec<u8>>;
+
+    /// Returns the keys of the stored tasks which can't be decoded. Reading such a task from
+    /// the storage traps.
+    fn undecodable_keys(&self) -> Vec<u32> {
+        self.try_iter()
+            .filter(|(_, task)| task.is_none())
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Removes the tasks which can't be decoded and returns their keys and encoded bytes, so
+    /// that they can be inspected or stored elsewhere.
+    fn quarantine_undecodable(&mut self) -> Vec<(u32, Vec<u8>)> {
+        self.undecodable_keys()
+            .into_iter()
+            .filter_map(|key| Some((key, self.remove_encoded(key)?)))
+            .collect()
+    }
+}
+
+impl<T, M> TaskStorage<T> for TieredTaskStorage<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    fn try_iter(&self) -> Box<dyn Iterator<Item = (u32, Option<InnerScheduledTask<T>>)> + '_> {
+        let mut iter = self.iter();
+        Box::new(std::iter::from_fn(move || {
+            let (key, bytes) = iter.next_encoded()?;
+            Some((key, InnerScheduledTask::try_from_bytes(&bytes)))
+        }))
+    }
+
+    fn remove_encoded(&mut self, key: u32) -> Option<Vec<u8>> {
+        match self.inline.remove(&key) {
+            Some(task) => Some(task.0),
+            None => self.large.remove(&key).map(|task| task.0),
+        }
+    }
+}
+
+impl<T, M> TaskStorage<T> for StableUnboundedMap<u32, InnerScheduledTask<T>, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    fn try_iter(&self) -> Box<dyn Iterator<Item = (u32, Option<InnerScheduledTask<T>>)> + '_> {
+        Box::new(
+            self.iter_encoded()
+                .map(|(key, bytes)| (key, InnerScheduledTask::try_from_bytes(&bytes))),
+        )
+    }
+
+    fn remove_encoded(&mut self, key: u32) -> Option<Vec<u8>> {
+        StableUnboundedMap::remove_encoded(self, &key)
+    }
+}
+
+impl<T, M> UnboundedMapStructure<u32, InnerScheduledTask<T>> for TieredTaskStorage<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    fn get(&self, key: &u32) -> Option<InnerScheduledTask<T>> {
+        match self.inline.get(key) {
+            Some(task) => Some(decode(task.0)),
+            None => self.large.get(key).map(|task| decode(task.0)),
+        }
+    }
+
+    fn first_key(&self) -> Option<u32> {
+        let inline = self.inline.iter().next().map(|(key, _)| key);
+        min_key(inline, self.large.first_key())
+    }
+
+    fn first_key_value(&self) -> Option<(u32, InnerScheduledTask<T>)> {
+        let key = self.first_key()?;
+        self.get(&key).map(|task| (key, task))
+    }
+
+    fn last_key(&self) -> Option<u32> {
+        let inline = self.inline.last_key_value().map(|(key, _)| key);
+        inline.max(self.large.last_key())
+    }
+
+    fn last_key_value(&self) -> Option<(u32, InnerScheduledTask<T>)> {
+        let key = self.last_key()?;
+        self.get(&key).map(|task| (key, task))
+    }
+
+    fn insert(
+        &mut self,
+        key: &u32,
+        value: &InnerScheduledTask<T>,
+    ) -> Option<InnerScheduledTask<T>> {
+        let bytes = value.to_bytes().into_owned();
+        let previous = if bytes.len() <= INLINE_TASK_MAX_SIZE as usize {
+            let previous = self.inline.insert(*key, InlineTask(bytes));
+            previous
+                .map(|task| task.0)
+                .or_else(|| self.large.remove(key).map(|task| task.0))
+        } else {
+            let previous = self.large.insert(key, &LargeTask(bytes));
+            previous
+                .map(|task| task.0)
+                .or_else(|| self.inline.remove(key).map(|task| task.0))
+        };
+
+        previous.map(decode)
+    }
+
+    fn remove(&mut self, key: &u32) -> Option<InnerScheduledTask<T>> {
+        match self.inline.remove(key) {
+            Some(task) => Some(decode(task.0)),
+            None => self.large.remove(key).map(|task| decode(task.0)),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.inline.len() + self.large.len()
+    }
+
+    /// Returns the number of the inline tasks and of the chunks of the large tasks.
+    fn total_chunks_number(&self) -> u64 {
+        self.inline.len() + self.large.total_chunks_number()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inline.is_empty() && self.large.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.inline.clear();
+        self.large.clear();
+    }
+}
+
+impl<T, M> IterableUnboundedMapStructure<u32, InnerScheduledTask<T>> for TieredTaskStorage<T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    type Iterator<'a> = TieredTaskStorageIter<'a, T, M> where Self: 'a;
+
+    fn iter(&self) -> Self::Iterator<'_> {
+        TieredTaskStorageIter {
+            inline: self.inline.iter().peekable(),
+            large: self.large.iter().peekable(),
+            _task: PhantomData,
+        }
+    }
+}
+
+/// Iterator over the tasks of [`TieredTaskStorage`] in the order of their keys.
+pub struct TieredTaskStorageIter<'a, T, M: Memory> {
+    inline: Peekable<StableBTreeMapIter<'a, u32, InlineTask, M>>,
+    large: Peekable<StableUnboundedIter<'a, u32, LargeTask, M>>,
+    _task: PhantomData<T>,
+}
+
+impl<T, M> Iterator for TieredTaskStorageIter<'_, T, M>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+    M: Memory,
+{
+    type Item = (u32, InnerScheduledTask<T>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, bytes) = self.next_encoded()?;
+        Some((key, decode(bytes)))
+    }
+}
+
+impl<T, M: Memory> TieredTaskStorageIter<'_, T, M> {
+    /// Returns the next key with the encoded task.
+    fn next_encoded(&mut self) -> Option<(u32, Vec<u8>)> {
+        let inline_first = match (self.inline.peek(), self.large.peek()) {
+            (Some((inline_key, _)), Some((large_key, _))) => inline_key < large_key,
+            (inline, _) => inline.is_some(),
+        };
+
+        if inline_first {
+            self.inline.next().map(|(key, task)| (key, task.0))
+        } else {
+            self.large.next().map(|(key, task)| (key, task.0))
+        }
+    }
+}
+
+fn decode<T>(bytes: Vec<u8>) -> InnerScheduledTask<T>
+where
+    T: 'static + Task + Serialize + DeserializeOwned,
+{
+    InnerScheduledTask::from_bytes(Cow::Owned(bytes))
+}
+
+fn min_key(first: Option<u32>, second: Option<u32>) -> Option<u32> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(first.min(second)),
+        (first, second) => first.or(second),
+    }
+}
+
+/// An encoded task stored as a single entry.
+struct InlineTask(Vec<u8>);
+
+impl Storable for InlineTask {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: INLINE_TASK_MAX_SIZE,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+/// An encoded task split into chunks.
+struct LargeTask(Vec<u8>);
+
+impl Storable for LargeTask {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl SlicedStorable for LargeTask {
+    const CHUNK_SIZE: ChunkSize = LARGE_TASK_CHUNK_SIZE;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+
+    use ic_stable_structures::VectorMemory;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::scheduler::{Scheduler, TaskProblem, TaskScheduler};
+    use crate::task::TaskStatus;
+    use crate::SchedulerError;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct PayloadTask(Vec<u8>);
+
+    impl Task for PayloadTask {
+        fn execute(
+            &self,
+            _task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn task(key: u32, payload_len: usize) -> InnerScheduledTask<PayloadTask> {
+        InnerScheduledTask::with_status(
+            key,
+            PayloadTask(vec![key as u8; payload_len]).into(),
+            TaskStatus::waiting(0),
+        )
+    }
+
+    #[test]
+    fn tasks_are_stored_by_size() {
+        let mut storage = TieredTaskStorage::new(VectorMemory::default(), VectorMemory::default());
+        for (key, payload_len) in [(0, 10), (1, 2000), (2, 20), (3, 5000)] {
+            assert!(storage.insert(&key, &task(key, payload_len)).is_none());
+        }
+
+        assert_eq!((storage.inline_len(), storage.large_len()), (2, 2));
+        // 5000 bytes take 5 chunks of 1024 bytes instead of 40 chunks of 128 bytes.
+        assert_eq!(storage.total_chunks_number(), 2 + 2 + 5);
+        assert_eq!(
+            (storage.first_key(), storage.last_key()),
+            (Some(0), Some(3))
+        );
+        assert_eq!(
+            storage.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+
+        // The task moves between the tiers when its size changes.
+        assert_eq!(storage.insert(&0, &task(0, 3000)), Some(task(0, 10)));
+        assert_eq!(storage.insert(&1, &task(1, 30)), Some(task(1, 2000)));
+        assert_eq!((storage.inline_len(), storage.large_len()), (2, 2));
+        assert_eq!(storage.get(&0), Some(task(0, 3000)));
+        assert_eq!(storage.get(&1), Some(task(1, 30)));
+
+        assert_eq!(storage.remove(&3), Some(task(3, 5000)));
+        assert_eq!(storage.last_key_value(), Some((2, task(2, 20))));
+        assert_eq!(storage.len(), 3);
+
+        storage.clear();
+        assert!(storage.is_empty());
+        assert_eq!(storage.iter().next(), None);
+    }
+
+    #[test]
+    fn undecodable_tasks_are_quarantined() {
+        let mut storage = TieredTaskStorage::new(VectorMemory::default(), VectorMemory::default());
+        storage.insert(&0, &task(0, 10));
+        storage.insert(&1, &task(1, 2000));
+        storage.inline.insert(2, InlineTask(vec![0xff; 3]));
+        storage.large.insert(&3, &LargeTask(vec![0xff; 5]));
+
+        assert_eq!(storage.undecodable_keys(), [2, 3]);
+        assert_eq!(
+            storage.quarantine_undecodable(),
+            [(2, vec![0xff; 3]), (3, vec![0xff; 5])]
+        );
+        assert!(storage.undecodable_keys().is_empty());
+        assert_eq!(
+            storage.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn undecodable_tasks_are_quarantined_after_upgrade() {
+        let mut storage = TieredTaskStorage::new(VectorMemory::default(), VectorMemory::default());
+        storage.insert(&0, &task(0, 10));
+        storage.insert(&1, &task(1, 2000));
+        storage.inline.insert(2, InlineTask(vec![0xff; 3]));
+        storage.large.insert(&3, &LargeTask(vec![0xff; 5]));
+        let scheduler = Scheduler::new(storage);
+
+        let report = scheduler.validate(false);
+        assert_eq!(report.checked, 4);
+        assert_eq!(
+            report
+                .issues
+                .iter()
+                .map(|issue| (issue.key, issue.problem.clone()))
+                .collect::<Vec<_>>(),
+            [(2, TaskProblem::Undecodable), (3, TaskProblem::Undecodable)]
+        );
+        assert!(report.undecodable_records.is_empty());
+
+        let report = scheduler.post_upgrade();
+        assert_eq!(report.quarantined, 2);
+        assert_eq!(
+            report.undecodable_records,
+            [(2, vec![0xff; 3]), (3, vec![0xff; 5])]
+        );
+        assert!(scheduler.validate(false).is_ok());
+        assert_eq!(scheduler.pending_tasks.lock().len(), 2);
+    }
+}
@@ -10,6 +10,9 @@ use crate::retry::{BackoffPolicy, RetryPolicy, RetryStrategy};
 use crate::scheduler::TaskScheduler;
 use crate::SchedulerError;
 
+/// The number of the last failures kept in the history of a task.
+pub const TASK_FAILURE_HISTORY_LEN: usize = 8;
+
 /// A sync task is a unit of work that can be executed by the scheduler.
 pub trait Task {
     /// Execute the task and return the next task to execute.
@@ -17,6 +20,12 @@ pub trait Task {
         &self,
         task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
     ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>>;
+
+    /// The type of the task, used to aggregate the statistics of the executions.
+    /// Defaults to the name of the Rust type, override it to tell apart the variants of an enum.
+    fn task_type(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 /// A scheduled task is a task that is ready to be executed.
@@ -57,6 +66,36 @@ pub struct InnerScheduledTask<T: Task> {
     pub(crate) task: T,
     pub(crate) options: TaskOptions,
     pub(crate) status: TaskStatus,
+    pub(crate) failure_history: Vec<TaskFailure>,
+    /// The number of the executions started, so that the completion of an execution superseded
+    /// by a later one is ignored.
+    pub(crate) attempt: u32,
+}
+
+/// The layout of the tasks stored before the attempts were counted.
+#[derive(Deserialize)]
+struct LegacyInnerScheduledTaskWithHistory<T> {
+    id: u32,
+    task: T,
+    options: TaskOptions,
+    status: TaskStatus,
+    failure_history: Vec<TaskFailure>,
+}
+
+/// The layout of the tasks stored before the failure history was added.
+#[derive(Deserialize)]
+struct LegacyInnerScheduledTask<T> {
+    id: u32,
+    task: T,
+    options: TaskOptions,
+    status: TaskStatus,
+}
+
+/// A failed execution of a task.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct TaskFailure {
+    pub timestamp_secs: u64,
+    pub error: String,
 }
 
 impl<T: Task> InnerScheduledTask<T> {
@@ -67,6 +106,8 @@ impl<T: Task> InnerScheduledTask<T> {
             task: task.task,
             options: task.options,
             status,
+            failure_history: vec![],
+            attempt: 0,
         }
     }
 
@@ -89,6 +130,62 @@ impl<T: Task> InnerScheduledTask<T> {
     pub fn id(&self) -> u32 {
         self.id
     }
+
+    /// Returns the number of the executions of the task started so far.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the last failures of the task, at most [`TASK_FAILURE_HISTORY_LEN`], from the
+    /// oldest to the latest one.
+    pub fn failure_history(&self) -> &[TaskFailure] {
+        &self.failure_history
+    }
+
+    /// Adds the failure to the history, dropping the oldest failure if the history is full.
+    pub(crate) fn record_failure(&mut self, timestamp_secs: u64, error: &SchedulerError) {
+        if self.failure_history.len() == TASK_FAILURE_HISTORY_LEN {
+            self.failure_history.remove(0);
+        }
+        self.failure_history.push(TaskFailure {
+            timestamp_secs,
+            error: error.to_string(),
+        });
+    }
+}
+
+impl<T: 'static + Task + Serialize + DeserializeOwned> InnerScheduledTask<T> {
+    /// Decodes the task stored in the current or in one of the legacy layouts, or returns
+    /// `None` if the bytes are not a valid task, e.g. to check the stored tasks without trapping.
+    ///
+    /// The layouts are tried from the newest one: the older layouts are prefixes of the newer
+    /// ones, so the bytes of an older task are too short to be decoded in a newer layout.
+    pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        if let Ok(task) = bincode::deserialize(bytes) {
+            return Some(task);
+        }
+
+        if let Ok(task) = bincode::deserialize::<LegacyInnerScheduledTaskWithHistory<T>>(bytes) {
+            return Some(Self {
+                id: task.id,
+                task: task.task,
+                options: task.options,
+                status: task.status,
+                failure_history: task.failure_history,
+                attempt: 0,
+            });
+        }
+
+        let task: LegacyInnerScheduledTask<T> = bincode::deserialize(bytes).ok()?;
+        Some(Self {
+            id: task.id,
+            task: task.task,
+            options: task.options,
+            status: task.status,
+            failure_history: vec![],
+            attempt: 0,
+        })
+    }
 }
 
 impl<T: 'static + Task + Serialize + DeserializeOwned> Storable for InnerScheduledTask<T> {
@@ -99,7 +196,7 @@ impl<T: 'static + Task + Serialize + DeserializeOwned> Storable for InnerSchedul
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        bincode::deserialize(&bytes).expect("failed to deserialize ScheduledTask")
+        Self::try_from_bytes(&bytes).expect("failed to deserialize ScheduledTask")
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -110,7 +207,7 @@ impl<T: 'static + Task + Serialize + DeserializeOwned> SlicedStorable for InnerS
 }
 
 /// The status of a task in the scheduler
-#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub enum TaskStatus {
     /// The task is waiting to be executed
     Waiting { timestamp_secs: u64 },
@@ -250,6 +347,8 @@ mod test {
                     .with_max_retries_policy(3)
                     .with_fixed_backoff_policy(2),
                 status: TaskStatus::Waiting { timestamp_secs: 0 },
+                failure_history: vec![],
+                attempt: 0,
             };
 
             let serialized = task.to_bytes();
@@ -266,6 +365,8 @@ mod test {
                     .with_retry_policy(RetryPolicy::None)
                     .with_backoff_policy(BackoffPolicy::None),
                 status: TaskStatus::Waiting { timestamp_secs: 0 },
+                failure_history: vec![],
+                attempt: 0,
             };
 
             let serialized = task.to_bytes();
@@ -287,6 +388,8 @@ mod test {
                 status: TaskStatus::Completed {
                     timestamp_secs: 1230,
                 },
+                failure_history: vec![],
+                attempt: 0,
             };
 
             let serialized = task.to_bytes();
@@ -307,6 +410,11 @@ mod test {
                 status: TaskStatus::Running {
                     timestamp_secs: 21230,
                 },
+                failure_history: vec![TaskFailure {
+                    timestamp_secs: 21000,
+                    error: "failed".to_string(),
+                }],
+                attempt: 2,
             };
 
             let serialized = task.to_bytes();
@@ -315,4 +423,65 @@ mod test {
             assert_eq!(task, deserialized);
         }
     }
+
+    #[test]
+    fn test_legacy_task_is_decoded_without_failure_history() {
+        let options = TaskOptions::new().with_max_retries_policy(3);
+        let status = TaskStatus::Waiting { timestamp_secs: 10 };
+        let legacy = bincode::serialize(&(7u32, TestTask {}, &options, &status)).unwrap();
+
+        let task = InnerScheduledTask::<TestTask>::from_bytes(legacy.into());
+
+        assert_eq!(
+            (task.id, &task.options, &task.status),
+            (7, &options, &status)
+        );
+        assert!(task.failure_history().is_empty());
+    }
+
+    #[test]
+    fn test_task_without_attempts_is_decoded() {
+        let options = TaskOptions::new().with_max_retries_policy(3);
+        let status = TaskStatus::Running { timestamp_secs: 10 };
+        let history = vec![TaskFailure {
+            timestamp_secs: 5,
+            error: "failed".to_string(),
+        }];
+        let stored = bincode::serialize(&(7u32, TestTask {}, &options, &status, &history)).unwrap();
+
+        let task = InnerScheduledTask::<TestTask>::from_bytes(stored.into());
+
+        assert_eq!(
+            (task.id, &task.status, task.failure_history()),
+            (7, &status, history.as_slice())
+        );
+        assert_eq!(task.attempt(), 0);
+    }
+
+    #[test]
+    fn test_failure_history_is_bounded() {
+        let mut task = InnerScheduledTask::with_status(
+            0,
+            TestTask {}.into(),
+            TaskStatus::Waiting { timestamp_secs: 0 },
+        );
+        for timestamp_secs in 0..20 {
+            let error = SchedulerError::TaskExecutionFailed(format!("failure {timestamp_secs}"));
+            task.record_failure(timestamp_secs, &error);
+        }
+
+        let history = task.failure_history();
+        assert_eq!(history.len(), TASK_FAILURE_HISTORY_LEN);
+        assert_eq!(
+            history[0].timestamp_secs,
+            20 - TASK_FAILURE_HISTORY_LEN as u64
+        );
+        assert_eq!(
+            history.last(),
+            Some(&TaskFailure {
+                timestamp_secs: 19,
+                error: "TaskExecutionFailed: failure 19".to_string(),
+            })
+        );
+    }
 }
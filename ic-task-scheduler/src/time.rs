@@ -1,12 +1,20 @@
+#[cfg(not(target_family = "wasm"))]
+thread_local! {
+    /// The time returned instead of the system time, set by the test harness.
+    static MOCK_TIME_SECS: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
 /// returns the timestamp in seconds
 #[inline]
 pub fn time_secs() -> u64 {
     #[cfg(not(target_family = "wasm"))]
     {
-        std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .expect("get current timestamp error")
-            .as_secs()
+        MOCK_TIME_SECS.with(|time| time.get()).unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .expect("get current timestamp error")
+                .as_secs()
+        })
     }
 
     // ic::time() return the nano_sec, we need to change it to sec.
@@ -16,3 +24,9 @@ pub fn time_secs() -> u64 {
         ic_kit::ic::time() / E_9
     }
 }
+
+/// Sets the time returned by [`time_secs`] in the current thread, `None` restores the system time.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn set_mock_time_secs(time: Option<u64>) {
+    MOCK_TIME_SECS.with(|mock_time| mock_time.set(time));
+}
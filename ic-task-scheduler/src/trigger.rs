@@ -0,0 +1,310 @@
+//! The triggers letting the authorized external principals enqueue the predefined tasks of the
+//! canister, e.g. a backend service asking to sync the data after a change.
+//!
+//! Every trigger has a name, the principals allowed to call it, a rate limit per caller and a
+//! builder which validates the candid encoded arguments and returns the task to append. The
+//! triggers are called with the `trigger` endpoint of [`TriggerCanister`].
+//!
+//! ```ignore
+//! // In `init` and `post_upgrade`.
+//! init_triggers(Triggers::new().with_trigger(
+//!     "sync",
+//!     Trigger::new(scheduler.clone(), |args: SyncArgs| {
+//!         if args.accounts.is_empty() {
+//!             return Err("no accounts to sync".to_string());
+//!         }
+//!         Ok(MyTask::Sync(args.accounts).into())
+//!     })
+//!     .with_callers(&[backend])
+//!     .with_rate_limit(10, 60),
+//! ));
+//! ```
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Principal};
+use ic_canister::{generate_exports, generate_idl, query, update, Canister, Idl, PreUpdate};
+use ic_helpers::rate_limit::{LimiterState, RateLimit, RateLimitError};
+use ic_kit::ic;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::error::{TriggerError, TriggerResult};
+use crate::scheduler::TaskScheduler;
+use crate::task::{ScheduledTask, Task};
+use crate::time::time_secs;
+
+type AppendFn = Box<dyn Fn(&[u8]) -> TriggerResult<u32>>;
+
+/// A trigger available to the caller of [`TriggerCanister::get_triggers`].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TriggerInfo {
+    pub name: String,
+    /// The max number of the calls of the caller in the window, unlimited if `None`.
+    pub max_calls: Option<u32>,
+    pub window_secs: u64,
+}
+
+/// A predefined task which the authorized principals can append to the scheduler.
+pub struct Trigger {
+    callers: Vec<Principal>,
+    max_calls: Option<u32>,
+    window_secs: u64,
+    append: AppendFn,
+}
+
+impl Trigger {
+    /// Create the trigger appending the task built from the arguments to the scheduler.
+    /// The builder validates the arguments and returns the description of the error if they
+    /// are invalid.
+    ///
+    /// The trigger can't be called until the callers are set with [`Trigger::with_callers`].
+    pub fn new<A, T, S, F>(scheduler: S, build_task: F) -> Self
+    where
+        A: CandidType + DeserializeOwned,
+        T: 'static + Task,
+        S: 'static + TaskScheduler<T>,
+        F: 'static + Fn(A) -> Result<ScheduledTask<T>, String>,
+    {
+        let append = move |args: &[u8]| {
+            let args = candid::decode_one::<A>(args)
+                .map_err(|err| TriggerError::InvalidArguments(err.to_string()))?;
+            let task = build_task(args).map_err(TriggerError::InvalidArguments)?;
+            Ok(scheduler.append_task(task))
+        };
+
+        Self {
+            callers: vec![],
+            max_calls: None,
+            window_secs: 0,
+            append: Box::new(append),
+        }
+    }
+
+    /// Allow the principals to call the trigger.
+    pub fn with_callers(mut self, callers: &[Principal]) -> Self {
+        self.callers = callers.to_vec();
+        self
+    }
+
+    /// Allow every caller to call the trigger at most `max_calls` times in `window_secs`
+    /// seconds.
+    pub fn with_rate_limit(mut self, max_calls: u32, window_secs: u64) -> Self {
+        self.max_calls = Some(max_calls);
+        self.window_secs = window_secs;
+        self
+    }
+
+    /// The window starting with the first call of the caller, whose calls are all refilled
+    /// when the window passes.
+    fn rate_limit(&self) -> Option<RateLimit> {
+        self.max_calls.map(|max_calls| RateLimit::TokenBucket {
+            capacity: max_calls,
+            refill: max_calls,
+            interval_secs: self.window_secs,
+        })
+    }
+}
+
+/// The triggers of the canister with the state of their rate limits.
+///
+/// The state of the rate limits is kept in the heap, so the limits start over after an upgrade.
+#[derive(Default)]
+pub struct Triggers {
+    triggers: BTreeMap<String, Trigger>,
+    limits: BTreeMap<(String, Principal), LimiterState>,
+}
+
+impl Triggers {
+    /// Create the registry without triggers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the trigger with the name, replacing the previous trigger with the name.
+    pub fn with_trigger(mut self, name: &str, trigger: Trigger) -> Self {
+        self.triggers.insert(name.to_string(), trigger);
+        self
+    }
+
+    /// Call the trigger with the candid encoded arguments on behalf of the caller.
+    /// Returns the key of the appended task.
+    ///
+    /// The calls with the invalid arguments count towards the rate limit of the caller.
+    pub fn trigger(&mut self, name: &str, caller: Principal, args: &[u8]) -> TriggerResult<u32> {
+        let trigger = self
+            .triggers
+            .get(name)
+            .ok_or_else(|| TriggerError::UnknownTrigger(name.to_string()))?;
+        if !trigger.callers.contains(&caller) {
+            return Err(TriggerError::Unauthorized(caller.to_string()));
+        }
+
+        if let Some(rate_limit) = trigger.rate_limit() {
+            let now = time_secs();
+            let state = self
+                .limits
+                .entry((name.to_string(), caller))
+                .or_insert_with(|| rate_limit.initial_state(now));
+            rate_limit
+                .try_acquire(state, 1, now)
+                .map_err(|err| match err {
+                    RateLimitError::Limited { retry_after_secs } => {
+                        TriggerError::RateLimited { retry_after_secs }
+                    }
+                    // No calls are allowed.
+                    RateLimitError::ExceedsLimit { .. } => TriggerError::RateLimited {
+                        retry_after_secs: trigger.window_secs,
+                    },
+                })?;
+        }
+
+        (trigger.append)(args)
+    }
+
+    /// The triggers which the caller is allowed to call.
+    pub fn triggers_of(&self, caller: Principal) -> Vec<TriggerInfo> {
+        self.triggers
+            .iter()
+            .filter(|(_, trigger)| trigger.callers.contains(&caller))
+            .map(|(name, trigger)| TriggerInfo {
+                name: name.clone(),
+                max_calls: trigger.max_calls,
+                window_secs: trigger.window_secs,
+            })
+            .collect()
+    }
+}
+
+thread_local! {
+    static TRIGGERS: RefCell<Option<Triggers>> = const { RefCell::new(None) };
+}
+
+/// Set the triggers of the canister. Must be called in `init` and `post_upgrade`.
+pub fn init_triggers(triggers: Triggers) {
+    TRIGGERS.with(|cell| *cell.borrow_mut() = Some(triggers));
+}
+
+/// Call the closure with the triggers of the canister.
+pub fn with_triggers<R>(f: impl FnOnce(&mut Triggers) -> R) -> TriggerResult<R> {
+    TRIGGERS.with(|cell| match &mut *cell.borrow_mut() {
+        Some(triggers) => Ok(f(triggers)),
+        None => Err(TriggerError::NotInitialized),
+    })
+}
+
+/// The API for the external principals to enqueue the predefined tasks of the canister.
+pub trait TriggerCanister: Canister + Sized {
+    /// Calls the trigger with the candid encoded arguments and returns the key of the
+    /// appended task.
+    ///
+    /// Only the principals allowed by the trigger can call it, within its rate limit.
+    #[update(trait = true)]
+    fn trigger(&self, name: String, args: Vec<u8>) -> TriggerResult<u32> {
+        with_triggers(|triggers| triggers.trigger(&name, ic::caller(), &args))?
+    }
+
+    /// Returns the triggers which the caller is allowed to call.
+    #[query(trait = true)]
+    fn get_triggers(&self) -> TriggerResult<Vec<TriggerInfo>> {
+        with_triggers(|triggers| triggers.triggers_of(ic::caller()))
+    }
+
+    // Important: This function *must* be defined to be the
+    // last one in the trait because it depends on the order
+    // of expansion of update/query(trait = true) methods.
+    fn get_idl() -> Idl {
+        generate_idl!()
+    }
+}
+
+generate_exports!(TriggerCanister);
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+
+    use ic_stable_structures::{StableUnboundedMap, VectorMemory};
+    use serde::Serialize;
+
+    use super::*;
+    use crate::scheduler::Scheduler;
+    use crate::time::set_mock_time_secs;
+    use crate::SchedulerError;
+
+    const BACKEND: Principal = Principal::from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct SyncTask(Vec<u64>);
+
+    impl Task for SyncTask {
+        fn execute(
+            &self,
+            _task_scheduler: Box<dyn 'static + TaskScheduler<Self>>,
+        ) -> Pin<Box<dyn Future<Output = Result<(), SchedulerError>>>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn authorized_callers_append_tasks_within_rate_limit() {
+        set_mock_time_secs(Some(1000));
+        let scheduler = Scheduler::new(StableUnboundedMap::new(VectorMemory::default()));
+        let trigger = Trigger::new(scheduler.clone(), |accounts: Vec<u64>| {
+            if accounts.is_empty() {
+                return Err("no accounts to sync".to_string());
+            }
+            Ok(SyncTask(accounts).into())
+        })
+        .with_callers(&[BACKEND])
+        .with_rate_limit(2, 60);
+        let mut triggers = Triggers::new().with_trigger("sync", trigger);
+        let args = |accounts: Vec<u64>| candid::encode_one(accounts).unwrap();
+
+        let key = triggers
+            .trigger("sync", BACKEND, &args(vec![1, 2]))
+            .unwrap();
+        assert_eq!(
+            scheduler.get_task(key).unwrap().task(),
+            &SyncTask(vec![1, 2])
+        );
+
+        assert_eq!(
+            triggers.trigger("sync", Principal::anonymous(), &args(vec![1])),
+            Err(TriggerError::Unauthorized(
+                Principal::anonymous().to_string()
+            ))
+        );
+        assert_eq!(
+            triggers.trigger("burn", BACKEND, &args(vec![1])),
+            Err(TriggerError::UnknownTrigger("burn".to_string()))
+        );
+        assert!(matches!(
+            triggers.trigger("sync", BACKEND, &args(vec![])),
+            Err(TriggerError::InvalidArguments(_))
+        ));
+
+        set_mock_time_secs(Some(1010));
+        assert_eq!(
+            triggers.trigger("sync", BACKEND, &args(vec![3])),
+            Err(TriggerError::RateLimited {
+                retry_after_secs: 50
+            })
+        );
+
+        set_mock_time_secs(Some(1060));
+        assert!(triggers.trigger("sync", BACKEND, &args(vec![3])).is_ok());
+        assert_eq!(
+            triggers.triggers_of(BACKEND),
+            [TriggerInfo {
+                name: "sync".to_string(),
+                max_calls: Some(2),
+                window_secs: 60
+            }]
+        );
+        assert!(triggers.triggers_of(Principal::anonymous()).is_empty());
+        set_mock_time_secs(None);
+    }
+}
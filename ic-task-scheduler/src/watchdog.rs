@@ -0,0 +1,232 @@
+//! A watchdog, which raises an alarm when a job of the canister stops running, e.g. because its
+//! timer was not restored after an upgrade or its heartbeat traps.
+//!
+//! Every successful run of a watched job "feeds" the watchdog. The watchdog is checked
+//! periodically, and if a job was not fed within its deadline, the actions of the watchdog are
+//! triggered once, until the job is fed again.
+//!
+//! ```ignore
+//! let watchdog = Rc::new(RefCell::new(
+//!     Watchdog::default()
+//!         .with_job("sync_blocks", 600)
+//!         .with_action(WatchdogAction::Log)
+//!         .with_action(WatchdogAction::pause_scheduler(&scheduler))
+//!         .with_action(WatchdogAction::NotifyCanister {
+//!             canister: ops_canister,
+//!             method: "on_watchdog_alarm".to_string(),
+//!         }),
+//! ));
+//!
+//! // In the job.
+//! watchdog.borrow_mut().feed("sync_blocks", time_secs);
+//!
+//! // In a timer, independent of the watched jobs.
+//! ic_cdk_timers::set_timer_interval(Duration::from_secs(60), move || {
+//!     watchdog.borrow_mut().check(time_secs);
+//! });
+//! ```
+
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use ic_stable_structures::IterableUnboundedMapStructure;
+use log::{error, info};
+use serde::Deserialize;
+
+use crate::scheduler::Scheduler;
+use crate::task::{InnerScheduledTask, Task};
+
+/// A job which missed its deadline.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchdogAlarm {
+    pub job: String,
+    /// The time in seconds the job was last fed, or the watchdog started watching it.
+    pub last_fed_secs: u64,
+    pub deadline_secs: u64,
+    pub timestamp_secs: u64,
+}
+
+/// What the watchdog does when a job misses its deadline.
+pub enum WatchdogAction {
+    /// Log the alarm as an error.
+    Log,
+    /// Call the closure, e.g. to increment a metric checked by an alert rule.
+    Callback(Box<dyn Fn(&WatchdogAlarm)>),
+    /// Call the update method of the canister with the [`WatchdogAlarm`] argument, ignoring
+    /// the response.
+    NotifyCanister { canister: Principal, method: String },
+}
+
+impl WatchdogAction {
+    /// Pause the scheduler, e.g. to stop the tasks which depend on the missed job.
+    pub fn pause_scheduler<T, P>(scheduler: &Scheduler<T, P>) -> Self
+    where
+        T: 'static + Task,
+        P: 'static + IterableUnboundedMapStructure<u32, InnerScheduledTask<T>>,
+    {
+        let scheduler = scheduler.clone();
+        Self::Callback(Box::new(move |_| scheduler.pause()))
+    }
+
+    fn trigger(&self, alarm: &WatchdogAlarm) {
+        match self {
+            WatchdogAction::Log => error!(
+                "Watchdog - Job {} was not fed since {}, the deadline is {} seconds",
+                alarm.job, alarm.last_fed_secs, alarm.deadline_secs
+            ),
+            WatchdogAction::Callback(callback) => callback(alarm),
+            WatchdogAction::NotifyCanister { canister, method } => {
+                let (canister, method, alarm) = (*canister, method.clone(), alarm.clone());
+                ic::spawn(async move {
+                    if let Err((code, message)) =
+                        ic::call::<_, (), _>(canister, method, (alarm,)).await
+                    {
+                        error!("Watchdog - Failed to notify {canister}: {code:?} {message}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct WatchedJob {
+    deadline_secs: u64,
+    /// `None` until the first check or feed.
+    last_fed_secs: Option<u64>,
+    alarmed: bool,
+}
+
+/// Watches the jobs of the canister and triggers its actions when a job misses its deadline.
+///
+/// The watchdog is kept in the heap, so after an upgrade the deadlines of the jobs start from
+/// the first check.
+#[derive(Default)]
+pub struct Watchdog {
+    jobs: BTreeMap<String, WatchedJob>,
+    actions: Vec<WatchdogAction>,
+}
+
+impl Watchdog {
+    /// Watch the job, which must be fed at least every `deadline_secs`.
+    pub fn with_job(mut self, job: &str, deadline_secs: u64) -> Self {
+        self.jobs.insert(
+            job.to_string(),
+            WatchedJob {
+                deadline_secs,
+                last_fed_secs: None,
+                alarmed: false,
+            },
+        );
+        self
+    }
+
+    /// Trigger the action when a job misses its deadline.
+    pub fn with_action(mut self, action: WatchdogAction) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    /// Record a successful run of the job. Returns `false` if the job is not watched.
+    pub fn feed(&mut self, job: &str, now_secs: u64) -> bool {
+        let Some(watched) = self.jobs.get_mut(job) else {
+            return false;
+        };
+
+        if watched.alarmed {
+            info!("Watchdog - Job {job} is running again");
+        }
+        watched.last_fed_secs = Some(now_secs);
+        watched.alarmed = false;
+        true
+    }
+
+    /// Trigger the actions for the jobs which missed their deadlines since the previous check,
+    /// and return their alarms.
+    pub fn check(&mut self, now_secs: u64) -> Vec<WatchdogAlarm> {
+        let mut alarms = vec![];
+        for (job, watched) in &mut self.jobs {
+            let last_fed_secs = *watched.last_fed_secs.get_or_insert(now_secs);
+            if watched.alarmed || now_secs <= last_fed_secs.saturating_add(watched.deadline_secs) {
+                continue;
+            }
+
+            watched.alarmed = true;
+            alarms.push(WatchdogAlarm {
+                job: job.clone(),
+                last_fed_secs,
+                deadline_secs: watched.deadline_secs,
+                timestamp_secs: now_secs,
+            });
+        }
+
+        for alarm in &alarms {
+            for action in &self.actions {
+                action.trigger(alarm);
+            }
+        }
+
+        alarms
+    }
+
+    /// The jobs which missed their deadlines and were not fed since.
+    pub fn alarmed_jobs(&self) -> Vec<&str> {
+        self.jobs
+            .iter()
+            .filter(|(_, watched)| watched.alarmed)
+            .map(|(job, _)| job.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use ic_stable_structures::{StableUnboundedMap, VectorMemory};
+
+    use super::*;
+    use crate::outbox::DeliveryTask;
+
+    #[test]
+    fn missed_deadlines_trigger_actions_once() {
+        let scheduler: Scheduler<DeliveryTask, _> =
+            Scheduler::new(StableUnboundedMap::new(VectorMemory::default()));
+        let alarms = Rc::new(RefCell::new(vec![]));
+        let callback_alarms = alarms.clone();
+        let mut watchdog = Watchdog::default()
+            .with_job("sync", 10)
+            .with_job("archive", 100)
+            .with_action(WatchdogAction::Log)
+            .with_action(WatchdogAction::Callback(Box::new(move |alarm| {
+                callback_alarms.borrow_mut().push(alarm.job.clone())
+            })))
+            .with_action(WatchdogAction::pause_scheduler(&scheduler));
+
+        assert!(watchdog.check(0).is_empty());
+        assert!(watchdog.feed("sync", 5));
+        assert!(!watchdog.feed("unknown", 5));
+        assert!(watchdog.check(15).is_empty());
+
+        assert_eq!(
+            watchdog.check(16),
+            [WatchdogAlarm {
+                job: "sync".to_string(),
+                last_fed_secs: 5,
+                deadline_secs: 10,
+                timestamp_secs: 16,
+            }]
+        );
+        assert!(watchdog.check(20).is_empty());
+        assert_eq!(watchdog.alarmed_jobs(), ["sync"]);
+        assert!(scheduler.is_paused());
+        assert_eq!(scheduler.run(), Ok(0));
+
+        watchdog.feed("sync", 95);
+        assert!(watchdog.alarmed_jobs().is_empty());
+        assert_eq!(watchdog.check(101).len(), 1);
+        assert_eq!(*alarms.borrow(), ["sync", "archive"]);
+    }
+}